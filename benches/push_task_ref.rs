@@ -0,0 +1,132 @@
+//! Bytes allocated and time taken to push one template to many queues, borrowed by
+//! `push_task_ref` or cloned for each `push_task`, against the in-memory client
+//!
+//! `cargo bench --bench push_task_ref --no-default-features --features gcp-tasks,testing`
+//!
+//! Both sides copy the body once per push: the generated `Task` owns it as a `Vec<u8>`.
+//! What `push_task_ref` leaves out are the output only fields of the template, e.g. the attempts
+//! of a task read back with the FULL view: a template built by hand allocates as much either way.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use google_cloudtasks2::api::{Attempt, Status, Task};
+use nimbus::task::TaskOverrides;
+use nimbus::testing::MemoryCloudTasks;
+use nimbus::{CloudTaskHelper, TaskHelper};
+
+const QUEUES: usize = 200;
+const BODY_LEN: usize = 16 * 1024;
+
+/// the system allocator, counting the bytes allocated
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// a task as read back with the FULL view, its attempts carrying error details
+fn template() -> Task {
+    let headers = HashMap::from([
+        ("Content-Type".to_owned(), "application/json".to_owned()),
+        ("X-Trace".to_owned(), "template".to_owned()),
+    ]);
+    let attempt = Attempt {
+        response_status: Some(Status {
+            code: Some(13),
+            message: Some("e".repeat(BODY_LEN)),
+            details: None,
+        }),
+        ..Default::default()
+    };
+
+    Task {
+        create_time: Some(Utc::now()),
+        dispatch_count: Some(3),
+        first_attempt: Some(attempt.clone()),
+        last_attempt: Some(attempt),
+        ..Task::new_task(
+            "https://example.com/fan-out",
+            "POST",
+            Some(vec![7; BODY_LEN]),
+            Some(headers),
+            None,
+            None,
+            None,
+        )
+    }
+}
+
+fn queues() -> Vec<String> {
+    (0..QUEUES)
+        .map(|i| format!("projects/p/locations/l/queues/q-{i}"))
+        .collect()
+}
+
+/// bytes allocated per push and time per push
+async fn measure<F, Fut>(push: F) -> (usize, Duration)
+where
+    F: Fn(MemoryCloudTasks, String) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let tasks = MemoryCloudTasks::new();
+    let queues = queues();
+
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for queue in queues {
+        push(tasks.clone(), queue).await;
+    }
+    let elapsed = start.elapsed();
+
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - allocated;
+    (bytes / QUEUES, elapsed / QUEUES as u32)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let read_back = template();
+    let built = Task {
+        create_time: None,
+        dispatch_count: None,
+        first_attempt: None,
+        last_attempt: None,
+        ..read_back.clone()
+    };
+
+    println!("{QUEUES} pushes of a {BODY_LEN} bytes body, per push:");
+    for (name, template) in [("read back", &read_back), ("built", &built)] {
+        let (by_ref, by_ref_time) = measure(|tasks, queue| async move {
+            tasks
+                .push_task_ref(&queue, template, TaskOverrides::new(), None)
+                .await
+                .unwrap();
+        })
+        .await;
+        let (cloned, cloned_time) = measure(|tasks, queue| async move {
+            tasks
+                .push_task(&queue, template.clone(), None)
+                .await
+                .unwrap();
+        })
+        .await;
+
+        println!("{name:<10} push_task_ref      {by_ref:>8} bytes {by_ref_time:>10?}");
+        println!("{name:<10} push_task(clone()) {cloned:>8} bytes {cloned_time:>10?}");
+    }
+}
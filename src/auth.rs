@@ -0,0 +1,417 @@
+//! Downscoped GCS credentials, to hand limited storage access to processes that aren't trusted
+//!
+//! A [`DownscopedToken`] is exchanged by the Security Token Service for the token of the source credentials,
+//! with a Credential Access Boundary of [`AccessBoundaryRule`]s: it can do no more than the rules allow, and no
+//! more than the source credentials can. The worker builds its storage client from the token string alone:
+//!
+//! ```ignore
+//! // trusted side
+//! let source = ClientConfig::default().with_auth().await?.token_source_provider.unwrap();
+//! let rules = vec![AccessBoundaryRule::new("media", BoundaryRole::ReadOnly).prefix("jobs/42/")];
+//! let token = auth::downscoped_token(source.token_source().as_ref(), rules).await?;
+//! send_to_worker(token.token(), token.expires_at());
+//!
+//! // worker
+//! let storage = auth::storage_from_token(token_string);
+//! ```
+//!
+//! Downscoped tokens are hand-off tokens: they aren't refreshed. The worker is done before
+//! [`DownscopedToken::expires_at`], or is handed a new token.
+
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::error::ErrorResponse;
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{validate_bucket_name, Error, Provider};
+use crate::NimbusError;
+
+/// Token exchange endpoint of the Security Token Service
+pub const STS_TOKEN_ENDPOINT: &str = "https://sts.googleapis.com/v1/token";
+
+/// Rules a Credential Access Boundary takes at most
+pub const MAX_ACCESS_BOUNDARY_RULES: usize = 10;
+
+const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// What a downscoped token may do with the objects of a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoundaryRole {
+    /// read and list objects
+    ReadOnly,
+    /// create objects, without reading or overwriting them
+    WriteOnly,
+}
+
+impl BoundaryRole {
+    /// IAM role the boundary makes available
+    pub fn gcs_role(&self) -> &'static str {
+        match self {
+            Self::ReadOnly => "roles/storage.objectViewer",
+            Self::WriteOnly => "roles/storage.objectCreator",
+        }
+    }
+}
+
+/// Access to one bucket, optionally restricted to the objects under a prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessBoundaryRule {
+    pub bucket: String,
+    pub prefix: Option<String>,
+    pub role: BoundaryRole,
+}
+
+impl AccessBoundaryRule {
+    pub fn new(bucket: impl Into<String>, role: BoundaryRole) -> Self {
+        Self {
+            bucket: bucket.into(),
+            prefix: None,
+            role,
+        }
+    }
+
+    /// objects whose key starts with `prefix`, listings included for read-only rules
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut rule = serde_json::json!({
+            "availableResource": format!("//storage.googleapis.com/projects/_/buckets/{}", self.bucket),
+            "availablePermissions": [format!("inRole:{}", self.role.gcs_role())],
+        });
+        if let Some(prefix) = self.prefix.as_deref().filter(|p| !p.is_empty()) {
+            let objects = format!(
+                "resource.name.startsWith({})",
+                cel_string(&format!(
+                    "projects/_/buckets/{}/objects/{prefix}",
+                    self.bucket
+                ))
+            );
+            let prefix = cel_string(prefix);
+            // listing the bucket is a call on the bucket, allowed by the prefix asked for
+            let expression = match self.role {
+                BoundaryRole::ReadOnly => format!(
+                    "{objects} || api.getAttribute('storage.googleapis.com/objectListPrefix', '').startsWith({prefix})"
+                ),
+                BoundaryRole::WriteOnly => objects,
+            };
+            rule["availabilityCondition"] = serde_json::json!({ "expression": expression });
+        }
+        rule
+    }
+}
+
+/// `s` as a single-quoted CEL string literal
+fn cel_string(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('\'');
+    for c in s.chars() {
+        if matches!(c, '\'' | '\\') {
+            literal.push('\\');
+        }
+        literal.push(c);
+    }
+    literal.push('\'');
+    literal
+}
+
+/// the `options` of the token exchange, the access boundary of `rules`
+fn access_boundary_json(rules: &[AccessBoundaryRule]) -> serde_json::Value {
+    serde_json::json!({
+        "accessBoundary": {
+            "accessBoundaryRules": rules.iter().map(AccessBoundaryRule::to_json).collect::<Vec<_>>(),
+        }
+    })
+}
+
+/// Short-lived token limited to a Credential Access Boundary, see the [module](self) docs
+#[derive(Clone)]
+pub struct DownscopedToken {
+    token: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl DownscopedToken {
+    /// the access token, without the `Bearer ` scheme
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// expiry of the token, `None` if the Security Token Service didn't tell it
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    /// storage client authenticated with this token, see [`storage_from_token`]
+    pub fn storage(&self) -> Client {
+        storage_from_token(self.token.clone())
+    }
+}
+
+impl fmt::Debug for DownscopedToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownscopedToken")
+            .field("token", &"<redacted>")
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct ExchangeRequest<'a> {
+    grant_type: &'static str,
+    subject_token_type: &'static str,
+    requested_token_type: &'static str,
+    subject_token: &'a str,
+    options: String,
+}
+
+#[derive(Deserialize)]
+struct ExchangeResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ExchangeError {
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// exchange the token of `source_auth` for a token limited to `rules`
+///
+/// The token isn't refreshed, see the [module](self) docs. Take the source from the provider of a
+/// `ClientConfig` with `token_source_provider.token_source()`.
+pub async fn downscoped_token(
+    source_auth: &dyn TokenSource,
+    rules: Vec<AccessBoundaryRule>,
+) -> Result<DownscopedToken, NimbusError> {
+    if rules.is_empty() || rules.len() > MAX_ACCESS_BOUNDARY_RULES {
+        return Err(Error::InvalidAccessBoundary(format!(
+            "{} rules, from 1 to {MAX_ACCESS_BOUNDARY_RULES} are allowed",
+            rules.len()
+        ))
+        .into());
+    }
+    for rule in &rules {
+        validate_bucket_name(&rule.bucket, Provider::Gcs)?;
+    }
+
+    let source = source_auth
+        .token()
+        .await
+        .map_err(|e| Error::Storage(google_cloud_storage::http::Error::TokenSource(e)))?;
+    let request = ExchangeRequest {
+        grant_type: TOKEN_EXCHANGE_GRANT,
+        subject_token_type: ACCESS_TOKEN_TYPE,
+        requested_token_type: ACCESS_TOKEN_TYPE,
+        subject_token: source.strip_prefix("Bearer ").unwrap_or(&source),
+        options: access_boundary_json(&rules).to_string(),
+    };
+
+    let issued_at = Utc::now();
+    let response = reqwest::Client::new()
+        .post(STS_TOKEN_ENDPOINT)
+        .form(&request)
+        .send()
+        .await
+        .map_err(sts_error)?;
+    let status = response.status();
+    let body = response.bytes().await.map_err(sts_error)?;
+    if !status.is_success() {
+        let message = serde_json::from_slice::<ExchangeError>(&body)
+            .ok()
+            .and_then(|e| e.error_description.or(e.error))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+        return Err(Error::Storage(
+            ErrorResponse {
+                code: status.as_u16(),
+                errors: vec![],
+                message: format!("token exchange: {message}"),
+            }
+            .into(),
+        )
+        .into());
+    }
+
+    let exchanged: ExchangeResponse = serde_json::from_slice(&body)
+        .map_err(|e| NimbusError::Other(format!("token exchange response: {e}")))?;
+    Ok(DownscopedToken {
+        token: exchanged.access_token,
+        expires_at: exchanged
+            .expires_in
+            .map(|secs| issued_at + chrono::Duration::seconds(secs)),
+    })
+}
+
+fn sts_error(e: reqwest::Error) -> NimbusError {
+    Error::Storage(e.into()).into()
+}
+
+/// storage client authenticated with an access token alone, e.g. a [`DownscopedToken`] handed to a worker
+///
+/// The token is used as is until it expires, calls fail with [`crate::ErrorCode::Unauthenticated`] after.
+pub fn storage_from_token(token: impl Into<String>) -> Client {
+    let source = Arc::new(StaticTokenSource {
+        header: format!("Bearer {}", token.into()),
+    });
+    Client::new(ClientConfig {
+        token_source_provider: Some(Box::new(StaticTokenProvider { source })),
+        ..ClientConfig::default()
+    })
+}
+
+struct StaticTokenSource {
+    header: String,
+}
+
+impl fmt::Debug for StaticTokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StaticTokenSource(<redacted>)")
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenSource for StaticTokenSource {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.header.clone())
+    }
+}
+
+#[derive(Debug)]
+struct StaticTokenProvider {
+    source: Arc<StaticTokenSource>,
+}
+
+impl TokenSourceProvider for StaticTokenProvider {
+    fn token_source(&self) -> Arc<dyn TokenSource> {
+        self.source.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, StorageHelper};
+
+    #[test]
+    fn rule_json_test() {
+        let rules = vec![
+            AccessBoundaryRule::new("media", BoundaryRole::ReadOnly).prefix("jobs/42/"),
+            AccessBoundaryRule::new("uploads", BoundaryRole::WriteOnly).prefix("in/"),
+            AccessBoundaryRule::new("public", BoundaryRole::ReadOnly),
+            // an empty prefix is the whole bucket
+            AccessBoundaryRule::new("public", BoundaryRole::WriteOnly).prefix(""),
+        ];
+        assert_eq!(
+            access_boundary_json(&rules),
+            serde_json::json!({
+                "accessBoundary": {
+                    "accessBoundaryRules": [
+                        {
+                            "availableResource": "//storage.googleapis.com/projects/_/buckets/media",
+                            "availablePermissions": ["inRole:roles/storage.objectViewer"],
+                            "availabilityCondition": {
+                                "expression": "resource.name.startsWith('projects/_/buckets/media/objects/jobs/42/') || api.getAttribute('storage.googleapis.com/objectListPrefix', '').startsWith('jobs/42/')"
+                            }
+                        },
+                        {
+                            "availableResource": "//storage.googleapis.com/projects/_/buckets/uploads",
+                            "availablePermissions": ["inRole:roles/storage.objectCreator"],
+                            "availabilityCondition": {
+                                "expression": "resource.name.startsWith('projects/_/buckets/uploads/objects/in/')"
+                            }
+                        },
+                        {
+                            "availableResource": "//storage.googleapis.com/projects/_/buckets/public",
+                            "availablePermissions": ["inRole:roles/storage.objectViewer"]
+                        },
+                        {
+                            "availableResource": "//storage.googleapis.com/projects/_/buckets/public",
+                            "availablePermissions": ["inRole:roles/storage.objectCreator"]
+                        }
+                    ]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn cel_string_test() {
+        assert_eq!(cel_string("jobs/42/"), "'jobs/42/'");
+        assert_eq!(cel_string("it's"), r"'it\'s'");
+        assert_eq!(cel_string(r"a\b"), r"'a\\b'");
+
+        let rule = AccessBoundaryRule::new("media", BoundaryRole::WriteOnly).prefix("o'k/");
+        assert_eq!(
+            rule.to_json()["availabilityCondition"]["expression"],
+            r"resource.name.startsWith('projects/_/buckets/media/objects/o\'k/')"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_rules_test() {
+        let source = StaticTokenSource {
+            header: "Bearer source".to_owned(),
+        };
+        let e = downscoped_token(&source, vec![]).await.unwrap_err();
+        assert_eq!(e.code(), ErrorCode::InvalidInput);
+        let rules = vec![AccessBoundaryRule::new("media", BoundaryRole::ReadOnly); 11];
+        let e = downscoped_token(&source, rules).await.unwrap_err();
+        assert_eq!(e.code(), ErrorCode::InvalidInput);
+        let rules = vec![AccessBoundaryRule::new(
+            "Not A Bucket",
+            BoundaryRole::ReadOnly,
+        )];
+        let e = downscoped_token(&source, rules).await.unwrap_err();
+        assert_eq!(e.code(), ErrorCode::InvalidInput);
+    }
+
+    #[test]
+    fn token_debug_test() {
+        let token = DownscopedToken {
+            token: "secret".to_owned(),
+            expires_at: None,
+        };
+        assert!(!format!("{token:?}").contains("secret"));
+        assert!(!token.is_expired());
+    }
+
+    #[tokio::test]
+    async fn downscoped_read_test() {
+        let config = ClientConfig::default().with_auth().await.unwrap();
+        let source = config.token_source_provider.unwrap().token_source();
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let inside = std::env::var("KEY").unwrap();
+        let outside = std::env::var("OTHER_KEY").unwrap();
+        let prefix = inside
+            .rsplit_once('/')
+            .map_or("", |(dir, _)| dir)
+            .to_owned()
+            + "/";
+
+        let rules = vec![AccessBoundaryRule::new(&bucket, BoundaryRole::ReadOnly).prefix(prefix)];
+        let token = downscoped_token(source.as_ref(), rules).await.unwrap();
+        assert!(token.expires_at().is_some_and(|at| at > Utc::now()));
+
+        let storage = storage_from_token(token.token());
+        storage.download_to_bytes(&bucket, &inside).await.unwrap();
+        let e = storage
+            .download_to_bytes(&bucket, &outside)
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ErrorCode::PermissionDenied);
+    }
+}
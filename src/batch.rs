@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::{ErrorCode, NimbusError};
+
+/// Failure of a single item of a batch
+#[derive(Debug)]
+pub struct BatchError<K> {
+    pub key: K,
+    pub error: NimbusError,
+}
+
+/// the key, the code of the error and its message, as errors show it
+#[cfg(feature = "serde")]
+impl<K: Serialize> Serialize for BatchError<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("BatchError", 3)?;
+        state.serialize_field("key", &self.key)?;
+        state.serialize_field("code", &self.error.code())?;
+        state.serialize_field("error", &self.error.to_string())?;
+        state.end()
+    }
+}
+
+/// Outcome of an operation applied to many items that fail independently
+///
+/// A failing item never fails the batch, it is reported in [`BatchOutcome::failed`] instead.
+#[derive(Debug)]
+pub struct BatchOutcome<K, V> {
+    pub succeeded: HashMap<K, V>,
+    pub failed: Vec<BatchError<K>>,
+}
+
+impl<K, V> Default for BatchOutcome<K, V> {
+    fn default() -> Self {
+        Self {
+            succeeded: HashMap::new(),
+            failed: vec![],
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> BatchOutcome<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record the result for `key`
+    pub fn push(&mut self, key: K, result: Result<V, NimbusError>) {
+        match result {
+            Ok(v) => {
+                self.succeeded.insert(key, v);
+            }
+            Err(error) => self.failed.push(BatchError { key, error }),
+        }
+    }
+
+    /// number of items recorded, succeeded or failed
+    pub fn len(&self) -> usize {
+        self.succeeded.len() + self.failed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// true if no item failed
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// keys of the items that failed because they don't exist
+    pub fn not_found(&self) -> impl Iterator<Item = &K> {
+        self.failed
+            .iter()
+            .filter(|e| e.error.code() == ErrorCode::NotFound)
+            .map(|e| &e.key)
+    }
+}
+
+impl<K: Eq + Hash, V> FromIterator<(K, Result<V, NimbusError>)> for BatchOutcome<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, Result<V, NimbusError>)>>(iter: T) -> Self {
+        let mut outcome = Self::new();
+        for (key, result) in iter {
+            outcome.push(key, result);
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage;
+
+    use super::*;
+
+    #[test]
+    fn batch_outcome_test() {
+        let outcome: BatchOutcome<&str, u32> = [
+            ("a", Ok(1)),
+            ("b", Err(storage::Error::NotFound("b".to_owned()).into())),
+            ("c", Err(NimbusError::Other("boom".to_owned()))),
+            ("d", Ok(4)),
+        ]
+        .into_iter()
+        .collect();
+
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.succeeded.len(), 2);
+        assert_eq!(outcome.succeeded["d"], 4);
+        assert_eq!(outcome.failed.len(), 2);
+        assert_eq!(outcome.not_found().collect::<Vec<_>>(), [&"b"]);
+
+        assert!(BatchOutcome::<&str, u32>::new().is_success());
+    }
+}
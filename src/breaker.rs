@@ -0,0 +1,607 @@
+//! Circuit breaker failing calls fast while a provider is down
+//!
+//! During an outage every call to the provider waits for its timeout, and the callers' request budget goes
+//! into waiting. A [`CircuitBreaker`] watches the outcomes of the calls and, once too many of them fail on
+//! timeouts or unavailability, opens: calls fail straight away with [`NimbusError::CircuitOpen`] until
+//! [`BreakerConfig::open_for`] passed. It then half-opens and lets a few probe calls through: if they all
+//! succeed it closes again, if one fails it opens for another period.
+//!
+//! Only the errors of an unreachable or failing provider count as failures, see [`trips`]: a missing
+//! object or a denied call is the provider answering, and counts as a success.
+//!
+//! A breaker guards a client wrapped in [`GuardedStorage`], [`GuardedSecretManager`] or
+//! `GuardedCloudTasks`. Clones share their state: one breaker per provider and region, shared by the
+//! clients calling it, opens for all of them at once.
+//!
+//! ```ignore
+//! let gcp = CircuitBreaker::new("gcp-europe-west1")
+//!     .config(BreakerConfig::new().failure_rate(0.5).open_for(Duration::from_secs(30)))
+//!     .on_state_change(|change| alerting.notify(change.breaker, change.to));
+//! let storage = GuardedStorage::new(client, gcp.clone());
+//! let secrets = GuardedSecretManager::new(secret_manager, gcp);
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{ErrorCode, NimbusError};
+
+mod secret;
+mod storage;
+#[cfg(feature = "gcp-tasks")]
+mod task;
+
+pub use secret::GuardedSecretManager;
+pub use storage::GuardedStorage;
+#[cfg(feature = "gcp-tasks")]
+pub use task::GuardedCloudTasks;
+
+/// Share of failed calls opening the breaker unless set with [`BreakerConfig::failure_rate`]
+pub const DEFAULT_FAILURE_RATE: f64 = 0.5;
+
+/// Span of the outcomes the failure rate is computed on unless set with [`BreakerConfig::window`]
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Calls in the window below which the breaker never opens unless set with [`BreakerConfig::min_calls`]
+pub const DEFAULT_MIN_CALLS: usize = 10;
+
+/// Time the breaker stays open unless set with [`BreakerConfig::open_for`]
+pub const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// Probe calls of a half-open breaker unless set with [`BreakerConfig::half_open_probes`]
+pub const DEFAULT_HALF_OPEN_PROBES: usize = 3;
+
+/// whether a call failing with `code` counts as a failure of the provider: timeouts and unavailability,
+/// 5xx and connection failures included; everything else is the provider answering
+pub fn trips(code: ErrorCode) -> bool {
+    matches!(code, ErrorCode::Timeout | ErrorCode::Unavailable)
+}
+
+/// When a [`CircuitBreaker`] opens and closes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    /// share of the calls in the window failing, from 0 to 1, opening the breaker
+    pub failure_rate: f64,
+    pub window: Duration,
+    /// the breaker never opens on fewer calls in the window, so a single failure doesn't open it
+    pub min_calls: usize,
+    pub open_for: Duration,
+    /// calls let through once half-open, all of them must succeed to close, at least 1
+    pub half_open_probes: usize,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: DEFAULT_FAILURE_RATE,
+            window: DEFAULT_WINDOW,
+            min_calls: DEFAULT_MIN_CALLS,
+            open_for: DEFAULT_OPEN_DURATION,
+            half_open_probes: DEFAULT_HALF_OPEN_PROBES,
+        }
+    }
+}
+
+impl BreakerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// clamped to `[0, 1]`
+    pub fn failure_rate(mut self, rate: f64) -> Self {
+        self.failure_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn min_calls(mut self, min_calls: usize) -> Self {
+        self.min_calls = min_calls;
+        self
+    }
+
+    pub fn open_for(mut self, open_for: Duration) -> Self {
+        self.open_for = open_for;
+        self
+    }
+
+    pub fn half_open_probes(mut self, probes: usize) -> Self {
+        self.half_open_probes = probes;
+        self
+    }
+}
+
+/// State of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BreakerState {
+    /// calls go through
+    Closed,
+    /// calls fail fast with [`NimbusError::CircuitOpen`]
+    Open,
+    /// probe calls go through, the others fail fast
+    HalfOpen,
+}
+
+/// Transition of a [`CircuitBreaker`], reported to the callback of [`CircuitBreaker::on_state_change`]
+#[derive(Debug)]
+pub struct StateChange<'a> {
+    /// name of the breaker, e.g. the provider and region it guards
+    pub breaker: &'a str,
+    pub from: BreakerState,
+    pub to: BreakerState,
+}
+
+type Observer = dyn Fn(StateChange<'_>) + Send + Sync;
+
+enum Phase {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probing: usize, succeeded: usize },
+}
+
+impl Phase {
+    fn state(&self) -> BreakerState {
+        match self {
+            Phase::Closed => BreakerState::Closed,
+            Phase::Open { .. } => BreakerState::Open,
+            Phase::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+}
+
+struct State {
+    phase: Phase,
+    /// outcomes of the calls in the window while closed, `true` for failures
+    outcomes: VecDeque<(Instant, bool)>,
+    /// incremented on every transition, outcomes of calls let through before are ignored
+    generation: u64,
+}
+
+struct Shared {
+    name: String,
+    config: BreakerConfig,
+    state: Mutex<State>,
+    observer: Option<Arc<Observer>>,
+}
+
+/// Breaker opening on provider failures, see the [module](self) docs
+///
+/// Clones share their state.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("name", &self.shared.name)
+            .field("config", &self.shared.config)
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CircuitBreaker {
+    /// `name` is reported in the [`StateChange`]s, e.g. the provider and region guarded
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                name: name.into(),
+                config: BreakerConfig::default(),
+                state: Mutex::new(State {
+                    phase: Phase::Closed,
+                    outcomes: VecDeque::new(),
+                    generation: 0,
+                }),
+                observer: None,
+            }),
+        }
+    }
+
+    pub fn config(mut self, config: BreakerConfig) -> Self {
+        self.configure().config = config;
+        self
+    }
+
+    /// report every transition to `observer`, e.g. to alert on an opening breaker
+    pub fn on_state_change(
+        mut self,
+        observer: impl Fn(StateChange<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.configure().observer = Some(Arc::new(observer));
+        self
+    }
+
+    fn configure(&mut self) -> &mut Shared {
+        Arc::get_mut(&mut self.shared).expect("breaker is configured before being cloned")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.name
+    }
+
+    /// the state as of now: an open breaker whose period is over is reported half-open
+    pub fn state(&self) -> BreakerState {
+        let state = self.shared.state.lock().unwrap();
+        match state.phase {
+            Phase::Open { until } if Instant::now() >= until => BreakerState::HalfOpen,
+            ref phase => phase.state(),
+        }
+    }
+
+    /// run `call` unless the breaker is open, and record its outcome
+    ///
+    /// Fails with [`NimbusError::CircuitOpen`] without running `call` while open, or while half-open
+    /// with all the probes in flight. A call dropped before completing records nothing.
+    pub async fn call<T>(
+        &self,
+        call: impl Future<Output = Result<T, NimbusError>>,
+    ) -> Result<T, NimbusError> {
+        let mut permit = self.acquire()?;
+        let result = call.await;
+        permit.record(result.as_ref().err().map(NimbusError::code));
+        result
+    }
+
+    fn acquire(&self) -> Result<Permit<'_>, NimbusError> {
+        let now = Instant::now();
+        let mut guard = self.shared.state.lock().unwrap();
+        let state = &mut *guard;
+        let mut change = None;
+
+        if let Phase::Open { until } = state.phase {
+            if now < until {
+                return Err(NimbusError::CircuitOpen {
+                    retry_after: until - now,
+                });
+            }
+            change = Some(state.transition(Phase::HalfOpen {
+                probing: 0,
+                succeeded: 0,
+            }));
+        }
+
+        let probe = match &mut state.phase {
+            Phase::HalfOpen { probing, succeeded }
+                if *probing + *succeeded >= self.shared.config.half_open_probes.max(1) =>
+            {
+                drop(guard);
+                self.notify(change);
+                // the probes in flight decide, the caller may try again any time
+                return Err(NimbusError::CircuitOpen {
+                    retry_after: Duration::ZERO,
+                });
+            }
+            Phase::HalfOpen { probing, .. } => {
+                *probing += 1;
+                true
+            }
+            _ => false,
+        };
+        let generation = state.generation;
+        drop(guard);
+        self.notify(change);
+
+        Ok(Permit {
+            breaker: self,
+            generation,
+            probe,
+            recorded: false,
+        })
+    }
+
+    /// `failure` is `None` for calls cancelled or dropped, which say nothing of the provider
+    fn record(&self, generation: u64, probe: bool, failure: Option<bool>) {
+        let now = Instant::now();
+        let config = &self.shared.config;
+        let mut guard = self.shared.state.lock().unwrap();
+        let state = &mut *guard;
+        if state.generation != generation {
+            return;
+        }
+
+        let next = match (&mut state.phase, failure) {
+            (Phase::HalfOpen { probing, succeeded }, _) if probe => {
+                *probing -= 1;
+                match failure {
+                    // one failing probe is a relapse
+                    Some(true) => Some(Phase::Open {
+                        until: now + config.open_for,
+                    }),
+                    Some(false) => {
+                        *succeeded += 1;
+                        (*succeeded >= config.half_open_probes.max(1)).then_some(Phase::Closed)
+                    }
+                    None => None,
+                }
+            }
+            (Phase::Closed, Some(failed)) => {
+                let outcomes = &mut state.outcomes;
+                outcomes.push_back((now, failed));
+                while outcomes
+                    .front()
+                    .is_some_and(|(at, _)| now.duration_since(*at) > config.window)
+                {
+                    outcomes.pop_front();
+                }
+
+                let calls = outcomes.len();
+                let failures = outcomes.iter().filter(|(_, failed)| *failed).count();
+                let open = calls >= config.min_calls.max(1)
+                    && failures > 0
+                    && failures as f64 >= config.failure_rate * calls as f64;
+                open.then(|| Phase::Open {
+                    until: now + config.open_for,
+                })
+            }
+            _ => None,
+        };
+        let change = next.map(|phase| state.transition(phase));
+        drop(guard);
+        self.notify(change);
+    }
+
+    fn notify(&self, change: Option<(BreakerState, BreakerState)>) {
+        if let (Some((from, to)), Some(observer)) = (change, &self.shared.observer) {
+            observer(StateChange {
+                breaker: &self.shared.name,
+                from,
+                to,
+            });
+        }
+    }
+}
+
+impl State {
+    /// move to `phase`, returning the states before and after
+    fn transition(&mut self, phase: Phase) -> (BreakerState, BreakerState) {
+        let from = self.phase.state();
+        self.phase = phase;
+        self.outcomes.clear();
+        self.generation += 1;
+        (from, self.phase.state())
+    }
+}
+
+/// a call let through, recording nothing if dropped before its outcome is known
+struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    generation: u64,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    fn record(&mut self, error: Option<ErrorCode>) {
+        self.recorded = true;
+        let failure = match error {
+            Some(ErrorCode::Cancelled) => None,
+            Some(code) => Some(trips(code)),
+            None => Some(false),
+        };
+        self.breaker.record(self.generation, self.probe, failure);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.record(self.generation, self.probe, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+    use crate::StorageHelper;
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    use crate::{testing::MemorySecretManager, SecretManagerHelper};
+
+    const OPEN_FOR: Duration = Duration::from_secs(30);
+
+    fn breaker(changes: &Arc<Mutex<Vec<String>>>) -> CircuitBreaker {
+        let changes = changes.clone();
+        CircuitBreaker::new("gcp-eu")
+            .config(
+                BreakerConfig::new()
+                    .failure_rate(0.5)
+                    .window(Duration::from_secs(10))
+                    .min_calls(4)
+                    .open_for(OPEN_FOR)
+                    .half_open_probes(2),
+            )
+            .on_state_change(move |change| {
+                changes.lock().unwrap().push(format!(
+                    "{}: {:?} -> {:?}",
+                    change.breaker, change.from, change.to
+                ))
+            })
+    }
+
+    async fn storage(breaker: &CircuitBreaker) -> GuardedStorage<MemoryStorage> {
+        let storage = GuardedStorage::new(MemoryStorage::new(), breaker.clone());
+        storage
+            .upload_from_bytes("b", "k", None, b"data".to_vec())
+            .await
+            .unwrap();
+        storage
+    }
+
+    fn fail(storage: &GuardedStorage<MemoryStorage>, code: ErrorCode) {
+        storage
+            .inner()
+            .mock_stats()
+            .set_fault("download_to_bytes", Fault::new().fail(1.0, code));
+    }
+
+    async fn download(storage: &GuardedStorage<MemoryStorage>) -> Result<Vec<u8>, NimbusError> {
+        storage.download_to_bytes("b", "k").await
+    }
+
+    #[test]
+    fn trips_test() {
+        assert!(trips(ErrorCode::Timeout));
+        assert!(trips(ErrorCode::Unavailable));
+        for code in [
+            ErrorCode::NotFound,
+            ErrorCode::PermissionDenied,
+            ErrorCode::Unauthenticated,
+            ErrorCode::InvalidInput,
+            ErrorCode::RateLimited,
+        ] {
+            assert!(!trips(code), "{code:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn breaker_trip_test() {
+        let changes = Arc::new(Mutex::new(vec![]));
+        let breaker = breaker(&changes);
+        let storage = storage(&breaker).await;
+
+        // the provider answering, however negatively, never opens the breaker
+        for code in [ErrorCode::NotFound, ErrorCode::PermissionDenied] {
+            fail(&storage, code);
+            for _ in 0..10 {
+                assert_eq!(download(&storage).await.unwrap_err().code(), code);
+            }
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // 3 failures among the calls so far, under the rate
+        fail(&storage, ErrorCode::Timeout);
+        for _ in 0..3 {
+            download(&storage).await.unwrap_err();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // failures older than the window are forgotten: 3 out of 3 calls, under the minimum
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        fail(&storage, ErrorCode::Unavailable);
+        for _ in 0..3 {
+            download(&storage).await.unwrap_err();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        download(&storage).await.unwrap_err();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(*changes.lock().unwrap(), ["gcp-eu: Closed -> Open"]);
+
+        // fast fail, without calling the provider
+        let calls = storage.inner().stats().calls("download_to_bytes");
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let err = download(&storage).await.unwrap_err();
+        assert!(matches!(err, NimbusError::CircuitOpen { .. }), "{err:?}");
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert_eq!(err.retry_after(), Some(OPEN_FOR - Duration::from_secs(10)));
+        assert_eq!(storage.inner().stats().calls("download_to_bytes"), calls);
+
+        // shared with the other wrappers
+        #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+        {
+            let secrets = GuardedSecretManager::new(MemorySecretManager::new(), breaker.clone());
+            let err = secrets.get_secret("p", "s").await.unwrap_err();
+            assert!(matches!(err, NimbusError::CircuitOpen { .. }), "{err:?}");
+            assert_eq!(secrets.inner().stats().total_calls(), 0);
+        }
+    }
+
+    async fn open(storage: &GuardedStorage<MemoryStorage>) {
+        fail(storage, ErrorCode::Unavailable);
+        for _ in 0..4 {
+            download(storage).await.unwrap_err();
+        }
+        assert_eq!(storage.breaker().state(), BreakerState::Open);
+        storage.inner().mock_stats().clear_faults();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn breaker_half_open_test() {
+        let changes = Arc::new(Mutex::new(vec![]));
+        let breaker = breaker(&changes);
+        let storage = storage(&breaker).await;
+        open(&storage).await;
+
+        tokio::time::sleep(OPEN_FOR).await;
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        // 2 probes in flight, the third call fails fast
+        storage.inner().mock_stats().set_fault(
+            "download_to_bytes",
+            Fault::new().latency(Latency::Fixed(Duration::from_secs(1))),
+        );
+        let (first, second, third) = tokio::join!(download(&storage), download(&storage), async {
+            tokio::task::yield_now().await;
+            download(&storage).await
+        });
+        assert_eq!(first.unwrap(), b"data");
+        assert_eq!(second.unwrap(), b"data");
+        let err = third.unwrap_err();
+        assert!(matches!(err, NimbusError::CircuitOpen { .. }), "{err:?}");
+        assert_eq!(err.retry_after(), Some(Duration::ZERO));
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                "gcp-eu: Closed -> Open",
+                "gcp-eu: Open -> HalfOpen",
+                "gcp-eu: HalfOpen -> Closed"
+            ]
+        );
+        download(&storage).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn breaker_relapse_test() {
+        let changes = Arc::new(Mutex::new(vec![]));
+        let breaker = breaker(&changes);
+        let storage = storage(&breaker).await;
+        open(&storage).await;
+        tokio::time::sleep(OPEN_FOR).await;
+
+        // a successful probe, then a failing one: open for another period
+        download(&storage).await.unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        fail(&storage, ErrorCode::Timeout);
+        download(&storage).await.unwrap_err();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        let err = download(&storage).await.unwrap_err();
+        assert_eq!(err.retry_after(), Some(OPEN_FOR));
+
+        // a dropped probe frees its slot without deciding anything
+        tokio::time::sleep(OPEN_FOR).await;
+        storage.inner().mock_stats().set_fault(
+            "download_to_bytes",
+            Fault::new().latency(Latency::Fixed(Duration::from_secs(60))),
+        );
+        let dropped = tokio::time::timeout(Duration::from_secs(1), download(&storage)).await;
+        assert!(dropped.is_err());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        storage.inner().mock_stats().clear_faults();
+        download(&storage).await.unwrap();
+        download(&storage).await.unwrap();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                "gcp-eu: Closed -> Open",
+                "gcp-eu: Open -> HalfOpen",
+                "gcp-eu: HalfOpen -> Open",
+                "gcp-eu: Open -> HalfOpen",
+                "gcp-eu: HalfOpen -> Closed"
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+#[cfg(feature = "gcp-secrets")]
+use google_secretmanager1::oauth2::authenticator::Authenticator;
+
+use super::CircuitBreaker;
+#[cfg(feature = "gcp-secrets")]
+use crate::CredentialMonitor;
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+use crate::TransportConfig;
+use crate::{NimbusError, SecretManagerHelper};
+
+/// Secret manager client whose calls go through a [`CircuitBreaker`], see the [module](super) docs
+///
+/// Built by [`SecretManagerHelper::new_with_authenticator`], it has a breaker of its own named `secrets`.
+#[derive(Debug, Clone)]
+pub struct GuardedSecretManager<M> {
+    inner: M,
+    breaker: CircuitBreaker,
+}
+
+impl<M> GuardedSecretManager<M> {
+    pub fn new(inner: M, breaker: CircuitBreaker) -> Self {
+        Self { inner, breaker }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: SecretManagerHelper<S> + Send + Sync, S: Send + Sync + 'static> SecretManagerHelper<S>
+    for GuardedSecretManager<M>
+{
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_authenticator(authenticator: Authenticator<S>) -> Self {
+        Self::new(
+            M::new_with_authenticator(authenticator).await,
+            CircuitBreaker::new("secrets"),
+        )
+    }
+
+    #[cfg(feature = "aws-secrets")]
+    async fn new_with_authenticator() -> Self {
+        Self::new(
+            M::new_with_authenticator().await,
+            CircuitBreaker::new("secrets"),
+        )
+    }
+
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_transport(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+    ) -> Self {
+        Self::new(
+            M::new_with_transport(authenticator, transport).await,
+            CircuitBreaker::new("secrets"),
+        )
+    }
+
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_monitor(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self {
+        Self::new(
+            M::new_with_monitor(authenticator, transport, monitor).await,
+            CircuitBreaker::new("secrets"),
+        )
+    }
+
+    #[cfg(feature = "aws-secrets")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Self::new(
+            M::new_with_transport(transport).await,
+            CircuitBreaker::new("secrets"),
+        )
+    }
+
+    async fn get_secret(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(self.inner.get_secret(project, secret))
+            .await
+    }
+
+    async fn create_secret(
+        &self,
+        project: &str,
+        secret_name: &str,
+        secret_val: &str,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.create_secret(project, secret_name, secret_val))
+            .await
+    }
+
+    async fn create_secret_bytes(
+        &self,
+        project: &str,
+        secret_name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.create_secret_bytes(project, secret_name, data))
+            .await
+    }
+
+    async fn delete_secret(&self, project: &str, secret: &str) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.delete_secret(project, secret))
+            .await
+    }
+
+    async fn get_secret_version(
+        &self,
+        project: &str,
+        secret: &str,
+        version: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(self.inner.get_secret_version(project, secret, version))
+            .await
+    }
+
+    async fn latest_version_id(&self, project: &str, secret: &str) -> Result<String, NimbusError> {
+        self.breaker
+            .call(self.inner.latest_version_id(project, secret))
+            .await
+    }
+
+    // provided by the trait, but overridden by some providers
+    async fn get_secret_version_id(
+        &self,
+        project: &str,
+        secret: &str,
+        version_id: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(
+                self.inner
+                    .get_secret_version_id(project, secret, version_id),
+            )
+            .await
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+
+use super::CircuitBreaker;
+use crate::storage::{
+    AclEntry, AppendOutcome, CorsRule, EncryptionKey, ListPage, ListParams, ObjectMeta,
+    ObjectStream, PolicyCondition, PostPolicy, Precondition, PublicAccess, ReplicationStatus,
+    ResumableUpload, StorageClass, UpdateOptions,
+};
+#[cfg(feature = "aws-storage")]
+use crate::TransportConfig;
+use crate::{ByteSize, ListLimits, NimbusError, StorageHelper};
+
+/// Storage client whose calls go through a [`CircuitBreaker`], see the [module](super) docs
+///
+/// Built by [`StorageHelper::new_with_authenticator`], it has a breaker of its own named `storage`.
+#[derive(Debug, Clone)]
+pub struct GuardedStorage<C> {
+    inner: C,
+    breaker: CircuitBreaker,
+}
+
+impl<C> GuardedStorage<C> {
+    pub fn new(inner: C, breaker: CircuitBreaker) -> Self {
+        Self { inner, breaker }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: StorageHelper + Send + Sync> StorageHelper for GuardedStorage<C> {
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_authenticator() -> Self {
+        Self::new(
+            C::new_with_authenticator().await,
+            CircuitBreaker::new("storage"),
+        )
+    }
+
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Self::new(
+            C::new_with_transport(transport).await,
+            CircuitBreaker::new("storage"),
+        )
+    }
+
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.upload_from_bytes(bucket, key, mime, data))
+            .await
+    }
+
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(self.inner.download_to_bytes(bucket, key))
+            .await
+    }
+
+    async fn download_to_writer<W>(
+        &self,
+        bucket: &str,
+        key: &str,
+        writer: &mut W,
+    ) -> Result<u64, NimbusError>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.breaker
+            .call(self.inner.download_to_writer(bucket, key, writer))
+            .await
+    }
+
+    /// only opening the stream is guarded, reading its chunks records nothing
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        self.breaker
+            .call(self.inner.download_stream(bucket, key))
+            .await
+    }
+
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(self.inner.download_with_encoding(bucket, key, decompress))
+            .await
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(self.inner.download_range(bucket, key, start, end))
+            .await
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(
+                self.inner
+                    .upload_encrypted(bucket, key, mime, data, encryption),
+            )
+            .await
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.breaker
+            .call(self.inner.download_encrypted(bucket, key, encryption))
+            .await
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        self.breaker
+            .call(
+                self.inner
+                    .object_metadata_encrypted(bucket, key, encryption),
+            )
+            .await
+    }
+
+    async fn copy_encrypted(
+        &self,
+        source: (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        destination: (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.copy_encrypted(
+                source,
+                source_encryption,
+                destination,
+                destination_encryption,
+            ))
+            .await
+    }
+
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.set_storage_class(bucket, key, class))
+            .await
+    }
+
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        self.breaker.call(self.inner.delete_file(bucket, key)).await
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        self.breaker
+            .call(self.inner.object_exists(bucket, key))
+            .await
+    }
+
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        self.breaker
+            .call(
+                self.inner
+                    .upload_conditional(bucket, key, mime, data, precondition),
+            )
+            .await
+    }
+
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        self.breaker
+            .call(
+                self.inner
+                    .upload_with_metadata(bucket, key, mime, metadata, data, precondition),
+            )
+            .await
+    }
+
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        self.breaker
+            .call(self.inner.append_bytes(bucket, key, mime, data, options))
+            .await
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.delete_conditional(bucket, key, version))
+            .await
+    }
+
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        self.breaker
+            .call(self.inner.download_versioned(bucket, key))
+            .await
+    }
+
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        self.breaker
+            .call(self.inner.list_page(bucket, params, page_token))
+            .await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        self.breaker
+            .call(self.inner.object_metadata(bucket, key))
+            .await
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        self.breaker
+            .call(self.inner.replication_status(bucket, key))
+            .await
+    }
+
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.set_object_tags(bucket, key, tags))
+            .await
+    }
+
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        self.breaker
+            .call(self.inner.get_object_tags(bucket, key))
+            .await
+    }
+
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        self.breaker
+            .call(self.inner.list_soft_deleted(bucket, prefix, limits))
+            .await
+    }
+
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.restore_object(bucket, key, generation))
+            .await
+    }
+
+    async fn test_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        self.breaker
+            .call(self.inner.test_permissions(bucket, permissions))
+            .await
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        self.breaker.call(self.inner.bucket_is_public(bucket)).await
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        self.breaker
+            .call(self.inner.object_acls_apply(bucket))
+            .await
+    }
+
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        self.breaker.call(self.inner.object_acl(bucket, key)).await
+    }
+
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        self.breaker.call(self.inner.get_cors(bucket)).await
+    }
+
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        self.breaker.call(self.inner.set_cors(bucket, rules)).await
+    }
+
+    async fn signed_post_policy(
+        &self,
+        bucket: &str,
+        key_prefix: &str,
+        max_size: ByteSize,
+        expires: Duration,
+        conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        self.breaker
+            .call(
+                self.inner
+                    .signed_post_policy(bucket, key_prefix, max_size, expires, conditions),
+            )
+            .await
+    }
+
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.breaker
+            .call(self.inner.signed_download_url(bucket, key, expires_in))
+            .await
+    }
+
+    async fn signed_upload_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.breaker
+            .call(self.inner.signed_upload_url(bucket, key, mime, expires_in))
+            .await
+    }
+
+    /// only the start is guarded, the chunks go straight through the returned upload
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        self.breaker
+            .call(self.inner.start_resumable_upload(bucket, key, mime))
+            .await
+    }
+}
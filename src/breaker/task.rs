@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use google_cloudtasks2::api::Task;
+use google_cloudtasks2::hyper::{Body, Response};
+use google_cloudtasks2::oauth2::authenticator::Authenticator;
+
+use super::CircuitBreaker;
+use crate::task::{QueueConfigSnapshot, QueueInfo, QueuePath, TaskOutcome, TaskView};
+use crate::{CloudTaskHelper, CredentialMonitor, ListLimits, NimbusError, TransportConfig};
+
+/// Cloud Tasks client whose calls go through a [`CircuitBreaker`], see the [module](super) docs
+///
+/// Built by [`CloudTaskHelper::new_with_authenticator`], it has a breaker of its own named `tasks`.
+#[derive(Debug, Clone)]
+pub struct GuardedCloudTasks<C> {
+    inner: C,
+    breaker: CircuitBreaker,
+}
+
+impl<C> GuardedCloudTasks<C> {
+    pub fn new(inner: C, breaker: CircuitBreaker) -> Self {
+        Self { inner, breaker }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: CloudTaskHelper<S> + Send + Sync, S: Send + Sync + 'static> CloudTaskHelper<S>
+    for GuardedCloudTasks<C>
+{
+    async fn new_with_authenticator(authenticator: Authenticator<S>) -> Self {
+        Self::new(
+            C::new_with_authenticator(authenticator).await,
+            CircuitBreaker::new("tasks"),
+        )
+    }
+
+    async fn new_with_transport(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+    ) -> Self {
+        Self::new(
+            C::new_with_transport(authenticator, transport).await,
+            CircuitBreaker::new("tasks"),
+        )
+    }
+
+    async fn new_with_monitor(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self {
+        Self::new(
+            C::new_with_monitor(authenticator, transport, monitor).await,
+            CircuitBreaker::new("tasks"),
+        )
+    }
+
+    async fn create_task(
+        &self,
+        queue: &str,
+        task: Task,
+        res_view: Option<TaskView>,
+    ) -> Result<(Response<Body>, Task), NimbusError> {
+        self.breaker
+            .call(self.inner.create_task(queue, task, res_view))
+            .await
+    }
+
+    async fn get_task(
+        &self,
+        name: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+    ) -> Result<Task, NimbusError> {
+        self.breaker
+            .call(self.inner.get_task(name, res_view, redact))
+            .await
+    }
+
+    async fn list_tasks(
+        &self,
+        queue: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+        page_token: Option<String>,
+    ) -> Result<(Vec<Task>, Option<String>), NimbusError> {
+        self.breaker
+            .call(self.inner.list_tasks(queue, res_view, redact, page_token))
+            .await
+    }
+
+    async fn delete_task(&self, name: &str) -> Result<(), NimbusError> {
+        self.breaker.call(self.inner.delete_task(name)).await
+    }
+
+    async fn wait_for_task_completion(
+        &self,
+        task_name: &str,
+        timeout: Duration,
+    ) -> Result<TaskOutcome, NimbusError> {
+        self.breaker
+            .call(self.inner.wait_for_task_completion(task_name, timeout))
+            .await
+    }
+
+    async fn list_locations(
+        &self,
+        project: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        self.breaker
+            .call(self.inner.list_locations(project, limits))
+            .await
+    }
+
+    async fn list_queues(
+        &self,
+        project: &str,
+        location: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<QueueInfo>, NimbusError> {
+        self.breaker
+            .call(self.inner.list_queues(project, location, limits))
+            .await
+    }
+
+    async fn export_queue_config(
+        &self,
+        queue: &QueuePath,
+    ) -> Result<QueueConfigSnapshot, NimbusError> {
+        self.breaker
+            .call(self.inner.export_queue_config(queue))
+            .await
+    }
+
+    async fn create_queue(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.create_queue(queue, config))
+            .await
+    }
+
+    async fn update_queue_config(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+        fields: &[&str],
+    ) -> Result<(), NimbusError> {
+        self.breaker
+            .call(self.inner.update_queue_config(queue, config, fields))
+            .await
+    }
+
+    async fn test_permissions(
+        &self,
+        queue: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        self.breaker
+            .call(self.inner.test_permissions(queue, permissions))
+            .await
+    }
+}
@@ -0,0 +1,175 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::NimbusError;
+
+/// Budget shared by every sub-call of a composite operation, e.g.
+/// [`crate::StorageHelper::objects_metadata`]
+///
+/// A composite operation checks the context before each sub-call and races every sub-call
+/// against it, so the whole operation stops at the deadline instead of each call getting its own timeout.
+/// It then fails with [`NimbusError::DeadlineExceeded`] (or [`NimbusError::Cancelled`]) telling how many items
+/// were done and how many are left.
+///
+/// The default context has no deadline and can't be cancelled. Cloning is cheap, clones share the
+/// cancellation token.
+///
+/// ```ignore
+/// let ctx = OpContext::new().timeout(Duration::from_secs(30));
+/// let metadata = storage.objects_metadata("bucket", &keys, 16, &ctx).await?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OpContext {
+    deadline: Option<Instant>,
+    cancel: Option<CancellationToken>,
+    idempotency_scope: Option<Arc<str>>,
+}
+
+impl OpContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// deadline `timeout` from now
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// scope of the idempotency keys derived with [`OpContext::idempotency_key`],
+    /// reuse it when retrying the operation
+    pub fn idempotency_scope(mut self, scope: impl Into<String>) -> Self {
+        self.idempotency_scope = Some(Arc::from(scope.into()));
+        self
+    }
+
+    pub fn deadline_at(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// budget left before the deadline, `None` without deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|t| t.is_cancelled())
+    }
+
+    /// whether sub-calls must stop: the deadline passed or the operation was cancelled
+    pub fn is_done(&self) -> bool {
+        self.is_cancelled() || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// idempotency key of `item` within the scope, `None` without scope
+    pub fn idempotency_key(&self, item: &str) -> Option<String> {
+        self.idempotency_scope
+            .as_deref()
+            .map(|scope| format!("{scope}/{item}"))
+    }
+
+    /// run a sub-call within the budget, `None` if the context is done before it completes
+    pub(crate) async fn run<F: Future>(&self, call: F) -> Option<F::Output> {
+        if self.is_done() {
+            return None;
+        }
+
+        let done = async {
+            let cancelled = async {
+                match &self.cancel {
+                    Some(token) => token.cancelled().await,
+                    None => future::pending().await,
+                }
+            };
+            let expired = async {
+                match self.deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => future::pending().await,
+                }
+            };
+            future::select(Box::pin(cancelled), Box::pin(expired)).await;
+        };
+
+        match future::select(Box::pin(call), Box::pin(done)).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// error of an operation stopped by the context after `completed` items, with `remaining` left
+    pub(crate) fn stopped(&self, completed: usize, remaining: usize) -> NimbusError {
+        if self.is_cancelled() {
+            NimbusError::Cancelled {
+                completed,
+                remaining,
+            }
+        } else {
+            NimbusError::DeadlineExceeded {
+                completed,
+                remaining,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+
+    #[tokio::test(start_paused = true)]
+    async fn op_context_test() {
+        let ctx = OpContext::default();
+        assert_eq!(ctx.remaining(), None);
+        assert!(!ctx.is_done());
+        assert_eq!(ctx.run(async { 1 }).await, Some(1));
+        assert_eq!(ctx.idempotency_key("a"), None);
+
+        let ctx = OpContext::new()
+            .timeout(Duration::from_secs(10))
+            .idempotency_scope("sync-1");
+        assert_eq!(ctx.remaining(), Some(Duration::from_secs(10)));
+        assert_eq!(ctx.idempotency_key("a").as_deref(), Some("sync-1/a"));
+
+        // a sub-call outliving the budget is dropped at the deadline
+        let start = Instant::now();
+        let slow = tokio::time::sleep(Duration::from_secs(60));
+        assert_eq!(ctx.run(slow).await, None);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert!(ctx.is_done());
+        assert_eq!(ctx.remaining(), Some(Duration::ZERO));
+
+        let err = ctx.stopped(3, 7);
+        assert!(matches!(
+            err,
+            NimbusError::DeadlineExceeded {
+                completed: 3,
+                remaining: 7
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::Timeout);
+
+        let token = CancellationToken::new();
+        let ctx = OpContext::new().cancel(token.clone());
+        let clone = ctx.clone();
+        token.cancel();
+        assert!(clone.is_done());
+        assert_eq!(clone.run(async { 1 }).await, None);
+        assert_eq!(clone.stopped(0, 1).code(), ErrorCode::Cancelled);
+    }
+}
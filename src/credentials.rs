@@ -0,0 +1,445 @@
+//! Health of the credentials of the GCP clients
+//!
+//! An expired or revoked service account key first shows up as 401s from every client, which look like
+//! an outage of each provider. A [`CredentialMonitor`] sees the token fetches of the clients built with it
+//! and turns them into a single signal: its [`status`](CredentialMonitor::status), and the
+//! [`CredentialEvent`]s reported to [`on_event`](CredentialMonitor::on_event) when fetches start failing,
+//! recover, or fail while the current token is about to expire.
+//!
+//! One monitor is meant to be shared by all the clients using the same credentials:
+//!
+//! ```ignore
+//! let monitor = CredentialMonitor::new().on_event(|event| alerting.page_if_needed(event));
+//! let transport = TransportConfig::default();
+//! let secrets = SecretManager::new_with_monitor(auth.clone(), &transport, &monitor).await;
+//! let tasks = CloudTasks::new_with_monitor(auth, &transport, &monitor).await;
+//! let storage = Client::new(monitor.apply(ClientConfig::default().with_auth().await?));
+//! ```
+//!
+//! The expiry of the token is only known for the clients built from an [`Authenticator`], the storage client
+//! hands out the token alone.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+use google_apis_common::GetToken;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::client::ClientConfig;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+use hyper::{client::connect::Connection, service::Service, Uri};
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+use yup_oauth2::authenticator::Authenticator;
+
+/// Expiry reported as imminent this long before, unless set with [`CredentialMonitor::expiry_warning`]
+pub const DEFAULT_EXPIRY_WARNING: Duration = Duration::from_secs(5 * 60);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Token fetches seen by a [`CredentialMonitor`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CredentialStatus {
+    /// last successful fetch
+    pub last_refresh: Option<DateTime<Utc>>,
+    /// expiry of the last token fetched, if known
+    pub expires_at: Option<DateTime<Utc>>,
+    /// failed fetches since the last successful one
+    pub consecutive_failures: u32,
+    /// why the last fetch failed, while failing
+    pub last_error: Option<String>,
+}
+
+impl CredentialStatus {
+    pub fn is_failing(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+
+    /// time before the token expires, zero once expired, `None` if unknown
+    pub fn expires_in(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.expires_at
+            .map(|at| (at - now).to_std().unwrap_or(Duration::ZERO))
+    }
+}
+
+/// What a [`CredentialMonitor`] saw, reported to the callback of [`CredentialMonitor::on_event`]
+#[derive(Debug)]
+pub enum CredentialEvent<'a> {
+    /// a fetch failed, every failure is reported
+    RefreshFailed {
+        consecutive_failures: u32,
+        error: &'a str,
+    },
+    /// a fetch succeeded after failing
+    Recovered { after_failures: u32 },
+    /// fetches fail and the token expires within the warning, reported once per token
+    ExpiryImminent {
+        expires_at: DateTime<Utc>,
+        consecutive_failures: u32,
+    },
+}
+
+type Observer = dyn Fn(CredentialEvent<'_>) + Send + Sync;
+
+#[derive(Default)]
+struct State {
+    status: CredentialStatus,
+    /// expiry already reported as imminent
+    warned: Option<DateTime<Utc>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    expiry_warning: Duration,
+    observer: Option<Arc<Observer>>,
+}
+
+/// Sees the token fetches of the clients built with it, see the [module](self) docs
+///
+/// Clones share their status.
+#[derive(Clone)]
+pub struct CredentialMonitor {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for CredentialMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CredentialMonitor")
+            .field("status", &self.status())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for CredentialMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CredentialMonitor {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                expiry_warning: DEFAULT_EXPIRY_WARNING,
+                observer: None,
+            }),
+        }
+    }
+
+    /// how long before the expiry of the token failing fetches are reported as [`CredentialEvent::ExpiryImminent`],
+    /// [`DEFAULT_EXPIRY_WARNING`] by default
+    pub fn expiry_warning(mut self, warning: Duration) -> Self {
+        self.configure().expiry_warning = warning;
+        self
+    }
+
+    /// report failing and recovering fetches to `observer`, e.g. to page on them
+    pub fn on_event(
+        mut self,
+        observer: impl Fn(CredentialEvent<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.configure().observer = Some(Arc::new(observer));
+        self
+    }
+
+    fn configure(&mut self) -> &mut Shared {
+        Arc::get_mut(&mut self.shared).expect("monitor is configured before being cloned")
+    }
+
+    pub fn status(&self) -> CredentialStatus {
+        self.shared.state.lock().unwrap().status.clone()
+    }
+
+    /// token source of the generated API clients (secret manager, tasks) reporting to this monitor
+    #[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+    pub fn wrap<S>(&self, authenticator: Authenticator<S>) -> MonitoredAuthenticator<S> {
+        MonitoredAuthenticator {
+            inner: authenticator,
+            monitor: self.clone(),
+        }
+    }
+
+    /// `config` of the storage client with its token source reporting to this monitor,
+    /// anonymous configs are returned as is
+    #[cfg(feature = "gcp-storage")]
+    pub fn apply(&self, mut config: ClientConfig) -> ClientConfig {
+        config.token_source_provider = config.token_source_provider.map(|inner| {
+            Box::new(MonitoredProvider {
+                inner,
+                monitor: self.clone(),
+            }) as Box<dyn TokenSourceProvider>
+        });
+        config
+    }
+
+    fn observe(&self, event: CredentialEvent<'_>) {
+        if let Some(observer) = &self.shared.observer {
+            observer(event);
+        }
+    }
+
+    /// `expires_at` of `None` keeps the known expiry, the storage client doesn't tell it
+    fn record_success(&self, expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) {
+        let after_failures = {
+            let mut state = self.shared.state.lock().unwrap();
+            let status = &mut state.status;
+            status.last_refresh = Some(now);
+            if expires_at.is_some() {
+                status.expires_at = expires_at;
+            }
+            status.last_error = None;
+            std::mem::take(&mut status.consecutive_failures)
+        };
+        if after_failures > 0 {
+            self.observe(CredentialEvent::Recovered { after_failures });
+        }
+    }
+
+    fn record_failure(&self, error: &str, now: DateTime<Utc>) {
+        let (consecutive_failures, imminent) = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.status.consecutive_failures += 1;
+            state.status.last_error = Some(error.to_owned());
+
+            let imminent = state.status.expires_at.filter(|at| {
+                state.warned != Some(*at)
+                    && state
+                        .status
+                        .expires_in(now)
+                        .is_some_and(|left| left <= self.shared.expiry_warning)
+            });
+            if imminent.is_some() {
+                state.warned = imminent;
+            }
+            (state.status.consecutive_failures, imminent)
+        };
+
+        self.observe(CredentialEvent::RefreshFailed {
+            consecutive_failures,
+            error,
+        });
+        if let Some(expires_at) = imminent {
+            self.observe(CredentialEvent::ExpiryImminent {
+                expires_at,
+                consecutive_failures,
+            });
+        }
+    }
+
+    fn record<T>(&self, result: &Result<T, BoxError>, expires_at: Option<DateTime<Utc>>) {
+        match result {
+            Ok(_) => self.record_success(expires_at, Utc::now()),
+            Err(e) => self.record_failure(&e.to_string(), Utc::now()),
+        }
+    }
+}
+
+/// [`Authenticator`] reporting its token fetches to a [`CredentialMonitor`], see [`CredentialMonitor::wrap`]
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+#[derive(Clone)]
+pub struct MonitoredAuthenticator<S> {
+    inner: Authenticator<S>,
+    monitor: CredentialMonitor,
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+impl<S> GetToken for MonitoredAuthenticator<S>
+where
+    S: Service<Uri> + Clone + Send + Sync + 'static,
+    S::Response: Connection + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    S::Future: Send + Unpin + 'static,
+    S::Error: Into<BoxError>,
+{
+    fn get_token<'a>(
+        &'a self,
+        scopes: &'a [&str],
+    ) -> std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Option<String>, BoxError>> + Send + 'a>,
+    > {
+        Box::pin(async move {
+            let token = self.inner.token(scopes).await.map_err(BoxError::from);
+            let expires_at = token
+                .as_ref()
+                .ok()
+                .and_then(|t| t.expiration_time())
+                .and_then(|at| crate::time::from_unix(at.unix_timestamp(), at.nanosecond()).ok());
+            self.monitor.record(&token, expires_at);
+            token.map(|t| t.token().map(str::to_owned))
+        })
+    }
+}
+
+#[cfg(feature = "gcp-storage")]
+#[derive(Debug)]
+struct MonitoredProvider {
+    inner: Box<dyn TokenSourceProvider>,
+    monitor: CredentialMonitor,
+}
+
+#[cfg(feature = "gcp-storage")]
+impl TokenSourceProvider for MonitoredProvider {
+    fn token_source(&self) -> Arc<dyn TokenSource> {
+        Arc::new(MonitoredSource {
+            inner: self.inner.token_source(),
+            monitor: self.monitor.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "gcp-storage")]
+#[derive(Debug)]
+struct MonitoredSource {
+    inner: Arc<dyn TokenSource>,
+    monitor: CredentialMonitor,
+}
+
+#[cfg(feature = "gcp-storage")]
+#[async_trait::async_trait]
+impl TokenSource for MonitoredSource {
+    async fn token(&self) -> Result<String, BoxError> {
+        let token = self.inner.token().await;
+        self.monitor.record(&token, None);
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "gcp-storage")]
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    /// token source failing while `fail` is set
+    #[cfg(feature = "gcp-storage")]
+    #[derive(Debug, Default)]
+    struct FlakySource {
+        fail: Arc<AtomicBool>,
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    #[async_trait::async_trait]
+    impl TokenSource for FlakySource {
+        async fn token(&self) -> Result<String, BoxError> {
+            if self.fail.load(Ordering::SeqCst) {
+                Err("invalid_grant: account not found".into())
+            } else {
+                Ok("Bearer token".to_owned())
+            }
+        }
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    #[derive(Debug)]
+    struct FlakyProvider(Arc<FlakySource>);
+
+    #[cfg(feature = "gcp-storage")]
+    impl TokenSourceProvider for FlakyProvider {
+        fn token_source(&self) -> Arc<dyn TokenSource> {
+            self.0.clone()
+        }
+    }
+
+    fn events(monitor: CredentialMonitor) -> (CredentialMonitor, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let observed = seen.clone();
+        let monitor = monitor.on_event(move |event| {
+            let event = match event {
+                CredentialEvent::RefreshFailed {
+                    consecutive_failures,
+                    ..
+                } => format!("failed {consecutive_failures}"),
+                CredentialEvent::Recovered { after_failures } => {
+                    format!("recovered {after_failures}")
+                }
+                CredentialEvent::ExpiryImminent { .. } => "imminent".to_owned(),
+            };
+            observed.lock().unwrap().push(event);
+        });
+        (monitor, seen)
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    #[tokio::test]
+    async fn token_source_test() {
+        let (monitor, seen) = events(CredentialMonitor::new());
+        let source = Arc::new(FlakySource::default());
+        let config = ClientConfig {
+            token_source_provider: Some(Box::new(FlakyProvider(source.clone()))),
+            ..Default::default()
+        };
+        let tokens = monitor
+            .apply(config)
+            .token_source_provider
+            .unwrap()
+            .token_source();
+
+        tokens.token().await.unwrap();
+        let status = monitor.status();
+        assert!(status.last_refresh.is_some() && !status.is_failing());
+
+        source.fail.store(true, Ordering::SeqCst);
+        tokens.token().await.unwrap_err();
+        tokens.token().await.unwrap_err();
+        let status = monitor.status();
+        assert_eq!(status.consecutive_failures, 2);
+        assert!(status.last_error.unwrap().contains("invalid_grant"));
+
+        source.fail.store(false, Ordering::SeqCst);
+        tokens.token().await.unwrap();
+        assert!(!monitor.status().is_failing());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["failed 1", "failed 2", "recovered 2"]
+        );
+    }
+
+    #[test]
+    fn expiry_imminent_test() {
+        let (monitor, seen) =
+            events(CredentialMonitor::new().expiry_warning(Duration::from_secs(60)));
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::minutes(10);
+        monitor.record_success(Some(expires_at), now);
+        // the storage client doesn't tell the expiry, the known one is kept
+        monitor.record_success(None, now);
+        assert_eq!(monitor.status().expires_at, Some(expires_at));
+        assert_eq!(
+            monitor.status().expires_in(now),
+            Some(Duration::from_secs(600))
+        );
+
+        // failing well before the expiry
+        monitor.record_failure("unavailable", now);
+        // then within the warning, reported once
+        let later = expires_at - chrono::Duration::seconds(30);
+        monitor.record_failure("unavailable", later);
+        monitor.record_failure("unavailable", later);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["failed 1", "failed 2", "imminent", "failed 3"]
+        );
+        assert_eq!(
+            monitor
+                .status()
+                .expires_in(expires_at + chrono::Duration::seconds(1)),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    #[test]
+    fn anonymous_config_test() {
+        let config = ClientConfig::default().anonymous();
+        assert!(CredentialMonitor::new()
+            .apply(config)
+            .token_source_provider
+            .is_none());
+    }
+}
@@ -0,0 +1,693 @@
+//! Environment variables read by nimbus and the SDKs under it, declared in one registry
+//!
+//! Every variable nimbus reads is a [`Var`], read through [`get`] and described by its [`EnvVarSpec`].
+//! [`validate`] checks the environment against the registry at startup: required variables that are
+//! missing, values that don't parse, and `NIMBUS_` variables nimbus doesn't know, most likely typos
+//! that would otherwise silently leave the default in place.
+//!
+//! ```ignore
+//! for issue in nimbus::env::validate(Mode::current()) {
+//!     eprintln!("{issue}");
+//! }
+//! let transport = TransportConfig::from_env()?;
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::ErrorCode;
+
+/// Prefix of the variables of nimbus itself, the unknown ones are reported by [`validate`]
+pub const NIMBUS_PREFIX: &str = "NIMBUS_";
+
+/// How the process runs, deciding which variables are required
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Gcp,
+    Aws,
+    /// against local emulators of the GCP services
+    Emulator,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gcp => "gcp",
+            Self::Aws => "aws",
+            Self::Emulator => "emulator",
+        }
+    }
+
+    /// mode named by `NIMBUS_PROVIDER`, the provider of the enabled features without it
+    /// (AWS when features of both are enabled); a malformed value is reported by [`validate`]
+    pub fn current() -> Self {
+        Self::from_vars(&Vars::from_process())
+    }
+
+    pub(crate) fn from_vars(vars: &Vars) -> Self {
+        match vars.get(Var::NimbusProvider).as_deref() {
+            Some("gcp") => Self::Gcp,
+            Some("aws") => Self::Aws,
+            Some("emulator") => Self::Emulator,
+            _ if cfg!(any(feature = "aws-secrets", feature = "aws-storage")) => Self::Aws,
+            _ if cfg!(any(
+                feature = "gcp-secrets",
+                feature = "gcp-storage",
+                feature = "gcp-tasks"
+            )) =>
+            {
+                Self::Gcp
+            }
+            _ => Self::Aws,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a variable holds, checked by [`validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    /// any non-empty value
+    Text,
+    /// path of an existing file
+    File,
+    /// `http` or `https` URL
+    Url,
+    /// `host:port`, optionally with an `http` or `https` scheme
+    HostPort,
+    /// duration as `250ms`, `30s`, `5m` or `1h`
+    Duration,
+    /// non-negative integer
+    Integer,
+    /// one of the given values
+    OneOf(&'static [&'static str]),
+}
+
+/// Variable of the registry, see [`describe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Var {
+    NimbusProvider,
+    NimbusConnectTimeout,
+    NimbusPoolIdleTimeout,
+    NimbusPoolMaxIdlePerHost,
+    GoogleApplicationCredentials,
+    GoogleCloudProject,
+    CloudTasksLocation,
+    StorageEmulatorHost,
+    CloudTasksEmulatorHost,
+    AwsRegion,
+    AwsProfile,
+    AwsEndpointUrl,
+}
+
+impl Var {
+    /// every variable of the registry
+    pub const ALL: [Var; 12] = [
+        Var::NimbusProvider,
+        Var::NimbusConnectTimeout,
+        Var::NimbusPoolIdleTimeout,
+        Var::NimbusPoolMaxIdlePerHost,
+        Var::GoogleApplicationCredentials,
+        Var::GoogleCloudProject,
+        Var::CloudTasksLocation,
+        Var::StorageEmulatorHost,
+        Var::CloudTasksEmulatorHost,
+        Var::AwsRegion,
+        Var::AwsProfile,
+        Var::AwsEndpointUrl,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        self.spec().name
+    }
+
+    pub fn spec(&self) -> EnvVarSpec {
+        use ValueKind::*;
+
+        let (name, purpose, kind, required_in, default): (_, _, _, &'static [Mode], _) = match self
+        {
+            Var::NimbusProvider => (
+                "NIMBUS_PROVIDER",
+                "provider the process runs against, see `env::Mode::current`",
+                OneOf(&["gcp", "aws", "emulator"]),
+                &[],
+                Some("the provider of the enabled features"),
+            ),
+            Var::NimbusConnectTimeout => (
+                "NIMBUS_CONNECT_TIMEOUT",
+                "time allowed to connect, `TransportConfig::from_env`",
+                Duration,
+                &[],
+                None,
+            ),
+            Var::NimbusPoolIdleTimeout => (
+                "NIMBUS_POOL_IDLE_TIMEOUT",
+                "how long idle connections are kept, `TransportConfig::from_env`",
+                Duration,
+                &[],
+                Some("90s"),
+            ),
+            Var::NimbusPoolMaxIdlePerHost => (
+                "NIMBUS_POOL_MAX_IDLE_PER_HOST",
+                "idle connections kept per host, `TransportConfig::from_env`",
+                Integer,
+                &[],
+                None,
+            ),
+            Var::GoogleApplicationCredentials => (
+                "GOOGLE_APPLICATION_CREDENTIALS",
+                "service account key file of the GCP clients",
+                File,
+                &[],
+                Some("the credentials of the metadata server or of gcloud"),
+            ),
+            Var::GoogleCloudProject => (
+                "GOOGLE_CLOUD_PROJECT",
+                "project of the GCP clients",
+                Text,
+                &[],
+                Some("the project of the credentials"),
+            ),
+            Var::CloudTasksLocation => (
+                "CLOUD_TASKS_LOCATION",
+                "location of the Cloud Tasks queues, e.g. `europe-west1`",
+                Text,
+                &[Mode::Emulator],
+                None,
+            ),
+            Var::StorageEmulatorHost => (
+                "STORAGE_EMULATOR_HOST",
+                "GCS emulator, e.g. `http://localhost:4443`",
+                Url,
+                &[Mode::Emulator],
+                None,
+            ),
+            Var::CloudTasksEmulatorHost => (
+                "CLOUD_TASKS_EMULATOR_HOST",
+                "Cloud Tasks emulator, e.g. `localhost:8123`",
+                HostPort,
+                &[],
+                None,
+            ),
+            Var::AwsRegion => (
+                "AWS_REGION",
+                "region of the AWS clients",
+                Text,
+                &[],
+                Some("the region of the profile or of the instance"),
+            ),
+            Var::AwsProfile => (
+                "AWS_PROFILE",
+                "profile of the shared AWS config and credentials files",
+                Text,
+                &[],
+                Some("default"),
+            ),
+            Var::AwsEndpointUrl => (
+                "AWS_ENDPOINT_URL",
+                "endpoint of the AWS clients, e.g. a local S3",
+                Url,
+                &[],
+                None,
+            ),
+        };
+        EnvVarSpec {
+            name,
+            purpose,
+            kind,
+            required_in,
+            default,
+        }
+    }
+}
+
+/// Description of a variable of the registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVarSpec {
+    pub name: &'static str,
+    pub purpose: &'static str,
+    pub kind: ValueKind,
+    /// modes failing [`validate`] without the variable
+    pub required_in: &'static [Mode],
+    /// what applies without the variable, `None` when nothing does
+    pub default: Option<&'static str>,
+}
+
+impl EnvVarSpec {
+    pub fn is_required_in(&self, mode: Mode) -> bool {
+        self.required_in.contains(&mode)
+    }
+}
+
+/// every variable of the registry, in the order of [`Var::ALL`]
+pub fn describe() -> Vec<EnvVarSpec> {
+    Var::ALL.iter().map(Var::spec).collect()
+}
+
+/// value of `var`, `None` when unset or blank
+pub fn get(var: Var) -> Option<String> {
+    Vars::from_process().get(var)
+}
+
+/// value of a [`ValueKind::Duration`] variable
+pub fn get_duration(var: Var) -> Result<Option<Duration>, EnvIssue> {
+    Vars::from_process().duration(var)
+}
+
+/// Problem of the environment found by [`validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvIssue {
+    /// a `NIMBUS_` variable nimbus doesn't read, with the known one it likely misspells
+    Unknown {
+        name: String,
+        suggestion: Option<&'static str>,
+    },
+    /// a variable required in the mode isn't set
+    Missing { name: &'static str, mode: Mode },
+    /// a variable is set to a value of the wrong kind
+    Malformed {
+        name: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl EnvIssue {
+    /// name of the variable the issue is about
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Unknown { name, .. } => name,
+            Self::Missing { name, .. } | Self::Malformed { name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for EnvIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown {
+                name,
+                suggestion: Some(suggestion),
+            } => write!(
+                f,
+                "{name} is not a nimbus variable, did you mean {suggestion}?"
+            ),
+            Self::Unknown { name, .. } => write!(f, "{name} is not a nimbus variable"),
+            Self::Missing { name, mode } => write!(f, "{name} is required in {mode} mode"),
+            Self::Malformed {
+                name,
+                value,
+                reason,
+            } => write!(f, "{name}={value:?} is invalid: {reason}"),
+        }
+    }
+}
+
+/// Misconfigured environment, with every issue found
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid environment: {}", issues.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+pub struct EnvError {
+    pub issues: Vec<EnvIssue>,
+}
+
+impl EnvError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidInput
+    }
+}
+
+/// issues of the environment of the process in `mode`, none when it is fine
+pub fn validate(mode: Mode) -> Vec<EnvIssue> {
+    Vars::from_process().validate(mode)
+}
+
+/// `Ok` when the environment has no issue in `mode`
+pub fn check(mode: Mode) -> Result<(), EnvError> {
+    let issues = validate(mode);
+    match issues.is_empty() {
+        true => Ok(()),
+        false => Err(EnvError { issues }),
+    }
+}
+
+/// `250ms`, `30s`, `5m` or `1h`
+pub(crate) fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let error = || format!("invalid duration {text:?}, expected e.g. \"250ms\" or \"30s\"");
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| error())?;
+
+    let seconds = match unit.trim() {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(error()),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(error)
+}
+
+/// why `value` isn't a `kind`, `None` when it is
+fn malformed(kind: ValueKind, value: &str) -> Option<String> {
+    match kind {
+        ValueKind::Text => None,
+        ValueKind::File => match std::fs::metadata(value) {
+            Ok(meta) if meta.is_file() => None,
+            Ok(_) => Some("not a file".to_owned()),
+            Err(e) => Some(e.to_string()),
+        },
+        ValueKind::Url => {
+            let rest = value
+                .strip_prefix("http://")
+                .or_else(|| value.strip_prefix("https://"));
+            match rest.map(|rest| rest.split(['/', '?', '#']).next().unwrap_or_default()) {
+                None => Some("expected an http:// or https:// URL".to_owned()),
+                Some(authority) => host_port_error(authority, false),
+            }
+        }
+        ValueKind::HostPort => {
+            let authority = value
+                .strip_prefix("http://")
+                .or_else(|| value.strip_prefix("https://"))
+                .unwrap_or(value);
+            host_port_error(authority.trim_end_matches('/'), true)
+        }
+        ValueKind::Duration => parse_duration(value).err(),
+        ValueKind::Integer => value
+            .parse::<usize>()
+            .err()
+            .map(|_| "expected a non-negative integer".to_owned()),
+        ValueKind::OneOf(values) => {
+            (!values.contains(&value)).then(|| format!("expected one of {}", values.join(", ")))
+        }
+    }
+}
+
+fn host_port_error(authority: &str, port_required: bool) -> Option<String> {
+    let (host, port) = match authority.rsplit_once(':') {
+        // an IPv6 address without a port
+        Some((_, port)) if port.ends_with(']') => (authority, None),
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Some("missing host".to_owned());
+    }
+    match port {
+        Some(port) if port.parse::<u16>().is_err() => Some(format!("invalid port {port:?}")),
+        None if port_required => Some("expected host:port".to_owned()),
+        _ => None,
+    }
+}
+
+/// Levenshtein distance, for the suggestions of unknown variables
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Snapshot of the environment
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Vars(BTreeMap<String, String>);
+
+impl Vars {
+    pub(crate) fn from_process() -> Self {
+        Self(
+            std::env::vars_os()
+                .filter_map(|(name, value)| {
+                    Some((name.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+        )
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new<'a>(vars: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        Self(
+            vars.into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn get(&self, var: Var) -> Option<String> {
+        self.0
+            .get(var.name())
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(str::to_owned)
+    }
+
+    pub(crate) fn duration(&self, var: Var) -> Result<Option<Duration>, EnvIssue> {
+        debug_assert_eq!(var.spec().kind, ValueKind::Duration);
+        self.parsed(var, parse_duration)
+    }
+
+    pub(crate) fn integer(&self, var: Var) -> Result<Option<usize>, EnvIssue> {
+        debug_assert_eq!(var.spec().kind, ValueKind::Integer);
+        self.parsed(var, |value| value.parse().map_err(|e| format!("{e}")))
+    }
+
+    fn parsed<T>(
+        &self,
+        var: Var,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, EnvIssue> {
+        self.get(var)
+            .map(|value| {
+                parse(&value).map_err(|reason| EnvIssue::Malformed {
+                    name: var.name(),
+                    value,
+                    reason,
+                })
+            })
+            .transpose()
+    }
+
+    pub(crate) fn validate(&self, mode: Mode) -> Vec<EnvIssue> {
+        let mut issues = vec![];
+        for var in Var::ALL {
+            let spec = var.spec();
+            match self.get(var) {
+                None if spec.is_required_in(mode) => issues.push(EnvIssue::Missing {
+                    name: spec.name,
+                    mode,
+                }),
+                None => {}
+                Some(value) => {
+                    if let Some(reason) = malformed(spec.kind, &value) {
+                        issues.push(EnvIssue::Malformed {
+                            name: spec.name,
+                            value,
+                            reason,
+                        });
+                    }
+                }
+            }
+        }
+
+        let known: Vec<&'static str> = Var::ALL.iter().map(Var::name).collect();
+        for name in self.0.keys() {
+            if name.starts_with(NIMBUS_PREFIX) && !known.contains(&name.as_str()) {
+                let suggestion = known
+                    .iter()
+                    .filter(|known| known.starts_with(NIMBUS_PREFIX))
+                    .map(|known| (distance(name, known), *known))
+                    .filter(|(distance, _)| *distance <= 3)
+                    .min()
+                    .map(|(_, known)| known);
+                issues.push(EnvIssue::Unknown {
+                    name: name.clone(),
+                    suggestion,
+                });
+            }
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_test() {
+        let specs = describe();
+        assert_eq!(specs.len(), Var::ALL.len());
+        for (var, spec) in Var::ALL.iter().zip(&specs) {
+            assert_eq!(var.name(), spec.name);
+            assert!(!spec.purpose.is_empty());
+            // a required variable has no default
+            assert!(
+                spec.required_in.is_empty() || spec.default.is_none(),
+                "{}",
+                spec.name
+            );
+        }
+        let mut names: Vec<_> = specs.iter().map(|s| s.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), specs.len());
+        assert!(Var::StorageEmulatorHost
+            .spec()
+            .is_required_in(Mode::Emulator));
+        assert!(!Var::StorageEmulatorHost.spec().is_required_in(Mode::Gcp));
+    }
+
+    #[test]
+    fn validate_test() {
+        let vars = Vars::new([
+            ("NIMBUS_PROVIDER", "gcp"),
+            ("NIMBUS_CONECT_TIMEOUT", "5s"),
+            ("NIMBUS_POOL_IDLE_TIMEOUT", "5 minutes"),
+            ("NIMBUS_SOMETHING_ELSE", "1"),
+            ("STORAGE_EMULATOR_HOST", "localhost:4443"),
+            ("CLOUD_TASKS_EMULATOR_HOST", "localhost:8123"),
+            ("GOOGLE_APPLICATION_CREDENTIALS", "/nonexistent/key.json"),
+            ("CLOUD_TASKS_LOCATION", "  "),
+            ("PATH", "/usr/bin"),
+        ]);
+        assert_eq!(Mode::from_vars(&vars), Mode::Gcp);
+
+        let issues = vars.validate(Mode::Gcp);
+        assert_eq!(issues.len(), 5, "{issues:?}");
+        let names: Vec<_> = issues.iter().map(EnvIssue::name).collect();
+        assert_eq!(
+            names,
+            [
+                "NIMBUS_POOL_IDLE_TIMEOUT",
+                "GOOGLE_APPLICATION_CREDENTIALS",
+                "STORAGE_EMULATOR_HOST",
+                "NIMBUS_CONECT_TIMEOUT",
+                "NIMBUS_SOMETHING_ELSE",
+            ]
+        );
+        assert_eq!(
+            issues[2],
+            EnvIssue::Malformed {
+                name: "STORAGE_EMULATOR_HOST",
+                value: "localhost:4443".to_owned(),
+                reason: "expected an http:// or https:// URL".to_owned(),
+            }
+        );
+        assert_eq!(
+            issues[3],
+            EnvIssue::Unknown {
+                name: "NIMBUS_CONECT_TIMEOUT".to_owned(),
+                suggestion: Some("NIMBUS_CONNECT_TIMEOUT"),
+            }
+        );
+        assert_eq!(
+            issues[3].to_string(),
+            "NIMBUS_CONECT_TIMEOUT is not a nimbus variable, did you mean NIMBUS_CONNECT_TIMEOUT?"
+        );
+        assert_eq!(
+            issues[4],
+            EnvIssue::Unknown {
+                name: "NIMBUS_SOMETHING_ELSE".to_owned(),
+                suggestion: None,
+            }
+        );
+        // blank is unset
+        let missing = vars.validate(Mode::Emulator);
+        assert!(missing.contains(&EnvIssue::Missing {
+            name: "CLOUD_TASKS_LOCATION",
+            mode: Mode::Emulator,
+        }));
+        assert_eq!(
+            missing[0].to_string(),
+            "NIMBUS_POOL_IDLE_TIMEOUT=\"5 minutes\" is invalid: invalid duration \"5 minutes\", expected e.g. \"250ms\" or \"30s\""
+        );
+
+        let fine = Vars::new([
+            ("NIMBUS_PROVIDER", "aws"),
+            ("AWS_REGION", "eu-west-1"),
+            ("AWS_ENDPOINT_URL", "http://127.0.0.1:9000/"),
+            ("NIMBUS_CONNECT_TIMEOUT", "2s"),
+        ]);
+        assert_eq!(Mode::from_vars(&fine), Mode::Aws);
+        assert_eq!(fine.validate(Mode::Aws), []);
+        assert_eq!(
+            fine.duration(Var::NimbusConnectTimeout),
+            Ok(Some(Duration::from_secs(2)))
+        );
+        assert_eq!(fine.duration(Var::NimbusPoolIdleTimeout), Ok(None));
+        assert_eq!(
+            fine.validate(Mode::Emulator)
+                .iter()
+                .map(EnvIssue::name)
+                .collect::<Vec<_>>(),
+            ["CLOUD_TASKS_LOCATION", "STORAGE_EMULATOR_HOST"]
+        );
+    }
+
+    #[test]
+    fn value_kinds_test() {
+        use ValueKind::*;
+
+        for (kind, value, valid) in [
+            (Url, "http://localhost:4443", true),
+            (Url, "https://storage.example.com/base?x=1", true),
+            (Url, "http://[::1]:9000", true),
+            (Url, "http://[::1]", true),
+            (Url, "ftp://localhost", false),
+            (Url, "http://", false),
+            (Url, "http://host:port", false),
+            (Url, "http://host:70000", false),
+            (HostPort, "localhost:8123", true),
+            (HostPort, "http://localhost:8123/", true),
+            (HostPort, "localhost", false),
+            (HostPort, ":8123", false),
+            (Duration, "250ms", true),
+            (Duration, "1h", true),
+            (Duration, "1.5s", false),
+            (Duration, "10", false),
+            (Integer, "256", true),
+            (Integer, "-1", false),
+            (OneOf(&["a", "b"]), "b", true),
+            (OneOf(&["a", "b"]), "B", false),
+            (File, "/nonexistent", false),
+            (File, "/", false),
+            (File, "Cargo.toml", true),
+            (Text, "anything", true),
+        ] {
+            assert_eq!(
+                malformed(kind, value).is_none(),
+                valid,
+                "{kind:?} {value:?}: {:?}",
+                malformed(kind, value)
+            );
+        }
+    }
+
+    #[test]
+    fn distance_test() {
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("same", "same"), 0);
+    }
+}
@@ -0,0 +1,432 @@
+use std::fmt;
+use std::future::Future;
+#[cfg(any(
+    test,
+    feature = "gcp-secrets",
+    feature = "gcp-tasks",
+    feature = "aws-secrets",
+    feature = "aws-storage"
+))]
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::{redact, NimbusError};
+
+/// Provider independent classification of a [`crate::NimbusError`]
+///
+/// Obtained with [`crate::NimbusError::code`]; use it instead of matching provider specific variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ErrorCode {
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    Unauthenticated,
+    PreconditionFailed,
+    InvalidInput,
+    RateLimited,
+    Timeout,
+    Unavailable,
+    Unsupported,
+    /// the caller cancelled the operation
+    Cancelled,
+    Internal,
+}
+
+impl ErrorCode {
+    /// classify an HTTP status returned by a provider
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            400 => Self::InvalidInput,
+            401 => Self::Unauthenticated,
+            403 => Self::PermissionDenied,
+            404 => Self::NotFound,
+            408 | 504 => Self::Timeout,
+            409 => Self::AlreadyExists,
+            412 => Self::PreconditionFailed,
+            429 => Self::RateLimited,
+            501 => Self::Unsupported,
+            500 | 502 | 503 => Self::Unavailable,
+            _ => Self::Internal,
+        }
+    }
+
+    /// whether retrying the same call may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::RateLimited | Self::Timeout | Self::Unavailable)
+    }
+
+    /// HTTP status a service should answer with when a call fails with this code
+    ///
+    /// Only codes a caller of the service can act on are passed through, everything else is a 500.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::NotFound => 404,
+            Self::PermissionDenied => 403,
+            Self::RateLimited => 429,
+            Self::Timeout => 504,
+            _ => 500,
+        }
+    }
+
+    /// short description, safe to show to a caller
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::NotFound => "resource not found",
+            Self::AlreadyExists => "resource already exists",
+            Self::PermissionDenied => "permission denied",
+            Self::Unauthenticated => "unauthenticated",
+            Self::PreconditionFailed => "precondition failed",
+            Self::InvalidInput => "invalid input",
+            Self::RateLimited => "rate limited",
+            Self::Timeout => "timed out",
+            Self::Unavailable => "service unavailable",
+            Self::Unsupported => "unsupported operation",
+            Self::Cancelled => "operation cancelled",
+            Self::Internal => "internal error",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// Summary of a [`crate::NimbusError`] without any provider detail
+/// (bucket names, keys, request ids...), safe to return to a caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ErrorSummary {
+    pub code: ErrorCode,
+    pub message: &'static str,
+    pub retryable: bool,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub retry_after_secs: Option<u64>,
+}
+
+/// Call an error is about: the method of the helper trait and the resource it was called on,
+/// see [`crate::NimbusError::context`]
+///
+/// The resource is shown as the [redaction policy](crate::redact) allows, e.g. `gs://bucket/<hash>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    operation: &'static str,
+    scheme: &'static str,
+    resource: String,
+    destination: Option<String>,
+}
+
+impl ErrorContext {
+    /// `resource` is a bucket, `bucket/key`, a secret or a queue or task name
+    pub fn new(operation: &'static str, resource: impl Into<String>) -> Self {
+        Self {
+            operation,
+            scheme: "",
+            resource: resource.into(),
+            destination: None,
+        }
+    }
+
+    /// prefix of the resources, e.g. `gs://`
+    #[cfg(any(test, feature = "gcp-storage", feature = "aws-storage"))]
+    pub(crate) fn scheme(mut self, scheme: &'static str) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    /// second resource of the call, e.g. the destination of a copy
+    #[cfg(any(test, feature = "gcp-storage", feature = "aws-storage"))]
+    pub(crate) fn to(mut self, destination: impl Into<String>) -> Self {
+        self.destination = Some(destination.into());
+        self
+    }
+
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// the resource as errors show it
+    pub fn resource(&self) -> String {
+        format!("{}{}", self.scheme, redact::resource(&self.resource))
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.operation, self.resource())?;
+        if let Some(destination) = &self.destination {
+            write!(f, " to {}{}", self.scheme, redact::resource(destination))?;
+        }
+        Ok(())
+    }
+}
+
+/// `call`, its error about the call described by `context`
+pub(crate) async fn with_context<T>(
+    context: impl FnOnce() -> ErrorContext,
+    call: impl Future<Output = Result<T, NimbusError>>,
+) -> Result<T, NimbusError> {
+    call.await.map_err(|e| e.with_context(context()))
+}
+
+/// classify an AWS SDK error from its error code, falling back to the HTTP status
+#[cfg(any(feature = "aws-secrets", feature = "aws-storage"))]
+pub(crate) fn classify_sdk_error<E>(
+    e: &aws_smithy_runtime_api::client::result::SdkError<
+        E,
+        aws_smithy_runtime_api::client::orchestrator::HttpResponse,
+    >,
+) -> (ErrorCode, Option<Duration>)
+where
+    E: aws_smithy_types::error::metadata::ProvideErrorMetadata,
+{
+    use aws_smithy_runtime_api::client::result::SdkError;
+    use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+
+    match e {
+        SdkError::TimeoutError(_) => return (ErrorCode::Timeout, None),
+        SdkError::DispatchFailure(f) if f.is_timeout() => return (ErrorCode::Timeout, None),
+        SdkError::DispatchFailure(f) if f.is_io() => return (ErrorCode::Unavailable, None),
+        _ => {}
+    }
+
+    // most AWS json APIs answer 400 for everything, the error code is more precise
+    let code = match e.code() {
+        Some("NoSuchKey" | "NoSuchBucket" | "NotFound" | "ResourceNotFoundException") => {
+            Some(ErrorCode::NotFound)
+        }
+        Some("AccessDenied" | "AccessDeniedException") => Some(ErrorCode::PermissionDenied),
+        Some("ResourceExistsException" | "BucketAlreadyExists" | "BucketAlreadyOwnedByYou") => {
+            Some(ErrorCode::AlreadyExists)
+        }
+        Some("PreconditionFailed") => Some(ErrorCode::PreconditionFailed),
+        Some("SlowDown" | "Throttling" | "ThrottlingException" | "TooManyRequestsException") => {
+            Some(ErrorCode::RateLimited)
+        }
+        Some("RequestTimeout") => Some(ErrorCode::Timeout),
+        _ => None,
+    };
+
+    let Some(raw) = e.raw_response() else {
+        return (code.unwrap_or(ErrorCode::Internal), None);
+    };
+
+    let retry_after = raw
+        .headers()
+        .get("retry-after")
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs);
+
+    (
+        code.unwrap_or_else(|| ErrorCode::from_status(raw.status().as_u16())),
+        retry_after,
+    )
+}
+
+/// classify an error of the generated google api clients (SecretManager, CloudTasks)
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+pub(crate) fn classify_api_error(e: &google_apis_common::Error) -> ErrorCode {
+    use google_apis_common::Error;
+
+    match e {
+        Error::HttpError(e) if e.is_timeout() => ErrorCode::Timeout,
+        Error::HttpError(_) => ErrorCode::Unavailable,
+        Error::BadRequest(json) => json["error"]["code"]
+            .as_u64()
+            .map_or(ErrorCode::InvalidInput, |c| {
+                ErrorCode::from_status(c as u16)
+            }),
+        Error::Failure(res) => ErrorCode::from_status(res.status().as_u16()),
+        Error::MissingAPIKey | Error::MissingToken(_) => ErrorCode::Unauthenticated,
+        Error::UploadSizeLimitExceeded(..) | Error::FieldClash(_) => ErrorCode::InvalidInput,
+        _ => ErrorCode::Internal,
+    }
+}
+
+/// `Retry-After` (in seconds) sent along a google api error, if any
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+pub(crate) fn api_retry_after(e: &google_apis_common::Error) -> Option<Duration> {
+    match e {
+        google_apis_common::Error::Failure(res) => res
+            .headers()
+            .get("retry-after")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{secret, storage, NimbusError};
+
+    use super::*;
+
+    #[test]
+    fn from_status_test() {
+        let cases = [
+            (400, ErrorCode::InvalidInput),
+            (401, ErrorCode::Unauthenticated),
+            (403, ErrorCode::PermissionDenied),
+            (404, ErrorCode::NotFound),
+            (408, ErrorCode::Timeout),
+            (409, ErrorCode::AlreadyExists),
+            (412, ErrorCode::PreconditionFailed),
+            (429, ErrorCode::RateLimited),
+            (500, ErrorCode::Unavailable),
+            (503, ErrorCode::Unavailable),
+            (504, ErrorCode::Timeout),
+            (418, ErrorCode::Internal),
+        ];
+
+        for (status, code) in cases {
+            assert_eq!(ErrorCode::from_status(status), code, "status {status}");
+        }
+    }
+
+    #[test]
+    fn retryable_codes_test() {
+        let retryable: Vec<_> = [
+            ErrorCode::NotFound,
+            ErrorCode::AlreadyExists,
+            ErrorCode::PermissionDenied,
+            ErrorCode::Unauthenticated,
+            ErrorCode::PreconditionFailed,
+            ErrorCode::InvalidInput,
+            ErrorCode::RateLimited,
+            ErrorCode::Timeout,
+            ErrorCode::Unavailable,
+            ErrorCode::Unsupported,
+            ErrorCode::Internal,
+        ]
+        .into_iter()
+        .filter(ErrorCode::is_retryable)
+        .collect();
+
+        assert_eq!(
+            retryable,
+            [
+                ErrorCode::RateLimited,
+                ErrorCode::Timeout,
+                ErrorCode::Unavailable
+            ]
+        );
+    }
+
+    #[test]
+    fn nimbus_error_code_test() {
+        let cases: Vec<(NimbusError, ErrorCode, Option<u64>)> = vec![
+            (
+                storage::Error::NotFound("k".to_owned()).into(),
+                ErrorCode::NotFound,
+                None,
+            ),
+            (
+                storage::Error::PreconditionFailed("k".to_owned()).into(),
+                ErrorCode::PreconditionFailed,
+                None,
+            ),
+            (
+                storage::Error::RateLimited {
+                    message: "SlowDown".to_owned(),
+                    retry_after: Some(Duration::from_secs(5)),
+                }
+                .into(),
+                ErrorCode::RateLimited,
+                Some(5),
+            ),
+            (
+                storage::Error::Unsupported("iam".to_owned()).into(),
+                ErrorCode::Unsupported,
+                None,
+            ),
+            (
+                storage::Error::Other("boom".to_owned()).into(),
+                ErrorCode::Internal,
+                None,
+            ),
+            (
+                secret::Error::PermissionDenied("s".to_owned()).into(),
+                ErrorCode::PermissionDenied,
+                None,
+            ),
+            (
+                secret::Error::Unavailable("s".to_owned()).into(),
+                ErrorCode::Unavailable,
+                None,
+            ),
+            (secret::Error::NoData.into(), ErrorCode::Internal, None),
+            #[cfg(feature = "gcp-tasks")]
+            (
+                crate::task::Error::CloudTasks(google_cloudtasks2::Error::BadRequest(
+                    serde_json::json!({ "error": { "code": 429 } }),
+                ))
+                .into(),
+                ErrorCode::RateLimited,
+                None,
+            ),
+            #[cfg(feature = "gcp-tasks")]
+            (
+                crate::task::Error::CloudTasks(google_cloudtasks2::Error::MissingAPIKey).into(),
+                ErrorCode::Unauthenticated,
+                None,
+            ),
+            (
+                NimbusError::Other("x".to_owned()),
+                ErrorCode::Internal,
+                None,
+            ),
+        ];
+
+        for (error, code, retry_after) in cases {
+            assert_eq!(error.code(), code, "{error}");
+            assert_eq!(error.is_retryable(), code.is_retryable(), "{error}");
+            assert_eq!(error.summary().retry_after_secs, retry_after, "{error}");
+        }
+    }
+
+    #[test]
+    fn error_context_test() {
+        let context = ErrorContext::new("copy", "bucket/a")
+            .scheme("gs://")
+            .to("other/b");
+        assert_eq!(context.operation(), "copy");
+        assert_eq!(context.resource(), "gs://bucket/a");
+        assert_eq!(context.to_string(), "copy gs://bucket/a to gs://other/b");
+
+        let error = NimbusError::from(storage::Error::RateLimited {
+            message: "SlowDown".to_owned(),
+            retry_after: Some(Duration::from_secs(5)),
+        })
+        .with_context(ErrorContext::new("download_to_bytes", "bucket/key").scheme("s3://"));
+        assert_eq!(
+            error.to_string(),
+            "download_to_bytes s3://bucket/key: Rate limited: SlowDown"
+        );
+        assert_eq!(error.operation(), Some("download_to_bytes"));
+        assert_eq!(error.resource().as_deref(), Some("s3://bucket/key"));
+        assert_eq!(error.code(), ErrorCode::RateLimited);
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(5)));
+        assert!(matches!(
+            error.without_context(),
+            NimbusError::StorageClient(storage::Error::RateLimited { .. })
+        ));
+        let source = std::error::Error::source(&error).expect("source");
+        assert!(source.to_string().starts_with("Storage error: "));
+
+        // the outer call wins, the error is not wrapped twice
+        let error = error.with_context(ErrorContext::new("upload_from_bytes", "bucket/key"));
+        assert_eq!(error.operation(), Some("upload_from_bytes"));
+        assert!(error.without_context().context().is_none());
+
+        let plain = NimbusError::Other("x".to_owned());
+        assert!(plain.context().is_none());
+        assert!(matches!(plain.without_context(), NimbusError::Other(_)));
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::time::Instant;
+
+#[cfg(any(
+    feature = "gcp-secrets",
+    feature = "gcp-storage",
+    feature = "gcp-tasks",
+    feature = "aws-secrets",
+    feature = "aws-storage"
+))]
+use crate::TransportConfig;
+use crate::{ErrorCode, NimbusError};
+
+/// How long a failed initialization is reported before the next call tries again
+pub const DEFAULT_FAILURE_BACKOFF: Duration = Duration::from_secs(5);
+
+type Init<T> = Box<dyn Fn() -> BoxFuture<'static, Result<T, NimbusError>> + Send + Sync>;
+
+/// last failed initialization
+struct Failure {
+    at: Instant,
+    code: ErrorCode,
+    message: String,
+}
+
+impl Failure {
+    fn error(&self, retry_after: Duration) -> NimbusError {
+        NimbusError::InitFailed {
+            code: self.code,
+            message: self.message.clone(),
+            retry_after,
+        }
+    }
+}
+
+/// Client built on first use, shared by every call afterwards
+///
+/// ```ignore
+/// static STORAGE: LazyLock<LazyHandle<Client>> = LazyLock::new(|| LazyHandle::storage(TransportConfig::new()));
+///
+/// let client = STORAGE.get().await?;
+/// ```
+///
+/// Concurrent calls of [`LazyHandle::get`] wait for a single initialization. Once it succeeds the client
+/// is kept for good. A failure is kept for [`LazyHandle::failure_backoff`] only: calls within that window
+/// fail straight away with [`NimbusError::InitFailed`], the first one after it tries again, so a transient
+/// failure at cold start (e.g. of the metadata server) doesn't break the instance for its whole life.
+pub struct LazyHandle<T> {
+    init: Init<T>,
+    value: OnceCell<T>,
+    /// held while initializing, so a single attempt runs at a time
+    failure: Mutex<Option<Failure>>,
+    failure_backoff: Duration,
+}
+
+impl<T: fmt::Debug> fmt::Debug for LazyHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyHandle")
+            .field("value", &self.value.get())
+            .field("failure_backoff", &self.failure_backoff)
+            .finish()
+    }
+}
+
+impl<T: Send + Sync> LazyHandle<T> {
+    /// handle whose value is built by `init`, not called before the first [`LazyHandle::get`]
+    pub fn new<F, Fut>(init: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, NimbusError>> + Send + 'static,
+    {
+        Self {
+            init: Box::new(move || Box::pin(init())),
+            value: OnceCell::new(),
+            failure: Mutex::new(None),
+            failure_backoff: DEFAULT_FAILURE_BACKOFF,
+        }
+    }
+
+    /// how long a failed initialization is reported before trying again, [`DEFAULT_FAILURE_BACKOFF`] by default
+    pub fn failure_backoff(mut self, backoff: Duration) -> Self {
+        self.failure_backoff = backoff;
+        self
+    }
+
+    /// whether the value is built, for readiness probes
+    pub fn initialized(&self) -> bool {
+        self.value.initialized()
+    }
+
+    /// the value, built on the first call
+    ///
+    /// Fails with [`NimbusError::InitFailed`] when the initialization fails, or failed less than
+    /// [`LazyHandle::failure_backoff`] ago.
+    pub async fn get(&self) -> Result<&T, NimbusError> {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+
+        let mut failure = self.failure.lock().await;
+        // built by the call we waited for
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        if let Some(last) = failure.as_ref() {
+            let elapsed = last.at.elapsed();
+            if elapsed < self.failure_backoff {
+                return Err(last.error(self.failure_backoff - elapsed));
+            }
+        }
+
+        match (self.init)().await {
+            Ok(value) => {
+                *failure = None;
+                Ok(self.value.get_or_init(|| async { value }).await)
+            }
+            Err(e) => {
+                let last = Failure {
+                    at: Instant::now(),
+                    code: e.code(),
+                    message: e.to_string(),
+                };
+                let error = last.error(self.failure_backoff);
+                *failure = Some(last);
+                Err(error)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gcp-secrets")]
+impl LazyHandle<crate::SecretManagerClient> {
+    /// Secret Manager client authenticated with the application default credentials
+    pub fn secret_manager(transport: TransportConfig) -> Self {
+        use crate::SecretManagerHelper;
+
+        Self::new(move || {
+            let transport = transport.clone();
+            async move {
+                let authenticator = default_authenticator().await?;
+                Ok(crate::SecretManagerClient::new_with_transport(authenticator, &transport).await)
+            }
+        })
+    }
+}
+
+#[cfg(feature = "gcp-tasks")]
+impl LazyHandle<crate::CloudTaskClient> {
+    /// Cloud Tasks client authenticated with the application default credentials
+    pub fn cloud_tasks(transport: TransportConfig) -> Self {
+        use crate::CloudTaskHelper;
+
+        Self::new(move || {
+            let transport = transport.clone();
+            async move {
+                let authenticator = default_authenticator().await?;
+                Ok(crate::CloudTaskClient::new_with_transport(authenticator, &transport).await)
+            }
+        })
+    }
+}
+
+#[cfg(feature = "gcp-storage")]
+impl LazyHandle<google_cloud_storage::client::Client> {
+    /// GCS client authenticated with the application default credentials
+    pub fn storage(transport: TransportConfig) -> Self {
+        use google_cloud_storage::client::{Client, ClientConfig};
+
+        Self::new(move || {
+            let transport = transport.clone();
+            async move {
+                let config = ClientConfig::default()
+                    .with_auth()
+                    .await
+                    .map_err(|e| NimbusError::Other(format!("GCS credentials: {e}")))?;
+                Ok(Client::new(transport.apply(config)))
+            }
+        })
+    }
+}
+
+#[cfg(feature = "aws-secrets")]
+impl LazyHandle<aws_sdk_secretsmanager::Client> {
+    /// Secrets Manager client with the default AWS configuration
+    pub fn secret_manager(transport: TransportConfig) -> Self {
+        Self::new(move || {
+            let transport = transport.clone();
+            async move {
+                Ok(aws_sdk_secretsmanager::Client::new(
+                    &transport.aws_sdk_config().await,
+                ))
+            }
+        })
+    }
+}
+
+#[cfg(feature = "aws-storage")]
+impl LazyHandle<aws_sdk_s3::Client> {
+    /// S3 client with the default AWS configuration
+    pub fn storage(transport: TransportConfig) -> Self {
+        Self::new(move || {
+            let transport = transport.clone();
+            async move { Ok(aws_sdk_s3::Client::new(&transport.aws_sdk_config().await)) }
+        })
+    }
+}
+
+/// authenticator of the application default credentials: the key file of `GOOGLE_APPLICATION_CREDENTIALS`
+/// if set, the metadata server otherwise
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+async fn default_authenticator(
+) -> Result<crate::Authenticator<crate::DefaultConnector>, NimbusError> {
+    use yup_oauth2::authenticator::ApplicationDefaultCredentialsTypes;
+    use yup_oauth2::{
+        ApplicationDefaultCredentialsAuthenticator, ApplicationDefaultCredentialsFlowOpts,
+    };
+
+    let opts = ApplicationDefaultCredentialsFlowOpts::default();
+    let authenticator = match ApplicationDefaultCredentialsAuthenticator::builder(opts).await {
+        ApplicationDefaultCredentialsTypes::ServiceAccount(builder) => builder.build().await,
+        ApplicationDefaultCredentialsTypes::InstanceMetadata(builder) => builder.build().await,
+    };
+
+    authenticator.map_err(|e| NimbusError::Other(format!("Application default credentials: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// handle counting its initializations, failing the first `failures` ones
+    fn counting(failures: usize) -> (LazyHandle<String>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let handle = LazyHandle::new(move || {
+            let counter = Arc::clone(&counter);
+            async move {
+                let call = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if call < failures {
+                    return Err(NimbusError::Other("metadata server unavailable".to_owned()));
+                }
+                Ok(format!("client {call}"))
+            }
+        });
+        (handle, calls)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn single_init_test() {
+        let (handle, calls) = counting(0);
+        let handle = Arc::new(handle);
+        assert!(!handle.initialized());
+
+        let gets: Vec<_> = (0..100)
+            .map(|_| {
+                let handle = Arc::clone(&handle);
+                tokio::spawn(async move { handle.get().await.unwrap().clone() })
+            })
+            .collect();
+        for get in gets {
+            assert_eq!(get.await.unwrap(), "client 0");
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(handle.initialized());
+        handle.get().await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failure_backoff_test() {
+        let (handle, calls) = counting(1);
+        let handle = handle.failure_backoff(Duration::from_secs(2));
+
+        let failed = handle.get().await.unwrap_err();
+        assert!(matches!(failed, NimbusError::InitFailed { .. }));
+        assert!(failed.to_string().contains("metadata server unavailable"));
+        assert_eq!(failed.retry_after(), Some(Duration::from_secs(2)));
+
+        // within the backoff the failure is reported without trying again
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let cached = handle.get().await.unwrap_err();
+        assert_eq!(cached.code(), ErrorCode::Internal);
+        assert!(cached.retry_after().unwrap() < Duration::from_secs(2));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(!handle.initialized());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(handle.get().await.unwrap(), "client 1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(handle.get().await.unwrap(), "client 1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
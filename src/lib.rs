@@ -72,50 +72,110 @@
 //!    assert_eq!(res.status(), 200);
 //! }
 //! ```
+//!
+//! # Features
+//!
+//! Each helper has a feature per provider, pulling in the SDK crates of that helper only:
+//! `gcp-secrets`, `gcp-storage`, `gcp-tasks`, `aws-secrets` and `aws-storage`.
+//! `gcp` and `aws` enable every helper of their provider, `aws` is the default.
+//! The GCP and AWS features of the same helper can't be enabled together.
+
+#[cfg(all(feature = "gcp-secrets", feature = "aws-secrets"))]
+compile_error!("features `gcp-secrets` and `aws-secrets` can't be enabled together");
+#[cfg(all(feature = "gcp-storage", feature = "aws-storage"))]
+compile_error!("features `gcp-storage` and `aws-storage` can't be enabled together");
+
+#[cfg(feature = "gcp-storage")]
+pub mod auth;
+mod batch;
+pub mod breaker;
+mod context;
+#[cfg(any(
+    feature = "gcp-secrets",
+    feature = "gcp-storage",
+    feature = "gcp-tasks"
+))]
+pub mod credentials;
+pub mod env;
+mod error;
+mod lazy;
+mod limits;
+pub mod preflight;
+#[cfg(feature = "serde")]
+pub mod profiles;
+pub mod redact;
+pub mod report;
+pub mod retry;
 pub mod secret;
+mod size;
 pub mod storage;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-tasks")]
 pub mod task;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod time;
+#[cfg(feature = "tracing")]
+pub mod trace;
+mod transport;
+#[cfg(any(feature = "axum", feature = "actix"))]
+mod web;
+
+pub use batch::{BatchError, BatchOutcome};
+pub use context::OpContext;
+#[cfg(any(
+    feature = "gcp-secrets",
+    feature = "gcp-storage",
+    feature = "gcp-tasks"
+))]
+pub use credentials::{CredentialMonitor, CredentialStatus};
+pub use error::{ErrorCode, ErrorContext, ErrorSummary};
+pub use lazy::{LazyHandle, DEFAULT_FAILURE_BACKOFF};
+pub use limits::{ListLimits, DEFAULT_MAX_RESULTS};
+pub use report::Report;
+pub use size::{ByteSize, ParseByteSizeError};
+pub use transport::{TransportConfig, DEFAULT_POOL_IDLE_TIMEOUT};
 
 pub use secret::SecretManagerHelper;
 pub use storage::StorageHelper;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-tasks")]
 pub use task::{CloudTaskHelper, TaskHelper};
 
 // Re-Export crates
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 pub use google_cloud_storage;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 pub use google_cloud_storage::client::{Client, ClientConfig};
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-tasks")]
 pub use google_cloudtasks2;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-tasks")]
 pub use google_cloudtasks2::{
     api::{OidcToken, Task},
     CloudTasks,
 };
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-secrets")]
 pub use google_secretmanager1;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-secrets")]
 pub use google_secretmanager1::SecretManager;
-#[cfg(feature = "gcp")]
+pub use tokio_util::sync::CancellationToken;
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
 pub use yup_oauth2;
-#[cfg(feature = "gcp")]
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
 pub use yup_oauth2::authenticator::Authenticator;
-#[cfg(feature = "gcp")]
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
 pub use yup_oauth2::hyper::client::HttpConnector;
-#[cfg(feature = "gcp")]
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
 pub use yup_oauth2::hyper_rustls::HttpsConnector;
 
 // custom types
 
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-tasks")]
 pub type CloudTaskClient = CloudTasks<HttpsConnector<HttpConnector>>;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-secrets")]
 pub type SecretManagerClient = SecretManager<HttpsConnector<HttpConnector>>;
-#[cfg(feature = "gcp")]
+#[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
 pub type DefaultConnector = HttpsConnector<HttpConnector>;
 
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -124,9 +184,389 @@ pub enum NimbusError {
     SecretManager(#[from] secret::Error),
     #[error("Storage error: {0}")]
     StorageClient(#[from] storage::Error),
-    #[cfg(feature = "gcp")]
+    #[cfg(feature = "gcp-tasks")]
     #[error("CloudTasks error: {0}")]
     TasksClient(#[from] task::Error),
+    #[error("Listing stopped at {returned} results, past the limit of {limit}")]
+    ResultsTruncated { returned: usize, limit: usize },
+    #[error("Listing stopped after {pages} pages, the limit, with more to list")]
+    PagesTruncated { pages: usize, limit: usize },
+    #[error("Deadline exceeded after {completed} items, {remaining} left")]
+    DeadlineExceeded { completed: usize, remaining: usize },
+    #[error("Cancelled after {completed} items, {remaining} left")]
+    Cancelled { completed: usize, remaining: usize },
     #[error("Error: {0}")]
     Other(String),
+    /// initialization of a [`LazyHandle`] failed, now or less than its failure backoff ago
+    #[error("Initialization failed: {message}")]
+    InitFailed {
+        code: ErrorCode,
+        message: String,
+        /// time left until the next attempt
+        retry_after: Duration,
+    },
+    /// a [`breaker::CircuitBreaker`] is open, the call wasn't made
+    #[error("Circuit open, retry after {retry_after:?}")]
+    CircuitOpen {
+        /// time left until the breaker lets calls through again, zero while half-open
+        retry_after: Duration,
+    },
+    /// error of a call of a client, with the operation and the resource it is about
+    #[error("{context}: {}", .source.detail())]
+    Context {
+        context: ErrorContext,
+        source: Box<NimbusError>,
+    },
+}
+
+impl NimbusError {
+    /// provider independent classification of the error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            NimbusError::SecretManager(e) => e.code(),
+            NimbusError::StorageClient(e) => e.code(),
+            #[cfg(feature = "gcp-tasks")]
+            NimbusError::TasksClient(e) => e.code(),
+            NimbusError::ResultsTruncated { .. } | NimbusError::PagesTruncated { .. } => {
+                ErrorCode::InvalidInput
+            }
+            NimbusError::DeadlineExceeded { .. } => ErrorCode::Timeout,
+            NimbusError::Cancelled { .. } => ErrorCode::Cancelled,
+            NimbusError::Other(_) => ErrorCode::Internal,
+            NimbusError::InitFailed { code, .. } => *code,
+            NimbusError::CircuitOpen { .. } => ErrorCode::Unavailable,
+            NimbusError::Context { source, .. } => source.code(),
+        }
+    }
+
+    /// operation and resource of the call that failed, for the errors of the clients
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            NimbusError::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// method of the helper trait that failed, e.g. `download_to_bytes`
+    pub fn operation(&self) -> Option<&'static str> {
+        self.context().map(ErrorContext::operation)
+    }
+
+    /// resource the failed call was about, e.g. `gs://bucket/key`, as the redaction policy allows
+    pub fn resource(&self) -> Option<String> {
+        self.context().map(ErrorContext::resource)
+    }
+
+    /// the error without its context, to match on its variant
+    /// e.g. `matches!(e.without_context(), NimbusError::StorageClient(storage::Error::NotFound(_)))`
+    pub fn without_context(&self) -> &NimbusError {
+        match self {
+            NimbusError::Context { source, .. } => source,
+            e => e,
+        }
+    }
+
+    /// `self` about the call of `context`, replacing the context of a nested call
+    pub(crate) fn with_context(self, context: ErrorContext) -> Self {
+        let source = match self {
+            NimbusError::Context { source, .. } => source,
+            e => Box::new(e),
+        };
+        NimbusError::Context { context, source }
+    }
+
+    /// the error without the name of the client it comes from, as shown after a context
+    fn detail(&self) -> String {
+        match self {
+            NimbusError::SecretManager(e) => e.to_string(),
+            NimbusError::StorageClient(e) => e.to_string(),
+            #[cfg(feature = "gcp-tasks")]
+            NimbusError::TasksClient(e) => e.to_string(),
+            e => e.to_string(),
+        }
+    }
+
+    /// whether retrying the same call may succeed
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_retryable()
+    }
+
+    /// how long the provider asked to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            NimbusError::SecretManager(e) => e.retry_after(),
+            NimbusError::StorageClient(e) => e.retry_after(),
+            #[cfg(feature = "gcp-tasks")]
+            NimbusError::TasksClient(e) => e.retry_after(),
+            NimbusError::ResultsTruncated { .. }
+            | NimbusError::PagesTruncated { .. }
+            | NimbusError::DeadlineExceeded { .. }
+            | NimbusError::Cancelled { .. }
+            | NimbusError::Other(_) => None,
+            NimbusError::InitFailed { retry_after, .. }
+            | NimbusError::CircuitOpen { retry_after } => Some(*retry_after),
+            NimbusError::Context { source, .. } => source.retry_after(),
+        }
+    }
+
+    /// summary of the error that is safe to hand out to callers
+    pub fn summary(&self) -> ErrorSummary {
+        let code = self.code();
+
+        ErrorSummary {
+            code,
+            message: code.description(),
+            retryable: code.is_retryable(),
+            retry_after_secs: self.retry_after().map(|d| d.as_secs()),
+        }
+    }
+}
+
+/// Compile time audit: clients, wrappers and handles can be shared across threads,
+/// and the futures of the inherent async methods can be spawned.
+/// The `async_trait` methods return boxed `Send` futures by construction.
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::stream;
+
+    use super::*;
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    use crate::secret::WithProject;
+    use crate::storage::*;
+    use crate::testing::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn send_sync_test() {
+        assert_send_sync::<NimbusError>();
+        assert_send_sync::<ErrorSummary>();
+        assert_send_sync::<BatchOutcome<String, Vec<u8>>>();
+        assert_send_sync::<ListLimits>();
+        assert_send_sync::<OpContext>();
+        assert_send_sync::<LazyHandle<testing::MemoryStorage>>();
+        assert_send_sync::<preflight::PreflightReport>();
+        assert_send_sync::<preflight::PreflightClients<'static>>();
+        assert_send_sync::<retry::ExponentialFullJitter>();
+        assert_send_sync::<retry::DecorrelatedJitter>();
+        assert_send_sync::<breaker::CircuitBreaker>();
+        assert_send_sync::<breaker::GuardedStorage<MemoryStorage>>();
+
+        #[cfg(feature = "aws-storage")]
+        {
+            assert_send_sync::<aws_sdk_s3::Client>();
+            assert_send_sync::<ListQuery<'static, aws_sdk_s3::Client>>();
+            assert_send_sync::<Lease<'static, aws_sdk_s3::Client>>();
+        }
+        #[cfg(feature = "aws-secrets")]
+        {
+            assert_send_sync::<aws_sdk_secretsmanager::Client>();
+            assert_send_sync::<WithProject<aws_sdk_secretsmanager::Client, ()>>();
+        }
+        #[cfg(feature = "gcp-storage")]
+        {
+            assert_send_sync::<Client>();
+            assert_send_sync::<ListQuery<'static, Client>>();
+            assert_send_sync::<Lease<'static, Client>>();
+        }
+        #[cfg(feature = "gcp-secrets")]
+        {
+            assert_send_sync::<SecretManagerClient>();
+            assert_send_sync::<WithProject<SecretManagerClient, DefaultConnector>>();
+        }
+        #[cfg(feature = "gcp-tasks")]
+        {
+            assert_send_sync::<CloudTaskClient>();
+            assert_send_sync::<task::Error>();
+            assert_send_sync::<task::QueueDiscovery<task::QueuePath>>();
+            assert_send_sync::<task::TaskOutcome>();
+            assert_send_sync::<MemoryCloudTasks>();
+        }
+
+        assert_send_sync::<ResumableUpload>();
+        assert_send_sync::<ObjectMeta>();
+        assert_send_sync::<ObjectChecksum>();
+        assert_send_sync::<ObjectEvent>();
+        assert_send_sync::<PostPolicy>();
+        assert_send_sync::<UploadConstraints>();
+        assert_send_sync::<PartitionScheme>();
+
+        assert_send_sync::<MemoryStorage>();
+        assert_send_sync::<MockStats>();
+        #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+        {
+            assert_send_sync::<breaker::GuardedSecretManager<MemorySecretManager>>();
+            assert_send_sync::<MemorySecretManager>();
+        }
+    }
+
+    #[cfg(all(feature = "aws-storage", feature = "aws-secrets"))]
+    #[tokio::test]
+    async fn client_error_context_test() {
+        use aws_sdk_s3::config::{Credentials, Region, SharedCredentialsProvider};
+
+        // nothing listens on port 1, every call fails without reaching a provider
+        let config = aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+                "id", "secret", None, None, "test",
+            )))
+            .endpoint_url("http://127.0.0.1:1")
+            .retry_config(aws_config::retry::RetryConfig::disabled())
+            .build();
+
+        let storage = aws_sdk_s3::Client::new(&config);
+        let error = storage
+            .download_to_bytes("bucket", "dir/key")
+            .await
+            .unwrap_err();
+        assert_eq!(error.operation(), Some("download_to_bytes"));
+        assert_eq!(error.resource().as_deref(), Some("s3://bucket/dir/key"));
+        assert!(error
+            .to_string()
+            .starts_with("download_to_bytes s3://bucket/dir/key: "));
+        assert!(matches!(
+            error.without_context(),
+            NimbusError::StorageClient(_)
+        ));
+
+        let error = storage
+            .copy_encrypted(("bucket", "a"), None, ("other", "b"), None)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("copy_encrypted s3://bucket/a to s3://other/b: "));
+
+        let secrets = aws_sdk_secretsmanager::Client::new(&config);
+        let error = secrets.get_secret("project", "api-key").await.unwrap_err();
+        assert_eq!(error.operation(), Some("get_secret"));
+        assert_eq!(error.resource().as_deref(), Some("api-key"));
+        assert!(matches!(
+            error.without_context(),
+            NimbusError::SecretManager(_)
+        ));
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[tokio::test]
+    async fn s3_bucket_name_checked_test() {
+        use aws_sdk_s3::config::{Credentials, Region, SharedCredentialsProvider};
+
+        // nothing listens on port 1, a call reaching the network fails as unavailable
+        let config = aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+                "id", "secret", None, None, "test",
+            )))
+            .endpoint_url("http://127.0.0.1:1")
+            .retry_config(aws_config::retry::RetryConfig::disabled())
+            .build();
+        let storage = aws_sdk_s3::Client::new(&config);
+
+        let bucket = "Not_A_Bucket";
+        let errors = [
+            storage.bucket_is_public(bucket).await.map(|_| ()),
+            storage.object_acls_apply(bucket).await.map(|_| ()),
+            storage.object_acl(bucket, "key").await.map(|_| ()),
+            storage.get_cors(bucket).await.map(|_| ()),
+            storage.set_cors(bucket, vec![]).await,
+        ];
+        for error in errors {
+            assert_eq!(error.unwrap_err().code(), ErrorCode::InvalidInput);
+        }
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[tokio::test]
+    async fn s3_object_exists_test() {
+        use aws_sdk_s3::config::{Credentials, Region, SharedCredentialsProvider};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // answers HEAD /bucket/<status> with that status
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split(' ').nth(1).unwrap_or_default();
+                    let status = path.rsplit('/').next().unwrap_or_default();
+                    let response = format!(
+                        "HTTP/1.1 {status} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    );
+                    socket.write_all(response.as_bytes()).await.ok();
+                });
+            }
+        });
+
+        let config = aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+                "id", "secret", None, None, "test",
+            )))
+            .endpoint_url(format!("http://{addr}"))
+            .retry_config(aws_config::retry::RetryConfig::disabled())
+            .build();
+        let storage = aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(true)
+                .build(),
+        );
+
+        assert!(storage.object_exists("bucket", "200").await.unwrap());
+        assert!(!storage.object_exists("bucket", "404").await.unwrap());
+        for (status, code) in [
+            ("403", ErrorCode::PermissionDenied),
+            ("503", ErrorCode::Unavailable),
+        ] {
+            let error = storage.object_exists("bucket", status).await.unwrap_err();
+            assert_eq!(error.code(), code, "{status}");
+            assert_eq!(error.operation(), Some("object_exists"));
+        }
+    }
+
+    #[allow(dead_code)]
+    async fn futures_are_send(storage: MemoryStorage, upload: &mut ResumableUpload) {
+        assert_send(&storage.list("b").collect());
+        assert_send(&storage.watch_prefix("b", "in/", Duration::from_secs(1)));
+        assert_send(&Lease::acquire(
+            &storage,
+            "b",
+            "k",
+            Duration::from_secs(1),
+            "o",
+        ));
+        assert_send(&upload.upload_chunk(Vec::new()));
+
+        let field = stream::empty::<Result<Vec<u8>, std::io::Error>>();
+        let upload = storage.upload_multipart_field(
+            "b",
+            |_| "k".to_owned(),
+            field,
+            UploadConstraints::new(),
+        );
+        assert_send(&upload);
+
+        #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+        {
+            let secrets = MemorySecretManager::new().with_project("p");
+            assert_send(&secrets.get("s"));
+        }
+    }
 }
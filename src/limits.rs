@@ -0,0 +1,144 @@
+// `NimbusError` is as large as the google api errors it wraps under the gcp feature
+#![allow(clippy::result_large_err)]
+
+use crate::NimbusError;
+
+/// Results collected by a listing unless set with [`ListLimits::max_results`]
+pub const DEFAULT_MAX_RESULTS: usize = 10_000;
+
+/// Bounds of the listings that collect every page into a `Vec`
+///
+/// A listing going past a bound fails with [`NimbusError::ResultsTruncated`] or
+/// [`NimbusError::PagesTruncated`] instead of returning part of the results, so a too broad prefix
+/// can't pull millions of entries into memory nor pass for a complete listing.
+/// Streaming listings (e.g. [`crate::storage::ListQuery::stream`]) are not bounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListLimits {
+    pub max_results: Option<usize>,
+    pub max_pages: Option<usize>,
+}
+
+/// at most [`DEFAULT_MAX_RESULTS`] results, any number of pages
+impl Default for ListLimits {
+    fn default() -> Self {
+        Self {
+            max_results: Some(DEFAULT_MAX_RESULTS),
+            max_pages: None,
+        }
+    }
+}
+
+impl ListLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// no bound at all
+    pub fn unlimited() -> Self {
+        Self {
+            max_results: None,
+            max_pages: None,
+        }
+    }
+
+    pub fn max_results(mut self, max_results: usize) -> Self {
+        self.max_results = Some(max_results);
+        self
+    }
+
+    pub fn max_pages(mut self, max_pages: usize) -> Self {
+        self.max_pages = Some(max_pages);
+        self
+    }
+
+    pub(crate) fn drain(self) -> Drain {
+        Drain {
+            limits: self,
+            results: 0,
+            pages: 0,
+        }
+    }
+
+    /// check a listing answered in one go
+    pub(crate) fn check(self, results: usize) -> Result<(), NimbusError> {
+        self.drain().page(results, false)
+    }
+}
+
+/// Progress of a listing against its [`ListLimits`]
+#[derive(Debug)]
+pub(crate) struct Drain {
+    limits: ListLimits,
+    results: usize,
+    pages: usize,
+}
+
+impl Drain {
+    /// record a page of `results`, `more` if another page follows
+    /// fails once the results went past the bound, or if another page is needed past the bound
+    pub fn page(&mut self, results: usize, more: bool) -> Result<(), NimbusError> {
+        self.pages += 1;
+        self.results += results;
+
+        if let Some(limit) = self.limits.max_results.filter(|l| self.results > *l) {
+            return Err(NimbusError::ResultsTruncated {
+                returned: self.results,
+                limit,
+            });
+        }
+        if let Some(limit) = self.limits.max_pages.filter(|l| more && self.pages >= *l) {
+            return Err(NimbusError::PagesTruncated {
+                pages: self.pages,
+                limit,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+
+    #[test]
+    fn drain_test() {
+        let mut drain = ListLimits::new().max_results(10).drain();
+        drain.page(4, true).unwrap();
+        drain.page(6, true).unwrap();
+        // a last, empty page doesn't go past the bound
+        drain.page(0, false).unwrap();
+
+        let mut drain = ListLimits::new().max_results(10).drain();
+        drain.page(4, true).unwrap();
+        drain.page(4, true).unwrap();
+        let err = drain.page(4, true).unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::ResultsTruncated {
+                returned: 12,
+                limit: 10
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+
+        let mut drain = ListLimits::unlimited().max_pages(2).drain();
+        drain.page(100, true).unwrap();
+        // the last allowed page is fine as long as no other follows
+        let err = drain.page(100, true).unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::PagesTruncated { pages: 2, limit: 2 }
+        ));
+        let mut drain = ListLimits::unlimited().max_pages(2).drain();
+        drain.page(100, true).unwrap();
+        drain.page(100, false).unwrap();
+
+        assert!(ListLimits::default().check(DEFAULT_MAX_RESULTS).is_ok());
+        assert!(ListLimits::default()
+            .check(DEFAULT_MAX_RESULTS + 1)
+            .is_err());
+        assert!(ListLimits::unlimited().check(usize::MAX).is_ok());
+    }
+}
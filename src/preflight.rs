@@ -0,0 +1,617 @@
+//! Pre-flight check of the permissions a service needs, e.g. at startup or before a deploy
+//!
+//! A [`PreflightPlan`] declares what the service will do, [`run`] exercises each intent the least
+//! intrusive way that still proves the permission:
+//! - [`Intent::ReadSecret`] reads the secret
+//! - [`Intent::WriteObject`] writes a tiny probe object under `{prefix}`[`PROBE_PREFIX`] and deletes it
+//! - [`Intent::DeleteObject`] writes a probe object the same way, the check is its delete
+//! - [`Intent::EnqueueTask`] pushes a probe task scheduled [`PROBE_TASK_DELAY`] ahead and deletes it
+//!
+//! Checks run concurrently within the timeout of the plan. Probes are deleted whether their check passed,
+//! failed or timed out, each cleanup gets [`CLEANUP_TIMEOUT`] on top of the timeout. Probes that couldn't
+//! be deleted are listed in [`PreflightReport::leftovers`].
+//!
+//! ```ignore
+//! let plan = PreflightPlan::new()
+//!     .read_secret("project", "db-password")
+//!     .write_object("uploads", "incoming/")
+//!     .timeout(Duration::from_secs(20));
+//! let clients = PreflightClients::new().storage(&storage).secrets(&secrets);
+//! let report = preflight::run(&plan, &clients).await;
+//! if !report.passed() {
+//!     eprintln!("{report}");
+//!     std::process::exit(1);
+//! }
+//! ```
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use chrono::Utc;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::report::{self, Report};
+#[cfg(feature = "gcp-tasks")]
+use crate::{CloudTaskHelper, Task, TaskHelper};
+use crate::{ErrorCode, NimbusError, OpContext, SecretManagerHelper, StorageHelper};
+
+/// Probe objects are written under `{prefix}{PROBE_PREFIX}`, keep it out of the listings of the service
+pub const PROBE_PREFIX: &str = ".nimbus-preflight/";
+
+/// Timeout of a plan unless set otherwise
+pub const DEFAULT_PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Budget of the cleanup of each probe, on top of the timeout of the plan
+pub const CLEANUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Probe tasks are scheduled this far ahead, long after they are deleted
+pub const PROBE_TASK_DELAY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const PROBE_DATA: &[u8] = b"nimbus preflight probe";
+
+/// Something the service will do, checked by [`run`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "intent", rename_all = "snake_case"))]
+pub enum Intent {
+    ReadSecret {
+        project: String,
+        name: String,
+    },
+    WriteObject {
+        bucket: String,
+        prefix: String,
+    },
+    DeleteObject {
+        bucket: String,
+        prefix: String,
+    },
+    /// `queue` is the full queue path
+    #[cfg(feature = "gcp-tasks")]
+    EnqueueTask {
+        queue: String,
+    },
+}
+
+impl fmt::Display for Intent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Intent::ReadSecret { project, name } => write!(f, "read secret {project}/{name}"),
+            Intent::WriteObject { bucket, prefix } => write!(f, "write object {bucket}/{prefix}"),
+            Intent::DeleteObject { bucket, prefix } => write!(f, "delete object {bucket}/{prefix}"),
+            #[cfg(feature = "gcp-tasks")]
+            Intent::EnqueueTask { queue } => write!(f, "enqueue task {queue}"),
+        }
+    }
+}
+
+/// Intents to check, and the time they are checked within
+#[derive(Debug, Clone)]
+pub struct PreflightPlan {
+    intents: Vec<Intent>,
+    timeout: Duration,
+}
+
+impl Default for PreflightPlan {
+    fn default() -> Self {
+        Self {
+            intents: vec![],
+            timeout: DEFAULT_PREFLIGHT_TIMEOUT,
+        }
+    }
+}
+
+impl PreflightPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intent(mut self, intent: Intent) -> Self {
+        self.intents.push(intent);
+        self
+    }
+
+    pub fn read_secret(self, project: impl Into<String>, name: impl Into<String>) -> Self {
+        self.intent(Intent::ReadSecret {
+            project: project.into(),
+            name: name.into(),
+        })
+    }
+
+    pub fn write_object(self, bucket: impl Into<String>, prefix: impl Into<String>) -> Self {
+        self.intent(Intent::WriteObject {
+            bucket: bucket.into(),
+            prefix: prefix.into(),
+        })
+    }
+
+    pub fn delete_object(self, bucket: impl Into<String>, prefix: impl Into<String>) -> Self {
+        self.intent(Intent::DeleteObject {
+            bucket: bucket.into(),
+            prefix: prefix.into(),
+        })
+    }
+
+    #[cfg(feature = "gcp-tasks")]
+    pub fn enqueue_task(self, queue: impl Into<String>) -> Self {
+        self.intent(Intent::EnqueueTask {
+            queue: queue.into(),
+        })
+    }
+
+    /// time every check must complete within, cleanups excluded
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn intents(&self) -> &[Intent] {
+        &self.intents
+    }
+}
+
+#[async_trait::async_trait]
+trait ProbeStorage: Send + Sync {
+    async fn write(&self, bucket: &str, key: &str) -> Result<(), NimbusError>;
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), NimbusError>;
+}
+
+#[async_trait::async_trait]
+impl<T: StorageHelper + Send + Sync> ProbeStorage for T {
+    async fn write(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        let mime = Some("text/plain".to_owned());
+        self.upload_from_bytes(bucket, key, mime, PROBE_DATA.to_vec())
+            .await
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        self.delete_file(bucket, key).await
+    }
+}
+
+#[async_trait::async_trait]
+trait ProbeSecrets: Send + Sync {
+    async fn read(&self, project: &str, name: &str) -> Result<(), NimbusError>;
+}
+
+/// a secret manager with its connector type erased
+struct Secrets<'a, M, S>(&'a M, PhantomData<fn() -> S>);
+
+#[async_trait::async_trait]
+impl<M: SecretManagerHelper<S> + Sync, S> ProbeSecrets for Secrets<'_, M, S> {
+    async fn read(&self, project: &str, name: &str) -> Result<(), NimbusError> {
+        self.0.get_secret(project, name).await.map(drop)
+    }
+}
+
+#[cfg(feature = "gcp-tasks")]
+#[async_trait::async_trait]
+trait ProbeTasks: Send + Sync {
+    async fn push(&self, queue: &str, name: &str) -> Result<(), NimbusError>;
+
+    async fn delete(&self, name: &str) -> Result<(), NimbusError>;
+}
+
+/// a cloud tasks client with its connector type erased
+#[cfg(feature = "gcp-tasks")]
+struct Tasks<'a, C, S>(&'a C, PhantomData<fn() -> S>);
+
+#[cfg(feature = "gcp-tasks")]
+#[async_trait::async_trait]
+impl<C: CloudTaskHelper<S> + Sync, S> ProbeTasks for Tasks<'_, C, S> {
+    async fn push(&self, queue: &str, name: &str) -> Result<(), NimbusError> {
+        let schedule =
+            Utc::now() + chrono::Duration::from_std(PROBE_TASK_DELAY).unwrap_or_default();
+        let task = Task::new_task(
+            "https://preflight.invalid/",
+            "POST",
+            None,
+            None,
+            Some(name.to_owned()),
+            Some(schedule),
+            None,
+        );
+        self.0.push_task(queue, task, None).await.map(drop)
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), NimbusError> {
+        self.0.delete_task(name).await
+    }
+}
+
+/// Clients [`run`] checks the intents with, an intent whose client is missing fails
+#[derive(Default)]
+pub struct PreflightClients<'a> {
+    storage: Option<&'a dyn ProbeStorage>,
+    secrets: Option<Box<dyn ProbeSecrets + 'a>>,
+    #[cfg(feature = "gcp-tasks")]
+    tasks: Option<Box<dyn ProbeTasks + 'a>>,
+}
+
+impl<'a> PreflightClients<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn storage<T: StorageHelper + Send + Sync>(mut self, storage: &'a T) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn secrets<M: SecretManagerHelper<S> + Sync, S: 'a>(mut self, secrets: &'a M) -> Self {
+        self.secrets = Some(Box::new(Secrets(secrets, PhantomData)));
+        self
+    }
+
+    #[cfg(feature = "gcp-tasks")]
+    pub fn tasks<C: CloudTaskHelper<S> + Sync, S: 'a>(mut self, tasks: &'a C) -> Self {
+        self.tasks = Some(Box::new(Tasks(tasks, PhantomData)));
+        self
+    }
+}
+
+/// Outcome of the check of an [`Intent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// the plan timed out before the check completed
+    TimedOut,
+}
+
+/// Check of an [`Intent`], in [`PreflightReport::checks`]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CheckResult {
+    pub intent: Intent,
+    pub status: CheckStatus,
+    /// classification of the error, `None` if the check passed
+    pub code: Option<ErrorCode>,
+    /// the error, with its provider details
+    pub error: Option<String>,
+    /// time the check took, its cleanup excluded
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_ms", serialize_with = "millis")
+    )]
+    pub elapsed: Duration,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.status == CheckStatus::Passed
+    }
+}
+
+#[cfg(feature = "serde")]
+fn millis<S: serde::Serializer>(elapsed: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(elapsed.as_millis())
+}
+
+/// Probe that couldn't be deleted, in [`PreflightReport::leftovers`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Leftover {
+    /// `{bucket}/{key}` of a probe object, full name of a probe task
+    pub resource: String,
+    pub error: String,
+}
+
+/// Outcome of [`run`], serializable with the `serde` feature for deploy tooling to gate on
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PreflightReport {
+    /// one per intent, in the order of the plan
+    pub checks: Vec<CheckResult>,
+    pub leftovers: Vec<Leftover>,
+}
+
+impl PreflightReport {
+    /// true if every check passed, leftovers don't fail the report
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(CheckResult::passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|c| !c.passed())
+    }
+}
+
+impl Report for PreflightReport {
+    fn summary_line(&self) -> String {
+        let count = |status| self.checks.iter().filter(|c| c.status == status).count();
+        format!(
+            "preflight: {} checks, {} passed, {} failed, {} timed out, {} leftover{}",
+            self.checks.len(),
+            count(CheckStatus::Passed),
+            count(CheckStatus::Failed),
+            count(CheckStatus::TimedOut),
+            self.leftovers.len(),
+            if self.leftovers.len() == 1 { "" } else { "s" }
+        )
+    }
+
+    /// failed and timed out checks, leftovers don't count
+    fn has_failures(&self) -> bool {
+        !self.passed()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    /// checks in the order of the plan
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())?;
+        report::write_section(
+            f,
+            "failed",
+            self.failures().map(|c| match (c.status, c.code, &c.error) {
+                (CheckStatus::TimedOut, ..) => format!("{}: timed out", c.intent),
+                (_, Some(code), Some(error)) => format!("{}: {code}: {error}", c.intent),
+                (_, Some(code), None) => format!("{}: {code}", c.intent),
+                (_, None, _) => format!("{}: failed", c.intent),
+            }),
+        )?;
+        report::write_section(
+            f,
+            "leftovers",
+            self.leftovers
+                .iter()
+                .map(|l| format!("{}: {}", l.resource, l.error)),
+        )
+    }
+}
+
+/// what a check may have left behind
+enum Probe {
+    Object {
+        bucket: String,
+        key: String,
+    },
+    #[cfg(feature = "gcp-tasks")]
+    Task {
+        name: String,
+    },
+}
+
+/// Check every intent of `plan` concurrently, see the [module documentation](self)
+pub async fn run(plan: &PreflightPlan, clients: &PreflightClients<'_>) -> PreflightReport {
+    let ctx = OpContext::new().timeout(plan.timeout);
+    // unique per run, so concurrent runs and leftovers of earlier ones don't collide
+    let run_id = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+
+    let checks = plan.intents.iter().enumerate().map(|(i, intent)| {
+        let ctx = &ctx;
+        async move {
+            let start = Instant::now();
+            let (outcome, probe) = check(intent, &format!("{run_id}-{i}"), ctx, clients).await;
+            let elapsed = start.elapsed();
+            let leftover = match probe {
+                Some(probe) => cleanup(probe, clients).await,
+                None => None,
+            };
+            (result(intent, outcome, elapsed), leftover)
+        }
+    });
+
+    let mut report = PreflightReport::default();
+    for (check, leftover) in futures::future::join_all(checks).await {
+        report.checks.push(check);
+        report.leftovers.extend(leftover);
+    }
+
+    report
+}
+
+fn missing(client: &str) -> NimbusError {
+    NimbusError::Other(format!("no {client} client given to the preflight"))
+}
+
+/// whether a write that failed with `outcome` may still have happened
+fn may_exist(outcome: &Option<Result<(), NimbusError>>) -> bool {
+    match outcome {
+        Some(Ok(())) | None => true,
+        Some(Err(e)) => e.is_retryable(),
+    }
+}
+
+/// run the check of `intent` within `ctx`, `None` if it timed out
+/// returns the probe to delete, if one may have been created and wasn't deleted
+async fn check(
+    intent: &Intent,
+    id: &str,
+    ctx: &OpContext,
+    clients: &PreflightClients<'_>,
+) -> (Option<Result<(), NimbusError>>, Option<Probe>) {
+    match intent {
+        Intent::ReadSecret { project, name } => match &clients.secrets {
+            Some(secrets) => (ctx.run(secrets.read(project, name)).await, None),
+            None => (Some(Err(missing("secret manager"))), None),
+        },
+        Intent::WriteObject { bucket, prefix } | Intent::DeleteObject { bucket, prefix } => {
+            let Some(storage) = clients.storage else {
+                return (Some(Err(missing("storage"))), None);
+            };
+            let key = format!("{prefix}{PROBE_PREFIX}{id}");
+            let probe = || Probe::Object {
+                bucket: bucket.clone(),
+                key: key.clone(),
+            };
+
+            let written = ctx.run(storage.write(bucket, &key)).await;
+            let probe = may_exist(&written).then(probe);
+            if !matches!(intent, Intent::DeleteObject { .. }) || !matches!(written, Some(Ok(()))) {
+                return (written, probe);
+            }
+
+            let deleted = ctx.run(storage.delete(bucket, &key)).await;
+            let probe = probe.filter(|_| !matches!(deleted, Some(Ok(()))));
+            (deleted, probe)
+        }
+        #[cfg(feature = "gcp-tasks")]
+        Intent::EnqueueTask { queue } => {
+            let Some(tasks) = &clients.tasks else {
+                return (Some(Err(missing("cloud tasks"))), None);
+            };
+            // task ids only take letters, digits, hyphens and underscores
+            let name = format!("{queue}/tasks/nimbus-preflight-{id}");
+
+            let pushed = ctx.run(tasks.push(queue, &name)).await;
+            let probe = may_exist(&pushed).then_some(Probe::Task { name });
+            (pushed, probe)
+        }
+    }
+}
+
+/// delete a probe within [`CLEANUP_TIMEOUT`], a probe that is already gone is deleted
+async fn cleanup(probe: Probe, clients: &PreflightClients<'_>) -> Option<Leftover> {
+    let (resource, deleted) = match &probe {
+        Probe::Object { bucket, key } => {
+            let storage = clients.storage?;
+            let deleted = tokio::time::timeout(CLEANUP_TIMEOUT, storage.delete(bucket, key)).await;
+            (format!("{bucket}/{key}"), deleted)
+        }
+        #[cfg(feature = "gcp-tasks")]
+        Probe::Task { name } => {
+            let tasks = clients.tasks.as_ref()?;
+            let deleted = tokio::time::timeout(CLEANUP_TIMEOUT, tasks.delete(name)).await;
+            (name.clone(), deleted)
+        }
+    };
+
+    let error = match deleted {
+        Ok(Ok(())) => return None,
+        Ok(Err(e)) if e.code() == ErrorCode::NotFound => return None,
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("cleanup timed out after {CLEANUP_TIMEOUT:?}"),
+    };
+    Some(Leftover { resource, error })
+}
+
+fn result(
+    intent: &Intent,
+    outcome: Option<Result<(), NimbusError>>,
+    elapsed: Duration,
+) -> CheckResult {
+    let (status, code, error) = match outcome {
+        Some(Ok(())) => (CheckStatus::Passed, None, None),
+        Some(Err(e)) => (CheckStatus::Failed, Some(e.code()), Some(e.to_string())),
+        None => (
+            CheckStatus::TimedOut,
+            Some(ErrorCode::Timeout),
+            Some("preflight timed out before the check completed".to_owned()),
+        ),
+    };
+
+    CheckResult {
+        intent: intent.clone(),
+        status,
+        code,
+        error,
+        elapsed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    #[tokio::test(start_paused = true)]
+    async fn preflight_test() {
+        use crate::testing::MemorySecretManager;
+
+        let storage = MemoryStorage::new();
+        let secrets = MemorySecretManager::new();
+        secrets.add_version("p", "db", "password");
+
+        let plan = PreflightPlan::new()
+            .read_secret("p", "db")
+            .read_secret("p", "missing")
+            .write_object("b", "uploads/")
+            .delete_object("b", "")
+            .timeout(Duration::from_secs(5));
+        let clients = PreflightClients::new().storage(&storage).secrets(&secrets);
+        let report = run(&plan, &clients).await;
+
+        let status: Vec<_> = report.checks.iter().map(|c| c.status).collect();
+        use CheckStatus::*;
+        assert_eq!(status, [Passed, Failed, Passed, Passed]);
+        assert_eq!(report.checks[1].code, Some(ErrorCode::NotFound));
+        assert!(!report.passed());
+        assert_eq!(report.failures().count(), 1);
+        assert!(report.leftovers.is_empty());
+        // every probe was deleted
+        assert!(storage.list("b").collect().await.unwrap().is_empty());
+        assert_eq!(storage.stats().calls("delete_file"), 2);
+
+        // a missing client fails its checks only
+        let report = run(&plan, &PreflightClients::new().storage(&storage)).await;
+        assert_eq!(
+            report.checks.iter().map(|c| c.status).collect::<Vec<_>>(),
+            [Failed, Failed, Passed, Passed]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn preflight_cleanup_test() {
+        let storage = MemoryStorage::new();
+        let plan = PreflightPlan::new()
+            .write_object("b", "")
+            .delete_object("b", "")
+            .timeout(Duration::from_secs(5));
+        let clients = PreflightClients::new().storage(&storage);
+
+        // denied deletes fail the delete check and leave both probes behind
+        storage.mock_stats().set_fault(
+            "delete_file",
+            Fault::new().fail(1.0, ErrorCode::PermissionDenied),
+        );
+        let report = run(&plan, &clients).await;
+        assert!(report.checks[0].passed());
+        assert_eq!(report.checks[1].code, Some(ErrorCode::PermissionDenied));
+        assert_eq!(report.leftovers.len(), 2);
+        assert!(report.leftovers[0]
+            .resource
+            .starts_with("b/.nimbus-preflight/"));
+        assert_eq!(storage.list("b").collect().await.unwrap().len(), 2);
+
+        // writes outliving the timeout are cleaned up after it
+        let storage = MemoryStorage::new();
+        storage.mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new().latency(Latency::Fixed(Duration::from_secs(60))),
+        );
+        let start = Instant::now();
+        let report = run(&plan, &PreflightClients::new().storage(&storage)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert!(report
+            .checks
+            .iter()
+            .all(|c| c.status == CheckStatus::TimedOut));
+        assert_eq!(report.checks[0].elapsed, Duration::from_secs(5));
+        assert!(report.leftovers.is_empty());
+        assert_eq!(storage.stats().calls("delete_file"), 2);
+    }
+
+    #[cfg(feature = "gcp-tasks")]
+    #[tokio::test]
+    async fn preflight_enqueue_test() {
+        use crate::testing::MemoryCloudTasks;
+
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+        let plan = PreflightPlan::new().enqueue_task(queue);
+        let report = run(&plan, &PreflightClients::new().tasks(&tasks)).await;
+        assert!(report.passed());
+        assert_eq!(tasks.stats().calls("delete_task"), 1);
+        let (left, _) = tasks.list_tasks(queue, None, true, None).await.unwrap();
+        assert!(left.is_empty());
+    }
+}
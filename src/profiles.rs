@@ -0,0 +1,939 @@
+//! Named configurations of buckets and queues, declared in a manifest
+//!
+//! A profile bundles how calls to a resource are made: retries, per-attempt timeout and defaults.
+//! The manifest declares the profiles, which bucket or queue uses which, and the profile of the
+//! resources it doesn't list. [`Manifest`] is deserializable from any serde format, e.g. JSON:
+//!
+//! ```json
+//! {
+//!   "bucket_profiles": [
+//!     { "name": "standard", "timeout": "30s",
+//!       "retry": { "strategy": "exponential", "base": "100ms", "max_delay": "10s" } },
+//!     { "name": "uploads", "timeout": "5m", "content_type": "application/octet-stream",
+//!       "max_in_memory": "64MiB" }
+//!   ],
+//!   "queue_profiles": [
+//!     { "name": "emails", "max_per_second": 50, "headers": { "Content-Type": "application/json" } }
+//!   ],
+//!   "default_bucket_profile": "standard",
+//!   "buckets": { "user-uploads": "uploads" },
+//!   "queues": { "projects/p/locations/l/queues/emails": "emails" }
+//! }
+//! ```
+//!
+//! Durations are a whole number followed by `ms`, `s`, `m` or `h`, sizes as parsed by [`ByteSize`].
+//!
+//! ```ignore
+//! let manifest = Manifest::from_json(&std::fs::read("profiles.json")?)?;
+//! let registry = ProfileRegistry::new(manifest, storage).with_tasks(tasks);
+//! registry.storage_for("user-uploads").upload("avatar.png", data).await?;
+//! registry.tasks_for("projects/p/locations/l/queues/emails").push(task).await?;
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::env::parse_duration;
+use crate::retry::{
+    self, Backoff, DecorrelatedJitter, ExponentialFullJitter, NoRetry, DEFAULT_MAX_ATTEMPTS,
+};
+use crate::{storage, ByteSize, ErrorCode, NimbusError, StorageHelper};
+
+#[cfg(feature = "gcp-tasks")]
+use std::sync::Mutex;
+
+#[cfg(feature = "gcp-tasks")]
+use tokio::time::Instant;
+
+#[cfg(feature = "gcp-tasks")]
+use crate::task::{self, TaskOverrides};
+#[cfg(feature = "gcp-tasks")]
+use crate::{CloudTaskHelper, Task};
+
+/// First delay of a retry profile unless set
+pub const DEFAULT_RETRY_BASE: Duration = Duration::from_millis(100);
+
+/// Longest delay of a retry profile unless set
+pub const DEFAULT_RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid manifest: {0}")]
+    Parse(String),
+    /// `path` of the offending field, e.g. `bucket_profiles[1].retry.base`
+    #[error("Invalid manifest field {path}: {message}")]
+    Invalid { path: String, message: String },
+    #[error("Unknown {kind} profile {name:?}")]
+    UnknownProfile { kind: &'static str, name: String },
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Parse(_) | Error::Invalid { .. } => ErrorCode::InvalidInput,
+            Error::UnknownProfile { .. } => ErrorCode::NotFound,
+        }
+    }
+}
+
+fn invalid(path: impl Into<String>, message: impl Into<String>) -> Error {
+    Error::Invalid {
+        path: path.into(),
+        message: message.into(),
+    }
+}
+
+/// Backoff strategy of a [`RetryProfile`], see [`crate::retry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryStrategy {
+    Exponential,
+    Decorrelated,
+    /// `base` between attempts
+    Fixed,
+    None,
+}
+
+/// How the calls of a profile are retried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryProfile {
+    pub strategy: RetryStrategy,
+    pub base: Duration,
+    pub max_delay: Duration,
+    /// the first attempt included
+    pub max_attempts: u32,
+}
+
+impl RetryProfile {
+    /// a fresh strategy, jittered ones with their own generator
+    pub fn backoff(&self) -> Box<dyn Backoff> {
+        match self.strategy {
+            RetryStrategy::Exponential => Box::new(
+                ExponentialFullJitter::new(self.base, self.max_delay)
+                    .max_attempts(self.max_attempts),
+            ),
+            RetryStrategy::Decorrelated => Box::new(
+                DecorrelatedJitter::new(self.base, self.max_delay).max_attempts(self.max_attempts),
+            ),
+            RetryStrategy::Fixed => Box::new(retry::Fixed::new(self.base, self.max_attempts)),
+            RetryStrategy::None => Box::new(NoRetry),
+        }
+    }
+}
+
+/// Configuration of the calls to a bucket, see [`ProfiledBucket`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BucketProfile {
+    pub name: String,
+    /// no retry when `None`
+    pub retry: Option<RetryProfile>,
+    /// of each attempt
+    pub timeout: Option<Duration>,
+    /// of the objects uploaded by [`ProfiledBucket::upload`]
+    pub content_type: Option<String>,
+    /// largest object [`ProfiledBucket::download`] reads into memory
+    pub max_in_memory: Option<ByteSize>,
+}
+
+/// Configuration of the pushes to a queue, see [`ProfiledQueue`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueProfile {
+    pub name: String,
+    pub retry: Option<RetryProfile>,
+    pub timeout: Option<Duration>,
+    /// added to the pushed tasks that don't set them
+    pub headers: HashMap<String, String>,
+    /// pushes started per second, by every clone of the registry
+    pub max_per_second: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManifest {
+    #[serde(default)]
+    bucket_profiles: Vec<RawBucketProfile>,
+    #[serde(default)]
+    queue_profiles: Vec<RawQueueProfile>,
+    default_bucket_profile: Option<String>,
+    default_queue_profile: Option<String>,
+    #[serde(default)]
+    buckets: BTreeMap<String, String>,
+    #[serde(default)]
+    queues: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetry {
+    strategy: RetryStrategy,
+    base: Option<String>,
+    max_delay: Option<String>,
+    max_attempts: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSize {
+    Bytes(u64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBucketProfile {
+    name: String,
+    retry: Option<RawRetry>,
+    timeout: Option<String>,
+    content_type: Option<String>,
+    max_in_memory: Option<RawSize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQueueProfile {
+    name: String,
+    retry: Option<RawRetry>,
+    timeout: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    max_per_second: Option<u32>,
+}
+
+fn duration(path: &str, text: Option<&str>) -> Result<Option<Duration>, Error> {
+    text.map(|text| parse_duration(text).map_err(|message| invalid(path, message)))
+        .transpose()
+}
+
+fn timeout(path: &str, text: Option<&str>) -> Result<Option<Duration>, Error> {
+    match duration(path, text)? {
+        Some(timeout) if timeout.is_zero() => Err(invalid(path, "timeout must not be zero")),
+        timeout => Ok(timeout),
+    }
+}
+
+impl RawRetry {
+    fn validate(self, path: &str) -> Result<RetryProfile, Error> {
+        let base =
+            duration(&format!("{path}.base"), self.base.as_deref())?.unwrap_or(DEFAULT_RETRY_BASE);
+        let max_delay = duration(&format!("{path}.max_delay"), self.max_delay.as_deref())?
+            .unwrap_or(DEFAULT_RETRY_MAX_DELAY);
+        if max_delay < base {
+            return Err(invalid(
+                format!("{path}.max_delay"),
+                format!("{max_delay:?} is shorter than the base delay of {base:?}"),
+            ));
+        }
+        let max_attempts = self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        if max_attempts == 0 {
+            return Err(invalid(
+                format!("{path}.max_attempts"),
+                "at least one attempt is made",
+            ));
+        }
+
+        Ok(RetryProfile {
+            strategy: self.strategy,
+            base,
+            max_delay,
+            max_attempts,
+        })
+    }
+}
+
+/// profiles by name, checking every name is declared once
+fn by_name<R, P>(
+    field: &str,
+    raw: Vec<R>,
+    name_of: impl Fn(&R) -> &str,
+    validate: impl Fn(R, &str) -> Result<P, Error>,
+) -> Result<HashMap<String, Arc<P>>, Error> {
+    let mut profiles = HashMap::new();
+    for (i, raw) in raw.into_iter().enumerate() {
+        let path = format!("{field}[{i}]");
+        let name = name_of(&raw).to_owned();
+        if name.is_empty() {
+            return Err(invalid(format!("{path}.name"), "profile name is empty"));
+        }
+        if profiles.contains_key(&name) {
+            return Err(invalid(
+                format!("{path}.name"),
+                format!("profile {name:?} is declared more than once"),
+            ));
+        }
+        let profile = validate(raw, &path)?;
+        profiles.insert(name, Arc::new(profile));
+    }
+
+    Ok(profiles)
+}
+
+/// the declared profile `name` refers to
+fn resolve<P>(
+    profiles: &HashMap<String, Arc<P>>,
+    kind: &str,
+    path: &str,
+    name: &str,
+) -> Result<Arc<P>, Error> {
+    profiles
+        .get(name)
+        .cloned()
+        .ok_or_else(|| invalid(path, format!("unknown {kind} profile {name:?}")))
+}
+
+/// Validated profiles and their assignment to buckets and queues, see the [module](self) docs
+///
+/// A resource the manifest doesn't list gets the declared default profile, or an empty profile
+/// (no retry, no timeout, no defaults) without one.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    bucket_profiles: HashMap<String, Arc<BucketProfile>>,
+    queue_profiles: HashMap<String, Arc<QueueProfile>>,
+    default_bucket: Arc<BucketProfile>,
+    default_queue: Arc<QueueProfile>,
+    buckets: HashMap<String, Arc<BucketProfile>>,
+    queues: HashMap<String, Arc<QueueProfile>>,
+}
+
+impl Manifest {
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        let raw: RawManifest =
+            serde_json::from_slice(json).map_err(|e| Error::Parse(e.to_string()))?;
+        Self::validate(raw)
+    }
+
+    fn validate(raw: RawManifest) -> Result<Self, Error> {
+        let bucket_profiles = by_name(
+            "bucket_profiles",
+            raw.bucket_profiles,
+            |p| &p.name,
+            |p, path| {
+                let max_in_memory =
+                    match p.max_in_memory {
+                        None => None,
+                        Some(RawSize::Bytes(bytes)) => Some(ByteSize::b(bytes)),
+                        Some(RawSize::Text(text)) => Some(text.parse().map_err(|e| {
+                            invalid(format!("{path}.max_in_memory"), format!("{e}"))
+                        })?),
+                    };
+                Ok(BucketProfile {
+                    retry: p
+                        .retry
+                        .map(|r| r.validate(&format!("{path}.retry")))
+                        .transpose()?,
+                    timeout: timeout(&format!("{path}.timeout"), p.timeout.as_deref())?,
+                    content_type: p.content_type,
+                    max_in_memory,
+                    name: p.name,
+                })
+            },
+        )?;
+        let queue_profiles = by_name(
+            "queue_profiles",
+            raw.queue_profiles,
+            |p| &p.name,
+            |p, path| {
+                if p.max_per_second == Some(0) {
+                    return Err(invalid(
+                        format!("{path}.max_per_second"),
+                        "rate must not be zero",
+                    ));
+                }
+                Ok(QueueProfile {
+                    retry: p
+                        .retry
+                        .map(|r| r.validate(&format!("{path}.retry")))
+                        .transpose()?,
+                    timeout: timeout(&format!("{path}.timeout"), p.timeout.as_deref())?,
+                    headers: p.headers,
+                    max_per_second: p.max_per_second,
+                    name: p.name,
+                })
+            },
+        )?;
+
+        let default_bucket = match raw.default_bucket_profile.as_deref() {
+            Some(name) => resolve(&bucket_profiles, "bucket", "default_bucket_profile", name)?,
+            None => Arc::default(),
+        };
+        let default_queue = match raw.default_queue_profile.as_deref() {
+            Some(name) => resolve(&queue_profiles, "queue", "default_queue_profile", name)?,
+            None => Arc::default(),
+        };
+        let buckets = raw
+            .buckets
+            .iter()
+            .map(|(bucket, name)| {
+                let path = format!("buckets.{bucket}");
+                Ok((
+                    bucket.clone(),
+                    resolve(&bucket_profiles, "bucket", &path, name)?,
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+        let queues = raw
+            .queues
+            .iter()
+            .map(|(queue, name)| {
+                let path = format!("queues.{queue}");
+                Ok((
+                    queue.clone(),
+                    resolve(&queue_profiles, "queue", &path, name)?,
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            bucket_profiles,
+            queue_profiles,
+            default_bucket,
+            default_queue,
+            buckets,
+            queues,
+        })
+    }
+
+    pub fn bucket_profile(&self, name: &str) -> Option<&BucketProfile> {
+        self.bucket_profiles.get(name).map(Arc::as_ref)
+    }
+
+    pub fn queue_profile(&self, name: &str) -> Option<&QueueProfile> {
+        self.queue_profiles.get(name).map(Arc::as_ref)
+    }
+
+    /// profile `bucket` is assigned, the default one if it isn't listed
+    pub fn profile_of_bucket(&self, bucket: &str) -> &BucketProfile {
+        self.buckets.get(bucket).unwrap_or(&self.default_bucket)
+    }
+
+    /// profile `queue` (full path) is assigned, the default one if it isn't listed
+    pub fn profile_of_queue(&self, queue: &str) -> &QueueProfile {
+        self.queues.get(queue).unwrap_or(&self.default_queue)
+    }
+
+    /// names of the resources listed in the manifest, sorted
+    pub fn buckets(&self) -> Vec<&str> {
+        let mut buckets: Vec<&str> = self.buckets.keys().map(String::as_str).collect();
+        buckets.sort_unstable();
+        buckets
+    }
+
+    pub fn queues(&self) -> Vec<&str> {
+        let mut queues: Vec<&str> = self.queues.keys().map(String::as_str).collect();
+        queues.sort_unstable();
+        queues
+    }
+
+    /// names of the declared profiles no resource nor default uses
+    pub fn unused_profiles(&self) -> Vec<&str> {
+        fn unused<'a, P>(
+            declared: &'a HashMap<String, Arc<P>>,
+            used: impl Iterator<Item = &'a Arc<P>> + Clone,
+        ) -> impl Iterator<Item = &'a str> {
+            declared
+                .iter()
+                .filter(move |(_, p)| !used.clone().any(|u| Arc::ptr_eq(u, p)))
+                .map(|(name, _)| name.as_str())
+        }
+
+        let buckets = self.buckets.values().chain([&self.default_bucket]);
+        let queues = self.queues.values().chain([&self.default_queue]);
+        let mut names: Vec<&str> = unused(&self.bucket_profiles, buckets)
+            .chain(unused(&self.queue_profiles, queues))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+impl<'de> Deserialize<'de> for Manifest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::validate(RawManifest::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// run `call` with the retries and the per-attempt timeout of a profile
+async fn run_profiled<R, F, Fut>(
+    retry: Option<&RetryProfile>,
+    timeout: Option<Duration>,
+    timed_out: impl Fn(Duration) -> NimbusError,
+    mut call: F,
+) -> Result<R, NimbusError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, NimbusError>>,
+{
+    let mut backoff = retry.map_or_else(
+        || Box::new(NoRetry) as Box<dyn Backoff>,
+        RetryProfile::backoff,
+    );
+    let timed_out = &timed_out;
+
+    retry::retry(backoff.as_mut(), || {
+        let attempt = call();
+        async move {
+            match timeout {
+                Some(limit) => tokio::time::timeout(limit, attempt)
+                    .await
+                    .unwrap_or_else(|_| Err(timed_out(limit))),
+                None => attempt.await,
+            }
+        }
+    })
+    .await
+}
+
+/// paces the pushes to a queue
+#[cfg(feature = "gcp-tasks")]
+#[derive(Debug)]
+struct Pacer {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+#[cfg(feature = "gcp-tasks")]
+impl Pacer {
+    /// wait for the next free slot
+    async fn wait(&self) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let at = next.map_or_else(Instant::now, |next| next.max(Instant::now()));
+            *next = Some(at + self.interval);
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+/// Clients configured per resource by a [`Manifest`], see the [module](self) docs
+///
+/// Cloning is cheap, clones share the manifest, the clients and the rate limits of the queues.
+#[derive(Debug)]
+pub struct ProfileRegistry<S, T = ()> {
+    manifest: Arc<Manifest>,
+    storage: Arc<S>,
+    tasks: Arc<T>,
+    #[cfg(feature = "gcp-tasks")]
+    pacers: Arc<Mutex<HashMap<String, Arc<Pacer>>>>,
+}
+
+// derived `Clone` would require the clients to be `Clone` as well
+impl<S, T> Clone for ProfileRegistry<S, T> {
+    fn clone(&self) -> Self {
+        Self {
+            manifest: Arc::clone(&self.manifest),
+            storage: Arc::clone(&self.storage),
+            tasks: Arc::clone(&self.tasks),
+            #[cfg(feature = "gcp-tasks")]
+            pacers: Arc::clone(&self.pacers),
+        }
+    }
+}
+
+impl<S> ProfileRegistry<S> {
+    pub fn new(manifest: Manifest, storage: S) -> Self {
+        Self {
+            manifest: Arc::new(manifest),
+            storage: Arc::new(storage),
+            tasks: Arc::new(()),
+            #[cfg(feature = "gcp-tasks")]
+            pacers: Arc::default(),
+        }
+    }
+}
+
+impl<S, T> ProfileRegistry<S, T> {
+    /// the cloud tasks client of [`ProfileRegistry::tasks_for`]
+    pub fn with_tasks<U>(self, tasks: U) -> ProfileRegistry<S, U> {
+        ProfileRegistry {
+            manifest: self.manifest,
+            storage: self.storage,
+            tasks: Arc::new(tasks),
+            #[cfg(feature = "gcp-tasks")]
+            pacers: self.pacers,
+        }
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// `bucket` with the profile the manifest assigns it
+    pub fn storage_for(&self, bucket: &str) -> ProfiledBucket<S> {
+        let profile = match self.manifest.buckets.get(bucket) {
+            Some(profile) => profile.clone(),
+            None => self.manifest.default_bucket.clone(),
+        };
+        self.bucket_with(bucket, profile)
+    }
+
+    /// `bucket` with the declared profile `profile`, whatever the manifest assigns it
+    pub fn storage_with_profile(
+        &self,
+        bucket: &str,
+        profile: &str,
+    ) -> Result<ProfiledBucket<S>, Error> {
+        let profile =
+            self.manifest
+                .bucket_profiles
+                .get(profile)
+                .ok_or_else(|| Error::UnknownProfile {
+                    kind: "bucket",
+                    name: profile.to_owned(),
+                })?;
+        Ok(self.bucket_with(bucket, profile.clone()))
+    }
+
+    fn bucket_with(&self, bucket: &str, profile: Arc<BucketProfile>) -> ProfiledBucket<S> {
+        ProfiledBucket {
+            storage: Arc::clone(&self.storage),
+            bucket: bucket.to_owned(),
+            profile,
+        }
+    }
+
+    /// `queue` (full path) with the profile the manifest assigns it
+    #[cfg(feature = "gcp-tasks")]
+    pub fn tasks_for(&self, queue: &str) -> ProfiledQueue<T> {
+        let profile = match self.manifest.queues.get(queue) {
+            Some(profile) => profile.clone(),
+            None => self.manifest.default_queue.clone(),
+        };
+        let pacer = profile.max_per_second.map(|rate| {
+            self.pacers
+                .lock()
+                .unwrap()
+                .entry(queue.to_owned())
+                .or_insert_with(|| {
+                    Arc::new(Pacer {
+                        interval: Duration::from_secs(1) / rate,
+                        next: Mutex::new(None),
+                    })
+                })
+                .clone()
+        });
+
+        ProfiledQueue {
+            tasks: Arc::clone(&self.tasks),
+            queue: queue.to_owned(),
+            profile,
+            pacer,
+        }
+    }
+}
+
+/// A bucket and the profile its calls are made with, from [`ProfileRegistry::storage_for`]
+#[derive(Debug)]
+pub struct ProfiledBucket<S> {
+    storage: Arc<S>,
+    bucket: String,
+    profile: Arc<BucketProfile>,
+}
+
+impl<S> Clone for ProfiledBucket<S> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: Arc::clone(&self.storage),
+            bucket: self.bucket.clone(),
+            profile: Arc::clone(&self.profile),
+        }
+    }
+}
+
+impl<S: StorageHelper + Send + Sync> ProfiledBucket<S> {
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn profile(&self) -> &BucketProfile {
+        &self.profile
+    }
+
+    /// the shared client, for calls made without the profile
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// run a call to the bucket with the retries and the timeout of the profile
+    ///
+    /// ```ignore
+    /// let meta = uploads.run(|| uploads.storage().object_metadata(uploads.bucket(), "a.png")).await?;
+    /// ```
+    pub async fn run<R, F, Fut>(&self, call: F) -> Result<R, NimbusError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, NimbusError>>,
+    {
+        let bucket = &self.bucket;
+        run_profiled(
+            self.profile.retry.as_ref(),
+            self.profile.timeout,
+            |limit| storage::Error::Timeout(format!("{bucket}: no answer within {limit:?}")).into(),
+            call,
+        )
+        .await
+    }
+
+    /// upload with the content type of the profile
+    pub async fn upload(&self, key: &str, data: Vec<u8>) -> Result<(), NimbusError> {
+        self.run(|| {
+            self.storage.upload_from_bytes(
+                &self.bucket,
+                key,
+                self.profile.content_type.clone(),
+                data.clone(),
+            )
+        })
+        .await
+    }
+
+    /// download into memory, fails with [`storage::Error::ObjectTooLarge`] past the `max_in_memory` of the profile
+    /// the size is checked first, an object growing in between is still caught once downloaded
+    pub async fn download(&self, key: &str) -> Result<Vec<u8>, NimbusError> {
+        let too_large = |limit: ByteSize| -> NimbusError {
+            storage::Error::ObjectTooLarge {
+                key: format!("{}/{key}", self.bucket),
+                limit,
+            }
+            .into()
+        };
+
+        if let Some(limit) = self.profile.max_in_memory {
+            let meta = self
+                .run(|| self.storage.object_metadata(&self.bucket, key))
+                .await?;
+            if meta.size > limit.bytes() {
+                return Err(too_large(limit));
+            }
+        }
+
+        let data = self
+            .run(|| self.storage.download_to_bytes(&self.bucket, key))
+            .await?;
+        match self.profile.max_in_memory {
+            Some(limit) if data.len() as u64 > limit.bytes() => Err(too_large(limit)),
+            _ => Ok(data),
+        }
+    }
+}
+
+/// A queue and the profile its pushes are made with, from [`ProfileRegistry::tasks_for`]
+#[cfg(feature = "gcp-tasks")]
+#[derive(Debug)]
+pub struct ProfiledQueue<T> {
+    tasks: Arc<T>,
+    queue: String,
+    profile: Arc<QueueProfile>,
+    pacer: Option<Arc<Pacer>>,
+}
+
+#[cfg(feature = "gcp-tasks")]
+impl<T> ProfiledQueue<T> {
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    pub fn profile(&self) -> &QueueProfile {
+        &self.profile
+    }
+
+    pub fn tasks(&self) -> &T {
+        &self.tasks
+    }
+
+    /// push with the headers, rate limit, retries and timeout of the profile, returns the created task
+    /// a named task pushed again after a timed out attempt that went through fails with `AlreadyExists`
+    pub async fn push<C>(&self, mut task: Task) -> Result<Task, NimbusError>
+    where
+        T: CloudTaskHelper<C> + Send + Sync,
+    {
+        if let Some(request) = task.http_request.as_mut() {
+            let headers = request.headers.get_or_insert_with(HashMap::new);
+            for (name, value) in &self.profile.headers {
+                if !headers.keys().any(|h| h.eq_ignore_ascii_case(name)) {
+                    headers.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        if let Some(pacer) = &self.pacer {
+            pacer.wait().await;
+        }
+
+        let queue = &self.queue;
+        let task = &task;
+        run_profiled(
+            self.profile.retry.as_ref(),
+            self.profile.timeout,
+            |limit| task::Error::Timeout(format!("{queue}: no answer within {limit:?}")).into(),
+            || async move {
+                self.tasks
+                    .push_task_ref(queue, task, TaskOverrides::new(), None)
+                    .await
+                    .map(|(_, task)| task)
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    const MANIFEST: &str = r#"{
+        "bucket_profiles": [
+            { "name": "standard", "timeout": "2s",
+              "retry": { "strategy": "fixed", "base": "100ms", "max_attempts": 3 } },
+            { "name": "uploads", "content_type": "image/png", "max_in_memory": "1KiB" },
+            { "name": "spare" }
+        ],
+        "queue_profiles": [
+            { "name": "emails", "max_per_second": 10, "headers": { "Content-Type": "application/json" } }
+        ],
+        "default_bucket_profile": "standard",
+        "buckets": { "user-uploads": "uploads" },
+        "queues": { "projects/p/locations/l/queues/emails": "emails" }
+    }"#;
+
+    fn error_of(json: &str) -> (String, String) {
+        match Manifest::from_json(json.as_bytes()).unwrap_err() {
+            Error::Invalid { path, message } => (path, message),
+            e => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn manifest_test() {
+        let manifest = Manifest::from_json(MANIFEST.as_bytes()).unwrap();
+
+        let standard = manifest.bucket_profile("standard").unwrap();
+        assert_eq!(standard.timeout, Some(Duration::from_secs(2)));
+        let retry = standard.retry.as_ref().unwrap();
+        assert_eq!(
+            (
+                retry.strategy,
+                retry.base,
+                retry.max_delay,
+                retry.max_attempts
+            ),
+            (
+                RetryStrategy::Fixed,
+                Duration::from_millis(100),
+                DEFAULT_RETRY_MAX_DELAY,
+                3
+            )
+        );
+
+        let uploads = manifest.profile_of_bucket("user-uploads");
+        assert_eq!(uploads.name, "uploads");
+        assert_eq!(uploads.max_in_memory, Some(ByteSize::kib(1)));
+        assert_eq!(manifest.profile_of_bucket("logs").name, "standard");
+        // no default queue profile declared
+        assert_eq!(manifest.profile_of_queue("other"), &QueueProfile::default());
+        assert_eq!(
+            manifest
+                .profile_of_queue("projects/p/locations/l/queues/emails")
+                .max_per_second,
+            Some(10)
+        );
+
+        assert_eq!(manifest.buckets(), ["user-uploads"]);
+        assert_eq!(manifest.unused_profiles(), ["spare"]);
+
+        // the same through serde
+        let deserialized: Manifest = serde_json::from_str(MANIFEST).unwrap();
+        assert_eq!(deserialized.profile_of_bucket("user-uploads"), uploads);
+    }
+
+    #[test]
+    fn manifest_error_test() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration(" 5m "), Ok(Duration::from_secs(300)));
+        for text in ["", "5", "1.5s", "-1s", "5 days"] {
+            assert!(parse_duration(text).is_err(), "{text}");
+        }
+
+        let (path, message) =
+            error_of(r#"{ "bucket_profiles": [{ "name": "a" }, { "name": "a" }] }"#);
+        assert_eq!(path, "bucket_profiles[1].name");
+        assert!(message.contains("more than once"));
+
+        let (path, message) = error_of(
+            r#"{ "queue_profiles": [{ "name": "q", "retry": { "strategy": "exponential", "base": "3x" } }] }"#,
+        );
+        assert_eq!(path, "queue_profiles[0].retry.base");
+        assert!(message.contains("\"3x\""));
+
+        let (path, _) = error_of(r#"{ "bucket_profiles": [{ "name": "a", "timeout": "0s" }] }"#);
+        assert_eq!(path, "bucket_profiles[0].timeout");
+        let (path, _) =
+            error_of(r#"{ "bucket_profiles": [{ "name": "a", "max_in_memory": "lots" }] }"#);
+        assert_eq!(path, "bucket_profiles[0].max_in_memory");
+
+        let (path, message) = error_of(r#"{ "buckets": { "user-uploads": "missing" } }"#);
+        assert_eq!(path, "buckets.user-uploads");
+        assert!(message.contains("unknown bucket profile \"missing\""));
+        let (path, _) = error_of(r#"{ "default_queue_profile": "missing" }"#);
+        assert_eq!(path, "default_queue_profile");
+
+        let err = Manifest::from_json(br#"{ "bucket": {} }"#).unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+
+        let err = serde_json::from_str::<Manifest>(r#"{ "buckets": { "b": "x" } }"#).unwrap_err();
+        assert!(err.to_string().contains("buckets.b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registry_storage_test() {
+        let manifest = Manifest::from_json(MANIFEST.as_bytes()).unwrap();
+        let registry = ProfileRegistry::new(manifest, MemoryStorage::new());
+        let storage = registry.storage_for("logs").storage().clone();
+
+        let uploads = registry.clone().storage_for("user-uploads");
+        uploads.upload("small.png", vec![0; 1024]).await.unwrap();
+        let meta = storage
+            .object_metadata("user-uploads", "small.png")
+            .await
+            .unwrap();
+        assert_eq!(meta.content_type.as_deref(), Some("image/png"));
+        assert_eq!(uploads.download("small.png").await.unwrap().len(), 1024);
+
+        uploads.upload("large.png", vec![0; 1025]).await.unwrap();
+        let err = uploads.download("large.png").await.unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(storage::Error::ObjectTooLarge { .. })
+        ));
+        assert_eq!(storage.stats().calls("download_to_bytes"), 1);
+
+        // the default profile retries then times out each attempt
+        let logs = registry.storage_for("logs");
+        storage.mock_stats().set_fault(
+            "download_to_bytes",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        let err = logs.download("small.png").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert_eq!(storage.stats().calls("download_to_bytes"), 4);
+
+        storage.mock_stats().set_fault(
+            "object_metadata",
+            Fault::new().latency(Latency::Fixed(Duration::from_secs(3))),
+        );
+        let err = logs
+            .run(|| logs.storage().object_metadata("logs", "small.png"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Timeout);
+
+        assert!(registry.storage_with_profile("b", "spare").is_ok());
+        let err = registry.storage_with_profile("b", "missing").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+}
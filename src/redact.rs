@@ -0,0 +1,338 @@
+//! What the crate considers sensitive, and how it is kept out of errors, traces and debug output
+//!
+//! A [`Policy`] lists the headers whose values are masked (in tasks printed or logged), the storage keys
+//! that are only ever shown hashed, and whether errors name the resources they are about at all.
+//! The process-wide policy is set once at startup with [`set_global_policy`]; clients taking a policy
+//! of their own, like [`crate::trace::TraceConfig::redact`], override it.
+//!
+//! Whatever the policy, secret payloads and the content of documents are never part of an error,
+//! a span or a `Debug` output: errors about a document give the position of the problem, not its content.
+//!
+//! ```ignore
+//! redact::set_global_policy(
+//!     Policy::new()
+//!         .hash_keys_under("users/")
+//!         .sensitive_header("X-Internal-Token"),
+//! )
+//! .expect("set once, at startup");
+//! ```
+
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use sha2::{Digest, Sha256};
+
+/// Length of the hashes shown in place of sensitive identifiers, in hex characters
+pub const HASH_LEN: usize = 12;
+
+/// Headers masked by the default policy, lowercase
+pub const DEFAULT_SENSITIVE_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// Shown in place of what is redacted
+pub const REDACTED: &str = "<redacted>";
+
+static GLOBAL_POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// first [`HASH_LEN`] hex characters of the SHA-256 of `value`
+pub fn short_hash(value: &str) -> String {
+    let mut hash = hex::encode(Sha256::digest(value.as_bytes()));
+    hash.truncate(HASH_LEN);
+    hash
+}
+
+/// Sensitive headers, keys and resource names, see the [module documentation](self)
+///
+/// The default masks [`DEFAULT_SENSITIVE_HEADERS`], hashes no key and names resources in errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    sensitive_headers: Vec<String>,
+    hashed_key_prefixes: Vec<String>,
+    resource_names: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            sensitive_headers: DEFAULT_SENSITIVE_HEADERS
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            hashed_key_prefixes: vec![],
+            resource_names: true,
+        }
+    }
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the process-wide policy, the default one until [`set_global_policy`]
+    pub fn global() -> &'static Policy {
+        GLOBAL_POLICY.get_or_init(Policy::default)
+    }
+
+    /// also mask `header`, matched case-insensitively
+    pub fn sensitive_header(mut self, header: &str) -> Self {
+        self.sensitive_headers.push(header.to_ascii_lowercase());
+        self
+    }
+
+    /// only ever show the keys starting with `prefix` hashed, see [`short_hash`]
+    pub fn hash_keys_under(mut self, prefix: impl Into<String>) -> Self {
+        self.hashed_key_prefixes.push(prefix.into());
+        self
+    }
+
+    /// name the buckets, keys and secrets errors are about, true by default
+    /// when false, errors say what went wrong but not on what
+    pub fn resource_names(mut self, include: bool) -> Self {
+        self.resource_names = include;
+        self
+    }
+
+    pub fn sensitive_headers(&self) -> impl Iterator<Item = &str> {
+        self.sensitive_headers.iter().map(String::as_str)
+    }
+
+    pub fn is_sensitive_header(&self, header: &str) -> bool {
+        self.sensitive_headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(header))
+    }
+
+    /// whether `key` is only ever shown hashed
+    pub fn hashes_key(&self, key: &str) -> bool {
+        self.hashed_key_prefixes.iter().any(|p| key.starts_with(p))
+    }
+
+    /// `key` as it may be shown
+    pub fn key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if self.hashes_key(key) {
+            Cow::Owned(short_hash(key))
+        } else {
+            Cow::Borrowed(key)
+        }
+    }
+
+    /// identifier of a resource, a key or `bucket/key`, as an error may show it
+    pub fn resource<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        if !self.resource_names {
+            return Cow::Borrowed(REDACTED);
+        }
+        if self.hashes_key(id) {
+            return Cow::Owned(short_hash(id));
+        }
+        match id.split_once('/') {
+            Some((bucket, key)) if self.hashes_key(key) => {
+                Cow::Owned(format!("{bucket}/{}", short_hash(key)))
+            }
+            _ => Cow::Borrowed(id),
+        }
+    }
+}
+
+/// make `policy` the process-wide one, can only be done once, before any error is shown preferably
+/// returns the policy back if one is set already
+pub fn set_global_policy(policy: Policy) -> Result<(), Policy> {
+    GLOBAL_POLICY.set(policy)
+}
+
+/// `id` as errors show it under the global policy, see [`Policy::resource`]
+pub(crate) fn resource(id: &str) -> Cow<'_, str> {
+    Policy::global().resource(id)
+}
+
+/// `value` of `header` as errors show it, masked if the header is sensitive
+#[cfg(feature = "gcp-tasks")]
+pub(crate) fn header_value<'a>(header: &str, value: &'a str) -> Cow<'a, str> {
+    if Policy::global().is_sensitive_header(header) {
+        Cow::Borrowed(REDACTED)
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// what went wrong parsing a document, without the values serde_json quotes from it
+pub(crate) fn json_error(e: &serde_json::Error) -> String {
+    use serde_json::error::Category;
+
+    let what = match e.classify() {
+        Category::Io => "I/O error",
+        Category::Syntax => "syntax error",
+        Category::Data => "unexpected data",
+        Category::Eof => "unexpected end",
+    };
+    if e.line() == 0 {
+        what.to_owned()
+    } else {
+        format!("{what} at line {} column {}", e.line(), e.column())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // the leak tests go through every client, the secret manager included
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    use crate::{
+        secret::{SourceChain, SourceChainSet},
+        storage::UpdateOptions,
+        testing::{Fault, MemorySecretManager, MemoryStorage},
+        ErrorCode, NimbusError, SecretManagerHelper, StorageHelper,
+    };
+
+    /// planted in every path below, must never come out
+    const PLANTED: &str = "pl4nted-s3cret-v4lue";
+
+    #[test]
+    fn policy_test() {
+        let policy = Policy::new().hash_keys_under("users/");
+        assert!(policy.is_sensitive_header("Set-Cookie"));
+        assert!(!policy.is_sensitive_header("Content-Type"));
+        assert!(policy
+            .clone()
+            .sensitive_header("X-Token")
+            .is_sensitive_header("x-token"));
+
+        assert_eq!(policy.key("reports/a"), "reports/a");
+        assert_eq!(policy.key("users/42"), short_hash("users/42"));
+        assert_eq!(policy.resource("b/reports/a"), "b/reports/a");
+        assert_eq!(
+            policy.resource("b/users/42"),
+            format!("b/{}", short_hash("users/42"))
+        );
+        assert_eq!(
+            policy.resource_names(false).resource("b/reports/a"),
+            REDACTED
+        );
+
+        assert_eq!(Policy::global(), &Policy::default());
+    }
+
+    #[test]
+    fn json_error_test() {
+        let e = serde_json::from_str::<u64>(&format!("\"{PLANTED}\"")).unwrap_err();
+        assert!(e.to_string().contains(PLANTED));
+        assert_eq!(json_error(&e), "unexpected data at line 1 column 22");
+    }
+
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    /// every way the output of the crate is shown
+    fn outputs(error: &NimbusError) -> [String; 3] {
+        [
+            error.to_string(),
+            format!("{error:?}"),
+            format!("{:?}", error.summary()),
+        ]
+    }
+
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    #[tokio::test(start_paused = true)]
+    async fn no_leak_test() {
+        let mut shown = vec![];
+
+        let secrets = MemorySecretManager::new();
+        secrets.add_version("p", "db-password", PLANTED);
+        shown.push(format!("{secrets:?}"));
+        let wrong_version = secrets
+            .get_secret_version("p", "db-password", "7")
+            .await
+            .unwrap_err();
+        shown.extend(outputs(&wrong_version));
+        secrets.mock_stats().set_fault(
+            "get_secret_version",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        shown.extend(outputs(
+            &secrets.get_secret("p", "db-password").await.unwrap_err(),
+        ));
+        secrets.mock_stats().clear_faults();
+
+        let chain = SourceChain::new()
+            .secret_manager("p", "db-password")
+            .default_value(PLANTED);
+        shown.push(format!("{chain:?}"));
+        let value = chain.resolve(&secrets).await.unwrap();
+        shown.push(format!("{value:?}"));
+        let strict = SourceChain::new()
+            .secret_manager("p", "missing")
+            .default_value(PLANTED)
+            .strict(true);
+        shown.extend(outputs(&strict.resolve(&secrets).await.unwrap_err()));
+        let outcome = SourceChainSet::new()
+            .chain("db", chain)
+            .chain("strict", strict)
+            .resolve(&secrets)
+            .await;
+        shown.push(format!("{outcome:?}"));
+
+        let storage = MemoryStorage::new();
+        storage
+            .upload_from_bytes(
+                "b",
+                "state.json",
+                None,
+                format!("\"{PLANTED}\"").into_bytes(),
+            )
+            .await
+            .unwrap();
+        let not_a_count = storage
+            .update_json(
+                "b",
+                "state.json",
+                |n: Option<u64>| Ok::<_, NimbusError>(n.unwrap_or_default() + 1),
+                UpdateOptions::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(not_a_count.code(), ErrorCode::InvalidInput);
+        shown.extend(outputs(&not_a_count));
+
+        assert!(shown.len() > 10);
+        for output in &shown {
+            assert!(!output.contains(PLANTED), "leaked in {output}");
+        }
+    }
+
+    #[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn no_leak_in_traces_test() {
+        use crate::trace::tests::Capture;
+        use crate::trace::{KeyRecording, TraceConfig, Traced};
+
+        let (capture, _guard) = Capture::install();
+        let secrets = Traced::new(MemorySecretManager::new())
+            .with_config(TraceConfig::new().record_keys(KeyRecording::Full));
+        secrets.create_secret("p", "s", PLANTED).await.unwrap();
+        secrets.get_secret("p", "s").await.unwrap();
+        secrets.get_secret_version("p", "s", "9").await.unwrap_err();
+
+        let storage = Traced::new(MemoryStorage::new()).with_config(
+            TraceConfig::new()
+                .record_keys(KeyRecording::Full)
+                .redact(Policy::new().hash_keys_under("users/")),
+        );
+        let key = format!("users/{PLANTED}");
+        storage
+            .upload_from_bytes("b", &key, None, PLANTED.into())
+            .await
+            .unwrap();
+        storage.download_to_bytes("b", &key).await.unwrap();
+
+        let spans = capture.spans();
+        assert_eq!(spans.len(), 5);
+        assert_eq!(spans[3]["key"], short_hash(&key));
+        for span in &spans {
+            assert!(span.values().all(|v| !v.contains(PLANTED)), "{span:?}");
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Common interface of the reports returned by batch and long-running operations
+//!
+//! Every report prints the same way: a summary line with its counts, then one section per kind of problem,
+//! each listing at most [`MAX_SAMPLES`] entries followed by `... and N more`. Lines are cut at
+//! [`MAX_LINE_WIDTH`] characters. Counts and sections always come in the same order, and entries
+//! are sorted, so the output of two runs can be diffed.
+//!
+//! ```text
+//! copy: 1200 copied, 3 skipped, 7 failed
+//!   failed:
+//!     - logs/a.gz -> archive/a.gz: Storage error: Unavailable: ...
+//!     ... and 6 more
+//! ```
+//!
+//! With the `serde` feature reports serialize in full, for tooling rather than people.
+
+use std::fmt;
+
+/// Entries listed per section of a report
+pub const MAX_SAMPLES: usize = 5;
+
+/// Width past which the lines of a report are cut
+pub const MAX_LINE_WIDTH: usize = 120;
+
+/// Report of an operation, printed with `Display`, see the [module documentation](self)
+pub trait Report: fmt::Display {
+    /// first line of the report, the counts only
+    fn summary_line(&self) -> String;
+
+    /// whether anything is reported as failed or differing
+    fn has_failures(&self) -> bool;
+
+    fn is_success(&self) -> bool {
+        !self.has_failures()
+    }
+}
+
+/// `line` cut to [`MAX_LINE_WIDTH`] characters, ending with `...` if it was
+pub(crate) fn truncate(line: &str) -> String {
+    match line.char_indices().nth(MAX_LINE_WIDTH - 3) {
+        Some((end, _)) if line.chars().count() > MAX_LINE_WIDTH => format!("{}...", &line[..end]),
+        _ => line.to_owned(),
+    }
+}
+
+/// write the section `title` listing the first [`MAX_SAMPLES`] of `entries`, nothing if there are none
+pub(crate) fn write_section(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    entries: impl IntoIterator<Item = String>,
+) -> fmt::Result {
+    let mut entries = entries.into_iter().peekable();
+    if entries.peek().is_none() {
+        return Ok(());
+    }
+
+    writeln!(f)?;
+    write!(f, "  {title}:")?;
+    let mut more = 0;
+    for (i, entry) in entries.enumerate() {
+        if i < MAX_SAMPLES {
+            writeln!(f)?;
+            write!(f, "{}", truncate(&format!("    - {entry}")))?;
+        } else {
+            more += 1;
+        }
+    }
+    if more > 0 {
+        writeln!(f)?;
+        write!(f, "    ... and {more} more")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::preflight::{CheckResult, CheckStatus, Intent, Leftover, PreflightReport};
+    use crate::secret::{Drift, DriftReport};
+    use crate::storage::{CopyBatchReport, CopyEntry, DiffReason, DiffReport, Differing};
+    use crate::{BatchError, ErrorCode, NimbusError};
+
+    fn error(message: &str) -> NimbusError {
+        NimbusError::Other(message.to_owned())
+    }
+
+    #[test]
+    fn truncate_test() {
+        let short = "a".repeat(MAX_LINE_WIDTH);
+        assert_eq!(truncate(&short), short);
+        let long = "é".repeat(MAX_LINE_WIDTH + 1);
+        let cut = truncate(&long);
+        assert_eq!(cut.chars().count(), MAX_LINE_WIDTH);
+        assert!(cut.ends_with("é..."));
+    }
+
+    #[test]
+    fn copy_report_test() {
+        let report = CopyBatchReport {
+            copied: 1200,
+            skipped: 3,
+            failed: (0..7)
+                .rev()
+                .map(|i| BatchError {
+                    key: CopyEntry::new(format!("logs/{i}.gz"), format!("archive/{i}.gz")),
+                    error: error("unavailable"),
+                })
+                .collect(),
+        };
+        assert!(report.has_failures());
+        assert_eq!(
+            report.summary_line(),
+            "copy: 1200 copied, 3 skipped, 7 failed"
+        );
+        assert_eq!(
+            report.to_string(),
+            "copy: 1200 copied, 3 skipped, 7 failed
+  failed:
+    - logs/0.gz -> archive/0.gz: Error: unavailable
+    - logs/1.gz -> archive/1.gz: Error: unavailable
+    - logs/2.gz -> archive/2.gz: Error: unavailable
+    - logs/3.gz -> archive/3.gz: Error: unavailable
+    - logs/4.gz -> archive/4.gz: Error: unavailable
+    ... and 2 more"
+        );
+
+        let clean = CopyBatchReport {
+            copied: 2,
+            ..Default::default()
+        };
+        assert!(Report::is_success(&clean));
+        assert_eq!(clean.to_string(), "copy: 2 copied, 0 skipped, 0 failed");
+    }
+
+    #[test]
+    fn preflight_report_test() {
+        let check = |intent, status, code: Option<ErrorCode>, error: Option<&str>| CheckResult {
+            intent,
+            status,
+            code,
+            error: error.map(str::to_owned),
+            elapsed: Duration::from_millis(12),
+        };
+        let report = PreflightReport {
+            checks: vec![
+                check(
+                    Intent::ReadSecret {
+                        project: "p".to_owned(),
+                        name: "db".to_owned(),
+                    },
+                    CheckStatus::Passed,
+                    None,
+                    None,
+                ),
+                check(
+                    Intent::WriteObject {
+                        bucket: "b".to_owned(),
+                        prefix: "incoming/".to_owned(),
+                    },
+                    CheckStatus::Failed,
+                    Some(ErrorCode::PermissionDenied),
+                    Some(&format!("denied {}", "x".repeat(200))),
+                ),
+                check(
+                    Intent::DeleteObject {
+                        bucket: "b".to_owned(),
+                        prefix: "tmp/".to_owned(),
+                    },
+                    CheckStatus::TimedOut,
+                    Some(ErrorCode::Timeout),
+                    None,
+                ),
+            ],
+            leftovers: vec![Leftover {
+                resource: "b/tmp/probe".to_owned(),
+                error: "timed out".to_owned(),
+            }],
+        };
+        assert!(report.has_failures());
+        let printed = report.to_string();
+        assert_eq!(
+            printed,
+            format!(
+                "preflight: 3 checks, 1 passed, 1 failed, 1 timed out, 1 leftover
+  failed:
+    - write object b/incoming/: permission denied: denied {}...
+    - delete object b/tmp/: timed out
+  leftovers:
+    - b/tmp/probe: timed out",
+                "x".repeat(
+                    MAX_LINE_WIDTH
+                        - 3
+                        - "    - write object b/incoming/: permission denied: denied ".len()
+                )
+            )
+        );
+        assert!(printed.lines().all(|l| l.chars().count() <= MAX_LINE_WIDTH));
+    }
+
+    #[test]
+    fn diff_report_test() {
+        let report = DiffReport {
+            only_in_a: vec!["a".to_owned(), "b".to_owned()],
+            only_in_b: vec![],
+            differing: vec![
+                Differing {
+                    key: "c".to_owned(),
+                    reason: DiffReason::SizeMismatch { a: 1, b: 2 },
+                },
+                Differing {
+                    key: "d".to_owned(),
+                    reason: DiffReason::ContentMismatch { offset: 8 },
+                },
+                Differing {
+                    key: "e".to_owned(),
+                    reason: DiffReason::ChecksumUnavailable,
+                },
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "diff: 2 only in a, 0 only in b, 3 differing
+  only in a:
+    - a
+    - b
+  differing:
+    - c: size 1 != 2
+    - d: content differs from byte 8
+    - e: no checksum to compare"
+        );
+        assert!(Report::is_success(&DiffReport::default()));
+    }
+
+    #[test]
+    fn drift_report_test() {
+        let report = DriftReport {
+            drifted: vec![Drift {
+                name: "api-key".to_owned(),
+                pinned: "2".to_owned(),
+                latest: "3".to_owned(),
+            }],
+            failed: vec![BatchError {
+                key: "db".to_owned(),
+                error: error("destroyed"),
+            }],
+        };
+        assert_eq!(report.summary_line(), "drift: 1 drifted, 1 failed");
+        assert_eq!(
+            report.to_string(),
+            "drift: 1 drifted, 1 failed
+  drifted:
+    - api-key: pinned 2, latest 3
+  failed:
+    - db: Error: destroyed"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_test() {
+        let report = CopyBatchReport {
+            copied: 1,
+            skipped: 0,
+            failed: vec![BatchError {
+                key: CopyEntry::new("a", "b"),
+                error: error("boom"),
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"copied":1,"skipped":0,"failed":[{"key":{"src_key":"a","dst_key":"b"},"code":"internal","error":"Error: boom"}]}"#
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Backoff strategies for retrying failed calls
+//!
+//! A [`Backoff`] decides how long to wait before the next attempt, or to give up. Every strategy honors
+//! the delay a provider asked for ([`NimbusError::retry_after`]): it waits at least that long.
+//! Jittered strategies draw from a [`JitterRng`], seed it for reproducible delays in tests.
+//!
+//! ```ignore
+//! let mut backoff = ExponentialFullJitter::new(Duration::from_millis(100), Duration::from_secs(10));
+//! let data = retry::retry(&mut backoff, || storage.download_to_bytes("bucket", "key")).await?;
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+use crate::NimbusError;
+
+/// Attempts of the jittered strategies unless set otherwise, the first one included
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Decides the delay before each retry of a failed call
+pub trait Backoff: Send {
+    /// delay before the next attempt once `attempt` attempts failed (1 after the first failure),
+    /// the last one with `last_error`; `None` to give up
+    fn next_delay(&mut self, attempt: u32, last_error: &NimbusError) -> Option<Duration>;
+
+    /// forget the previous attempts, before reusing the strategy for another call
+    fn reset(&mut self) {}
+}
+
+impl<B: Backoff + ?Sized> Backoff for Box<B> {
+    fn next_delay(&mut self, attempt: u32, last_error: &NimbusError) -> Option<Duration> {
+        (**self).next_delay(attempt, last_error)
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+}
+
+/// `delay`, or longer if the provider asked to wait longer
+fn honor_retry_after(delay: Duration, last_error: &NimbusError) -> Duration {
+    last_error
+        .retry_after()
+        .map_or(delay, |after| delay.max(after))
+}
+
+/// Small seedable generator of the jitter (SplitMix64), not for anything security related
+#[derive(Debug, Clone)]
+pub struct JitterRng {
+    state: u64,
+}
+
+impl JitterRng {
+    /// generator drawing the same delays for the same seed
+    pub fn seeded(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// generator seeded differently on every call
+    pub fn from_entropy() -> Self {
+        Self::seeded(RandomState::new().hash_one(std::time::SystemTime::now()))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// duration drawn uniformly from `[low, high]`, `low` if `high` is below it
+    pub fn between(&mut self, low: Duration, high: Duration) -> Duration {
+        if high <= low {
+            return low;
+        }
+        let span = (high - low).as_nanos().min(u64::MAX as u128) as u64;
+        let offset = match span.checked_add(1) {
+            Some(n) => self.next_u64() % n,
+            None => self.next_u64(),
+        };
+        low + Duration::from_nanos(offset)
+    }
+}
+
+impl Default for JitterRng {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+/// Exponential backoff with full jitter: a delay drawn from `[0, min(max_delay, base * 2^(attempt - 1))]`
+///
+/// Spreads the retries of many clients failing at once the most, see
+/// <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>.
+#[derive(Debug, Clone)]
+pub struct ExponentialFullJitter {
+    base: Duration,
+    max_delay: Duration,
+    max_attempts: u32,
+    rng: JitterRng,
+}
+
+impl ExponentialFullJitter {
+    pub fn new(base: Duration, max_delay: Duration) -> Self {
+        Self {
+            base,
+            max_delay,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            rng: JitterRng::default(),
+        }
+    }
+
+    /// attempts before giving up, the first one included
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn rng(mut self, rng: JitterRng) -> Self {
+        self.rng = rng;
+        self
+    }
+}
+
+impl Backoff for ExponentialFullJitter {
+    fn next_delay(&mut self, attempt: u32, last_error: &NimbusError) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let ceiling = self
+            .base
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let delay = self.rng.between(Duration::ZERO, ceiling);
+        Some(honor_retry_after(delay, last_error))
+    }
+}
+
+/// Decorrelated jitter: a delay drawn from `[base, 3 * previous delay]`, capped at `max_delay`
+///
+/// Grows about as fast as exponential backoff but never waits less than `base`.
+#[derive(Debug, Clone)]
+pub struct DecorrelatedJitter {
+    base: Duration,
+    max_delay: Duration,
+    max_attempts: u32,
+    previous: Duration,
+    rng: JitterRng,
+}
+
+impl DecorrelatedJitter {
+    pub fn new(base: Duration, max_delay: Duration) -> Self {
+        Self {
+            base,
+            max_delay,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            previous: base,
+            rng: JitterRng::default(),
+        }
+    }
+
+    /// attempts before giving up, the first one included
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn rng(mut self, rng: JitterRng) -> Self {
+        self.rng = rng;
+        self
+    }
+}
+
+impl Backoff for DecorrelatedJitter {
+    fn next_delay(&mut self, attempt: u32, last_error: &NimbusError) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let high = self.previous.saturating_mul(3);
+        let delay = self.rng.between(self.base, high).min(self.max_delay);
+        self.previous = delay;
+        Some(honor_retry_after(delay, last_error))
+    }
+
+    fn reset(&mut self) {
+        self.previous = self.base;
+    }
+}
+
+/// The same delay before every retry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixed {
+    delay: Duration,
+    max_attempts: u32,
+}
+
+impl Fixed {
+    /// `max_attempts` counts the first attempt
+    pub fn new(delay: Duration, max_attempts: u32) -> Self {
+        Self {
+            delay,
+            max_attempts,
+        }
+    }
+}
+
+impl Backoff for Fixed {
+    fn next_delay(&mut self, attempt: u32, last_error: &NimbusError) -> Option<Duration> {
+        (attempt < self.max_attempts).then(|| honor_retry_after(self.delay, last_error))
+    }
+}
+
+/// Never retries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoRetry;
+
+impl Backoff for NoRetry {
+    fn next_delay(&mut self, _: u32, _: &NimbusError) -> Option<Duration> {
+        None
+    }
+}
+
+/// run `call` until it succeeds, fails with an error that isn't retryable ([`NimbusError::is_retryable`]),
+/// or `backoff` gives up; returns the last error then
+///
+/// The strategy is [reset](Backoff::reset) first, so one can be reused call after call.
+pub async fn retry<T, F, Fut>(backoff: &mut dyn Backoff, mut call: F) -> Result<T, NimbusError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, NimbusError>>,
+{
+    backoff.reset();
+
+    let mut attempt = 0;
+    loop {
+        let error = match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) if !e.is_retryable() => return Err(e),
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        match backoff.next_delay(attempt, &error) {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::storage::Error;
+
+    const BASE: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(5);
+
+    fn unavailable() -> NimbusError {
+        Error::Unavailable("down".to_owned()).into()
+    }
+
+    fn delays(backoff: &mut dyn Backoff, n: u32) -> Vec<Duration> {
+        (1..=n)
+            .map_while(|attempt| backoff.next_delay(attempt, &unavailable()))
+            .collect()
+    }
+
+    #[test]
+    fn full_jitter_bounds_test() {
+        let mut backoff = ExponentialFullJitter::new(BASE, MAX).max_attempts(u32::MAX);
+        for _ in 0..200 {
+            backoff.reset();
+            for (i, delay) in delays(&mut backoff, 12).into_iter().enumerate() {
+                assert!(
+                    delay <= (BASE * 2u32.pow(i as u32)).min(MAX),
+                    "{i}: {delay:?}"
+                );
+            }
+        }
+
+        // the delays are spread over the whole range
+        let mut rng = JitterRng::seeded(1);
+        let draws: Vec<Duration> = (0..1000)
+            .map(|_| rng.between(Duration::ZERO, MAX))
+            .collect();
+        let mean = draws.iter().sum::<Duration>() / 1000;
+        assert!(mean > MAX * 4 / 10 && mean < MAX * 6 / 10, "{mean:?}");
+    }
+
+    #[test]
+    fn decorrelated_jitter_bounds_test() {
+        let mut backoff = DecorrelatedJitter::new(BASE, MAX).max_attempts(u32::MAX);
+        let mut previous = BASE;
+        for delay in delays(&mut backoff, 1000) {
+            assert!(delay >= BASE && delay <= MAX, "{delay:?}");
+            assert!(delay <= previous * 3);
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn seeded_determinism_test() {
+        let full = || ExponentialFullJitter::new(BASE, MAX).rng(JitterRng::seeded(42));
+        assert_eq!(delays(&mut full(), 4), delays(&mut full(), 4));
+
+        let decorrelated = || DecorrelatedJitter::new(BASE, MAX).rng(JitterRng::seeded(42));
+        assert_eq!(
+            delays(&mut decorrelated(), 4),
+            delays(&mut decorrelated(), 4)
+        );
+
+        let other = ExponentialFullJitter::new(BASE, MAX).rng(JitterRng::seeded(43));
+        assert_ne!(delays(&mut full(), 4), delays(&mut other.clone(), 4));
+    }
+
+    #[test]
+    fn max_attempts_test() {
+        let mut backoff = ExponentialFullJitter::new(BASE, MAX);
+        assert_eq!(
+            delays(&mut backoff, 10).len(),
+            DEFAULT_MAX_ATTEMPTS as usize - 1
+        );
+        assert_eq!(delays(&mut Fixed::new(BASE, 3), 10), [BASE, BASE]);
+        assert!(delays(&mut NoRetry, 10).is_empty());
+    }
+
+    #[test]
+    fn retry_after_test() {
+        let rate_limited: NimbusError = Error::RateLimited {
+            message: "slow down".to_owned(),
+            retry_after: Some(Duration::from_secs(30)),
+        }
+        .into();
+
+        let mut strategies: Vec<Box<dyn Backoff>> = vec![
+            Box::new(ExponentialFullJitter::new(BASE, MAX)),
+            Box::new(DecorrelatedJitter::new(BASE, MAX)),
+            Box::new(Fixed::new(BASE, 3)),
+        ];
+        for backoff in &mut strategies {
+            assert_eq!(
+                backoff.next_delay(1, &rate_limited),
+                Some(Duration::from_secs(30))
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_test() {
+        let calls = AtomicU32::new(0);
+        let flaky = || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(unavailable()),
+                n => Ok(n),
+            }
+        };
+
+        let mut backoff = Fixed::new(Duration::from_secs(1), 3);
+        let start = tokio::time::Instant::now();
+        assert_eq!(retry(&mut backoff, flaky).await.unwrap(), 2);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        // gives up after the last attempt, with its error
+        calls.store(0, Ordering::SeqCst);
+        let mut backoff = Fixed::new(Duration::from_secs(1), 2);
+        let err = retry(&mut backoff, flaky).await.unwrap_err();
+        assert!(err.is_retryable());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // errors that can't succeed on retry are returned at once
+        calls.store(0, Ordering::SeqCst);
+        let mut backoff = Fixed::new(Duration::from_secs(1), 5);
+        let err = retry(&mut backoff, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(Error::NotFound("k".to_owned()).into())
+        })
+        .await
+        .unwrap_err();
+        assert!(!err.is_retryable());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,20 +1,47 @@
+#[cfg(feature = "aws-secrets")]
 use aws_config::BehaviorVersion;
 
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-secrets")]
 use google_secretmanager1::{
     api::{AddSecretVersionRequest, Automatic, Replication, Secret, SecretPayload},
-    hyper::{client::HttpConnector, Client},
-    hyper_rustls::{HttpsConnector, HttpsConnectorBuilder},
+    hyper::client::HttpConnector,
+    hyper_rustls::HttpsConnector,
     oauth2::authenticator::Authenticator,
     SecretManager,
 };
 
-#[cfg(feature = "aws")]
+#[cfg(feature = "aws-secrets")]
+use aws_sdk_secretsmanager::error::{ProvideErrorMetadata, SdkError};
+#[cfg(feature = "aws-secrets")]
+use aws_sdk_secretsmanager::primitives::Blob;
+#[cfg(feature = "aws-secrets")]
 use aws_sdk_secretsmanager::Client;
 
+use std::time::Duration;
 use thiserror::Error;
 
-use crate::NimbusError;
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+use crate::error::with_context;
+#[cfg(feature = "gcp-secrets")]
+use crate::CredentialMonitor;
+use crate::{redact, BatchError, ErrorCode, NimbusError};
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+use crate::{ErrorContext, TransportConfig};
+
+pub mod cache;
+pub mod encoding;
+pub mod fallback;
+pub mod large;
+pub mod pin;
+mod project;
+mod source;
+pub use cache::{CacheEvent, CacheTtl, CachedSecretManager};
+pub use encoding::{detect_encoding, PayloadEncoding, Strictness};
+pub use fallback::{FallbackEvent, FallbackPolicy};
+pub use large::{LARGE_SECRET_CONCURRENCY, SECRET_SIZE_LIMIT};
+pub use pin::{create_pinfile, Drift, DriftReport, Pinfile};
+pub use project::WithProject;
+pub use source::{SecretValue, Source, SourceChain, SourceChainSet};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -24,12 +51,191 @@ pub enum Error {
     NoPayload,
     #[error("Error: {0}")]
     Other(String),
-    #[cfg(feature = "gcp")]
+    #[cfg(feature = "gcp-secrets")]
     #[error("SecretManager error: {0}")]
     SecretManager(#[from] google_secretmanager1::Error),
-    #[cfg(feature = "aws")]
+    #[cfg(feature = "aws-secrets")]
     #[error("SecretManager error: {0}")]
     SecretManager(String),
+    #[error("Not found: {}", redact::resource(.0))]
+    NotFound(String),
+    #[error("Already exists: {}", redact::resource(.0))]
+    AlreadyExists(String),
+    #[error("Permission denied: {}", redact::resource(.0))]
+    PermissionDenied(String),
+    #[error("Rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    #[error("Secret {} is not pinned", redact::resource(.0))]
+    NotPinned(String),
+    #[error("Invalid pinfile: {0}")]
+    InvalidPinfile(String),
+    #[error("Project {} is not allowed", redact::resource(.0))]
+    ProjectNotAllowed(String),
+    #[error("No source has secret {}", redact::resource(.0))]
+    NoSource(String),
+    #[error("Secret {} would come from {from} in strict mode", redact::resource(.secret))]
+    StrictSource { secret: String, from: String },
+    #[error("Part {} of a large secret is missing", redact::resource(.0))]
+    PartMissing(String),
+    #[error("Part {} of a large secret is corrupted (expected sha256: {expected}, actual: {actual})", redact::resource(.part))]
+    PartCorrupted {
+        part: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Invalid large secret manifest {}: {reason}", redact::resource(.secret))]
+    InvalidManifest { secret: String, reason: String },
+    #[error("Secret {} is not valid {alphabet} base64: {reason}", redact::resource(.secret))]
+    InvalidBase64 {
+        secret: String,
+        alphabet: encoding::Base64Alphabet,
+        reason: String,
+    },
+    #[error("No name of the secret could be read: {}", describe_names(.0))]
+    NoNameServed(Vec<BatchError<String>>),
+}
+
+/// names tried by [`SecretManagerHelper::get_secret_with_fallback`] with their errors, in order
+fn describe_names(errors: &[BatchError<String>]) -> String {
+    let errors: Vec<String> = errors
+        .iter()
+        .map(|e| format!("{}: {}", redact::resource(&e.key), e.error))
+        .collect();
+    errors.join("; ")
+}
+
+impl Error {
+    /// provider independent classification of the error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "gcp-secrets")]
+            Error::SecretManager(e) => crate::error::classify_api_error(e),
+            Error::NotFound(_)
+            | Error::NotPinned(_)
+            | Error::NoSource(_)
+            | Error::PartMissing(_) => ErrorCode::NotFound,
+            Error::InvalidPinfile(_) | Error::InvalidBase64 { .. } => ErrorCode::InvalidInput,
+            Error::StrictSource { .. } => ErrorCode::PreconditionFailed,
+            Error::AlreadyExists(_) => ErrorCode::AlreadyExists,
+            Error::PermissionDenied(_) | Error::ProjectNotAllowed(_) => ErrorCode::PermissionDenied,
+            Error::RateLimited { .. } => ErrorCode::RateLimited,
+            Error::Timeout(_) => ErrorCode::Timeout,
+            Error::Unavailable(_) => ErrorCode::Unavailable,
+            // the error that ended the lookup
+            Error::NoNameServed(errors) => errors
+                .last()
+                .map_or(ErrorCode::Internal, |e| e.error.code()),
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// how long the provider asked to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            #[cfg(feature = "gcp-secrets")]
+            Error::SecretManager(e) => crate::error::api_retry_after(e),
+            Error::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// convert a SecretsManager error, keeping its classification
+    #[cfg(feature = "aws-secrets")]
+    pub(crate) fn from_sdk<E: ProvideErrorMetadata>(e: SdkError<E>) -> Self {
+        let (code, retry_after) = crate::error::classify_sdk_error(&e);
+        let message = e.to_string();
+
+        match code {
+            ErrorCode::NotFound => Error::NotFound(message),
+            ErrorCode::AlreadyExists => Error::AlreadyExists(message),
+            ErrorCode::PermissionDenied => Error::PermissionDenied(message),
+            ErrorCode::RateLimited => Error::RateLimited {
+                message,
+                retry_after,
+            },
+            ErrorCode::Timeout => Error::Timeout(message),
+            ErrorCode::Unavailable => Error::Unavailable(message),
+            _ => Error::SecretManager(message),
+        }
+    }
+}
+
+/// context of the errors of a call on a secret, or one of its versions
+#[cfg(feature = "gcp-secrets")]
+fn gcp_secret_context(
+    operation: &'static str,
+    project: &str,
+    secret: &str,
+    version: Option<&str>,
+) -> ErrorContext {
+    let mut resource = format!("projects/{project}/secrets/{secret}");
+    if let Some(version) = version {
+        resource = format!("{resource}/versions/{version}");
+    }
+    ErrorContext::new(operation, resource)
+}
+
+/// context of the errors of a call on a secret, or one of its versions
+#[cfg(feature = "aws-secrets")]
+fn aws_secret_context(
+    operation: &'static str,
+    secret: &str,
+    version: Option<&str>,
+) -> ErrorContext {
+    match version {
+        Some(version) => ErrorContext::new(operation, format!("{secret}/{version}")),
+        None => ErrorContext::new(operation, secret),
+    }
+}
+
+/// create a secret and its first version holding `data`
+#[cfg(feature = "gcp-secrets")]
+async fn gcp_create_secret(
+    secrets: &SecretManager<HttpsConnector<HttpConnector>>,
+    project: &str,
+    secret_name: &str,
+    data: &[u8],
+) -> Result<(), NimbusError> {
+    secrets
+        .projects()
+        .secrets_create(
+            Secret {
+                replication: Some(Replication {
+                    automatic: Some(Automatic::default()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            format!("projects/{project}").as_str(),
+        )
+        .secret_id(secret_name)
+        .doit()
+        .await
+        .map_err(Error::SecretManager)?;
+
+    let vrq = AddSecretVersionRequest {
+        payload: Some(SecretPayload {
+            data: Some(data.to_vec()),
+            ..Default::default()
+        }),
+    };
+
+    let parent = format!("projects/{project}/secrets/{secret_name}");
+    secrets
+        .projects()
+        .secrets_add_version(vrq, &parent)
+        .doit()
+        .await
+        .map_err(Error::SecretManager)?;
+
+    Ok(())
 }
 
 /// SecretManagerHelper trait
@@ -38,12 +244,54 @@ pub enum Error {
 pub trait SecretManagerHelper<S> {
     /// Create a new SecretManager with an Authenticator
     /// Deals with boilerplate of creating a new SecretManager
-    #[cfg(feature = "gcp")]
+    #[cfg(feature = "gcp-secrets")]
     async fn new_with_authenticator(authenticator: Authenticator<S>) -> Self;
 
-    #[cfg(feature = "aws")]
+    #[cfg(feature = "aws-secrets")]
     async fn new_with_authenticator() -> Self;
 
+    /// new client whose connections are pooled and kept alive as set in `transport`
+    /// clients without an HTTP transport, like the in-memory one, ignore it
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_transport(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+    ) -> Self
+    where
+        Self: Sized,
+        S: Send + Sync + 'static,
+    {
+        let _ = transport;
+        Self::new_with_authenticator(authenticator).await
+    }
+
+    /// new client reporting its token fetches to `monitor`, with its transport as in `new_with_transport`
+    /// clients without credentials, like the in-memory one, ignore it
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_monitor(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self
+    where
+        Self: Sized,
+        S: Send + Sync + 'static,
+    {
+        let _ = monitor;
+        Self::new_with_transport(authenticator, transport).await
+    }
+
+    /// new client whose connections are pooled and kept alive as set in `transport`
+    /// clients without an HTTP transport, like the in-memory one, ignore it
+    #[cfg(feature = "aws-secrets")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self
+    where
+        Self: Sized,
+    {
+        let _ = transport;
+        Self::new_with_authenticator().await
+    }
+
     /// Get the latest version of a secret
     async fn get_secret(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError>;
 
@@ -55,6 +303,17 @@ pub trait SecretManagerHelper<S> {
         secret_val: &str,
     ) -> Result<(), NimbusError>;
 
+    /// Creates a new secret holding binary data, read back as is by [`SecretManagerHelper::get_secret`]
+    async fn create_secret_bytes(
+        &self,
+        project: &str,
+        secret_name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError>;
+
+    /// Deletes a secret and all its versions, without recovery window on AWS
+    async fn delete_secret(&self, project: &str, secret: &str) -> Result<(), NimbusError>;
+
     /// Get a specific version of a secret
     async fn get_secret_version(
         &self,
@@ -62,9 +321,170 @@ pub trait SecretManagerHelper<S> {
         secret: &str,
         version: &str,
     ) -> Result<Vec<u8>, NimbusError>;
+
+    /// id of the current version of a secret: the version number on GCP,
+    /// the `VersionId` staged as `AWSCURRENT` on AWS
+    async fn latest_version_id(&self, project: &str, secret: &str) -> Result<String, NimbusError>;
+
+    /// Get a version of a secret by its id, as returned by [`SecretManagerHelper::latest_version_id`]
+    /// same as [`SecretManagerHelper::get_secret_version`] on GCP, which takes a staging label on AWS
+    async fn get_secret_version_id(
+        &self,
+        project: &str,
+        secret: &str,
+        version_id: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.get_secret_version(project, secret, version_id).await
+    }
+
+    /// Get the version of `name` pinned in `pinfile`, fails with [`Error::NotPinned`] if it isn't pinned
+    async fn get_pinned(&self, pinfile: &Pinfile, name: &str) -> Result<Vec<u8>, NimbusError> {
+        let version = pinfile
+            .version(name)
+            .ok_or_else(|| Error::NotPinned(name.to_owned()))?;
+        self.get_secret_version_id(&pinfile.project, name, version)
+            .await
+    }
+
+    /// Secrets of `pinfile` whose current version is not the pinned one
+    /// a secret whose pinned version can't be read (e.g. destroyed) or whose current version can't be resolved
+    /// is reported in [`DriftReport::failed`], the others are still checked
+    async fn check_drift(&self, pinfile: &Pinfile) -> Result<DriftReport, NimbusError> {
+        let mut report = DriftReport::default();
+        for (name, pinned) in &pinfile.secrets {
+            let checked = match self
+                .get_secret_version_id(&pinfile.project, name, pinned)
+                .await
+            {
+                Ok(_) => self.latest_version_id(&pinfile.project, name).await,
+                Err(e) => Err(e),
+            };
+
+            match checked {
+                Ok(latest) if latest != *pinned => report.drifted.push(pin::Drift {
+                    name: name.clone(),
+                    pinned: pinned.clone(),
+                    latest,
+                }),
+                Ok(_) => {}
+                Err(error) => report.failed.push(BatchError {
+                    key: name.clone(),
+                    error,
+                }),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Creates a secret of any size: payloads over [`SECRET_SIZE_LIMIT`] are split across several secrets
+    /// as described in [`large`], smaller ones are stored as is by [`SecretManagerHelper::create_secret_bytes`]
+    ///
+    /// Fails with [`ErrorCode::AlreadyExists`] if any of these secrets exists, the parts created by the
+    /// call are deleted then.
+    async fn put_large_secret(
+        &self,
+        project: &str,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError> {
+        large::put(self, project, name, data, SECRET_SIZE_LIMIT.as_usize()).await
+    }
+
+    /// Get a secret stored by [`SecretManagerHelper::put_large_secret`], or the latest version of a plain secret
+    ///
+    /// Parts are read concurrently and checked against the manifest, a missing part fails with
+    /// [`Error::PartMissing`] and a damaged one with [`Error::PartCorrupted`].
+    async fn get_large_secret(&self, project: &str, name: &str) -> Result<Vec<u8>, NimbusError> {
+        large::get(self, project, name).await
+    }
+
+    /// Delete a secret stored by [`SecretManagerHelper::put_large_secret`], its parts then its manifest
+    async fn delete_large_secret(&self, project: &str, name: &str) -> Result<(), NimbusError> {
+        large::delete(self, project, name).await
+    }
+
+    /// Get the latest version of a secret stored base64 encoded, decoded
+    ///
+    /// The payload must be standard or URL-safe base64, padded or not, as in [`encoding::decode_base64`]:
+    /// anything else, a trailing newline included, fails with [`Error::InvalidBase64`].
+    async fn get_secret_base64_decoded(
+        &self,
+        project: &str,
+        secret: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let data = self.get_secret(project, secret).await?;
+        encoding::decode_base64(&data).map_err(|e| {
+            Error::InvalidBase64 {
+                secret: secret.to_owned(),
+                alphabet: e.alphabet,
+                reason: e.reason,
+            }
+            .into()
+        })
+    }
+
+    /// Creates a new secret holding `raw` as padded standard base64, for systems reading it encoded
+    async fn create_secret_base64(
+        &self,
+        project: &str,
+        name: &str,
+        raw: &[u8],
+    ) -> Result<(), NimbusError> {
+        self.create_secret(project, name, &encoding::encode_base64(raw))
+            .await
+    }
+
+    /// Get the latest version of a secret, decoded if [`encoding::detect_encoding_with`] takes it for base64
+    ///
+    /// Only one layer is decoded: a double encoded payload comes back as base64.
+    async fn get_secret_auto(
+        &self,
+        project: &str,
+        secret: &str,
+        strictness: Strictness,
+    ) -> Result<(Vec<u8>, PayloadEncoding), NimbusError> {
+        let data = self.get_secret(project, secret).await?;
+        Ok(match encoding::classify(&data, strictness) {
+            (encoding, Some(decoded)) => (decoded, encoding),
+            (encoding, None) => (data, encoding),
+        })
+    }
+
+    /// Get the latest version of the first of `names` that can be read, with the name that served it
+    ///
+    /// Meant for renames: the new name first, then the old ones. Not found falls through to the next
+    /// name, permission denied too on the names after the first, any other error fails the lookup.
+    /// Fails with [`Error::NoNameServed`] holding the error of every name tried.
+    /// See [`fallback`] to choose the errors falling through and report which name served.
+    async fn get_secret_with_fallback(
+        &self,
+        project: &str,
+        names: &[&str],
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        fallback::get(self, project, names, &FallbackPolicy::default()).await
+    }
+
+    /// [`SecretManagerHelper::get_secret_with_fallback`] with the errors falling through and the observer of `policy`
+    async fn get_secret_with_fallback_policy(
+        &self,
+        project: &str,
+        names: &[&str],
+        policy: &FallbackPolicy,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        fallback::get(self, project, names, policy).await
+    }
+
+    /// bind the secret manager to `project`, for calls without the project argument
+    fn with_project(self, project: impl Into<String>) -> WithProject<Self, S>
+    where
+        Self: Sized + Sync,
+    {
+        WithProject::new(self, project)
+    }
 }
 
-#[cfg(feature = "aws")]
+#[cfg(feature = "aws-secrets")]
 #[async_trait::async_trait]
 impl SecretManagerHelper<()> for aws_sdk_secretsmanager::Client {
     async fn new_with_authenticator() -> Self {
@@ -72,26 +492,33 @@ impl SecretManagerHelper<()> for aws_sdk_secretsmanager::Client {
         Client::new(&config)
     }
 
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Client::new(&transport.aws_sdk_config().await)
+    }
+
     async fn get_secret(&self, _: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
-        let res = match self
-            .get_secret_value()
-            .secret_id(secret.to_owned())
-            .send()
-            .await
-        {
-            Ok(res) => {
-                if let Some(data) = res.secret_binary {
-                    data
-                } else {
-                    return Err(NimbusError::from(Error::SecretManager(
-                        "invalid secret".to_string(),
-                    )));
+        with_context(|| aws_secret_context("get_secret", secret, None), async {
+            let res = match self
+                .get_secret_value()
+                .secret_id(secret.to_owned())
+                .send()
+                .await
+            {
+                Ok(res) => {
+                    if let Some(data) = res.secret_binary {
+                        data
+                    } else {
+                        return Err(NimbusError::from(Error::SecretManager(
+                            "invalid secret".to_string(),
+                        )));
+                    }
                 }
-            }
-            Err(e) => return Err(NimbusError::from(Error::SecretManager(e.to_string()))),
-        };
+                Err(e) => return Err(NimbusError::from(Error::from_sdk(e))),
+            };
 
-        Ok(res.into_inner())
+            Ok(res.into_inner())
+        })
+        .await
     }
 
     /// Get a specific version of a secret
@@ -101,26 +528,32 @@ impl SecretManagerHelper<()> for aws_sdk_secretsmanager::Client {
         secret: &str,
         version: &str,
     ) -> Result<Vec<u8>, NimbusError> {
-        let res = match self
-            .get_secret_value()
-            .secret_id(secret)
-            .version_stage(version)
-            .send()
-            .await
-        {
-            Ok(res) => {
-                if let Some(data) = res.secret_binary {
-                    data
-                } else {
-                    return Err(NimbusError::from(Error::SecretManager(
-                        "invalid secret".to_string(),
-                    )));
-                }
-            }
-            Err(e) => return Err(NimbusError::from(Error::SecretManager(e.to_string()))),
-        };
-
-        Ok(res.into_inner())
+        with_context(
+            || aws_secret_context("get_secret_version", secret, Some(version)),
+            async {
+                let res = match self
+                    .get_secret_value()
+                    .secret_id(secret)
+                    .version_stage(version)
+                    .send()
+                    .await
+                {
+                    Ok(res) => {
+                        if let Some(data) = res.secret_binary {
+                            data
+                        } else {
+                            return Err(NimbusError::from(Error::SecretManager(
+                                "invalid secret".to_string(),
+                            )));
+                        }
+                    }
+                    Err(e) => return Err(NimbusError::from(Error::from_sdk(e))),
+                };
+
+                Ok(res.into_inner())
+            },
+        )
+        .await
     }
 
     async fn create_secret(
@@ -129,21 +562,120 @@ impl SecretManagerHelper<()> for aws_sdk_secretsmanager::Client {
         secret_name: &str,
         secret_val: &str,
     ) -> Result<(), NimbusError> {
-        if let Err(e) = self
-            .create_secret()
-            .secret_string(secret_val)
-            .name(secret_name)
-            .send()
-            .await
-        {
-            return Err(NimbusError::from(Error::SecretManager(e.to_string())));
-        }
+        with_context(
+            || aws_secret_context("create_secret", secret_name, None),
+            async {
+                if let Err(e) = self
+                    .create_secret()
+                    .secret_string(secret_val)
+                    .name(secret_name)
+                    .send()
+                    .await
+                {
+                    return Err(NimbusError::from(Error::from_sdk(e)));
+                }
+
+                Ok(())
+            },
+        )
+        .await
+    }
 
-        Ok(())
+    async fn create_secret_bytes(
+        &self,
+        _: &str,
+        secret_name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || aws_secret_context("create_secret_bytes", secret_name, None),
+            async {
+                self.create_secret()
+                    .secret_binary(Blob::new(data))
+                    .name(secret_name)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// deleted right away, a secret in its recovery window couldn't be created again
+    async fn delete_secret(&self, _: &str, secret: &str) -> Result<(), NimbusError> {
+        with_context(
+            || aws_secret_context("delete_secret", secret, None),
+            async {
+                self.delete_secret()
+                    .secret_id(secret)
+                    .force_delete_without_recovery(true)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn latest_version_id(&self, _: &str, secret: &str) -> Result<String, NimbusError> {
+        with_context(
+            || aws_secret_context("latest_version_id", secret, None),
+            async {
+                let res = self
+                    .describe_secret()
+                    .secret_id(secret)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                res.version_ids_to_stages()
+                    .and_then(|versions| {
+                        versions
+                            .iter()
+                            .find(|(_, stages)| stages.iter().any(|s| s == "AWSCURRENT"))
+                    })
+                    .map(|(id, _)| id.clone())
+                    .ok_or_else(|| {
+                        Error::NotFound(format!("no current version of {secret}")).into()
+                    })
+            },
+        )
+        .await
+    }
+
+    /// `version_id` is a `VersionId`, not a staging label
+    async fn get_secret_version_id(
+        &self,
+        _: &str,
+        secret: &str,
+        version_id: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || aws_secret_context("get_secret_version_id", secret, Some(version_id)),
+            async {
+                let res = self
+                    .get_secret_value()
+                    .secret_id(secret)
+                    .version_id(version_id)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                match res.secret_binary {
+                    Some(data) => Ok(data.into_inner()),
+                    None => Err(Error::SecretManager("invalid secret".to_string()).into()),
+                }
+            },
+        )
+        .await
     }
 }
 
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-secrets")]
 #[async_trait::async_trait]
 impl SecretManagerHelper<HttpsConnector<HttpConnector>>
     for SecretManager<HttpsConnector<HttpConnector>>
@@ -151,39 +683,51 @@ impl SecretManagerHelper<HttpsConnector<HttpConnector>>
     async fn new_with_authenticator(
         authenticator: Authenticator<HttpsConnector<HttpConnector>>,
     ) -> Self {
-        SecretManager::new(
-            Client::builder().build(
-                HttpsConnectorBuilder::new()
-                    .with_native_roots()
-                    .https_only()
-                    .enable_http1()
-                    .enable_http2()
-                    .build(),
-            ),
-            authenticator,
-        )
+        Self::new_with_transport(authenticator, &TransportConfig::default()).await
     }
 
-    async fn get_secret(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
-        let secret_name = format!("projects/{}/secrets/{}/versions/latest", project, secret);
-        let (_r, s) = self
-            .projects()
-            .secrets_versions_access(&secret_name)
-            .doit()
-            .await
-            .map_err(Error::SecretManager)?;
+    async fn new_with_transport(
+        authenticator: Authenticator<HttpsConnector<HttpConnector>>,
+        transport: &TransportConfig,
+    ) -> Self {
+        SecretManager::new(transport.hyper_client(), authenticator)
+    }
 
-        let secret = if let Some(pl) = s.payload {
-            if let Some(data) = pl.data {
-                data
-            } else {
-                return Err(Error::NoData.into());
-            }
-        } else {
-            return Err(Error::NoPayload.into());
-        };
+    async fn new_with_monitor(
+        authenticator: Authenticator<HttpsConnector<HttpConnector>>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self {
+        SecretManager::new(transport.hyper_client(), monitor.wrap(authenticator))
+    }
 
-        Ok(secret)
+    async fn get_secret(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || gcp_secret_context("get_secret", project, secret, None),
+            async {
+                let secret_name =
+                    format!("projects/{}/secrets/{}/versions/latest", project, secret);
+                let (_r, s) = self
+                    .projects()
+                    .secrets_versions_access(&secret_name)
+                    .doit()
+                    .await
+                    .map_err(Error::SecretManager)?;
+
+                let secret = if let Some(pl) = s.payload {
+                    if let Some(data) = pl.data {
+                        data
+                    } else {
+                        return Err(Error::NoData.into());
+                    }
+                } else {
+                    return Err(Error::NoPayload.into());
+                };
+
+                Ok(secret)
+            },
+        )
+        .await
     }
 
     async fn create_secret(
@@ -192,37 +736,41 @@ impl SecretManagerHelper<HttpsConnector<HttpConnector>>
         secret_name: &str,
         secret_val: &str,
     ) -> Result<(), NimbusError> {
-        self.projects()
-            .secrets_create(
-                Secret {
-                    replication: Some(Replication {
-                        automatic: Some(Automatic::default()),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                },
-                format!("projects/{project}").as_str(),
-            )
-            .secret_id(secret_name)
-            .doit()
-            .await
-            .map_err(Error::SecretManager)?;
-
-        let vrq = AddSecretVersionRequest {
-            payload: Some(SecretPayload {
-                data: Some(secret_val.as_bytes().to_vec()),
-                ..Default::default()
-            }),
-        };
+        with_context(
+            || gcp_secret_context("create_secret", project, secret_name, None),
+            gcp_create_secret(self, project, secret_name, secret_val.as_bytes()),
+        )
+        .await
+    }
 
-        let parent = format!("projects/{project}/secrets/{secret_name}");
-        self.projects()
-            .secrets_add_version(vrq, &parent)
-            .doit()
-            .await
-            .map_err(Error::SecretManager)?;
+    async fn create_secret_bytes(
+        &self,
+        project: &str,
+        secret_name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || gcp_secret_context("create_secret_bytes", project, secret_name, None),
+            gcp_create_secret(self, project, secret_name, data),
+        )
+        .await
+    }
 
-        Ok(())
+    async fn delete_secret(&self, project: &str, secret: &str) -> Result<(), NimbusError> {
+        with_context(
+            || gcp_secret_context("delete_secret", project, secret, None),
+            async {
+                let name = format!("projects/{project}/secrets/{secret}");
+                self.projects()
+                    .secrets_delete(&name)
+                    .doit()
+                    .await
+                    .map_err(Error::SecretManager)?;
+
+                Ok(())
+            },
+        )
+        .await
     }
 
     async fn get_secret_version(
@@ -231,32 +779,61 @@ impl SecretManagerHelper<HttpsConnector<HttpConnector>>
         secret: &str,
         version: &str,
     ) -> Result<Vec<u8>, NimbusError> {
-        let secret_name = format!(
-            "projects/{}/secrets/{}/versions/{}",
-            project, secret, version
-        );
-        let (_, s) = self
-            .projects()
-            .secrets_versions_access(&secret_name)
-            .doit()
-            .await
-            .map_err(Error::SecretManager)?;
+        with_context(
+            || gcp_secret_context("get_secret_version", project, secret, Some(version)),
+            async {
+                let secret_name = format!(
+                    "projects/{}/secrets/{}/versions/{}",
+                    project, secret, version
+                );
+                let (_, s) = self
+                    .projects()
+                    .secrets_versions_access(&secret_name)
+                    .doit()
+                    .await
+                    .map_err(Error::SecretManager)?;
+
+                let secret = if let Some(pl) = s.payload {
+                    if let Some(data) = pl.data {
+                        data
+                    } else {
+                        return Err(Error::NoData.into());
+                    }
+                } else {
+                    return Err(Error::NoPayload.into());
+                };
 
-        let secret = if let Some(pl) = s.payload {
-            if let Some(data) = pl.data {
-                data
-            } else {
-                return Err(Error::NoData.into());
-            }
-        } else {
-            return Err(Error::NoPayload.into());
-        };
+                Ok(secret)
+            },
+        )
+        .await
+    }
 
-        Ok(secret)
+    async fn latest_version_id(&self, project: &str, secret: &str) -> Result<String, NimbusError> {
+        with_context(
+            || gcp_secret_context("latest_version_id", project, secret, None),
+            async {
+                let name = format!("projects/{project}/secrets/{secret}/versions/latest");
+                let (_, version) = self
+                    .projects()
+                    .secrets_versions_get(&name)
+                    .doit()
+                    .await
+                    .map_err(Error::SecretManager)?;
+
+                version
+                    .name
+                    .as_deref()
+                    .and_then(|name| name.rsplit('/').next())
+                    .map(str::to_owned)
+                    .ok_or_else(|| Error::Other(format!("No version name for {name}")).into())
+            },
+        )
+        .await
     }
 }
 
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-secrets")]
 #[cfg(test)]
 mod tests {
     use google_auth_helper::helper::AuthHelper;
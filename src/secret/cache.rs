@@ -0,0 +1,430 @@
+//! Secrets cached in memory with stale-while-revalidate, for services reading them on every request
+//!
+//! An entry younger than the soft TTL of its [`CacheTtl`] is served as is. Between the soft and the
+//! hard TTL it is still served right away, and a single background refresh replaces it. Only past the
+//! hard TTL do callers wait for the secret manager, and concurrent callers share a single fetch, so an
+//! expiry under load never stampedes the provider.
+//!
+//! ```ignore
+//! let secrets = CachedSecretManager::new(secret_manager)
+//!     .ttl(CacheTtl::new(Duration::from_secs(60), Duration::from_secs(3600)))
+//!     .secret_ttl("rotating-token", CacheTtl::new(Duration::from_secs(5), Duration::from_secs(60)))
+//!     .on_event(|event| metrics.record(event));
+//! let key = secrets.get("my-project", "api-key").await?;
+//! ```
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::SecretManagerHelper;
+use crate::NimbusError;
+
+/// TTLs of an entry unless set otherwise, see [`CachedSecretManager::ttl`]
+pub const DEFAULT_CACHE_TTL: CacheTtl = CacheTtl {
+    soft: Duration::from_secs(5 * 60),
+    hard: Duration::from_secs(60 * 60),
+};
+
+/// Wait after a failed refresh when the provider didn't ask for one, see [`CachedSecretManager::refresh_backoff`]
+pub const DEFAULT_REFRESH_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How long a cached secret is served, see the [module](self) docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheTtl {
+    /// served without refreshing before
+    pub soft: Duration,
+    /// served while refreshing before, never after
+    pub hard: Duration,
+}
+
+impl CacheTtl {
+    /// `hard` is raised to `soft` if shorter
+    pub fn new(soft: Duration, hard: Duration) -> Self {
+        Self {
+            soft,
+            hard: hard.max(soft),
+        }
+    }
+
+    /// no stale serving, entries are fetched again once `ttl` passed
+    pub fn fixed(ttl: Duration) -> Self {
+        Self::new(ttl, ttl)
+    }
+}
+
+/// What a [`CachedSecretManager`] did, reported to the callback of [`CachedSecretManager::on_event`]
+#[derive(Debug)]
+pub enum CacheEvent<'a> {
+    /// served within the soft TTL
+    Hit { project: &'a str, secret: &'a str },
+    /// served between the soft and the hard TTL
+    Stale { project: &'a str, secret: &'a str },
+    /// missing or past the hard TTL, fetched while the caller waited
+    Miss { project: &'a str, secret: &'a str },
+    /// replaced by a background refresh
+    Refreshed { project: &'a str, secret: &'a str },
+    /// a background refresh failed, the stale entry is kept until its hard TTL
+    RefreshFailed {
+        project: &'a str,
+        secret: &'a str,
+        error: &'a NimbusError,
+    },
+}
+
+type Observer = dyn Fn(CacheEvent<'_>) + Send + Sync;
+
+#[derive(Default)]
+struct State {
+    value: Option<(Vec<u8>, Instant)>,
+    refreshing: bool,
+    /// no background refresh before, after a failed one
+    retry_at: Option<Instant>,
+}
+
+/// cache entry of a secret
+#[derive(Default)]
+struct Slot {
+    state: Mutex<State>,
+    /// held while fetching, callers past the hard TTL queue on it
+    fetching: futures::lock::Mutex<()>,
+}
+
+struct Shared<M> {
+    secrets: M,
+    slots: Mutex<HashMap<(String, String), Arc<Slot>>>,
+    ttl: CacheTtl,
+    overrides: HashMap<String, CacheTtl>,
+    refresh_backoff: Duration,
+    observer: Option<Arc<Observer>>,
+}
+
+impl<M> Shared<M> {
+    fn observe(&self, event: CacheEvent<'_>) {
+        if let Some(observer) = &self.observer {
+            observer(event);
+        }
+    }
+
+    fn ttl_of(&self, secret: &str) -> CacheTtl {
+        self.overrides.get(secret).copied().unwrap_or(self.ttl)
+    }
+}
+
+/// Secret manager caching the latest version of secrets, see the [module](self) docs
+///
+/// Clones share their cache. Background refreshes are spawned on the tokio runtime of the caller.
+pub struct CachedSecretManager<M, S> {
+    shared: Arc<Shared<M>>,
+    connector: PhantomData<fn() -> S>,
+}
+
+impl<M, S> Clone for CachedSecretManager<M, S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            connector: PhantomData,
+        }
+    }
+}
+
+impl<M, S> CachedSecretManager<M, S>
+where
+    M: SecretManagerHelper<S> + Send + Sync + 'static,
+    S: 'static,
+{
+    pub fn new(secrets: M) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                secrets,
+                slots: Mutex::new(HashMap::new()),
+                ttl: DEFAULT_CACHE_TTL,
+                overrides: HashMap::new(),
+                refresh_backoff: DEFAULT_REFRESH_BACKOFF,
+                observer: None,
+            }),
+            connector: PhantomData,
+        }
+    }
+
+    /// TTLs of the secrets without their own, [`DEFAULT_CACHE_TTL`] by default
+    pub fn ttl(mut self, ttl: CacheTtl) -> Self {
+        self.configure().ttl = ttl;
+        self
+    }
+
+    /// TTLs of `secret`, in every project
+    pub fn secret_ttl(mut self, secret: impl Into<String>, ttl: CacheTtl) -> Self {
+        self.configure().overrides.insert(secret.into(), ttl);
+        self
+    }
+
+    /// wait after a failed refresh before the next one, unless the provider asked for longer
+    pub fn refresh_backoff(mut self, backoff: Duration) -> Self {
+        self.configure().refresh_backoff = backoff;
+        self
+    }
+
+    /// report hits, misses and refreshes to `observer`, e.g. to count them
+    pub fn on_event(mut self, observer: impl Fn(CacheEvent<'_>) + Send + Sync + 'static) -> Self {
+        self.configure().observer = Some(Arc::new(observer));
+        self
+    }
+
+    fn configure(&mut self) -> &mut Shared<M> {
+        Arc::get_mut(&mut self.shared).expect("cache is configured before being cloned")
+    }
+
+    /// the wrapped secret manager, for uncached calls
+    pub fn inner(&self) -> &M {
+        &self.shared.secrets
+    }
+
+    /// latest version of a secret, from the cache while within its TTLs
+    pub async fn get(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
+        let shared = &self.shared;
+        let slot = self.slot(project, secret);
+        let ttl = shared.ttl_of(secret);
+
+        {
+            let now = Instant::now();
+            let mut state = slot.state.lock().unwrap();
+            if let Some((value, fetched_at)) = &state.value {
+                let age = now - *fetched_at;
+                if age < ttl.soft {
+                    shared.observe(CacheEvent::Hit { project, secret });
+                    return Ok(value.clone());
+                }
+                if age < ttl.hard {
+                    let value = value.clone();
+                    if !state.refreshing && state.retry_at.is_none_or(|at| now >= at) {
+                        state.refreshing = true;
+                        self.spawn_refresh(project, secret, slot.clone());
+                    }
+                    shared.observe(CacheEvent::Stale { project, secret });
+                    return Ok(value);
+                }
+            }
+        }
+
+        let _fetching = slot.fetching.lock().await;
+        // fetched by whoever held the lock before
+        if let Some((value, fetched_at)) = &slot.state.lock().unwrap().value {
+            if fetched_at.elapsed() < ttl.hard {
+                shared.observe(CacheEvent::Hit { project, secret });
+                return Ok(value.clone());
+            }
+        }
+
+        shared.observe(CacheEvent::Miss { project, secret });
+        let value = shared.secrets.get_secret(project, secret).await?;
+        let mut state = slot.state.lock().unwrap();
+        state.value = Some((value.clone(), Instant::now()));
+        state.retry_at = None;
+
+        Ok(value)
+    }
+
+    /// drop the cached secret, the next [`CachedSecretManager::get`] fetches it
+    pub fn invalidate(&self, project: &str, secret: &str) {
+        self.shared
+            .slots
+            .lock()
+            .unwrap()
+            .remove(&(project.to_owned(), secret.to_owned()));
+    }
+
+    fn slot(&self, project: &str, secret: &str) -> Arc<Slot> {
+        self.shared
+            .slots
+            .lock()
+            .unwrap()
+            .entry((project.to_owned(), secret.to_owned()))
+            .or_default()
+            .clone()
+    }
+
+    fn spawn_refresh(&self, project: &str, secret: &str, slot: Arc<Slot>) {
+        let shared = Arc::clone(&self.shared);
+        let (project, secret) = (project.to_owned(), secret.to_owned());
+
+        tokio::spawn(async move {
+            let _fetching = slot.fetching.lock().await;
+            let fetched = shared.secrets.get_secret(&project, &secret).await;
+
+            let mut state = slot.state.lock().unwrap();
+            state.refreshing = false;
+            match fetched {
+                Ok(value) => {
+                    state.value = Some((value, Instant::now()));
+                    state.retry_at = None;
+                    shared.observe(CacheEvent::Refreshed {
+                        project: &project,
+                        secret: &secret,
+                    });
+                }
+                Err(error) => {
+                    let backoff = error.retry_after().map_or(shared.refresh_backoff, |after| {
+                        after.max(shared.refresh_backoff)
+                    });
+                    state.retry_at = Some(Instant::now() + backoff);
+                    shared.observe(CacheEvent::RefreshFailed {
+                        project: &project,
+                        secret: &secret,
+                        error: &error,
+                    });
+                }
+            }
+        });
+    }
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::testing::{Fault, Latency, MemorySecretManager};
+    use crate::ErrorCode;
+
+    const SOFT: Duration = Duration::from_secs(60);
+    const HARD: Duration = Duration::from_secs(600);
+    const FETCH: Duration = Duration::from_millis(50);
+
+    #[cfg(feature = "gcp-secrets")]
+    type Connector = google_secretmanager1::hyper_rustls::HttpsConnector<
+        google_secretmanager1::hyper::client::HttpConnector,
+    >;
+    #[cfg(feature = "aws-secrets")]
+    type Connector = ();
+
+    type Cache = CachedSecretManager<MemorySecretManager, Connector>;
+    type Events = Arc<Mutex<Vec<String>>>;
+
+    fn cache(backend: &MemorySecretManager) -> (Cache, Events) {
+        let events = Events::default();
+        let recorded = events.clone();
+        backend.mock_stats().set_fault(
+            "get_secret_version",
+            Fault::new().latency(Latency::Fixed(FETCH)),
+        );
+        let cache = CachedSecretManager::new(backend.clone())
+            .ttl(CacheTtl::new(SOFT, HARD))
+            .on_event(move |event| {
+                let name = match event {
+                    CacheEvent::Hit { .. } => "hit".to_owned(),
+                    CacheEvent::Stale { .. } => "stale".to_owned(),
+                    CacheEvent::Miss { .. } => "miss".to_owned(),
+                    CacheEvent::Refreshed { .. } => "refreshed".to_owned(),
+                    CacheEvent::RefreshFailed { error, .. } => format!("failed {:?}", error.code()),
+                };
+                recorded.lock().unwrap().push(name);
+            });
+        (cache, events)
+    }
+
+    /// let the background refresh run
+    async fn settle() {
+        tokio::time::sleep(FETCH * 2).await;
+    }
+
+    fn count(events: &Events, name: &str) -> usize {
+        events.lock().unwrap().iter().filter(|e| *e == name).count()
+    }
+
+    async fn get_all(cache: &Cache, n: usize) -> Vec<Vec<u8>> {
+        join_all((0..n).map(|_| cache.get("p", "s")))
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_while_revalidate_test() {
+        let backend = MemorySecretManager::new();
+        backend.add_version("p", "s", "v1");
+        let (cache, events) = cache(&backend);
+
+        // a cold cache fetches once for every concurrent caller
+        assert!(get_all(&cache, 100).await.iter().all(|v| v == b"v1"));
+        assert_eq!(backend.stats().calls("get_secret_version"), 1);
+        assert_eq!(count(&events, "miss"), 1);
+
+        // past the soft TTL, stale values are served while a single refresh runs
+        backend.add_version("p", "s", "v2");
+        tokio::time::advance(SOFT + Duration::from_secs(1)).await;
+        assert!(get_all(&cache, 100).await.iter().all(|v| v == b"v1"));
+        assert_eq!(count(&events, "stale"), 100);
+        settle().await;
+        assert_eq!(backend.stats().calls("get_secret_version"), 2);
+        assert_eq!(count(&events, "refreshed"), 1);
+        assert_eq!(cache.get("p", "s").await.unwrap(), b"v2");
+
+        // past the hard TTL, callers wait on a single fetch
+        backend.add_version("p", "s", "v3");
+        tokio::time::advance(HARD + Duration::from_secs(1)).await;
+        assert!(get_all(&cache, 100).await.iter().all(|v| v == b"v3"));
+        assert_eq!(backend.stats().calls("get_secret_version"), 3);
+        assert_eq!(count(&events, "miss"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_failure_test() {
+        let backend = MemorySecretManager::new();
+        backend.add_version("p", "s", "v1");
+        let (cache, events) = cache(&backend);
+        let cache = cache.refresh_backoff(Duration::from_secs(30));
+        cache.get("p", "s").await.unwrap();
+
+        backend.mock_stats().set_fault(
+            "get_secret_version",
+            Fault::new().fail(1.0, ErrorCode::RateLimited),
+        );
+        tokio::time::advance(SOFT).await;
+        assert_eq!(cache.get("p", "s").await.unwrap(), b"v1");
+        settle().await;
+        assert_eq!(count(&events, "failed RateLimited"), 1);
+
+        // no refresh during the backoff, the stale value is still served
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(cache.get("p", "s").await.unwrap(), b"v1");
+        settle().await;
+        assert_eq!(backend.stats().calls("get_secret_version"), 2);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        cache.get("p", "s").await.unwrap();
+        settle().await;
+        assert_eq!(backend.stats().calls("get_secret_version"), 3);
+
+        // never served past the hard TTL
+        tokio::time::advance(HARD).await;
+        let err = cache.get("p", "s").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn secret_ttl_test() {
+        let backend = MemorySecretManager::new();
+        backend.add_version("p", "s", "v1");
+        backend.add_version("p", "other", "o1");
+        let cache =
+            Cache::new(backend.clone()).secret_ttl("s", CacheTtl::fixed(Duration::from_secs(1)));
+
+        cache.get("p", "s").await.unwrap();
+        cache.get("p", "other").await.unwrap();
+        backend.add_version("p", "s", "v2");
+        backend.add_version("p", "other", "o2");
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        assert_eq!(cache.get("p", "s").await.unwrap(), b"v2");
+        assert_eq!(cache.get("p", "other").await.unwrap(), b"o1");
+
+        cache.invalidate("p", "other");
+        assert_eq!(cache.get("p", "other").await.unwrap(), b"o2");
+    }
+}
@@ -0,0 +1,296 @@
+//! Secret values stored base64 encoded by other tools
+//!
+//! [`detect_encoding`] tells text, base64 and binary payloads apart with these heuristics, in order:
+//! 1. a payload that isn't UTF-8, or holds control characters other than tab, CR and LF, is
+//!    [`PayloadEncoding::Binary`]
+//! 2. a payload of hex digits only is [`PayloadEncoding::Utf8Text`]: hex strings (digests, ids) are
+//!    very often valid base64 too, and decoding them is never what was meant
+//! 3. a payload accepted as base64 by the [`Strictness`] in use is [`PayloadEncoding::Base64`]
+//! 4. anything else is [`PayloadEncoding::Utf8Text`]
+//!
+//! A short word like `Test` is valid base64, so is any string of letters of the right length: only
+//! [`Strictness::Lenient`] takes those for base64.
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+
+/// Shortest payload [`Strictness::Strict`] takes for base64, the encoding of 12 bytes
+pub const STRICT_BASE64_MIN_LEN: usize = 16;
+
+/// standard alphabet, padding optional but checked when present
+const STANDARD_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// URL-safe alphabet, padding optional but checked when present
+const URL_SAFE_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// What a secret payload looks like, as guessed by [`detect_encoding`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PayloadEncoding {
+    Utf8Text,
+    Base64,
+    Binary,
+}
+
+/// How readily [`detect_encoding_with`] takes a text payload for base64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Strictness {
+    /// padded base64 of at least [`STRICT_BASE64_MIN_LEN`] characters, without surrounding whitespace,
+    /// holding at least one digit, `+`, `/`, `-`, `_` or `=`: words and sentences stay text
+    #[default]
+    Strict,
+    /// any base64 of at least 4 characters, padded or not, once surrounding whitespace is trimmed
+    Lenient,
+}
+
+/// Guess the encoding of `data` with [`Strictness::Strict`]
+pub fn detect_encoding(data: &[u8]) -> PayloadEncoding {
+    detect_encoding_with(data, Strictness::Strict)
+}
+
+/// Guess the encoding of `data`, as described in the [module documentation](self)
+pub fn detect_encoding_with(data: &[u8], strictness: Strictness) -> PayloadEncoding {
+    classify(data, strictness).0
+}
+
+/// the encoding of `data` and, for base64, its decoded bytes
+pub(crate) fn classify(data: &[u8], strictness: Strictness) -> (PayloadEncoding, Option<Vec<u8>>) {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return (PayloadEncoding::Binary, None);
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\r' | '\n'))
+    {
+        return (PayloadEncoding::Binary, None);
+    }
+
+    match base64_candidate(text, strictness) {
+        Some(decoded) => (PayloadEncoding::Base64, Some(decoded)),
+        None => (PayloadEncoding::Utf8Text, None),
+    }
+}
+
+fn base64_candidate(text: &str, strictness: Strictness) -> Option<Vec<u8>> {
+    let text = match strictness {
+        Strictness::Strict => text,
+        Strictness::Lenient => text.trim_matches(|c: char| c.is_ascii_whitespace()),
+    };
+    if text.len() < 4 || text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    if strictness == Strictness::Strict
+        && (text.len() < STRICT_BASE64_MIN_LEN
+            || text.len() % 4 != 0
+            || text.bytes().all(|b| b.is_ascii_alphabetic()))
+    {
+        return None;
+    }
+
+    decode_base64(text.as_bytes()).ok()
+}
+
+/// Alphabet of a base64 payload, named in [`super::Error::InvalidBase64`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Base64Alphabet {
+    /// `A-Z a-z 0-9 + /`
+    Standard,
+    /// `A-Z a-z 0-9 - _`
+    UrlSafe,
+}
+
+impl Base64Alphabet {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Base64Alphabet::Standard => "standard",
+            Base64Alphabet::UrlSafe => "URL-safe",
+        }
+    }
+}
+
+impl std::fmt::Display for Base64Alphabet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why [`decode_base64`] rejected a payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Base64Error {
+    /// alphabet the payload was decoded with, from its first `+ / - _` character
+    pub alphabet: Base64Alphabet,
+    pub reason: String,
+}
+
+/// Decode `data` as standard or URL-safe base64, padded or not
+///
+/// Strict: whitespace, a mix of both alphabets, wrong padding and non zero trailing bits are errors.
+pub fn decode_base64(data: &[u8]) -> Result<Vec<u8>, Base64Error> {
+    let alphabet = match data.iter().find(|b| matches!(b, b'+' | b'/' | b'-' | b'_')) {
+        Some(b'-' | b'_') => Base64Alphabet::UrlSafe,
+        _ => Base64Alphabet::Standard,
+    };
+    let engine = match alphabet {
+        Base64Alphabet::Standard => &STANDARD_ANY_PAD,
+        Base64Alphabet::UrlSafe => &URL_SAFE_ANY_PAD,
+    };
+
+    engine.decode(data).map_err(|e| Base64Error {
+        alphabet,
+        reason: e.to_string(),
+    })
+}
+
+/// Encode `data` as padded standard base64, the form written by [`super::SecretManagerHelper::create_secret_base64`]
+pub fn encode_base64(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::SecretManagerHelper;
+    use crate::testing::MemorySecretManager;
+    use crate::ErrorCode;
+
+    fn both(data: &[u8]) -> (PayloadEncoding, PayloadEncoding) {
+        (
+            detect_encoding_with(data, Strictness::Strict),
+            detect_encoding_with(data, Strictness::Lenient),
+        )
+    }
+
+    #[test]
+    fn detect_encoding_test() {
+        use PayloadEncoding::*;
+
+        let cases: [(&[u8], PayloadEncoding, PayloadEncoding); 16] = [
+            (b"", Utf8Text, Utf8Text),
+            // hex is valid base64 of the right length, it stays text
+            (b"deadbeefcafebabe", Utf8Text, Utf8Text),
+            (
+                b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                Utf8Text,
+                Utf8Text,
+            ),
+            (b"DEADBEEF00112233", Utf8Text, Utf8Text),
+            // padded and unpadded base64
+            (b"aGVsbG8gd29ybGQ=", Base64, Base64),
+            (b"aGVsbG8gd29ybGQ", Utf8Text, Base64),
+            (b"c2VjcmV0LWtleS0xMg==", Base64, Base64),
+            (b"c2VjcmV0LWtleS0xMg", Utf8Text, Base64),
+            (b"-_-_AAAAAAAAAAAAAAAAAA==", Base64, Base64),
+            // text that happens to be valid base64
+            (b"Test", Utf8Text, Base64),
+            (b"ThisIsMyPassword", Utf8Text, Base64),
+            (b"correct horse battery staple", Utf8Text, Utf8Text),
+            // surrounding whitespace
+            (b"aGVsbG8gd29ybGQ=\n", Utf8Text, Base64),
+            // mixed alphabets
+            (b"ab+cd-efghijklmn", Utf8Text, Utf8Text),
+            // binary blobs
+            (&[0xff, 0xfe, 0x00, 0x41], Binary, Binary),
+            (b"text\0with nul", Binary, Binary),
+        ];
+
+        for (data, strict, lenient) in cases {
+            assert_eq!(
+                both(data),
+                (strict, lenient),
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+
+        let blob: Vec<u8> = (0..=255).collect();
+        assert_eq!(both(&blob), (Binary, Binary));
+        assert_eq!(both(encode_base64(&blob).as_bytes()), (Base64, Base64));
+        assert_eq!(detect_encoding(b"plain text\n"), Utf8Text);
+    }
+
+    #[test]
+    fn decode_base64_test() {
+        assert_eq!(decode_base64(b"aGVsbG8gd29ybGQ=").unwrap(), b"hello world");
+        assert_eq!(decode_base64(b"aGVsbG8gd29ybGQ").unwrap(), b"hello world");
+        assert_eq!(decode_base64(b"-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_base64(b"+/8=").unwrap(), [0xfb, 0xff]);
+
+        let err = decode_base64(b"+/8-").unwrap_err();
+        assert_eq!(err.alphabet, Base64Alphabet::Standard);
+        let err = decode_base64(b"-_8/").unwrap_err();
+        assert_eq!(err.alphabet, Base64Alphabet::UrlSafe);
+        // wrong padding, whitespace and non zero trailing bits
+        assert!(decode_base64(b"aGVsbG8gd29ybGQ==").is_err());
+        assert!(decode_base64(b"aGVsbG8gd29ybGQ=\n").is_err());
+        assert!(decode_base64(b"aGVsbG8gd29ybGR=").is_err());
+    }
+
+    #[tokio::test]
+    async fn secret_base64_test() {
+        let secrets = MemorySecretManager::new();
+        let key: Vec<u8> = (0..32).map(|i| i * 7).collect();
+        secrets
+            .create_secret_base64("p", "key", &key)
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets.get_secret("p", "key").await.unwrap(),
+            encode_base64(&key).as_bytes()
+        );
+        assert_eq!(
+            secrets.get_secret_base64_decoded("p", "key").await.unwrap(),
+            key
+        );
+        assert_eq!(
+            secrets
+                .get_secret_auto("p", "key", Strictness::Strict)
+                .await
+                .unwrap(),
+            (key.clone(), PayloadEncoding::Base64)
+        );
+
+        // double encoded: one layer at a time
+        secrets
+            .create_secret_base64("p", "double", encode_base64(&key).as_bytes())
+            .await
+            .unwrap();
+        let (once, encoding) = secrets
+            .get_secret_auto("p", "double", Strictness::Strict)
+            .await
+            .unwrap();
+        assert_eq!(encoding, PayloadEncoding::Base64);
+        assert_eq!(decode_base64(&once).unwrap(), key);
+
+        secrets
+            .create_secret("p", "word", "not base64!")
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets
+                .get_secret_auto("p", "word", Strictness::Strict)
+                .await
+                .unwrap(),
+            (b"not base64!".to_vec(), PayloadEncoding::Utf8Text)
+        );
+        let err = secrets
+            .get_secret_base64_decoded("p", "word")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+
+        secrets.create_secret("p", "url", "ab-c_d/e").await.unwrap();
+        let err = secrets
+            .get_secret_base64_decoded("p", "url")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("URL-safe"), "{err}");
+    }
+}
@@ -0,0 +1,306 @@
+//! Secrets read under several names, to rename them without downtime
+//!
+//! While a secret is renamed, consumers read the new name first and fall back to the old one until
+//! every writer moved. [`super::SecretManagerHelper::get_secret_with_fallback`] tries the names in order
+//! and tells which one served the value, so lingering reads of the old name can be found:
+//!
+//! ```ignore
+//! let policy = FallbackPolicy::new().on_event(|event| {
+//!     if let FallbackEvent::Served { name, index, .. } = event {
+//!         metrics.increment("secret_served", &[("name", name), ("index", &index.to_string())]);
+//!     }
+//! });
+//! let (key, served) = secrets
+//!     .get_secret_with_fallback_policy(project, &["payments-api-key", "api-key"], &policy)
+//!     .await?;
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::{Error, SecretManagerHelper};
+use crate::{BatchError, ErrorCode, NimbusError};
+
+/// What [`super::SecretManagerHelper::get_secret_with_fallback_policy`] did, reported to the callback
+/// of [`FallbackPolicy::on_event`]
+#[derive(Debug)]
+pub enum FallbackEvent<'a> {
+    /// `name`, at `index` in the names, served the value: 0 is the new name
+    Served {
+        project: &'a str,
+        name: &'a str,
+        index: usize,
+    },
+    /// reading `name` failed with an error falling through to the next name
+    FellThrough {
+        project: &'a str,
+        name: &'a str,
+        index: usize,
+        error: &'a NimbusError,
+    },
+}
+
+type Observer = dyn Fn(FallbackEvent<'_>) + Send + Sync;
+
+/// Errors falling through to the next name, and the observer of the lookups
+///
+/// By default [`ErrorCode::NotFound`] falls through on every name, and [`ErrorCode::PermissionDenied`]
+/// on the old names only, those after the first: a consumer denied the new name is misconfigured and
+/// fails rather than silently reading the old one.
+#[derive(Clone)]
+pub struct FallbackPolicy {
+    fall_through: Vec<ErrorCode>,
+    old_name_fall_through: Vec<ErrorCode>,
+    observer: Option<Arc<Observer>>,
+}
+
+impl Default for FallbackPolicy {
+    fn default() -> Self {
+        Self {
+            fall_through: vec![ErrorCode::NotFound],
+            old_name_fall_through: vec![ErrorCode::PermissionDenied],
+            observer: None,
+        }
+    }
+}
+
+impl FallbackPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// errors falling through on every name, in place of [`ErrorCode::NotFound`]
+    pub fn fall_through(mut self, codes: impl IntoIterator<Item = ErrorCode>) -> Self {
+        self.fall_through = codes.into_iter().collect();
+        self
+    }
+
+    /// errors also falling through on the names after the first, in place of [`ErrorCode::PermissionDenied`]
+    pub fn old_name_fall_through(mut self, codes: impl IntoIterator<Item = ErrorCode>) -> Self {
+        self.old_name_fall_through = codes.into_iter().collect();
+        self
+    }
+
+    /// report the name serving each lookup and the failures falling through to `observer`,
+    /// e.g. to follow the progress of a migration
+    pub fn on_event(
+        mut self,
+        observer: impl Fn(FallbackEvent<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// whether `code` on the name at `index` falls through to the next one
+    pub fn falls_through(&self, index: usize, code: ErrorCode) -> bool {
+        self.fall_through.contains(&code)
+            || (index > 0 && self.old_name_fall_through.contains(&code))
+    }
+
+    fn observe(&self, event: FallbackEvent<'_>) {
+        if let Some(observer) = &self.observer {
+            observer(event);
+        }
+    }
+}
+
+impl fmt::Debug for FallbackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FallbackPolicy")
+            .field("fall_through", &self.fall_through)
+            .field("old_name_fall_through", &self.old_name_fall_through)
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+pub(crate) async fn get<M, S>(
+    secrets: &M,
+    project: &str,
+    names: &[&str],
+    policy: &FallbackPolicy,
+) -> Result<(Vec<u8>, String), NimbusError>
+where
+    M: SecretManagerHelper<S> + Sync + ?Sized,
+{
+    if names.is_empty() {
+        return Err(Error::Other("No secret name to read".to_owned()).into());
+    }
+
+    let mut errors = vec![];
+    for (index, &name) in names.iter().enumerate() {
+        let error = match secrets.get_secret(project, name).await {
+            Ok(value) => {
+                policy.observe(FallbackEvent::Served {
+                    project,
+                    name,
+                    index,
+                });
+                return Ok((value, name.to_owned()));
+            }
+            Err(error) => error,
+        };
+
+        let falls_through = policy.falls_through(index, error.code());
+        if falls_through {
+            policy.observe(FallbackEvent::FellThrough {
+                project,
+                name,
+                index,
+                error: &error,
+            });
+        }
+        errors.push(BatchError {
+            key: name.to_owned(),
+            error,
+        });
+        if !falls_through {
+            break;
+        }
+    }
+
+    Err(Error::NoNameServed(errors).into())
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::{Fault, MemorySecretManager};
+
+    const NAMES: [&str; 3] = ["new", "mid", "old"];
+
+    async fn secrets(names: &[&str]) -> MemorySecretManager {
+        let secrets = MemorySecretManager::new();
+        for name in names {
+            secrets.create_secret("p", name, name).await.unwrap();
+        }
+        secrets
+    }
+
+    #[tokio::test]
+    async fn fallback_order_test() {
+        let served = Arc::new(Mutex::new(vec![]));
+        let policy = FallbackPolicy::new().on_event({
+            let served = served.clone();
+            move |event| match event {
+                FallbackEvent::Served { name, index, .. } => {
+                    served.lock().unwrap().push(format!("{name}@{index}"))
+                }
+                FallbackEvent::FellThrough { name, error, .. } => served
+                    .lock()
+                    .unwrap()
+                    .push(format!("{name}: {:?}", error.code())),
+            }
+        });
+
+        // the first name present serves, whatever the others
+        let all = secrets(&NAMES).await;
+        let (value, name) = all
+            .get_secret_with_fallback_policy("p", &NAMES, &policy)
+            .await
+            .unwrap();
+        assert_eq!((value.as_slice(), name.as_str()), (&b"new"[..], "new"));
+
+        let old_only = secrets(&["old"]).await;
+        let (value, name) = old_only
+            .get_secret_with_fallback_policy("p", &NAMES, &policy)
+            .await
+            .unwrap();
+        assert_eq!((value.as_slice(), name.as_str()), (&b"old"[..], "old"));
+        assert_eq!(
+            *served.lock().unwrap(),
+            ["new@0", "new: NotFound", "mid: NotFound", "old@2"]
+        );
+
+        let (_, name) = old_only
+            .get_secret_with_fallback("p", &["old", "new"])
+            .await
+            .unwrap();
+        assert_eq!(name, "old");
+
+        let err = old_only
+            .get_secret_with_fallback("p", &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Internal);
+    }
+
+    #[tokio::test]
+    async fn fallback_kinds_test() {
+        let secrets = secrets(&NAMES).await;
+        let fail = |name, code| {
+            secrets
+                .mock_stats()
+                .set_object_fault(name, Fault::new().fail(1.0, code))
+        };
+        let calls = || secrets.stats().calls("get_secret_version");
+
+        // denied on an old name: falls through
+        fail("mid", ErrorCode::PermissionDenied);
+        secrets.delete_secret("p", "new").await.unwrap();
+        let (_, name) = secrets.get_secret_with_fallback("p", &NAMES).await.unwrap();
+        assert_eq!(name, "old");
+
+        // denied on the new name: fails at once
+        fail("new", ErrorCode::PermissionDenied);
+        secrets.reset_stats();
+        let err = secrets
+            .get_secret_with_fallback("p", &NAMES)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::PermissionDenied);
+        assert_eq!(calls(), 1);
+
+        // unless configured to fall through
+        let policy =
+            FallbackPolicy::new().fall_through([ErrorCode::NotFound, ErrorCode::PermissionDenied]);
+        let (_, name) = secrets
+            .get_secret_with_fallback_policy("p", &NAMES, &policy)
+            .await
+            .unwrap();
+        assert_eq!(name, "old");
+
+        // with only not found falling through, denied on an old name fails
+        let policy = FallbackPolicy::new().old_name_fall_through([]);
+        fail("new", ErrorCode::NotFound);
+        let err = secrets
+            .get_secret_with_fallback_policy("p", &NAMES, &policy)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::PermissionDenied);
+
+        // unavailable never falls through by default
+        fail("new", ErrorCode::Unavailable);
+        secrets.reset_stats();
+        let err = secrets
+            .get_secret_with_fallback("p", &NAMES)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert_eq!(calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_all_fail_test() {
+        let secrets = secrets(&[]).await;
+        let err = secrets
+            .get_secret_with_fallback("p", &NAMES)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let NimbusError::SecretManager(Error::NoNameServed(errors)) = &err else {
+            panic!("{err:?}");
+        };
+        let tried: Vec<_> = errors.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(tried, NAMES);
+        assert!(errors.iter().all(|e| e.error.code() == ErrorCode::NotFound));
+        let message = err.to_string();
+        for name in NAMES {
+            assert!(message.contains(name), "{message}");
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Secrets larger than the provider limit, split across several secrets
+//!
+//! A payload of at most [`SECRET_SIZE_LIMIT`] bytes is stored as is under its name, and stays readable
+//! with [`super::SecretManagerHelper::get_secret`]. A larger one named `name` is stored as:
+//! - `name__part0` .. `name__partN`: the payload cut in pieces of [`SECRET_SIZE_LIMIT`] bytes, the last
+//!   one possibly shorter, each stored as binary data
+//! - `name__manifest`: a JSON document describing the parts, written once every part is stored
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "size": 204800,
+//!   "sha256": "<hex SHA-256 of the whole payload>",
+//!   "parts": [{ "size": 65536, "sha256": "<hex SHA-256 of the part>" }, ...]
+//! }
+//! ```
+//!
+//! A reader looks for the manifest first and falls back to the plain secret without one. It reads the
+//! parts listed in the manifest, checks the size and hash of each, then the hash of the whole payload.
+
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use super::{Error, SecretManagerHelper};
+use crate::redact::json_error;
+use crate::storage::sha256_hex;
+use crate::{ByteSize, ErrorCode, NimbusError};
+
+/// Largest payload of a single secret, on GCP as on AWS
+pub const SECRET_SIZE_LIMIT: ByteSize = ByteSize::kib(64);
+
+/// Parts read, written or deleted at once by the large secret calls
+pub const LARGE_SECRET_CONCURRENCY: usize = 8;
+
+/// version of the manifest written by this crate
+const MANIFEST_VERSION: u32 = 1;
+
+/// name of the secret holding part `index` of `name`
+pub fn part_name(name: &str, index: usize) -> String {
+    format!("{name}__part{index}")
+}
+
+/// name of the secret holding the manifest of `name`
+pub fn manifest_name(name: &str) -> String {
+    format!("{name}__manifest")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Part {
+    size: usize,
+    sha256: String,
+}
+
+/// content of the manifest secret
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    size: usize,
+    sha256: String,
+    parts: Vec<Part>,
+}
+
+impl Manifest {
+    fn new(data: &[u8], part_size: usize) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            size: data.len(),
+            sha256: sha256_hex(data),
+            parts: data
+                .chunks(part_size)
+                .map(|chunk| Part {
+                    size: chunk.len(),
+                    sha256: sha256_hex(chunk),
+                })
+                .collect(),
+        }
+    }
+
+    fn parse(name: &str, data: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: String| Error::InvalidManifest {
+            secret: name.to_owned(),
+            reason,
+        };
+
+        let manifest: Self = serde_json::from_slice(data).map_err(|e| invalid(json_error(&e)))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(invalid(format!("unsupported version {}", manifest.version)));
+        }
+        if manifest.parts.iter().map(|p| p.size).sum::<usize>() != manifest.size {
+            return Err(invalid("sizes of the parts don't add up".to_owned()));
+        }
+
+        Ok(manifest)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("large secret manifest serializes")
+    }
+}
+
+/// check a part read back against the manifest
+fn verify_part(name: &str, part: &Part, data: &[u8]) -> Result<(), Error> {
+    let actual = sha256_hex(data);
+    if data.len() != part.size || actual != part.sha256 {
+        return Err(Error::PartCorrupted {
+            part: name.to_owned(),
+            expected: part.sha256.clone(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn put<M, S>(
+    secrets: &M,
+    project: &str,
+    name: &str,
+    data: &[u8],
+    part_size: usize,
+) -> Result<(), NimbusError>
+where
+    M: SecretManagerHelper<S> + Sync + ?Sized,
+{
+    if data.len() <= part_size {
+        return secrets.create_secret_bytes(project, name, data).await;
+    }
+
+    let manifest = Manifest::new(data, part_size);
+    // indices rather than the chunks themselves, borrowed arguments trip the `Send` check of async_trait
+    let results: Vec<_> = futures::stream::iter(0..manifest.parts.len())
+        .map(|index| async move {
+            let part = part_name(name, index);
+            let end = data.len().min((index + 1) * part_size);
+            let res = secrets
+                .create_secret_bytes(project, &part, &data[index * part_size..end])
+                .await;
+            (part, res)
+        })
+        .buffer_unordered(LARGE_SECRET_CONCURRENCY)
+        .collect()
+        .await;
+
+    // parts that existed before are left alone, they may belong to another payload
+    if let Some(error) = results.iter().position(|(_, res)| res.is_err()) {
+        let created = results.iter().filter(|(_, res)| res.is_ok());
+        futures::stream::iter(created)
+            .for_each_concurrent(LARGE_SECRET_CONCURRENCY, |(part, _)| async move {
+                let _ = secrets.delete_secret(project, part).await;
+            })
+            .await;
+
+        let (_, res) = results.into_iter().nth(error).expect("failed part");
+        return res;
+    }
+
+    secrets
+        .create_secret_bytes(project, &manifest_name(name), &manifest.to_bytes())
+        .await
+}
+
+pub(crate) async fn get<M, S>(
+    secrets: &M,
+    project: &str,
+    name: &str,
+) -> Result<Vec<u8>, NimbusError>
+where
+    M: SecretManagerHelper<S> + Sync + ?Sized,
+{
+    let Some(manifest) = read_manifest(secrets, project, name).await? else {
+        return secrets.get_secret(project, name).await;
+    };
+
+    let manifest = &manifest;
+    let parts: Vec<Vec<u8>> = futures::stream::iter(0..manifest.parts.len())
+        .map(|index| async move {
+            let part = &manifest.parts[index];
+            let part_name = part_name(name, index);
+            let data = match secrets.get_secret(project, &part_name).await {
+                Ok(data) => data,
+                Err(e) if e.code() == ErrorCode::NotFound => {
+                    return Err(Error::PartMissing(part_name).into())
+                }
+                Err(e) => return Err(e),
+            };
+            verify_part(&part_name, part, &data)?;
+            Ok::<_, NimbusError>(data)
+        })
+        .buffered(LARGE_SECRET_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let data = parts.concat();
+    if sha256_hex(&data) != manifest.sha256 {
+        return Err(Error::InvalidManifest {
+            secret: manifest_name(name),
+            reason: "hash of the payload doesn't match".to_owned(),
+        }
+        .into());
+    }
+
+    Ok(data)
+}
+
+pub(crate) async fn delete<M, S>(secrets: &M, project: &str, name: &str) -> Result<(), NimbusError>
+where
+    M: SecretManagerHelper<S> + Sync + ?Sized,
+{
+    let Some(manifest) = read_manifest(secrets, project, name).await? else {
+        return secrets.delete_secret(project, name).await;
+    };
+
+    // parts already gone were deleted by an interrupted call, the manifest goes last so it can be retried
+    futures::stream::iter(0..manifest.parts.len())
+        .map(|index| async move {
+            match secrets
+                .delete_secret(project, &part_name(name, index))
+                .await
+            {
+                Err(e) if e.code() != ErrorCode::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        })
+        .buffer_unordered(LARGE_SECRET_CONCURRENCY)
+        .try_collect::<()>()
+        .await?;
+
+    secrets.delete_secret(project, &manifest_name(name)).await
+}
+
+/// manifest of `name`, `None` for a secret stored as is
+async fn read_manifest<M, S>(
+    secrets: &M,
+    project: &str,
+    name: &str,
+) -> Result<Option<Manifest>, NimbusError>
+where
+    M: SecretManagerHelper<S> + Sync + ?Sized,
+{
+    let manifest = manifest_name(name);
+    match secrets.get_secret(project, &manifest).await {
+        Ok(data) => Ok(Some(Manifest::parse(&manifest, &data)?)),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemorySecretManager;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn manifest_test() {
+        let data = payload(10);
+        let manifest = Manifest::new(&data, 4);
+        assert_eq!(
+            manifest.parts.iter().map(|p| p.size).collect::<Vec<_>>(),
+            [4, 4, 2]
+        );
+        assert_eq!(manifest.parts[2].sha256, sha256_hex(&data[8..]));
+
+        let parsed = Manifest::parse("m", &manifest.to_bytes()).unwrap();
+        assert_eq!(parsed, manifest);
+
+        let mut future = manifest.to_bytes();
+        future[11] = b'2';
+        assert!(Manifest::parse("m", &future).is_err());
+        assert!(Manifest::parse("m", b"{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn large_secret_test() {
+        let secrets = MemorySecretManager::new();
+        let data = payload(SECRET_SIZE_LIMIT.as_usize() * 3 + 100);
+        secrets
+            .put_large_secret("p", "bundle", &data)
+            .await
+            .unwrap();
+
+        assert_eq!(secrets.get_large_secret("p", "bundle").await.unwrap(), data);
+        assert_eq!(
+            secrets
+                .get_secret("p", &part_name("bundle", 3))
+                .await
+                .unwrap(),
+            &data[SECRET_SIZE_LIMIT.as_usize() * 3..]
+        );
+        let plain = secrets.get_secret("p", "bundle").await.unwrap_err();
+        assert_eq!(plain.code(), ErrorCode::NotFound);
+
+        // small payloads are plain secrets
+        secrets
+            .put_large_secret("p", "small", b"value")
+            .await
+            .unwrap();
+        assert_eq!(secrets.get_secret("p", "small").await.unwrap(), b"value");
+        assert_eq!(
+            secrets.get_large_secret("p", "small").await.unwrap(),
+            b"value"
+        );
+
+        secrets.delete_large_secret("p", "bundle").await.unwrap();
+        secrets.delete_large_secret("p", "small").await.unwrap();
+        for name in [
+            manifest_name("bundle"),
+            part_name("bundle", 0),
+            "small".to_owned(),
+        ] {
+            let gone = secrets.get_secret("p", &name).await.unwrap_err();
+            assert_eq!(gone.code(), ErrorCode::NotFound);
+        }
+    }
+
+    #[tokio::test]
+    async fn damaged_part_test() {
+        let secrets = MemorySecretManager::new();
+        let data = payload(SECRET_SIZE_LIMIT.as_usize() * 2 + 1);
+        secrets
+            .put_large_secret("p", "bundle", &data)
+            .await
+            .unwrap();
+
+        secrets.add_version("p", &part_name("bundle", 1), b"tampered".to_vec());
+        let corrupted = secrets.get_large_secret("p", "bundle").await.unwrap_err();
+        assert!(matches!(
+            corrupted.without_context(),
+            NimbusError::SecretManager(Error::PartCorrupted { part, .. }) if *part == part_name("bundle", 1)
+        ));
+
+        secrets
+            .delete_secret("p", &part_name("bundle", 0))
+            .await
+            .unwrap();
+        let missing = secrets.get_large_secret("p", "bundle").await.unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::NotFound);
+        assert!(missing.to_string().contains("bundle__part0"));
+
+        // deleting again after an interrupted delete
+        secrets.delete_large_secret("p", "bundle").await.unwrap();
+        let gone = secrets.get_large_secret("p", "bundle").await.unwrap_err();
+        assert_eq!(gone.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn failed_put_test() {
+        let secrets = MemorySecretManager::new();
+        secrets.add_version("p", &part_name("bundle", 1), b"someone else's".to_vec());
+
+        let data = payload(SECRET_SIZE_LIMIT.as_usize() * 2 + 1);
+        let exists = secrets
+            .put_large_secret("p", "bundle", &data)
+            .await
+            .unwrap_err();
+        assert_eq!(exists.code(), ErrorCode::AlreadyExists);
+
+        // the parts it created are removed, the one it didn't is kept
+        for name in [
+            part_name("bundle", 0),
+            part_name("bundle", 2),
+            manifest_name("bundle"),
+        ] {
+            let gone = secrets.get_secret("p", &name).await.unwrap_err();
+            assert_eq!(gone.code(), ErrorCode::NotFound);
+        }
+        assert!(secrets
+            .get_secret("p", &part_name("bundle", 1))
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,242 @@
+//! Secret versions pinned per release, like a lockfile
+//!
+//! A [`Pinfile`] records the version of each secret a release was tested with.
+//! Deploys read the pinned versions with [`SecretManagerHelper::get_pinned`] and CI warns about secrets
+//! rotated since with [`SecretManagerHelper::check_drift`].
+//!
+//! # Format
+//!
+//! Pinfiles are JSON, written with [`Pinfile::to_json`]:
+//!
+//! ```json
+//! {
+//!   "format": 1,
+//!   "project": "my-project",
+//!   "secrets": {
+//!     "api-key": "7",
+//!     "db-password": "3"
+//!   }
+//! }
+//! ```
+//!
+//! - `format` is [`PINFILE_FORMAT`], bumped on incompatible changes; other formats are rejected
+//! - `project` is the project of the secrets, unused on AWS
+//! - `secrets` maps each secret name to its pinned version id, sorted by name:
+//!   the version number on GCP, the `VersionId` on AWS
+//!
+//! Unknown fields are ignored.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Error, SecretManagerHelper};
+use crate::report::{self, Report};
+use crate::{BatchError, NimbusError};
+
+/// Version of the pinfile format written by this crate
+pub const PINFILE_FORMAT: u32 = 1;
+
+/// Secret versions pinned for a release, see the [module documentation](self) for the format
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pinfile {
+    pub format: u32,
+    pub project: String,
+    /// pinned version id by secret name
+    pub secrets: BTreeMap<String, String>,
+}
+
+impl Pinfile {
+    pub fn new(project: impl Into<String>) -> Self {
+        Self {
+            format: PINFILE_FORMAT,
+            project: project.into(),
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    /// pin `name` to `version`
+    pub fn pin(mut self, name: impl Into<String>, version: impl Into<String>) -> Self {
+        self.secrets.insert(name.into(), version.into());
+        self
+    }
+
+    /// pinned version of `name`
+    pub fn version(&self, name: &str) -> Option<&str> {
+        self.secrets.get(name).map(String::as_str)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a pinfile is always serializable")
+    }
+
+    /// fails with [`Error::InvalidPinfile`] on malformed JSON or another format
+    // `Error` is as large as the google api error it wraps under the gcp feature
+    #[allow(clippy::result_large_err)]
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let pinfile: Self =
+            serde_json::from_str(json).map_err(|e| Error::InvalidPinfile(e.to_string()))?;
+        if pinfile.format != PINFILE_FORMAT {
+            return Err(Error::InvalidPinfile(format!(
+                "unsupported format {}, expected {PINFILE_FORMAT}",
+                pinfile.format
+            )));
+        }
+
+        Ok(pinfile)
+    }
+}
+
+/// Pin the current version of each of `names`, fails if any can't be resolved
+pub async fn create_pinfile<M, S>(
+    secrets: &M,
+    project: &str,
+    names: &[&str],
+) -> Result<Pinfile, NimbusError>
+where
+    M: SecretManagerHelper<S> + Sync + ?Sized,
+{
+    let mut pinfile = Pinfile::new(project);
+    for name in names {
+        let version = secrets.latest_version_id(project, name).await?;
+        pinfile = pinfile.pin(*name, version);
+    }
+
+    Ok(pinfile)
+}
+
+/// Secret whose current version is not the pinned one
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Drift {
+    pub name: String,
+    pub pinned: String,
+    pub latest: String,
+}
+
+/// Outcome of [`SecretManagerHelper::check_drift`]
+///
+/// A secret that can't be checked, e.g. because its pinned version was destroyed,
+/// is reported in [`DriftReport::failed`] without stopping the check of the others.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DriftReport {
+    pub drifted: Vec<Drift>,
+    pub failed: Vec<BatchError<String>>,
+}
+
+impl DriftReport {
+    /// true if every pinned version is current and readable
+    pub fn is_clean(&self) -> bool {
+        self.drifted.is_empty() && self.failed.is_empty()
+    }
+}
+
+impl Report for DriftReport {
+    fn summary_line(&self) -> String {
+        format!(
+            "drift: {} drifted, {} failed",
+            self.drifted.len(),
+            self.failed.len()
+        )
+    }
+
+    fn has_failures(&self) -> bool {
+        !self.is_clean()
+    }
+}
+
+impl fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())?;
+        let mut drifted: Vec<_> = self.drifted.iter().collect();
+        drifted.sort_by(|a, b| a.name.cmp(&b.name));
+        report::write_section(
+            f,
+            "drifted",
+            drifted
+                .iter()
+                .map(|d| format!("{}: pinned {}, latest {}", d.name, d.pinned, d.latest)),
+        )?;
+        let mut failed: Vec<_> = self.failed.iter().collect();
+        failed.sort_by(|a, b| a.key.cmp(&b.key));
+        report::write_section(
+            f,
+            "failed",
+            failed.iter().map(|e| format!("{}: {}", e.key, e.error)),
+        )
+    }
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemorySecretManager;
+    use crate::ErrorCode;
+
+    #[test]
+    fn pinfile_format_test() {
+        let pinfile = Pinfile::new("p").pin("b", "3").pin("a", "7");
+        let json = pinfile.to_json();
+        assert_eq!(
+            json,
+            "{\n  \"format\": 1,\n  \"project\": \"p\",\n  \"secrets\": {\n    \"a\": \"7\",\n    \"b\": \"3\"\n  }\n}"
+        );
+        assert_eq!(Pinfile::from_json(&json).unwrap(), pinfile);
+
+        let newer = json.replace("\"format\": 1", "\"format\": 2");
+        let err = Pinfile::from_json(&newer).unwrap_err();
+        assert!(matches!(err, Error::InvalidPinfile(_)));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert!(Pinfile::from_json("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn pin_drift_test() {
+        let secrets = MemorySecretManager::new();
+        secrets.add_version("p", "api-key", "key-1");
+        secrets.add_version("p", "api-key", "key-2");
+        secrets.add_version("p", "db", "db-1");
+        secrets.add_version("p", "token", "token-1");
+
+        let pinfile = create_pinfile(&secrets, "p", &["api-key", "db", "token"])
+            .await
+            .unwrap();
+        assert_eq!(pinfile.version("api-key"), Some("2"));
+        let missing = create_pinfile(&secrets, "p", &["db", "nope"]).await;
+        assert_eq!(missing.unwrap_err().code(), ErrorCode::NotFound);
+
+        assert!(secrets.check_drift(&pinfile).await.unwrap().is_clean());
+
+        // rotations don't change what a release reads, but are reported
+        secrets.add_version("p", "api-key", "key-3");
+        secrets.add_version("p", "db", "db-2");
+        secrets.destroy_version("p", "db", 1);
+        assert_eq!(
+            secrets.get_pinned(&pinfile, "api-key").await.unwrap(),
+            b"key-2"
+        );
+        let not_pinned = secrets.get_pinned(&pinfile, "other").await.unwrap_err();
+        assert!(matches!(
+            not_pinned,
+            NimbusError::SecretManager(Error::NotPinned(_))
+        ));
+
+        let report = secrets.check_drift(&pinfile).await.unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(
+            report.drifted,
+            [Drift {
+                name: "api-key".to_owned(),
+                pinned: "2".to_owned(),
+                latest: "3".to_owned()
+            }]
+        );
+        // the destroyed pin is reported on its own, `token` is still checked
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].key, "db");
+        assert_eq!(report.failed[0].error.code(), ErrorCode::NotFound);
+    }
+}
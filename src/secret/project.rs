@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use super::{Error, SecretManagerHelper};
+use crate::NimbusError;
+
+/// Secret manager bound to a project, from [`SecretManagerHelper::with_project`]
+///
+/// ```ignore
+/// let secrets = SecretManager::new_with_authenticator(auth).await.with_project("my-project");
+/// secrets.create("api-key", "value").await?;
+/// let key = secrets.get("api-key").await?;
+/// ```
+///
+/// Calls to other projects go through the `_in` methods, e.g. [`WithProject::get_in`]. With an
+/// allow-list (see [`WithProject::allow_projects`]) they fail with [`Error::ProjectNotAllowed`]
+/// for any project not in it, a guard against reading another tenant's secrets.
+/// [`WithProject::inner`] bypasses the allow-list.
+///
+/// Cloning is as cheap as cloning the wrapped secret manager, which for the SDK clients and
+/// the mocks only bumps a reference count; clones share the same connections.
+#[derive(Debug)]
+pub struct WithProject<M, S> {
+    secrets: M,
+    project: Arc<str>,
+    /// projects the `_in` methods may call besides the bound one, any without allow-list
+    allowed: Option<Arc<HashSet<String>>>,
+    connector: PhantomData<fn() -> S>,
+}
+
+// derived `Clone` would require the connector to be `Clone` as well
+impl<M: Clone, S> Clone for WithProject<M, S> {
+    fn clone(&self) -> Self {
+        Self {
+            secrets: self.secrets.clone(),
+            project: Arc::clone(&self.project),
+            allowed: self.allowed.clone(),
+            connector: PhantomData,
+        }
+    }
+}
+
+impl<M: SecretManagerHelper<S> + Sync, S> WithProject<M, S> {
+    pub fn new(secrets: M, project: impl Into<String>) -> Self {
+        Self {
+            secrets,
+            project: Arc::from(project.into()),
+            allowed: None,
+            connector: PhantomData,
+        }
+    }
+
+    /// only let the `_in` methods call `projects`, and the bound project
+    pub fn allow_projects(mut self, projects: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let allowed = projects.into_iter().map(Into::into).collect();
+        self.allowed = Some(Arc::new(allowed));
+        self
+    }
+
+    pub fn project(&self) -> &str {
+        &self.project
+    }
+
+    /// whether the `_in` methods may call `project`
+    pub fn is_allowed(&self, project: &str) -> bool {
+        *self.project == *project
+            || self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(project))
+    }
+
+    /// the wrapped secret manager, for calls to other projects without the allow-list
+    pub fn inner(&self) -> &M {
+        &self.secrets
+    }
+
+    pub fn into_inner(self) -> M {
+        self.secrets
+    }
+
+    /// latest version of a secret
+    pub async fn get(&self, name: &str) -> Result<Vec<u8>, NimbusError> {
+        self.secrets.get_secret(&self.project, name).await
+    }
+
+    pub async fn get_version(&self, name: &str, version: &str) -> Result<Vec<u8>, NimbusError> {
+        self.secrets
+            .get_secret_version(&self.project, name, version)
+            .await
+    }
+
+    pub async fn create(&self, name: &str, value: &str) -> Result<(), NimbusError> {
+        self.secrets.create_secret(&self.project, name, value).await
+    }
+
+    pub async fn create_bytes(&self, name: &str, data: &[u8]) -> Result<(), NimbusError> {
+        self.secrets
+            .create_secret_bytes(&self.project, name, data)
+            .await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), NimbusError> {
+        self.secrets.delete_secret(&self.project, name).await
+    }
+
+    /// see [`SecretManagerHelper::put_large_secret`]
+    pub async fn put_large(&self, name: &str, data: &[u8]) -> Result<(), NimbusError> {
+        self.secrets
+            .put_large_secret(&self.project, name, data)
+            .await
+    }
+
+    /// see [`SecretManagerHelper::get_large_secret`]
+    pub async fn get_large(&self, name: &str) -> Result<Vec<u8>, NimbusError> {
+        self.secrets.get_large_secret(&self.project, name).await
+    }
+
+    /// see [`SecretManagerHelper::delete_large_secret`]
+    pub async fn delete_large(&self, name: &str) -> Result<(), NimbusError> {
+        self.secrets.delete_large_secret(&self.project, name).await
+    }
+
+    /// id of the current version of a secret, see [`SecretManagerHelper::latest_version_id`]
+    pub async fn latest_version_id(&self, name: &str) -> Result<String, NimbusError> {
+        self.secrets.latest_version_id(&self.project, name).await
+    }
+
+    /// latest version of a secret of `project`, if allowed
+    pub async fn get_in(&self, project: &str, name: &str) -> Result<Vec<u8>, NimbusError> {
+        self.check(project)?;
+        self.secrets.get_secret(project, name).await
+    }
+
+    pub async fn get_version_in(
+        &self,
+        project: &str,
+        name: &str,
+        version: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.check(project)?;
+        self.secrets
+            .get_secret_version(project, name, version)
+            .await
+    }
+
+    pub async fn create_in(
+        &self,
+        project: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), NimbusError> {
+        self.check(project)?;
+        self.secrets.create_secret(project, name, value).await
+    }
+
+    fn check(&self, project: &str) -> Result<(), Error> {
+        if !self.is_allowed(project) {
+            return Err(Error::ProjectNotAllowed(project.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use crate::testing::MemorySecretManager;
+    use crate::ErrorCode;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn with_project_test() {
+        let memory = MemorySecretManager::new();
+        let secrets = memory.clone().with_project("p");
+        assert_eq!(secrets.project(), "p");
+
+        secrets.create("s", "one").await.unwrap();
+        memory.add_version("p", "s", "two");
+        memory.add_version("other", "s", "elsewhere");
+
+        assert_eq!(secrets.get("s").await.unwrap(), b"two");
+        assert_eq!(secrets.get_version("s", "1").await.unwrap(), b"one");
+        assert_eq!(memory.get_secret("p", "s").await.unwrap(), b"two");
+
+        let missing = secrets.get("elsewhere").await.unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn with_project_clone_test() {
+        let secrets = MemorySecretManager::new().with_project("p");
+        let clone = secrets.clone();
+
+        // clones are handed to tasks and share the same secrets
+        tokio::spawn(async move { clone.create("s", "from task").await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(secrets.get("s").await.unwrap(), b"from task");
+    }
+
+    #[tokio::test]
+    async fn project_override_test() {
+        let memory = MemorySecretManager::new();
+        memory.add_version("tenant-a", "s", "a");
+        memory.add_version("tenant-b", "s", "b");
+
+        // without allow-list any project goes
+        let secrets = memory.clone().with_project("home");
+        assert!(secrets.is_allowed("tenant-b"));
+        assert_eq!(secrets.get_in("tenant-a", "s").await.unwrap(), b"a");
+        secrets.create_in("tenant-b", "new", "value").await.unwrap();
+        assert_eq!(
+            memory.get_secret("tenant-b", "new").await.unwrap(),
+            b"value"
+        );
+        assert_eq!(
+            secrets.latest_version_id("s").await.unwrap_err().code(),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn allow_projects_test() {
+        let memory = MemorySecretManager::new();
+        memory.add_version("home", "s", "home");
+        memory.add_version("tenant-a", "s", "a");
+        memory.add_version("tenant-b", "s", "b");
+
+        let secrets = memory
+            .clone()
+            .with_project("home")
+            .allow_projects(["tenant-a"]);
+        assert!(secrets.is_allowed("home"));
+        assert!(secrets.is_allowed("tenant-a"));
+        assert!(!secrets.is_allowed("tenant-b"));
+
+        assert_eq!(secrets.get_in("home", "s").await.unwrap(), b"home");
+        assert_eq!(secrets.get_in("tenant-a", "s").await.unwrap(), b"a");
+        assert_eq!(
+            secrets.get_version_in("tenant-a", "s", "1").await.unwrap(),
+            b"a"
+        );
+
+        let calls = memory.stats().total_calls();
+        let err = secrets.get_in("tenant-b", "s").await.unwrap_err();
+        assert!(matches!(
+            &err,
+            NimbusError::SecretManager(Error::ProjectNotAllowed(p)) if p == "tenant-b"
+        ));
+        assert_eq!(err.code(), ErrorCode::PermissionDenied);
+        let err = secrets
+            .create_in("tenant-b", "s", "overwritten")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::PermissionDenied);
+        secrets
+            .get_version_in("tenant-b", "s", "1")
+            .await
+            .unwrap_err();
+        // refused before reaching the backend
+        assert_eq!(memory.stats().total_calls(), calls);
+        assert_eq!(memory.get_secret("tenant-b", "s").await.unwrap(), b"b");
+
+        // clones keep the allow-list
+        assert!(!secrets.clone().is_allowed("tenant-b"));
+    }
+}
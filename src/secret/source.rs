@@ -0,0 +1,382 @@
+use std::fmt;
+
+use super::{Error, SecretManagerHelper};
+use crate::{BatchOutcome, ErrorCode, NimbusError};
+
+/// Where the value of a secret came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// the environment variable of that name
+    Env(String),
+    /// the latest version of a secret
+    SecretManager { project: String, name: String },
+    /// the default of the chain
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Env(var) => write!(f, "env {var}"),
+            Source::SecretManager { project, name } => write!(f, "secret manager {project}/{name}"),
+            Source::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// Value resolved by a [`SourceChain`], with the source that had it
+///
+/// Debug leaves the value out, so it can be logged as is.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretValue {
+    value: Vec<u8>,
+    source: Source,
+}
+
+impl SecretValue {
+    /// the source that satisfied the lookup
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// the value, if it is UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.value
+    }
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretValue")
+            .field("value", &"<redacted>")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+/// Sources of a secret, tried in order
+///
+/// An unset environment variable or a secret that doesn't exist falls through to the next source,
+/// any other failure, e.g. a denied or unavailable Secret Manager, fails the lookup.
+/// The default, if any, is tried last.
+///
+/// In [strict](SourceChain::strict) mode only Secret Manager can satisfy the lookup:
+/// a set environment variable or the default that would have been used fails it with
+/// [`Error::StrictSource`] instead of being silently ignored.
+///
+/// ```ignore
+/// let password = SourceChain::new()
+///     .env("DB_PASSWORD")
+///     .secret_manager(project, "db-password")
+///     .default_value("postgres")
+///     .strict(app_env == "prod")
+///     .resolve(&secrets)
+///     .await?;
+/// info!("db password from {}", password.source());
+/// ```
+#[derive(Clone, Default)]
+pub struct SourceChain {
+    sources: Vec<Source>,
+    default: Option<Vec<u8>>,
+    strict: bool,
+}
+
+impl SourceChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// try the environment variable `var`
+    pub fn env(mut self, var: impl Into<String>) -> Self {
+        self.sources.push(Source::Env(var.into()));
+        self
+    }
+
+    /// try the latest version of `name` in `project`
+    pub fn secret_manager(mut self, project: impl Into<String>, name: impl Into<String>) -> Self {
+        self.sources.push(Source::SecretManager {
+            project: project.into(),
+            name: name.into(),
+        });
+        self
+    }
+
+    /// value if no source has the secret
+    pub fn default_value(mut self, value: impl Into<Vec<u8>>) -> Self {
+        self.default = Some(value.into());
+        self
+    }
+
+    /// only accept values from Secret Manager, defaults to false
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// the sources, in the order they are tried, the default excluded
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    /// value of the first source that has the secret
+    ///
+    /// fails with [`Error::NoSource`] if none has it, [`Error::StrictSource`] if
+    /// in strict mode it would come from anywhere else than Secret Manager
+    pub async fn resolve<M, S>(&self, secrets: &M) -> Result<SecretValue, NimbusError>
+    where
+        M: SecretManagerHelper<S> + Sync + ?Sized,
+    {
+        for source in &self.sources {
+            let value = match source {
+                Source::Env(var) => match std::env::var_os(var) {
+                    Some(value) => value.into_string().map(String::into_bytes).map_err(|_| {
+                        Error::Other(format!("Environment variable {var} is not unicode"))
+                    })?,
+                    None => continue,
+                },
+                Source::SecretManager { project, name } => {
+                    match secrets.get_secret(project, name).await {
+                        Ok(value) => value,
+                        Err(e) if e.code() == ErrorCode::NotFound => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Source::Default => unreachable!("the default is not in the sources"),
+            };
+
+            return self.accept(source.clone(), value);
+        }
+
+        match &self.default {
+            Some(value) => self.accept(Source::Default, value.clone()),
+            None => Err(Error::NoSource(self.describe()).into()),
+        }
+    }
+
+    fn accept(&self, source: Source, value: Vec<u8>) -> Result<SecretValue, NimbusError> {
+        if self.strict && !matches!(source, Source::SecretManager { .. }) {
+            return Err(Error::StrictSource {
+                secret: self.describe(),
+                from: source.to_string(),
+            }
+            .into());
+        }
+
+        Ok(SecretValue { value, source })
+    }
+
+    /// the sources for error messages, never the default value
+    fn describe(&self) -> String {
+        let mut sources: Vec<String> = self.sources.iter().map(Source::to_string).collect();
+        if self.default.is_some() {
+            sources.push(Source::Default.to_string());
+        }
+        format!("[{}]", sources.join(", "))
+    }
+}
+
+impl fmt::Debug for SourceChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SourceChain")
+            .field("sources", &self.sources)
+            .field("default", &self.default.as_ref().map(|_| "<redacted>"))
+            .field("strict", &self.strict)
+            .finish()
+    }
+}
+
+/// Named [`SourceChain`]s, resolved together at startup
+///
+/// ```ignore
+/// let resolved = SourceChainSet::new()
+///     .chain("db", SourceChain::new().env("DB_PASSWORD").secret_manager(project, "db-password"))
+///     .chain("api", SourceChain::new().secret_manager(project, "api-key"))
+///     .strict(app_env == "prod")
+///     .resolve(&secrets)
+///     .await;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SourceChainSet {
+    chains: Vec<(String, SourceChain)>,
+    strict: bool,
+}
+
+impl SourceChainSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add `chain` under `name`, replacing any chain of that name
+    pub fn chain(mut self, name: impl Into<String>, chain: SourceChain) -> Self {
+        let name = name.into();
+        self.chains.retain(|(n, _)| *n != name);
+        self.chains.push((name, chain));
+        self
+    }
+
+    /// make every chain strict, see [`SourceChain::strict`]
+    /// chains made strict on their own stay strict
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// resolve every chain concurrently
+    /// a failing chain is reported in the outcome without stopping the others
+    pub async fn resolve<M, S>(&self, secrets: &M) -> BatchOutcome<String, SecretValue>
+    where
+        M: SecretManagerHelper<S> + Sync + ?Sized,
+    {
+        let lookups = self.chains.iter().map(|(name, chain)| async move {
+            let result = if self.strict && !chain.strict {
+                chain.clone().strict(true).resolve(secrets).await
+            } else {
+                chain.resolve(secrets).await
+            };
+            (name.clone(), result)
+        });
+
+        futures::future::join_all(lookups)
+            .await
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, MemorySecretManager};
+
+    fn secrets() -> MemorySecretManager {
+        let secrets = MemorySecretManager::new();
+        secrets.add_version("p", "db-password", "from-sm");
+        secrets
+    }
+
+    fn chain(var: &str) -> SourceChain {
+        SourceChain::new()
+            .env(var)
+            .secret_manager("p", "db-password")
+            .default_value("from-default")
+    }
+
+    #[tokio::test]
+    async fn fallback_test() {
+        // env vars are process wide, each test uses its own
+        let var = "NIMBUS_SOURCE_FALLBACK_TEST";
+        std::env::set_var(var, "from-env");
+        let value = chain(var).resolve(&secrets()).await.unwrap();
+        assert_eq!(value.as_str(), Some("from-env"));
+        assert_eq!(value.source(), &Source::Env(var.to_owned()));
+        assert!(!format!("{value:?}").contains("from-env"));
+
+        std::env::remove_var(var);
+        let value = chain(var).resolve(&secrets()).await.unwrap();
+        assert_eq!(value.as_bytes(), b"from-sm");
+        assert_eq!(value.source().to_string(), "secret manager p/db-password");
+
+        let value = chain(var)
+            .resolve(&MemorySecretManager::new())
+            .await
+            .unwrap();
+        assert_eq!(value.into_bytes(), b"from-default");
+
+        let err = SourceChain::new()
+            .env(var)
+            .secret_manager("p", "db-password")
+            .resolve(&MemorySecretManager::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::SecretManager(Error::NoSource(_))
+        ));
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.to_string().contains(var));
+
+        // an unavailable secret manager is not a missing secret
+        let secrets = secrets();
+        secrets.mock_stats().set_fault(
+            "get_secret_version",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        let err = chain(var).resolve(&secrets).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+
+        // sources are tried in the order given
+        std::env::set_var(var, "from-env");
+        let value = SourceChain::new()
+            .secret_manager("p", "db-password")
+            .env(var)
+            .resolve(&self::secrets())
+            .await
+            .unwrap();
+        assert_eq!(value.as_str(), Some("from-sm"));
+        std::env::remove_var(var);
+    }
+
+    #[tokio::test]
+    async fn strict_test() {
+        let var = "NIMBUS_SOURCE_STRICT_TEST";
+        let value = chain(var).strict(true).resolve(&secrets()).await.unwrap();
+        assert_eq!(value.as_str(), Some("from-sm"));
+
+        let err = chain(var)
+            .strict(true)
+            .resolve(&MemorySecretManager::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            NimbusError::SecretManager(Error::StrictSource { from, .. }) if from == "default"
+        ));
+        assert_eq!(err.code(), ErrorCode::PreconditionFailed);
+        assert!(!err.to_string().contains("from-default"));
+
+        std::env::set_var(var, "from-env");
+        let err = chain(var)
+            .strict(true)
+            .resolve(&secrets())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            NimbusError::SecretManager(Error::StrictSource { from, .. }) if *from == format!("env {var}")
+        ));
+        std::env::remove_var(var);
+    }
+
+    #[tokio::test]
+    async fn chain_set_test() {
+        let var = "NIMBUS_SOURCE_SET_TEST";
+        std::env::set_var(var, "from-env");
+        let secrets = secrets();
+        secrets.add_version("p", "api-key", "key");
+
+        let set = SourceChainSet::new()
+            .chain("db", chain(var))
+            .chain("api", SourceChain::new().secret_manager("p", "api-key"))
+            .chain("missing", SourceChain::new().secret_manager("p", "nope"));
+        let outcome = set.resolve(&secrets).await;
+        assert_eq!(outcome.succeeded["db"].as_str(), Some("from-env"));
+        assert_eq!(outcome.succeeded["api"].as_str(), Some("key"));
+        assert_eq!(outcome.not_found().collect::<Vec<_>>(), ["missing"]);
+
+        let outcome = set.strict(true).resolve(&secrets).await;
+        assert_eq!(outcome.succeeded.len(), 1);
+        assert!(outcome.succeeded.contains_key("api"));
+        let db = outcome.failed.iter().find(|e| e.key == "db").unwrap();
+        assert_eq!(db.error.code(), ErrorCode::PreconditionFailed);
+        std::env::remove_var(var);
+    }
+}
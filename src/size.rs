@@ -0,0 +1,182 @@
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+const UNITS: [(&str, u64); 5] = [
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("B", 1),
+];
+
+/// A number of bytes, taken by every size limit of the crate instead of a bare integer
+///
+/// Built with the binary unit constructors (`ByteSize::mib(8)`) or from a `u64` of bytes,
+/// parsed from and displayed as `8MiB`, `64KiB` or `1500B`.
+/// Decimal units (`kB`, `MB`...) are rejected when parsing: they are too often meant as binary ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn b(bytes: u64) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn kib(kib: u64) -> Self {
+        Self(kib << 10)
+    }
+
+    pub const fn mib(mib: u64) -> Self {
+        Self(mib << 20)
+    }
+
+    pub const fn gib(gib: u64) -> Self {
+        Self(gib << 30)
+    }
+
+    pub const fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// bytes as a buffer length, saturating on 32 bit targets
+    pub fn as_usize(self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+/// a `u64` is a number of bytes; other integers need an explicit constructor
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+/// largest unit the size is a whole number of
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (unit, scale) = UNITS
+            .iter()
+            .find(|(_, scale)| self.0 != 0 && self.0.is_multiple_of(*scale))
+            .unwrap_or(&("B", 1));
+        write!(f, "{}{unit}", self.0 / scale)
+    }
+}
+
+/// Error parsing a [`ByteSize`]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid size {input:?}: {reason}")]
+pub struct ParseByteSizeError {
+    input: String,
+    reason: &'static str,
+}
+
+/// a whole number, optionally followed by `B`, `KiB`, `MiB`, `GiB` or `TiB`
+impl FromStr for ByteSize {
+    type Err = ParseByteSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ParseByteSizeError {
+            input: s.to_owned(),
+            reason,
+        };
+
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| error("expected a whole number"))?;
+
+        let scale = match unit.trim() {
+            "" => 1,
+            unit => match UNITS.iter().find(|(u, _)| *u == unit) {
+                Some((_, scale)) => *scale,
+                None => return Err(error("expected B, KiB, MiB, GiB or TiB")),
+            },
+        };
+
+        number
+            .checked_mul(scale)
+            .map(Self)
+            .ok_or_else(|| error("too large"))
+    }
+}
+
+/// serialized as displayed, e.g. `"8MiB"`
+#[cfg(feature = "serde")]
+impl serde::Serialize for ByteSize {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// from a string like `"8MiB"` or a number of bytes
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Bytes(bytes) => Ok(Self(bytes)),
+            Repr::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_size_test() {
+        assert_eq!(ByteSize::kib(64).bytes(), 65_536);
+        assert_eq!(ByteSize::mib(8), ByteSize::kib(8 * 1024));
+        assert_eq!(ByteSize::from(1024), ByteSize::kib(1));
+        assert!(ByteSize::mib(1) > ByteSize::kib(1023));
+
+        for (size, text) in [
+            (ByteSize::mib(8), "8MiB"),
+            (ByteSize::kib(1536), "1536KiB"),
+            (ByteSize::b(1500), "1500B"),
+            (ByteSize::gib(2), "2GiB"),
+            (ByteSize::ZERO, "0B"),
+        ] {
+            assert_eq!(size.to_string(), text);
+            assert_eq!(text.parse::<ByteSize>().unwrap(), size);
+        }
+
+        assert_eq!("64 KiB".parse::<ByteSize>().unwrap(), ByteSize::kib(64));
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize::b(512));
+        for invalid in ["8MB", "8kb", "1.5MiB", "-1", "MiB", "", "99999999999TiB"] {
+            assert!(invalid.parse::<ByteSize>().is_err(), "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn byte_size_serde_test() {
+        let size: ByteSize = serde_json::from_str("\"8MiB\"").unwrap();
+        assert_eq!(size, ByteSize::mib(8));
+        let size: ByteSize = serde_json::from_str("1024").unwrap();
+        assert_eq!(size, ByteSize::kib(1));
+        assert_eq!(serde_json::to_string(&size).unwrap(), "\"1KiB\"");
+        assert!(serde_json::from_str::<ByteSize>("\"8MB\"").is_err());
+    }
+}
@@ -1,52 +1,610 @@
-use crate::NimbusError;
+// without a storage provider only the trait and the in-memory storage are left, the helpers of the providers are unused
+#![cfg_attr(
+    not(any(feature = "gcp-storage", feature = "aws-storage")),
+    allow(dead_code, unreachable_code, unused_imports, unused_variables)
+)]
 
+use crate::error::with_context;
+#[cfg(feature = "aws-storage")]
+use crate::TransportConfig;
+use crate::{
+    redact, BatchOutcome, ByteSize, ErrorCode, ErrorContext, ListLimits, NimbusError, OpContext,
+};
+
+#[cfg(feature = "aws-storage")]
 use aws_sdk_s3::primitives::ByteStream;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 use google_cloud_storage::client::Client;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::buckets::get::GetBucketRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::buckets::get_iam_policy::GetIamPolicyRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::buckets::iam_configuration::PublicAccessPrevention;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::buckets::test_iam_permissions::TestIamPermissionsRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::buckets::Bucket;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::object_access_controls::list::ListObjectAccessControlsRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::object_access_controls::Projection;
+#[cfg(feature = "gcp-storage")]
 use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 use google_cloud_storage::http::objects::download::Range;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 use google_cloud_storage::http::objects::get::GetObjectRequest;
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::objects::list::ListObjectsRequest;
+#[cfg(feature = "gcp-storage")]
 use google_cloud_storage::http::objects::upload::{UploadObjectRequest, UploadType};
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 use google_cloud_storage::http::objects::Object;
 
-#[cfg(feature = "aws")]
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::types::{Tag, Tagging};
+#[cfg(feature = "aws-storage")]
 use aws_sdk_s3::Client;
 
-use std::io::Write;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 use tokio;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+mod append;
+mod archive;
+mod audit;
+mod bucket;
+mod content;
+mod copy;
+mod cors;
+mod diff;
+mod encryption;
+mod ext;
+mod gzip;
+mod json;
+#[cfg(feature = "gcp-storage")]
+mod json_api;
+mod kv;
+pub mod lease;
+mod ledger;
+mod list;
+mod metadata;
+mod migrate;
+mod multipart;
+pub mod partition;
+mod post_policy;
+mod prefetch;
+mod progress;
+mod purge;
+mod replication;
+mod resumable;
+mod scan;
+mod signed_url;
+mod traffic;
+mod watch;
+
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use append::rewrite as rewrite_append;
+pub use append::{to_ndjson, AppendOutcome, AppendPath, NDJSON_CONTENT_TYPE};
+pub use archive::{
+    glob_matches, ArchiveOptions, ArchiveReport, StorageClass, DEFAULT_ARCHIVE_ATTEMPTS,
+    DEFAULT_ARCHIVE_CONCURRENCY,
+};
+pub(crate) use audit::Exposure;
+pub use audit::{AclEntry, Grantee, PublicAccess, AUDIT_CONCURRENCY};
+pub use bucket::{suggest_bucket_name, validate_bucket_name, Provider};
+pub use content::{content_key, sha256_hex};
+pub use copy::{
+    CopyBatchOptions, CopyBatchReport, CopyEntry, CopyProgress, DEFAULT_COPY_ATTEMPTS,
+    DEFAULT_COPY_CONCURRENCY,
+};
+pub use cors::{validate_cors, CorsRule, CORS_METHODS, MAX_CORS_RULES};
+pub use diff::{
+    diff_prefixes, diff_prefixes_stream, DiffEntry, DiffOptions, DiffReason, DiffReport, DiffSide,
+    Differing, CONTENT_CHECK_CHUNK,
+};
+pub use encryption::{EncryptionKey, ENCRYPTION_ALGORITHM};
+pub use ext::ObjectExt;
+pub use json::{
+    to_json_document, JsonUpdate, UpdateOptions, DEFAULT_UPDATE_ATTEMPTS, JSON_CONTENT_TYPE,
+};
+pub use kv::{KvStore, KV_CONTENT_TYPE};
+pub use lease::Lease;
+pub use ledger::{Claim, ProcessingLedger};
+pub use list::{ListPage, ListParams, ListQuery};
+pub use metadata::{
+    ObjectChecksum, ObjectMeta, ObjectMetadata, DEFAULT_METADATA_CONCURRENCY, LISTING_THRESHOLD,
+};
+pub use migrate::{
+    Divergence, DivergenceCounts, DivergenceKind, MigratingStorage, MirrorPolicy,
+    DEFAULT_DIVERGENCE_LOG_CAPACITY,
+};
+pub use multipart::{PartInfo, UploadConstraints, UploadedObject, DEFAULT_BUFFER_SIZE, SNIFF_LEN};
+pub use partition::PartitionScheme;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use post_policy::unsigned_post_policy;
+pub use post_policy::{PolicyCondition, PostPolicy, MAX_POLICY_EXPIRY};
+pub use prefetch::{PrefetchOptions, DEFAULT_PREFETCH, DEFAULT_PREFETCH_BYTE_BUDGET};
+pub use progress::{ProgressEvent, ProgressPhase, DEFAULT_PROGRESS_INTERVAL};
+pub use purge::{
+    DeletePrefixOptions, DeletePrefixReport, DEFAULT_CHECKPOINT_INTERVAL, DEFAULT_DELETE_ATTEMPTS,
+    DEFAULT_DELETE_CONCURRENCY,
+};
+pub use replication::{
+    ReplicationState, ReplicationStatus, REPLICATION_POLL_INITIAL_DELAY, REPLICATION_POLL_MAX_DELAY,
+};
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use resumable::Session;
+pub use resumable::{crc32c_base64, ResumableUpload, CHUNK_ALIGNMENT, MIN_CHUNK_SIZE};
+pub use scan::{
+    ContentScanner, NoopScanner, PolicyScanner, Quarantine, ScanError, ScanInput, ScanStream,
+    ScanVerdict, ScannedStorage, SCAN_BUFFER_CHUNKS,
+};
+pub(crate) use signed_url::validate_expiry;
+pub use signed_url::MAX_SIGNED_URL_EXPIRY;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use traffic::record as record_traffic;
+pub use traffic::{Direction, TrafficMeter, TrafficSnapshot};
+pub use watch::{ObjectEvent, WatchOptions, DEFAULT_MAX_WATCHED_KEYS};
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[cfg(feature = "gcp")]
+    #[cfg(feature = "gcp-storage")]
     #[error("Storage auth error: {0}")]
     StorageAuth(#[from] google_cloud_storage::client::google_cloud_auth::error::Error),
-    #[cfg(feature = "gcp")]
+    #[cfg(feature = "gcp-storage")]
     #[error("Storage error: {0}")]
     Storage(#[from] google_cloud_storage::http::Error),
-    #[cfg(feature = "aws")]
+    #[cfg(feature = "aws-storage")]
     #[error("Storage error: {0}")]
     Storage(String),
     #[error("IO error: {0}")]
     IO(#[from] std::io::Error),
     #[error("File Type Validation Error: {0}")]
     InvalidFileType(String),
+    #[error("Upload exceeds the limit of {limit}")]
+    UploadTooLarge { limit: ByteSize },
+    #[error("Invalid POST policy: {0}")]
+    InvalidPolicy(String),
+    #[error("Invalid expiry {requested:?}: must be more than 0s and at most {}s", .max.as_secs())]
+    InvalidExpiry { requested: Duration, max: Duration },
+    #[error("Invalid CORS rule: {0}")]
+    InvalidCors(String),
+    #[error("Invalid access boundary: {0}")]
+    InvalidAccessBoundary(String),
+    #[error("Invalid key {:?}: {reason}", redact::resource(.key))]
+    InvalidKey { key: String, reason: &'static str },
+    #[error("Content rejected: {reason}")]
+    ContentRejected { reason: String },
+    #[error("Content scan failed: {0}")]
+    ScanFailed(#[from] ScanError),
+    #[error("Invalid buffer size {0}: must be at least {MIN_CHUNK_SIZE} and a multiple of {CHUNK_ALIGNMENT}")]
+    InvalidBufferSize(ByteSize),
+    #[error("Chunk checksum mismatch (chunk: {chunk:?}, expected crc32c: {expected}, actual: {actual:?})")]
+    ChunkChecksumMismatch {
+        /// index of the rejected chunk, `None` when only the assembled object could be verified
+        chunk: Option<u32>,
+        expected: String,
+        actual: Option<String>,
+    },
+    #[error("Content hash mismatch for {key} (actual sha256: {actual})")]
+    ContentHashMismatch { key: String, actual: String },
+    #[error("Lease {key} is held by {owner} until {expires_at}")]
+    LeaseHeld {
+        key: String,
+        owner: String,
+        expires_at: DateTime<Utc>,
+    },
+    #[error("Lease {0} was lost to another owner")]
+    LeaseLost(String),
+    #[error(
+        "Generation {generation} of {} is claimed by {owner} until {expires_at}",
+        redact::resource(.key)
+    )]
+    ClaimHeld {
+        key: String,
+        generation: i64,
+        owner: String,
+        expires_at: DateTime<Utc>,
+    },
+    #[error("Generation {generation} of {} was processed already", redact::resource(.key))]
+    AlreadyProcessed { key: String, generation: i64 },
+    #[error(
+        "Claim on generation {generation} of {} was taken over by another owner",
+        redact::resource(.key)
+    )]
+    ClaimLost { key: String, generation: i64 },
+    #[error("More than {limit} objects under watched prefix {prefix:?}")]
+    WatchLimitExceeded { prefix: String, limit: usize },
+    #[error("Checkpoint {} is of the deletion of prefix {prefix:?}", redact::resource(.key))]
+    CheckpointMismatch { key: String, prefix: String },
+    #[error("Object {0} is encrypted with a customer-supplied key, which is missing or wrong")]
+    EncryptionKeyRequired(String),
+    #[error("Object {0} is a folder placeholder, it can't be written to a file")]
+    IsDirectoryPlaceholder(String),
+    #[error("Object {key} is larger than the in-memory limit of {limit}")]
+    ObjectTooLarge { key: String, limit: ByteSize },
+    #[error("Invalid JSON document: {0}")]
+    InvalidJson(String),
+    #[error(
+        "Update of {} kept conflicting with other writers, gave up after {attempts} attempts",
+        redact::resource(.key)
+    )]
+    UpdateConflict { key: String, attempts: u32 },
+    #[error("Invalid bucket name {:?}: {reason}", redact::resource(.name))]
+    InvalidBucketName { name: String, reason: String },
+    #[error(
+        "Copied {} to {}, the source is left too as deleting it failed: {source}",
+        redact::resource(.from),
+        redact::resource(.to)
+    )]
+    DeleteAfterCopy {
+        from: String,
+        to: String,
+        source: Box<NimbusError>,
+    },
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    #[error("Not found: {}", redact::resource(.0))]
+    NotFound(String),
+    #[error("Permission denied: {}", redact::resource(.0))]
+    PermissionDenied(String),
+    #[error("Precondition failed: {}", redact::resource(.0))]
+    PreconditionFailed(String),
+    #[error("Rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
     #[error("Error: {0}")]
     Other(String),
 }
 
+impl Error {
+    /// provider independent classification of the error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "gcp-storage")]
+            Error::StorageAuth(_) => ErrorCode::Unauthenticated,
+            #[cfg(feature = "gcp-storage")]
+            Error::Storage(e) => {
+                use google_cloud_storage::http::Error as HttpError;
+
+                match e {
+                    HttpError::Response(r) => ErrorCode::from_status(r.code),
+                    HttpError::HttpClient(e) if e.is_timeout() => ErrorCode::Timeout,
+                    HttpError::HttpClient(e) if e.is_connect() => ErrorCode::Unavailable,
+                    HttpError::HttpClient(e) => e
+                        .status()
+                        .map_or(ErrorCode::Internal, |s| ErrorCode::from_status(s.as_u16())),
+                    HttpError::TokenSource(_) => ErrorCode::Unauthenticated,
+                    HttpError::HttpMiddleware(_) => ErrorCode::Internal,
+                }
+            }
+            Error::InvalidFileType(_)
+            | Error::InvalidBucketName { .. }
+            | Error::InvalidJson(_)
+            | Error::UploadTooLarge { .. }
+            | Error::ObjectTooLarge { .. }
+            | Error::IsDirectoryPlaceholder(_)
+            | Error::InvalidBufferSize(_)
+            | Error::InvalidPolicy(_)
+            | Error::InvalidExpiry { .. }
+            | Error::InvalidCors(_)
+            | Error::InvalidAccessBoundary(_)
+            | Error::InvalidKey { .. }
+            | Error::ContentRejected { .. }
+            | Error::EncryptionKeyRequired(_)
+            | Error::WatchLimitExceeded { .. }
+            | Error::CheckpointMismatch { .. } => ErrorCode::InvalidInput,
+            Error::ScanFailed(e) => e.code(),
+            // the copy is there, retrying the delete may do
+            Error::DeleteAfterCopy { source, .. } => source.code(),
+            Error::Unsupported(_) => ErrorCode::Unsupported,
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            Error::PreconditionFailed(_) => ErrorCode::PreconditionFailed,
+            Error::LeaseHeld { .. }
+            | Error::LeaseLost(_)
+            | Error::ClaimHeld { .. }
+            | Error::ClaimLost { .. }
+            | Error::UpdateConflict { .. } => ErrorCode::PreconditionFailed,
+            Error::AlreadyProcessed { .. } => ErrorCode::AlreadyExists,
+            Error::RateLimited { .. } => ErrorCode::RateLimited,
+            Error::Timeout(_) => ErrorCode::Timeout,
+            Error::Unavailable(_) => ErrorCode::Unavailable,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// how long the provider asked to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after, .. } => *retry_after,
+            Error::DeleteAfterCopy { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// convert an S3 error, keeping its classification
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn from_sdk<E: ProvideErrorMetadata>(e: SdkError<E>) -> Self {
+        let (code, retry_after) = crate::error::classify_sdk_error(&e);
+        let message = e.to_string();
+
+        if encryption::s3_key_required(e.code(), e.message()) {
+            return Error::EncryptionKeyRequired(message);
+        }
+
+        match code {
+            ErrorCode::NotFound => Error::NotFound(message),
+            ErrorCode::PermissionDenied => Error::PermissionDenied(message),
+            ErrorCode::PreconditionFailed => Error::PreconditionFailed(message),
+            ErrorCode::RateLimited => Error::RateLimited {
+                message,
+                retry_after,
+            },
+            ErrorCode::Timeout => Error::Timeout(message),
+            ErrorCode::Unavailable => Error::Unavailable(message),
+            _ => Error::Storage(message),
+        }
+    }
+}
+
+/// convert a GCS error of a call on `object`, telling apart objects that need a customer-supplied key
+#[cfg(feature = "gcp-storage")]
+fn gcs_object_error(e: google_cloud_storage::http::Error, bucket: &str, object: &str) -> Error {
+    match &e {
+        google_cloud_storage::http::Error::Response(r) if encryption::gcs_key_required(r) => {
+            Error::EncryptionKeyRequired(format!("{bucket}/{object}"))
+        }
+        _ => Error::Storage(e),
+    }
+}
+
+/// convert an error signing a GCS URL, the client holding no service account credentials being unsupported
+#[cfg(feature = "gcp-storage")]
+fn gcs_sign_error(e: google_cloud_storage::sign::SignedURLError) -> Error {
+    use google_cloud_storage::sign::SignedURLError;
+
+    match e {
+        SignedURLError::SignBlob(e) => Error::Storage(e),
+        // the client was built from credentials without a service account email or key to sign with
+        SignedURLError::InvalidOption(reason) if reason.starts_with("No ") => Error::Unsupported(
+            format!("signing URLs without service account credentials ({reason})"),
+        ),
+        e => Error::Other(format!("Failed to sign URL: {e}")),
+    }
+}
+
+/// Chunks of an object, see [`StorageHelper::download_stream`]
+pub type ObjectStream = BoxStream<'static, Result<Bytes, NimbusError>>;
+
+/// Condition checked by the provider as part of a write, see [`StorageHelper::upload_conditional`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// no object exists under the key
+    DoesNotExist,
+    /// the current version of the object (see [`ObjectMeta::version`]) is the given one
+    VersionMatches(String),
+}
+
+/// `Range` header value of bytes `start..=end`, or `start..` without `end`
+/// fails with [`Error::Other`] if `start` is after `end`
+#[cfg(any(
+    test,
+    feature = "testing",
+    feature = "gcp-storage",
+    feature = "aws-storage"
+))]
+pub(crate) fn range_header(start: u64, end: Option<u64>) -> Result<String, Error> {
+    match end {
+        Some(end) if start > end => Err(Error::Other(format!(
+            "Invalid range: start {start} is after end {end}"
+        ))),
+        Some(end) => Ok(format!("bytes={start}-{end}")),
+        None => Ok(format!("bytes={start}-")),
+    }
+}
+
+/// key of the folder placeholder of `prefix`
+fn placeholder_key(prefix: &str) -> String {
+    if prefix.ends_with('/') {
+        prefix.to_owned()
+    } else {
+        format!("{prefix}/")
+    }
+}
+
+/// public access block of an S3 bucket, empty when none is set
+#[cfg(feature = "aws-storage")]
+async fn s3_public_access_block(
+    client: &Client,
+    bucket: &str,
+) -> Result<aws_sdk_s3::types::PublicAccessBlockConfiguration, Error> {
+    use aws_sdk_s3::types::PublicAccessBlockConfiguration;
+
+    let none = || PublicAccessBlockConfiguration::builder().build();
+    match client.get_public_access_block().bucket(bucket).send().await {
+        Ok(res) => Ok(res.public_access_block_configuration.unwrap_or_else(none)),
+        Err(e) if e.code() == Some("NoSuchPublicAccessBlockConfiguration") => Ok(none()),
+        Err(e) => Err(Error::from_sdk(e)),
+    }
+}
+
+/// `bucket/key` of the source of an S3 copy, with the key url-encoded as S3 expects
+#[cfg(feature = "aws-storage")]
+fn s3_copy_source(bucket: &str, key: &str) -> String {
+    let mut source = format!("{bucket}/");
+    for b in key.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                source.push(b as char)
+            }
+            b => source.push_str(&format!("%{b:02X}")),
+        }
+    }
+    source
+}
+
+/// generation of an S3 delete marker, the time it was written in milliseconds since the epoch
+#[cfg(feature = "aws-storage")]
+fn marker_generation(marker: &aws_sdk_s3::types::DeleteMarkerEntry) -> Option<i64> {
+    marker.last_modified()?.to_millis().ok()
+}
+
+/// page of an S3 listing within the bounds of `params`, S3 only knows an exclusive start
+/// the listing ends with the first page reaching past the end bound, the keys come in order
+#[cfg(feature = "aws-storage")]
+fn s3_bounded_page(
+    params: &ListParams,
+    mut objects: Vec<ObjectMeta>,
+    mut prefixes: Vec<String>,
+    next_page_token: Option<String>,
+) -> ListPage {
+    let ended = objects.iter().any(|o| params.past_end(&o.key))
+        || prefixes.iter().any(|p| params.past_end(p));
+    objects.retain(|o| params.in_bounds(&o.key));
+    prefixes.retain(|p| params.prefix_in_bounds(p));
+
+    ListPage {
+        objects,
+        prefixes,
+        next_page_token: next_page_token.filter(|_| !ended),
+    }
+}
+
+/// whether ACLs of an S3 bucket are disabled by `BucketOwnerEnforced` object ownership
+#[cfg(feature = "aws-storage")]
+async fn s3_owner_enforced(client: &Client, bucket: &str) -> Result<bool, Error> {
+    use aws_sdk_s3::types::ObjectOwnership;
+
+    match client
+        .get_bucket_ownership_controls()
+        .bucket(bucket)
+        .send()
+        .await
+    {
+        Ok(res) => Ok(res.ownership_controls().is_some_and(|c| {
+            c.rules()
+                .iter()
+                .any(|r| r.object_ownership() == &ObjectOwnership::BucketOwnerEnforced)
+        })),
+        // buckets created before ownership controls keep their ACLs
+        Err(e) if e.code() == Some("OwnershipControlsNotFoundError") => Ok(false),
+        Err(e) => Err(Error::from_sdk(e)),
+    }
+}
+
+/// whether the IAM configuration of a GCS bucket enforces public access prevention and uniform access
+#[cfg(feature = "gcp-storage")]
+fn gcs_access_config(bucket: &Bucket) -> (bool, bool) {
+    let iam = bucket.iam_configuration.as_ref();
+    let prevented = iam.and_then(|c| c.public_access_prevention.as_ref())
+        == Some(&PublicAccessPrevention::Enforced);
+    let uniform = iam
+        .and_then(|c| c.uniform_bucket_level_access.as_ref())
+        .is_some_and(|u| u.enabled);
+    (prevented, uniform)
+}
+
+/// body of an S3 response as a stream, metered as for [`read_body`]
+#[cfg(feature = "aws-storage")]
+fn body_stream(bucket: &str, body: ByteStream) -> ObjectStream {
+    let bucket = bucket.to_owned();
+    futures::stream::try_unfold(body, move |mut body| {
+        let bucket = bucket.clone();
+        async move {
+            let chunk = body
+                .try_next()
+                .await
+                .map_err(|e| Error::Storage(e.to_string()))?;
+            Ok(chunk.map(|chunk| {
+                traffic::record(&bucket, Direction::Egress, chunk.len() as u64);
+                (chunk, body)
+            }))
+        }
+    })
+    .boxed()
+}
+
+/// body of an S3 response, metered as it arrives: a body failing midway still counts what was received
+#[cfg(feature = "aws-storage")]
+async fn read_body(bucket: &str, mut body: ByteStream) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    while let Some(bytes) = body
+        .try_next()
+        .await
+        .map_err(|e| Error::Storage(e.to_string()))?
+    {
+        traffic::record(bucket, Direction::Egress, bytes.len() as u64);
+        data.extend_from_slice(&bytes);
+    }
+
+    Ok(data)
+}
+
+#[cfg(feature = "gcp-storage")]
+const GCS_SCHEME: &str = "gs://";
+
+#[cfg(feature = "aws-storage")]
+const S3_SCHEME: &str = "s3://";
+
+/// context of the errors of a call on `key` in `bucket`, or on the bucket if `key` is empty
+#[cfg(any(feature = "gcp-storage", feature = "aws-storage"))]
+fn object_context(
+    operation: &'static str,
+    scheme: &'static str,
+    bucket: &str,
+    key: &str,
+) -> ErrorContext {
+    let resource = if key.is_empty() {
+        bucket.to_owned()
+    } else {
+        format!("{bucket}/{key}")
+    };
+    ErrorContext::new(operation, resource).scheme(scheme)
+}
+
+/// GCS generation from a version token
+#[cfg(feature = "gcp-storage")]
+fn generation(version: &str) -> Result<i64, Error> {
+    version
+        .parse()
+        .map_err(|_| Error::Other(format!("Invalid generation: {version}")))
+}
+
 #[async_trait::async_trait]
 pub trait StorageHelper {
-    #[cfg(feature = "aws")]
+    #[cfg(feature = "aws-storage")]
     /// returns a new client for simplicity
     async fn new_with_authenticator() -> Self;
 
+    /// new client whose connections are pooled and kept alive as set in `transport`
+    /// clients without an HTTP transport, like the in-memory one, ignore it
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self
+    where
+        Self: Sized,
+    {
+        let _ = transport;
+        Self::new_with_authenticator().await
+    }
+
     /// upload from bytes to a bucket
     async fn upload_from_bytes(
         &self,
@@ -57,192 +615,3371 @@ pub trait StorageHelper {
     ) -> Result<(), NimbusError>;
 
     /// download to bytes from a bucket
-    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError>;
+    /// the whole object is held in memory, see [`StorageHelper::download_to_writer`] to stream it
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        let mut data = vec![];
+        self.download_to_writer(bucket, key, &mut data).await?;
+        Ok(data)
+    }
+
+    /// stream an object into `writer`, returning the number of bytes written
+    ///
+    /// The body is copied a chunk at a time and the next chunk is only read once the previous one is
+    /// written, so a slow writer slows the download down instead of piling chunks up in memory.
+    /// `writer` is flushed once the object is written. A download failing midway, e.g. on a dropped
+    /// connection, returns its error once the chunks written so far are flushed to `writer`.
+    async fn download_to_writer<W>(
+        &self,
+        bucket: &str,
+        key: &str,
+        writer: &mut W,
+    ) -> Result<u64, NimbusError>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let mut stream = self.download_stream(bucket, key).await?;
+        let mut written = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // the download error is the one worth reporting
+                    let _ = writer.flush().await;
+                    return Err(e);
+                }
+            };
+            writer.write_all(&chunk).await.map_err(Error::IO)?;
+            written += chunk.len() as u64;
+        }
+        writer.flush().await.map_err(Error::IO)?;
+
+        Ok(written)
+    }
+
+    /// the body of an object, chunk by chunk as the provider sends it
+    ///
+    /// A missing object fails here, with [`ErrorCode::NotFound`]; a body failing midway ends the stream
+    /// with its error. Chunks are only read from the network as the stream is polled.
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError>;
+
+    /// download to bytes with explicit handling of objects stored with `Content-Encoding: gzip`
+    /// `decompress: true` returns the decompressed bytes, `false` the stored compressed bytes
+    /// objects with any other encoding are returned as stored
+    ///
+    /// [`StorageHelper::download_to_bytes`] keeps the provider default instead:
+    /// GCS decompresses gzip objects server side, S3 returns them as stored.
+    ///
+    /// GCS decompresses gzip objects for clients that don't accept gzip, as the storage client doesn't,
+    /// so `decompress: false` downloads them through the JSON API with the application default credentials.
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError>;
+
+    /// bytes `start..=end` of an object, fewer past its end, or `start..` to its end without `end`
+    /// `start` must be within the object, and fails with [`Error::Other`] if it is after `end`
+    ///
+    /// GCS returns gzip objects it decompresses whole, whatever the range, unless they are stored
+    /// with `Cache-Control: no-transform`.
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError>;
+
+    /// upload from bytes, encrypted with a customer-supplied key
+    /// reading the content back needs the same key, see [`EncryptionKey`]
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError>;
+
+    /// download an object encrypted with a customer-supplied key
+    /// fails with [`Error::EncryptionKeyRequired`] if `encryption` isn't its key, as does
+    /// [`StorageHelper::download_to_bytes`] on such an object
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError>;
+
+    /// metadata of an object encrypted with a customer-supplied key, including its checksums
+    /// GCS leaves the checksums out without the key, S3 fails without it
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError>;
+
+    /// server side copy of an object, each side encrypted with its customer-supplied key if any
+    /// a `None` destination key stores the copy with the default encryption of the bucket
+    async fn copy_encrypted(
+        &self,
+        source: (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        destination: (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError>;
+
+    /// server side copy of an object, within a bucket or across buckets, without downloading it
+    ///
+    /// A rewrite on GCS, a `CopyObject` on S3 with the source key url-encoded, so keys holding spaces,
+    /// slashes or any other character are copied as is. Copying an object onto itself only checks
+    /// that it exists, S3 would reject it; a missing source fails with [`ErrorCode::NotFound`].
+    async fn copy_object(
+        &self,
+        src_bucket: &str,
+        src_key: &str,
+        dst_bucket: &str,
+        dst_key: &str,
+    ) -> Result<(), NimbusError> {
+        if (src_bucket, src_key) == (dst_bucket, dst_key) {
+            if !self.object_exists(src_bucket, src_key).await? {
+                return Err(Error::NotFound(format!("{src_bucket}/{src_key}")).into());
+            }
+            return Ok(());
+        }
+        self.copy_encrypted((src_bucket, src_key), None, (dst_bucket, dst_key), None)
+            .await
+    }
+
+    /// [copy](StorageHelper::copy_object) an object then delete the source, only once copied
+    ///
+    /// Not atomic: a failing delete leaves the object at both places, and fails with
+    /// [`Error::DeleteAfterCopy`] wrapping its error. Moving an object onto itself only checks that it
+    /// exists, as the copy does.
+    async fn move_object(
+        &self,
+        src_bucket: &str,
+        src_key: &str,
+        dst_bucket: &str,
+        dst_key: &str,
+    ) -> Result<(), NimbusError> {
+        if (src_bucket, src_key) == (dst_bucket, dst_key) {
+            return self
+                .copy_object(src_bucket, src_key, dst_bucket, dst_key)
+                .await;
+        }
+        self.copy_object(src_bucket, src_key, dst_bucket, dst_key)
+            .await?;
+        self.delete_file(src_bucket, src_key).await.map_err(|e| {
+            Error::DeleteAfterCopy {
+                from: format!("{src_bucket}/{src_key}"),
+                to: format!("{dst_bucket}/{dst_key}"),
+                source: Box::new(e),
+            }
+            .into()
+        })
+    }
+
+    /// [move](StorageHelper::move_object) an object to another key of its bucket, replacing
+    /// the object at `to_key` if any
+    async fn rename_object(
+        &self,
+        bucket: &str,
+        from_key: &str,
+        to_key: &str,
+    ) -> Result<(), NimbusError> {
+        self.move_object(bucket, from_key, bucket, to_key).await
+    }
+
+    /// server side copy of every entry of `entries` from `src_bucket` to `dst_bucket`
+    ///
+    /// Entries are pulled from the stream as copies complete, at most `options.concurrency` in flight,
+    /// so a manifest of any size can be streamed in. Each copy is retried on retryable errors;
+    /// a copy that still fails is reported in [`CopyBatchReport::failed`] without stopping the others,
+    /// and [`CopyBatchReport::retry_entries`] resumes the batch.
+    async fn copy_batch<E>(
+        &self,
+        src_bucket: &str,
+        dst_bucket: &str,
+        entries: E,
+        options: CopyBatchOptions,
+    ) -> Result<CopyBatchReport, NimbusError>
+    where
+        E: futures::Stream<Item = CopyEntry> + Send,
+    {
+        copy::copy_batch(self, src_bucket, dst_bucket, entries, options).await
+    }
+
+    /// delete every object under `prefix`, resuming from the checkpoint object `checkpoint_key`
+    /// of a previous run that was killed or failed, see the [module documentation](purge)
+    ///
+    /// Pages of keys are listed in lexicographic order and deleted concurrently, calls are retried
+    /// on retryable errors and the concurrency backs off when rate limited. A delete that still fails
+    /// stops the run, its progress is kept in the checkpoint. The checkpoint is deleted once done.
+    async fn delete_prefix_resumable(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        checkpoint_key: &str,
+        options: DeletePrefixOptions,
+    ) -> Result<DeletePrefixReport, NimbusError> {
+        purge::delete_prefix_resumable(self, bucket, prefix, checkpoint_key, options).await
+    }
+
+    /// change the storage class of an object in place, keeping its data and metadata
+    ///
+    /// GCS rewrites the object, S3 copies it onto itself, which is limited to objects of 5 GiB.
+    /// Both give the object a new version and update time.
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError>;
+
+    /// move every object under `prefix` last updated at least `age` ago to the class `target`,
+    /// see the [module documentation](archive)
+    ///
+    /// The listing is streamed and classes are changed concurrently, each change retried on retryable
+    /// errors with the concurrency backing off when rate limited. A change that still fails is reported
+    /// in [`ArchiveReport::failed`] and doesn't stop the run, a failing listing does.
+    async fn archive_older_than(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        age: Duration,
+        target: StorageClass,
+        options: ArchiveOptions,
+    ) -> Result<ArchiveReport, NimbusError>
+    where
+        Self: Sized + Sync,
+    {
+        archive::archive_older_than(self, bucket, prefix, age, target, options).await
+    }
 
     /// delete a file from a bucket
     async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError>;
 
-    /// upload a file from a path to a bucket
-    /// takes a PathBuf to file and key
-    /// file name does not matter as key will be used to create the file in the bucket
-    async fn upload_file(&self, bucket: &str, key: &str, path: PathBuf) -> Result<(), NimbusError> {
-        let data = tokio::fs::read(path).await.map_err(Error::IO)?;
-        self.upload_from_bytes(bucket, key, None, data).await?;
-        Ok(())
-    }
+    /// check if an object exists, reading its metadata only
+    /// only a not found answer is `false`: any other error, e.g. permission denied, is returned
+    ///
+    /// S3 answers permission denied rather than not found for a missing object when the caller
+    /// can't list the bucket.
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError>;
 
-    /// download a file from a bucket to a path to given destination directory
-    async fn download_file(
+    /// upload from bytes only if `precondition` holds
+    /// the check is done by the provider as part of the upload, so concurrent writers can't overwrite each other
+    /// returns the version of the new object, fails with [`ErrorCode::PreconditionFailed`] if the precondition doesn't hold
+    async fn upload_conditional(
         &self,
         bucket: &str,
         key: &str,
-        path_dir: PathBuf,
-    ) -> Result<PathBuf, NimbusError> {
-        if !path_dir.exists() {
-            tokio::fs::create_dir_all(path_dir.clone())
-                .await
-                .map_err(Error::IO)?;
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError>;
+
+    /// upload from bytes with custom metadata, returned in [`ObjectMeta::metadata`]
+    /// only if `precondition` holds when there is one, as [`StorageHelper::upload_conditional`] checks it
+    /// returns the version of the new object
+    ///
+    /// Custom metadata is the `metadata` of the object on GCS, its user metadata on S3, sent as
+    /// `x-amz-meta-*` headers: S3 takes ASCII keys only, and returns them in lowercase.
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError>;
+
+    /// upload from bytes only if no object exists under `key`
+    /// returns `false` if the object already existed
+    async fn upload_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<bool, NimbusError> {
+        let res = self
+            .upload_conditional(bucket, key, mime, data, &Precondition::DoesNotExist)
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == ErrorCode::PreconditionFailed => Ok(false),
+            Err(e) => Err(e),
         }
+    }
 
-        if !path_dir.is_dir() {
-            return Err(
-                Error::Other(format!("Path {} is not a directory", path_dir.display())).into(),
-            );
+    /// delete an object only if its current version is `version`
+    /// fails with [`ErrorCode::PreconditionFailed`] if the object was replaced in between
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError>;
+
+    /// download to bytes along with the version of the downloaded data
+    /// the version can be passed to [`Precondition::VersionMatches`] for a read-modify-write
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError>;
+
+    /// atomically update the JSON document under `key` with `f`
+    ///
+    /// `f` gets the current document, `None` if there is none yet, and returns the new one, which is written
+    /// only if the object wasn't written since it was downloaded. When it was, the whole cycle runs again on the
+    /// newer document after a backoff, up to `options.max_attempts` times, then fails with [`Error::UpdateConflict`].
+    /// `f` may thus be called several times and must only compute the new document: side effects would be repeated.
+    /// An error returned by `f` aborts the update.
+    ///
+    /// Documents are written with [`to_json_document`], so their diffs stay readable;
+    /// a document `f` leaves unchanged is not written again.
+    async fn update_json<T, E, F>(
+        &self,
+        bucket: &str,
+        key: &str,
+        f: F,
+        options: UpdateOptions,
+    ) -> Result<JsonUpdate<T>, NimbusError>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + Send,
+        E: Into<NimbusError>,
+        F: Fn(Option<T>) -> Result<T, E> + Send + Sync,
+    {
+        json::update(self, bucket, key, f, options).await
+    }
+
+    /// append `records` to the NDJSON object under `key`, one line each, creating the object if missing
+    ///
+    /// The append is atomic: it is written only if no other writer appended in between, otherwise it is
+    /// attempted again on the newer object after a backoff, up to `options.max_attempts` times, then fails
+    /// with [`Error::UpdateConflict`]. The cost of an append doesn't grow with the object on GCS, nor on S3
+    /// once the object is [`MIN_CHUNK_SIZE`] large, see [`StorageHelper::append_bytes`].
+    async fn append_ndjson<T>(
+        &self,
+        bucket: &str,
+        key: &str,
+        records: &[T],
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError>
+    where
+        T: serde::Serialize + Sync,
+    {
+        let data = append::to_ndjson(records)?;
+        self.append_bytes(
+            bucket,
+            key,
+            Some(NDJSON_CONTENT_TYPE.to_owned()),
+            data,
+            options,
+        )
+        .await
+    }
+
+    /// append `data` to the object under `key` atomically, creating it with `mime` if missing,
+    /// see [`StorageHelper::append_ndjson`]
+    ///
+    /// GCS composes the object with a temporary object holding `data`. S3 can't compose: objects of at
+    /// least [`MIN_CHUNK_SIZE`] are copied into a multipart upload with `data` as the last part, smaller
+    /// ones are downloaded and uploaded again with `data`, conditionally on their ETag.
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError>;
+
+    /// store `data` under its SHA-256 (see [`content_key`]) and return the key
+    /// identical data is only uploaded once
+    async fn put_content_addressed(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        data: Vec<u8>,
+    ) -> Result<String, NimbusError> {
+        let key = content_key(prefix, &data);
+
+        if !self.object_exists(bucket, &key).await? {
+            // losing a race against another writer is fine, it stored the same bytes
+            self.upload_if_absent(bucket, &key, None, data).await?;
         }
 
+        Ok(key)
+    }
+
+    /// download an object stored with [`StorageHelper::put_content_addressed`]
+    /// with `verify` the data is checked against the hash in the key
+    async fn get_content_addressed(
+        &self,
+        bucket: &str,
+        key: &str,
+        verify: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
         let data = self.download_to_bytes(bucket, key).await?;
-        let path = path_dir.join(key);
 
-        if let Some(parent) = path.parent() {
-            tokio::fs::create_dir_all(parent).await.map_err(Error::IO)?;
-        }
+        if verify {
+            let actual = sha256_hex(&data);
+            if !actual.eq_ignore_ascii_case(content::key_hash(key)) {
+                return Err(Error::ContentHashMismatch {
+                    key: key.to_owned(),
+                    actual,
+                }
+                .into());
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// list one page of a bucket, see [`StorageHelper::list`] for a friendlier interface
+    /// listings return less than [`StorageHelper::object_metadata`] on S3 (no content type nor checksum)
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError>;
+
+    /// build a listing of a bucket, see [`ListQuery`]
+    fn list(&self, bucket: &str) -> ListQuery<'_, Self>
+    where
+        Self: Sized + Sync,
+    {
+        ListQuery::new(self, bucket)
+    }
+
+    /// poll a prefix for changes, for when bucket notifications aren't available
+    ///
+    /// Each poll lists the prefix and diffs it against the previous listing by object version,
+    /// the first listing only sets the baseline. A failed poll yields an `Err` item and the stream goes on.
+    /// Panics if `interval` is zero. See [`StorageHelper::watch_prefix_with`] for the options.
+    fn watch_prefix(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        interval: Duration,
+    ) -> impl futures::Stream<Item = Result<ObjectEvent, NimbusError>> + Send + '_
+    where
+        Self: Sized + Sync,
+    {
+        self.watch_prefix_with(bucket, prefix, interval, WatchOptions::default())
+    }
+
+    /// [`StorageHelper::watch_prefix`] tracking at most `options.max_keys` keys,
+    /// polls listing more yield [`Error::WatchLimitExceeded`] and are otherwise ignored
+    fn watch_prefix_with(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        interval: Duration,
+        options: WatchOptions,
+    ) -> impl futures::Stream<Item = Result<ObjectEvent, NimbusError>> + Send + '_
+    where
+        Self: Sized + Sync,
+    {
+        watch::watch(self, bucket, prefix, interval, options)
+    }
+
+    /// download `keys` in order, up to `prefetch` objects ahead of the consumer
+    /// see [`StorageHelper::prefetching_downloader_with`], with a byte budget of [`DEFAULT_PREFETCH_BYTE_BUDGET`]
+    fn prefetching_downloader<I>(
+        &self,
+        bucket: &str,
+        keys: I,
+        prefetch: usize,
+    ) -> impl futures::Stream<Item = Result<(String, Vec<u8>), NimbusError>> + Send + Unpin + '_
+    where
+        Self: Sized + Sync,
+        I: IntoIterator<Item = String>,
+        I::IntoIter: Send + 'static,
+    {
+        let options = PrefetchOptions::new().prefetch(prefetch);
+        self.prefetching_downloader_with(bucket, keys, options)
+    }
+
+    /// download `keys` with [`StorageHelper::download_to_bytes`], yielding each object with its key in the
+    /// order of `keys` while the next ones download
+    ///
+    /// At most `options.prefetch` objects are downloading or waiting for the consumer, and no download
+    /// starts past `options.byte_budget`, see [`PrefetchOptions::byte_budget`]. Downloads only progress
+    /// while the stream is polled, dropping it cancels those in flight.
+    /// A failed download is an `Err` item and the stream goes on, unless it failed as unauthenticated or
+    /// permission denied: the stream then ends after it.
+    fn prefetching_downloader_with<I>(
+        &self,
+        bucket: &str,
+        keys: I,
+        options: PrefetchOptions,
+    ) -> impl futures::Stream<Item = Result<(String, Vec<u8>), NimbusError>> + Send + Unpin + '_
+    where
+        Self: Sized + Sync,
+        I: IntoIterator<Item = String>,
+        I::IntoIter: Send + 'static,
+    {
+        prefetch::prefetch(self, bucket, keys.into_iter(), options)
+    }
+
+    /// list one page of object keys in a bucket, optionally under a prefix
+    /// returns the keys and the token for the next page, `None` on the last page
+    async fn list_keys(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        page_token: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>), NimbusError> {
+        let (metas, next) = self.list_metadata(bucket, prefix, page_token).await?;
+        Ok((metas.into_iter().map(|m| m.key).collect(), next))
+    }
+
+    /// [`StorageHelper::list_keys`], named after the calls it wraps: `objects.list` on GCS, `ListObjectsV2` on S3
+    ///
+    /// An empty bucket or prefix is an empty page, not an error. Pass the returned token back until it is `None`
+    /// to list every key; the token is opaque and only valid for the same bucket and prefix.
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        page_token: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>), NimbusError> {
+        self.list_keys(bucket, prefix, page_token).await
+    }
+
+    /// every key under `prefix` in lexicographic order, of the whole bucket for `None` or `""`
+    /// pages are followed to the last one within `limits`, a prefix matching nothing is an empty `Vec`
+    /// see [`StorageHelper::list_all_metadata`] for the size and update time of each object
+    async fn list_all_objects(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        let objects = self.list_all_metadata(bucket, prefix, limits).await?;
+        Ok(objects.into_iter().map(|o| o.key).collect())
+    }
+
+    /// metadata of every object under `prefix`, see [`StorageHelper::list_all_objects`]
+    /// listings return less than [`StorageHelper::object_metadata`] on S3 (no content type nor checksum)
+    async fn list_all_metadata(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        limits: ListLimits,
+    ) -> Result<Vec<ObjectMeta>, NimbusError> {
+        let prefix = prefix.filter(|p| !p.is_empty());
+        let mut drain = limits.drain();
+        let mut objects = vec![];
+        let mut token = None;
+        loop {
+            let (page, next) = self.list_metadata(bucket, prefix, token).await?;
+            drain.page(page.len(), next.is_some())?;
+            objects.extend(page);
+            match next {
+                Some(t) => token = Some(t),
+                None => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// metadata of an object
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError>;
+
+    /// [`StorageHelper::object_metadata`], read without the content: `objects.get` on GCS, `HeadObject` on S3
+    /// the fields a provider doesn't return are `None`, so the shape is the same for both
+    async fn get_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMetadata, NimbusError> {
+        self.object_metadata(bucket, key).await
+    }
+
+    /// server side checksums of an object, from a metadata call: compare a local file to the stored
+    /// object without downloading it, see [`ObjectChecksum`] for what each provider returns
+    /// a missing object fails with [`ErrorCode::NotFound`]
+    async fn object_checksum(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ObjectChecksum, NimbusError> {
+        Ok(self.object_metadata(bucket, key).await?.into())
+    }
+
+    /// replication status of an object as reported by the provider, see [`ReplicationStatus`]
+    /// S3 reports it per object, GCS only tells whether the bucket replicates at all
+    /// a missing object fails with [`ErrorCode::NotFound`]
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError>;
+
+    /// poll [`StorageHelper::replication_status`] until it is no longer [`ReplicationState::Pending`]
+    /// or `timeout` elapsed, returning the last status: still pending after the timeout
+    ///
+    /// Polls are [`REPLICATION_POLL_INITIAL_DELAY`] apart, doubling up to [`REPLICATION_POLL_MAX_DELAY`].
+    async fn wait_for_replication(
+        &self,
+        bucket: &str,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<ReplicationStatus, NimbusError>
+    where
+        Self: Sync,
+    {
+        replication::wait(self, bucket, key, timeout).await
+    }
+
+    /// list one page of object metadata in a bucket, optionally under a prefix
+    /// listings return less than [`StorageHelper::object_metadata`] on S3 (no content type nor checksum)
+    async fn list_metadata(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        page_token: Option<String>,
+    ) -> Result<(Vec<ObjectMeta>, Option<String>), NimbusError> {
+        let params = ListParams {
+            prefix: prefix.map(str::to_owned),
+            ..Default::default()
+        };
+        let page = self.list_page(bucket, &params, page_token).await?;
+        Ok((page.objects, page.next_page_token))
+    }
+
+    /// metadata of many objects, with at most `concurrency` requests in flight
+    /// results are keyed by the requested key, missing objects are reported as failed with [`ErrorCode::NotFound`]
+    /// the whole batch runs within `ctx`, requests still in flight when it runs out are dropped
+    async fn objects_metadata(
+        &self,
+        bucket: &str,
+        keys: &[String],
+        concurrency: usize,
+        ctx: &OpContext,
+    ) -> Result<BatchOutcome<String, ObjectMeta>, NimbusError> {
+        let mut results = futures::stream::iter(keys.iter().cloned())
+            .map(|key| async move {
+                let meta = ctx.run(self.object_metadata(bucket, &key)).await;
+                (key, meta)
+            })
+            .buffer_unordered(concurrency.max(1));
+
+        let mut outcome = BatchOutcome::new();
+        while let Some((key, meta)) = results.next().await {
+            let Some(meta) = meta else {
+                return Err(ctx.stopped(outcome.len(), keys.len() - outcome.len()));
+            };
+            outcome.push(key, meta);
+        }
+
+        Ok(outcome)
+    }
+
+    /// like [`StorageHelper::objects_metadata`], but keys clustered under a common prefix
+    /// (at least [`LISTING_THRESHOLD`] keys in the same directory) are found by listing the prefix
+    /// instead of one request per key; the other keys are fetched one by one
+    /// a prefix whose listing fails falls back to one request per key
+    async fn objects_metadata_via_listing(
+        &self,
+        bucket: &str,
+        keys: &[String],
+        ctx: &OpContext,
+    ) -> Result<BatchOutcome<String, ObjectMeta>, NimbusError> {
+        let plan = metadata::plan_fetch(keys, LISTING_THRESHOLD);
+        let mut outcome = self
+            .objects_metadata(bucket, &plan.heads, DEFAULT_METADATA_CONCURRENCY, ctx)
+            .await
+            .map_err(|e| match e {
+                // the listed keys are left too
+                NimbusError::DeadlineExceeded { completed, .. }
+                | NimbusError::Cancelled { completed, .. } => {
+                    ctx.stopped(completed, keys.len() - completed)
+                }
+                e => e,
+            })?;
+
+        let mut listings = futures::stream::iter(plan.listings)
+            .map(|(prefix, keys)| async move {
+                let mut found = HashMap::new();
+                let mut token = None;
+                loop {
+                    // `None` once the context is done
+                    let listed = ctx
+                        .run(self.list_metadata(bucket, Some(&prefix), token))
+                        .await?;
+                    let Ok((page, next)) = listed else {
+                        return Some(Err(keys));
+                    };
+                    found.extend(page.into_iter().map(|m| (m.key.clone(), m)));
+                    match next {
+                        Some(t) => token = Some(t),
+                        None => break,
+                    }
+                }
+                Some(Ok((keys, found)))
+            })
+            .buffer_unordered(DEFAULT_METADATA_CONCURRENCY);
+
+        while let Some(listing) = listings.next().await {
+            let Some(listing) = listing else {
+                return Err(ctx.stopped(outcome.len(), keys.len() - outcome.len()));
+            };
+            match listing {
+                Ok((keys, found)) => {
+                    for key in keys {
+                        let meta = found
+                            .get(&key)
+                            .cloned()
+                            .ok_or_else(|| Error::NotFound(key.clone()).into());
+                        outcome.push(key, meta);
+                    }
+                }
+                Err(prefix_keys) => {
+                    let fallback = self
+                        .objects_metadata(bucket, &prefix_keys, DEFAULT_METADATA_CONCURRENCY, ctx)
+                        .await;
+                    let fallback = match fallback {
+                        Err(
+                            NimbusError::DeadlineExceeded { completed, .. }
+                            | NimbusError::Cancelled { completed, .. },
+                        ) => {
+                            let completed = outcome.len() + completed;
+                            return Err(ctx.stopped(completed, keys.len() - completed));
+                        }
+                        fallback => fallback?,
+                    };
+                    outcome.succeeded.extend(fallback.succeeded);
+                    outcome.failed.extend(fallback.failed);
+                }
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// list the keys of a [`PartitionScheme`] whose partition overlaps `[from, to)`
+    /// one listing per prefix from [`PartitionScheme::prefixes_for_range`], run concurrently
+    /// keys are returned in lexicographic order
+    /// `limits` bound each listing, and the keys returned for `max_results`
+    async fn list_objects_in_range(
+        &self,
+        bucket: &str,
+        scheme: &PartitionScheme,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        let listings = scheme
+            .prefixes_for_range(from, to)
+            .into_iter()
+            .map(|prefix| async move {
+                let mut drain = limits.drain();
+                let mut keys = vec![];
+                let mut token = None;
+                loop {
+                    let (page, next) = self.list_keys(bucket, Some(&prefix), token).await?;
+                    drain.page(page.len(), next.is_some())?;
+                    keys.extend(page);
+                    match next {
+                        Some(t) => token = Some(t),
+                        None => break,
+                    }
+                }
+                Ok::<_, NimbusError>(keys)
+            });
+
+        let keys: Vec<String> = futures::future::try_join_all(listings)
+            .await?
+            .into_iter()
+            .flatten()
+            .filter(|key| scheme.key_in_range(key, from, to))
+            .collect();
+        limits.check(keys.len())?;
+
+        Ok(keys)
+    }
+
+    /// replace the tags of an object
+    ///
+    /// Tags are S3 object tags, which lifecycle rules and IAM policies can match on, unlike user metadata.
+    /// S3 allows at most 10 tags per object and rejects more.
+    /// GCS has no object tags and its lifecycle rules can't match custom metadata,
+    /// so this fails with [`Error::Unsupported`] there.
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError>;
+
+    /// tags of an object, see [`StorageHelper::set_object_tags`]
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError>;
+
+    /// deleted objects that can still be restored, as keys and the generations to pass to
+    /// [`StorageHelper::restore_object`]
+    ///
+    /// On GCS these are the soft-deleted objects of a bucket with a soft delete policy, listed through
+    /// the JSON API with the application default credentials: the storage client doesn't expose its own.
+    /// On S3 these are the keys of a versioned bucket whose latest version is a delete marker;
+    /// S3 has no generations, the generation is the time of the marker in milliseconds since the epoch.
+    /// Every page is listed, within `limits`.
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError>;
+
+    /// make a deleted object live again, `generation` comes from [`StorageHelper::list_soft_deleted`]
+    ///
+    /// On GCS the restored object gets a new generation. On S3 this removes the delete marker,
+    /// after checking that it is the latest version of the key: removing any other version would
+    /// delete data for good.
+    /// Fails with [`Error::PreconditionFailed`] if the key was written again since.
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError>;
+
+    /// returns which of the given IAM permissions the caller holds on a bucket
+    /// e.g. `storage.objects.create`
+    async fn test_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError>;
+
+    /// returns which of the given IAM permissions the caller lacks on a bucket
+    async fn missing_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        let held = self.test_permissions(bucket, permissions).await?;
+        Ok(permissions
+            .iter()
+            .filter(|p| !held.iter().any(|h| h == *p))
+            .map(|p| p.to_string())
+            .collect())
+    }
+
+    /// whether anyone can read the bucket, or every object in it
+    ///
+    /// On GCS this checks public access prevention, the IAM bindings of `allUsers` and `allAuthenticatedUsers`
+    /// and, without uniform bucket-level access, the bucket ACL. Conditional bindings to the public are
+    /// [`PublicAccess::Indeterminate`].
+    /// On S3 this checks the public access block, the policy status and the ACL of the bucket.
+    /// The account level public access block isn't read, a bucket it protects may be reported as public.
+    /// Buckets whose objects can be made public one by one (see [`StorageHelper::object_acls_apply`]) are
+    /// indeterminate unless public as a whole, [`StorageHelper::audit_public_objects`] lists those objects.
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError>;
+
+    /// whether objects of the bucket can be made public by their own ACL
+    ///
+    /// False under GCS uniform bucket-level access or enforced public access prevention,
+    /// and under S3 `BucketOwnerEnforced` object ownership or `IgnorePublicAcls`.
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError>;
+
+    /// ACL of an object
+    /// GCS refuses to read object ACLs under uniform bucket-level access
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError>;
+
+    /// keys under `prefix` whose own ACL makes them public, in lexicographic order
+    ///
+    /// The listing is streamed and ACLs are read [`AUDIT_CONCURRENCY`] at a time.
+    /// Nothing is read when object ACLs don't apply to the bucket, see [`StorageHelper::object_acls_apply`].
+    /// Objects deleted during the audit are skipped, any other error fails the audit.
+    /// Objects public through the bucket itself are not listed, see [`StorageHelper::bucket_is_public`].
+    async fn audit_public_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Vec<String>, NimbusError>
+    where
+        Self: Sized + Sync,
+    {
+        if !self.object_acls_apply(bucket).await? {
+            return Ok(vec![]);
+        }
+
+        let checks = self
+            .list(bucket)
+            .prefix(prefix)
+            .stream()
+            .map(|meta| async move {
+                let key = meta?.key;
+                match self.object_acl(bucket, &key).await {
+                    Ok(acl) => Ok(acl.iter().any(AclEntry::is_public).then_some(key)),
+                    Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })
+            .buffer_unordered(AUDIT_CONCURRENCY);
+        let mut checks = std::pin::pin!(checks);
+
+        let mut public = vec![];
+        while let Some(key) = checks.next().await {
+            public.extend(key?);
+        }
+        public.sort();
+
+        Ok(public)
+    }
+
+    /// CORS rules of the bucket, empty when it has none
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError>;
+
+    /// replace every CORS rule of the bucket with `rules`, an empty list removes them
+    ///
+    /// The rules are validated first, see [`validate_cors`]. The rule set is replaced at once on both providers.
+    /// On GCS the rest of the bucket configuration is sent back unchanged, and the update fails
+    /// with [`ErrorCode::PreconditionFailed`] if the configuration changed in between.
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError>;
+
+    /// add `rule` to the CORS rules of the bucket unless a [same](CorsRule::same_as) rule is there
+    /// returns whether the rules were changed
+    ///
+    /// Read-modify-write: a concurrent change of the rules between the read and the write is lost on S3.
+    async fn ensure_cors_contains(&self, bucket: &str, rule: CorsRule) -> Result<bool, NimbusError>
+    where
+        Self: Sync,
+    {
+        rule.validate()?;
+        let mut rules = self.get_cors(bucket).await?;
+        if rules.iter().any(|r| r.same_as(&rule)) {
+            return Ok(false);
+        }
+        rules.push(rule);
+        self.set_cors(bucket, rules).await?;
+        Ok(true)
+    }
+
+    /// policy letting a browser form upload one file under `key_prefix`, without credentials
+    ///
+    /// The provider rejects files larger than `max_size`, keys outside of `key_prefix`
+    /// and forms breaking `conditions`. The key is the prefix followed by the name of the uploaded file.
+    /// `expires` is at most [`MAX_POLICY_EXPIRY`], and no longer than the credentials signing it.
+    ///
+    /// On S3 the policy is signed with the credentials of the default provider chain, the ones
+    /// [`StorageHelper::new_with_authenticator`] builds the client from.
+    /// The storage client doesn't expose its signing credentials on GCS, so this fails with
+    /// [`Error::Unsupported`] there.
+    async fn signed_post_policy(
+        &self,
+        bucket: &str,
+        key_prefix: &str,
+        max_size: ByteSize,
+        expires: Duration,
+        conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError>;
+
+    /// URL letting a browser download `key` without credentials for `expires_in`
+    ///
+    /// `expires_in` is at most [`MAX_SIGNED_URL_EXPIRY`], and no longer than the credentials signing it.
+    /// The object isn't looked up, a URL of a missing object fails when used.
+    /// On GCS the URL is signed with V4 signing, by the service account the client was built with:
+    /// a client built from other credentials, e.g. the user credentials of `gcloud`, fails with
+    /// [`Error::Unsupported`].
+    /// On S3 it is presigned with SigV4 by the credentials of the client.
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError>;
+
+    /// URL letting a browser upload `key` with a PUT, without credentials, for `expires_in`
+    ///
+    /// `mime` is part of the signature: the provider then rejects uploads without this exact
+    /// `Content-Type` header. Signed like [`StorageHelper::signed_download_url`], with the same limits.
+    async fn signed_upload_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError>;
+
+    /// start a resumable upload for large objects
+    /// chunks are checksummed, see [`ResumableUpload`]
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError>;
+
+    /// upload everything `reader` yields, by parts of `part_size` bytes through a resumable upload,
+    /// e.g. a backup too large for a single request
+    ///
+    /// `part_size` must be at least [`MIN_CHUNK_SIZE`] and a multiple of [`CHUNK_ALIGNMENT`], else
+    /// [`Error::InvalidBufferSize`]; one part is held in memory at a time. Content shorter than a part
+    /// is sent in a single request. On failure the upload is aborted, no part is left billed.
+    async fn upload_large_from_reader<R>(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        reader: R,
+        part_size: usize,
+    ) -> Result<(), NimbusError>
+    where
+        Self: Sync,
+        R: AsyncRead + Unpin + Send,
+    {
+        resumable::upload_from_reader(self, bucket, key, mime, reader, part_size).await
+    }
+
+    /// stream a multipart form field into an object, e.g. an `axum` or `actix` upload
+    ///
+    /// The type is detected from the first [`SNIFF_LEN`] bytes and checked against `constraints`,
+    /// then `key_fn` names the object. Fields larger than the buffer (see [`UploadConstraints::buffer_size`])
+    /// go through a resumable upload so only one buffer is held in memory.
+    /// The upload stops as soon as the field exceeds `constraints.max_size`, with [`Error::UploadTooLarge`],
+    /// and the chunks sent so far are discarded. A failing stream is discarded too.
+    async fn upload_multipart_field<B, E>(
+        &self,
+        bucket: &str,
+        key_fn: impl for<'p> Fn(&'p PartInfo) -> String + Send + Sync,
+        field: impl futures::Stream<Item = Result<B, E>> + Send,
+        constraints: UploadConstraints,
+    ) -> Result<UploadedObject, NimbusError>
+    where
+        Self: Sync,
+        B: AsRef<[u8]> + Send,
+        E: std::fmt::Display + Send,
+    {
+        multipart::upload_field(self, bucket, key_fn, Box::pin(field), &constraints).await
+    }
+
+    /// upload a file from a path to a bucket
+    /// takes a PathBuf to file and key
+    /// file name does not matter as key will be used to create the file in the bucket
+    async fn upload_file(&self, bucket: &str, key: &str, path: PathBuf) -> Result<(), NimbusError> {
+        let data = tokio::fs::read(path).await.map_err(Error::IO)?;
+        self.upload_from_bytes(bucket, key, None, data).await?;
+        Ok(())
+    }
+
+    /// [upload](StorageHelper::upload_file) many `(key, path)` files, at most `concurrency` at once
+    ///
+    /// Returns the first error; the uploads still in flight are dropped then, which cancels them,
+    /// and the files not started are not uploaded. Nothing is spawned, so nothing outlives the call.
+    async fn upload_files(
+        &self,
+        bucket: &str,
+        files: Vec<(String, PathBuf)>,
+        concurrency: usize,
+    ) -> Result<(), NimbusError> {
+        let mut uploads = futures::stream::iter(files)
+            .map(|(key, path)| async move { self.upload_file(bucket, &key, path).await })
+            .buffer_unordered(concurrency.max(1));
+
+        while let Some(uploaded) = uploads.next().await {
+            uploaded?;
+        }
+        Ok(())
+    }
+
+    /// download a file from a bucket to a path to given destination directory
+    /// keys ending in `/` fail with [`Error::IsDirectoryPlaceholder`], they name no file
+    /// the object is streamed to disk, with [`StorageHelper::download_to_writer`], and the file removed
+    /// if the download fails midway
+    async fn download_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path_dir: PathBuf,
+    ) -> Result<PathBuf, NimbusError> {
+        if key.ends_with('/') {
+            return Err(Error::IsDirectoryPlaceholder(format!("{bucket}/{key}")).into());
+        }
+
+        if !path_dir.exists() {
+            tokio::fs::create_dir_all(path_dir.clone())
+                .await
+                .map_err(Error::IO)?;
+        }
+
+        if !path_dir.is_dir() {
+            return Err(
+                Error::Other(format!("Path {} is not a directory", path_dir.display())).into(),
+            );
+        }
+
+        let path = path_dir.join(key);
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(Error::IO)?;
+        }
+
+        let written = async {
+            let mut file = tokio::fs::File::create(&path).await.map_err(Error::IO)?;
+            self.download_to_writer(bucket, key, &mut file).await
+        };
+        if let Err(e) = written.await {
+            // no partial file is left behind
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e);
+        }
+
+        Ok(path)
+    }
+
+    /// like [`StorageHelper::upload_file`], calling `on_progress` every `every`
+    /// (see [`DEFAULT_PROGRESS_INTERVAL`]) while reading the file and after each chunk sent
+    ///
+    /// Files larger than [`DEFAULT_BUFFER_SIZE`] go through a resumable upload by chunks of that size,
+    /// so only one chunk is held in memory. A panic in `on_progress` is caught and the callback
+    /// isn't called again, the upload goes on.
+    async fn upload_file_with_progress(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: PathBuf,
+        every: impl Into<ByteSize> + Send,
+        on_progress: impl Fn(ProgressEvent) + Send + Sync,
+    ) -> Result<(), NimbusError>
+    where
+        Self: Sync,
+    {
+        progress::upload_file(self, bucket, key, &path, every.into(), on_progress).await
+    }
+
+    /// like [`StorageHelper::download_file`], calling `on_progress` every `every` written
+    ///
+    /// The object is fetched in one response: [`ProgressPhase::Downloading`] is reported when it
+    /// starts and once it is received. Panics in `on_progress` are handled as for uploads.
+    async fn download_file_with_progress(
+        &self,
+        bucket: &str,
+        key: &str,
+        path_dir: PathBuf,
+        every: impl Into<ByteSize> + Send,
+        on_progress: impl Fn(ProgressEvent) + Send + Sync,
+    ) -> Result<PathBuf, NimbusError>
+    where
+        Self: Sync,
+    {
+        if path_dir.exists() && !path_dir.is_dir() {
+            return Err(
+                Error::Other(format!("Path {} is not a directory", path_dir.display())).into(),
+            );
+        }
+
+        if key.ends_with('/') {
+            return Err(Error::IsDirectoryPlaceholder(format!("{bucket}/{key}")).into());
+        }
+
+        let path = path_dir.join(key);
+        progress::download_file(self, bucket, key, &path, every.into(), on_progress).await
+    }
+
+    /// create the zero-byte "folder" placeholder of `prefix` (`/` appended if missing) for the tools
+    /// that need folders to exist, returns its key; an object already under the key is left as is
+    async fn create_placeholder_dir(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<String, NimbusError> {
+        let key = placeholder_key(prefix);
+        self.upload_if_absent(bucket, &key, None, vec![]).await?;
+        Ok(key)
+    }
+
+    /// delete the placeholder of `prefix`, the objects under it are left
+    /// fails if the object under the key isn't a placeholder, or was replaced by one that isn't in between
+    async fn delete_placeholder_dir(&self, bucket: &str, prefix: &str) -> Result<(), NimbusError> {
+        let key = placeholder_key(prefix);
+        let meta = self.object_metadata(bucket, &key).await?;
+        if !meta.is_placeholder_dir() {
+            return Err(Error::Other(format!(
+                "{bucket}/{key} holds {} bytes, it is not a folder placeholder",
+                meta.size
+            ))
+            .into());
+        }
+
+        match meta.version.as_deref() {
+            Some(version) => self.delete_conditional(bucket, &key, version).await,
+            None => self.delete_file(bucket, &key).await,
+        }
+    }
+
+    /// check if file type is valid
+    fn valid_file_type(&self, file: &[u8], expected: &str) -> Result<(), NimbusError> {
+        let file_type = infer::get(file)
+            .ok_or_else(|| Error::InvalidFileType("Failed to get file type".to_owned()))?;
+
+        if file_type.extension() != expected {
+            return Err(Error::InvalidFileType(format!(
+                "File type is not valid. Expected: {}, got: {}",
+                expected,
+                file_type.extension()
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gcp-storage")]
+#[async_trait::async_trait]
+impl StorageHelper for Client {
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("upload_from_bytes", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let up_type = UploadType::Multipart(Box::new(Object {
+                    name: key.to_string(),
+                    content_type: mime,
+                    ..Default::default()
+                }));
+
+                let len = data.len() as u64;
+                let _ = self
+                    .upload_object(
+                        &UploadObjectRequest {
+                            bucket: bucket.to_string(),
+                            ..Default::default()
+                        },
+                        data,
+                        &up_type,
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Ingress, len);
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_to_bytes", GCS_SCHEME, bucket, key),
+            async {
+                let mut data = vec![];
+                self.download_to_writer(bucket, key, &mut data).await?;
+                Ok(data)
+            },
+        )
+        .await
+    }
+
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        with_context(
+            || object_context("download_stream", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let stream = self
+                    .download_streamed_object(
+                        &GetObjectRequest {
+                            bucket: bucket.to_owned(),
+                            object: key.to_owned(),
+                            ..Default::default()
+                        },
+                        &Range::default(),
+                    )
+                    .await
+                    .map_err(|e| gcs_object_error(e, bucket, key))?;
+
+                let bucket = bucket.to_owned();
+                let stream = stream.map(move |chunk| {
+                    let chunk = chunk.map_err(Error::Storage)?;
+                    traffic::record(&bucket, Direction::Egress, chunk.len() as u64);
+                    Ok(chunk)
+                });
+
+                Ok(stream.boxed())
+            },
+        )
+        .await
+    }
+
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_with_encoding", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let req = GetObjectRequest {
+                    bucket: bucket.to_owned(),
+                    object: key.to_owned(),
+                    ..Default::default()
+                };
+
+                let object = self.get_object(&req).await.map_err(Error::Storage)?;
+                let gzip = gzip::is_gzip_encoding(object.content_encoding.as_deref());
+
+                // the client doesn't accept gzip, so GCS would decompress the object for it
+                if gzip && !decompress {
+                    let data = json_api::download_stored(bucket, key, object.generation).await?;
+                    traffic::record(bucket, Direction::Egress, data.len() as u64);
+                    return Ok(data);
+                }
+
+                // pin the generation the encoding was read from
+                let data = self
+                    .download_object(
+                        &GetObjectRequest {
+                            generation: Some(object.generation),
+                            ..req
+                        },
+                        &Range::default(),
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Egress, data.len() as u64);
+
+                // no-transform objects are served as stored
+                if gzip && decompress && gzip::has_gzip_magic(&data) {
+                    return Ok(gzip::gunzip(&data)?);
+                }
+
+                Ok(data)
+            },
+        )
+        .await
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_range", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                range_header(start, end)?;
+
+                let data = self
+                    .download_object(
+                        &GetObjectRequest {
+                            bucket: bucket.to_owned(),
+                            object: key.to_owned(),
+                            ..Default::default()
+                        },
+                        &Range(Some(start), end),
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Egress, data.len() as u64);
+
+                Ok(data)
+            },
+        )
+        .await
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("upload_encrypted", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let up_type = UploadType::Multipart(Box::new(Object {
+                    name: key.to_string(),
+                    content_type: mime,
+                    ..Default::default()
+                }));
+
+                let len = data.len() as u64;
+                self.upload_object(
+                    &UploadObjectRequest {
+                        bucket: bucket.to_string(),
+                        encryption: Some(encryption.to_gcs()),
+                        ..Default::default()
+                    },
+                    data,
+                    &up_type,
+                )
+                .await
+                .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Ingress, len);
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_encrypted", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let data = self
+                    .download_object(
+                        &GetObjectRequest {
+                            bucket: bucket.to_owned(),
+                            object: key.to_owned(),
+                            encryption: Some(encryption.to_gcs()),
+                            ..Default::default()
+                        },
+                        &Range::default(),
+                    )
+                    .await
+                    .map_err(|e| gcs_object_error(e, bucket, key))?;
+                traffic::record(bucket, Direction::Egress, data.len() as u64);
+
+                Ok(data)
+            },
+        )
+        .await
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        with_context(
+            || object_context("object_metadata_encrypted", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let object = self
+                    .get_object(&GetObjectRequest {
+                        bucket: bucket.to_owned(),
+                        object: key.to_owned(),
+                        encryption: Some(encryption.to_gcs()),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(|e| gcs_object_error(e, bucket, key))?;
+
+                Ok(object.into())
+            },
+        )
+        .await
+    }
+
+    /// a rewrite rather than a copy: only rewrites take distinct source and destination keys
+    async fn copy_encrypted(
+        &self,
+        (source_bucket, source_key): (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        (destination_bucket, destination_key): (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || {
+                object_context("copy_encrypted", GCS_SCHEME, source_bucket, source_key)
+                    .to(format!("{destination_bucket}/{destination_key}"))
+            },
+            async {
+                use google_cloud_storage::http::objects::rewrite::RewriteObjectRequest;
+
+                validate_bucket_name(source_bucket, Provider::Gcs)?;
+                validate_bucket_name(destination_bucket, Provider::Gcs)?;
+
+                let mut req = RewriteObjectRequest {
+                    source_bucket: source_bucket.to_owned(),
+                    source_object: source_key.to_owned(),
+                    destination_bucket: destination_bucket.to_owned(),
+                    destination_object: destination_key.to_owned(),
+                    source_encryption: source_encryption.map(EncryptionKey::to_gcs),
+                    destination_encryption: destination_encryption.map(EncryptionKey::to_gcs),
+                    ..Default::default()
+                };
+                // large objects, or objects changing location or class, take several calls
+                loop {
+                    let res = self
+                        .rewrite_object(&req)
+                        .await
+                        .map_err(|e| gcs_object_error(e, source_bucket, source_key))?;
+                    if res.done {
+                        return Ok(());
+                    }
+                    req.rewrite_token = res.rewrite_token;
+                }
+            },
+        )
+        .await
+    }
+
+    /// a rewrite onto itself carrying the current metadata, a rewrite without it would reset it
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("set_storage_class", GCS_SCHEME, bucket, key),
+            async {
+                use google_cloud_storage::http::objects::rewrite::RewriteObjectRequest;
+
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let object = self
+                    .get_object(&GetObjectRequest {
+                        bucket: bucket.to_owned(),
+                        object: key.to_owned(),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(|e| gcs_object_error(e, bucket, key))?;
+                let mut req = RewriteObjectRequest {
+                    source_bucket: bucket.to_owned(),
+                    source_object: key.to_owned(),
+                    destination_bucket: bucket.to_owned(),
+                    destination_object: key.to_owned(),
+                    destination_metadata: Some(Object {
+                        storage_class: Some(class.gcs_name().to_owned()),
+                        ..object
+                    }),
+                    ..Default::default()
+                };
+                // a class change takes several calls for large objects
+                loop {
+                    let res = self
+                        .rewrite_object(&req)
+                        .await
+                        .map_err(|e| gcs_object_error(e, bucket, key))?;
+                    if res.done {
+                        return Ok(());
+                    }
+                    req.rewrite_token = res.rewrite_token;
+                }
+            },
+        )
+        .await
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("delete_file", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let _ = self
+                    .delete_object(&DeleteObjectRequest {
+                        bucket: bucket.to_owned(),
+                        object: key.to_owned(),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        with_context(
+            || object_context("object_exists", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let res = self
+                    .get_object(&GetObjectRequest {
+                        bucket: bucket.to_owned(),
+                        object: key.to_owned(),
+                        ..Default::default()
+                    })
+                    .await;
+
+                match res.map_err(Error::Storage) {
+                    Ok(_) => Ok(true),
+                    Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+                    Err(e) => Err(e.into()),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("upload_conditional", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let if_generation_match = match precondition {
+                    // generation 0 only matches a missing object
+                    Precondition::DoesNotExist => 0,
+                    Precondition::VersionMatches(version) => generation(version)?,
+                };
+
+                let up_type = UploadType::Multipart(Box::new(Object {
+                    name: key.to_string(),
+                    content_type: mime,
+                    ..Default::default()
+                }));
+
+                let len = data.len() as u64;
+                let object = self
+                    .upload_object(
+                        &UploadObjectRequest {
+                            bucket: bucket.to_string(),
+                            if_generation_match: Some(if_generation_match),
+                            ..Default::default()
+                        },
+                        data,
+                        &up_type,
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Ingress, len);
+
+                Ok(object.generation.to_string())
+            },
+        )
+        .await
+    }
+
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("upload_with_metadata", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let if_generation_match = match precondition {
+                    None => None,
+                    Some(Precondition::DoesNotExist) => Some(0),
+                    Some(Precondition::VersionMatches(version)) => Some(generation(version)?),
+                };
+
+                let up_type = UploadType::Multipart(Box::new(Object {
+                    name: key.to_string(),
+                    content_type: mime,
+                    metadata: Some(metadata),
+                    ..Default::default()
+                }));
+
+                let len = data.len() as u64;
+                let object = self
+                    .upload_object(
+                        &UploadObjectRequest {
+                            bucket: bucket.to_string(),
+                            if_generation_match,
+                            ..Default::default()
+                        },
+                        data,
+                        &up_type,
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Ingress, len);
+
+                Ok(object.generation.to_string())
+            },
+        )
+        .await
+    }
+
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        with_context(
+            || object_context("append_bytes", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                append::gcs(self, bucket, key, mime, data, &options).await
+            },
+        )
+        .await
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("delete_conditional", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                self.delete_object(&DeleteObjectRequest {
+                    bucket: bucket.to_owned(),
+                    object: key.to_owned(),
+                    if_generation_match: Some(generation(version)?),
+                    ..Default::default()
+                })
+                .await
+                .map_err(Error::Storage)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        with_context(
+            || object_context("download_versioned", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let req = GetObjectRequest {
+                    bucket: bucket.to_owned(),
+                    object: key.to_owned(),
+                    ..Default::default()
+                };
+
+                let object = self.get_object(&req).await.map_err(Error::Storage)?;
+                let data = self
+                    .download_object(
+                        &GetObjectRequest {
+                            generation: Some(object.generation),
+                            ..req
+                        },
+                        &Range::default(),
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+                traffic::record(bucket, Direction::Egress, data.len() as u64);
+
+                Ok((data, object.generation.to_string()))
+            },
+        )
+        .await
+    }
+
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        with_context(
+            || object_context("list_page", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                // the client's own listing, not `StorageHelper::list_objects`
+                let res = (**self)
+                    .list_objects(&ListObjectsRequest {
+                        bucket: bucket.to_owned(),
+                        prefix: params.prefix.clone(),
+                        delimiter: params.delimiter.clone(),
+                        max_results: params.page_size.map(|n| n.min(i32::MAX as u32) as i32),
+                        versions: params.versions.then_some(true),
+                        // the offsets are inclusive at the start, exclusive at the end,
+                        // the `start_after` key itself is left out below
+                        start_offset: match (&params.start_after, &params.start_at) {
+                            (Some(after), Some(at)) => Some(after.max(at).clone()),
+                            (after, at) => after.clone().or_else(|| at.clone()),
+                        },
+                        end_offset: params.end_before.clone(),
+                        page_token,
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(ListPage {
+                    objects: res
+                        .items
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|o| params.start_after.as_ref() != Some(&o.name))
+                        .map(ObjectMeta::from)
+                        .collect(),
+                    prefixes: res.prefixes.unwrap_or_default(),
+                    next_page_token: res.next_page_token,
+                })
+            },
+        )
+        .await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        with_context(
+            || object_context("object_metadata", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let object = self
+                    .get_object(&GetObjectRequest {
+                        bucket: bucket.to_owned(),
+                        object: key.to_owned(),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(object.into())
+            },
+        )
+        .await
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        with_context(
+            || object_context("replication_status", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                // for the NotFound of a missing object
+                self.get_object(&GetObjectRequest {
+                    bucket: bucket.to_owned(),
+                    object: key.to_owned(),
+                    ..Default::default()
+                })
+                .await
+                .map_err(Error::Storage)?;
+
+                let config = self
+                    .get_bucket(&GetBucketRequest {
+                        bucket: bucket.to_owned(),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(ReplicationStatus::from_gcs(
+                    &config.location_type,
+                    config.rpo.as_deref(),
+                ))
+            },
+        )
+        .await
+    }
+
+    async fn set_object_tags(
+        &self,
+        _: &str,
+        _: &str,
+        _: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        Err(Error::Unsupported("object tags on GCS".to_owned()).into())
+    }
+
+    async fn get_object_tags(
+        &self,
+        _: &str,
+        _: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        Err(Error::Unsupported("object tags on GCS".to_owned()).into())
+    }
+
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        with_context(
+            || object_context("list_soft_deleted", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                json_api::list_soft_deleted(bucket, prefix, limits).await
+            },
+        )
+        .await
+    }
+
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("restore_object", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                json_api::restore(bucket, key, generation).await
+            },
+        )
+        .await
+    }
+
+    async fn test_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        with_context(
+            || object_context("test_permissions", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let res = self
+                    .test_iam_permissions(&TestIamPermissionsRequest {
+                        resource: bucket.to_owned(),
+                        permissions: permissions.iter().map(|p| p.to_string()).collect(),
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(res.permissions)
+            },
+        )
+        .await
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        with_context(
+            || object_context("bucket_is_public", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let config = self
+                    .get_bucket(&GetBucketRequest {
+                        bucket: bucket.to_owned(),
+                        projection: Some(Projection::Full),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+                let (prevented, uniform) = gcs_access_config(&config);
+                if prevented {
+                    return Ok(PublicAccess::NotPublic);
+                }
+
+                // conditional bindings are only returned from version 3
+                let policy = self
+                    .get_iam_policy(&GetIamPolicyRequest {
+                        resource: bucket.to_owned(),
+                        options_requested_policy_version: Some(3),
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                let mut exposure = Exposure {
+                    object_acls: !uniform,
+                    ..Default::default()
+                };
+                for binding in &policy.bindings {
+                    let Some(member) = binding
+                        .members
+                        .iter()
+                        .find(|m| Grantee::from_gcs(m).is_public())
+                    else {
+                        continue;
+                    };
+                    match &binding.condition {
+                        None => exposure.granted = true,
+                        Some(condition) => {
+                            exposure.conditional = Some(format!(
+                                "{} is granted to {member} under condition {:?}",
+                                binding.role, condition.expression
+                            ))
+                        }
+                    }
+                }
+                // the bucket ACL is ignored under uniform access
+                if !uniform {
+                    let acl = config.acl.iter().flatten();
+                    exposure.granted |= acl
+                        .into_iter()
+                        .any(|a| Grantee::from_gcs(&a.entity).is_public());
+                }
+
+                Ok(exposure.verdict())
+            },
+        )
+        .await
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        with_context(
+            || object_context("object_acls_apply", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let config = self
+                    .get_bucket(&GetBucketRequest {
+                        bucket: bucket.to_owned(),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+                let (prevented, uniform) = gcs_access_config(&config);
+
+                Ok(!prevented && !uniform)
+            },
+        )
+        .await
+    }
+
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        with_context(
+            || object_context("object_acl", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let res = self
+                    .list_object_access_controls(&ListObjectAccessControlsRequest {
+                        bucket: bucket.to_owned(),
+                        object: audit::escape_object_name(key),
+                        generation: None,
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(res
+                    .items
+                    .into_iter()
+                    .map(|a| AclEntry::new(Grantee::from_gcs(&a.entity), format!("{:?}", a.role)))
+                    .collect())
+            },
+        )
+        .await
+    }
+
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        with_context(
+            || object_context("get_cors", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let config = self
+                    .get_bucket(&GetBucketRequest {
+                        bucket: bucket.to_owned(),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(config
+                    .cors
+                    .iter()
+                    .flatten()
+                    .map(CorsRule::from_gcs)
+                    .collect())
+            },
+        )
+        .await
+    }
+
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("set_cors", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                validate_cors(&rules)?;
+
+                // the ACLs are only returned with the full projection, and would be cleared otherwise
+                let config = self
+                    .get_bucket(&GetBucketRequest {
+                        bucket: bucket.to_owned(),
+                        projection: Some(Projection::Full),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(Error::Storage)?;
+                self.patch_bucket(&cors::gcs_cors_patch(config, &rules))
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn signed_post_policy(
+        &self,
+        bucket: &str,
+        _: &str,
+        _: ByteSize,
+        _: Duration,
+        _: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        with_context(
+            || object_context("signed_post_policy", GCS_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                Err(Error::Unsupported("signing POST policies on GCS".to_owned()).into())
+            },
+        )
+        .await
+    }
+
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("signed_download_url", GCS_SCHEME, bucket, key),
+            async {
+                use google_cloud_storage::sign::SignedURLOptions;
+
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                validate_expiry(expires_in)?;
+
+                let options = SignedURLOptions {
+                    expires: expires_in,
+                    ..Default::default()
+                };
+                let url = self
+                    .signed_url(bucket, key, None, None, options)
+                    .await
+                    .map_err(gcs_sign_error)?;
+
+                Ok(url)
+            },
+        )
+        .await
+    }
+
+    async fn signed_upload_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("signed_upload_url", GCS_SCHEME, bucket, key),
+            async {
+                use google_cloud_storage::sign::{SignedURLMethod, SignedURLOptions};
+
+                validate_bucket_name(bucket, Provider::Gcs)?;
+                validate_expiry(expires_in)?;
+
+                let options = SignedURLOptions {
+                    method: SignedURLMethod::PUT,
+                    expires: expires_in,
+                    content_type: mime,
+                    ..Default::default()
+                };
+                let url = self
+                    .signed_url(bucket, key, None, None, options)
+                    .await
+                    .map_err(gcs_sign_error)?;
+
+                Ok(url)
+            },
+        )
+        .await
+    }
+
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        with_context(
+            || object_context("start_resumable_upload", GCS_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::Gcs)?;
+
+                let up_type = UploadType::Multipart(Box::new(Object {
+                    name: key.to_string(),
+                    content_type: mime,
+                    ..Default::default()
+                }));
+
+                let uploader = self
+                    .prepare_resumable_upload(
+                        &UploadObjectRequest {
+                            bucket: bucket.to_string(),
+                            ..Default::default()
+                        },
+                        &up_type,
+                    )
+                    .await
+                    .map_err(Error::Storage)?;
+
+                Ok(ResumableUpload::new(resumable::Session::Gcs {
+                    client: self.clone(),
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    uploader,
+                }))
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(feature = "aws-storage")]
+#[async_trait::async_trait]
+impl StorageHelper for Client {
+    async fn new_with_authenticator() -> Self {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        Client::new(&config)
+    }
+
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Client::new(&transport.aws_sdk_config().await)
+    }
+
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("upload_from_bytes", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let len = data.len() as u64;
+                let builder = self
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .body(ByteStream::from(data))
+                    .set_content_type(mime);
+
+                if let Err(e) = builder.send().await {
+                    return Err(NimbusError::from(Error::from_sdk(e)));
+                }
+                traffic::record(bucket, Direction::Ingress, len);
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_to_bytes", S3_SCHEME, bucket, key),
+            async {
+                let mut data = vec![];
+                self.download_to_writer(bucket, key, &mut data).await?;
+                Ok(data)
+            },
+        )
+        .await
+    }
+
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        with_context(
+            || object_context("download_stream", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let builder = self.get_object().bucket(bucket).key(key);
+
+                match builder.send().await {
+                    Ok(d) => Ok(body_stream(bucket, d.body)),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_with_encoding", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let out = match self.get_object().bucket(bucket).key(key).send().await {
+                    Ok(out) => out,
+                    Err(e) => return Err(NimbusError::from(Error::from_sdk(e))),
+                };
+
+                let gzip = gzip::is_gzip_encoding(out.content_encoding());
+                let data = read_body(bucket, out.body).await?;
+
+                if gzip && decompress && gzip::has_gzip_magic(&data) {
+                    return Ok(gzip::gunzip(&data)?);
+                }
+
+                Ok(data)
+            },
+        )
+        .await
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_range", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+                let range = range_header(start, end)?;
+
+                let res = self
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .range(range)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(read_body(bucket, res.body).await?)
+            },
+        )
+        .await
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("upload_encrypted", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let len = data.len() as u64;
+                self.put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .body(ByteStream::from(data))
+                    .set_content_type(mime)
+                    .sse_customer_algorithm(ENCRYPTION_ALGORITHM)
+                    .sse_customer_key(encryption.key_base64())
+                    .sse_customer_key_md5(encryption.md5_base64())
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+                traffic::record(bucket, Direction::Ingress, len);
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        with_context(
+            || object_context("download_encrypted", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let res = self
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .sse_customer_algorithm(ENCRYPTION_ALGORITHM)
+                    .sse_customer_key(encryption.key_base64())
+                    .sse_customer_key_md5(encryption.md5_base64())
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(read_body(bucket, res.body).await?)
+            },
+        )
+        .await
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        with_context(
+            || object_context("object_metadata_encrypted", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let out = self
+                    .head_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .checksum_mode(aws_sdk_s3::types::ChecksumMode::Enabled)
+                    .sse_customer_algorithm(ENCRYPTION_ALGORITHM)
+                    .sse_customer_key(encryption.key_base64())
+                    .sse_customer_key_md5(encryption.md5_base64())
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(ObjectMeta::from_head(key, &out))
+            },
+        )
+        .await
+    }
+
+    async fn copy_encrypted(
+        &self,
+        (source_bucket, source_key): (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        (destination_bucket, destination_key): (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || {
+                object_context("copy_encrypted", S3_SCHEME, source_bucket, source_key)
+                    .to(format!("{destination_bucket}/{destination_key}"))
+            },
+            async {
+                validate_bucket_name(source_bucket, Provider::S3)?;
+                validate_bucket_name(destination_bucket, Provider::S3)?;
+
+                let mut builder = self
+                    .copy_object()
+                    .copy_source(s3_copy_source(source_bucket, source_key))
+                    .bucket(destination_bucket)
+                    .key(destination_key);
+                if let Some(encryption) = source_encryption {
+                    builder = builder
+                        .copy_source_sse_customer_algorithm(ENCRYPTION_ALGORITHM)
+                        .copy_source_sse_customer_key(encryption.key_base64())
+                        .copy_source_sse_customer_key_md5(encryption.md5_base64());
+                }
+                if let Some(encryption) = destination_encryption {
+                    builder = builder
+                        .sse_customer_algorithm(ENCRYPTION_ALGORITHM)
+                        .sse_customer_key(encryption.key_base64())
+                        .sse_customer_key_md5(encryption.md5_base64());
+                }
+                builder.send().await.map_err(Error::from_sdk)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("set_storage_class", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                self.copy_object()
+                    .copy_source(s3_copy_source(bucket, key))
+                    .bucket(bucket)
+                    .key(key)
+                    .storage_class(class.to_s3())
+                    .metadata_directive(aws_sdk_s3::types::MetadataDirective::Copy)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("delete_file", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let r = self.delete_object().bucket(bucket).key(key).send().await;
+
+                match r {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        with_context(
+            || object_context("object_exists", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let r = self.head_object().bucket(bucket).key(key).send().await;
+
+                match r.map_err(Error::from_sdk) {
+                    Ok(_) => Ok(true),
+                    Err(Error::NotFound(_)) => Ok(false),
+                    Err(e) => Err(e.into()),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("upload_conditional", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let (header, value) = match precondition {
+                    Precondition::DoesNotExist => ("If-None-Match", "*".to_owned()),
+                    Precondition::VersionMatches(etag) => ("If-Match", etag.clone()),
+                };
+
+                let len = data.len() as u64;
+                let r = self
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .body(ByteStream::from(data))
+                    .set_content_type(mime)
+                    .customize()
+                    .mutate_request(move |req| {
+                        req.headers_mut().insert(header, value.clone());
+                    })
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(out) => {
+                        traffic::record(bucket, Direction::Ingress, len);
+                        Ok(out.e_tag().unwrap_or_default().to_owned())
+                    }
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("upload_with_metadata", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let header = precondition.map(|precondition| match precondition {
+                    Precondition::DoesNotExist => ("If-None-Match", "*".to_owned()),
+                    Precondition::VersionMatches(etag) => ("If-Match", etag.clone()),
+                });
+
+                let len = data.len() as u64;
+                let r = self
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .body(ByteStream::from(data))
+                    .set_content_type(mime)
+                    .set_metadata(Some(metadata))
+                    .customize()
+                    .mutate_request(move |req| {
+                        if let Some((name, value)) = &header {
+                            req.headers_mut().insert(*name, value.clone());
+                        }
+                    })
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(out) => {
+                        traffic::record(bucket, Direction::Ingress, len);
+                        Ok(out.e_tag().unwrap_or_default().to_owned())
+                    }
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        with_context(
+            || object_context("append_bytes", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+                append::s3(self, bucket, key, mime, data, &options).await
+            },
+        )
+        .await
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("delete_conditional", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let etag = version.to_owned();
+                let r = self
+                    .delete_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .customize()
+                    .mutate_request(move |req| {
+                        req.headers_mut().insert("If-Match", etag.clone());
+                    })
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        with_context(
+            || object_context("download_versioned", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let out = match self.get_object().bucket(bucket).key(key).send().await {
+                    Ok(out) => out,
+                    Err(e) => return Err(NimbusError::from(Error::from_sdk(e))),
+                };
+
+                let etag = out.e_tag().unwrap_or_default().to_owned();
+                let data = read_body(bucket, out.body).await?;
+
+                Ok((data, etag))
+            },
+        )
+        .await
+    }
+
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        with_context(
+            || object_context("list_page", S3_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let max_keys = params.page_size.map(|n| n.min(i32::MAX as u32) as i32);
+
+                if params.versions {
+                    let (key_marker, version_id_marker) = page_token
+                        .as_deref()
+                        .and_then(list::parse_version_token)
+                        .unzip();
+
+                    let r = self
+                        .list_object_versions()
+                        .bucket(bucket)
+                        .set_prefix(params.prefix.clone())
+                        .set_delimiter(params.delimiter.clone())
+                        .set_max_keys(max_keys)
+                        .set_key_marker(key_marker.or_else(|| params.exclusive_start()))
+                        .set_version_id_marker(version_id_marker)
+                        .send()
+                        .await;
+
+                    return match r {
+                        Ok(out) => {
+                            let next_page_token =
+                                match (out.next_key_marker(), out.next_version_id_marker()) {
+                                    (Some(key), version)
+                                        if out.is_truncated().unwrap_or_default() =>
+                                    {
+                                        Some(list::version_token(key, version.unwrap_or_default()))
+                                    }
+                                    _ => None,
+                                };
+                            Ok(s3_bounded_page(
+                                params,
+                                out.versions()
+                                    .iter()
+                                    .map(ObjectMeta::from_version)
+                                    .collect(),
+                                out.common_prefixes()
+                                    .iter()
+                                    .filter_map(|p| p.prefix().map(str::to_owned))
+                                    .collect(),
+                                next_page_token,
+                            ))
+                        }
+                        Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                    };
+                }
+
+                let r = self
+                    .list_objects_v2()
+                    .bucket(bucket)
+                    .set_prefix(params.prefix.clone())
+                    .set_delimiter(params.delimiter.clone())
+                    .set_max_keys(max_keys)
+                    .set_start_after(params.exclusive_start())
+                    .set_continuation_token(page_token)
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(out) => Ok(s3_bounded_page(
+                        params,
+                        out.contents()
+                            .iter()
+                            .map(ObjectMeta::from_listing)
+                            .collect(),
+                        out.common_prefixes()
+                            .iter()
+                            .filter_map(|p| p.prefix().map(str::to_owned))
+                            .collect(),
+                        out.next_continuation_token().map(str::to_owned),
+                    )),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        with_context(
+            || object_context("object_metadata", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let r = self
+                    .head_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .checksum_mode(aws_sdk_s3::types::ChecksumMode::Enabled)
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(out) => Ok(ObjectMeta::from_head(key, &out)),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        with_context(
+            || object_context("replication_status", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let out = self
+                    .head_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(ReplicationStatus::from_s3(
+                    out.replication_status().map(|s| s.as_str()),
+                ))
+            },
+        )
+        .await
+    }
+
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("set_object_tags", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let tag_set = tags
+                    .into_iter()
+                    .map(|(key, value)| Tag::builder().key(key).value(value).build())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Error::Storage(e.to_string()))?;
+                let tagging = Tagging::builder()
+                    .set_tag_set(Some(tag_set))
+                    .build()
+                    .map_err(|e| Error::Storage(e.to_string()))?;
+
+                let r = self
+                    .put_object_tagging()
+                    .bucket(bucket)
+                    .key(key)
+                    .tagging(tagging)
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        with_context(
+            || object_context("get_object_tags", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let r = self
+                    .get_object_tagging()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await;
+
+                match r {
+                    Ok(out) => Ok(out
+                        .tag_set()
+                        .iter()
+                        .map(|t| (t.key().to_owned(), t.value().to_owned()))
+                        .collect()),
+                    Err(e) => Err(NimbusError::from(Error::from_sdk(e))),
+                }
+            },
+        )
+        .await
+    }
+
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        with_context(
+            || object_context("list_soft_deleted", S3_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let mut drain = limits.drain();
+                let mut deleted = vec![];
+                let (mut key_marker, mut version_id_marker) = (None, None);
+                loop {
+                    let out = self
+                        .list_object_versions()
+                        .bucket(bucket)
+                        .set_prefix(prefix.clone())
+                        .set_key_marker(key_marker)
+                        .set_version_id_marker(version_id_marker)
+                        .send()
+                        .await
+                        .map_err(Error::from_sdk)?;
+
+                    let markers: Vec<(String, i64)> = out
+                        .delete_markers()
+                        .iter()
+                        .filter(|m| m.is_latest().unwrap_or_default())
+                        .filter_map(|m| Some((m.key()?.to_owned(), marker_generation(m)?)))
+                        .collect();
+                    drain.page(markers.len(), out.is_truncated().unwrap_or_default())?;
+                    deleted.extend(markers);
+
+                    if !out.is_truncated().unwrap_or_default() {
+                        return Ok(deleted);
+                    }
+                    key_marker = out.next_key_marker().map(str::to_owned);
+                    version_id_marker = out.next_version_id_marker().map(str::to_owned);
+                }
+            },
+        )
+        .await
+    }
+
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("restore_object", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                // the version must be a delete marker, deleting an object version loses it
+                let (mut key_marker, mut version_id_marker) = (None, None);
+                let (version, is_latest) = loop {
+                    let out = self
+                        .list_object_versions()
+                        .bucket(bucket)
+                        .prefix(key)
+                        .set_key_marker(key_marker)
+                        .set_version_id_marker(version_id_marker)
+                        .send()
+                        .await
+                        .map_err(Error::from_sdk)?;
+
+                    let marker = out
+                        .delete_markers()
+                        .iter()
+                        .find(|m| m.key() == Some(key) && marker_generation(m) == Some(generation));
+                    if let Some(marker) = marker {
+                        let version = marker.version_id().unwrap_or_default().to_owned();
+                        break (version, marker.is_latest().unwrap_or_default());
+                    }
+
+                    if !out.is_truncated().unwrap_or_default() {
+                        return Err(Error::NotFound(format!(
+                            "delete marker {generation} of {bucket}/{key}"
+                        ))
+                        .into());
+                    }
+                    key_marker = out.next_key_marker().map(str::to_owned);
+                    version_id_marker = out.next_version_id_marker().map(str::to_owned);
+                };
+
+                if !is_latest {
+                    return Err(Error::PreconditionFailed(format!(
+                        "{bucket}/{key} was written again"
+                    ))
+                    .into());
+                }
+
+                self.delete_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .version_id(version)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// S3 has no per-bucket permission test
+    /// an answer would need IAM policy simulation, which this crate does not depend on
+    async fn test_permissions(&self, _: &str, _: &[&str]) -> Result<Vec<String>, NimbusError> {
+        Err(Error::Unsupported("testing IAM permissions on S3 buckets".to_owned()).into())
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        with_context(
+            || object_context("bucket_is_public", S3_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let block = s3_public_access_block(self, bucket).await?;
+                let (ignore_acls, restrict) = (
+                    block.ignore_public_acls().unwrap_or(false),
+                    block.restrict_public_buckets().unwrap_or(false),
+                );
+                let object_acls = !ignore_acls && !s3_owner_enforced(self, bucket).await?;
+                let mut exposure = Exposure {
+                    blocked: ignore_acls && restrict,
+                    object_acls,
+                    ..Default::default()
+                };
+                if exposure.blocked {
+                    return Ok(exposure.verdict());
+                }
+
+                if !restrict {
+                    exposure.granted =
+                        match self.get_bucket_policy_status().bucket(bucket).send().await {
+                            Ok(res) => res
+                                .policy_status()
+                                .and_then(|s| s.is_public())
+                                .unwrap_or(false),
+                            Err(e) if e.code() == Some("NoSuchBucketPolicy") => false,
+                            Err(e) => return Err(Error::from_sdk(e).into()),
+                        };
+                }
+                if object_acls {
+                    let acl = self
+                        .get_bucket_acl()
+                        .bucket(bucket)
+                        .send()
+                        .await
+                        .map_err(Error::from_sdk)?;
+                    exposure.granted |= acl
+                        .grants()
+                        .iter()
+                        .filter_map(|g| g.grantee())
+                        .any(|g| Grantee::from_s3(g).is_public());
+                }
+
+                Ok(exposure.verdict())
+            },
+        )
+        .await
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        with_context(
+            || object_context("object_acls_apply", S3_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let block = s3_public_access_block(self, bucket).await?;
+                if block.ignore_public_acls().unwrap_or(false) {
+                    return Ok(false);
+                }
+
+                Ok(!s3_owner_enforced(self, bucket).await?)
+            },
+        )
+        .await
+    }
+
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        with_context(
+            || object_context("object_acl", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                let res = self
+                    .get_object_acl()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
 
-        tokio::fs::write(path.clone(), data)
-            .await
-            .map_err(Error::IO)?;
+                Ok(res
+                    .grants()
+                    .iter()
+                    .filter_map(|g| {
+                        let permission = g.permission().map_or("", |p| p.as_str());
+                        Some(AclEntry::new(Grantee::from_s3(g.grantee()?), permission))
+                    })
+                    .collect())
+            },
+        )
+        .await
+    }
 
-        Ok(path)
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        with_context(
+            || object_context("get_cors", S3_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+
+                match self.get_bucket_cors().bucket(bucket).send().await {
+                    Ok(res) => Ok(res.cors_rules().iter().map(CorsRule::from_s3).collect()),
+                    Err(e) if e.code() == Some("NoSuchCORSConfiguration") => Ok(vec![]),
+                    Err(e) => Err(Error::from_sdk(e).into()),
+                }
+            },
+        )
+        .await
     }
 
-    /// check if file type is valid
-    fn valid_file_type(&self, file: &[u8], expected: &str) -> Result<(), NimbusError> {
-        let file_type = infer::get(file)
-            .ok_or_else(|| Error::InvalidFileType("Failed to get file type".to_owned()))?;
+    /// S3 refuses an empty configuration, the rules are deleted instead
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        with_context(
+            || object_context("set_cors", S3_SCHEME, bucket, ""),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
+                validate_cors(&rules)?;
 
-        if file_type.extension() != expected {
-            return Err(Error::InvalidFileType(format!(
-                "File type is not valid. Expected: {}, got: {}",
-                expected,
-                file_type.extension()
-            ))
-            .into());
-        }
+                if rules.is_empty() {
+                    self.delete_bucket_cors()
+                        .bucket(bucket)
+                        .send()
+                        .await
+                        .map_err(Error::from_sdk)?;
+                    return Ok(());
+                }
+                let rules = rules
+                    .iter()
+                    .map(CorsRule::to_s3)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let config = aws_sdk_s3::types::CorsConfiguration::builder()
+                    .set_cors_rules(Some(rules))
+                    .build()
+                    .map_err(|e| Error::InvalidCors(e.to_string()))?;
+                self.put_bucket_cors()
+                    .bucket(bucket)
+                    .cors_configuration(config)
+                    .send()
+                    .await
+                    .map_err(Error::from_sdk)?;
 
-        Ok(())
+                Ok(())
+            },
+        )
+        .await
     }
-}
 
-#[cfg(feature = "gcp")]
-#[async_trait::async_trait]
-impl StorageHelper for Client {
-    async fn upload_from_bytes(
+    async fn signed_post_policy(
         &self,
         bucket: &str,
-        key: &str,
-        mime: Option<String>,
-        data: Vec<u8>,
-    ) -> Result<(), NimbusError> {
-        let up_type = UploadType::Multipart(Box::new(Object {
-            name: key.to_string(),
-            content_type: mime,
-            ..Default::default()
-        }));
-
-        let _ = self
-            .upload_object(
-                &UploadObjectRequest {
-                    bucket: bucket.to_string(),
-                    ..Default::default()
-                },
-                data,
-                &up_type,
-            )
-            .await
-            .map_err(Error::Storage)?;
+        key_prefix: &str,
+        max_size: ByteSize,
+        expires: Duration,
+        conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        with_context(
+            || object_context("signed_post_policy", S3_SCHEME, bucket, key_prefix),
+            async {
+                use aws_sdk_s3::config::ProvideCredentials;
 
-        Ok(())
-    }
+                validate_bucket_name(bucket, Provider::S3)?;
 
-    #[cfg(feature = "gcp")]
-    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
-        let a = self
-            .download_object(
-                &GetObjectRequest {
-                    bucket: bucket.to_owned(),
-                    object: key.to_owned(),
-                    ..Default::default()
-                },
-                &Range::default(),
-            )
-            .await
-            .map_err(Error::Storage)?;
+                let region = self
+                    .config()
+                    .region()
+                    .ok_or_else(|| Error::Other("No region configured".to_owned()))?;
+                // the client doesn't hand out its credentials, load them like `new_with_authenticator`
+                let defaults =
+                    aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+                let credentials = defaults
+                    .credentials_provider()
+                    .ok_or_else(|| Error::Other("No credentials configured".to_owned()))?
+                    .provide_credentials()
+                    .await
+                    .map_err(|e| Error::Other(format!("Failed to load credentials: {e}")))?;
 
-        Ok(a)
+                Ok(post_policy::s3_post_policy(
+                    bucket,
+                    key_prefix,
+                    max_size,
+                    expires,
+                    &conditions,
+                    &credentials,
+                    region.as_ref(),
+                    Utc::now(),
+                )?)
+            },
+        )
+        .await
     }
 
-    #[cfg(feature = "gcp")]
-    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
-        let _ = self
-            .delete_object(&DeleteObjectRequest {
-                bucket: bucket.to_owned(),
-                object: key.to_owned(),
-                ..Default::default()
-            })
-            .await
-            .map_err(Error::Storage)?;
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("signed_download_url", S3_SCHEME, bucket, key),
+            async {
+                use aws_sdk_s3::presigning::PresigningConfig;
 
-        Ok(())
-    }
-}
+                validate_bucket_name(bucket, Provider::S3)?;
+                validate_expiry(expires_in)?;
 
-#[cfg(feature = "aws")]
-#[async_trait::async_trait]
-impl StorageHelper for Client {
-    async fn new_with_authenticator() -> Self {
-        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-        Client::new(&config)
+                let config = PresigningConfig::expires_in(expires_in)
+                    .map_err(|e| Error::Other(format!("Invalid presigning config: {e}")))?;
+                let request = self
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .presigned(config)
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(request.uri().to_owned())
+            },
+        )
+        .await
     }
 
-    async fn upload_from_bytes(
+    async fn signed_upload_url(
         &self,
         bucket: &str,
         key: &str,
         mime: Option<String>,
-        data: Vec<u8>,
-    ) -> Result<(), NimbusError> {
-        let builder = self
-            .put_object()
-            .bucket(bucket)
-            .key(key)
-            .body(ByteStream::from(data))
-            .set_content_type(mime);
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        with_context(
+            || object_context("signed_upload_url", S3_SCHEME, bucket, key),
+            async {
+                use aws_sdk_s3::presigning::PresigningConfig;
 
-        if let Err(e) = builder.send().await {
-            return Err(NimbusError::from(Error::Storage(e.to_string())));
-        }
+                validate_bucket_name(bucket, Provider::S3)?;
+                validate_expiry(expires_in)?;
 
-        Ok(())
+                let config = PresigningConfig::expires_in(expires_in)
+                    .map_err(|e| Error::Other(format!("Invalid presigning config: {e}")))?;
+                // the content type is a signed header of the request
+                let request = self
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_content_type(mime)
+                    .presigned(config)
+                    .await
+                    .map_err(Error::from_sdk)?;
+
+                Ok(request.uri().to_owned())
+            },
+        )
+        .await
     }
 
-    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
-        let builder = self.get_object().bucket(bucket).key(key);
-
-        match builder.send().await {
-            Ok(mut d) => {
-                let mut res = vec![];
-                while let Ok(Some(bytes)) = d.body.try_next().await {
-                    if let Err(e) = res.write_all(&bytes) {
-                        return Err(NimbusError::from(Error::Storage(e.to_string())));
-                    }
-                }
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        with_context(
+            || object_context("start_resumable_upload", S3_SCHEME, bucket, key),
+            async {
+                validate_bucket_name(bucket, Provider::S3)?;
 
-                Ok(res)
-            }
-            Err(e) => Err(NimbusError::from(Error::Storage(e.to_string()))),
-        }
-    }
+                let r = self
+                    .create_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_content_type(mime)
+                    .checksum_algorithm(aws_sdk_s3::types::ChecksumAlgorithm::Crc32C)
+                    .send()
+                    .await;
 
-    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
-        let r = self.delete_object().bucket(bucket).key(key).send().await;
+                let upload_id = match r {
+                    Ok(out) => out.upload_id.ok_or_else(|| {
+                        Error::Storage("No upload id in CreateMultipartUploadOutput".to_owned())
+                    })?,
+                    Err(e) => return Err(NimbusError::from(Error::from_sdk(e))),
+                };
 
-        match r {
-            Ok(_) => Ok(()),
-            Err(e) => Err(NimbusError::from(Error::Storage(e.to_string()))),
-        }
+                Ok(ResumableUpload::new(resumable::Session::S3 {
+                    client: self.clone(),
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id,
+                    parts: vec![],
+                }))
+            },
+        )
+        .await
     }
 }
 
-#[cfg(feature = "gcp")]
+#[cfg(feature = "gcp-storage")]
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -269,6 +4006,22 @@ mod tests {
         storage.delete_file(&bucket, &key).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn error_context_test() {
+        let storage = Client::new(ClientConfig::default().anonymous());
+
+        let error = storage
+            .upload_from_bytes("Not_A_Bucket", "dir/key", None, vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(error.operation(), Some("upload_from_bytes"));
+        assert_eq!(
+            error.resource().as_deref(),
+            Some("gs://Not_A_Bucket/dir/key")
+        );
+        assert_eq!(error.code(), ErrorCode::InvalidInput);
+    }
+
     #[tokio::test]
     async fn upload_file_download_file_test() {
         let auth = ClientConfig::auth().await.unwrap();
@@ -306,6 +4059,303 @@ mod tests {
         tokio::fs::remove_file(path).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn resumable_upload_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = std::env::var("KEY").unwrap();
+
+        let first = vec![7u8; MIN_CHUNK_SIZE.as_usize()];
+        let last = b"tail".to_vec();
+
+        let mut upload = storage
+            .start_resumable_upload(&bucket, &key, None)
+            .await
+            .unwrap();
+        upload.upload_chunk(first.clone()).await.unwrap();
+        upload.upload_chunk(last.clone()).await.unwrap();
+        assert_eq!(upload.bytes_uploaded(), first.len() as u64);
+        upload.finish().await.unwrap();
+
+        let data = storage.download_to_bytes(&bucket, &key).await.unwrap();
+        assert_eq!(data, [first, last].concat());
+
+        storage.delete_file(&bucket, &key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cors_round_trip_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let before = storage.get_cors(&bucket).await.unwrap();
+
+        let rules = vec![
+            CorsRule::new()
+                .origin("https://app.example.com")
+                .method("put")
+                .method("GET")
+                .response_header("Content-Type")
+                .max_age(Duration::from_secs(3600)),
+            CorsRule::new().origin("*").method("HEAD"),
+        ];
+        storage.set_cors(&bucket, rules.clone()).await.unwrap();
+        let read = storage.get_cors(&bucket).await.unwrap();
+        assert_eq!(read.len(), rules.len());
+        for (read, rule) in read.iter().zip(&rules) {
+            assert!(read.same_as(rule), "{read:?} != {rule:?}");
+        }
+
+        let added = CorsRule::new()
+            .origin("https://admin.example.com")
+            .method("POST");
+        assert!(storage
+            .ensure_cors_contains(&bucket, added.clone())
+            .await
+            .unwrap());
+        assert!(!storage.ensure_cors_contains(&bucket, added).await.unwrap());
+        assert_eq!(storage.get_cors(&bucket).await.unwrap().len(), 3);
+
+        storage.set_cors(&bucket, vec![]).await.unwrap();
+        assert!(storage.get_cors(&bucket).await.unwrap().is_empty());
+        storage.set_cors(&bucket, before).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_permissions_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let wanted = ["storage.objects.get", "storage.objects.create"];
+
+        let held = storage.test_permissions(&bucket, &wanted).await.unwrap();
+        let missing = storage.missing_permissions(&bucket, &wanted).await.unwrap();
+        assert_eq!(held.len() + missing.len(), wanted.len());
+    }
+
+    #[tokio::test]
+    async fn content_addressed_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let data = b"Hello World from content".to_vec();
+
+        let key = storage
+            .put_content_addressed(&bucket, "cas", data.clone())
+            .await
+            .unwrap();
+        assert_eq!(key, content_key("cas", &data));
+        assert!(storage.object_exists(&bucket, &key).await.unwrap());
+
+        // second put is deduplicated, conditional create refuses to overwrite
+        let again = storage
+            .put_content_addressed(&bucket, "cas", data.clone())
+            .await
+            .unwrap();
+        assert_eq!(key, again);
+        assert!(!storage
+            .upload_if_absent(&bucket, &key, None, data.clone())
+            .await
+            .unwrap());
+
+        let data2 = storage
+            .get_content_addressed(&bucket, &key, true)
+            .await
+            .unwrap();
+        assert_eq!(data, data2);
+
+        storage.delete_file(&bucket, &key).await.unwrap();
+        assert!(!storage.object_exists(&bucket, &key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn objects_metadata_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = std::env::var("KEY").unwrap();
+        let missing = format!("{key}-missing");
+
+        let data = b"Hello World".to_vec();
+        storage
+            .upload_from_bytes(&bucket, &key, None, data.clone())
+            .await
+            .unwrap();
+
+        let outcome = storage
+            .objects_metadata(
+                &bucket,
+                &[key.clone(), missing.clone()],
+                2,
+                &OpContext::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.succeeded[&key].size, data.len() as u64);
+        assert_eq!(
+            outcome.succeeded[&key].crc32c.as_deref(),
+            Some(crc32c_base64(&data).as_str())
+        );
+        assert_eq!(outcome.not_found().collect::<Vec<_>>(), [&missing]);
+
+        storage.delete_file(&bucket, &key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lease_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = std::env::var("KEY").unwrap();
+        let ttl = Duration::from_secs(30);
+
+        let mut lease = Lease::acquire(&storage, &bucket, &key, ttl, "a")
+            .await
+            .unwrap();
+        let err = Lease::acquire(&storage, &bucket, &key, ttl, "b")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), ErrorCode::PreconditionFailed);
+
+        lease.renew().await.unwrap();
+        lease.release().await.unwrap();
+        assert!(!storage.object_exists(&bucket, &key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn object_tags_unsupported_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = std::env::var("KEY").unwrap();
+
+        let err = storage.get_object_tags(&bucket, &key).await.err().unwrap();
+        assert_eq!(err.code(), ErrorCode::Unsupported);
+    }
+
+    /// the bucket needs a soft delete policy
+    #[tokio::test]
+    async fn soft_delete_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = "nimbus-soft-delete-test";
+        storage
+            .upload_from_bytes(&bucket, key, None, b"data".to_vec())
+            .await
+            .unwrap();
+        storage.delete_file(&bucket, key).await.unwrap();
+        let deleted = storage
+            .list_soft_deleted(&bucket, Some(key.to_owned()), ListLimits::default())
+            .await
+            .unwrap();
+        let (_, generation) = *deleted.last().unwrap();
+
+        // a live object is never replaced
+        storage
+            .upload_from_bytes(&bucket, key, None, b"again".to_vec())
+            .await
+            .unwrap();
+        let err = storage
+            .restore_object(&bucket, key, generation)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::PreconditionFailed);
+
+        storage.delete_file(&bucket, key).await.unwrap();
+        storage
+            .restore_object(&bucket, key, generation)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_to_bytes(&bucket, key).await.unwrap(),
+            b"data"
+        );
+        storage.delete_file(&bucket, key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_with_encoding_test() {
+        use std::io::Write;
+
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = "nimbus-encoding-test";
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"Hello World").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let up_type = UploadType::Multipart(Box::new(Object {
+            name: key.to_owned(),
+            content_encoding: Some("gzip".to_owned()),
+            ..Default::default()
+        }));
+        storage
+            .upload_object(
+                &UploadObjectRequest {
+                    bucket: bucket.clone(),
+                    ..Default::default()
+                },
+                compressed.clone(),
+                &up_type,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .download_with_encoding(&bucket, key, false)
+                .await
+                .unwrap(),
+            compressed
+        );
+        assert_eq!(
+            storage
+                .download_with_encoding(&bucket, key, true)
+                .await
+                .unwrap(),
+            b"Hello World"
+        );
+        storage.delete_file(&bucket, key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_query_test() {
+        let auth = ClientConfig::auth().await.unwrap();
+        let storage = Client::new(auth);
+
+        let bucket = std::env::var("BUCKET").unwrap();
+        let key = std::env::var("KEY").unwrap();
+
+        storage
+            .upload_from_bytes(&bucket, &key, None, b"Hello World".to_vec())
+            .await
+            .unwrap();
+
+        let objects = storage
+            .list(&bucket)
+            .prefix(key.clone())
+            .page_size(1)
+            .collect()
+            .await
+            .unwrap();
+        assert!(objects.iter().any(|o| o.key == key));
+
+        storage.delete_file(&bucket, &key).await.unwrap();
+    }
+
     #[tokio::test]
     async fn valid_file_type_test() {
         let buf = [0xFF, 0xD8, 0xFF, 0xAA];
@@ -321,3 +4371,24 @@ mod tests {
         tokio::fs::remove_file(path).await.unwrap();
     }
 }
+
+#[cfg(feature = "aws-storage")]
+#[cfg(test)]
+mod s3_tests {
+    use super::*;
+
+    #[test]
+    fn s3_copy_source_test() {
+        let cases = [
+            ("plain.txt", "bucket/plain.txt"),
+            ("dir/sub dir/a b.txt", "bucket/dir/sub%20dir/a%20b.txt"),
+            ("a+b=c&d?e#f", "bucket/a%2Bb%3Dc%26d%3Fe%23f"),
+            ("100%.csv", "bucket/100%25.csv"),
+            ("été/ü.txt", "bucket/%C3%A9t%C3%A9/%C3%BC.txt"),
+            ("a~b_c-d.e", "bucket/a~b_c-d.e"),
+        ];
+        for (key, source) in cases {
+            assert_eq!(s3_copy_source("bucket", key), source, "{key}");
+        }
+    }
+}
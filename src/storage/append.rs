@@ -0,0 +1,644 @@
+//! Atomic appends to an object, for logs written by many writers, see [`StorageHelper::append_bytes`]
+//!
+//! No provider appends in place, each append writes a new version of the object made of the current one
+//! and the new data, only if the current one is still the latest. A writer losing the race to another
+//! one starts over on the newer version, so records are never lost nor written twice.
+//!
+//! - GCS composes the object with a temporary object holding the new data, nothing is downloaded
+//! - S3 copies the object into a multipart upload with the new data as the last part, once it is large
+//!   enough to be a part ([`MIN_CHUNK_SIZE`]); smaller objects are downloaded and written again
+
+use serde::Serialize;
+
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::client::Client as GcsClient;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::objects::compose::{ComposeObjectRequest, ComposingTargets};
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::objects::{Object, SourceObjects};
+
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::error::ProvideErrorMetadata;
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::primitives::ByteStream;
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::Client as S3Client;
+
+#[cfg(feature = "aws-storage")]
+use super::MIN_CHUNK_SIZE;
+use super::{Error, Precondition, StorageHelper, UpdateOptions};
+use crate::redact::json_error;
+use crate::retry::{Backoff, ExponentialFullJitter};
+use crate::{ErrorCode, NimbusError};
+
+/// Content type of the objects created by [`StorageHelper::append_ndjson`]
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Largest range of an object S3 copies into a single part
+#[cfg(feature = "aws-storage")]
+const MAX_COPY_PART: u64 = 5 * 1024 * 1024 * 1024;
+
+/// How an append was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendPath {
+    /// there was no object yet, it was created with the data
+    Created,
+    /// the object was composed with the data (GCS)
+    Compose,
+    /// the object was copied into a multipart upload, the data as its last part (S3)
+    MultipartCopy,
+    /// the object was downloaded and written again with the data
+    Rewrite,
+}
+
+/// Object written by [`StorageHelper::append_bytes`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendOutcome {
+    /// size of the object after the append
+    pub size: u64,
+    /// version of the object after the append
+    pub version: String,
+    pub path: AppendPath,
+    /// attempts run again because another writer appended in between
+    pub retries: u32,
+}
+
+impl AppendOutcome {
+    /// whether the append went another way than the native one of the provider: S3 can't compose
+    pub fn used_fallback(&self) -> bool {
+        matches!(self.path, AppendPath::MultipartCopy | AppendPath::Rewrite)
+    }
+}
+
+/// `records` as NDJSON, one compact JSON document per line
+pub fn to_ndjson<T: Serialize>(records: &[T]) -> Result<Vec<u8>, NimbusError> {
+    let mut data = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut data, record).map_err(|e| Error::InvalidJson(json_error(&e)))?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+/// attempts of an append, waiting after each conflict
+pub(crate) struct Conflicts {
+    backoff: ExponentialFullJitter,
+    retries: u32,
+    resource: String,
+}
+
+impl Conflicts {
+    pub fn new(options: &UpdateOptions, bucket: &str, key: &str) -> Self {
+        Self {
+            backoff: ExponentialFullJitter::new(options.base_delay, options.max_delay)
+                .max_attempts(options.max_attempts.max(1)),
+            retries: 0,
+            resource: format!("{bucket}/{key}"),
+        }
+    }
+
+    /// `outcome` with the retries it took
+    pub fn done(&self, outcome: AppendOutcome) -> AppendOutcome {
+        AppendOutcome {
+            retries: self.retries,
+            ..outcome
+        }
+    }
+
+    /// wait before the next attempt, fails with `error` if it isn't a conflict,
+    /// with [`Error::UpdateConflict`] once there are no attempts left
+    pub async fn wait(&mut self, error: NimbusError) -> Result<(), NimbusError> {
+        if error.code() != ErrorCode::PreconditionFailed {
+            return Err(error);
+        }
+        self.retries += 1;
+        match self.backoff.next_delay(self.retries, &error) {
+            Some(delay) => {
+                tokio::time::sleep(delay).await;
+                Ok(())
+            }
+            None => Err(Error::UpdateConflict {
+                key: self.resource.clone(),
+                attempts: self.retries,
+            }
+            .into()),
+        }
+    }
+}
+
+/// append by downloading the object and writing it again if it didn't change, on any provider
+#[cfg(any(test, feature = "testing"))]
+pub(crate) async fn rewrite<S: StorageHelper + Sync + ?Sized>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    data: Vec<u8>,
+    options: &UpdateOptions,
+) -> Result<AppendOutcome, NimbusError> {
+    let mut conflicts = Conflicts::new(options, bucket, key);
+    loop {
+        match rewrite_once(storage, bucket, key, mime.clone(), data.clone()).await {
+            Ok(outcome) => return Ok(conflicts.done(outcome)),
+            Err(e) => conflicts.wait(e).await?,
+        }
+    }
+}
+
+/// a single attempt of [`rewrite`], fails with [`ErrorCode::PreconditionFailed`] on a conflict
+#[cfg(any(test, feature = "testing", feature = "aws-storage"))]
+async fn rewrite_once<S: StorageHelper + Sync + ?Sized>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    data: Vec<u8>,
+) -> Result<AppendOutcome, NimbusError> {
+    let (content, precondition, path) = match storage.download_versioned(bucket, key).await {
+        Ok((mut current, version)) => {
+            current.extend_from_slice(&data);
+            (
+                current,
+                Precondition::VersionMatches(version),
+                AppendPath::Rewrite,
+            )
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            (data, Precondition::DoesNotExist, AppendPath::Created)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let size = content.len() as u64;
+    let version = storage
+        .upload_conditional(bucket, key, mime, content, &precondition)
+        .await?;
+    Ok(AppendOutcome {
+        size,
+        version,
+        path,
+        retries: 0,
+    })
+}
+
+/// name of the temporary object holding the data of an append to `key`
+#[cfg(feature = "gcp-storage")]
+fn temp_key(key: &str) -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write(key.as_bytes());
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{key}.append-{:016x}", hasher.finish())
+}
+
+/// append on GCS by composing the object with a temporary object, deleted afterwards
+#[cfg(feature = "gcp-storage")]
+pub(crate) async fn gcs(
+    client: &GcsClient,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    data: Vec<u8>,
+    options: &UpdateOptions,
+) -> Result<AppendOutcome, NimbusError> {
+    let mut conflicts = Conflicts::new(options, bucket, key);
+    // uploaded once the object is known to exist, for every attempt
+    let mut temp: Option<(String, i64)> = None;
+
+    let result = loop {
+        let attempt = gcs_once(client, bucket, key, mime.clone(), &data, &mut temp).await;
+        match attempt {
+            Ok(outcome) => break Ok(conflicts.done(outcome)),
+            Err(e) => {
+                if let Err(e) = conflicts.wait(e).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+
+    if let Some((temp, _)) = temp {
+        // a leftover temporary object is harmless, the append doesn't fail for it
+        let _ = client
+            .delete_object(&DeleteObjectRequest {
+                bucket: bucket.to_owned(),
+                object: temp,
+                ..Default::default()
+            })
+            .await;
+    }
+    result
+}
+
+#[cfg(feature = "gcp-storage")]
+async fn gcs_once(
+    client: &GcsClient,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    data: &[u8],
+    temp: &mut Option<(String, i64)>,
+) -> Result<AppendOutcome, NimbusError> {
+    let current = client
+        .get_object(&GetObjectRequest {
+            bucket: bucket.to_owned(),
+            object: key.to_owned(),
+            ..Default::default()
+        })
+        .await;
+    let current = match current.map_err(Error::Storage) {
+        Ok(current) => current,
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            let version = client
+                .upload_conditional(
+                    bucket,
+                    key,
+                    mime,
+                    data.to_vec(),
+                    &Precondition::DoesNotExist,
+                )
+                .await?;
+            return Ok(AppendOutcome {
+                size: data.len() as u64,
+                version,
+                path: AppendPath::Created,
+                retries: 0,
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let (temp_name, temp_generation) = match temp {
+        Some(temp) => temp.clone(),
+        None => {
+            let name = temp_key(key);
+            let version = client
+                .upload_conditional(
+                    bucket,
+                    &name,
+                    None,
+                    data.to_vec(),
+                    &Precondition::DoesNotExist,
+                )
+                .await?;
+            let uploaded = (name, super::generation(&version)?);
+            *temp = Some(uploaded.clone());
+            uploaded
+        }
+    };
+
+    let composed = client
+        .compose_object(&ComposeObjectRequest {
+            bucket: bucket.to_owned(),
+            destination_object: key.to_owned(),
+            composing_targets: ComposingTargets {
+                destination: Some(Object {
+                    name: key.to_owned(),
+                    bucket: bucket.to_owned(),
+                    content_type: current.content_type.clone(),
+                    ..Default::default()
+                }),
+                source_objects: vec![
+                    SourceObjects {
+                        name: key.to_owned(),
+                        generation: Some(current.generation),
+                        object_preconditions: None,
+                    },
+                    SourceObjects {
+                        name: temp_name,
+                        generation: Some(temp_generation),
+                        object_preconditions: None,
+                    },
+                ],
+            },
+            if_generation_match: Some(current.generation),
+            ..Default::default()
+        })
+        .await
+        .map_err(Error::Storage)?;
+
+    Ok(AppendOutcome {
+        size: composed.size.max(0) as u64,
+        version: composed.generation.to_string(),
+        path: AppendPath::Compose,
+        retries: 0,
+    })
+}
+
+/// append on S3, by a multipart copy of objects large enough to be a part, by a rewrite otherwise
+#[cfg(feature = "aws-storage")]
+pub(crate) async fn s3(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    data: Vec<u8>,
+    options: &UpdateOptions,
+) -> Result<AppendOutcome, NimbusError> {
+    let mut conflicts = Conflicts::new(options, bucket, key);
+    loop {
+        match s3_once(client, bucket, key, mime.clone(), data.clone()).await {
+            Ok(outcome) => return Ok(conflicts.done(outcome)),
+            Err(e) => conflicts.wait(e).await?,
+        }
+    }
+}
+
+#[cfg(feature = "aws-storage")]
+async fn s3_once(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    data: Vec<u8>,
+) -> Result<AppendOutcome, NimbusError> {
+    let head = match client.head_object().bucket(bucket).key(key).send().await {
+        Ok(head) => head,
+        Err(e) => {
+            let e = Error::from_sdk(e);
+            if e.code() != ErrorCode::NotFound {
+                return Err(e.into());
+            }
+            // created, or a rewrite if another writer created it in between
+            return rewrite_once(client, bucket, key, mime, data).await;
+        }
+    };
+
+    let size = head.content_length().unwrap_or_default().max(0) as u64;
+    if size < MIN_CHUNK_SIZE.bytes() {
+        let mime = head.content_type().map(str::to_owned).or(mime);
+        return rewrite_once(client, bucket, key, mime, data).await;
+    }
+    let etag = head.e_tag().unwrap_or_default().to_owned();
+
+    let upload = client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .set_content_type(head.content_type().map(str::to_owned))
+        .send()
+        .await
+        .map_err(Error::from_sdk)?;
+    let upload_id = upload.upload_id().unwrap_or_default().to_owned();
+
+    let appended = s3_multipart_copy(client, bucket, key, &upload_id, &etag, size, data).await;
+    if appended.is_err() {
+        // parts left behind are billed until the upload is aborted
+        let _ = client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .send()
+            .await;
+    }
+    appended
+}
+
+/// copy the `size` bytes of the object with `etag` as the first parts of the upload, `data` as the last one
+#[cfg(feature = "aws-storage")]
+async fn s3_multipart_copy(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    etag: &str,
+    size: u64,
+    data: Vec<u8>,
+) -> Result<AppendOutcome, NimbusError> {
+    let appended = data.len() as u64;
+    let mut parts = Vec::new();
+
+    let mut start = 0;
+    while start < size {
+        let end = (start + MAX_COPY_PART).min(size) - 1;
+        let part_number = parts.len() as i32 + 1;
+        let out = client
+            .upload_part_copy()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .copy_source(super::s3_copy_source(bucket, key))
+            .copy_source_range(format!("bytes={start}-{end}"))
+            .copy_source_if_match(etag)
+            .send()
+            .await
+            .map_err(Error::from_sdk)?;
+        parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(
+                    out.copy_part_result()
+                        .and_then(|r| r.e_tag())
+                        .map(str::to_owned),
+                )
+                .build(),
+        );
+        start = end + 1;
+    }
+
+    let part_number = parts.len() as i32 + 1;
+    let out = client
+        .upload_part()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .body(ByteStream::from(data))
+        .send()
+        .await
+        .map_err(Error::from_sdk)?;
+    parts.push(
+        CompletedPart::builder()
+            .part_number(part_number)
+            .set_e_tag(out.e_tag().map(str::to_owned))
+            .build(),
+    );
+
+    // the object may have been replaced since it was copied
+    let if_match = etag.to_owned();
+    let completed = client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .customize()
+        .mutate_request(move |req| {
+            req.headers_mut().insert("If-Match", if_match.clone());
+        })
+        .send()
+        .await;
+
+    match completed {
+        Ok(out) => Ok(AppendOutcome {
+            size: size + appended,
+            version: out.e_tag().unwrap_or_default().to_owned(),
+            path: AppendPath::MultipartCopy,
+            retries: 0,
+        }),
+        // a concurrent completion on the same key
+        Err(e) if e.code() == Some("ConditionalRequestConflict") => {
+            Err(Error::PreconditionFailed(format!("{bucket}/{key}")).into())
+        }
+        Err(e) => Err(Error::from_sdk(e).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        writer: u32,
+        seq: u32,
+    }
+
+    #[tokio::test]
+    async fn append_ndjson_test() {
+        let storage = MemoryStorage::new();
+        let first = [Record { writer: 0, seq: 0 }];
+
+        let created = storage
+            .append_ndjson("logs", "day.ndjson", &first, UpdateOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(created.path, AppendPath::Created);
+        assert!(!created.used_fallback());
+
+        let records = [Record { writer: 0, seq: 1 }, Record { writer: 0, seq: 2 }];
+        let appended = storage
+            .append_ndjson("logs", "day.ndjson", &records, UpdateOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(appended.path, AppendPath::Rewrite);
+        assert!(appended.used_fallback());
+
+        let data = storage
+            .download_to_bytes("logs", "day.ndjson")
+            .await
+            .unwrap();
+        assert_eq!(appended.size, data.len() as u64);
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "{\"writer\":0,\"seq\":0}\n{\"writer\":0,\"seq\":1}\n{\"writer\":0,\"seq\":2}\n"
+        );
+        let meta = storage.object_metadata("logs", "day.ndjson").await.unwrap();
+        assert_eq!(meta.content_type.as_deref(), Some(NDJSON_CONTENT_TYPE));
+        assert_eq!(meta.version, Some(appended.version));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_append_test() {
+        let storage = Arc::new(MemoryStorage::new());
+        // widen the window between the download and the upload, checked once the latency elapsed
+        storage.mock_stats().set_fault(
+            "upload_conditional",
+            Fault::new().latency(Latency::Uniform {
+                min: Duration::from_millis(1),
+                max: Duration::from_millis(10),
+            }),
+        );
+
+        let writers = (0..8).map(|writer| {
+            let storage = Arc::clone(&storage);
+            tokio::spawn(async move {
+                let mut retries = 0;
+                for seq in 0..10 {
+                    let outcome = storage
+                        .append_ndjson(
+                            "logs",
+                            "day.ndjson",
+                            &[Record { writer, seq }],
+                            UpdateOptions::new().max_attempts(1000),
+                        )
+                        .await
+                        .unwrap();
+                    retries += outcome.retries;
+                }
+                retries
+            })
+        });
+        let mut retries = 0;
+        for writer in writers.collect::<Vec<_>>() {
+            retries += writer.await.unwrap();
+        }
+        assert!(retries > 0, "the writers never conflicted");
+
+        let data = storage
+            .download_to_bytes("logs", "day.ndjson")
+            .await
+            .unwrap();
+        let records: Vec<Record> = data
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 80);
+        // the records of each writer in its order, none lost nor duplicated
+        for writer in 0..8 {
+            let seqs: Vec<u32> = records
+                .iter()
+                .filter(|r| r.writer == writer)
+                .map(|r| r.seq)
+                .collect();
+            assert_eq!(seqs, (0..10).collect::<Vec<_>>(), "writer {writer}");
+        }
+    }
+
+    #[tokio::test]
+    async fn append_conflict_test() {
+        let storage = MemoryStorage::new();
+        storage.mock_stats().set_fault(
+            "upload_conditional",
+            Fault::new().fail(1.0, ErrorCode::PreconditionFailed),
+        );
+
+        let options = UpdateOptions::new()
+            .max_attempts(3)
+            .base_delay(Duration::ZERO)
+            .max_delay(Duration::ZERO);
+        let error = storage
+            .append_ndjson(
+                "logs",
+                "day.ndjson",
+                &[Record { writer: 0, seq: 0 }],
+                options,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error.without_context(),
+                NimbusError::StorageClient(Error::UpdateConflict { attempts: 3, .. })
+            ),
+            "{error:?}"
+        );
+        assert_eq!(storage.stats().calls("upload_conditional"), 3);
+    }
+}
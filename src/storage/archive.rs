@@ -0,0 +1,670 @@
+//! Moving the old objects under a prefix to a colder storage class
+//!
+//! [`StorageHelper::archive_older_than`] streams the listing of the prefix, never holding more than a page
+//! and the changes in flight, and changes the class of every object last updated at least `age` ago.
+//! Objects already in the target class, or matching an exclusion, are left alone. The number of objects
+//! changed by a run can be capped, so a scheduled job moves a bounded slice each time and the next run
+//! picks up where the listing shows objects left to move.
+//!
+//! ```ignore
+//! let report = storage
+//!     .archive_older_than(
+//!         "raw",
+//!         "logs/",
+//!         Duration::from_secs(90 * 24 * 60 * 60),
+//!         StorageClass::Archive,
+//!         ArchiveOptions::new().exclude("logs/**/keep-*").max_objects(10_000),
+//!     )
+//!     .await?;
+//! println!("{report}");
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::{ObjectMeta, StorageHelper};
+use crate::report::{self, Report};
+use crate::retry::{self, ExponentialFullJitter};
+use crate::{BatchError, ErrorCode, NimbusError};
+
+/// Class changes in flight in [`StorageHelper::archive_older_than`] unless set with [`ArchiveOptions::concurrency`]
+pub const DEFAULT_ARCHIVE_CONCURRENCY: usize = 16;
+
+/// Attempts of each class change unless set with [`ArchiveOptions::max_attempts`]
+pub const DEFAULT_ARCHIVE_ATTEMPTS: u32 = 5;
+
+/// Storage class of an object, from the most to the least frequently read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum StorageClass {
+    /// `STANDARD` on both providers
+    Standard,
+    /// `NEARLINE` on GCS, `STANDARD_IA` on S3
+    InfrequentAccess,
+    /// `COLDLINE` on GCS, `GLACIER_IR` on S3
+    Cold,
+    /// `ARCHIVE` on GCS, `GLACIER` on S3
+    Archive,
+    /// `ARCHIVE` on GCS, which has nothing colder, `DEEP_ARCHIVE` on S3
+    DeepArchive,
+}
+
+impl StorageClass {
+    pub fn gcs_name(&self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::InfrequentAccess => "NEARLINE",
+            StorageClass::Cold => "COLDLINE",
+            StorageClass::Archive | StorageClass::DeepArchive => "ARCHIVE",
+        }
+    }
+
+    pub fn s3_name(&self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::InfrequentAccess => "STANDARD_IA",
+            StorageClass::Cold => "GLACIER_IR",
+            StorageClass::Archive => "GLACIER",
+            StorageClass::DeepArchive => "DEEP_ARCHIVE",
+        }
+    }
+
+    /// whether `name`, as a provider names a class, is this class on that provider
+    pub fn is_named(&self, name: &str) -> bool {
+        name.eq_ignore_ascii_case(self.gcs_name()) || name.eq_ignore_ascii_case(self.s3_name())
+    }
+
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn to_s3(self) -> aws_sdk_s3::types::StorageClass {
+        aws_sdk_s3::types::StorageClass::from(self.s3_name())
+    }
+}
+
+/// whether `key` matches the glob `pattern`: `*` is any run of characters but `/`, `**` any run
+/// of characters, `?` a character but `/`
+pub fn glob_matches(pattern: &str, key: &str) -> bool {
+    fn matches(pattern: &[u8], key: &[u8]) -> bool {
+        match pattern {
+            [] => key.is_empty(),
+            [b'*', b'*', rest @ ..] => (0..=key.len()).any(|skip| matches(rest, &key[skip..])),
+            [b'*', rest @ ..] => {
+                let segment = key.iter().position(|b| *b == b'/').unwrap_or(key.len());
+                (0..=segment).any(|skip| matches(rest, &key[skip..]))
+            }
+            [b'?', rest @ ..] => matches!(key, [c, ..] if *c != b'/') && matches(rest, &key[1..]),
+            [p, rest @ ..] => key.first() == Some(p) && matches(rest, &key[1..]),
+        }
+    }
+    matches(pattern.as_bytes(), key.as_bytes())
+}
+
+/// Options of [`StorageHelper::archive_older_than`]
+///
+/// Class changes are retried when the error is retryable, with an exponential backoff from `base_delay`
+/// to `max_delay` that waits at least as long as the provider asks when rate limited.
+/// After a rate limited change the concurrency is halved, it grows back by one per change that wasn't.
+#[derive(Debug, Clone)]
+pub struct ArchiveOptions {
+    pub concurrency: usize,
+    /// attempts of each class change, the first one included
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// report what would change without changing anything
+    pub dry_run: bool,
+    /// globs of keys left alone, see [`glob_matches`]
+    pub exclude: Vec<String>,
+    /// objects changed, or failing to, by a run at most
+    pub max_objects: Option<u64>,
+    /// time the age of objects is measured from, now by default
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_ARCHIVE_CONCURRENCY,
+            max_attempts: DEFAULT_ARCHIVE_ATTEMPTS,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            dry_run: false,
+            exclude: vec![],
+            max_objects: None,
+            as_of: None,
+        }
+    }
+}
+
+impl ArchiveOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn retry_delays(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// leave the keys matching `pattern` alone, e.g. `logs/**/*.keep`
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    pub fn max_objects(mut self, max_objects: u64) -> Self {
+        self.max_objects = Some(max_objects);
+        self
+    }
+
+    /// measure ages from `as_of` rather than now, e.g. to reproduce a run
+    pub fn as_of(mut self, as_of: DateTime<Utc>) -> Self {
+        self.as_of = Some(as_of);
+        self
+    }
+
+    fn backoff(&self) -> ExponentialFullJitter {
+        ExponentialFullJitter::new(self.base_delay, self.max_delay)
+            .max_attempts(self.max_attempts.max(1))
+    }
+}
+
+/// Outcome of [`StorageHelper::archive_older_than`]
+///
+/// The failed keys form a manifest, see [`ArchiveReport::retry_manifest`]. Running the archival again
+/// retries them as well, as they are still old and in their class.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ArchiveReport {
+    /// objects updated at or before it were old enough
+    pub cutoff: DateTime<Utc>,
+    pub dry_run: bool,
+    /// objects moved to the target class, or that would be in a dry run
+    pub transitioned: u64,
+    pub transitioned_bytes: u64,
+    /// updated after the cutoff, or at an unknown time
+    pub recent: u64,
+    pub excluded: u64,
+    pub already_in_class: u64,
+    /// class changes rate limited by the provider, and retried
+    pub throttled: u64,
+    /// the run stopped at [`ArchiveOptions::max_objects`] with objects left to move
+    pub capped: bool,
+    pub failed: Vec<BatchError<String>>,
+}
+
+impl ArchiveReport {
+    /// the keys that failed, to retry
+    pub fn retry_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.failed.iter().map(|e| e.key.clone()).collect();
+        keys.sort();
+        keys
+    }
+
+    /// the failed keys as newline delimited JSON, one `{"key", "code", "error"}` per line in key order
+    #[cfg(feature = "serde")]
+    pub fn retry_manifest(&self) -> String {
+        let mut failed: Vec<_> = self.failed.iter().collect();
+        failed.sort_by(|a, b| a.key.cmp(&b.key));
+        failed
+            .iter()
+            .map(|e| serde_json::to_string(e).expect("a batch error is always serializable") + "\n")
+            .collect()
+    }
+}
+
+impl Report for ArchiveReport {
+    fn summary_line(&self) -> String {
+        format!(
+            "archive{}: {} transitioned ({} bytes), {} recent, {} excluded, {} already archived, {} failed{}",
+            if self.dry_run { " (dry run)" } else { "" },
+            self.transitioned,
+            self.transitioned_bytes,
+            self.recent,
+            self.excluded,
+            self.already_in_class,
+            self.failed.len(),
+            if self.capped { ", capped" } else { "" },
+        )
+    }
+
+    fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for ArchiveReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())?;
+        let mut failed: Vec<_> = self.failed.iter().collect();
+        failed.sort_by(|a, b| a.key.cmp(&b.key));
+        report::write_section(
+            f,
+            "failed",
+            failed.iter().map(|e| format!("{}: {}", e.key, e.error)),
+        )
+    }
+}
+
+/// what to do with a listed object
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Recent,
+    Excluded,
+    AlreadyInClass,
+    Transition,
+}
+
+fn verdict(
+    meta: &ObjectMeta,
+    cutoff: DateTime<Utc>,
+    target: StorageClass,
+    options: &ArchiveOptions,
+) -> Verdict {
+    if meta.updated.is_none_or(|updated| updated > cutoff) {
+        return Verdict::Recent;
+    }
+    if options.exclude.iter().any(|p| glob_matches(p, &meta.key)) {
+        return Verdict::Excluded;
+    }
+    match &meta.storage_class {
+        Some(class) if target.is_named(class) => Verdict::AlreadyInClass,
+        _ => Verdict::Transition,
+    }
+}
+
+/// class change of an object, once done
+struct Transitioned {
+    key: String,
+    size: u64,
+    /// whether a change was rate limited while this one ran
+    throttled: bool,
+    result: Result<(), NimbusError>,
+}
+
+impl Transitioned {
+    /// count it in `report`, returns the concurrency to go on with
+    fn record(self, report: &mut ArchiveReport, concurrency: usize, max: usize) -> usize {
+        match self.result {
+            Ok(()) => {
+                report.transitioned += 1;
+                report.transitioned_bytes += self.size;
+            }
+            Err(error) => report.failed.push(BatchError {
+                key: self.key,
+                error,
+            }),
+        }
+        match self.throttled {
+            true => (concurrency / 2).max(1),
+            false => (concurrency + 1).min(max),
+        }
+    }
+}
+
+async fn transition<S>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    target: StorageClass,
+    options: &ArchiveOptions,
+    throttled: &AtomicU64,
+) -> Result<(), NimbusError>
+where
+    S: StorageHelper + Sync,
+{
+    retry::retry(&mut options.backoff(), || async {
+        let changed = storage.set_storage_class(bucket, key, target).await;
+        if matches!(&changed, Err(e) if e.code() == ErrorCode::RateLimited) {
+            throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        changed
+    })
+    .await
+}
+
+pub(crate) async fn archive_older_than<S>(
+    storage: &S,
+    bucket: &str,
+    prefix: &str,
+    age: Duration,
+    target: StorageClass,
+    options: ArchiveOptions,
+) -> Result<ArchiveReport, NimbusError>
+where
+    S: StorageHelper + Sync,
+{
+    let options = &options;
+    let now = options.as_of.unwrap_or_else(Utc::now);
+    let mut report = ArchiveReport {
+        // an age beyond the range of timestamps is older than anything
+        cutoff: chrono::Duration::from_std(age)
+            .ok()
+            .and_then(|age| now.checked_sub_signed(age))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC),
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+
+    let throttled = &AtomicU64::new(0);
+    let max_concurrency = options.concurrency.max(1);
+    let mut concurrency = max_concurrency;
+    let mut started = 0;
+    let mut in_flight = FuturesUnordered::new();
+    let mut listing = std::pin::pin!(storage.list(bucket).prefix(prefix).stream());
+
+    while let Some(meta) = listing.next().await {
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(e) => {
+                // the changes started are let finish, the next run lists them in their new class
+                while in_flight.next().await.is_some() {}
+                return Err(e);
+            }
+        };
+        match verdict(&meta, report.cutoff, target, options) {
+            Verdict::Recent => report.recent += 1,
+            Verdict::Excluded => report.excluded += 1,
+            Verdict::AlreadyInClass => report.already_in_class += 1,
+            Verdict::Transition => {
+                if options.max_objects.is_some_and(|max| started >= max) {
+                    report.capped = true;
+                    break;
+                }
+                started += 1;
+                if options.dry_run {
+                    report.transitioned += 1;
+                    report.transitioned_bytes += meta.size;
+                    continue;
+                }
+
+                while in_flight.len() >= concurrency {
+                    let Some(outcome): Option<Transitioned> = in_flight.next().await else {
+                        break;
+                    };
+                    concurrency = outcome.record(&mut report, concurrency, max_concurrency);
+                }
+                let throttled_before = throttled.load(Ordering::Relaxed);
+                in_flight.push(async move {
+                    let result =
+                        transition(storage, bucket, &meta.key, target, options, throttled).await;
+                    Transitioned {
+                        key: meta.key,
+                        size: meta.size,
+                        throttled: throttled.load(Ordering::Relaxed) > throttled_before,
+                        result,
+                    }
+                });
+            }
+        }
+    }
+
+    while let Some(outcome) = in_flight.next().await {
+        concurrency = outcome.record(&mut report, concurrency, max_concurrency);
+    }
+
+    report.throttled = throttled.load(Ordering::Relaxed);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, MemoryStorage};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn glob_matches_test() {
+        for (pattern, key, expected) in [
+            ("logs/*.gz", "logs/a.gz", true),
+            ("logs/*.gz", "logs/2024/a.gz", false),
+            ("logs/**.gz", "logs/2024/a.gz", true),
+            ("logs/**/keep-*", "logs/2024/03/keep-1", true),
+            ("logs/**/keep-*", "logs/keep-1", false),
+            ("**/keep-*", "logs/keep-1", true),
+            ("logs/?.gz", "logs/a.gz", true),
+            ("logs/?.gz", "logs/ab.gz", false),
+            ("logs?a.gz", "logs/a.gz", false),
+            ("*", "", true),
+            ("*", "a/b", false),
+            ("**", "a/b", true),
+            ("logs/a.gz", "logs/a.gz", true),
+            ("logs/a.gz", "logs/a.gzip", false),
+            ("", "", true),
+        ] {
+            assert_eq!(glob_matches(pattern, key), expected, "{pattern} {key}");
+        }
+    }
+
+    #[test]
+    fn storage_class_test() {
+        assert!(StorageClass::Archive.is_named("ARCHIVE"));
+        assert!(StorageClass::Archive.is_named("GLACIER"));
+        assert!(StorageClass::DeepArchive.is_named("ARCHIVE"));
+        assert!(StorageClass::DeepArchive.is_named("deep_archive"));
+        assert!(!StorageClass::DeepArchive.is_named("GLACIER"));
+        assert!(StorageClass::InfrequentAccess.is_named("STANDARD_IA"));
+        assert!(!StorageClass::Standard.is_named("STANDARD_IA"));
+    }
+
+    /// the storage with `keys` and the latest time one was updated
+    async fn bucket(keys: &[&str]) -> (MemoryStorage, DateTime<Utc>) {
+        let storage = MemoryStorage::new();
+        for key in keys {
+            storage
+                .upload_from_bytes("raw", key, None, vec![0; 10])
+                .await
+                .unwrap();
+        }
+        let latest = storage
+            .list("raw")
+            .collect()
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|o| o.updated)
+            .max()
+            .unwrap_or_else(Utc::now);
+        (storage, latest)
+    }
+
+    async fn classes(storage: &MemoryStorage) -> Vec<(String, Option<String>)> {
+        let listing = storage.list("raw").collect().await.unwrap();
+        listing
+            .into_iter()
+            .map(|o| (o.key, o.storage_class))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn age_boundary_test() {
+        let (storage, updated) = bucket(&["logs/a"]).await;
+        let run = |as_of: DateTime<Utc>| {
+            storage.archive_older_than(
+                "raw",
+                "logs/",
+                30 * DAY,
+                StorageClass::Archive,
+                ArchiveOptions::new().as_of(as_of),
+            )
+        };
+        let thirty_days = updated + chrono::Duration::days(30);
+
+        // one nanosecond short of 30 days
+        let report = run(thirty_days - chrono::Duration::nanoseconds(1))
+            .await
+            .unwrap();
+        assert_eq!((report.transitioned, report.recent), (0, 1), "{report}");
+        assert_eq!(classes(&storage).await, [("logs/a".to_owned(), None)]);
+
+        // exactly 30 days, written with another offset: the comparison is of instants
+        let offset = chrono::FixedOffset::west_opt(7 * 3600).unwrap();
+        let exactly =
+            DateTime::parse_from_rfc3339(&thirty_days.with_timezone(&offset).to_rfc3339())
+                .unwrap()
+                .with_timezone(&Utc);
+        let report = run(exactly).await.unwrap();
+        assert_eq!(report.cutoff, updated);
+        assert_eq!((report.transitioned, report.recent), (1, 0), "{report}");
+        assert_eq!(report.transitioned_bytes, 10);
+        assert_eq!(
+            classes(&storage).await,
+            [("logs/a".to_owned(), Some("ARCHIVE".to_owned()))]
+        );
+
+        // a second run has nothing left to do
+        let report = run(exactly + DAY).await.unwrap();
+        assert_eq!((report.transitioned, report.already_in_class), (0, 1));
+        assert_eq!(storage.stats().calls("set_storage_class"), 1);
+    }
+
+    #[tokio::test]
+    async fn dry_run_test() {
+        let keys = ["logs/a", "logs/keep/b", "logs/c", "other/d"];
+        let (storage, latest) = bucket(&keys).await;
+        let options = ArchiveOptions::new()
+            .exclude("logs/keep/**")
+            .as_of(latest + chrono::Duration::days(2));
+
+        let dry = storage
+            .archive_older_than(
+                "raw",
+                "logs/",
+                DAY,
+                StorageClass::Cold,
+                options.clone().dry_run(true),
+            )
+            .await
+            .unwrap();
+        assert!(dry.dry_run);
+        assert_eq!(storage.stats().calls("set_storage_class"), 0);
+        assert!(classes(&storage).await.iter().all(|(_, c)| c.is_none()));
+
+        let wet = storage
+            .archive_older_than("raw", "logs/", DAY, StorageClass::Cold, options)
+            .await
+            .unwrap();
+        assert!(!wet.dry_run);
+        // the dry run reported exactly what the real run did
+        for (dry, wet) in [
+            (dry.transitioned, wet.transitioned),
+            (dry.transitioned_bytes, wet.transitioned_bytes),
+            (dry.excluded, wet.excluded),
+            (dry.recent, wet.recent),
+            (dry.already_in_class, wet.already_in_class),
+        ] {
+            assert_eq!(dry, wet);
+        }
+        assert_eq!((wet.transitioned, wet.excluded), (2, 1));
+        assert_eq!(
+            classes(&storage).await,
+            [
+                ("logs/a".to_owned(), Some("COLDLINE".to_owned())),
+                ("logs/c".to_owned(), Some("COLDLINE".to_owned())),
+                ("logs/keep/b".to_owned(), None),
+                ("other/d".to_owned(), None),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn capped_and_failed_test() {
+        let keys: Vec<String> = (0..50).map(|i| format!("logs/{i:02}")).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let (storage, latest) = bucket(&keys).await;
+        let options = ArchiveOptions::new()
+            .concurrency(4)
+            .max_attempts(1)
+            .as_of(latest + chrono::Duration::days(2));
+
+        let report = storage
+            .archive_older_than(
+                "raw",
+                "logs/",
+                DAY,
+                StorageClass::Archive,
+                options.clone().max_objects(20),
+            )
+            .await
+            .unwrap();
+        assert!(report.capped);
+        assert_eq!(report.transitioned, 20);
+        assert!(report.summary_line().ends_with(", capped"), "{report}");
+
+        storage.mock_stats().set_fault(
+            "set_storage_class",
+            Fault::new().fail(0.5, ErrorCode::Unavailable),
+        );
+        let report = storage
+            .archive_older_than("raw", "logs/", DAY, StorageClass::Archive, options.clone())
+            .await
+            .unwrap();
+        assert!(!report.capped);
+        assert_eq!(report.already_in_class, 20);
+        assert_eq!(report.transitioned + report.failed.len() as u64, 30);
+        assert!(!report.is_success());
+        let retry = report.retry_keys();
+        assert!(retry.windows(2).all(|w| w[0] < w[1]));
+        #[cfg(feature = "serde")]
+        assert_eq!(report.retry_manifest().lines().count(), retry.len());
+
+        // the next run retries the failures
+        storage.mock_stats().clear_faults();
+        let report = storage
+            .archive_older_than("raw", "logs/", DAY, StorageClass::Archive, options)
+            .await
+            .unwrap();
+        assert_eq!(report.transitioned, retry.len() as u64);
+        assert_eq!(report.already_in_class, 50 - retry.len() as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_test() {
+        let keys: Vec<String> = (0..20).map(|i| format!("logs/{i:02}")).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let (storage, latest) = bucket(&keys).await;
+        storage.mock_stats().set_fault(
+            "set_storage_class",
+            Fault::new().fail(0.3, ErrorCode::RateLimited),
+        );
+
+        let report = storage
+            .archive_older_than(
+                "raw",
+                "logs/",
+                DAY,
+                StorageClass::Archive,
+                ArchiveOptions::new()
+                    .max_attempts(20)
+                    .as_of(latest + chrono::Duration::days(2)),
+            )
+            .await
+            .unwrap();
+        assert!(report.is_success(), "{report}");
+        assert_eq!(report.transitioned, 20);
+        assert!(report.throttled > 0);
+    }
+}
@@ -0,0 +1,187 @@
+/// Concurrency of the ACL requests issued by [`super::StorageHelper::audit_public_objects`]
+pub const AUDIT_CONCURRENCY: usize = 16;
+
+/// Whether a bucket is readable by anyone, from [`super::StorageHelper::bucket_is_public`]
+///
+/// Settings the crate can't see or evaluate (conditional bindings, ACLs of objects, organization policies)
+/// make the answer [`PublicAccess::Indeterminate`] rather than a guess.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicAccess {
+    /// the bucket, or every object in it, is readable without credentials or by any authenticated user
+    Public,
+    /// nothing in the bucket can be read by the public
+    NotPublic,
+    /// the configuration doesn't settle it, `reason` tells what to check by hand
+    Indeterminate { reason: String },
+}
+
+impl PublicAccess {
+    pub fn is_public(&self) -> bool {
+        matches!(self, PublicAccess::Public)
+    }
+}
+
+/// Holder of an [`AclEntry`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Grantee {
+    /// anyone, `allUsers` on GCS and the `AllUsers` group on S3
+    AllUsers,
+    /// anyone with a Google or AWS account, `allAuthenticatedUsers` on GCS and the `AuthenticatedUsers` group on S3
+    AllAuthenticatedUsers,
+    /// a user, group, domain or project, as named by the provider
+    Other(String),
+}
+
+impl Grantee {
+    pub fn is_public(&self) -> bool {
+        !matches!(self, Grantee::Other(_))
+    }
+
+    /// grantee of a GCS ACL entity or IAM member
+    #[cfg(any(test, feature = "gcp-storage"))]
+    pub(crate) fn from_gcs(entity: &str) -> Self {
+        match entity {
+            "allUsers" => Grantee::AllUsers,
+            "allAuthenticatedUsers" => Grantee::AllAuthenticatedUsers,
+            entity => Grantee::Other(entity.to_owned()),
+        }
+    }
+
+    /// grantee of an S3 grant, groups are identified by their uri
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn from_s3(grantee: &aws_sdk_s3::types::Grantee) -> Self {
+        match grantee.uri() {
+            Some("http://acs.amazonaws.com/groups/global/AllUsers") => Grantee::AllUsers,
+            Some("http://acs.amazonaws.com/groups/global/AuthenticatedUsers") => {
+                Grantee::AllAuthenticatedUsers
+            }
+            uri => Grantee::Other(
+                uri.or(grantee.id())
+                    .or(grantee.email_address())
+                    .or(grantee.display_name())
+                    .unwrap_or_default()
+                    .to_owned(),
+            ),
+        }
+    }
+}
+
+/// Entry of an object ACL, from [`super::StorageHelper::object_acl`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclEntry {
+    pub grantee: Grantee,
+    /// as named by the provider, e.g. `READER` on GCS or `READ` on S3
+    pub permission: String,
+}
+
+impl AclEntry {
+    pub fn new(grantee: Grantee, permission: impl Into<String>) -> Self {
+        Self {
+            grantee,
+            permission: permission.into(),
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.grantee.is_public()
+    }
+}
+
+/// percent-encode an object name for a GCS path,
+/// the storage client leaves it as is when listing object ACLs
+#[cfg(any(test, feature = "gcp-storage"))]
+pub(crate) fn escape_object_name(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// provider settings deciding whether a bucket is public, normalized
+#[derive(Debug, Default)]
+pub(crate) struct Exposure {
+    /// public access is prevented whatever is granted
+    pub blocked: bool,
+    /// the public is granted access unconditionally
+    pub granted: bool,
+    /// the public is granted access under a condition the crate doesn't evaluate
+    pub conditional: Option<String>,
+    /// objects can be made public by their own ACL
+    pub object_acls: bool,
+}
+
+impl Exposure {
+    pub fn verdict(self) -> PublicAccess {
+        if self.blocked {
+            PublicAccess::NotPublic
+        } else if self.granted {
+            PublicAccess::Public
+        } else if let Some(reason) = self.conditional {
+            PublicAccess::Indeterminate { reason }
+        } else if self.object_acls {
+            PublicAccess::Indeterminate {
+                reason: "objects can be made public by their own ACL, see audit_public_objects"
+                    .to_owned(),
+            }
+        } else {
+            PublicAccess::NotPublic
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposure_test() {
+        assert_eq!(Exposure::default().verdict(), PublicAccess::NotPublic);
+
+        // blocking wins over any grant
+        let blocked = Exposure {
+            blocked: true,
+            granted: true,
+            object_acls: true,
+            ..Default::default()
+        };
+        assert_eq!(blocked.verdict(), PublicAccess::NotPublic);
+
+        let granted = Exposure {
+            granted: true,
+            conditional: Some("condition".to_owned()),
+            ..Default::default()
+        };
+        assert!(granted.verdict().is_public());
+
+        let conditional = Exposure {
+            conditional: Some("condition".to_owned()),
+            object_acls: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            conditional.verdict(),
+            PublicAccess::Indeterminate {
+                reason: "condition".to_owned()
+            }
+        );
+
+        let acls = Exposure {
+            object_acls: true,
+            ..Default::default()
+        };
+        assert!(matches!(acls.verdict(), PublicAccess::Indeterminate { .. }));
+
+        assert!(Grantee::from_gcs("allUsers").is_public());
+        assert!(Grantee::from_gcs("allAuthenticatedUsers").is_public());
+        assert_eq!(
+            Grantee::from_gcs("user-a@b.c"),
+            Grantee::Other("user-a@b.c".to_owned())
+        );
+
+        assert_eq!(escape_object_name("a/b c~é.txt"), "a%2Fb%20c~%C3%A9.txt");
+    }
+}
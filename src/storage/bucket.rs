@@ -0,0 +1,292 @@
+use std::net::Ipv4Addr;
+
+use super::Error;
+
+/// Storage provider whose naming rules apply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Gcs,
+    S3,
+}
+
+impl Provider {
+    /// provider of the enabled feature
+    #[cfg(feature = "gcp-storage")]
+    pub const CURRENT: Provider = Provider::Gcs;
+    #[cfg(feature = "aws-storage")]
+    pub const CURRENT: Provider = Provider::S3;
+}
+
+const MIN_LEN: usize = 3;
+const MAX_LEN: usize = 63;
+/// GCS accepts longer names made of dot-separated components of at most [`MAX_LEN`]
+const GCS_MAX_DOTTED_LEN: usize = 222;
+
+/// GCS rejects names containing "google" or close misspellings
+const GCS_RESERVED_WORDS: [&str; 2] = ["google", "g00gle"];
+const S3_RESERVED_PREFIXES: [&str; 4] = ["xn--", "sthree-", "sthree-configurator", "amzn-s3-demo-"];
+const S3_RESERVED_SUFFIXES: [&str; 5] = ["-s3alias", "--ol-s3", ".mrap", "--x-s3", "--table-s3"];
+
+/// check a bucket name against the documented naming rules of `provider`
+///
+/// Every [`super::StorageHelper`] method taking a bucket checks it before calling the provider.
+pub fn validate_bucket_name(name: &str, provider: Provider) -> Result<(), Error> {
+    invalid_reason(name, provider).map_or(Ok(()), |reason| {
+        Err(Error::InvalidBucketName {
+            name: name.to_owned(),
+            reason,
+        })
+    })
+}
+
+fn invalid_reason(name: &str, provider: Provider) -> Option<String> {
+    let max_len = match provider {
+        Provider::Gcs if name.contains('.') => GCS_MAX_DOTTED_LEN,
+        _ => MAX_LEN,
+    };
+    if name.len() < MIN_LEN || name.len() > max_len {
+        return Some(format!(
+            "must be {MIN_LEN} to {max_len} characters long, not {}",
+            name.len()
+        ));
+    }
+
+    let allowed = |c: char| {
+        c.is_ascii_lowercase()
+            || c.is_ascii_digit()
+            || c == '-'
+            || c == '.'
+            || (c == '_' && provider == Provider::Gcs)
+    };
+    if let Some(c) = name.chars().find(|c| !allowed(*c)) {
+        return Some(format!("contains {c:?}, {}", charset(provider)));
+    }
+
+    let alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if !alphanumeric(name.chars().next()) || !alphanumeric(name.chars().last()) {
+        return Some("must start and end with a letter or a digit".to_owned());
+    }
+
+    if name.contains("..") {
+        return Some("must not contain adjacent dots".to_owned());
+    }
+    if name.parse::<Ipv4Addr>().is_ok() {
+        return Some("must not look like an IP address".to_owned());
+    }
+
+    match provider {
+        Provider::Gcs => {
+            if let Some(component) = name.split('.').find(|c| c.len() > MAX_LEN) {
+                return Some(format!(
+                    "dot-separated component {component:?} is longer than {MAX_LEN} characters"
+                ));
+            }
+            if name.starts_with("goog") {
+                return Some("must not start with \"goog\"".to_owned());
+            }
+            if let Some(word) = GCS_RESERVED_WORDS.iter().find(|w| name.contains(*w)) {
+                return Some(format!("must not contain {word:?}"));
+            }
+        }
+        Provider::S3 => {
+            if let Some(prefix) = S3_RESERVED_PREFIXES.iter().find(|p| name.starts_with(*p)) {
+                return Some(format!(
+                    "must not start with the reserved prefix {prefix:?}"
+                ));
+            }
+            if let Some(suffix) = S3_RESERVED_SUFFIXES.iter().find(|s| name.ends_with(*s)) {
+                return Some(format!("must not end with the reserved suffix {suffix:?}"));
+            }
+        }
+    }
+
+    None
+}
+
+fn charset(provider: Provider) -> &'static str {
+    match provider {
+        Provider::Gcs => "only lowercase letters, digits, '-', '_' and '.' are allowed",
+        Provider::S3 => "only lowercase letters, digits, '-' and '.' are allowed",
+    }
+}
+
+/// normalize `input` into a name valid for both providers, e.g. to name a bucket after a tenant
+///
+/// The input is lowercased, runs of other characters than letters and digits become a single '-',
+/// reserved words are rewritten and the result is trimmed to 63 characters.
+/// Short results are padded, an input without any letter or digit gives `"bucket"`.
+pub fn suggest_bucket_name(input: &str) -> String {
+    let mut name = String::with_capacity(input.len());
+    for c in input.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+
+    for word in GCS_RESERVED_WORDS {
+        name = name.replace(word, "ggl");
+    }
+    let reserved_prefix =
+        name.starts_with("goog") || S3_RESERVED_PREFIXES.iter().any(|p| name.starts_with(p));
+    if reserved_prefix {
+        name.insert(0, 'b');
+    }
+
+    name.truncate(MAX_LEN);
+    let mut name = name.trim_end_matches('-').to_owned();
+
+    if let Some(suffix) = S3_RESERVED_SUFFIXES.iter().find(|s| name.ends_with(*s)) {
+        // drop the dash, e.g. "x-s3alias" becomes "xs3alias"
+        name.remove(name.len() - suffix.len());
+    }
+
+    if name.is_empty() {
+        "bucket".to_owned()
+    } else if name.len() < MIN_LEN {
+        format!("{name}-bucket")
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+
+    const PROVIDERS: [Provider; 2] = [Provider::Gcs, Provider::S3];
+
+    #[test]
+    fn valid_names_test() {
+        let cases: &[(&str, &[Provider])] = &[
+            ("abc", &PROVIDERS),
+            ("my-bucket-01", &PROVIDERS),
+            ("my.bucket.example", &PROVIDERS),
+            ("0bucket9", &PROVIDERS),
+            (&"a".repeat(63), &PROVIDERS),
+            ("my_bucket", &[Provider::Gcs]),
+            ("sthree-bucket", &[Provider::Gcs]),
+            ("bucket-s3alias", &[Provider::Gcs]),
+            ("googlebucket", &[Provider::S3]),
+            ("goog-bucket", &[Provider::S3]),
+            (
+                &format!("{}.{}", "a".repeat(63), "b".repeat(63)),
+                &[Provider::Gcs],
+            ),
+        ];
+
+        for (name, providers) in cases {
+            for provider in providers.iter() {
+                assert!(
+                    validate_bucket_name(name, *provider).is_ok(),
+                    "{name:?} should be valid on {provider:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_names_test() {
+        let cases: &[(&str, &[Provider], &str)] = &[
+            ("ab", &PROVIDERS, "characters long"),
+            ("", &PROVIDERS, "characters long"),
+            (&"a".repeat(64), &PROVIDERS, "characters long"),
+            (&"a.".repeat(112), &[Provider::Gcs], "characters long"),
+            ("My-Bucket", &PROVIDERS, "contains 'M'"),
+            ("my bucket", &PROVIDERS, "contains ' '"),
+            ("my_bucket", &[Provider::S3], "contains '_'"),
+            ("bücket", &PROVIDERS, "contains 'ü'"),
+            ("-bucket", &PROVIDERS, "start and end"),
+            ("bucket.", &PROVIDERS, "start and end"),
+            ("_bucket", &[Provider::Gcs], "start and end"),
+            ("my..bucket", &PROVIDERS, "adjacent dots"),
+            ("192.168.5.4", &PROVIDERS, "IP address"),
+            (
+                &format!("{}.b", "a".repeat(64)),
+                &[Provider::Gcs],
+                "dot-separated component",
+            ),
+            ("goog-bucket", &[Provider::Gcs], "\"goog\""),
+            ("my-google-bucket", &[Provider::Gcs], "\"google\""),
+            ("my-g00gle-bucket", &[Provider::Gcs], "\"g00gle\""),
+            ("xn--bucket", &[Provider::S3], "reserved prefix"),
+            ("sthree-bucket", &[Provider::S3], "reserved prefix"),
+            ("amzn-s3-demo-bucket", &[Provider::S3], "reserved prefix"),
+            ("bucket-s3alias", &[Provider::S3], "reserved suffix"),
+            ("bucket--ol-s3", &[Provider::S3], "reserved suffix"),
+            ("bucket.mrap", &[Provider::S3], "reserved suffix"),
+            ("bucket--x-s3", &[Provider::S3], "reserved suffix"),
+        ];
+
+        for (name, providers, reason) in cases {
+            for provider in providers.iter() {
+                let err = validate_bucket_name(name, *provider).unwrap_err();
+                assert_eq!(err.code(), ErrorCode::InvalidInput);
+                match err {
+                    Error::InvalidBucketName { name: n, reason: r } => {
+                        assert_eq!(&n, name);
+                        assert!(
+                            r.contains(reason),
+                            "{name:?} on {provider:?}: {r:?} should mention {reason:?}"
+                        );
+                    }
+                    e => panic!("unexpected error {e:?}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn suggest_bucket_name_test() {
+        let cases = [
+            ("Acme Corp", "acme-corp"),
+            ("acme_corp__EU", "acme-corp-eu"),
+            ("  --Tenant.42--  ", "tenant-42"),
+            ("Zoë's Café", "zo-s-caf"),
+            ("ab", "ab-bucket"),
+            ("!!", "bucket"),
+            ("", "bucket"),
+            ("Google Fans", "ggl-fans"),
+            ("goog", "bgoog"),
+            ("sthree-tenant", "bsthree-tenant"),
+            ("tenant-s3alias", "tenants3alias"),
+            ("192.168.0.1", "192-168-0-1"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(suggest_bucket_name(input), expected, "{input:?}");
+        }
+
+        let long = suggest_bucket_name(&format!("{}_tail", "x".repeat(62)));
+        assert_eq!(long, "x".repeat(62));
+    }
+
+    #[test]
+    fn suggestions_are_valid_test() {
+        let inputs = [
+            "Acme Corp",
+            "ÄÖÜ",
+            "a",
+            "---",
+            "GOOGLE",
+            "xn--tenant",
+            "amzn-s3-demo-tenant",
+            "tenant--x-s3",
+            "tenant.mrap",
+            "10.0.0.1",
+            &"Tenant ".repeat(20),
+            &format!("{}-s3alias", "t".repeat(60)),
+        ];
+
+        for input in inputs {
+            let name = suggest_bucket_name(input);
+            for provider in PROVIDERS {
+                assert!(
+                    validate_bucket_name(&name, provider).is_ok(),
+                    "{input:?} gave {name:?}, invalid on {provider:?}"
+                );
+            }
+        }
+    }
+}
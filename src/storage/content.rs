@@ -0,0 +1,53 @@
+use sha2::{Digest, Sha256};
+
+/// hex encoded SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// key under which `data` is stored by [`super::StorageHelper::put_content_addressed`]:
+/// `{prefix}/{sha256}`, or just the hash for an empty prefix
+pub fn content_key(prefix: &str, data: &[u8]) -> String {
+    let hash = sha256_hex(data);
+    let prefix = prefix.trim_end_matches('/');
+
+    if prefix.is_empty() {
+        hash
+    } else {
+        format!("{prefix}/{hash}")
+    }
+}
+
+/// hash embedded in a content addressed key (its last path segment)
+pub(crate) fn key_hash(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn sha256_hex_test() {
+        assert_eq!(sha256_hex(b"hello"), HELLO);
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn content_key_test() {
+        assert_eq!(content_key("blobs", b"hello"), format!("blobs/{HELLO}"));
+        assert_eq!(content_key("blobs/", b"hello"), format!("blobs/{HELLO}"));
+        assert_eq!(content_key("", b"hello"), HELLO);
+    }
+
+    #[test]
+    fn key_hash_test() {
+        assert_eq!(key_hash(&content_key("a/b", b"hello")), HELLO);
+        assert_eq!(key_hash(HELLO), HELLO);
+    }
+}
@@ -0,0 +1,456 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::StorageHelper;
+use crate::report::{self, Report};
+use crate::retry::{self, ExponentialFullJitter};
+use crate::{BatchError, NimbusError};
+
+/// Copies in flight in [`StorageHelper::copy_batch`] unless set with [`CopyBatchOptions::concurrency`]
+pub const DEFAULT_COPY_CONCURRENCY: usize = 32;
+
+/// Attempts of each copy of [`StorageHelper::copy_batch`] unless set with [`CopyBatchOptions::max_attempts`]
+pub const DEFAULT_COPY_ATTEMPTS: u32 = 3;
+
+/// Object to copy and where, one line of a copy manifest
+///
+/// With the `serde` feature an entry is `{"src_key": "...", "dst_key": "..."}`,
+/// so a manifest can be kept as newline delimited JSON, see [`CopyBatchReport::retry_manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CopyEntry {
+    pub src_key: String,
+    pub dst_key: String,
+}
+
+impl CopyEntry {
+    pub fn new(src_key: impl Into<String>, dst_key: impl Into<String>) -> Self {
+        Self {
+            src_key: src_key.into(),
+            dst_key: dst_key.into(),
+        }
+    }
+}
+
+impl<S: Into<String>, D: Into<String>> From<(S, D)> for CopyEntry {
+    fn from((src_key, dst_key): (S, D)) -> Self {
+        Self::new(src_key, dst_key)
+    }
+}
+
+/// Progress of [`StorageHelper::copy_batch`], counting the entries done so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyProgress {
+    pub copied: usize,
+    /// left alone because their destination existed, see [`CopyBatchOptions::skip_existing`]
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl CopyProgress {
+    /// entries done, whatever their outcome
+    pub fn done(&self) -> usize {
+        self.copied + self.skipped + self.failed
+    }
+}
+
+type OnProgress = dyn Fn(CopyProgress) + Send + Sync;
+
+/// Options of [`StorageHelper::copy_batch`]
+///
+/// Failed copies are retried when the error is retryable, with an exponential backoff
+/// from `base_delay` to `max_delay`.
+#[derive(Clone)]
+pub struct CopyBatchOptions {
+    pub concurrency: usize,
+    /// attempts of each copy, the first one included
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// leave the entries whose destination exists alone
+    pub skip_existing: bool,
+    progress_every: usize,
+    on_progress: Option<Arc<OnProgress>>,
+}
+
+impl Default for CopyBatchOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_COPY_CONCURRENCY,
+            max_attempts: DEFAULT_COPY_ATTEMPTS,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            skip_existing: false,
+            progress_every: 0,
+            on_progress: None,
+        }
+    }
+}
+
+impl fmt::Debug for CopyBatchOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CopyBatchOptions")
+            .field("concurrency", &self.concurrency)
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("skip_existing", &self.skip_existing)
+            .field("progress_every", &self.progress_every)
+            .finish()
+    }
+}
+
+impl CopyBatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn retry_delays(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn skip_existing(mut self, skip_existing: bool) -> Self {
+        self.skip_existing = skip_existing;
+        self
+    }
+
+    /// call `on_progress` every `every` entries done, and once all are
+    pub fn on_progress(
+        mut self,
+        every: usize,
+        on_progress: impl Fn(CopyProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_every = every.max(1);
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+}
+
+/// Outcome of [`StorageHelper::copy_batch`]
+///
+/// The failed entries form a manifest of their own: copying them again resumes the batch.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CopyBatchReport {
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: Vec<BatchError<CopyEntry>>,
+}
+
+impl CopyBatchReport {
+    /// true if no entry failed
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// the failed entries, to pass to [`StorageHelper::copy_batch`] again
+    pub fn retry_entries(&self) -> Vec<CopyEntry> {
+        self.failed.iter().map(|e| e.key.clone()).collect()
+    }
+
+    /// the failed entries as newline delimited JSON, one [`CopyEntry`] per line
+    #[cfg(feature = "serde")]
+    pub fn retry_manifest(&self) -> String {
+        self.failed
+            .iter()
+            .map(|e| {
+                serde_json::to_string(&e.key).expect("a copy entry is always serializable") + "\n"
+            })
+            .collect()
+    }
+}
+
+impl Report for CopyBatchReport {
+    fn summary_line(&self) -> String {
+        format!(
+            "copy: {} copied, {} skipped, {} failed",
+            self.copied,
+            self.skipped,
+            self.failed.len()
+        )
+    }
+
+    fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for CopyBatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())?;
+        // in key order, entries complete in any order
+        let mut failed: Vec<_> = self.failed.iter().collect();
+        failed.sort_by(|a, b| {
+            (&a.key.src_key, &a.key.dst_key).cmp(&(&b.key.src_key, &b.key.dst_key))
+        });
+        report::write_section(
+            f,
+            "failed",
+            failed
+                .iter()
+                .map(|e| format!("{} -> {}: {}", e.key.src_key, e.key.dst_key, e.error)),
+        )
+    }
+}
+
+/// whether the entry was copied (true) or skipped
+async fn copy_one<S>(
+    storage: &S,
+    src_bucket: &str,
+    dst_bucket: &str,
+    entry: &CopyEntry,
+    options: &CopyBatchOptions,
+) -> Result<bool, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let mut backoff = ExponentialFullJitter::new(options.base_delay, options.max_delay)
+        .max_attempts(options.max_attempts.max(1));
+
+    retry::retry(&mut backoff, || async {
+        if options.skip_existing && storage.object_exists(dst_bucket, &entry.dst_key).await? {
+            return Ok(false);
+        }
+        storage
+            .copy_encrypted(
+                (src_bucket, &entry.src_key),
+                None,
+                (dst_bucket, &entry.dst_key),
+                None,
+            )
+            .await?;
+        Ok(true)
+    })
+    .await
+}
+
+pub(crate) async fn copy_batch<S, E>(
+    storage: &S,
+    src_bucket: &str,
+    dst_bucket: &str,
+    entries: E,
+    options: CopyBatchOptions,
+) -> Result<CopyBatchReport, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+    E: Stream<Item = CopyEntry>,
+{
+    let options = &options;
+    let copies = entries
+        .map(|entry| async move {
+            let result = copy_one(storage, src_bucket, dst_bucket, &entry, options).await;
+            (entry, result)
+        })
+        .buffer_unordered(options.concurrency.max(1));
+    futures::pin_mut!(copies);
+
+    let mut report = CopyBatchReport::default();
+    let mut progress = CopyProgress::default();
+    while let Some((entry, result)) = copies.next().await {
+        match result {
+            Ok(true) => progress.copied += 1,
+            Ok(false) => progress.skipped += 1,
+            Err(error) => {
+                progress.failed += 1;
+                report.failed.push(BatchError { key: entry, error });
+            }
+        }
+
+        if let Some(on_progress) = &options.on_progress {
+            if progress.done() % options.progress_every == 0 {
+                on_progress(progress);
+            }
+        }
+    }
+
+    if let Some(on_progress) = &options.on_progress {
+        if progress.done() % options.progress_every != 0 {
+            on_progress(progress);
+        }
+    }
+
+    report.copied = progress.copied;
+    report.skipped = progress.skipped;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::{Fault, MemoryStorage};
+    use crate::ErrorCode;
+
+    async fn raw_bucket(n: usize) -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        for i in 0..n {
+            storage
+                .upload_from_bytes("raw", &format!("in/{i}.csv"), None, vec![i as u8])
+                .await
+                .unwrap();
+        }
+        storage
+    }
+
+    fn manifest(n: usize) -> impl Stream<Item = CopyEntry> {
+        // the manifest is never collected, the key mapping applies as it streams
+        futures::stream::iter(0..n)
+            .map(|i| CopyEntry::new(format!("in/{i}.csv"), format!("curated/{i}.csv")))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn copy_batch_test() {
+        let storage = raw_bucket(100).await;
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink = Arc::clone(&events);
+
+        let report = storage
+            .copy_batch(
+                "raw",
+                "curated",
+                manifest(100),
+                CopyBatchOptions::new()
+                    .concurrency(8)
+                    .on_progress(30, move |p| sink.lock().unwrap().push(p)),
+            )
+            .await
+            .unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.copied, 100);
+        assert_eq!(
+            storage
+                .download_to_bytes("curated", "curated/42.csv")
+                .await
+                .unwrap(),
+            [42]
+        );
+        let done: Vec<_> = events.lock().unwrap().iter().map(|p| p.done()).collect();
+        assert_eq!(done, [30, 60, 90, 100]);
+
+        // only the missing destination is copied again
+        storage
+            .delete_file("curated", "curated/7.csv")
+            .await
+            .unwrap();
+        let report = storage
+            .copy_batch(
+                "raw",
+                "curated",
+                manifest(100),
+                CopyBatchOptions::new().skip_existing(true),
+            )
+            .await
+            .unwrap();
+        assert_eq!((report.copied, report.skipped), (1, 99));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn copy_batch_resume_test() {
+        let storage = raw_bucket(50).await;
+        let entries = manifest(50).chain(futures::stream::iter([CopyEntry::new(
+            "in/missing.csv",
+            "curated/missing.csv",
+        )]));
+        storage.mock_stats().set_fault(
+            "copy_encrypted",
+            Fault::new().fail(0.3, ErrorCode::Unavailable),
+        );
+
+        let report = storage
+            .copy_batch(
+                "raw",
+                "curated",
+                entries,
+                CopyBatchOptions::new().max_attempts(1),
+            )
+            .await
+            .unwrap();
+        assert!(!report.is_success());
+        assert_eq!(report.copied + report.failed.len(), 51);
+        assert!(report
+            .failed
+            .iter()
+            .any(|e| e.key.src_key == "in/missing.csv" && e.error.code() == ErrorCode::NotFound));
+
+        // fed back, the failed entries resume the batch
+        storage.mock_stats().clear_faults();
+        let retried = storage
+            .copy_batch(
+                "raw",
+                "curated",
+                futures::stream::iter(report.retry_entries()),
+                CopyBatchOptions::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(retried.copied, report.failed.len() - 1);
+        assert_eq!(
+            retried.retry_entries(),
+            [CopyEntry::new("in/missing.csv", "curated/missing.csv")]
+        );
+        for i in 0..50 {
+            assert!(storage
+                .object_exists("curated", &format!("curated/{i}.csv"))
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn copy_batch_retry_test() {
+        let storage = raw_bucket(20).await;
+        storage.mock_stats().set_fault(
+            "copy_encrypted",
+            Fault::new().fail(0.3, ErrorCode::Unavailable),
+        );
+
+        let report = storage
+            .copy_batch(
+                "raw",
+                "curated",
+                manifest(20),
+                CopyBatchOptions::new().max_attempts(20),
+            )
+            .await
+            .unwrap();
+        assert!(report.is_success());
+        assert!(storage.stats().calls("copy_encrypted") > 20);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn retry_manifest_test() {
+        let report = CopyBatchReport {
+            failed: vec![BatchError {
+                key: CopyEntry::new("in/1.csv", "out/1.csv"),
+                error: NimbusError::Other("down".to_owned()),
+            }],
+            ..Default::default()
+        };
+        let manifest = report.retry_manifest();
+        assert_eq!(
+            manifest,
+            "{\"src_key\":\"in/1.csv\",\"dst_key\":\"out/1.csv\"}\n"
+        );
+        let entry: CopyEntry = serde_json::from_str(manifest.trim_end()).unwrap();
+        assert_eq!(entry, report.failed[0].key);
+    }
+}
@@ -0,0 +1,428 @@
+use std::time::Duration;
+
+use super::Error;
+
+/// Most rules in the CORS configuration of a bucket, the S3 limit
+pub const MAX_CORS_RULES: usize = 100;
+
+/// Methods a [`CorsRule`] can allow, the ones both providers accept
+pub const CORS_METHODS: [&str; 5] = ["GET", "HEAD", "PUT", "POST", "DELETE"];
+
+/// Cross-origin rule of a bucket, see [`super::StorageHelper::set_cors`]
+///
+/// Maps onto a GCS `cors` entry and an S3 `CORSRule`. On S3 the response headers are both allowed in
+/// preflight requests and exposed to the browser, as GCS does with its single list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorsRule {
+    /// e.g. `https://app.example.com`, `*` for any origin
+    pub origins: Vec<String>,
+    /// among [`CORS_METHODS`]
+    pub methods: Vec<String>,
+    /// headers the browser may send and read, e.g. `Content-Type`
+    pub response_headers: Vec<String>,
+    /// how long the browser caches a preflight response
+    pub max_age: Option<Duration>,
+}
+
+impl CorsRule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn origin(mut self, origin: impl Into<String>) -> Self {
+        self.origins.push(origin.into());
+        self
+    }
+
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.methods.push(method.into());
+        self
+    }
+
+    pub fn response_header(mut self, header: impl Into<String>) -> Self {
+        self.response_headers.push(header.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// reject what either provider would: no origin or method, an empty value, more than one `*`
+    /// in an origin or header, a wildcard or unknown method, a max age beyond `i32::MAX` seconds
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: String| Err(Error::InvalidCors(reason));
+
+        if self.origins.is_empty() {
+            return invalid("no origin".to_owned());
+        }
+        if self.methods.is_empty() {
+            return invalid("no method".to_owned());
+        }
+        for (kind, values) in [
+            ("origin", &self.origins),
+            ("response header", &self.response_headers),
+        ] {
+            for value in values {
+                if value.trim().is_empty() {
+                    return invalid(format!("empty {kind}"));
+                }
+                if value.matches('*').count() > 1 {
+                    return invalid(format!("{kind} {value:?} has more than one `*`"));
+                }
+            }
+        }
+        for method in &self.methods {
+            if method == "*" {
+                return invalid("wildcard method, S3 needs them listed".to_owned());
+            }
+            if !CORS_METHODS.contains(&method.to_ascii_uppercase().as_str()) {
+                return invalid(format!(
+                    "method {method:?} is not one of {}",
+                    CORS_METHODS.join(", ")
+                ));
+            }
+        }
+        if self
+            .max_age
+            .is_some_and(|max_age| max_age.as_secs() > i32::MAX as u64)
+        {
+            return invalid(format!("max age over {} seconds", i32::MAX));
+        }
+
+        Ok(())
+    }
+
+    /// the rule as the providers read it back: methods in uppercase, headers in lowercase,
+    /// every list sorted without duplicates, max age in whole seconds and none when zero
+    pub fn normalized(&self) -> Self {
+        let sorted = |values: &[String], case: fn(&str) -> String| {
+            let mut values: Vec<String> = values.iter().map(|v| case(v.trim())).collect();
+            values.sort();
+            values.dedup();
+            values
+        };
+        Self {
+            origins: sorted(&self.origins, str::to_owned),
+            methods: sorted(&self.methods, str::to_ascii_uppercase),
+            response_headers: sorted(&self.response_headers, str::to_ascii_lowercase),
+            max_age: self
+                .max_age
+                .map(|max_age| Duration::from_secs(max_age.as_secs()))
+                .filter(|max_age| !max_age.is_zero()),
+        }
+    }
+
+    /// whether both rules are the same once [normalized](CorsRule::normalized)
+    pub fn same_as(&self, other: &CorsRule) -> bool {
+        self.normalized() == other.normalized()
+    }
+
+    fn max_age_seconds(&self) -> Option<i32> {
+        self.max_age.map(|max_age| max_age.as_secs() as i32)
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    pub(crate) fn to_gcs(&self) -> google_cloud_storage::http::buckets::Cors {
+        google_cloud_storage::http::buckets::Cors {
+            origin: self.origins.clone(),
+            method: self
+                .methods
+                .iter()
+                .map(|m| m.to_ascii_uppercase())
+                .collect(),
+            response_header: self.response_headers.clone(),
+            max_age_seconds: self.max_age_seconds().unwrap_or_default(),
+        }
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    pub(crate) fn from_gcs(cors: &google_cloud_storage::http::buckets::Cors) -> Self {
+        Self {
+            origins: cors.origin.clone(),
+            methods: cors.method.clone(),
+            response_headers: cors.response_header.clone(),
+            max_age: (cors.max_age_seconds > 0)
+                .then(|| Duration::from_secs(cors.max_age_seconds as u64)),
+        }
+    }
+
+    /// headers with a wildcard are allowed but not exposed, S3 only takes exact exposed headers
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn to_s3(&self) -> Result<aws_sdk_s3::types::CorsRule, Error> {
+        let exposed = self.response_headers.iter().filter(|h| !h.contains('*'));
+        aws_sdk_s3::types::CorsRule::builder()
+            .set_allowed_origins(Some(self.origins.clone()))
+            .set_allowed_methods(Some(
+                self.methods
+                    .iter()
+                    .map(|m| m.to_ascii_uppercase())
+                    .collect(),
+            ))
+            .set_allowed_headers(Some(self.response_headers.clone()))
+            .set_expose_headers(Some(exposed.cloned().collect()))
+            .set_max_age_seconds(self.max_age_seconds())
+            .build()
+            .map_err(|e| Error::InvalidCors(e.to_string()))
+    }
+
+    /// the allowed headers followed by the exposed ones not allowed already
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn from_s3(rule: &aws_sdk_s3::types::CorsRule) -> Self {
+        let mut response_headers = rule.allowed_headers().to_vec();
+        for header in rule.expose_headers() {
+            if !response_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(header))
+            {
+                response_headers.push(header.clone());
+            }
+        }
+        Self {
+            origins: rule.allowed_origins().to_vec(),
+            methods: rule.allowed_methods().to_vec(),
+            response_headers,
+            max_age: rule
+                .max_age_seconds()
+                .filter(|s| *s > 0)
+                .map(|s| Duration::from_secs(s as u64)),
+        }
+    }
+}
+
+/// validate a whole rule set before it replaces the one of a bucket
+pub fn validate_cors(rules: &[CorsRule]) -> Result<(), Error> {
+    if rules.len() > MAX_CORS_RULES {
+        return Err(Error::InvalidCors(format!(
+            "{} rules, at most {MAX_CORS_RULES} are accepted",
+            rules.len()
+        )));
+    }
+    for (index, rule) in rules.iter().enumerate() {
+        rule.validate().map_err(|e| match e {
+            Error::InvalidCors(reason) => Error::InvalidCors(format!("rule {index}: {reason}")),
+            e => e,
+        })?;
+    }
+    Ok(())
+}
+
+/// patch replacing the CORS rules of a bucket and nothing else
+///
+/// The patch type of the client sends every field it has, unset ones as `null` which clears them,
+/// so the current configuration of the bucket is sent back along with the new rules.
+/// The patch only applies to the metageneration read, a concurrent change fails it.
+#[cfg(feature = "gcp-storage")]
+pub(crate) fn gcs_cors_patch(
+    config: google_cloud_storage::http::buckets::Bucket,
+    rules: &[CorsRule],
+) -> google_cloud_storage::http::buckets::patch::PatchBucketRequest {
+    use google_cloud_storage::http::buckets::insert::RetentionPolicyCreationConfig;
+    use google_cloud_storage::http::buckets::patch::{BucketPatchConfig, PatchBucketRequest};
+    use google_cloud_storage::http::object_access_controls::insert::ObjectAccessControlCreationConfig;
+
+    let default_object_acl = config.default_object_acl.map(|acl| {
+        acl.into_iter()
+            .map(|a| ObjectAccessControlCreationConfig {
+                entity: a.entity,
+                role: a.role,
+            })
+            .collect()
+    });
+    let retention_policy = config
+        .retention_policy
+        .map(|p| RetentionPolicyCreationConfig {
+            retention_period: p.retention_period,
+        });
+
+    PatchBucketRequest {
+        bucket: config.name,
+        if_metageneration_match: Some(config.metageneration),
+        metadata: Some(BucketPatchConfig {
+            acl: config.acl,
+            default_object_acl,
+            lifecycle: config.lifecycle,
+            cors: Some(rules.iter().map(CorsRule::to_gcs).collect()),
+            storage_class: Some(config.storage_class),
+            default_event_based_hold: config.default_event_based_hold.unwrap_or(false),
+            labels: config.labels,
+            website: config.website,
+            versioning: config.versioning,
+            logging: config.logging,
+            encryption: config.encryption,
+            billing: config.billing,
+            retention_policy,
+            iam_configuration: config.iam_configuration,
+            rpo: config.rpo,
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload_rule() -> CorsRule {
+        CorsRule::new()
+            .origin("https://app.example.com")
+            .method("PUT")
+            .method("get")
+            .response_header("Content-Type")
+            .max_age(Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn validate_test() {
+        upload_rule().validate().unwrap();
+        CorsRule::new()
+            .origin("*")
+            .method("GET")
+            .validate()
+            .unwrap();
+        CorsRule::new()
+            .origin("https://*.example.com")
+            .method("HEAD")
+            .response_header("x-amz-meta-*")
+            .validate()
+            .unwrap();
+
+        for (rule, reason) in [
+            (CorsRule::new().method("GET"), "no origin"),
+            (CorsRule::new().origin("*"), "no method"),
+            (upload_rule().origin(" "), "empty origin"),
+            (upload_rule().response_header(""), "empty response header"),
+            (
+                upload_rule().origin("https://*.*.example.com"),
+                "more than one `*`",
+            ),
+            (upload_rule().response_header("**"), "more than one `*`"),
+            (upload_rule().method("*"), "wildcard method"),
+            (upload_rule().method("PATCH"), "not one of"),
+            (upload_rule().method("OPTIONS"), "not one of"),
+            (
+                upload_rule().max_age(Duration::from_secs(1 << 31)),
+                "max age",
+            ),
+        ] {
+            let error = rule.validate().unwrap_err();
+            assert!(matches!(error, Error::InvalidCors(_)), "{error}");
+            assert!(error.to_string().contains(reason), "{error} for {rule:?}");
+            assert_eq!(error.code(), crate::ErrorCode::InvalidInput);
+        }
+
+        let mut rules = vec![upload_rule(); MAX_CORS_RULES];
+        validate_cors(&rules).unwrap();
+        rules.push(upload_rule());
+        assert!(validate_cors(&rules).is_err());
+
+        let error = validate_cors(&[upload_rule(), CorsRule::new().origin("*")]).unwrap_err();
+        assert!(error.to_string().contains("rule 1: no method"), "{error}");
+    }
+
+    #[test]
+    fn normalized_test() {
+        let rule = CorsRule::new()
+            .origin("https://b.example.com")
+            .origin("https://a.example.com")
+            .origin("https://b.example.com")
+            .method("put")
+            .method("GET")
+            .response_header("Content-Type")
+            .response_header("content-type")
+            .max_age(Duration::from_millis(3_600_500));
+        assert_eq!(
+            rule.normalized(),
+            CorsRule {
+                origins: vec![
+                    "https://a.example.com".into(),
+                    "https://b.example.com".into()
+                ],
+                methods: vec!["GET".into(), "PUT".into()],
+                response_headers: vec!["content-type".into()],
+                max_age: Some(Duration::from_secs(3600)),
+            }
+        );
+        assert_eq!(rule.normalized(), rule.normalized().normalized());
+
+        let zero = upload_rule().max_age(Duration::ZERO);
+        assert!(zero.same_as(&CorsRule {
+            max_age: None,
+            ..upload_rule()
+        }));
+        assert!(!upload_rule().same_as(&upload_rule().origin("*")));
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    #[test]
+    fn gcs_mapping_test() {
+        use google_cloud_storage::http::buckets::{Bucket, Cors, Versioning};
+
+        let rule = upload_rule();
+        let cors = rule.to_gcs();
+        assert_eq!(
+            cors,
+            Cors {
+                origin: vec!["https://app.example.com".into()],
+                method: vec!["PUT".into(), "GET".into()],
+                response_header: vec!["Content-Type".into()],
+                max_age_seconds: 3600,
+            }
+        );
+        assert!(CorsRule::from_gcs(&cors).same_as(&rule));
+
+        let no_max_age = CorsRule::new().origin("*").method("GET").to_gcs();
+        assert_eq!(no_max_age.max_age_seconds, 0);
+        assert_eq!(CorsRule::from_gcs(&no_max_age).max_age, None);
+
+        let config = Bucket {
+            name: "b".into(),
+            metageneration: 7,
+            storage_class: "NEARLINE".into(),
+            labels: Some([("team".to_owned(), "web".to_owned())].into()),
+            versioning: Some(Versioning { enabled: true }),
+            cors: Some(vec![no_max_age]),
+            ..Default::default()
+        };
+        let patch = gcs_cors_patch(config, &[rule]);
+        assert_eq!(patch.bucket, "b");
+        assert_eq!(patch.if_metageneration_match, Some(7));
+        let metadata = patch.metadata.unwrap();
+        assert_eq!(metadata.cors, Some(vec![cors]));
+        assert_eq!(metadata.storage_class.as_deref(), Some("NEARLINE"));
+        assert_eq!(metadata.labels.unwrap()["team"], "web");
+        assert_eq!(metadata.versioning, Some(Versioning { enabled: true }));
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[test]
+    fn s3_mapping_test() {
+        let rule = upload_rule().response_header("x-amz-meta-*");
+        let s3 = rule.to_s3().unwrap();
+        assert_eq!(s3.allowed_origins(), ["https://app.example.com"]);
+        assert_eq!(s3.allowed_methods(), ["PUT", "GET"]);
+        assert_eq!(s3.allowed_headers(), ["Content-Type", "x-amz-meta-*"]);
+        assert_eq!(s3.expose_headers(), ["Content-Type"]);
+        assert_eq!(s3.max_age_seconds(), Some(3600));
+        assert!(CorsRule::from_s3(&s3).same_as(&rule));
+
+        // rules set outside of nimbus may expose headers they don't allow
+        let outside = aws_sdk_s3::types::CorsRule::builder()
+            .allowed_origins("*")
+            .allowed_methods("GET")
+            .allowed_headers("Authorization")
+            .expose_headers("ETag")
+            .expose_headers("authorization")
+            .build()
+            .unwrap();
+        assert_eq!(
+            CorsRule::from_s3(&outside),
+            CorsRule::new()
+                .origin("*")
+                .method("GET")
+                .response_header("Authorization")
+                .response_header("ETag")
+        );
+    }
+}
@@ -0,0 +1,611 @@
+//! Comparison of the objects under two prefixes, e.g. before cutting over from a bucket to its copy
+//!
+//! Both listings are walked side by side and merge-joined on the key relative to each prefix.
+//! This relies on listings being in lexicographic (byte) order of the keys, which GCS and S3 both guarantee;
+//! a listing out of order fails the diff rather than reporting wrong differences.
+//! Only the current page of each listing is held, whatever the number of objects.
+//!
+//! ```ignore
+//! let report = storage::diff_prefixes((&old, "old-bucket", ""), (&new, "new-bucket", ""), DiffOptions::new()).await?;
+//! if !report.is_empty() {
+//!     println!("{}", serde_json::to_string_pretty(&report)?);
+//! }
+//! ```
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::pin::Pin;
+
+use futures::{Stream, TryStreamExt};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::{Error, ObjectMeta, StorageHelper};
+use crate::report::{self, Report};
+use crate::{ByteSize, NimbusError};
+
+/// Size of the ranges compared at once by a content check
+pub const CONTENT_CHECK_CHUNK: ByteSize = ByteSize::mib(8);
+
+/// Why an object present on both sides differs
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "reason", rename_all = "snake_case"))]
+pub enum DiffReason {
+    SizeMismatch {
+        a: u64,
+        b: u64,
+    },
+    /// the CRC32C or the MD5 returned by both listings differ
+    ChecksumMismatch,
+    /// the listings don't return a checksum both sides share, and the content wasn't compared
+    ChecksumUnavailable,
+    /// the content check found different bytes from `offset` on
+    ContentMismatch {
+        offset: u64,
+    },
+}
+
+impl fmt::Display for DiffReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffReason::SizeMismatch { a, b } => write!(f, "size {a} != {b}"),
+            DiffReason::ChecksumMismatch => f.write_str("checksum differs"),
+            DiffReason::ChecksumUnavailable => f.write_str("no checksum to compare"),
+            DiffReason::ContentMismatch { offset } => {
+                write!(f, "content differs from byte {offset}")
+            }
+        }
+    }
+}
+
+/// Difference between the two sides of [`diff_prefixes`], keys are relative to the prefix of each side
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum DiffEntry {
+    OnlyInA {
+        key: String,
+    },
+    OnlyInB {
+        key: String,
+    },
+    Differing {
+        key: String,
+        #[cfg_attr(feature = "serde", serde(flatten))]
+        reason: DiffReason,
+    },
+}
+
+impl DiffEntry {
+    pub fn key(&self) -> &str {
+        match self {
+            DiffEntry::OnlyInA { key }
+            | DiffEntry::OnlyInB { key }
+            | DiffEntry::Differing { key, .. } => key,
+        }
+    }
+}
+
+/// Object present on both sides that differs, see [`DiffReport::differing`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Differing {
+    pub key: String,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub reason: DiffReason,
+}
+
+/// Outcome of [`diff_prefixes`], keys are relative to the prefix of each side and sorted
+///
+/// It holds every difference; diffs expected to be huge are better consumed with [`diff_prefixes_stream`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DiffReport {
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+    pub differing: Vec<Differing>,
+}
+
+impl DiffReport {
+    /// true if both sides hold the same objects
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.differing.is_empty()
+    }
+
+    /// record a difference
+    pub fn push(&mut self, entry: DiffEntry) {
+        match entry {
+            DiffEntry::OnlyInA { key } => self.only_in_a.push(key),
+            DiffEntry::OnlyInB { key } => self.only_in_b.push(key),
+            DiffEntry::Differing { key, reason } => self.differing.push(Differing { key, reason }),
+        }
+    }
+}
+
+impl Report for DiffReport {
+    fn summary_line(&self) -> String {
+        format!(
+            "diff: {} only in a, {} only in b, {} differing",
+            self.only_in_a.len(),
+            self.only_in_b.len(),
+            self.differing.len()
+        )
+    }
+
+    fn has_failures(&self) -> bool {
+        !self.is_empty()
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())?;
+        report::write_section(f, "only in a", self.only_in_a.iter().cloned())?;
+        report::write_section(f, "only in b", self.only_in_b.iter().cloned())?;
+        report::write_section(
+            f,
+            "differing",
+            self.differing
+                .iter()
+                .map(|d| format!("{}: {}", d.key, d.reason)),
+        )
+    }
+}
+
+/// Storage, bucket and prefix of a side of a diff
+pub type DiffSide<'a, S> = (&'a S, &'a str, &'a str);
+
+/// How [`diff_prefixes`] compares the objects
+///
+/// By default objects with the same size and checksum are equal, and folder placeholders
+/// ([`ObjectMeta::is_placeholder_dir`]) are left out: a bucket written by a console that
+/// creates them doesn't differ from its copy without them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffOptions {
+    pub content_check: bool,
+    pub include_placeholders: bool,
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// compare the content of the objects without a checksum in common, see [`diff_prefixes_stream`]
+    pub fn content_check(mut self, content_check: bool) -> Self {
+        self.content_check = content_check;
+        self
+    }
+
+    /// compare folder placeholders like other objects
+    pub fn include_placeholders(mut self, include_placeholders: bool) -> Self {
+        self.include_placeholders = include_placeholders;
+        self
+    }
+}
+
+type Listing<'a> = Pin<Box<dyn Stream<Item = Result<ObjectMeta, NimbusError>> + Send + 'a>>;
+
+/// One side of the merge-join, with the object it stopped at
+struct Cursor<'a, S> {
+    storage: &'a S,
+    bucket: &'a str,
+    prefix: &'a str,
+    listing: Listing<'a>,
+    head: Option<ObjectMeta>,
+    /// key of the last object read, to catch a listing out of order
+    last: Option<String>,
+    done: bool,
+}
+
+impl<'a, S: StorageHelper + Sync + Send> Cursor<'a, S> {
+    fn new((storage, bucket, prefix): DiffSide<'a, S>, options: DiffOptions) -> Self {
+        let listing = storage
+            .list(bucket)
+            .prefix(prefix)
+            .skip_placeholders(!options.include_placeholders)
+            .stream();
+
+        Self {
+            storage,
+            bucket,
+            prefix,
+            listing: Box::pin(listing),
+            head: None,
+            last: None,
+            done: false,
+        }
+    }
+
+    /// read the next object unless the cursor is at one
+    async fn fill(&mut self) -> Result<(), NimbusError> {
+        if self.head.is_none() && !self.done {
+            match self.listing.try_next().await? {
+                Some(meta) => {
+                    if self.last.as_ref().is_some_and(|last| *last >= meta.key) {
+                        return Err(Error::Other(format!(
+                            "listing of {}/{} is not in key order at {}",
+                            self.bucket, self.prefix, meta.key
+                        ))
+                        .into());
+                    }
+                    self.last = Some(meta.key.clone());
+                    self.head = Some(meta);
+                }
+                None => self.done = true,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// key of `meta` relative to the prefix
+    fn relative(&self, meta: &ObjectMeta) -> String {
+        meta.key[self.prefix.len()..].to_owned()
+    }
+}
+
+/// compare the objects under two prefixes, see [`diff_prefixes_stream`]
+pub async fn diff_prefixes<A, B>(
+    a: DiffSide<'_, A>,
+    b: DiffSide<'_, B>,
+    options: DiffOptions,
+) -> Result<DiffReport, NimbusError>
+where
+    A: StorageHelper + Sync + Send,
+    B: StorageHelper + Sync + Send,
+{
+    diff_prefixes_stream(a, b, options)
+        .try_fold(DiffReport::default(), |mut report, entry| async move {
+            report.push(entry);
+            Ok(report)
+        })
+        .await
+}
+
+/// differences between the objects under two prefixes, in key order, as the listings are read
+///
+/// Objects under both prefixes are compared by size, then by CRC32C or MD5 if both listings return it.
+/// Without a checksum in common they are [`DiffReason::ChecksumUnavailable`], unless [`DiffOptions::content_check`] is set:
+/// their content is then downloaded by ranges of [`CONTENT_CHECK_CHUNK`] from both sides and compared.
+/// S3 listings never return checksums, so diffs involving S3 need `content_check` to tell objects apart.
+/// The stream ends after the first error.
+pub fn diff_prefixes_stream<'a, A, B>(
+    a: DiffSide<'a, A>,
+    b: DiffSide<'a, B>,
+    options: DiffOptions,
+) -> impl Stream<Item = Result<DiffEntry, NimbusError>> + Send + 'a
+where
+    A: StorageHelper + Sync + Send,
+    B: StorageHelper + Sync + Send,
+{
+    let cursors = (Cursor::new(a, options), Cursor::new(b, options));
+
+    futures::stream::try_unfold(cursors, move |(mut a, mut b)| async move {
+        loop {
+            a.fill().await?;
+            b.fill().await?;
+            let order = match (&a.head, &b.head) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(x), Some(y)) => x.key[a.prefix.len()..].cmp(&y.key[b.prefix.len()..]),
+            };
+
+            let entry = match order {
+                Ordering::Less => {
+                    let meta = a.head.take().expect("filled");
+                    Some(DiffEntry::OnlyInA {
+                        key: a.relative(&meta),
+                    })
+                }
+                Ordering::Greater => {
+                    let meta = b.head.take().expect("filled");
+                    Some(DiffEntry::OnlyInB {
+                        key: b.relative(&meta),
+                    })
+                }
+                Ordering::Equal => {
+                    let x = a.head.take().expect("filled");
+                    let y = b.head.take().expect("filled");
+                    compare(
+                        (a.storage, a.bucket, &x),
+                        (b.storage, b.bucket, &y),
+                        options.content_check,
+                    )
+                    .await?
+                    .map(|reason| DiffEntry::Differing {
+                        key: a.relative(&x),
+                        reason,
+                    })
+                }
+            };
+
+            if let Some(entry) = entry {
+                return Ok(Some((entry, (a, b))));
+            }
+        }
+    })
+}
+
+/// whether the checksums of two objects match, `None` when they have none in common
+fn checksums_match(x: &ObjectMeta, y: &ObjectMeta) -> Option<bool> {
+    match (&x.crc32c, &y.crc32c, &x.md5, &y.md5) {
+        (Some(cx), Some(cy), _, _) => Some(cx == cy),
+        (_, _, Some(mx), Some(my)) => Some(mx == my),
+        _ => None,
+    }
+}
+
+/// why two objects under the same relative key differ, `None` if they don't
+async fn compare<A, B>(
+    (a, a_bucket, x): (&A, &str, &ObjectMeta),
+    (b, b_bucket, y): (&B, &str, &ObjectMeta),
+    content_check: bool,
+) -> Result<Option<DiffReason>, NimbusError>
+where
+    A: StorageHelper + Sync + Send,
+    B: StorageHelper + Sync + Send,
+{
+    if x.size != y.size {
+        return Ok(Some(DiffReason::SizeMismatch {
+            a: x.size,
+            b: y.size,
+        }));
+    }
+
+    match checksums_match(x, y) {
+        Some(true) => return Ok(None),
+        Some(false) => return Ok(Some(DiffReason::ChecksumMismatch)),
+        None if !content_check => return Ok(Some(DiffReason::ChecksumUnavailable)),
+        None => {}
+    }
+
+    let chunk = CONTENT_CHECK_CHUNK.bytes();
+    let mut offset = 0;
+    while offset < x.size {
+        let (dx, dy) = futures::try_join!(
+            a.download_range(a_bucket, &x.key, offset, Some(offset + chunk - 1)),
+            b.download_range(b_bucket, &y.key, offset, Some(offset + chunk - 1)),
+        )?;
+
+        if let Some(i) = dx.iter().zip(&dy).position(|(p, q)| p != q) {
+            return Ok(Some(DiffReason::ContentMismatch {
+                offset: offset + i as u64,
+            }));
+        }
+        if dx.len() != dy.len() || dx.is_empty() {
+            // changed since it was listed
+            let offset = offset + dx.len().min(dy.len()) as u64;
+            return Ok(Some(DiffReason::ContentMismatch { offset }));
+        }
+
+        offset += dx.len() as u64;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::MemoryStorage;
+
+    use super::*;
+
+    async fn put(storage: &MemoryStorage, bucket: &str, key: &str, data: &[u8]) {
+        storage
+            .upload_from_bytes(bucket, key, None, data.to_vec())
+            .await
+            .unwrap();
+    }
+
+    fn meta(key: &str, size: u64) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_owned(),
+            size,
+            content_type: None,
+            content_encoding: None,
+            updated: None,
+            etag: None,
+            crc32c: None,
+            md5: None,
+            version: None,
+            storage_class: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn checksums_match_test() {
+        let plain = meta("k", 1);
+        let crc = |c: &str| ObjectMeta {
+            crc32c: Some(c.to_owned()),
+            ..plain.clone()
+        };
+        let md5 = |m: &str| ObjectMeta {
+            md5: Some(m.to_owned()),
+            ..plain.clone()
+        };
+
+        assert_eq!(checksums_match(&crc("x"), &crc("x")), Some(true));
+        assert_eq!(checksums_match(&crc("x"), &crc("y")), Some(false));
+        assert_eq!(checksums_match(&md5("x"), &md5("y")), Some(false));
+        assert_eq!(checksums_match(&crc("x"), &md5("x")), None);
+        assert_eq!(checksums_match(&plain, &plain), None);
+    }
+
+    #[tokio::test]
+    async fn diff_prefixes_test() {
+        let old = MemoryStorage::new();
+        let new = MemoryStorage::new();
+        for key in ["a", "b/1", "b/2", "c", "e"] {
+            put(&old, "old", &format!("data/{key}"), key.as_bytes()).await;
+        }
+        for key in ["b/1", "b/2", "c", "d"] {
+            put(&new, "new", key, key.as_bytes()).await;
+        }
+        put(&new, "new", "e", b"longer").await;
+        put(&new, "new", "c", b"C").await;
+        put(&old, "old", "other", b"").await;
+
+        let report = diff_prefixes(
+            (&old, "old", "data/"),
+            (&new, "new", ""),
+            DiffOptions::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.only_in_a, ["a"]);
+        assert_eq!(report.only_in_b, ["d"]);
+        assert_eq!(
+            report.differing,
+            [
+                Differing {
+                    key: "c".to_owned(),
+                    reason: DiffReason::ChecksumMismatch,
+                },
+                Differing {
+                    key: "e".to_owned(),
+                    reason: DiffReason::SizeMismatch { a: 1, b: 6 },
+                },
+            ]
+        );
+        assert!(!report.is_empty());
+
+        let same = diff_prefixes(
+            (&old, "old", "data/b/"),
+            (&new, "new", "b/"),
+            DiffOptions::new(),
+        )
+        .await
+        .unwrap();
+        assert!(same.is_empty());
+    }
+
+    #[tokio::test]
+    async fn diff_prefixes_stream_test() {
+        let storage = MemoryStorage::new();
+        for i in 0..30 {
+            put(&storage, "a", &format!("{i:02}"), b"x").await;
+            if i % 10 != 0 {
+                put(&storage, "b", &format!("{i:02}"), b"x").await;
+            }
+        }
+
+        let entries: Vec<DiffEntry> =
+            diff_prefixes_stream((&storage, "a", ""), (&storage, "b", ""), DiffOptions::new())
+                .try_collect()
+                .await
+                .unwrap();
+        let keys: Vec<&str> = entries.iter().map(DiffEntry::key).collect();
+        assert_eq!(keys, ["00", "10", "20"]);
+        assert!(matches!(entries[0], DiffEntry::OnlyInA { .. }));
+    }
+
+    #[tokio::test]
+    async fn diff_content_check_test() {
+        let storage = MemoryStorage::new().without_listing_checksums();
+        let mut data = vec![7; CONTENT_CHECK_CHUNK.as_usize() + 10];
+        put(&storage, "a", "same", &data).await;
+        put(&storage, "b", "same", &data).await;
+        put(&storage, "a", "empty", b"").await;
+        put(&storage, "b", "empty", b"").await;
+        put(&storage, "a", "changed", &data).await;
+        *data.last_mut().unwrap() = 8;
+        put(&storage, "b", "changed", &data).await;
+
+        let report = diff_prefixes((&storage, "a", ""), (&storage, "b", ""), DiffOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(report.differing.len(), 3);
+        assert!(report
+            .differing
+            .iter()
+            .all(|d| d.reason == DiffReason::ChecksumUnavailable));
+
+        storage.reset_stats();
+        let report = diff_prefixes(
+            (&storage, "a", ""),
+            (&storage, "b", ""),
+            DiffOptions::new().content_check(true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            report.differing,
+            [Differing {
+                key: "changed".to_owned(),
+                reason: DiffReason::ContentMismatch {
+                    offset: data.len() as u64 - 1
+                },
+            }]
+        );
+        // two ranges of each side of "changed" and "same"
+        assert_eq!(storage.stats().calls("download_range"), 8);
+    }
+
+    #[tokio::test]
+    async fn diff_placeholders_test() {
+        let storage = MemoryStorage::new();
+        for key in ["a/", "a/b/", "a/b/1", "c"] {
+            put(
+                &storage,
+                "gcs",
+                key,
+                if key.ends_with('/') { b"" } else { b"x" },
+            )
+            .await;
+        }
+        for key in ["a/b/1", "c"] {
+            put(&storage, "s3", key, b"x").await;
+        }
+        // holds data, not a placeholder
+        put(&storage, "gcs", "d/", b"x").await;
+
+        let report = diff_prefixes(
+            (&storage, "gcs", ""),
+            (&storage, "s3", ""),
+            DiffOptions::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.only_in_a, ["d/"]);
+        assert!(report.differing.is_empty());
+
+        let options = DiffOptions::new().include_placeholders(true);
+        let report = diff_prefixes((&storage, "gcs", ""), (&storage, "s3", ""), options)
+            .await
+            .unwrap();
+        assert_eq!(report.only_in_a, ["a/", "a/b/", "d/"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn diff_report_serde_test() {
+        let mut report = DiffReport::default();
+        report.push(DiffEntry::OnlyInA {
+            key: "a".to_owned(),
+        });
+        report.push(DiffEntry::Differing {
+            key: "c".to_owned(),
+            reason: DiffReason::SizeMismatch { a: 1, b: 2 },
+        });
+
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "only_in_a": ["a"],
+                "only_in_b": [],
+                "differing": [{"key": "c", "reason": "size_mismatch", "a": 1, "b": 2}],
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(DiffEntry::OnlyInB {
+                key: "b".to_owned()
+            })
+            .unwrap(),
+            serde_json::json!({"kind": "only_in_b", "key": "b"})
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use super::Error;
+
+/// Algorithm of customer-supplied keys, the only one both providers accept
+pub const ENCRYPTION_ALGORITHM: &str = "AES256";
+
+/// Customer-supplied AES-256 key an object is encrypted with: CSEK on GCS, SSE-C on S3
+///
+/// The provider only keeps a hash of the key, every call reading the object's content needs it again.
+/// The key never shows in `Debug` output nor in errors.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// key from its base64, as generated by e.g. `openssl rand -base64 32`
+    pub fn from_base64(b64: &str) -> Result<Self, Error> {
+        base64::engine::general_purpose::STANDARD
+            .decode(b64.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .map(Self::new)
+            .ok_or_else(|| Error::Other("Encryption key must be the base64 of 32 bytes".to_owned()))
+    }
+
+    pub fn key_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.key)
+    }
+
+    /// base64 of the SHA-256 of the key, identifies the key on GCS
+    pub fn sha256_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(self.key))
+    }
+
+    /// base64 of the MD5 of the key, checks the key made it intact on S3
+    #[cfg(feature = "aws-storage")]
+    pub fn md5_base64(&self) -> String {
+        use md5::Md5;
+
+        base64::engine::general_purpose::STANDARD.encode(Md5::digest(self.key))
+    }
+
+    /// request headers supplying the key to GCS, `copy_source` for the source of a rewrite
+    pub fn gcs_headers(&self, copy_source: bool) -> Vec<(String, String)> {
+        let prefix = if copy_source {
+            "x-goog-copy-source-encryption"
+        } else {
+            "x-goog-encryption"
+        };
+
+        vec![
+            (
+                format!("{prefix}-algorithm"),
+                ENCRYPTION_ALGORITHM.to_owned(),
+            ),
+            (format!("{prefix}-key"), self.key_base64()),
+            (format!("{prefix}-key-sha256"), self.sha256_base64()),
+        ]
+    }
+
+    /// request headers supplying the key to S3, `copy_source` for the source of a copy
+    #[cfg(feature = "aws-storage")]
+    pub fn s3_headers(&self, copy_source: bool) -> Vec<(String, String)> {
+        let prefix = if copy_source {
+            "x-amz-copy-source-server-side-encryption-customer"
+        } else {
+            "x-amz-server-side-encryption-customer"
+        };
+
+        vec![
+            (
+                format!("{prefix}-algorithm"),
+                ENCRYPTION_ALGORITHM.to_owned(),
+            ),
+            (format!("{prefix}-key"), self.key_base64()),
+            (format!("{prefix}-key-MD5"), self.md5_base64()),
+        ]
+    }
+
+    #[cfg(feature = "gcp-storage")]
+    pub(crate) fn to_gcs(&self) -> google_cloud_storage::http::objects::Encryption {
+        google_cloud_storage::http::objects::Encryption {
+            encryption_algorithm: ENCRYPTION_ALGORITHM.to_owned(),
+            encryption_key: self.key_base64(),
+            encryption_key_sha256: self.sha256_base64(),
+        }
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+/// whether a GCS error tells the object needs a customer-supplied key the call didn't (correctly) supply
+#[cfg(feature = "gcp-storage")]
+pub(crate) fn gcs_key_required(e: &google_cloud_storage::http::error::ErrorResponse) -> bool {
+    e.errors.iter().any(|item| {
+        matches!(
+            item.reason.as_str(),
+            "resourceIsEncryptedWithCustomerEncryptionKey"
+                | "customerEncryptionKeyIsIncorrect"
+                | "customerEncryptionKeySha256IsInvalid"
+        )
+    })
+}
+
+/// whether an S3 error tells the object needs a customer-supplied key the call didn't (correctly) supply
+/// S3 answers a bare 400 to head requests, those stay unclassified
+#[cfg(feature = "aws-storage")]
+pub(crate) fn s3_key_required(code: Option<&str>, message: Option<&str>) -> bool {
+    let message = message.unwrap_or_default();
+    match code {
+        Some("InvalidRequest") => message.contains("Server Side Encryption"),
+        Some("AccessDenied") => message.contains("MD5 hash of the key"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> EncryptionKey {
+        EncryptionKey::new([7; 32])
+    }
+
+    #[test]
+    fn encryption_key_test() {
+        let key = key();
+        assert_eq!(
+            key.key_base64(),
+            "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc="
+        );
+        assert_eq!(EncryptionKey::from_base64(&key.key_base64()).unwrap(), key);
+        assert!(EncryptionKey::from_base64("c2hvcnQ=").is_err());
+        assert!(EncryptionKey::from_base64("not base64").is_err());
+
+        let debug = format!("{:?}", Some(key.clone()));
+        assert!(!debug.contains(&key.key_base64()));
+        assert!(debug.contains("redacted"));
+    }
+
+    #[test]
+    fn gcs_headers_test() {
+        let key = key();
+        let headers = key.gcs_headers(false);
+        assert_eq!(
+            headers[0],
+            (
+                "x-goog-encryption-algorithm".to_owned(),
+                "AES256".to_owned()
+            )
+        );
+        assert_eq!(headers[1].1, key.key_base64());
+        assert_eq!(headers[2].0, "x-goog-encryption-key-sha256");
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(&headers[2].1)
+                .unwrap(),
+            Sha256::digest([7; 32]).to_vec()
+        );
+
+        assert!(key
+            .gcs_headers(true)
+            .iter()
+            .all(|(name, _)| name.starts_with("x-goog-copy-source-encryption-")));
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[test]
+    fn s3_headers_test() {
+        let headers = key().s3_headers(false);
+        assert_eq!(
+            headers
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "x-amz-server-side-encryption-customer-algorithm",
+                "x-amz-server-side-encryption-customer-key",
+                "x-amz-server-side-encryption-customer-key-MD5",
+            ]
+        );
+        assert_eq!(headers[2].1.len(), 24);
+        assert!(key().s3_headers(true)[0]
+            .0
+            .starts_with("x-amz-copy-source-server-side-encryption-customer"));
+
+        assert!(s3_key_required(
+            Some("InvalidRequest"),
+            Some("The object was stored using a form of Server Side Encryption. The correct parameters must be provided to retrieve the object.")
+        ));
+        assert!(!s3_key_required(Some("InvalidRequest"), Some("other")));
+        assert!(!s3_key_required(None, None));
+    }
+}
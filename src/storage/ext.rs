@@ -0,0 +1,87 @@
+use chrono::{DateTime, Utc};
+
+/// Borrowing accessors of the object metadata returned by the provider clients
+///
+/// Prefer them to digging through the nested options, e.g. `object.metadata.unwrap()["owner"]`,
+/// which panics on objects without custom metadata. Nothing is cloned. For a provider neutral,
+/// owned view of an object, see [`super::ObjectMeta`].
+pub trait ObjectExt {
+    fn content_type(&self) -> Option<&str>;
+
+    /// base64 of the big-endian CRC32C, as [`super::crc32c_base64`]; S3 only returns it when
+    /// the object was uploaded with one and asked for
+    fn crc32c(&self) -> Option<&str>;
+
+    /// value of the custom metadata `name`, S3 returns the names lowercase
+    fn metadata_value(&self, name: &str) -> Option<&str>;
+
+    fn updated(&self) -> Option<DateTime<Utc>>;
+}
+
+#[cfg(feature = "gcp-storage")]
+impl ObjectExt for google_cloud_storage::http::objects::Object {
+    fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    fn crc32c(&self) -> Option<&str> {
+        self.crc32c.as_deref()
+    }
+
+    fn metadata_value(&self, name: &str) -> Option<&str> {
+        self.metadata.as_ref()?.get(name).map(String::as_str)
+    }
+
+    fn updated(&self) -> Option<DateTime<Utc>> {
+        let t = self.updated?;
+        crate::time::from_unix(t.unix_timestamp(), t.nanosecond()).ok()
+    }
+}
+
+#[cfg(feature = "aws-storage")]
+impl ObjectExt for aws_sdk_s3::operation::head_object::HeadObjectOutput {
+    fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    fn crc32c(&self) -> Option<&str> {
+        self.checksum_crc32_c.as_deref()
+    }
+
+    fn metadata_value(&self, name: &str) -> Option<&str> {
+        self.metadata.as_ref()?.get(name).map(String::as_str)
+    }
+
+    fn updated(&self) -> Option<DateTime<Utc>> {
+        crate::time::from_aws_datetime(self.last_modified.as_ref()?).ok()
+    }
+}
+
+#[cfg(feature = "aws-storage")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn head_object_ext_test() {
+        use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+
+        let out = HeadObjectOutput::builder()
+            .content_type("text/plain")
+            .checksum_crc32_c("4waSgw==")
+            .metadata("owner", "team-a")
+            .last_modified(aws_sdk_s3::primitives::DateTime::from_secs(1_700_000_000))
+            .build();
+        assert_eq!(ObjectExt::content_type(&out), Some("text/plain"));
+        assert_eq!(out.crc32c(), Some("4waSgw=="));
+        assert_eq!(out.metadata_value("owner"), Some("team-a"));
+        assert_eq!(out.metadata_value("other"), None);
+        assert_eq!(out.updated().map(|t| t.timestamp()), Some(1_700_000_000));
+
+        let bare = HeadObjectOutput::builder().build();
+        assert_eq!(
+            (bare.crc32c(), bare.metadata_value("owner"), bare.updated()),
+            (None, None, None)
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use super::Error;
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// `Content-Encoding` value of gzip compressed objects
+pub(crate) fn is_gzip_encoding(encoding: Option<&str>) -> bool {
+    encoding.is_some_and(|e| e.trim().eq_ignore_ascii_case("gzip"))
+}
+
+/// whether `data` starts like a gzip stream
+pub(crate) fn has_gzip_magic(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+pub(crate) fn gunzip(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = vec![];
+    GzDecoder::new(data)
+        .read_to_end(&mut out)
+        .map_err(Error::IO)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gunzip_test() {
+        let compressed = gzip(b"Hello World");
+        assert!(has_gzip_magic(&compressed));
+        assert_eq!(gunzip(&compressed).unwrap(), b"Hello World");
+
+        assert!(!has_gzip_magic(b"Hello World"));
+        assert!(gunzip(b"Hello World").is_err());
+    }
+
+    #[test]
+    fn is_gzip_encoding_test() {
+        assert!(is_gzip_encoding(Some("gzip")));
+        assert!(is_gzip_encoding(Some("GZIP ")));
+        assert!(!is_gzip_encoding(Some("br")));
+        assert!(!is_gzip_encoding(None));
+    }
+}
@@ -0,0 +1,313 @@
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Error, Precondition, StorageHelper};
+use crate::redact::json_error;
+use crate::retry::{Backoff, ExponentialFullJitter};
+use crate::{ErrorCode, NimbusError};
+
+/// Attempts of [`StorageHelper::update_json`] unless set with [`UpdateOptions::max_attempts`]
+pub const DEFAULT_UPDATE_ATTEMPTS: u32 = 10;
+
+/// Content type of the documents written by [`StorageHelper::update_json`]
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Options of [`StorageHelper::update_json`] and [`StorageHelper::append_ndjson`]
+///
+/// The delay before retrying a conflicting update is drawn with [`ExponentialFullJitter`]
+/// from `base_delay` and `max_delay`, so writers in a conflict don't collide again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateOptions {
+    /// read-modify-write cycles before giving up, the first one included
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_UPDATE_ATTEMPTS,
+            base_delay: Duration::from_millis(20),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl UpdateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+/// Value committed by [`StorageHelper::update_json`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonUpdate<T> {
+    pub value: T,
+    /// version of the object holding `value`
+    pub version: String,
+    /// cycles run again because another writer updated the object in between
+    pub retries: u32,
+}
+
+/// `value` pretty-printed with the keys of every object sorted, and a trailing newline
+pub fn to_json_document<T: Serialize>(value: &T) -> Result<Vec<u8>, NimbusError> {
+    // going through `Value` sorts the keys, maps included
+    let value = serde_json::to_value(value).map_err(|e| Error::InvalidJson(json_error(&e)))?;
+    let mut data = serde_json::to_vec_pretty(&value).expect("a json value is always serializable");
+    data.push(b'\n');
+    Ok(data)
+}
+
+pub(crate) async fn update<S, T, E, F>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    f: F,
+    options: UpdateOptions,
+) -> Result<JsonUpdate<T>, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+    T: Serialize + DeserializeOwned,
+    E: Into<NimbusError>,
+    F: Fn(Option<T>) -> Result<T, E>,
+{
+    let mut backoff = ExponentialFullJitter::new(options.base_delay, options.max_delay)
+        .max_attempts(options.max_attempts.max(1));
+
+    let mut retries = 0;
+    loop {
+        let (current, precondition) = match storage.download_versioned(bucket, key).await {
+            Ok((data, version)) => (Some(data), Precondition::VersionMatches(version)),
+            Err(e) if e.code() == ErrorCode::NotFound => (None, Precondition::DoesNotExist),
+            Err(e) => return Err(e),
+        };
+        let previous = current
+            .as_deref()
+            .map(|data| {
+                serde_json::from_slice(data)
+                    .map_err(|e| Error::InvalidJson(format!("{bucket}/{key}: {}", json_error(&e))))
+            })
+            .transpose()?;
+
+        let value = f(previous).map_err(Into::into)?;
+        let data = to_json_document(&value)?;
+
+        if let (Some(current), Precondition::VersionMatches(version)) = (&current, &precondition) {
+            // nothing to write, the document is current as of the download
+            if *current == data {
+                let version = version.clone();
+                return Ok(JsonUpdate {
+                    value,
+                    version,
+                    retries,
+                });
+            }
+        }
+
+        let mime = Some(JSON_CONTENT_TYPE.to_owned());
+        let error = match storage
+            .upload_conditional(bucket, key, mime, data, &precondition)
+            .await
+        {
+            Ok(version) => {
+                return Ok(JsonUpdate {
+                    value,
+                    version,
+                    retries,
+                })
+            }
+            Err(e) if e.code() == ErrorCode::PreconditionFailed => e,
+            Err(e) => return Err(e),
+        };
+
+        retries += 1;
+        match backoff.next_delay(retries, &error) {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => {
+                return Err(Error::UpdateConflict {
+                    key: format!("{bucket}/{key}"),
+                    attempts: retries,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct State {
+        count: u64,
+        labels: HashMap<String, String>,
+    }
+
+    fn increment(state: Option<State>) -> Result<State, NimbusError> {
+        let mut state = state.unwrap_or_default();
+        state.count += 1;
+        Ok(state)
+    }
+
+    #[test]
+    fn json_document_test() {
+        let state = State {
+            count: 1,
+            labels: HashMap::from([
+                ("zone".to_owned(), "b".to_owned()),
+                ("app".to_owned(), "a".to_owned()),
+            ]),
+        };
+        let data = to_json_document(&state).unwrap();
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "{\n  \"count\": 1,\n  \"labels\": {\n    \"app\": \"a\",\n    \"zone\": \"b\"\n  }\n}\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_json_test() {
+        let storage = MemoryStorage::new();
+
+        let created = storage
+            .update_json("b", "state.json", increment, UpdateOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(created.value.count, 1);
+        assert_eq!(created.retries, 0);
+        let meta = storage.object_metadata("b", "state.json").await.unwrap();
+        assert_eq!(meta.content_type.as_deref(), Some(JSON_CONTENT_TYPE));
+
+        let updated = storage
+            .update_json("b", "state.json", increment, UpdateOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(updated.value.count, 2);
+        assert_ne!(updated.version, created.version);
+
+        // unchanged, not written again
+        let same = storage
+            .update_json(
+                "b",
+                "state.json",
+                |state: Option<State>| Ok::<_, NimbusError>(state.unwrap()),
+                UpdateOptions::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(same.version, updated.version);
+        assert_eq!(storage.stats().calls("upload_conditional"), 2);
+
+        // the closure aborts the update
+        let err = storage
+            .update_json(
+                "b",
+                "state.json",
+                |_: Option<State>| Err(NimbusError::Other("rejected".to_owned())),
+                UpdateOptions::new(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NimbusError::Other(_)));
+
+        storage
+            .upload_from_bytes("b", "bad.json", None, b"not json".to_vec())
+            .await
+            .unwrap();
+        let err = storage
+            .update_json("b", "bad.json", increment, UpdateOptions::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::InvalidJson(_))
+        ));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_json_concurrency_test() {
+        let storage = Arc::new(MemoryStorage::new());
+        // widen the window between the download and the upload, checked once the latency elapsed
+        storage.mock_stats().set_fault(
+            "upload_conditional",
+            Fault::new().latency(Latency::Uniform {
+                min: Duration::from_millis(1),
+                max: Duration::from_millis(10),
+            }),
+        );
+
+        let updates = (0..20).map(|_| {
+            let storage = Arc::clone(&storage);
+            tokio::spawn(async move {
+                storage
+                    .update_json(
+                        "b",
+                        "counter.json",
+                        increment,
+                        UpdateOptions::new().max_attempts(100),
+                    )
+                    .await
+                    .unwrap()
+            })
+        });
+        let updates: Vec<_> = futures::future::try_join_all(updates).await.unwrap();
+
+        let (data, _) = storage
+            .download_versioned("b", "counter.json")
+            .await
+            .unwrap();
+        let state: State = serde_json::from_slice(&data).unwrap();
+        assert_eq!(state.count, 20);
+        // every increment committed a distinct value
+        let mut counts: Vec<_> = updates.iter().map(|u| u.value.count).collect();
+        counts.sort();
+        assert_eq!(counts, (1..=20).collect::<Vec<_>>());
+        assert!(updates.iter().any(|u| u.retries > 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_json_gives_up_test() {
+        let storage = MemoryStorage::new();
+        storage.mock_stats().set_fault(
+            "upload_conditional",
+            Fault::new().fail(1.0, ErrorCode::PreconditionFailed),
+        );
+
+        let err = storage
+            .update_json("b", "k", increment, UpdateOptions::new().max_attempts(3))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::UpdateConflict { attempts: 3, .. })
+        ));
+        assert_eq!(err.code(), ErrorCode::PreconditionFailed);
+        assert_eq!(storage.stats().calls("upload_conditional"), 3);
+    }
+}
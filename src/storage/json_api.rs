@@ -0,0 +1,210 @@
+//! GCS requests through the JSON API that the storage client can't make: listing and restoring
+//! soft-deleted objects, and downloading gzip objects as stored
+//!
+//! The client keeps its credentials to itself, so these requests are authenticated with the
+//! application default credentials, as [`crate::LazyHandle::storage`] does.
+
+use std::sync::Arc;
+
+use google_cloud_storage::client::ClientConfig;
+use google_cloud_storage::http::error::ErrorResponse;
+use google_cloud_storage::http::objects::list::ListObjectsResponse;
+use google_cloud_storage::http::Error as HttpError;
+use google_cloud_token::TokenSource;
+use reqwest::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_LENGTH};
+use reqwest::{RequestBuilder, Url};
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+use super::Error;
+use crate::{ListLimits, NimbusError};
+
+/// token source and endpoint, resolved on first use
+struct Api {
+    token: Arc<dyn TokenSource>,
+    endpoint: String,
+    http: reqwest::Client,
+}
+
+static API: OnceCell<Api> = OnceCell::const_new();
+
+async fn api() -> Result<&'static Api, NimbusError> {
+    API.get_or_try_init(|| async {
+        let config = ClientConfig::default()
+            .with_auth()
+            .await
+            .map_err(Error::StorageAuth)?;
+        let token = config
+            .token_source_provider
+            .ok_or_else(|| Error::Other("no GCS credentials".to_owned()))?
+            .token_source();
+
+        Ok(Api {
+            token,
+            endpoint: config.storage_endpoint,
+            http: reqwest::Client::new(),
+        })
+    })
+    .await
+}
+
+impl Api {
+    /// `{endpoint}/storage/v1/b/{bucket}/o` followed by `segments`, each percent-encoded
+    fn objects_url(&self, bucket: &str, segments: &[&str]) -> Result<Url, NimbusError> {
+        let mut url = Url::parse(&self.endpoint)
+            .map_err(|e| Error::Other(format!("GCS endpoint {}: {e}", self.endpoint)))?;
+        url.path_segments_mut()
+            .map_err(|_| Error::Other(format!("GCS endpoint {}", self.endpoint)))?
+            .pop_if_empty()
+            .extend(["storage", "v1", "b", bucket, "o"])
+            .extend(segments);
+        Ok(url)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Vec<u8>, NimbusError> {
+        let token = self
+            .token
+            .token()
+            .await
+            .map_err(|e| Error::Storage(HttpError::TokenSource(e)))?;
+        let response = request
+            .header(AUTHORIZATION, token)
+            .send()
+            .await
+            .map_err(|e| Error::Storage(e.into()))?;
+
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| Error::Storage(e.into()))?;
+        if !status.is_success() {
+            let error = serde_json::from_slice::<ErrorBody>(&body)
+                .map(|b| b.error)
+                .unwrap_or_else(|_| ErrorResponse {
+                    code: status.as_u16(),
+                    errors: vec![],
+                    message: String::from_utf8_lossy(&body).into_owned(),
+                });
+            return Err(Error::Storage(error.into()).into());
+        }
+
+        Ok(body.to_vec())
+    }
+}
+
+/// the error of a GCS JSON API response
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorResponse,
+}
+
+/// soft-deleted objects of `bucket` under `prefix`, as keys and generations
+pub(crate) async fn list_soft_deleted(
+    bucket: &str,
+    prefix: Option<String>,
+    limits: ListLimits,
+) -> Result<Vec<(String, i64)>, NimbusError> {
+    let api = api().await?;
+    let url = api.objects_url(bucket, &[])?;
+
+    let mut drain = limits.drain();
+    let mut deleted = vec![];
+    let mut page_token = None;
+    loop {
+        let mut query = vec![("softDeleted", "true".to_owned())];
+        query.extend(prefix.clone().map(|p| ("prefix", p)));
+        query.extend(page_token.map(|t| ("pageToken", t)));
+
+        let body = api.send(api.http.get(url.clone()).query(&query)).await?;
+        let page: ListObjectsResponse = serde_json::from_slice(&body)
+            .map_err(|e| Error::Other(format!("soft-deleted objects of {bucket}: {e}")))?;
+
+        let objects: Vec<_> = page
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .map(|o| (o.name, o.generation))
+            .collect();
+        drain.page(objects.len(), page.next_page_token.is_some())?;
+        deleted.extend(objects);
+
+        match page.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => return Ok(deleted),
+        }
+    }
+}
+
+/// restore the soft-deleted `generation` of `key`, as long as no live object replaced it
+pub(crate) async fn restore(bucket: &str, key: &str, generation: i64) -> Result<(), NimbusError> {
+    let api = api().await?;
+    let url = api.objects_url(bucket, &[key, "restore"])?;
+
+    let query = [
+        ("generation", generation.to_string()),
+        // fails with 412 if a live object exists
+        ("ifGenerationMatch", "0".to_owned()),
+    ];
+    api.send(api.http.post(url).query(&query).header(CONTENT_LENGTH, 0))
+        .await?;
+
+    Ok(())
+}
+
+/// the `generation` of `key` as stored, without GCS decompressing gzip objects
+pub(crate) async fn download_stored(
+    bucket: &str,
+    key: &str,
+    generation: i64,
+) -> Result<Vec<u8>, NimbusError> {
+    let api = api().await?;
+    let url = api.objects_url(bucket, &[key])?;
+
+    let query = [
+        ("alt", "media".to_owned()),
+        ("generation", generation.to_string()),
+    ];
+    // GCS serves gzip objects compressed to clients accepting gzip
+    api.send(
+        api.http
+            .get(url)
+            .query(&query)
+            .header(ACCEPT_ENCODING, "gzip"),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_url_test() {
+        let api = Api {
+            token: Arc::new(NoToken),
+            endpoint: "https://storage.googleapis.com".to_owned(),
+            http: reqwest::Client::new(),
+        };
+        assert_eq!(
+            api.objects_url("b", &["dir/a b", "restore"])
+                .unwrap()
+                .as_str(),
+            "https://storage.googleapis.com/storage/v1/b/b/o/dir%2Fa%20b/restore"
+        );
+        assert_eq!(
+            api.objects_url("b", &[]).unwrap().as_str(),
+            "https://storage.googleapis.com/storage/v1/b/b/o"
+        );
+    }
+
+    #[derive(Debug)]
+    struct NoToken;
+
+    #[async_trait::async_trait]
+    impl TokenSource for NoToken {
+        async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            Err("no token".into())
+        }
+    }
+}
@@ -0,0 +1,674 @@
+//! Key-value store over the objects under a prefix of a bucket
+//!
+//! Each entry is an object: its key is the entry key [encoded](KvStore::object_key) under the prefix,
+//! its content the value. Entries can expire: the expiry is kept in the custom metadata of the object
+//! (`nimbus-expires`, milliseconds since the epoch), so reads check it from the metadata of the object
+//! before downloading it. Expired entries read as missing and are deleted by the read
+//! that finds them, nothing else removes them: a lifecycle rule on the prefix keeps the bucket clean.
+//!
+//! ```ignore
+//! let sessions = KvStore::new(&client, "app-state", "sessions/").ttl(Duration::from_secs(3600));
+//! sessions.put_json("user:42", &session).await?;
+//! let session: Option<Session> = sessions.get_json("user:42").await?;
+//! let count = sessions.update("visits", |n| Ok::<_, NimbusError>(Some(bump(n)))).await?;
+//! ```
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::lease::to_chrono;
+use super::{Error, Precondition, StorageHelper, UpdateOptions, JSON_CONTENT_TYPE};
+use crate::redact::json_error;
+use crate::retry::{Backoff, ExponentialFullJitter};
+use crate::{ErrorCode, NimbusError};
+
+/// Content type of the values written with [`KvStore::put`]
+pub const KV_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// custom metadata holding the expiry of an entry, lowercase as S3 returns it
+const EXPIRES_KEY: &str = "nimbus-expires";
+
+/// longest object key GCS and S3 take, in bytes
+const MAX_OBJECT_KEY_LEN: usize = 1024;
+
+/// reads of an entry replaced between its metadata and its content before giving up
+const READ_ATTEMPTS: u32 = 3;
+
+/// `key` with every byte but ASCII letters, digits, `-`, `_` and `~` percent-encoded
+///
+/// The encoding is a prefix of the encoding of every key `key` is a prefix of, so listings by prefix work.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'~') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+/// key encoded by [`encode_key`], `None` for what it can't have produced
+fn decode_key(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            if hex.bytes().any(|b| b.is_ascii_lowercase()) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'~') {
+            bytes.push(b);
+            rest = tail;
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// custom metadata carrying the expiry of an entry
+fn metadata(expires_at: Option<DateTime<Utc>>) -> HashMap<String, String> {
+    expires_at
+        .map(|at| (EXPIRES_KEY.to_owned(), at.timestamp_millis().to_string()))
+        .into_iter()
+        .collect()
+}
+
+/// expiry of an entry from the custom metadata of its object, `None` if it doesn't expire
+fn expires_at(metadata: Option<&HashMap<String, String>>) -> Option<DateTime<Utc>> {
+    let value = metadata?.get(EXPIRES_KEY)?;
+    DateTime::from_timestamp_millis(value.trim().parse().ok()?)
+}
+
+/// an entry expires once its expiry is reached
+fn is_expired(metadata: Option<&HashMap<String, String>>, now: DateTime<Utc>) -> bool {
+    expires_at(metadata).is_some_and(|at| at <= now)
+}
+
+/// state of an entry as read
+enum Current {
+    Missing,
+    /// the version of the expired object
+    Expired(String),
+    /// the value and its version
+    Live(Vec<u8>, String),
+}
+
+impl Current {
+    fn precondition(&self) -> Precondition {
+        match self {
+            Current::Missing => Precondition::DoesNotExist,
+            Current::Expired(version) | Current::Live(_, version) => {
+                Precondition::VersionMatches(version.clone())
+            }
+        }
+    }
+}
+
+/// Key-value store over the objects under a prefix of a bucket, see the [module](self) docs
+///
+/// Any string is a key, `/` and unicode included. Keys are listed by [`KvStore::list_keys`] in the
+/// lexicographic order of their encoding, which isn't the order of the keys themselves.
+pub struct KvStore<'a, S: StorageHelper + Sync> {
+    storage: &'a S,
+    bucket: String,
+    prefix: String,
+    ttl: Option<Duration>,
+    options: UpdateOptions,
+}
+
+impl<'a, S: StorageHelper + Sync> KvStore<'a, S> {
+    /// entries are stored under `prefix`, e.g. `sessions/`, and don't expire
+    pub fn new(storage: &'a S, bucket: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            storage,
+            bucket: bucket.into(),
+            prefix: prefix.into(),
+            ttl: None,
+            options: UpdateOptions::default(),
+        }
+    }
+
+    /// entries written from now on expire `ttl` after their write, see [`KvStore::put_with_ttl`]
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// attempts and backoff of [`KvStore::update`] when another writer wins the race
+    pub fn update_options(mut self, options: UpdateOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// object key of the entry `key`
+    /// fails with [`ErrorCode::InvalidInput`] for an empty key or one too long once encoded
+    pub fn object_key(&self, key: &str) -> Result<String, NimbusError> {
+        if key.is_empty() {
+            return Err(Error::InvalidKey {
+                key: key.to_owned(),
+                reason: "empty",
+            }
+            .into());
+        }
+        let object_key = format!("{}{}", self.prefix, encode_key(key));
+        if object_key.len() > MAX_OBJECT_KEY_LEN {
+            return Err(Error::InvalidKey {
+                key: key.to_owned(),
+                reason: "longer than 1024 bytes once encoded",
+            }
+            .into());
+        }
+        Ok(object_key)
+    }
+
+    /// value of `key`, `None` if missing or expired
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, NimbusError> {
+        let object_key = self.object_key(key)?;
+        match self.read(&object_key).await? {
+            Current::Live(data, _) => Ok(Some(data)),
+            Current::Expired(version) => {
+                self.delete_expired(&object_key, &version).await?;
+                Ok(None)
+            }
+            Current::Missing => Ok(None),
+        }
+    }
+
+    /// set `key` to `value`, expiring after the TTL of the store if any
+    pub async fn put(&self, key: &str, value: Vec<u8>) -> Result<(), NimbusError> {
+        self.put_with_ttl(key, value, self.ttl).await
+    }
+
+    /// set `key` to `value`, expiring `ttl` after now, never with `None`
+    pub async fn put_with_ttl(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), NimbusError> {
+        let object_key = self.object_key(key)?;
+        let expires_at = ttl.map(|ttl| Utc::now() + to_chrono(ttl));
+        self.storage
+            .upload_with_metadata(
+                &self.bucket,
+                &object_key,
+                Some(KV_CONTENT_TYPE.to_owned()),
+                metadata(expires_at),
+                value,
+                None,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    /// remove `key`, a missing key is removed already
+    pub async fn delete(&self, key: &str) -> Result<(), NimbusError> {
+        let object_key = self.object_key(key)?;
+        match self.storage.delete_file(&self.bucket, &object_key).await {
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    /// keys starting with `prefix`, expired ones included: listings don't tell the expiry
+    ///
+    /// Every page is listed within the default [`crate::ListLimits`]. Objects under the prefix of the store
+    /// that aren't entries are skipped.
+    pub async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, NimbusError> {
+        let list_prefix = format!("{}{}", self.prefix, encode_key(prefix));
+        let objects = self
+            .storage
+            .list(&self.bucket)
+            .prefix(list_prefix)
+            .collect()
+            .await?;
+        Ok(objects
+            .iter()
+            .filter_map(|o| decode_key(o.key.strip_prefix(&self.prefix)?))
+            .collect())
+    }
+
+    /// value of `key` deserialized from JSON, `None` if missing or expired
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, NimbusError> {
+        self.get(key)
+            .await?
+            .map(|data| self.parse_json(key, &data))
+            .transpose()
+    }
+
+    /// set `key` to `value` serialized as JSON, expiring after the TTL of the store if any
+    pub async fn put_json<T: Serialize>(&self, key: &str, value: &T) -> Result<(), NimbusError> {
+        let object_key = self.object_key(key)?;
+        let data = serde_json::to_vec(value).map_err(|e| Error::InvalidJson(json_error(&e)))?;
+        let expires_at = self.ttl.map(|ttl| Utc::now() + to_chrono(ttl));
+        self.storage
+            .upload_with_metadata(
+                &self.bucket,
+                &object_key,
+                Some(JSON_CONTENT_TYPE.to_owned()),
+                metadata(expires_at),
+                data,
+                None,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    /// atomically replace the value of `key` with what `f` returns for the current one, and return it
+    ///
+    /// `f` gets `None` for a missing or expired entry, and returns `None` to delete the entry. The result is
+    /// written only if the entry wasn't written since it was read, otherwise the cycle runs again after a
+    /// backoff, up to the [attempts](KvStore::update_options) of the store, then fails with
+    /// [`Error::UpdateConflict`]. `f` may thus be called several times, and an error it returns aborts the
+    /// update. The new value expires after the TTL of the store if any.
+    pub async fn update<E, F>(&self, key: &str, f: F) -> Result<Option<Vec<u8>>, NimbusError>
+    where
+        E: Into<NimbusError>,
+        F: Fn(Option<Vec<u8>>) -> Result<Option<Vec<u8>>, E>,
+    {
+        self.update_as(key, KV_CONTENT_TYPE, f).await
+    }
+
+    /// [`KvStore::update`] of a value serialized as JSON
+    pub async fn update_json<T, E, F>(&self, key: &str, f: F) -> Result<Option<T>, NimbusError>
+    where
+        T: Serialize + DeserializeOwned,
+        E: Into<NimbusError>,
+        F: Fn(Option<T>) -> Result<Option<T>, E>,
+    {
+        let updated = self
+            .update_as(key, JSON_CONTENT_TYPE, |current| {
+                let current = current
+                    .map(|data| self.parse_json(key, &data))
+                    .transpose()?;
+                f(current)
+                    .map_err(Into::<NimbusError>::into)?
+                    .map(|value| {
+                        serde_json::to_vec(&value).map_err(|e| Error::InvalidJson(json_error(&e)))
+                    })
+                    .transpose()
+                    .map_err(NimbusError::from)
+            })
+            .await?;
+        updated.map(|data| self.parse_json(key, &data)).transpose()
+    }
+
+    async fn update_as<E, F>(
+        &self,
+        key: &str,
+        mime: &str,
+        f: F,
+    ) -> Result<Option<Vec<u8>>, NimbusError>
+    where
+        E: Into<NimbusError>,
+        F: Fn(Option<Vec<u8>>) -> Result<Option<Vec<u8>>, E>,
+    {
+        let object_key = self.object_key(key)?;
+        let mut backoff =
+            ExponentialFullJitter::new(self.options.base_delay, self.options.max_delay)
+                .max_attempts(self.options.max_attempts.max(1));
+
+        let mut retries = 0;
+        loop {
+            let current = self.read(&object_key).await?;
+            let precondition = current.precondition();
+            let value = match current {
+                Current::Live(data, _) => Some(data),
+                Current::Missing | Current::Expired(_) => None,
+            };
+
+            let updated = f(value).map_err(Into::into)?;
+            let res = match (&updated, precondition) {
+                (None, Precondition::DoesNotExist) => return Ok(None),
+                (None, Precondition::VersionMatches(version)) => {
+                    match self
+                        .storage
+                        .delete_conditional(&self.bucket, &object_key, &version)
+                        .await
+                    {
+                        // deleted in between, as asked
+                        Err(e) if e.code() == ErrorCode::NotFound => Ok(()),
+                        res => res,
+                    }
+                }
+                (Some(data), precondition) => {
+                    let expires_at = self.ttl.map(|ttl| Utc::now() + to_chrono(ttl));
+                    self.storage
+                        .upload_with_metadata(
+                            &self.bucket,
+                            &object_key,
+                            Some(mime.to_owned()),
+                            metadata(expires_at),
+                            data.clone(),
+                            Some(&precondition),
+                        )
+                        .await
+                        .map(|_| ())
+                }
+            };
+            let error = match res {
+                Ok(()) => return Ok(updated),
+                Err(e) if e.code() == ErrorCode::PreconditionFailed => e,
+                Err(e) => return Err(e),
+            };
+
+            retries += 1;
+            match backoff.next_delay(retries, &error) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => {
+                    return Err(Error::UpdateConflict {
+                        key: format!("{}/{object_key}", self.bucket),
+                        attempts: retries,
+                    }
+                    .into())
+                }
+            }
+        }
+    }
+
+    /// the entry under `object_key`, its expiry read from the metadata before its content
+    async fn read(&self, object_key: &str) -> Result<Current, NimbusError> {
+        for _ in 0..READ_ATTEMPTS {
+            let meta = match self.storage.object_metadata(&self.bucket, object_key).await {
+                Ok(meta) => meta,
+                Err(e) if e.code() == ErrorCode::NotFound => return Ok(Current::Missing),
+                Err(e) => return Err(e),
+            };
+            let Some(version) = meta.version else {
+                return Err(
+                    Error::Other(format!("no version for {}/{object_key}", self.bucket)).into(),
+                );
+            };
+            if is_expired(meta.metadata.as_ref(), Utc::now()) {
+                return Ok(Current::Expired(version));
+            }
+
+            match self
+                .storage
+                .download_versioned(&self.bucket, object_key)
+                .await
+            {
+                Ok((data, downloaded)) if downloaded == version => {
+                    return Ok(Current::Live(data, version))
+                }
+                // written again in between, its expiry is unknown
+                Ok(_) => {}
+                Err(e) if e.code() == ErrorCode::NotFound => return Ok(Current::Missing),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::UpdateConflict {
+            key: format!("{}/{object_key}", self.bucket),
+            attempts: READ_ATTEMPTS,
+        }
+        .into())
+    }
+
+    /// delete an expired entry unless written again since
+    async fn delete_expired(&self, object_key: &str, version: &str) -> Result<(), NimbusError> {
+        match self
+            .storage
+            .delete_conditional(&self.bucket, object_key, version)
+            .await
+        {
+            Err(e)
+                if matches!(
+                    e.code(),
+                    ErrorCode::NotFound | ErrorCode::PreconditionFailed
+                ) =>
+            {
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn parse_json<T: DeserializeOwned>(&self, key: &str, data: &[u8]) -> Result<T, NimbusError> {
+        serde_json::from_slice(data).map_err(|e| {
+            Error::InvalidJson(format!("{}/{key}: {}", self.bucket, json_error(&e))).into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::testing::{Fault, MemoryStorage};
+
+    #[test]
+    fn key_encoding_test() {
+        for (key, encoded) in [
+            ("user-42", "user-42"),
+            ("a/b", "a%2Fb"),
+            ("50%", "50%25"),
+            ("..", "%2E%2E"),
+            ("héllo wörld", "h%C3%A9llo%20w%C3%B6rld"),
+            ("日本", "%E6%97%A5%E6%9C%AC"),
+            ("🦀", "%F0%9F%A6%80"),
+            ("tab\there\n", "tab%09here%0A"),
+            ("~_-", "~_-"),
+        ] {
+            assert_eq!(encode_key(key), encoded, "{key:?}");
+            assert_eq!(decode_key(encoded).as_deref(), Some(key), "{encoded:?}");
+        }
+
+        // not produced by the encoding
+        for encoded in ["a/b", "a.b", "%2", "%zz", "%2f", "%FF", "a b"] {
+            assert_eq!(decode_key(encoded), None, "{encoded:?}");
+        }
+
+        // prefixes of keys encode to prefixes of their encoding
+        for (prefix, key) in [("a/", "a/b"), ("h\u{e9}", "h\u{e9}llo"), ("", "x")] {
+            assert!(encode_key(key).starts_with(&encode_key(prefix)));
+        }
+    }
+
+    #[test]
+    fn expiry_test() {
+        let now = DateTime::from_timestamp_millis(1_710_072_000_000).unwrap();
+        let meta = metadata(Some(now));
+        assert_eq!(
+            meta,
+            HashMap::from([(EXPIRES_KEY.to_owned(), "1710072000000".to_owned())])
+        );
+        assert_eq!(expires_at(Some(&meta)), Some(now));
+
+        // expired once the expiry is reached, not before
+        let just_before = now - chrono::Duration::milliseconds(1);
+        assert!(!is_expired(Some(&meta), just_before));
+        assert!(is_expired(Some(&meta), now));
+
+        assert!(metadata(None).is_empty());
+        let other = HashMap::from([("owner".to_owned(), "me".to_owned())]);
+        let invalid = HashMap::from([(EXPIRES_KEY.to_owned(), "soon".to_owned())]);
+        for meta in [None, Some(&other), Some(&invalid)] {
+            assert_eq!(expires_at(meta), None, "{meta:?}");
+            assert!(!is_expired(meta, now));
+        }
+    }
+
+    #[tokio::test]
+    async fn get_put_delete_test() {
+        let storage = MemoryStorage::new();
+        let kv = KvStore::new(&storage, "b", "kv/");
+
+        assert_eq!(kv.get("a/b").await.unwrap(), None);
+        kv.put("a/b", b"1".to_vec()).await.unwrap();
+        kv.put("a/c", b"2".to_vec()).await.unwrap();
+        kv.put("\u{e9}t\u{e9}", b"3".to_vec()).await.unwrap();
+        assert_eq!(kv.get("a/b").await.unwrap(), Some(b"1".to_vec()));
+        assert!(storage.object_exists("b", "kv/a%2Fb").await.unwrap());
+
+        let mut keys = kv.list_keys("a/").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["a/b", "a/c"]);
+        assert_eq!(kv.list_keys("").await.unwrap().len(), 3);
+
+        // objects that aren't entries are skipped
+        storage
+            .upload_from_bytes("b", "kv/not.an.entry", None, vec![])
+            .await
+            .unwrap();
+        assert_eq!(kv.list_keys("").await.unwrap().len(), 3);
+
+        kv.delete("a/b").await.unwrap();
+        kv.delete("a/b").await.unwrap();
+        assert_eq!(kv.get("a/b").await.unwrap(), None);
+
+        for key in ["", &"x".repeat(1100)] {
+            let e = kv.put(key, vec![]).await.unwrap_err();
+            assert_eq!(e.code(), ErrorCode::InvalidInput);
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        user: String,
+        visits: u32,
+    }
+
+    #[tokio::test]
+    async fn json_test() {
+        let storage = MemoryStorage::new();
+        let kv = KvStore::new(&storage, "b", "sessions/");
+
+        let session = Session {
+            user: "ana".to_owned(),
+            visits: 1,
+        };
+        kv.put_json("s1", &session).await.unwrap();
+        assert_eq!(kv.get_json("s1").await.unwrap(), Some(session));
+        assert_eq!(kv.get_json::<Session>("s2").await.unwrap(), None);
+
+        kv.put("s2", b"not json".to_vec()).await.unwrap();
+        let e = kv.get_json::<Session>("s2").await.unwrap_err();
+        assert!(matches!(
+            e.without_context(),
+            NimbusError::StorageClient(Error::InvalidJson(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn ttl_test() {
+        let storage = MemoryStorage::new();
+        let kv = KvStore::new(&storage, "b", "kv/").ttl(Duration::from_secs(3600));
+
+        kv.put("live", b"1".to_vec()).await.unwrap();
+        kv.put_with_ttl("expired", b"2".to_vec(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        kv.put_with_ttl("forever", b"3".to_vec(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(kv.get("live").await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(kv.get("forever").await.unwrap(), Some(b"3".to_vec()));
+        let meta = storage.object_metadata("b", "kv/live").await.unwrap();
+        assert!(expires_at(meta.metadata.as_ref()).is_some());
+        assert_eq!(meta.content_type.as_deref(), Some(KV_CONTENT_TYPE));
+
+        // listed until read, then lazily deleted
+        assert_eq!(kv.list_keys("").await.unwrap().len(), 3);
+        assert_eq!(kv.get("expired").await.unwrap(), None);
+        assert!(!storage.object_exists("b", "kv/expired").await.unwrap());
+        assert_eq!(kv.list_keys("").await.unwrap().len(), 2);
+
+        // an expired entry is missing to an update, and replaced by it
+        kv.put_with_ttl("expired", b"old".to_vec(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        let updated = kv
+            .update("expired", |current| {
+                assert_eq!(current, None);
+                Ok::<_, NimbusError>(Some(b"new".to_vec()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(updated, Some(b"new".to_vec()));
+        assert_eq!(kv.get("expired").await.unwrap(), Some(b"new".to_vec()));
+    }
+
+    fn increment(current: Option<u32>) -> Result<Option<u32>, NimbusError> {
+        Ok(Some(current.unwrap_or_default() + 1))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_test() {
+        let storage = Arc::new(MemoryStorage::new());
+
+        let writers = (0..8).map(|_| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let kv = KvStore::new(storage.as_ref(), "b", "kv/")
+                    .update_options(UpdateOptions::new().max_attempts(100));
+                for _ in 0..5 {
+                    kv.update_json("counter", increment).await.unwrap();
+                }
+            })
+        });
+        for writer in writers.collect::<Vec<_>>() {
+            writer.await.unwrap();
+        }
+
+        let kv = KvStore::new(storage.as_ref(), "b", "kv/");
+        assert_eq!(kv.get_json::<u32>("counter").await.unwrap(), Some(40));
+
+        // `None` deletes
+        let deleted = kv
+            .update("counter", |_| Ok::<_, NimbusError>(None))
+            .await
+            .unwrap();
+        assert_eq!(deleted, None);
+        assert_eq!(kv.get("counter").await.unwrap(), None);
+        assert_eq!(
+            kv.update("counter", |_| Ok::<_, NimbusError>(None))
+                .await
+                .unwrap(),
+            None
+        );
+
+        // an error of `f` aborts
+        let e = kv
+            .update("counter", |_| Err(Error::Other("nope".to_owned())))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("nope"));
+        assert_eq!(kv.get("counter").await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_conflict_test() {
+        let storage = MemoryStorage::new();
+        let kv =
+            KvStore::new(&storage, "b", "kv/").update_options(UpdateOptions::new().max_attempts(3));
+        kv.put("k", b"1".to_vec()).await.unwrap();
+
+        storage.mock_stats().set_fault(
+            "upload_with_metadata",
+            Fault::new().fail(1.0, ErrorCode::PreconditionFailed),
+        );
+        let e = kv
+            .update("k", |_| Ok::<_, NimbusError>(Some(b"2".to_vec())))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.without_context(),
+            NimbusError::StorageClient(Error::UpdateConflict { attempts: 3, .. })
+        ));
+        assert_eq!(kv.get("k").await.unwrap(), Some(b"1".to_vec()));
+    }
+}
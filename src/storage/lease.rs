@@ -0,0 +1,459 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{Error, Precondition, StorageHelper};
+use crate::{ErrorCode, NimbusError};
+
+/// Clock difference between lease holders tolerated by [`Lease::acquire`]
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+/// times [`Lease::acquire_with_skew`] starts over when the lease changes hands while it's being acquired
+const ACQUIRE_ATTEMPTS: usize = 3;
+
+const LEASE_MIME: &str = "application/json";
+
+/// durations too long for chrono are capped to a century, longer than any lease needs
+pub(super) fn to_chrono(d: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(d).unwrap_or_else(|_| chrono::Duration::days(36_500))
+}
+
+/// content of a lease object
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    owner: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl Record {
+    fn new(owner: &str, ttl: Duration) -> Self {
+        Self {
+            owner: owner.to_owned(),
+            expires_at: Utc::now() + to_chrono(ttl),
+        }
+    }
+
+    fn parse(key: &str, data: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(data).map_err(|e| Error::Other(format!("Invalid lease {key}: {e}")))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("lease record serializes")
+    }
+
+    /// whether the holder's lease ran out, allowing its clock to be up to `skew` behind ours
+    fn expired(&self, now: DateTime<Utc>, skew: Duration) -> bool {
+        now > self.expires_at + to_chrono(skew)
+    }
+}
+
+/// Exclusive lease on a key of a bucket, e.g. to run singleton work across replicas
+///
+/// The lease is an object holding its owner and expiry. Every change is a conditional write on the
+/// version of the object, so of concurrent owners exactly one wins and the others fail with
+/// [`ErrorCode::PreconditionFailed`] ([`Error::LeaseHeld`] or [`Error::LeaseLost`]).
+///
+/// A lease not renewed before [`Lease::expires_at`] may be taken over by another owner
+/// once its recorded expiry plus the clock skew allowance has passed.
+pub struct Lease<'a, S: StorageHelper + Sync> {
+    storage: &'a S,
+    bucket: String,
+    key: String,
+    owner: String,
+    ttl: Duration,
+    version: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl<'a, S: StorageHelper + Sync> Lease<'a, S> {
+    /// acquire the lease on `key` for `ttl`, with [`DEFAULT_CLOCK_SKEW`]
+    pub async fn acquire(
+        storage: &'a S,
+        bucket: &str,
+        key: &str,
+        ttl: Duration,
+        owner_id: &str,
+    ) -> Result<Self, NimbusError> {
+        Self::acquire_with_skew(storage, bucket, key, ttl, owner_id, DEFAULT_CLOCK_SKEW).await
+    }
+
+    /// acquire the lease on `key` for `ttl`
+    /// a lease held by another owner is taken over only once its recorded expiry is more than `skew` in the past,
+    /// otherwise this fails with [`Error::LeaseHeld`]
+    pub async fn acquire_with_skew(
+        storage: &'a S,
+        bucket: &str,
+        key: &str,
+        ttl: Duration,
+        owner_id: &str,
+        skew: Duration,
+    ) -> Result<Self, NimbusError> {
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            let record = Record::new(owner_id, ttl);
+            let res = storage
+                .upload_conditional(
+                    bucket,
+                    key,
+                    Some(LEASE_MIME.to_owned()),
+                    record.to_bytes(),
+                    &Precondition::DoesNotExist,
+                )
+                .await;
+
+            match res {
+                Ok(version) => return Ok(Self::held(storage, bucket, key, ttl, record, version)),
+                Err(e) if e.code() == ErrorCode::PreconditionFailed => {}
+                Err(e) => return Err(e),
+            }
+
+            let (data, version) = match storage.download_versioned(bucket, key).await {
+                Ok(held) => held,
+                // released in between
+                Err(e) if e.code() == ErrorCode::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            let holder = Record::parse(key, &data)?;
+            if !holder.expired(Utc::now(), skew) {
+                return Err(Error::LeaseHeld {
+                    key: key.to_owned(),
+                    owner: holder.owner,
+                    expires_at: holder.expires_at,
+                }
+                .into());
+            }
+
+            // of the owners taking over the expired lease only one still matches its version
+            let record = Record::new(owner_id, ttl);
+            let res = storage
+                .upload_conditional(
+                    bucket,
+                    key,
+                    Some(LEASE_MIME.to_owned()),
+                    record.to_bytes(),
+                    &Precondition::VersionMatches(version),
+                )
+                .await;
+
+            match res {
+                Ok(version) => return Ok(Self::held(storage, bucket, key, ttl, record, version)),
+                Err(e) if e.code() == ErrorCode::PreconditionFailed => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(Error::PreconditionFailed(format!(
+            "Lease {key} changed hands {ACQUIRE_ATTEMPTS} times while acquiring it"
+        ))
+        .into())
+    }
+
+    fn held(
+        storage: &'a S,
+        bucket: &str,
+        key: &str,
+        ttl: Duration,
+        record: Record,
+        version: String,
+    ) -> Self {
+        Self {
+            storage,
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            owner: record.owner,
+            ttl,
+            version,
+            expires_at: record.expires_at,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// end of the lease as recorded in its object
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// whether the lease ran out on the local clock
+    /// work guarded by the lease should stop once it did, another owner may take it over
+    pub fn is_expired(&self) -> bool {
+        Utc::now() >= self.expires_at
+    }
+
+    /// extend the lease by its ttl from now
+    /// fails with [`Error::LeaseLost`] if another owner took it over
+    pub async fn renew(&mut self) -> Result<(), NimbusError> {
+        let record = Record::new(&self.owner, self.ttl);
+        let res = self
+            .storage
+            .upload_conditional(
+                &self.bucket,
+                &self.key,
+                Some(LEASE_MIME.to_owned()),
+                record.to_bytes(),
+                &Precondition::VersionMatches(self.version.clone()),
+            )
+            .await;
+
+        match res {
+            Ok(version) => {
+                self.version = version;
+                self.expires_at = record.expires_at;
+                Ok(())
+            }
+            Err(e)
+                if matches!(
+                    e.code(),
+                    ErrorCode::PreconditionFailed | ErrorCode::NotFound
+                ) =>
+            {
+                Err(Error::LeaseLost(self.key.clone()).into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// give the lease up
+    /// fails with [`Error::LeaseLost`] if another owner took it over, its lease is left in place
+    pub async fn release(self) -> Result<(), NimbusError> {
+        let res = self
+            .storage
+            .delete_conditional(&self.bucket, &self.key, &self.version)
+            .await;
+
+        match res {
+            Ok(()) => Ok(()),
+            // taken over and released already
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(()),
+            Err(e) if e.code() == ErrorCode::PreconditionFailed => {
+                Err(Error::LeaseLost(self.key).into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::testing::MemoryStorage;
+
+    use super::*;
+
+    const BUCKET: &str = "bucket";
+    const KEY: &str = "cron/lock";
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn is_lease_held(e: &NimbusError) -> bool {
+        matches!(e, NimbusError::StorageClient(Error::LeaseHeld { .. }))
+    }
+
+    fn is_lease_lost(e: &NimbusError) -> bool {
+        matches!(e, NimbusError::StorageClient(Error::LeaseLost(_)))
+    }
+
+    /// a lease that expired long ago, as left behind by a crashed owner
+    async fn crashed_lease(storage: &MemoryStorage) {
+        let record = Record {
+            owner: "crashed".to_owned(),
+            expires_at: Utc::now() - chrono::Duration::hours(1),
+        };
+        storage
+            .upload_from_bytes(BUCKET, KEY, None, record.to_bytes())
+            .await
+            .unwrap();
+    }
+
+    async fn race(storage: Arc<MemoryStorage>, contenders: usize) -> Vec<Result<String, bool>> {
+        let handles = (0..contenders).map(|i| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let owner = format!("owner-{i}");
+                match Lease::acquire(storage.as_ref(), BUCKET, KEY, TTL, &owner).await {
+                    Ok(lease) => Ok(lease.owner().to_owned()),
+                    Err(e) => Err(is_lease_held(&e) || e.code() == ErrorCode::PreconditionFailed),
+                }
+            })
+        });
+
+        futures::future::join_all(handles)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn record_expired_test() {
+        let record = Record::new("a", Duration::from_secs(10));
+        let skew = Duration::from_secs(5);
+
+        assert!(!record.expired(Utc::now(), skew));
+        assert!(!record.expired(record.expires_at + chrono::Duration::seconds(4), skew));
+        assert!(record.expired(record.expires_at + chrono::Duration::seconds(6), skew));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_acquire_single_winner_test() {
+        let storage = Arc::new(MemoryStorage::new());
+        let results = race(storage.clone(), 16).await;
+
+        let winners: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        assert_eq!(winners.len(), 1);
+        // every loser went through the precondition path
+        assert!(results.iter().all(|r| matches!(r, Ok(_) | Err(true))));
+
+        let (data, _) = storage.download_versioned(BUCKET, KEY).await.unwrap();
+        assert_eq!(&Record::parse(KEY, &data).unwrap().owner, winners[0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_steal_single_winner_test() {
+        let storage = Arc::new(MemoryStorage::new());
+        crashed_lease(&storage).await;
+
+        let results = race(storage.clone(), 16).await;
+
+        let winners: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        assert_eq!(winners.len(), 1);
+        assert!(results.iter().all(|r| matches!(r, Ok(_) | Err(true))));
+
+        let (data, _) = storage.download_versioned(BUCKET, KEY).await.unwrap();
+        assert_eq!(&Record::parse(KEY, &data).unwrap().owner, winners[0]);
+    }
+
+    #[tokio::test]
+    async fn held_lease_is_not_stolen_test() {
+        let storage = MemoryStorage::new();
+        let lease = Lease::acquire(&storage, BUCKET, KEY, TTL, "a")
+            .await
+            .unwrap();
+
+        let err = Lease::acquire(&storage, BUCKET, KEY, TTL, "b")
+            .await
+            .err()
+            .unwrap();
+        assert!(is_lease_held(&err));
+        assert_eq!(err.code(), ErrorCode::PreconditionFailed);
+
+        // expired on the holder's clock, but within the skew allowance
+        let short = Lease::acquire_with_skew(&storage, BUCKET, "short", Duration::ZERO, "a", TTL)
+            .await
+            .unwrap();
+        assert!(short.is_expired());
+        let err = Lease::acquire_with_skew(&storage, BUCKET, "short", TTL, "b", TTL)
+            .await
+            .err()
+            .unwrap();
+        assert!(is_lease_held(&err));
+
+        lease.release().await.unwrap();
+        let lease = Lease::acquire(&storage, BUCKET, KEY, TTL, "b")
+            .await
+            .unwrap();
+        assert_eq!(lease.owner(), "b");
+    }
+
+    #[tokio::test]
+    async fn stolen_lease_is_lost_test() {
+        let storage = MemoryStorage::new();
+        let mut old =
+            Lease::acquire_with_skew(&storage, BUCKET, KEY, Duration::ZERO, "a", Duration::ZERO)
+                .await
+                .unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let new = Lease::acquire_with_skew(&storage, BUCKET, KEY, TTL, "b", Duration::ZERO)
+            .await
+            .unwrap();
+
+        // the old holder finds out on its next write and can't touch the new lease
+        assert!(is_lease_lost(&old.renew().await.err().unwrap()));
+        assert!(is_lease_lost(&old.release().await.err().unwrap()));
+
+        let (data, _) = storage.download_versioned(BUCKET, KEY).await.unwrap();
+        assert_eq!(Record::parse(KEY, &data).unwrap().owner, "b");
+        new.release().await.unwrap();
+        assert!(!storage.object_exists(BUCKET, KEY).await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn renew_races_steal_test() {
+        // the holder renewing while others take over its expired lease: exactly one side wins
+        let ttl = Duration::from_millis(100);
+
+        for _ in 0..5 {
+            let storage = Arc::new(MemoryStorage::new());
+            let mut lease = Lease::acquire(storage.as_ref(), BUCKET, KEY, ttl, "a")
+                .await
+                .unwrap();
+            tokio::time::sleep(ttl + Duration::from_millis(10)).await;
+
+            let thieves = (0..4).map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let owner = format!("thief-{i}");
+                    Lease::acquire_with_skew(
+                        storage.as_ref(),
+                        BUCKET,
+                        KEY,
+                        TTL,
+                        &owner,
+                        Duration::ZERO,
+                    )
+                    .await
+                    .is_ok()
+                })
+            });
+            let renewed = lease.renew().await.is_ok();
+            let stolen = futures::future::join_all(thieves)
+                .await
+                .into_iter()
+                .filter(|r| *r.as_ref().unwrap())
+                .count();
+
+            assert!(stolen <= 1);
+            assert_ne!(renewed, stolen == 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn renew_extends_expiry_test() {
+        let storage = MemoryStorage::new();
+        let mut lease = Lease::acquire(&storage, BUCKET, KEY, TTL, "a")
+            .await
+            .unwrap();
+        let first = lease.expires_at();
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        lease.renew().await.unwrap();
+        assert!(lease.expires_at() > first);
+
+        let (data, _) = storage.download_versioned(BUCKET, KEY).await.unwrap();
+        assert_eq!(
+            Record::parse(KEY, &data).unwrap().expires_at,
+            lease.expires_at()
+        );
+        lease.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn crashed_owner_lease_is_taken_over_test() {
+        let storage = MemoryStorage::new();
+        crashed_lease(&storage).await;
+
+        let lease = Lease::acquire(&storage, BUCKET, KEY, TTL, "b")
+            .await
+            .unwrap();
+        assert_eq!(lease.owner(), "b");
+        assert!(!lease.is_expired());
+    }
+}
@@ -0,0 +1,492 @@
+//! Exactly-once processing of objects delivered at least once
+//!
+//! Watches, polls and bucket notifications may deliver the same object several times.
+//! A [`ProcessingLedger`] records, per object generation, a marker object under its prefix:
+//! a worker [claims](ProcessingLedger::claim) the generation before processing it and
+//! [completes](ProcessingLedger::complete) the claim after. The marker is created if-not-exists and
+//! changed on its version only, the way [`Lease`](super::Lease) is, so of concurrent claims exactly one wins.
+//!
+//! A claim not completed within its ttl, e.g. because its worker crashed, may be taken over once its expiry
+//! plus the clock skew allowance has passed. A new generation of an object has a marker of its own and is
+//! processed again. Completed markers are kept until pruned with [`ProcessingLedger::gc`].
+//!
+//! ```ignore
+//! let ledger = ProcessingLedger::new(&storage, "ops", "ledger/thumbnails/");
+//! match ledger.claim(&meta.key, generation, worker_id, Duration::from_secs(300)).await {
+//!     Ok(claim) => {
+//!         make_thumbnail(&meta).await?;
+//!         ledger.complete(claim).await?;
+//!     }
+//!     // processed, or being processed elsewhere
+//!     Err(e) if matches!(e.code(), ErrorCode::AlreadyExists | ErrorCode::PreconditionFailed) => {}
+//!     Err(e) => return Err(e),
+//! }
+//! ```
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::lease::{to_chrono, DEFAULT_CLOCK_SKEW};
+use super::{Error, Precondition, StorageHelper};
+use crate::redact::json_error;
+use crate::{ErrorCode, NimbusError};
+
+/// times [`ProcessingLedger::claim`] starts over when the marker changes while it's being claimed
+const CLAIM_ATTEMPTS: usize = 3;
+
+const MARKER_MIME: &str = "application/json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Claimed,
+    Done,
+}
+
+/// content of a marker object
+#[derive(Debug, Serialize, Deserialize)]
+struct Marker {
+    object_key: String,
+    generation: i64,
+    owner: String,
+    status: Status,
+    /// end of the claim, or completion time once done
+    at: DateTime<Utc>,
+}
+
+impl Marker {
+    fn parse(key: &str, data: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(data)
+            .map_err(|e| Error::InvalidJson(format!("marker {key}: {}", json_error(&e))))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("ledger marker serializes")
+    }
+
+    /// whether an unfinished claim ran out, allowing its owner's clock to be up to `skew` behind ours
+    fn expired(&self, now: DateTime<Utc>, skew: Duration) -> bool {
+        self.status == Status::Claimed && now > self.at + to_chrono(skew)
+    }
+}
+
+/// Claim on a generation of an object, returned by [`ProcessingLedger::claim`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claim {
+    object_key: String,
+    generation: i64,
+    owner: String,
+    marker_key: String,
+    version: String,
+    expires_at: DateTime<Utc>,
+    taken_over_from: Option<String>,
+}
+
+impl Claim {
+    pub fn object_key(&self) -> &str {
+        &self.object_key
+    }
+
+    pub fn generation(&self) -> i64 {
+        self.generation
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// end of the claim as recorded in its marker
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// owner of the expired claim this one took over, the object may have been partially processed
+    pub fn taken_over_from(&self) -> Option<&str> {
+        self.taken_over_from.as_deref()
+    }
+}
+
+/// Markers of the object generations processed, see the [module documentation](self)
+pub struct ProcessingLedger<'a, S: StorageHelper + Sync> {
+    storage: &'a S,
+    bucket: String,
+    prefix: String,
+    skew: Duration,
+}
+
+impl<'a, S: StorageHelper + Sync> ProcessingLedger<'a, S> {
+    /// ledger keeping its markers in `bucket` under `ledger_prefix`, with [`DEFAULT_CLOCK_SKEW`]
+    pub fn new(storage: &'a S, bucket: &str, ledger_prefix: &str) -> Self {
+        Self {
+            storage,
+            bucket: bucket.to_owned(),
+            prefix: ledger_prefix.to_owned(),
+            skew: DEFAULT_CLOCK_SKEW,
+        }
+    }
+
+    /// clock difference between workers tolerated before taking over an expired claim
+    pub fn skew(mut self, skew: Duration) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    fn marker_key(&self, object_key: &str, generation: i64) -> String {
+        format!("{}{object_key}@{generation}", self.prefix)
+    }
+
+    /// claim `generation` of `object_key` for `owner` during `ttl`
+    ///
+    /// fails with [`Error::AlreadyProcessed`] if the generation was completed, [`Error::ClaimHeld`]
+    /// if another claim on it hasn't expired
+    pub async fn claim(
+        &self,
+        object_key: &str,
+        generation: i64,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<Claim, NimbusError> {
+        let key = self.marker_key(object_key, generation);
+        let marker = || Marker {
+            object_key: object_key.to_owned(),
+            generation,
+            owner: owner.to_owned(),
+            status: Status::Claimed,
+            at: Utc::now() + to_chrono(ttl),
+        };
+
+        for _ in 0..CLAIM_ATTEMPTS {
+            let claim = marker();
+            let res = self
+                .storage
+                .upload_conditional(
+                    &self.bucket,
+                    &key,
+                    Some(MARKER_MIME.to_owned()),
+                    claim.to_bytes(),
+                    &Precondition::DoesNotExist,
+                )
+                .await;
+
+            match res {
+                Ok(version) => return Ok(self.claimed(key, claim, version, None)),
+                Err(e) if e.code() == ErrorCode::PreconditionFailed => {}
+                Err(e) => return Err(e),
+            }
+
+            let (data, version) = match self.storage.download_versioned(&self.bucket, &key).await {
+                Ok(held) => held,
+                // pruned in between
+                Err(e) if e.code() == ErrorCode::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            let holder = Marker::parse(&key, &data)?;
+            if holder.status == Status::Done {
+                return Err(Error::AlreadyProcessed {
+                    key: object_key.to_owned(),
+                    generation,
+                }
+                .into());
+            }
+            if !holder.expired(Utc::now(), self.skew) {
+                return Err(Error::ClaimHeld {
+                    key: object_key.to_owned(),
+                    generation,
+                    owner: holder.owner,
+                    expires_at: holder.at,
+                }
+                .into());
+            }
+
+            // of the workers taking over the expired claim only one still matches its version
+            let claim = marker();
+            let res = self
+                .storage
+                .upload_conditional(
+                    &self.bucket,
+                    &key,
+                    Some(MARKER_MIME.to_owned()),
+                    claim.to_bytes(),
+                    &Precondition::VersionMatches(version),
+                )
+                .await;
+
+            match res {
+                Ok(version) => return Ok(self.claimed(key, claim, version, Some(holder.owner))),
+                Err(e) if e.code() == ErrorCode::PreconditionFailed => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(Error::PreconditionFailed(format!(
+            "Marker {key} changed {CLAIM_ATTEMPTS} times while claiming it"
+        ))
+        .into())
+    }
+
+    fn claimed(
+        &self,
+        marker_key: String,
+        marker: Marker,
+        version: String,
+        taken_over_from: Option<String>,
+    ) -> Claim {
+        Claim {
+            object_key: marker.object_key,
+            generation: marker.generation,
+            owner: marker.owner,
+            marker_key,
+            version,
+            expires_at: marker.at,
+            taken_over_from,
+        }
+    }
+
+    /// record the generation of `claim` as processed
+    ///
+    /// an expired claim still completes unless another worker took it over,
+    /// in which case this fails with [`Error::ClaimLost`]
+    pub async fn complete(&self, claim: Claim) -> Result<(), NimbusError> {
+        let done = Marker {
+            object_key: claim.object_key,
+            generation: claim.generation,
+            owner: claim.owner,
+            status: Status::Done,
+            at: Utc::now(),
+        };
+        let res = self
+            .storage
+            .upload_conditional(
+                &self.bucket,
+                &claim.marker_key,
+                Some(MARKER_MIME.to_owned()),
+                done.to_bytes(),
+                &Precondition::VersionMatches(claim.version),
+            )
+            .await;
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(e)
+                if matches!(
+                    e.code(),
+                    ErrorCode::PreconditionFailed | ErrorCode::NotFound
+                ) =>
+            {
+                Err(Error::ClaimLost {
+                    key: done.object_key,
+                    generation: done.generation,
+                }
+                .into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// whether `generation` of `object_key` was processed, claimed is not processed
+    pub async fn is_done(&self, object_key: &str, generation: i64) -> Result<bool, NimbusError> {
+        let key = self.marker_key(object_key, generation);
+        match self.storage.download_versioned(&self.bucket, &key).await {
+            Ok((data, _)) => Ok(Marker::parse(&key, &data)?.status == Status::Done),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// delete the markers completed more than `older_than` ago, returns how many were
+    ///
+    /// claims, expired or not, are left in place. A generation delivered again after its marker
+    /// is pruned is processed again, `older_than` should exceed the redelivery window.
+    pub async fn gc(&self, older_than: Duration) -> Result<usize, NimbusError> {
+        let cutoff = Utc::now() - to_chrono(older_than);
+        let markers: Vec<_> = self
+            .storage
+            .list(&self.bucket)
+            .prefix(&self.prefix)
+            .stream()
+            .map_ok(|meta| meta.key)
+            .try_collect()
+            .await?;
+
+        let mut pruned = 0;
+        for key in markers {
+            let (data, version) = match self.storage.download_versioned(&self.bucket, &key).await {
+                Ok(marker) => marker,
+                Err(e) if e.code() == ErrorCode::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let marker = Marker::parse(&key, &data)?;
+            if marker.status != Status::Done || marker.at >= cutoff {
+                continue;
+            }
+
+            // a marker claimed again since is left alone
+            match self
+                .storage
+                .delete_conditional(&self.bucket, &key, &version)
+                .await
+            {
+                Ok(()) => pruned += 1,
+                Err(e)
+                    if matches!(
+                        e.code(),
+                        ErrorCode::PreconditionFailed | ErrorCode::NotFound
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::testing::MemoryStorage;
+
+    const BUCKET: &str = "ops";
+    const PREFIX: &str = "ledger/";
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn ledger(storage: &MemoryStorage) -> ProcessingLedger<'_, MemoryStorage> {
+        ProcessingLedger::new(storage, BUCKET, PREFIX)
+    }
+
+    fn is_held(e: &NimbusError) -> bool {
+        matches!(e, NimbusError::StorageClient(Error::ClaimHeld { .. }))
+    }
+
+    fn is_processed(e: &NimbusError) -> bool {
+        matches!(
+            e,
+            NimbusError::StorageClient(Error::AlreadyProcessed { .. })
+        )
+    }
+
+    #[tokio::test]
+    async fn claim_complete_test() {
+        let storage = MemoryStorage::new();
+        let ledger = ledger(&storage);
+
+        let claim = ledger.claim("in/a.csv", 1, "w1", TTL).await.unwrap();
+        assert_eq!(claim.generation(), 1);
+        assert_eq!(claim.taken_over_from(), None);
+        assert!(!ledger.is_done("in/a.csv", 1).await.unwrap());
+
+        // a duplicate delivery while processing
+        let err = ledger.claim("in/a.csv", 1, "w2", TTL).await.unwrap_err();
+        assert!(is_held(&err));
+        assert_eq!(err.code(), ErrorCode::PreconditionFailed);
+
+        ledger.complete(claim).await.unwrap();
+        assert!(ledger.is_done("in/a.csv", 1).await.unwrap());
+
+        // a duplicate delivery once processed
+        let err = ledger.claim("in/a.csv", 1, "w2", TTL).await.unwrap_err();
+        assert!(is_processed(&err));
+        assert_eq!(err.code(), ErrorCode::AlreadyExists);
+
+        // the object was uploaded again
+        assert!(!ledger.is_done("in/a.csv", 2).await.unwrap());
+        let claim = ledger.claim("in/a.csv", 2, "w2", TTL).await.unwrap();
+        ledger.complete(claim).await.unwrap();
+        assert!(ledger.is_done("in/a.csv", 2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn concurrent_claims_single_winner_test() {
+        let storage = Arc::new(MemoryStorage::new());
+        let claims = (0..10).map(|i| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                ledger(&storage)
+                    .claim("in/a.csv", 7, &format!("w{i}"), TTL)
+                    .await
+                    .map(|c| c.owner().to_owned())
+                    .map_err(|e| e.code())
+            })
+        });
+        let results: Vec<_> = futures::future::try_join_all(claims).await.unwrap();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Ok(_) | Err(ErrorCode::PreconditionFailed))));
+    }
+
+    #[tokio::test]
+    async fn crash_before_complete_test() {
+        let storage = MemoryStorage::new();
+        let ledger = ledger(&storage).skew(Duration::ZERO);
+
+        // w1 crashes while processing, its claim runs out
+        let crashed = ledger
+            .claim("in/a.csv", 1, "w1", Duration::ZERO)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let claim = ledger.claim("in/a.csv", 1, "w2", TTL).await.unwrap();
+        assert_eq!(claim.taken_over_from(), Some("w1"));
+
+        // w1 coming back can't complete what w2 took over
+        let err = ledger.complete(crashed).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::ClaimLost { generation: 1, .. })
+        ));
+        assert!(!ledger.is_done("in/a.csv", 1).await.unwrap());
+
+        ledger.complete(claim).await.unwrap();
+        assert!(ledger.is_done("in/a.csv", 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_claim_within_skew_test() {
+        let storage = MemoryStorage::new();
+        let ledger = ledger(&storage).skew(TTL);
+
+        let late = ledger
+            .claim("in/a.csv", 1, "w1", Duration::ZERO)
+            .await
+            .unwrap();
+        let err = ledger.claim("in/a.csv", 1, "w2", TTL).await.unwrap_err();
+        assert!(is_held(&err));
+
+        // expired but not taken over, the late worker still completes
+        ledger.complete(late).await.unwrap();
+        assert!(ledger.is_done("in/a.csv", 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn gc_test() {
+        let storage = MemoryStorage::new();
+        let ledger = ledger(&storage);
+
+        for generation in 1..=3 {
+            let claim = ledger
+                .claim("in/a.csv", generation, "w1", TTL)
+                .await
+                .unwrap();
+            ledger.complete(claim).await.unwrap();
+        }
+        ledger.claim("in/b.csv", 1, "w1", TTL).await.unwrap();
+
+        assert_eq!(ledger.gc(Duration::from_secs(3600)).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(ledger.gc(Duration::ZERO).await.unwrap(), 3);
+
+        // the pending claim is kept, a pruned generation can be claimed again
+        let err = ledger.claim("in/b.csv", 1, "w2", TTL).await.unwrap_err();
+        assert!(is_held(&err));
+        assert!(!ledger.is_done("in/a.csv", 1).await.unwrap());
+        ledger.claim("in/a.csv", 1, "w2", TTL).await.unwrap();
+    }
+}
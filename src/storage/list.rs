@@ -0,0 +1,555 @@
+use futures::{Stream, TryStreamExt};
+
+use super::{ObjectMeta, StorageHelper};
+use crate::{ListLimits, NimbusError};
+
+/// Parameters of a listing, built with [`ListQuery`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListParams {
+    pub prefix: Option<String>,
+    /// group the keys containing the delimiter after the prefix into [`ListPage::prefixes`]
+    pub delimiter: Option<String>,
+    /// maximum number of entries per page, the provider default when `None`
+    pub page_size: Option<u32>,
+    /// list every version of the objects instead of the live ones
+    pub versions: bool,
+    /// list only the keys after this one, in lexicographic order
+    pub start_after: Option<String>,
+    /// list only this key and the ones after it, the inclusive counterpart of `start_after`
+    pub start_at: Option<String>,
+    /// list only the keys before this one, which is left out
+    pub end_before: Option<String>,
+}
+
+impl ListParams {
+    /// whether `key` is within the bounds, whatever the prefix
+    pub fn in_bounds(&self, key: &str) -> bool {
+        self.start_after.as_deref().is_none_or(|start| key > start)
+            && self.start_at.as_deref().is_none_or(|start| key >= start)
+            && self.end_before.as_deref().is_none_or(|end| key < end)
+    }
+
+    /// whether keys grouped under the common prefix `prefix` may be within the bounds
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn prefix_in_bounds(&self, prefix: &str) -> bool {
+        // the keys under a prefix all come before a start it is before and isn't a prefix of
+        let reaches = |start: &str| prefix > start || start.starts_with(prefix);
+        self.start_after.as_deref().is_none_or(reaches)
+            && self.start_at.as_deref().is_none_or(reaches)
+            && self.end_before.as_deref().is_none_or(|end| prefix < end)
+    }
+
+    /// whether `key` is at or past the end bound, no key after it is listed
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn past_end(&self, key: &str) -> bool {
+        self.end_before.as_deref().is_some_and(|end| key >= end)
+    }
+
+    /// `StartAfter` of an S3 listing: `start_after`, or `start_at` without its last character if later
+    /// the keys listed before `start_at` are then left out with [`ListParams::in_bounds`]
+    #[cfg(feature = "aws-storage")]
+    pub(crate) fn exclusive_start(&self) -> Option<String> {
+        let start_at = self.start_at.as_deref().and_then(|start| {
+            let mut chars = start.chars();
+            chars.next_back();
+            Some(chars.as_str()).filter(|s| !s.is_empty())
+        });
+        match (self.start_after.as_deref(), start_at) {
+            (Some(after), Some(at)) => Some(after.max(at)),
+            (after, at) => after.or(at),
+        }
+        .map(str::to_owned)
+    }
+}
+
+/// One page of a listing
+#[derive(Debug, Clone, Default)]
+pub struct ListPage {
+    pub objects: Vec<ObjectMeta>,
+    /// common prefixes of the keys grouped by the delimiter, with the delimiter included
+    pub prefixes: Vec<String>,
+    /// token of the next page, `None` on the last page
+    pub next_page_token: Option<String>,
+}
+
+/// Listing of a bucket, from [`StorageHelper::list`]
+///
+/// [`ListQuery::collect`] is bounded by [`ListQuery::limits`], the streams are not.
+///
+/// ```ignore
+/// let objects = storage
+///     .list("bucket")
+///     .prefix("x/")
+///     .delimiter("/")
+///     .page_size(500)
+///     .collect()
+///     .await?;
+/// ```
+pub struct ListQuery<'a, S: StorageHelper + Sync> {
+    storage: &'a S,
+    bucket: String,
+    params: ListParams,
+    limits: ListLimits,
+    skip_placeholders: bool,
+}
+
+impl<'a, S: StorageHelper + Sync> ListQuery<'a, S> {
+    pub(crate) fn new(storage: &'a S, bucket: &str) -> Self {
+        Self {
+            storage,
+            bucket: bucket.to_owned(),
+            params: ListParams::default(),
+            limits: ListLimits::default(),
+            skip_placeholders: false,
+        }
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.params.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn delimiter(mut self, delimiter: impl Into<String>) -> Self {
+        self.params.delimiter = Some(delimiter.into());
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.params.page_size = Some(page_size);
+        self
+    }
+
+    /// list every version of the objects, noncurrent ones included
+    /// on S3 [`ObjectMeta::version`] then holds the version id instead of the ETag
+    pub fn versions(mut self, versions: bool) -> Self {
+        self.params.versions = versions;
+        self
+    }
+
+    /// start the listing after `key`, e.g. to resume a listing from the last key processed
+    pub fn start_after(mut self, key: impl Into<String>) -> Self {
+        self.params.start_after = Some(key.into());
+        self
+    }
+
+    /// start the listing at `key`, included if it exists
+    pub fn start_at(mut self, key: impl Into<String>) -> Self {
+        self.params.start_at = Some(key.into());
+        self
+    }
+
+    /// end the listing before `key`, left out if it exists; no page is fetched past it
+    ///
+    /// With `start_at` it bounds a shard of the keyspace: shards `[a, f)`, `[f, m)`, `[m, ..)`
+    /// list every key once. The bounds apply to the keys, along with the prefix. With a delimiter,
+    /// S3 keeps the common prefixes before the end bound, even when all of their keys are past it.
+    pub fn end_before(mut self, key: impl Into<String>) -> Self {
+        self.params.end_before = Some(key.into());
+        self
+    }
+
+    /// bounds of [`ListQuery::collect`], [`ListLimits::default`] if not set
+    pub fn limits(mut self, limits: ListLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// leave out the folder placeholders ([`ObjectMeta::is_placeholder_dir`]), they are listed by default
+    /// pages then hold fewer objects than their size, possibly none
+    pub fn skip_placeholders(mut self, skip: bool) -> Self {
+        self.skip_placeholders = skip;
+        self
+    }
+
+    pub fn params(&self) -> &ListParams {
+        &self.params
+    }
+
+    /// fetch a single page, the first one for `page_token: None`
+    pub async fn page(&self, page_token: Option<String>) -> Result<ListPage, NimbusError> {
+        let mut page = self
+            .storage
+            .list_page(&self.bucket, &self.params, page_token)
+            .await?;
+        if self.skip_placeholders {
+            page.objects.retain(|o| !o.is_placeholder_dir());
+        }
+
+        Ok(page)
+    }
+
+    /// every page, fetched as the stream is polled
+    pub fn pages(self) -> impl Stream<Item = Result<ListPage, NimbusError>> + 'a {
+        // `None` once the last page was returned
+        let token: Option<Option<String>> = Some(None);
+
+        futures::stream::try_unfold((self, token), |(query, token)| async move {
+            let Some(token) = token else {
+                return Ok(None);
+            };
+
+            let page = query.page(token).await?;
+            let next = page.next_page_token.clone().map(Some);
+            Ok(Some((page, (query, next))))
+        })
+    }
+
+    /// every object, pages are fetched as the stream is polled
+    /// common prefixes are only returned by [`ListQuery::pages`]
+    pub fn stream(self) -> impl Stream<Item = Result<ObjectMeta, NimbusError>> + 'a {
+        self.pages()
+            .map_ok(|page| futures::stream::iter(page.objects.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// every object of every page
+    /// fails with [`NimbusError::ResultsTruncated`] or [`NimbusError::PagesTruncated`] past the limits
+    pub async fn collect(self) -> Result<Vec<ObjectMeta>, NimbusError> {
+        let mut drain = self.limits.drain();
+        let mut objects = vec![];
+
+        let mut pages = std::pin::pin!(self.pages());
+        while let Some(page) = pages.try_next().await? {
+            drain.page(page.objects.len(), page.next_page_token.is_some())?;
+            objects.extend(page.objects);
+        }
+
+        Ok(objects)
+    }
+}
+
+/// S3 resumes a listing of versions from two markers, carried in a single page token
+#[cfg(feature = "aws-storage")]
+pub(crate) fn version_token(key_marker: &str, version_id_marker: &str) -> String {
+    serde_json::to_string(&(key_marker, version_id_marker)).expect("markers serialize")
+}
+
+#[cfg(feature = "aws-storage")]
+pub(crate) fn parse_version_token(token: &str) -> Option<(String, String)> {
+    serde_json::from_str(token).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::MemoryStorage;
+
+    use super::*;
+
+    async fn storage() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        for key in ["a/1", "a/2", "a/b/3", "a/c/4", "a/c/5", "z"] {
+            storage
+                .upload_from_bytes("bucket", key, None, key.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        storage
+    }
+
+    fn keys(objects: &[ObjectMeta]) -> Vec<&str> {
+        objects.iter().map(|o| o.key.as_str()).collect()
+    }
+
+    #[test]
+    fn builder_test() {
+        let storage = MemoryStorage::new();
+        let query = storage
+            .list("bucket")
+            .prefix("x/")
+            .delimiter("/")
+            .page_size(500)
+            .versions(true)
+            .start_after("x/1")
+            .start_at("x/0")
+            .end_before("x/9");
+
+        assert_eq!(
+            query.params(),
+            &ListParams {
+                prefix: Some("x/".to_owned()),
+                delimiter: Some("/".to_owned()),
+                page_size: Some(500),
+                versions: true,
+                start_after: Some("x/1".to_owned()),
+                start_at: Some("x/0".to_owned()),
+                end_before: Some("x/9".to_owned()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_prefix_test() {
+        let storage = storage().await;
+
+        let all = storage.list("bucket").collect().await.unwrap();
+        assert_eq!(all.len(), 6);
+
+        let under_a = storage.list("bucket").prefix("a/").collect().await.unwrap();
+        assert_eq!(keys(&under_a), ["a/1", "a/2", "a/b/3", "a/c/4", "a/c/5"]);
+    }
+
+    #[tokio::test]
+    async fn delimiter_test() {
+        let storage = storage().await;
+
+        let page = storage
+            .list("bucket")
+            .prefix("a/")
+            .delimiter("/")
+            .page(None)
+            .await
+            .unwrap();
+
+        assert_eq!(keys(&page.objects), ["a/1", "a/2"]);
+        assert_eq!(page.prefixes, ["a/b/", "a/c/"]);
+        assert!(page.next_page_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn paged_stream_test() {
+        let storage = storage().await;
+
+        let pages: Vec<ListPage> = storage
+            .list("bucket")
+            .page_size(4)
+            .pages()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].objects.len(), 4);
+        assert!(pages[1].next_page_token.is_none());
+
+        let streamed: Vec<ObjectMeta> = storage
+            .list("bucket")
+            .page_size(1)
+            .stream()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            keys(&streamed),
+            ["a/1", "a/2", "a/b/3", "a/c/4", "a/c/5", "z"]
+        );
+    }
+
+    #[tokio::test]
+    async fn start_after_test() {
+        let storage = storage().await;
+
+        let rest = storage
+            .list("bucket")
+            .prefix("a/")
+            .start_after("a/b/3")
+            .page_size(1)
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(keys(&rest), ["a/c/4", "a/c/5"]);
+
+        // a key that isn't there works as well
+        let rest = storage
+            .list("bucket")
+            .start_after("a/bz")
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(keys(&rest), ["a/c/4", "a/c/5", "z"]);
+    }
+
+    #[tokio::test]
+    async fn end_before_test() {
+        let storage = storage().await;
+
+        // both ends exclusive, along with the prefix
+        let between = storage
+            .list("bucket")
+            .prefix("a/")
+            .start_after("a/1")
+            .end_before("a/c/5")
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(keys(&between), ["a/2", "a/b/3", "a/c/4"]);
+
+        // an inclusive start
+        let from = storage
+            .list("bucket")
+            .start_at("a/2")
+            .end_before("a/c")
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(keys(&from), ["a/2", "a/b/3"]);
+
+        // no page is fetched past the end
+        storage.reset_stats();
+        let streamed: Vec<ObjectMeta> = storage
+            .list("bucket")
+            .page_size(2)
+            .end_before("a/b")
+            .stream()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(keys(&streamed), ["a/1", "a/2"]);
+        assert_eq!(storage.stats().calls("list_page"), 1);
+    }
+
+    #[tokio::test]
+    async fn shard_coverage_test() {
+        let storage = MemoryStorage::new();
+        let mut all = vec![];
+        for first in ['a', 'f', 'g', 'm', 'n', 't', 'z'] {
+            for key in [first.to_string(), format!("{first}/1"), format!("{first}x")] {
+                storage
+                    .upload_from_bytes("bucket", &key, None, vec![])
+                    .await
+                    .unwrap();
+                all.push(key);
+            }
+        }
+        all.sort();
+
+        let bounds = [None, Some("f"), Some("m"), Some("t"), None];
+        let mut covered = vec![];
+        for shard in bounds.windows(2) {
+            let mut query = storage.list("bucket").page_size(2);
+            if let Some(start) = shard[0] {
+                query = query.start_at(start);
+            }
+            if let Some(end) = shard[1] {
+                query = query.end_before(end);
+            }
+            let listed = query.collect().await.unwrap();
+            assert!(!listed.is_empty());
+            covered.extend(listed.into_iter().map(|o| o.key));
+        }
+        // every key once, in order
+        assert_eq!(covered, all);
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[test]
+    fn s3_bounds_test() {
+        let params = |start_after: Option<&str>, start_at: Option<&str>| ListParams {
+            start_after: start_after.map(str::to_owned),
+            start_at: start_at.map(str::to_owned),
+            end_before: Some("m".to_owned()),
+            ..Default::default()
+        };
+
+        // S3 lists after a key, the keys before an inclusive start are left out afterwards
+        assert_eq!(
+            params(None, Some("f/1")).exclusive_start().as_deref(),
+            Some("f/")
+        );
+        assert_eq!(params(None, Some("f")).exclusive_start(), None);
+        assert_eq!(
+            params(Some("g"), Some("f/1")).exclusive_start().as_deref(),
+            Some("g")
+        );
+        let bounded = params(None, Some("f/1"));
+        assert!(!bounded.in_bounds("f/0") && bounded.in_bounds("f/1") && !bounded.in_bounds("m"));
+
+        assert!(bounded.prefix_in_bounds("f/"));
+        assert!(bounded.prefix_in_bounds("k/"));
+        assert!(!bounded.prefix_in_bounds("e/"));
+        assert!(!bounded.prefix_in_bounds("m/"));
+        assert!(bounded.past_end("m") && !bounded.past_end("l/z"));
+    }
+
+    #[tokio::test]
+    async fn collect_limits_test() {
+        let storage = storage().await;
+        let query = || storage.list("bucket").page_size(4);
+
+        // the bound is crossed by the second page
+        let err = query()
+            .limits(ListLimits::new().max_results(5))
+            .collect()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::ResultsTruncated {
+                returned: 6,
+                limit: 5
+            }
+        ));
+
+        let err = query()
+            .limits(ListLimits::unlimited().max_pages(1))
+            .collect()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::PagesTruncated { pages: 1, limit: 1 }
+        ));
+
+        let exact = ListLimits::new().max_results(6).max_pages(2);
+        assert_eq!(query().limits(exact).collect().await.unwrap().len(), 6);
+
+        // streams aren't bounded
+        let streamed: Vec<ObjectMeta> = query()
+            .limits(ListLimits::new().max_results(1))
+            .stream()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn skip_placeholders_test() {
+        let storage = storage().await;
+        for key in ["a/", "a/b/", "a/b/c/", "y/"] {
+            storage
+                .upload_from_bytes("bucket", key, None, vec![])
+                .await
+                .unwrap();
+        }
+        // a trailing slash alone doesn't make a placeholder
+        storage
+            .upload_from_bytes("bucket", "x/", None, b"data".to_vec())
+            .await
+            .unwrap();
+
+        let all = storage.list("bucket").collect().await.unwrap();
+        assert_eq!(all.len(), 11);
+        let real = storage
+            .list("bucket")
+            .page_size(2)
+            .skip_placeholders(true)
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            keys(&real),
+            ["a/1", "a/2", "a/b/3", "a/c/4", "a/c/5", "x/", "z"]
+        );
+
+        let page = storage
+            .list("bucket")
+            .prefix("a/b/")
+            .delimiter("/")
+            .skip_placeholders(true)
+            .page(None)
+            .await
+            .unwrap();
+        assert_eq!(keys(&page.objects), ["a/b/3"]);
+        assert_eq!(page.prefixes, ["a/b/c/"]);
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[test]
+    fn version_token_test() {
+        let token = version_token("a/\"b\"\n", "v1");
+        assert_eq!(
+            parse_version_token(&token),
+            Some(("a/\"b\"\n".to_owned(), "v1".to_owned()))
+        );
+        assert_eq!(parse_version_token("garbage"), None);
+    }
+}
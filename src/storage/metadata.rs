@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+
+#[cfg(any(feature = "gcp-storage", feature = "aws-storage"))]
+use super::ObjectExt;
+
+/// Concurrency of the head requests issued by [`super::StorageHelper::objects_metadata_via_listing`]
+pub const DEFAULT_METADATA_CONCURRENCY: usize = 32;
+
+/// Keys sharing a prefix are listed instead of fetched one by one from this many keys on
+pub const LISTING_THRESHOLD: usize = 64;
+
+/// Metadata of a stored object
+///
+/// Fields a provider (or the call used to fetch them) doesn't return are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub updated: Option<DateTime<Utc>>,
+    pub etag: Option<String>,
+    /// base64 of the big-endian CRC32C, as returned by [`super::crc32c_base64`]
+    pub crc32c: Option<String>,
+    /// base64 of the MD5 digest
+    pub md5: Option<String>,
+    /// opaque token identifying this version of the object: the generation on GCS, the ETag on S3
+    pub version: Option<String>,
+    /// as named by the provider, e.g. `NEARLINE` or `STANDARD_IA`, see [`super::StorageClass::is_named`]
+    /// S3 leaves it out of object metadata for `STANDARD`
+    pub storage_class: Option<String>,
+    /// custom metadata, as written by [`super::StorageHelper::upload_with_metadata`]
+    /// S3 returns it from metadata calls only, with its keys in lowercase
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// [`ObjectMeta`], as returned by [`super::StorageHelper::get_metadata`]
+pub type ObjectMetadata = ObjectMeta;
+
+impl ObjectMeta {
+    /// whether the object is a zero-byte "folder" placeholder, as consoles create with a key ending in `/`
+    pub fn is_placeholder_dir(&self) -> bool {
+        self.size == 0 && self.key.ends_with('/')
+    }
+}
+
+/// Server side checksums of an object, from [`super::StorageHelper::object_checksum`]
+///
+/// GCS always returns the CRC32C and, for objects not uploaded by parts, the MD5.
+/// S3 returns the CRC32C only for objects uploaded with one, and never the MD5:
+/// the ETag of a single part upload is its hex MD5 unless the object is encrypted with KMS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectChecksum {
+    pub crc32c: Option<u32>,
+    pub md5: Option<[u8; 16]>,
+    pub etag: String,
+}
+
+impl ObjectChecksum {
+    /// whether `data` has the stored CRC32C, `None` when the provider didn't return one
+    pub fn matches_crc32c(&self, data: &[u8]) -> Option<bool> {
+        self.crc32c.map(|crc| crc == crc32c::crc32c(data))
+    }
+}
+
+impl From<ObjectMeta> for ObjectChecksum {
+    /// checksums that aren't valid base64 of the expected length are dropped
+    fn from(meta: ObjectMeta) -> Self {
+        let decode =
+            |b64: Option<String>| base64::engine::general_purpose::STANDARD.decode(b64?).ok();
+
+        Self {
+            crc32c: decode(meta.crc32c)
+                .and_then(|b| b.try_into().ok())
+                .map(u32::from_be_bytes),
+            md5: decode(meta.md5).and_then(|b| b.try_into().ok()),
+            etag: meta.etag.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(feature = "gcp-storage")]
+impl From<google_cloud_storage::http::objects::Object> for ObjectMeta {
+    fn from(o: google_cloud_storage::http::objects::Object) -> Self {
+        Self {
+            updated: o.updated(),
+            key: o.name,
+            size: o.size.max(0) as u64,
+            content_type: o.content_type,
+            content_encoding: o.content_encoding,
+            etag: Some(o.etag),
+            crc32c: o.crc32c,
+            md5: o.md5_hash,
+            version: Some(o.generation.to_string()),
+            storage_class: o.storage_class,
+            metadata: o.metadata,
+        }
+    }
+}
+
+#[cfg(feature = "aws-storage")]
+fn from_aws_time(t: &aws_sdk_s3::primitives::DateTime) -> Option<DateTime<Utc>> {
+    crate::time::from_aws_datetime(t).ok()
+}
+
+#[cfg(feature = "aws-storage")]
+impl ObjectMeta {
+    pub(crate) fn from_head(
+        key: &str,
+        out: &aws_sdk_s3::operation::head_object::HeadObjectOutput,
+    ) -> Self {
+        Self {
+            key: key.to_owned(),
+            size: out.content_length().unwrap_or_default().max(0) as u64,
+            content_type: out.content_type().map(str::to_owned),
+            content_encoding: out.content_encoding().map(str::to_owned),
+            updated: out.updated(),
+            etag: out.e_tag().map(str::to_owned),
+            crc32c: out.crc32c().map(str::to_owned),
+            md5: None,
+            version: out.e_tag().map(str::to_owned),
+            storage_class: out.storage_class().map(|c| c.as_str().to_owned()),
+            metadata: out.metadata().cloned(),
+        }
+    }
+
+    /// version is the version id, see [`super::ListQuery::versions`]
+    pub(crate) fn from_version(o: &aws_sdk_s3::types::ObjectVersion) -> Self {
+        Self {
+            key: o.key().unwrap_or_default().to_owned(),
+            size: o.size().unwrap_or_default().max(0) as u64,
+            content_type: None,
+            content_encoding: None,
+            updated: o.last_modified().and_then(from_aws_time),
+            etag: o.e_tag().map(str::to_owned),
+            crc32c: None,
+            md5: None,
+            version: o.version_id().map(str::to_owned),
+            storage_class: o.storage_class().map(|c| c.as_str().to_owned()),
+            metadata: None,
+        }
+    }
+
+    pub(crate) fn from_listing(o: &aws_sdk_s3::types::Object) -> Self {
+        Self {
+            key: o.key().unwrap_or_default().to_owned(),
+            size: o.size().unwrap_or_default().max(0) as u64,
+            content_type: None,
+            content_encoding: None,
+            updated: o.last_modified().and_then(from_aws_time),
+            etag: o.e_tag().map(str::to_owned),
+            crc32c: None,
+            md5: None,
+            version: o.e_tag().map(str::to_owned),
+            storage_class: o.storage_class().map(|c| c.as_str().to_owned()),
+            metadata: None,
+        }
+    }
+}
+
+/// How [`super::StorageHelper::objects_metadata_via_listing`] fetches the metadata of a set of keys
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct FetchPlan {
+    /// prefixes to list, with the requested keys under each
+    pub listings: BTreeMap<String, Vec<String>>,
+    /// keys fetched with one metadata call each
+    pub heads: Vec<String>,
+}
+
+/// parent "directory" of a key, including the trailing `/`
+fn parent(key: &str) -> &str {
+    key.rfind('/').map_or("", |i| &key[..=i])
+}
+
+/// group keys by parent directory; directories holding at least `threshold` of the keys are listed,
+/// the remaining keys are fetched one by one
+pub(crate) fn plan_fetch(keys: &[String], threshold: usize) -> FetchPlan {
+    let mut groups: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for key in keys {
+        groups.entry(parent(key)).or_default().push(key.clone());
+    }
+
+    let mut plan = FetchPlan::default();
+    for (prefix, keys) in groups {
+        // listing the whole bucket to find a few keys is never worth it
+        if keys.len() >= threshold.max(1) && !prefix.is_empty() {
+            plan.listings.insert(prefix.to_owned(), keys);
+        } else {
+            plan.heads.extend(keys);
+        }
+    }
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(prefix: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{prefix}{i:05}.json")).collect()
+    }
+
+    #[test]
+    fn object_checksum_test() {
+        let meta = ObjectMeta {
+            key: "k".to_owned(),
+            size: 5,
+            content_type: None,
+            content_encoding: None,
+            updated: None,
+            etag: Some("\"5d41402abc4b2a76b9719d911017c592\"".to_owned()),
+            crc32c: Some(super::super::crc32c_base64(b"hello")),
+            md5: Some("XUFAKrxLKna5cZ2REBfFkg==".to_owned()),
+            version: None,
+            storage_class: None,
+            metadata: None,
+        };
+
+        let checksum = ObjectChecksum::from(meta.clone());
+        assert_eq!(checksum.crc32c, Some(crc32c::crc32c(b"hello")));
+        assert_eq!(
+            checksum.md5.map(hex::encode).as_deref(),
+            Some("5d41402abc4b2a76b9719d911017c592")
+        );
+        assert_eq!(checksum.etag, "\"5d41402abc4b2a76b9719d911017c592\"");
+        assert_eq!(checksum.matches_crc32c(b"hello"), Some(true));
+        assert_eq!(checksum.matches_crc32c(b"hello!"), Some(false));
+
+        let missing = ObjectChecksum::from(ObjectMeta {
+            etag: None,
+            crc32c: None,
+            md5: Some("not base64".to_owned()),
+            ..meta
+        });
+        assert_eq!(missing.crc32c, None);
+        assert_eq!(missing.md5, None);
+        assert_eq!(missing.etag, "");
+        assert_eq!(missing.matches_crc32c(b"hello"), None);
+    }
+
+    #[test]
+    fn parent_test() {
+        assert_eq!(parent("a/b/c.json"), "a/b/");
+        assert_eq!(parent("a/"), "a/");
+        assert_eq!(parent("c.json"), "");
+    }
+
+    #[test]
+    fn plan_clustered_keys_are_listed_test() {
+        let mut all = keys("events/2024/01/", 100);
+        all.extend(keys("events/2024/02/", 3));
+        all.extend(keys("", 100));
+
+        let plan = plan_fetch(&all, LISTING_THRESHOLD);
+
+        assert_eq!(plan.listings.len(), 1);
+        assert_eq!(plan.listings["events/2024/01/"].len(), 100);
+        // small cluster and bucket root are fetched key by key
+        assert_eq!(plan.heads.len(), 103);
+    }
+
+    #[test]
+    fn plan_scattered_keys_are_heads_test() {
+        let all: Vec<String> = (0..50).map(|i| format!("dir{i:02}/file")).collect();
+        let plan = plan_fetch(&all, LISTING_THRESHOLD);
+
+        assert!(plan.listings.is_empty());
+        assert_eq!(plan.heads, all);
+    }
+
+    #[test]
+    fn plan_keeps_every_key_once_test() {
+        let mut all = keys("a/", 70);
+        all.extend(keys("a/b/", 70));
+        all.extend(keys("c/", 1));
+
+        let plan = plan_fetch(&all, LISTING_THRESHOLD);
+        let mut planned: Vec<String> = plan
+            .listings
+            .values()
+            .flatten()
+            .chain(plan.heads.iter())
+            .cloned()
+            .collect();
+        planned.sort();
+        all.sort();
+
+        assert_eq!(planned, all);
+        assert_eq!(plan.listings.keys().collect::<Vec<_>>(), ["a/", "a/b/"]);
+    }
+}
@@ -0,0 +1,1167 @@
+//! Dual writes while cutting over from a bucket to another
+//!
+//! A [`MigratingStorage`] serves the new storage (the primary) and keeps the old one (the secondary) up to date:
+//! - uploads, copies, tags and deletes are applied to the primary, then mirrored to the secondary
+//! - reads are served by the primary, and by the secondary for the objects the primary doesn't have yet
+//! - the rest (listings, versioned reads and conditional deletes, ACLs, CORS, storage classes, replication
+//!   status, policies, signed URLs, resumable uploads) only involves the primary
+//!
+//! Every mirror that failed, every read served by the secondary and every write that couldn't be mirrored
+//! is a [`Divergence`], reported to [`MigratingStorage::on_divergence`] and kept in a log queried with
+//! [`MigratingStorage::divergences`]. No divergence over a representative window, and a clean
+//! [`diff_prefixes`](super::diff_prefixes), is the sign the buckets converged and the secondary can go.
+//!
+//! ```ignore
+//! let storage = MigratingStorage::new(new_client, old_client, MirrorPolicy::BestEffort)
+//!     .map_bucket("media-v2", "media")
+//!     .on_divergence(|d| metrics.record(d));
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use super::{
+    AclEntry, AppendOutcome, CorsRule, EncryptionKey, ListPage, ListParams, ObjectMeta,
+    ObjectStream, PolicyCondition, PostPolicy, Precondition, PublicAccess, ReplicationStatus,
+    ResumableUpload, StorageClass, StorageHelper, UpdateOptions,
+};
+#[cfg(feature = "aws-storage")]
+use crate::TransportConfig;
+use crate::{ByteSize, ErrorCode, ListLimits, NimbusError};
+
+/// Divergences kept by the log unless set with [`MigratingStorage::log_capacity`]
+pub const DEFAULT_DIVERGENCE_LOG_CAPACITY: usize = 1000;
+
+/// How writes are mirrored to the secondary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MirrorPolicy {
+    /// the mirror runs after the write returned, its failure is only reported as a divergence
+    /// the mirrors of a key are applied in the order of its writes
+    BestEffort,
+    /// the write returns once mirrored, and fails if the mirror does
+    /// the primary is written by then, a failed write is not rolled back
+    #[default]
+    Strict,
+}
+
+/// What diverged between the primary and the secondary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DivergenceKind {
+    /// a write applied to the primary failed on the secondary
+    MirrorFailed,
+    /// a read found the object on the secondary only
+    FallbackRead,
+    /// a write went to the primary only, e.g. a resumable upload
+    NotMirrored,
+}
+
+/// Difference between the primary and the secondary, see the [module](self) docs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub kind: DivergenceKind,
+    /// the method of [`StorageHelper`] called
+    pub op: &'static str,
+    /// bucket on the primary
+    pub bucket: String,
+    pub key: String,
+    /// why the mirror failed
+    pub error: Option<(ErrorCode, String)>,
+    pub at: DateTime<Utc>,
+}
+
+/// Divergences per kind since the storage was built or [cleared](MigratingStorage::clear_divergences),
+/// whatever the capacity of the log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DivergenceCounts {
+    pub mirror_failed: u64,
+    pub fallback_reads: u64,
+    pub not_mirrored: u64,
+}
+
+impl DivergenceCounts {
+    pub fn total(&self) -> u64 {
+        self.mirror_failed + self.fallback_reads + self.not_mirrored
+    }
+}
+
+type Observer = dyn Fn(&Divergence) + Send + Sync;
+
+#[derive(Default)]
+struct Log {
+    entries: VecDeque<Divergence>,
+    counts: DivergenceCounts,
+}
+
+/// write to apply to the secondary
+enum Mirror {
+    Upload {
+        mime: Option<String>,
+        data: Vec<u8>,
+    },
+    UploadWithMetadata {
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+    },
+    UploadEncrypted {
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: EncryptionKey,
+    },
+    /// an object already missing is deleted
+    Delete,
+    /// to the key of the mirror, from this source key
+    Copy {
+        source: (String, String),
+        source_encryption: Option<EncryptionKey>,
+        destination_encryption: Option<EncryptionKey>,
+    },
+    Tags(HashMap<String, String>),
+    /// appended concurrently, records may be in another order on the secondary
+    Append {
+        mime: Option<String>,
+        data: Vec<u8>,
+    },
+}
+
+/// the latest best-effort mirror in flight of a key: its sequence number and its completion
+type Tail = (u64, oneshot::Receiver<()>);
+
+struct Shared<P, S> {
+    primary: P,
+    secondary: S,
+    policy: MirrorPolicy,
+    buckets: HashMap<String, String>,
+    capacity: usize,
+    log: Mutex<Log>,
+    observer: Option<Arc<Observer>>,
+    pending: Mutex<Vec<JoinHandle<()>>>,
+    tails: Mutex<HashMap<(String, String), Tail>>,
+    sequence: AtomicU64,
+}
+
+impl<P, S: StorageHelper + Sync> Shared<P, S> {
+    fn secondary_bucket<'b>(&'b self, bucket: &'b str) -> &'b str {
+        self.buckets.get(bucket).map_or(bucket, String::as_str)
+    }
+
+    fn record(
+        &self,
+        kind: DivergenceKind,
+        op: &'static str,
+        bucket: &str,
+        key: &str,
+        error: Option<&NimbusError>,
+    ) {
+        let divergence = Divergence {
+            kind,
+            op,
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            error: error.map(|e| (e.code(), e.to_string())),
+            at: Utc::now(),
+        };
+        if let Some(observer) = &self.observer {
+            observer(&divergence);
+        }
+
+        let mut log = self.log.lock().unwrap();
+        match kind {
+            DivergenceKind::MirrorFailed => log.counts.mirror_failed += 1,
+            DivergenceKind::FallbackRead => log.counts.fallback_reads += 1,
+            DivergenceKind::NotMirrored => log.counts.not_mirrored += 1,
+        }
+        if self.capacity > 0 {
+            if log.entries.len() == self.capacity {
+                log.entries.pop_front();
+            }
+            log.entries.push_back(divergence);
+        }
+    }
+
+    async fn apply(&self, bucket: &str, key: &str, mirror: Mirror) -> Result<(), NimbusError> {
+        let bucket = self.secondary_bucket(bucket);
+        match mirror {
+            Mirror::Upload { mime, data } => {
+                self.secondary
+                    .upload_from_bytes(bucket, key, mime, data)
+                    .await
+            }
+            Mirror::UploadWithMetadata {
+                mime,
+                metadata,
+                data,
+            } => self
+                .secondary
+                .upload_with_metadata(bucket, key, mime, metadata, data, None)
+                .await
+                .map(|_| ()),
+            Mirror::UploadEncrypted {
+                mime,
+                data,
+                encryption,
+            } => {
+                self.secondary
+                    .upload_encrypted(bucket, key, mime, data, &encryption)
+                    .await
+            }
+            Mirror::Delete => match self.secondary.delete_file(bucket, key).await {
+                Err(e) if e.code() == ErrorCode::NotFound => Ok(()),
+                res => res,
+            },
+            Mirror::Copy {
+                source: (source_bucket, source_key),
+                source_encryption,
+                destination_encryption,
+            } => {
+                self.secondary
+                    .copy_encrypted(
+                        (self.secondary_bucket(&source_bucket), &source_key),
+                        source_encryption.as_ref(),
+                        (bucket, key),
+                        destination_encryption.as_ref(),
+                    )
+                    .await
+            }
+            Mirror::Tags(tags) => self.secondary.set_object_tags(bucket, key, tags).await,
+            Mirror::Append { mime, data } => self
+                .secondary
+                .append_bytes(bucket, key, mime, data, UpdateOptions::default())
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+/// Storage writing to two storages and reading from the first, see the [module](self) docs
+///
+/// Clones share their log and pending mirrors. Best-effort mirrors are spawned on the tokio runtime of the caller.
+pub struct MigratingStorage<P, S> {
+    shared: Arc<Shared<P, S>>,
+}
+
+impl<P, S> Clone for MigratingStorage<P, S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<P, S> MigratingStorage<P, S>
+where
+    P: StorageHelper + Send + Sync + 'static,
+    S: StorageHelper + Send + Sync + 'static,
+{
+    /// serve `primary`, mirroring its writes to `secondary` per `policy`
+    pub fn new(primary: P, secondary: S, policy: MirrorPolicy) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                primary,
+                secondary,
+                policy,
+                buckets: HashMap::new(),
+                capacity: DEFAULT_DIVERGENCE_LOG_CAPACITY,
+                log: Mutex::new(Log::default()),
+                observer: None,
+                pending: Mutex::new(vec![]),
+                tails: Mutex::new(HashMap::new()),
+                sequence: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// `primary` on the primary is `secondary` on the secondary, buckets not mapped keep their name
+    pub fn map_bucket(mut self, primary: impl Into<String>, secondary: impl Into<String>) -> Self {
+        self.configure()
+            .buckets
+            .insert(primary.into(), secondary.into());
+        self
+    }
+
+    /// divergences kept by the log, the oldest are dropped first, [`DEFAULT_DIVERGENCE_LOG_CAPACITY`] by default
+    pub fn log_capacity(mut self, capacity: usize) -> Self {
+        self.configure().capacity = capacity;
+        self
+    }
+
+    /// report every divergence to `observer`, e.g. to count them
+    pub fn on_divergence(mut self, observer: impl Fn(&Divergence) + Send + Sync + 'static) -> Self {
+        self.configure().observer = Some(Arc::new(observer));
+        self
+    }
+
+    fn configure(&mut self) -> &mut Shared<P, S> {
+        Arc::get_mut(&mut self.shared).expect("storage is configured before being cloned")
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.shared.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.shared.secondary
+    }
+
+    pub fn policy(&self) -> MirrorPolicy {
+        self.shared.policy
+    }
+
+    /// the divergences logged, oldest first
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.shared
+            .log
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn divergence_counts(&self) -> DivergenceCounts {
+        self.shared.log.lock().unwrap().counts
+    }
+
+    /// empty the log and reset the counts, e.g. to start a verification window
+    pub fn clear_divergences(&self) {
+        *self.shared.log.lock().unwrap() = Log::default();
+    }
+
+    /// wait for the best-effort mirrors in flight
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.shared.pending.lock().unwrap());
+        for mirror in pending {
+            // a panicking mirror is lost like a failed one
+            let _ = mirror.await;
+        }
+    }
+
+    /// apply `mirror` to the secondary after `op` succeeded on the primary
+    async fn mirror(
+        &self,
+        op: &'static str,
+        bucket: &str,
+        key: &str,
+        mirror: Mirror,
+    ) -> Result<(), NimbusError> {
+        match self.shared.policy {
+            MirrorPolicy::Strict => {
+                let res = self.shared.apply(bucket, key, mirror).await;
+                if let Err(e) = &res {
+                    self.shared
+                        .record(DivergenceKind::MirrorFailed, op, bucket, key, Some(e));
+                }
+                res
+            }
+            MirrorPolicy::BestEffort => {
+                // mirrors of a key are applied in the order of the writes, each waits for the previous one
+                let id = (bucket.to_owned(), key.to_owned());
+                let sequence = self.shared.sequence.fetch_add(1, Ordering::Relaxed);
+                let (done, tail) = oneshot::channel();
+                let previous = self
+                    .shared
+                    .tails
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), (sequence, tail))
+                    .map(|(_, previous)| previous);
+
+                let shared = Arc::clone(&self.shared);
+                let handle = tokio::spawn(async move {
+                    let (bucket, key) = &id;
+                    if let Some(previous) = previous {
+                        // closed once the previous mirror is done, even if it panicked
+                        let _ = previous.await;
+                    }
+                    if let Err(e) = shared.apply(bucket, key, mirror).await {
+                        shared.record(DivergenceKind::MirrorFailed, op, bucket, key, Some(&e));
+                    }
+
+                    let mut tails = shared.tails.lock().unwrap();
+                    if tails.get(&id).is_some_and(|(tail, _)| *tail == sequence) {
+                        tails.remove(&id);
+                    }
+                    drop(done);
+                });
+
+                let mut pending = self.shared.pending.lock().unwrap();
+                pending.retain(|mirror| !mirror.is_finished());
+                pending.push(handle);
+                Ok(())
+            }
+        }
+    }
+
+    /// `primary`, or `secondary` if the primary doesn't have the object
+    async fn fallback<T, F>(
+        &self,
+        op: &'static str,
+        bucket: &str,
+        key: &str,
+        primary: Result<T, NimbusError>,
+        secondary: impl FnOnce() -> F,
+    ) -> Result<T, NimbusError>
+    where
+        F: Future<Output = Result<T, NimbusError>>,
+    {
+        match primary {
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                let res = secondary().await;
+                if res.is_ok() {
+                    self.shared
+                        .record(DivergenceKind::FallbackRead, op, bucket, key, None);
+                }
+                // not found anywhere, or failing on the secondary, the caller sees the primary's answer
+                res.map_err(|_| e)
+            }
+            res => res,
+        }
+    }
+
+    fn secondary_bucket<'b>(&'b self, bucket: &'b str) -> &'b str {
+        self.shared.secondary_bucket(bucket)
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, S> StorageHelper for MigratingStorage<P, S>
+where
+    P: StorageHelper + Send + Sync + 'static,
+    S: StorageHelper + Send + Sync + 'static,
+{
+    /// both clients from the default provider chain, mirrored strictly
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_authenticator() -> Self {
+        Self::new(
+            P::new_with_authenticator().await,
+            S::new_with_authenticator().await,
+            MirrorPolicy::Strict,
+        )
+    }
+
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Self::new(
+            P::new_with_transport(transport).await,
+            S::new_with_transport(transport).await,
+            MirrorPolicy::Strict,
+        )
+    }
+
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        let mirror = Mirror::Upload {
+            mime: mime.clone(),
+            data: data.clone(),
+        };
+        self.primary()
+            .upload_from_bytes(bucket, key, mime, data)
+            .await?;
+        self.mirror("upload_from_bytes", bucket, key, mirror).await
+    }
+
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        let primary = self.primary().download_to_bytes(bucket, key).await;
+        self.fallback("download_to_bytes", bucket, key, primary, || {
+            self.secondary()
+                .download_to_bytes(self.secondary_bucket(bucket), key)
+        })
+        .await
+    }
+
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        let primary = self.primary().download_stream(bucket, key).await;
+        self.fallback("download_stream", bucket, key, primary, || {
+            self.secondary()
+                .download_stream(self.secondary_bucket(bucket), key)
+        })
+        .await
+    }
+
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let primary = self
+            .primary()
+            .download_with_encoding(bucket, key, decompress)
+            .await;
+        self.fallback("download_with_encoding", bucket, key, primary, || {
+            self.secondary()
+                .download_with_encoding(self.secondary_bucket(bucket), key, decompress)
+        })
+        .await
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let primary = self.primary().download_range(bucket, key, start, end).await;
+        self.fallback("download_range", bucket, key, primary, || {
+            self.secondary()
+                .download_range(self.secondary_bucket(bucket), key, start, end)
+        })
+        .await
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        let mirror = Mirror::UploadEncrypted {
+            mime: mime.clone(),
+            data: data.clone(),
+            encryption: encryption.clone(),
+        };
+        self.primary()
+            .upload_encrypted(bucket, key, mime, data, encryption)
+            .await?;
+        self.mirror("upload_encrypted", bucket, key, mirror).await
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let primary = self
+            .primary()
+            .download_encrypted(bucket, key, encryption)
+            .await;
+        self.fallback("download_encrypted", bucket, key, primary, || {
+            self.secondary()
+                .download_encrypted(self.secondary_bucket(bucket), key, encryption)
+        })
+        .await
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        let primary = self
+            .primary()
+            .object_metadata_encrypted(bucket, key, encryption)
+            .await;
+        self.fallback("object_metadata_encrypted", bucket, key, primary, || {
+            self.secondary().object_metadata_encrypted(
+                self.secondary_bucket(bucket),
+                key,
+                encryption,
+            )
+        })
+        .await
+    }
+
+    /// copied on each side from its own copy of the source
+    async fn copy_encrypted(
+        &self,
+        source: (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        destination: (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        self.primary()
+            .copy_encrypted(
+                source,
+                source_encryption,
+                destination,
+                destination_encryption,
+            )
+            .await?;
+        let mirror = Mirror::Copy {
+            source: (source.0.to_owned(), source.1.to_owned()),
+            source_encryption: source_encryption.cloned(),
+            destination_encryption: destination_encryption.cloned(),
+        };
+        self.mirror("copy_encrypted", destination.0, destination.1, mirror)
+            .await
+    }
+
+    /// deleted on both sides, an object missing on one side only is deleted
+    /// fails with [`ErrorCode::NotFound`] if it is missing on both
+    /// archival is of the primary, the secondary goes away with its classes
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        self.primary().set_storage_class(bucket, key, class).await
+    }
+
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        match self.primary().delete_file(bucket, key).await {
+            Ok(()) => {
+                self.mirror("delete_file", bucket, key, Mirror::Delete)
+                    .await
+            }
+            // maybe not migrated yet, the secondary decides
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                match self
+                    .secondary()
+                    .delete_file(self.secondary_bucket(bucket), key)
+                    .await
+                {
+                    Ok(()) => Ok(()),
+                    Err(secondary) if secondary.code() == ErrorCode::NotFound => Err(e),
+                    Err(secondary) => {
+                        self.shared.record(
+                            DivergenceKind::MirrorFailed,
+                            "delete_file",
+                            bucket,
+                            key,
+                            Some(&secondary),
+                        );
+                        Err(secondary)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        if self.primary().object_exists(bucket, key).await? {
+            return Ok(true);
+        }
+        let exists = self
+            .secondary()
+            .object_exists(self.secondary_bucket(bucket), key)
+            .await?;
+        if exists {
+            self.shared.record(
+                DivergenceKind::FallbackRead,
+                "object_exists",
+                bucket,
+                key,
+                None,
+            );
+        }
+        Ok(exists)
+    }
+
+    /// the precondition applies to the primary, the mirror is unconditional
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        let mirror = Mirror::Upload {
+            mime: mime.clone(),
+            data: data.clone(),
+        };
+        let version = self
+            .primary()
+            .upload_conditional(bucket, key, mime, data, precondition)
+            .await?;
+        self.mirror("upload_conditional", bucket, key, mirror)
+            .await?;
+        Ok(version)
+    }
+
+    /// the precondition applies to the primary, the mirror is unconditional
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        let mirror = Mirror::UploadWithMetadata {
+            mime: mime.clone(),
+            metadata: metadata.clone(),
+            data: data.clone(),
+        };
+        let version = self
+            .primary()
+            .upload_with_metadata(bucket, key, mime, metadata, data, precondition)
+            .await?;
+        self.mirror("upload_with_metadata", bucket, key, mirror)
+            .await?;
+        Ok(version)
+    }
+
+    /// versions are the primary's, the mirror deletes whatever the secondary has
+    /// appended to the secondary as well, with the default options
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        let mirror = Mirror::Append {
+            mime: mime.clone(),
+            data: data.clone(),
+        };
+        let outcome = self
+            .primary()
+            .append_bytes(bucket, key, mime, data, options)
+            .await?;
+        self.mirror("append_bytes", bucket, key, mirror).await?;
+        Ok(outcome)
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        self.primary()
+            .delete_conditional(bucket, key, version)
+            .await?;
+        self.mirror("delete_conditional", bucket, key, Mirror::Delete)
+            .await
+    }
+
+    /// from the primary only, versions of the secondary wouldn't match its conditional writes
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        self.primary().download_versioned(bucket, key).await
+    }
+
+    /// the primary only, objects not migrated yet are not listed
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        self.primary().list_page(bucket, params, page_token).await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        let primary = self.primary().object_metadata(bucket, key).await;
+        self.fallback("object_metadata", bucket, key, primary, || {
+            self.secondary()
+                .object_metadata(self.secondary_bucket(bucket), key)
+        })
+        .await
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        self.primary().replication_status(bucket, key).await
+    }
+
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        let mirror = Mirror::Tags(tags.clone());
+        self.primary().set_object_tags(bucket, key, tags).await?;
+        self.mirror("set_object_tags", bucket, key, mirror).await
+    }
+
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        let primary = self.primary().get_object_tags(bucket, key).await;
+        self.fallback("get_object_tags", bucket, key, primary, || {
+            self.secondary()
+                .get_object_tags(self.secondary_bucket(bucket), key)
+        })
+        .await
+    }
+
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        self.primary()
+            .list_soft_deleted(bucket, prefix, limits)
+            .await
+    }
+
+    /// restored on the primary only, reported as [`DivergenceKind::NotMirrored`]
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        self.primary()
+            .restore_object(bucket, key, generation)
+            .await?;
+        self.shared.record(
+            DivergenceKind::NotMirrored,
+            "restore_object",
+            bucket,
+            key,
+            None,
+        );
+        Ok(())
+    }
+
+    async fn test_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        self.primary().test_permissions(bucket, permissions).await
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        self.primary().bucket_is_public(bucket).await
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        self.primary().object_acls_apply(bucket).await
+    }
+
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        self.primary().object_acl(bucket, key).await
+    }
+
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        self.primary().get_cors(bucket).await
+    }
+
+    /// browsers are served by the primary, the secondary keeps its rules
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        self.primary().set_cors(bucket, rules).await
+    }
+
+    /// browsers upload to the primary only
+    async fn signed_post_policy(
+        &self,
+        bucket: &str,
+        key_prefix: &str,
+        max_size: ByteSize,
+        expires: Duration,
+        conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        self.primary()
+            .signed_post_policy(bucket, key_prefix, max_size, expires, conditions)
+            .await
+    }
+
+    /// signed by the primary, the URL of an object the primary doesn't have yet fails when used
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.primary()
+            .signed_download_url(bucket, key, expires_in)
+            .await
+    }
+
+    /// browsers upload to the primary only
+    async fn signed_upload_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.primary()
+            .signed_upload_url(bucket, key, mime, expires_in)
+            .await
+    }
+
+    /// uploaded to the primary only, reported as [`DivergenceKind::NotMirrored`] when started
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        let upload = self
+            .primary()
+            .start_resumable_upload(bucket, key, mime)
+            .await?;
+        self.shared.record(
+            DivergenceKind::NotMirrored,
+            "start_resumable_upload",
+            bucket,
+            key,
+            None,
+        );
+        Ok(upload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    fn storage(policy: MirrorPolicy) -> MigratingStorage<MemoryStorage, MemoryStorage> {
+        MigratingStorage::new(MemoryStorage::new(), MemoryStorage::new(), policy)
+            .map_bucket("new", "old")
+    }
+
+    fn fail_uploads(storage: &MemoryStorage) {
+        storage.mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+    }
+
+    #[tokio::test]
+    async fn dual_write_test() {
+        let storage = storage(MirrorPolicy::Strict);
+        storage
+            .upload_from_bytes("new", "a", None, b"a".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .primary()
+                .download_to_bytes("new", "a")
+                .await
+                .unwrap(),
+            b"a"
+        );
+        assert_eq!(
+            storage
+                .secondary()
+                .download_to_bytes("old", "a")
+                .await
+                .unwrap(),
+            b"a"
+        );
+
+        storage
+            .set_object_tags(
+                "new",
+                "a",
+                HashMap::from([("t".to_owned(), "1".to_owned())]),
+            )
+            .await
+            .unwrap();
+        storage
+            .copy_encrypted(("new", "a"), None, ("new", "b"), None)
+            .await
+            .unwrap();
+        assert!(storage.secondary().object_exists("old", "b").await.unwrap());
+        assert_eq!(
+            storage
+                .secondary()
+                .get_object_tags("old", "a")
+                .await
+                .unwrap()["t"],
+            "1"
+        );
+        assert_eq!(storage.divergence_counts().total(), 0);
+    }
+
+    #[tokio::test]
+    async fn fallback_read_test() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let observed = seen.clone();
+        let storage = storage(MirrorPolicy::Strict)
+            .on_divergence(move |d| observed.lock().unwrap().push(d.clone()));
+        storage
+            .secondary()
+            .upload_from_bytes("old", "legacy", None, b"old".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.download_to_bytes("new", "legacy").await.unwrap(),
+            b"old"
+        );
+        assert!(storage.object_exists("new", "legacy").await.unwrap());
+        assert_eq!(
+            storage.object_metadata("new", "legacy").await.unwrap().size,
+            3
+        );
+        // versions are the primary's
+        let err = storage
+            .download_versioned("new", "legacy")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let err = storage
+            .download_to_bytes("new", "nowhere")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let divergences = storage.divergences();
+        assert_eq!(divergences.len(), 3);
+        assert!(divergences
+            .iter()
+            .all(|d| d.kind == DivergenceKind::FallbackRead
+                && d.bucket == "new"
+                && d.key == "legacy"));
+        assert_eq!(divergences[0].op, "download_to_bytes");
+        assert_eq!(*seen.lock().unwrap(), divergences);
+        assert_eq!(storage.divergence_counts().fallback_reads, 3);
+    }
+
+    #[tokio::test]
+    async fn strict_mirror_failure_test() {
+        let storage = storage(MirrorPolicy::Strict);
+        fail_uploads(storage.secondary());
+
+        let err = storage
+            .upload_from_bytes("new", "a", None, b"a".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        // not rolled back
+        assert!(storage.primary().object_exists("new", "a").await.unwrap());
+
+        let divergences = storage.divergences();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].kind, DivergenceKind::MirrorFailed);
+        assert_eq!(divergences[0].op, "upload_from_bytes");
+        assert_eq!(
+            divergences[0].error.as_ref().unwrap().0,
+            ErrorCode::Unavailable
+        );
+    }
+
+    #[tokio::test]
+    async fn best_effort_mirror_failure_test() {
+        let storage = storage(MirrorPolicy::BestEffort);
+        fail_uploads(storage.secondary());
+
+        storage
+            .upload_from_bytes("new", "a", None, b"a".to_vec())
+            .await
+            .unwrap();
+        storage.flush().await;
+        assert!(!storage.secondary().object_exists("old", "a").await.unwrap());
+        assert_eq!(storage.divergence_counts().mirror_failed, 1);
+
+        storage.secondary().mock_stats().clear_faults();
+        storage
+            .upload_from_bytes("new", "b", None, b"b".to_vec())
+            .await
+            .unwrap();
+        storage.flush().await;
+        assert!(storage.secondary().object_exists("old", "b").await.unwrap());
+        assert_eq!(storage.divergence_counts().total(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn best_effort_mirror_order_test() {
+        let storage = storage(MirrorPolicy::BestEffort);
+        // a slow upload mirror, overtaken by the later delete without ordering
+        storage.secondary().mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new().latency(Latency::Fixed(Duration::from_millis(50))),
+        );
+
+        storage
+            .upload_from_bytes("new", "a", None, b"a".to_vec())
+            .await
+            .unwrap();
+        storage.delete_file("new", "a").await.unwrap();
+        storage
+            .upload_from_bytes("new", "b", None, b"b".to_vec())
+            .await
+            .unwrap();
+        storage.flush().await;
+
+        assert!(!storage.secondary().object_exists("old", "a").await.unwrap());
+        assert!(storage.secondary().object_exists("old", "b").await.unwrap());
+        assert_eq!(storage.divergence_counts().total(), 0);
+        // no key is left waiting
+        assert!(storage.shared.tails.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn delete_test() {
+        let storage = storage(MirrorPolicy::Strict);
+        storage
+            .upload_from_bytes("new", "both", None, vec![])
+            .await
+            .unwrap();
+        storage
+            .primary()
+            .upload_from_bytes("new", "primary", None, vec![])
+            .await
+            .unwrap();
+        storage
+            .secondary()
+            .upload_from_bytes("old", "secondary", None, vec![])
+            .await
+            .unwrap();
+
+        for key in ["both", "primary", "secondary"] {
+            storage.delete_file("new", key).await.unwrap();
+            assert!(!storage.object_exists("new", key).await.unwrap());
+        }
+        let err = storage.delete_file("new", "nowhere").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(storage.divergence_counts().total(), 0);
+    }
+
+    #[tokio::test]
+    async fn divergence_log_test() {
+        let storage = storage(MirrorPolicy::Strict).log_capacity(2);
+        for i in 0..3 {
+            storage
+                .secondary()
+                .upload_from_bytes("old", &format!("k{i}"), None, vec![])
+                .await
+                .unwrap();
+            storage
+                .download_to_bytes("new", &format!("k{i}"))
+                .await
+                .unwrap();
+        }
+
+        // the oldest are dropped, the counts keep counting
+        let keys: Vec<_> = storage.divergences().into_iter().map(|d| d.key).collect();
+        assert_eq!(keys, ["k1", "k2"]);
+        assert_eq!(storage.divergence_counts().fallback_reads, 3);
+
+        storage.clear_divergences();
+        assert!(storage.divergences().is_empty());
+        assert_eq!(storage.divergence_counts(), DivergenceCounts::default());
+    }
+}
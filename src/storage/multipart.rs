@@ -0,0 +1,474 @@
+use std::fmt::Display;
+
+use futures::{Stream, StreamExt};
+
+use super::resumable::is_valid_chunk_size;
+use super::{Error, ResumableUpload, StorageHelper};
+use crate::{ByteSize, NimbusError};
+
+/// Bytes buffered before the content type of a field is detected, unless the field is shorter
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+/// Bytes buffered and sent per chunk unless set with [`UploadConstraints::buffer_size`]
+pub const DEFAULT_BUFFER_SIZE: ByteSize = ByteSize::mib(8);
+
+/// What is known of a field once its first bytes arrived, passed to the key function
+/// of [`StorageHelper::upload_multipart_field`]
+///
+/// The type is detected from the content, the name and type declared by the client aren't trusted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartInfo {
+    /// e.g. `image/png`, `None` if the content type wasn't recognized
+    pub content_type: Option<String>,
+    /// e.g. `png`
+    pub extension: Option<String>,
+}
+
+impl PartInfo {
+    fn detect(data: &[u8]) -> Self {
+        let detected = infer::get(data);
+        Self {
+            content_type: detected.map(|t| t.mime_type().to_owned()),
+            extension: detected.map(|t| t.extension().to_owned()),
+        }
+    }
+}
+
+/// Limits enforced while a field is streamed into storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadConstraints {
+    pub max_size: Option<ByteSize>,
+    /// accepted types, as MIME types or extensions; any type, recognized or not, when empty
+    pub allowed_types: Vec<String>,
+    /// buffered before a chunk is sent, see [`UploadConstraints::buffer_size`]
+    pub buffer_size: ByteSize,
+}
+
+impl Default for UploadConstraints {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            allowed_types: vec![],
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+impl UploadConstraints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a bare `u64` is a number of bytes
+    pub fn max_size(mut self, max_size: impl Into<ByteSize>) -> Self {
+        self.max_size = Some(max_size.into());
+        self
+    }
+
+    /// accept a MIME type e.g. `image/png` or an extension e.g. `png`
+    pub fn allow_type(mut self, content_type: impl Into<String>) -> Self {
+        self.allowed_types.push(content_type.into());
+        self
+    }
+
+    /// memory held per upload, and sent per chunk once a field outgrows one buffer
+    ///
+    /// Larger buffers mean fewer requests on big transfers, smaller ones less memory per concurrent upload.
+    /// Must be at least [`super::MIN_CHUNK_SIZE`] and a multiple of [`super::CHUNK_ALIGNMENT`], uploads fail with
+    /// [`Error::InvalidBufferSize`] otherwise.
+    pub fn buffer_size(mut self, buffer_size: impl Into<ByteSize>) -> Self {
+        self.buffer_size = buffer_size.into();
+        self
+    }
+
+    fn check_buffer_size(&self) -> Result<(), Error> {
+        if !is_valid_chunk_size(self.buffer_size) {
+            return Err(Error::InvalidBufferSize(self.buffer_size));
+        }
+
+        Ok(())
+    }
+
+    fn check_type(&self, info: &PartInfo) -> Result<(), Error> {
+        if self.allowed_types.is_empty() {
+            return Ok(());
+        }
+
+        let (Some(content_type), Some(extension)) = (&info.content_type, &info.extension) else {
+            return Err(Error::InvalidFileType("Failed to get file type".to_owned()));
+        };
+        if self
+            .allowed_types
+            .iter()
+            .any(|t| t == content_type || t == extension)
+        {
+            return Ok(());
+        }
+
+        Err(Error::InvalidFileType(format!(
+            "File type is not valid. Expected one of: {}, got: {content_type}",
+            self.allowed_types.join(", ")
+        )))
+    }
+}
+
+/// Object written by [`StorageHelper::upload_multipart_field`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedObject {
+    pub key: String,
+    pub size: u64,
+    /// detected content type, stored on the object
+    pub content_type: Option<String>,
+}
+
+pub(crate) async fn upload_field<S, B, E>(
+    storage: &S,
+    bucket: &str,
+    key_fn: impl Fn(&PartInfo) -> String,
+    field: impl Stream<Item = Result<B, E>>,
+    constraints: &UploadConstraints,
+) -> Result<UploadedObject, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    constraints.check_buffer_size()?;
+
+    let mut field = std::pin::pin!(field);
+    let mut upload = Upload {
+        storage,
+        bucket,
+        key_fn,
+        info: None,
+        key: None,
+        session: None,
+        buffer_size: constraints.buffer_size.as_usize(),
+        buffer: vec![],
+    };
+    let mut size = 0u64;
+
+    while let Some(chunk) = field.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let failure = Error::Other(format!("Upload stream failed: {e}"));
+                return Err(upload.abort(failure.into()).await);
+            }
+        };
+        let chunk = chunk.as_ref();
+
+        size += chunk.len() as u64;
+        if let Some(limit) = constraints.max_size.filter(|limit| size > limit.bytes()) {
+            return Err(upload.abort(Error::UploadTooLarge { limit }.into()).await);
+        }
+        upload.buffer.extend_from_slice(chunk);
+
+        if upload.info.is_none() && upload.buffer.len() >= SNIFF_LEN {
+            if let Err(e) = upload.detect(constraints) {
+                return Err(upload.abort(e.into()).await);
+            }
+        }
+        if let Err(e) = upload.send_full_chunks().await {
+            return Err(upload.abort(e).await);
+        }
+    }
+
+    if upload.info.is_none() {
+        upload.detect(constraints)?;
+    }
+    upload.finish(size).await
+}
+
+/// state of a field being uploaded, a resumable session is only started once a full buffer is held
+struct Upload<'a, S: ?Sized, F> {
+    storage: &'a S,
+    bucket: &'a str,
+    key_fn: F,
+    info: Option<PartInfo>,
+    key: Option<String>,
+    session: Option<ResumableUpload>,
+    buffer_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl<S, F> Upload<'_, S, F>
+where
+    S: StorageHelper + Sync + ?Sized,
+    F: Fn(&PartInfo) -> String,
+{
+    fn detect(&mut self, constraints: &UploadConstraints) -> Result<(), Error> {
+        let info = PartInfo::detect(&self.buffer);
+        constraints.check_type(&info)?;
+        self.key = Some((self.key_fn)(&info));
+        self.info = Some(info);
+        Ok(())
+    }
+
+    async fn send_full_chunks(&mut self) -> Result<(), NimbusError> {
+        let (Some(info), Some(key)) = (&self.info, &self.key) else {
+            return Ok(());
+        };
+
+        while self.buffer.len() >= self.buffer_size {
+            let session = match &mut self.session {
+                Some(session) => session,
+                None => self.session.insert(
+                    self.storage
+                        .start_resumable_upload(self.bucket, key, info.content_type.clone())
+                        .await?,
+                ),
+            };
+
+            let rest = self.buffer.split_off(self.buffer_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            session.upload_chunk(chunk).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self, size: u64) -> Result<UploadedObject, NimbusError> {
+        let info = self.info.take().unwrap_or_default();
+        let key = self.key.take().unwrap_or_else(|| (self.key_fn)(&info));
+
+        match self.session.take() {
+            Some(mut session) => {
+                session
+                    .upload_chunk(std::mem::take(&mut self.buffer))
+                    .await?;
+                session.finish().await?;
+            }
+            None => {
+                self.storage
+                    .upload_from_bytes(
+                        self.bucket,
+                        &key,
+                        info.content_type.clone(),
+                        std::mem::take(&mut self.buffer),
+                    )
+                    .await?
+            }
+        }
+
+        Ok(UploadedObject {
+            key,
+            size,
+            content_type: info.content_type,
+        })
+    }
+
+    /// drop the chunks sent so far, returns `error`
+    async fn abort(mut self, error: NimbusError) -> NimbusError {
+        if let Some(session) = self.session.take() {
+            // the upload failed already, a failing abort leaves the provider to expire the session
+            let _ = session.abort().await;
+        }
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::storage::{CHUNK_ALIGNMENT, MIN_CHUNK_SIZE};
+    use crate::testing::MemoryStorage;
+    use crate::ErrorCode;
+
+    const PNG: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    const MIB: usize = 1024 * 1024;
+
+    /// a PNG of `chunks` chunks of `chunk_len` bytes, counting the chunks polled
+    fn png_field(
+        chunks: usize,
+        chunk_len: usize,
+        polled: &AtomicUsize,
+    ) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> + '_ {
+        futures::stream::iter(0..chunks).map(move |i| {
+            polled.fetch_add(1, Ordering::SeqCst);
+            let mut chunk = vec![i as u8; chunk_len];
+            if i == 0 {
+                chunk[..PNG.len()].copy_from_slice(&PNG);
+            }
+            Ok(chunk)
+        })
+    }
+
+    fn key_fn(info: &PartInfo) -> String {
+        format!(
+            "uploads/avatar.{}",
+            info.extension.as_deref().unwrap_or("bin")
+        )
+    }
+
+    #[tokio::test]
+    async fn small_field_test() {
+        let storage = MemoryStorage::new();
+        let polled = AtomicUsize::new(0);
+        let constraints = UploadConstraints::new()
+            .max_size(ByteSize::mib(1))
+            .allow_type("png");
+
+        let uploaded = storage
+            .upload_multipart_field("b", key_fn, png_field(4, 1000, &polled), constraints)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            uploaded,
+            UploadedObject {
+                key: "uploads/avatar.png".to_owned(),
+                size: 4000,
+                content_type: Some("image/png".to_owned()),
+            }
+        );
+        let meta = storage.object_metadata("b", &uploaded.key).await.unwrap();
+        assert_eq!(meta.size, 4000);
+        assert_eq!(meta.content_type.as_deref(), Some("image/png"));
+        assert_eq!(storage.stats().calls("start_resumable_upload"), 0);
+    }
+
+    #[tokio::test]
+    async fn chunked_field_test() {
+        let storage = MemoryStorage::new();
+        let polled = AtomicUsize::new(0);
+
+        let uploaded = storage
+            .upload_multipart_field(
+                "b",
+                key_fn,
+                png_field(3, 4 * MIB, &polled),
+                UploadConstraints::new().allow_type("image/png"),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(uploaded.size, 12 * MIB as u64);
+        let data = storage.download_to_bytes("b", &uploaded.key).await.unwrap();
+        assert_eq!(data.len(), 12 * MIB);
+        assert_eq!(data[..PNG.len()], PNG);
+        assert_eq!(data[12 * MIB - 1], 2);
+        assert_eq!(storage.stats().calls("start_resumable_upload"), 1);
+    }
+
+    #[tokio::test]
+    async fn buffer_size_test() {
+        let storage = MemoryStorage::new();
+        let polled = AtomicUsize::new(0);
+
+        // 5 MiB chunks are sent as soon as 8 MiB are held, then the 7 MiB left
+        let uploaded = storage
+            .upload_multipart_field(
+                "b",
+                key_fn,
+                png_field(3, 4 * MIB, &polled),
+                UploadConstraints::new().buffer_size(MIN_CHUNK_SIZE),
+            )
+            .await
+            .unwrap();
+        let data = storage.download_to_bytes("b", &uploaded.key).await.unwrap();
+        assert_eq!(data.len(), 12 * MIB);
+        assert_eq!((data[0], data[4 * MIB], data[8 * MIB]), (PNG[0], 1, 2));
+
+        let min = MIN_CHUNK_SIZE.bytes();
+        for invalid in [MIB as u64, min + 1, min + CHUNK_ALIGNMENT.bytes() / 2] {
+            let constraints = UploadConstraints::new().buffer_size(invalid);
+            let err = storage
+                .upload_multipart_field("b", key_fn, png_field(1, 10, &polled), constraints)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, NimbusError::StorageClient(Error::InvalidBufferSize(size)) if size.bytes() == invalid)
+            );
+            assert_eq!(err.code(), ErrorCode::InvalidInput);
+        }
+        // nothing is read from a field that can't be uploaded
+        assert_eq!(polled.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn size_limit_aborts_mid_stream_test() {
+        let storage = MemoryStorage::new();
+        let polled = AtomicUsize::new(0);
+
+        // the first 8 MiB chunk is sent before the limit is crossed
+        let err = storage
+            .upload_multipart_field(
+                "b",
+                key_fn,
+                png_field(5, 4 * MIB, &polled),
+                UploadConstraints::new().max_size(ByteSize::mib(11)),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::UploadTooLarge { limit }) if limit == ByteSize::mib(11)
+        ));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert_eq!(polled.load(Ordering::SeqCst), 3);
+        assert_eq!(storage.stats().calls("start_resumable_upload"), 1);
+        assert!(!storage
+            .object_exists("b", "uploads/avatar.png")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejected_type_test() {
+        let storage = MemoryStorage::new();
+        let polled = AtomicUsize::new(0);
+        let constraints = UploadConstraints::new().allow_type("jpg");
+
+        let err = storage
+            .upload_multipart_field("b", key_fn, png_field(3, 4 * MIB, &polled), constraints)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::InvalidFileType(_))
+        ));
+        // rejected from the first chunk
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+
+        let text = futures::stream::iter([Ok::<_, std::io::Error>(b"hello".to_vec())]);
+        let err = storage
+            .upload_multipart_field(
+                "b",
+                key_fn,
+                text,
+                UploadConstraints::new().allow_type("png"),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::InvalidFileType(_))
+        ));
+        assert_eq!(storage.stats().calls("upload_from_bytes"), 0);
+    }
+
+    #[tokio::test]
+    async fn stream_error_aborts_test() {
+        let storage = MemoryStorage::new();
+        let field = futures::stream::iter([
+            Ok(vec![0; 6 * MIB]),
+            Err(std::io::Error::other("connection reset")),
+        ]);
+
+        let err = storage
+            .upload_multipart_field(
+                "b",
+                |_: &PartInfo| "k".to_owned(),
+                field,
+                UploadConstraints::new(),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("connection reset"), "{err}");
+        assert!(!storage.object_exists("b", "k").await.unwrap());
+    }
+}
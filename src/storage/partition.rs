@@ -0,0 +1,373 @@
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeZone, Timelike, Utc};
+
+/// Size of a time partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Granularity {
+    Hourly,
+    Daily,
+    Monthly,
+}
+
+/// prefix levels, finest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Unit {
+    Hour,
+    Day,
+    Month,
+    Year,
+}
+
+impl Unit {
+    const LARGEST_FIRST: [Unit; 4] = [Unit::Year, Unit::Month, Unit::Day, Unit::Hour];
+
+    fn floor(self, ts: DateTime<Utc>) -> DateTime<Utc> {
+        let (year, month, day, hour) = match self {
+            Unit::Hour => (ts.year(), ts.month(), ts.day(), ts.hour()),
+            Unit::Day => (ts.year(), ts.month(), ts.day(), 0),
+            Unit::Month => (ts.year(), ts.month(), 1, 0),
+            Unit::Year => (ts.year(), 1, 1, 0),
+        };
+
+        Utc.with_ymd_and_hms(year, month, day, hour, 0, 0)
+            .single()
+            .expect("valid UTC date")
+    }
+
+    fn next(self, ts: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Unit::Hour => ts + chrono::Duration::hours(1),
+            Unit::Day => ts + chrono::Duration::days(1),
+            Unit::Month => ts + Months::new(1),
+            Unit::Year => ts + Months::new(12),
+        }
+    }
+
+    fn is_aligned(self, ts: DateTime<Utc>) -> bool {
+        self.floor(ts) == ts
+    }
+
+    fn path(self, ts: DateTime<Utc>) -> String {
+        match self {
+            Unit::Hour => format!(
+                "{:04}/{:02}/{:02}/{:02}/",
+                ts.year(),
+                ts.month(),
+                ts.day(),
+                ts.hour()
+            ),
+            Unit::Day => format!("{:04}/{:02}/{:02}/", ts.year(), ts.month(), ts.day()),
+            Unit::Month => format!("{:04}/{:02}/", ts.year(), ts.month()),
+            Unit::Year => format!("{:04}/", ts.year()),
+        }
+    }
+}
+
+impl From<Granularity> for Unit {
+    fn from(g: Granularity) -> Self {
+        match g {
+            Granularity::Hourly => Unit::Hour,
+            Granularity::Daily => Unit::Day,
+            Granularity::Monthly => Unit::Month,
+        }
+    }
+}
+
+/// Time partitioned key layout: `base/YYYY/MM/DD/HH/suffix` (hourly),
+/// `base/YYYY/MM/DD/suffix` (daily) or `base/YYYY/MM/suffix` (monthly)
+///
+/// All times are UTC. Ranges are half open: `from` is inclusive, `to` is exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionScheme {
+    base: String,
+    granularity: Granularity,
+}
+
+impl PartitionScheme {
+    pub fn new(base: &str, granularity: Granularity) -> Self {
+        let base = match base.trim_end_matches('/') {
+            "" => String::new(),
+            b => format!("{b}/"),
+        };
+
+        Self { base, granularity }
+    }
+
+    /// `base/YYYY/MM/DD/HH/`
+    pub fn hourly(base: &str) -> Self {
+        Self::new(base, Granularity::Hourly)
+    }
+
+    /// `base/YYYY/MM/DD/`
+    pub fn daily(base: &str) -> Self {
+        Self::new(base, Granularity::Daily)
+    }
+
+    /// `base/YYYY/MM/`
+    pub fn monthly(base: &str) -> Self {
+        Self::new(base, Granularity::Monthly)
+    }
+
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    /// prefix of the partition containing `timestamp`
+    pub fn prefix_for(&self, timestamp: DateTime<Utc>) -> String {
+        let unit = Unit::from(self.granularity);
+        format!("{}{}", self.base, unit.path(unit.floor(timestamp)))
+    }
+
+    /// key for an object written at `timestamp`
+    pub fn key_for(&self, timestamp: DateTime<Utc>, suffix: &str) -> String {
+        format!("{}{}", self.prefix_for(timestamp), suffix)
+    }
+
+    /// start of the partition a key belongs to
+    /// `None` if the key does not follow the scheme
+    pub fn partition_of(&self, key: &str) -> Option<DateTime<Utc>> {
+        let rest = key.strip_prefix(self.base.as_str())?;
+        let depth = match self.granularity {
+            Granularity::Monthly => 2,
+            Granularity::Daily => 3,
+            Granularity::Hourly => 4,
+        };
+
+        let mut parts = rest.splitn(depth + 1, '/');
+        let mut fields = [1u32; 4];
+        for (i, field) in fields.iter_mut().enumerate().take(depth) {
+            let part = parts.next()?;
+            let width = if i == 0 { 4 } else { 2 };
+            if part.len() != width || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            *field = part.parse().ok()?;
+        }
+        // the partition must be followed by a separator
+        parts.next()?;
+
+        if depth < 4 {
+            fields[3] = 0;
+        }
+
+        let date = NaiveDate::from_ymd_opt(fields[0] as i32, fields[1], fields[2])?;
+        let time = date.and_hms_opt(fields[3], 0, 0)?;
+        Some(Utc.from_utc_datetime(&time))
+    }
+
+    /// whether the partition of `key` overlaps `[from, to)`
+    pub fn key_in_range(&self, key: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        match self.partition_of(key) {
+            Some(start) => start < to && Unit::from(self.granularity).next(start) > from,
+            None => false,
+        }
+    }
+
+    /// minimal set of prefixes covering every partition that overlaps `[from, to)`
+    ///
+    /// runs of whole days, months or years are collapsed into the shorter prefix
+    /// so that as few list calls as possible are needed
+    /// partitions at the edges may hold objects outside the range, see [`PartitionScheme::key_in_range`]
+    pub fn prefixes_for_range(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<String> {
+        if from >= to {
+            return vec![];
+        }
+
+        let finest = Unit::from(self.granularity);
+        let mut cursor = finest.floor(from);
+        let end = if finest.is_aligned(to) {
+            to
+        } else {
+            finest.next(finest.floor(to))
+        };
+
+        let mut prefixes = vec![];
+        while cursor < end {
+            let unit = Unit::LARGEST_FIRST
+                .into_iter()
+                .filter(|u| *u >= finest)
+                .find(|u| u.is_aligned(cursor) && u.next(cursor) <= end)
+                .unwrap_or(finest);
+
+            prefixes.push(format!("{}{}", self.base, unit.path(cursor)));
+            cursor = unit.next(cursor);
+        }
+
+        prefixes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn key_for_test() {
+        let scheme = PartitionScheme::hourly("events");
+        assert_eq!(
+            scheme.key_for(ts(2024, 3, 5, 7, 42), "batch.ndjson"),
+            "events/2024/03/05/07/batch.ndjson"
+        );
+
+        let scheme = PartitionScheme::daily("events/");
+        assert_eq!(
+            scheme.key_for(ts(2024, 3, 5, 7, 42), "a"),
+            "events/2024/03/05/a"
+        );
+
+        let scheme = PartitionScheme::monthly("");
+        assert_eq!(scheme.key_for(ts(2024, 12, 31, 23, 59), "a"), "2024/12/a");
+    }
+
+    #[test]
+    fn partition_of_test() {
+        let scheme = PartitionScheme::hourly("events");
+        assert_eq!(
+            scheme.partition_of("events/2024/03/05/07/x"),
+            Some(ts(2024, 3, 5, 7, 0))
+        );
+        assert_eq!(scheme.partition_of("events/2024/03/05/07"), None);
+        assert_eq!(scheme.partition_of("events/2024/03/05/7/x"), None);
+        assert_eq!(scheme.partition_of("events/2024/02/30/07/x"), None);
+        assert_eq!(scheme.partition_of("events/2024/03/05/24/x"), None);
+        assert_eq!(scheme.partition_of("other/2024/03/05/07/x"), None);
+
+        let scheme = PartitionScheme::monthly("events");
+        assert_eq!(
+            scheme.partition_of("events/2024/03/x/y"),
+            Some(ts(2024, 3, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn prefixes_within_one_hour_test() {
+        let scheme = PartitionScheme::hourly("events");
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 3, 5, 7, 10), ts(2024, 3, 5, 7, 50)),
+            vec!["events/2024/03/05/07/"]
+        );
+    }
+
+    #[test]
+    fn prefixes_exclusive_end_test() {
+        let scheme = PartitionScheme::hourly("events");
+        // 09:00 is excluded, so the 09 partition is not listed
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 3, 5, 7, 0), ts(2024, 3, 5, 9, 0)),
+            vec!["events/2024/03/05/07/", "events/2024/03/05/08/"]
+        );
+        // one minute past the hour pulls the partition in
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 3, 5, 7, 0), ts(2024, 3, 5, 9, 1)),
+            vec![
+                "events/2024/03/05/07/",
+                "events/2024/03/05/08/",
+                "events/2024/03/05/09/"
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixes_empty_range_test() {
+        let scheme = PartitionScheme::hourly("events");
+        let t = ts(2024, 3, 5, 7, 0);
+        assert!(scheme.prefixes_for_range(t, t).is_empty());
+        assert!(scheme
+            .prefixes_for_range(t, t - chrono::Duration::hours(1))
+            .is_empty());
+    }
+
+    #[test]
+    fn prefixes_collapse_days_test() {
+        let scheme = PartitionScheme::hourly("events");
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 3, 4, 22, 0), ts(2024, 3, 7, 2, 0)),
+            vec![
+                "events/2024/03/04/22/",
+                "events/2024/03/04/23/",
+                "events/2024/03/05/",
+                "events/2024/03/06/",
+                "events/2024/03/07/00/",
+                "events/2024/03/07/01/",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixes_collapse_months_and_years_test() {
+        let scheme = PartitionScheme::hourly("events");
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2023, 11, 30, 23, 0), ts(2025, 2, 2, 0, 0)),
+            vec![
+                "events/2023/11/30/23/",
+                "events/2023/12/",
+                "events/2024/",
+                "events/2025/01/",
+                "events/2025/02/01/",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixes_leap_day_test() {
+        let scheme = PartitionScheme::daily("events");
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 2, 28, 0, 0), ts(2024, 3, 1, 0, 0)),
+            vec!["events/2024/02/28/", "events/2024/02/29/"]
+        );
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 2, 1, 0, 0), ts(2024, 3, 1, 0, 0)),
+            vec!["events/2024/02/"]
+        );
+    }
+
+    #[test]
+    fn prefixes_monthly_test() {
+        let scheme = PartitionScheme::monthly("events");
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 11, 15, 0, 0), ts(2025, 1, 1, 0, 0)),
+            vec!["events/2024/11/", "events/2024/12/"]
+        );
+        assert_eq!(
+            scheme.prefixes_for_range(ts(2024, 1, 1, 0, 0), ts(2025, 1, 1, 0, 1)),
+            vec!["events/2024/", "events/2025/01/"]
+        );
+    }
+
+    #[test]
+    fn prefixes_cover_every_hour_once_test() {
+        let scheme = PartitionScheme::hourly("events");
+        let from = ts(2023, 12, 30, 5, 30);
+        let to = ts(2024, 2, 2, 3, 15);
+        let prefixes = scheme.prefixes_for_range(from, to);
+
+        let mut hour = ts(2023, 12, 30, 5, 0);
+        while hour < to {
+            let key = scheme.key_for(hour, "x");
+            let matching = prefixes.iter().filter(|p| key.starts_with(*p)).count();
+            assert_eq!(matching, 1, "{key}");
+            hour += chrono::Duration::hours(1);
+        }
+
+        let before = scheme.key_for(ts(2023, 12, 30, 4, 59), "x");
+        let after = scheme.key_for(ts(2024, 2, 2, 4, 0), "x");
+        assert!(!prefixes.iter().any(|p| before.starts_with(p)));
+        assert!(!prefixes.iter().any(|p| after.starts_with(p)));
+    }
+
+    #[test]
+    fn key_in_range_test() {
+        let scheme = PartitionScheme::hourly("events");
+        let from = ts(2024, 3, 5, 7, 30);
+        let to = ts(2024, 3, 5, 9, 0);
+
+        assert!(!scheme.key_in_range("events/2024/03/05/06/x", from, to));
+        assert!(scheme.key_in_range("events/2024/03/05/07/x", from, to));
+        assert!(scheme.key_in_range("events/2024/03/05/08/x", from, to));
+        assert!(!scheme.key_in_range("events/2024/03/05/09/x", from, to));
+        assert!(!scheme.key_in_range("events/2024/03/05/08", from, to));
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{Stream, StreamExt};
+
+use super::StorageHelper;
+use crate::{ByteSize, ErrorCode, NimbusError};
+
+/// Objects downloaded ahead of the consumer unless set with [`PrefetchOptions::prefetch`]
+pub const DEFAULT_PREFETCH: usize = 4;
+
+/// Downloaded bytes waiting for the consumer unless set with [`PrefetchOptions::byte_budget`]
+pub const DEFAULT_PREFETCH_BYTE_BUDGET: ByteSize = ByteSize::mib(256);
+
+/// Options of [`StorageHelper::prefetching_downloader_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchOptions {
+    /// objects downloading or downloaded but not yet consumed, at least 1
+    pub prefetch: usize,
+    /// no download starts while the downloaded objects not yet consumed hold this many bytes, counting
+    /// each download in flight as large as the largest object downloaded so far; those in flight still complete
+    pub byte_budget: ByteSize,
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> Self {
+        Self {
+            prefetch: DEFAULT_PREFETCH,
+            byte_budget: DEFAULT_PREFETCH_BYTE_BUDGET,
+        }
+    }
+}
+
+impl PrefetchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    pub fn byte_budget(mut self, byte_budget: ByteSize) -> Self {
+        self.byte_budget = byte_budget;
+        self
+    }
+}
+
+type Item = Result<(String, Vec<u8>), NimbusError>;
+
+/// errors the following downloads would fail with as well
+fn is_fatal(e: &NimbusError) -> bool {
+    matches!(
+        e.code(),
+        ErrorCode::Unauthenticated | ErrorCode::PermissionDenied
+    )
+}
+
+struct Prefetch<'a, S> {
+    storage: &'a S,
+    bucket: Arc<str>,
+    keys: Box<dyn Iterator<Item = String> + Send + 'a>,
+    options: PrefetchOptions,
+    in_flight: FuturesUnordered<BoxFuture<'a, (usize, Item)>>,
+    /// completed downloads by position, waiting for the ones before them
+    ready: BTreeMap<usize, Item>,
+    ready_bytes: u64,
+    /// largest object downloaded so far, the expected size of those in flight
+    largest: u64,
+    started: usize,
+    yielded: usize,
+    done: bool,
+}
+
+pub(crate) fn prefetch<'a, S, I>(
+    storage: &'a S,
+    bucket: &str,
+    keys: I,
+    options: PrefetchOptions,
+) -> impl Stream<Item = Item> + Send + Unpin + 'a
+where
+    S: StorageHelper + Sync,
+    I: Iterator<Item = String> + Send + 'a,
+{
+    Prefetch {
+        storage,
+        bucket: bucket.into(),
+        keys: Box::new(keys),
+        options,
+        in_flight: FuturesUnordered::new(),
+        ready: BTreeMap::new(),
+        ready_bytes: 0,
+        largest: 0,
+        started: 0,
+        yielded: 0,
+        done: false,
+    }
+}
+
+impl<S: StorageHelper + Sync> Prefetch<'_, S> {
+    /// start the downloads the options allow, the next one to yield always, returns whether any started
+    fn start(&mut self) -> bool {
+        let mut any = false;
+        while !self.done {
+            let idle = self.in_flight.is_empty() && self.ready.is_empty();
+            let expected = self.ready_bytes + self.in_flight.len() as u64 * self.largest;
+            let full = self.in_flight.len() + self.ready.len() >= self.options.prefetch.max(1)
+                || expected >= self.options.byte_budget.bytes();
+            if full && !idle {
+                break;
+            }
+            let Some(key) = self.keys.next() else {
+                break;
+            };
+
+            let (storage, bucket, index) = (self.storage, self.bucket.clone(), self.started);
+            self.in_flight.push(Box::pin(async move {
+                let data = storage.download_to_bytes(&bucket, &key).await;
+                (index, data.map(|data| (key, data)))
+            }));
+            self.started += 1;
+            any = true;
+        }
+        any
+    }
+
+    /// move the completed downloads to `ready`
+    fn collect(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some((index, item))) = self.in_flight.poll_next_unpin(cx) {
+            if let Ok((_, data)) = &item {
+                self.ready_bytes += data.len() as u64;
+                self.largest = self.largest.max(data.len() as u64);
+            }
+            self.ready.insert(index, item);
+        }
+    }
+}
+
+impl<S: StorageHelper + Sync> Stream for Prefetch<'_, S> {
+    type Item = Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        let this = self.get_mut();
+        loop {
+            this.collect(cx);
+
+            if let Some(item) = this.ready.remove(&this.yielded) {
+                this.yielded += 1;
+                match &item {
+                    Ok((_, data)) => this.ready_bytes -= data.len() as u64,
+                    Err(e) if is_fatal(e) => {
+                        // dropping the downloads in flight cancels them
+                        this.done = true;
+                        this.in_flight.clear();
+                        this.ready.clear();
+                    }
+                    Err(_) => {}
+                }
+                // the freed slot downloads while the consumer handles the item
+                if this.start() {
+                    this.collect(cx);
+                }
+                return Poll::Ready(Some(item));
+            }
+
+            if !this.start() {
+                return if this.in_flight.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    async fn put(storage: &MemoryStorage, keys: &[String], size: usize) {
+        for key in keys {
+            storage
+                .upload_from_bytes("b", key, None, vec![0; size])
+                .await
+                .unwrap();
+        }
+        storage.reset_stats();
+    }
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("k{i}")).collect()
+    }
+
+    fn latency(ms: u64) -> Fault {
+        Fault::new().latency(Latency::Fixed(Duration::from_millis(ms)))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prefetch_throughput_test() {
+        let storage = MemoryStorage::new();
+        let keys = keys(8);
+        put(&storage, &keys, 10).await;
+        // later keys download faster, the order is kept anyway
+        for (i, key) in keys.iter().enumerate() {
+            storage
+                .mock_stats()
+                .set_object_fault(key, latency(100 - 10 * i as u64));
+        }
+        let process = Duration::from_millis(100);
+
+        let start = tokio::time::Instant::now();
+        for key in &keys {
+            storage.download_to_bytes("b", key).await.unwrap();
+            tokio::time::sleep(process).await;
+        }
+        let sequential = start.elapsed();
+        assert_eq!(sequential, Duration::from_millis(520 + 800));
+
+        let start = tokio::time::Instant::now();
+        let mut seen = vec![];
+        let mut objects = storage.prefetching_downloader("b", keys.clone(), 4);
+        while let Some(item) = objects.next().await {
+            seen.push(item.unwrap().0);
+            tokio::time::sleep(process).await;
+        }
+        let prefetched = start.elapsed();
+        assert_eq!(seen, keys);
+        // the first download, then processing only
+        assert_eq!(prefetched, Duration::from_millis(100 + 8 * 100));
+    }
+
+    #[tokio::test]
+    async fn prefetch_byte_budget_test() {
+        let storage = MemoryStorage::new();
+        let keys = keys(12);
+        put(&storage, &keys, 100).await;
+        let calls = || storage.stats().calls("download_to_bytes");
+
+        let options = PrefetchOptions::new()
+            .prefetch(8)
+            .byte_budget(ByteSize::b(250));
+        let mut objects = storage.prefetching_downloader_with("b", keys.clone(), options);
+        assert_eq!(objects.next().await.unwrap().unwrap().0, "k0");
+        assert_eq!(calls(), 8);
+
+        // 700 bytes wait for the consumer, over the budget: nothing starts until it's under it
+        let mut starts = vec![];
+        for key in &keys[1..8] {
+            assert_eq!(&objects.next().await.unwrap().unwrap().0, key);
+            starts.push(calls());
+        }
+        assert_eq!(starts, [8, 8, 8, 8, 9, 10, 11]);
+
+        let rest: Vec<_> = objects.map(|item| item.unwrap().0).collect().await;
+        assert_eq!(rest, &keys[8..]);
+        assert_eq!(calls(), 12);
+
+        // without a budget the freed slot is refilled right away
+        storage.reset_stats();
+        let mut objects = storage.prefetching_downloader("b", keys.clone(), 8);
+        objects.next().await.unwrap().unwrap();
+        assert_eq!(calls(), 9);
+    }
+
+    #[tokio::test]
+    async fn prefetch_errors_test() {
+        let storage = MemoryStorage::new();
+        let keys = keys(6);
+        put(&storage, &keys, 10).await;
+
+        let mut with_missing = keys.clone();
+        with_missing.insert(2, "missing".to_owned());
+        let items: Vec<_> = storage
+            .prefetching_downloader("b", with_missing, 3)
+            .collect()
+            .await;
+        assert_eq!(items.len(), 7);
+        assert_eq!(items[2].as_ref().unwrap_err().code(), ErrorCode::NotFound);
+        assert_eq!(items[3].as_ref().unwrap().0, "k2");
+
+        // a fatal error ends the stream
+        storage
+            .mock_stats()
+            .set_object_fault("k2", Fault::new().fail(1.0, ErrorCode::PermissionDenied));
+        let items: Vec<_> = storage
+            .prefetching_downloader("b", keys.clone(), 2)
+            .collect()
+            .await;
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items[2].as_ref().unwrap_err().code(),
+            ErrorCode::PermissionDenied
+        );
+
+        let items: Vec<_> = storage
+            .prefetching_downloader("b", Vec::new(), 2)
+            .collect()
+            .await;
+        assert!(items.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prefetch_drop_test() {
+        let storage = MemoryStorage::new();
+        let keys = keys(4);
+        put(&storage, &keys, 10).await;
+        for key in &keys[1..] {
+            storage.mock_stats().set_object_fault(key, latency(10_000));
+        }
+
+        let mut objects = storage.prefetching_downloader("b", keys, 4);
+        objects.next().await.unwrap().unwrap();
+        assert_eq!(storage.stats().calls("download_to_bytes"), 4);
+        drop(objects);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        // the 3 downloads in flight were cancelled before reading anything
+        assert_eq!(storage.stats().operations["download_to_bytes"].bytes, 10);
+    }
+}
@@ -0,0 +1,247 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{Error, ResumableUpload, StorageHelper, DEFAULT_BUFFER_SIZE};
+use crate::{ByteSize, NimbusError};
+
+/// Transferred between two progress events unless set otherwise, see [`StorageHelper::upload_file_with_progress`]
+pub const DEFAULT_PROGRESS_INTERVAL: ByteSize = ByteSize::mib(1);
+
+/// Step of a transfer reported by a [`ProgressEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProgressPhase {
+    /// reading the local file
+    Reading,
+    /// sending to the bucket
+    Uploading,
+    /// every byte was sent, the upload is being committed
+    Finalizing,
+    /// fetching from the bucket
+    Downloading,
+    /// writing the local file
+    Writing,
+}
+
+/// Progress of [`StorageHelper::upload_file_with_progress`] or [`StorageHelper::download_file_with_progress`]
+///
+/// `bytes_done` never decreases over the events of a transfer and ends at the size of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub phase: ProgressPhase,
+    pub bytes_done: u64,
+    /// size of the transfer, `None` until known
+    pub total: Option<u64>,
+}
+
+/// throttles the events of a transfer and shields it from the callback
+struct Progress<F> {
+    on_progress: F,
+    every: u64,
+    total: Option<u64>,
+    last: Option<(ProgressPhase, u64)>,
+    panicked: bool,
+}
+
+impl<F: Fn(ProgressEvent)> Progress<F> {
+    fn new(on_progress: F, every: ByteSize, total: Option<u64>) -> Self {
+        Self {
+            on_progress,
+            every: every.bytes().max(1),
+            total,
+            last: None,
+            panicked: false,
+        }
+    }
+
+    /// report `bytes_done` if `every` bytes passed since the last event of the phase,
+    /// or when the phase changes or reaches the total
+    fn report(&mut self, phase: ProgressPhase, bytes_done: u64) {
+        let due = match self.last {
+            Some((last_phase, last)) if last_phase == phase => {
+                bytes_done >= last + self.every || Some(bytes_done) == self.total
+            }
+            _ => true,
+        };
+        if due {
+            self.emit(phase, bytes_done);
+        }
+    }
+
+    /// a panicking callback is not called again, the transfer goes on
+    fn emit(&mut self, phase: ProgressPhase, bytes_done: u64) {
+        self.last = Some((phase, bytes_done));
+        if self.panicked {
+            return;
+        }
+
+        let event = ProgressEvent {
+            phase,
+            bytes_done,
+            total: self.total,
+        };
+        self.panicked = catch_unwind(AssertUnwindSafe(|| (self.on_progress)(event))).is_err();
+    }
+}
+
+pub(crate) async fn upload_file<S>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    path: &Path,
+    every: ByteSize,
+    on_progress: impl Fn(ProgressEvent),
+) -> Result<(), NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let mut file = tokio::fs::File::open(path).await.map_err(Error::IO)?;
+    let total = file.metadata().await.map_err(Error::IO)?.len();
+    let mut progress = Progress::new(on_progress, every, Some(total));
+
+    let mut session: Option<ResumableUpload> = None;
+    let result = send_file(storage, bucket, key, &mut file, &mut session, &mut progress).await;
+    if result.is_err() {
+        if let Some(session) = session {
+            // the upload failed already, a failing abort leaves the provider to expire the session
+            let _ = session.abort().await;
+        }
+    }
+    result
+}
+
+/// read the file `every` bytes at a time, files larger than [`DEFAULT_BUFFER_SIZE`] are sent
+/// by chunks of that size through a resumable upload
+async fn send_file<S, F>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    file: &mut tokio::fs::File,
+    session: &mut Option<ResumableUpload>,
+    progress: &mut Progress<F>,
+) -> Result<(), NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+    F: Fn(ProgressEvent),
+{
+    let chunk_size = DEFAULT_BUFFER_SIZE.as_usize();
+    let mut read = vec![0; (progress.every as usize).min(chunk_size)];
+    let mut buffer = Vec::with_capacity(chunk_size);
+    let (mut bytes_read, mut bytes_sent) = (0u64, 0u64);
+
+    loop {
+        let n = file.read(&mut read).await.map_err(Error::IO)?;
+        if n == 0 {
+            break;
+        }
+        bytes_read += n as u64;
+        buffer.extend_from_slice(&read[..n]);
+        progress.report(ProgressPhase::Reading, bytes_read);
+
+        while buffer.len() >= chunk_size {
+            let upload = match session {
+                Some(upload) => upload,
+                None => session.insert(storage.start_resumable_upload(bucket, key, None).await?),
+            };
+            let rest = buffer.split_off(chunk_size);
+            let chunk = std::mem::replace(&mut buffer, rest);
+            bytes_sent += chunk.len() as u64;
+            upload.upload_chunk(chunk).await?;
+            progress.report(ProgressPhase::Uploading, bytes_sent);
+        }
+    }
+
+    let Some(upload) = session else {
+        bytes_sent = buffer.len() as u64;
+        storage.upload_from_bytes(bucket, key, None, buffer).await?;
+        progress.emit(ProgressPhase::Uploading, bytes_sent);
+        return Ok(());
+    };
+
+    if !buffer.is_empty() {
+        bytes_sent += buffer.len() as u64;
+        upload.upload_chunk(buffer).await?;
+        progress.report(ProgressPhase::Uploading, bytes_sent);
+    }
+    progress.emit(ProgressPhase::Finalizing, bytes_sent);
+    if let Some(upload) = session.take() {
+        upload.finish().await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn download_file<S>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    path: &Path,
+    every: ByteSize,
+    on_progress: impl Fn(ProgressEvent),
+) -> Result<PathBuf, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let mut progress = Progress::new(on_progress, every, None);
+    progress.emit(ProgressPhase::Downloading, 0);
+
+    let data = storage.download_to_bytes(bucket, key).await?;
+    progress.total = Some(data.len() as u64);
+    progress.emit(ProgressPhase::Downloading, data.len() as u64);
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(Error::IO)?;
+    }
+    let mut file = tokio::fs::File::create(path).await.map_err(Error::IO)?;
+    let mut written = 0u64;
+    for chunk in data.chunks(progress.every as usize) {
+        file.write_all(chunk).await.map_err(Error::IO)?;
+        written += chunk.len() as u64;
+        progress.report(ProgressPhase::Writing, written);
+    }
+    file.flush().await.map_err(Error::IO)?;
+
+    Ok(path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn progress_test() {
+        let events = Mutex::new(vec![]);
+        let mut progress = Progress::new(
+            |e: ProgressEvent| events.lock().unwrap().push((e.phase, e.bytes_done)),
+            ByteSize::b(10),
+            Some(25),
+        );
+        for done in [4, 10, 14, 20, 25] {
+            progress.report(ProgressPhase::Reading, done);
+        }
+        progress.report(ProgressPhase::Uploading, 25);
+
+        use ProgressPhase::*;
+        assert_eq!(
+            *events.lock().unwrap(),
+            [(Reading, 4), (Reading, 14), (Reading, 25), (Uploading, 25)]
+        );
+
+        // a panicking callback is called once, the progress goes on
+        let calls = Mutex::new(0);
+        let mut progress = Progress::new(
+            |_| {
+                *calls.lock().unwrap() += 1;
+                panic!("progress bar gone");
+            },
+            ByteSize::b(1),
+            None,
+        );
+        progress.report(Reading, 1);
+        progress.report(Reading, 2);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,527 @@
+//! Resumable deletion of every object under a prefix
+//!
+//! [`StorageHelper::delete_prefix_resumable`] lists the prefix in lexicographic order and deletes each page
+//! concurrently. Progress is kept in a small checkpoint object: the last key of the last page fully deleted,
+//! and the counts so far. A run started again after being killed reads it back and resumes the listing after
+//! that key. The checkpoint is deleted once the whole prefix is.
+//!
+//! Keys deleted by a killed run after its last checkpoint are gone but not counted,
+//! [`DeletePrefixReport::total_deleted`] may fall short of them, never count a key twice.
+//!
+//! ```ignore
+//! let report = storage
+//!     .delete_prefix_resumable("raw", "2023/", "ops/delete-2023.json", DeletePrefixOptions::new())
+//!     .await?;
+//! println!("{report}");
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::{Error, ListPage, ListParams, StorageHelper};
+use crate::redact::json_error;
+use crate::report::Report;
+use crate::retry::{self, ExponentialFullJitter};
+use crate::{ErrorCode, NimbusError};
+
+/// Deletes in flight in [`StorageHelper::delete_prefix_resumable`] unless set with [`DeletePrefixOptions::concurrency`]
+pub const DEFAULT_DELETE_CONCURRENCY: usize = 32;
+
+/// Attempts of each call of [`StorageHelper::delete_prefix_resumable`] unless set with [`DeletePrefixOptions::max_attempts`]
+pub const DEFAULT_DELETE_ATTEMPTS: u32 = 5;
+
+/// How often [`StorageHelper::delete_prefix_resumable`] writes its checkpoint unless set with
+/// [`DeletePrefixOptions::checkpoint_interval`]
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+const CHECKPOINT_MIME: &str = "application/json";
+
+/// Options of [`StorageHelper::delete_prefix_resumable`]
+///
+/// Calls are retried when the error is retryable, with an exponential backoff from `base_delay`
+/// to `max_delay` that waits at least as long as the provider asks when rate limited.
+/// After a page with rate limited deletes the concurrency is halved, it grows back by one per page that had none.
+#[derive(Debug, Clone)]
+pub struct DeletePrefixOptions {
+    pub concurrency: usize,
+    /// keys listed, and deleted, at a time
+    pub page_size: u32,
+    /// attempts of each call, the first one included
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// minimum time between two writes of the checkpoint, zero to write it after every page
+    pub checkpoint_interval: Duration,
+}
+
+impl Default for DeletePrefixOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_DELETE_CONCURRENCY,
+            page_size: 1000,
+            max_attempts: DEFAULT_DELETE_ATTEMPTS,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+        }
+    }
+}
+
+impl DeletePrefixOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn retry_delays(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    fn backoff(&self) -> ExponentialFullJitter {
+        ExponentialFullJitter::new(self.base_delay, self.max_delay)
+            .max_attempts(self.max_attempts.max(1))
+    }
+}
+
+/// Outcome of [`StorageHelper::delete_prefix_resumable`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DeletePrefixReport {
+    /// deleted by this run
+    pub deleted: u64,
+    /// deleted by every run, this one included, as recorded in the checkpoint
+    pub total_deleted: u64,
+    /// runs it took, 1 if the deletion wasn't resumed
+    pub runs: u32,
+    /// last key deleted by the previous runs, this run listed the keys after it
+    pub resumed_after: Option<String>,
+    /// deletes rate limited by the provider, and retried
+    pub throttled: u64,
+}
+
+impl Report for DeletePrefixReport {
+    fn summary_line(&self) -> String {
+        format!(
+            "delete prefix: {} deleted, {} in total over {} runs, {} throttled",
+            self.deleted, self.total_deleted, self.runs, self.throttled
+        )
+    }
+
+    fn has_failures(&self) -> bool {
+        false
+    }
+}
+
+impl fmt::Display for DeletePrefixReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())
+    }
+}
+
+/// content of the checkpoint object
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    prefix: String,
+    /// last key of the last page fully deleted
+    last_key: Option<String>,
+    deleted: u64,
+    runs: u32,
+}
+
+impl Checkpoint {
+    fn parse(key: &str, data: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(data)
+            .map_err(|e| Error::InvalidJson(format!("checkpoint {key}: {}", json_error(&e))))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("delete checkpoint serializes")
+    }
+}
+
+async fn read_checkpoint<S>(
+    storage: &S,
+    bucket: &str,
+    prefix: &str,
+    checkpoint_key: &str,
+    options: &DeletePrefixOptions,
+) -> Result<Checkpoint, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let data = retry::retry(&mut options.backoff(), || {
+        storage.download_to_bytes(bucket, checkpoint_key)
+    })
+    .await;
+    let checkpoint = match data {
+        Ok(data) => Checkpoint::parse(checkpoint_key, &data)?,
+        Err(e) if e.code() == ErrorCode::NotFound => Checkpoint {
+            prefix: prefix.to_owned(),
+            ..Default::default()
+        },
+        Err(e) => return Err(e),
+    };
+
+    if checkpoint.prefix != prefix {
+        return Err(Error::CheckpointMismatch {
+            key: checkpoint_key.to_owned(),
+            prefix: checkpoint.prefix,
+        }
+        .into());
+    }
+
+    Ok(checkpoint)
+}
+
+async fn write_checkpoint<S>(
+    storage: &S,
+    bucket: &str,
+    checkpoint_key: &str,
+    checkpoint: &Checkpoint,
+    options: &DeletePrefixOptions,
+) -> Result<(), NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let data = checkpoint.to_bytes();
+    retry::retry(&mut options.backoff(), || {
+        storage.upload_from_bytes(
+            bucket,
+            checkpoint_key,
+            Some(CHECKPOINT_MIME.to_owned()),
+            data.clone(),
+        )
+    })
+    .await
+}
+
+/// whether the key was deleted (true) or already gone
+async fn delete_one<S>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    options: &DeletePrefixOptions,
+    throttled: &AtomicU64,
+) -> Result<bool, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let deleted = retry::retry(&mut options.backoff(), || async {
+        let deleted = storage.delete_file(bucket, key).await;
+        if matches!(&deleted, Err(e) if e.code() == ErrorCode::RateLimited) {
+            throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        deleted
+    })
+    .await;
+
+    match deleted {
+        Ok(()) => Ok(true),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) async fn delete_prefix_resumable<S>(
+    storage: &S,
+    bucket: &str,
+    prefix: &str,
+    checkpoint_key: &str,
+    options: DeletePrefixOptions,
+) -> Result<DeletePrefixReport, NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+{
+    let options = &options;
+    let mut checkpoint = read_checkpoint(storage, bucket, prefix, checkpoint_key, options).await?;
+    checkpoint.runs += 1;
+
+    let mut report = DeletePrefixReport {
+        resumed_after: checkpoint.last_key.clone(),
+        ..Default::default()
+    };
+    let params = ListParams {
+        prefix: Some(prefix.to_owned()),
+        page_size: Some(options.page_size.max(1)),
+        start_after: checkpoint.last_key.clone(),
+        ..Default::default()
+    };
+    let throttled = AtomicU64::new(0);
+    let mut concurrency = options.concurrency.max(1);
+    let mut last_write = Instant::now();
+    let mut token = None;
+
+    loop {
+        let page: ListPage = retry::retry(&mut options.backoff(), || {
+            storage.list_page(bucket, &params, token.clone())
+        })
+        .await?;
+        let Some(last_key) = page.objects.last().map(|o| o.key.clone()) else {
+            break;
+        };
+
+        // the checkpoint may live under the prefix
+        let keys = page
+            .objects
+            .into_iter()
+            .map(|o| o.key)
+            .filter(|key| key != checkpoint_key);
+        let throttled_before = throttled.load(Ordering::Relaxed);
+        let throttled = &throttled;
+        let mut deletes = futures::stream::iter(keys)
+            .map(|key| async move { delete_one(storage, bucket, &key, options, throttled).await })
+            .buffer_unordered(concurrency);
+
+        let mut deleted = 0;
+        let mut failure = None;
+        while let Some(result) = deletes.next().await {
+            match result {
+                Ok(true) => deleted += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        drop(deletes);
+
+        report.deleted += deleted;
+        checkpoint.deleted += deleted;
+
+        if let Some(e) = failure {
+            // the page isn't fully deleted, the next run lists it again
+            write_checkpoint(storage, bucket, checkpoint_key, &checkpoint, options).await?;
+            return Err(e);
+        }
+        checkpoint.last_key = Some(last_key);
+
+        concurrency = if throttled.load(Ordering::Relaxed) > throttled_before {
+            (concurrency / 2).max(1)
+        } else {
+            (concurrency + 1).min(options.concurrency.max(1))
+        };
+
+        token = page.next_page_token;
+        if token.is_none() {
+            break;
+        }
+        if last_write.elapsed() >= options.checkpoint_interval {
+            write_checkpoint(storage, bucket, checkpoint_key, &checkpoint, options).await?;
+            last_write = Instant::now();
+        }
+    }
+
+    match retry::retry(&mut options.backoff(), || {
+        storage.delete_file(bucket, checkpoint_key)
+    })
+    .await
+    {
+        Err(e) if e.code() != ErrorCode::NotFound => return Err(e),
+        _ => {}
+    }
+
+    report.total_deleted = checkpoint.deleted;
+    report.runs = checkpoint.runs;
+    report.throttled = throttled.load(Ordering::Relaxed);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, Latency, MemoryStorage};
+
+    const KEYS: usize = 500;
+
+    async fn bucket() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        for i in 0..KEYS {
+            storage
+                .upload_from_bytes("raw", &format!("in/{i:04}"), None, vec![])
+                .await
+                .unwrap();
+        }
+        storage
+            .upload_from_bytes("raw", "keep", None, vec![])
+            .await
+            .unwrap();
+        storage
+    }
+
+    async fn remaining(storage: &MemoryStorage, prefix: &str) -> Vec<String> {
+        storage
+            .list("raw")
+            .prefix(prefix)
+            .collect()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.key)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delete_prefix_test() {
+        let storage = bucket().await;
+
+        let report = storage
+            .delete_prefix_resumable(
+                "raw",
+                "in/",
+                "in/checkpoint.json",
+                DeletePrefixOptions::new().page_size(64),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.deleted, KEYS as u64);
+        assert_eq!(report.total_deleted, KEYS as u64);
+        assert_eq!(report.runs, 1);
+        assert_eq!(report.resumed_after, None);
+        assert!(remaining(&storage, "in/").await.is_empty());
+        assert_eq!(remaining(&storage, "").await, ["keep"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kill_and_resume_test() {
+        let storage = bucket().await;
+        let options = DeletePrefixOptions::new()
+            .page_size(50)
+            .concurrency(8)
+            .checkpoint_interval(Duration::ZERO);
+
+        // killed mid-way through a page
+        storage.mock_stats().set_fault(
+            "delete_file",
+            Fault::new().latency(Latency::Fixed(Duration::from_millis(10))),
+        );
+        let killed = tokio::time::timeout(
+            Duration::from_millis(400),
+            storage.delete_prefix_resumable("raw", "in/", "ops/checkpoint", options.clone()),
+        )
+        .await;
+        assert!(killed.is_err());
+        let left = remaining(&storage, "in/").await;
+        assert!(!left.is_empty() && left.len() < KEYS, "{}", left.len());
+
+        let data = storage
+            .download_to_bytes("raw", "ops/checkpoint")
+            .await
+            .unwrap();
+        let checkpoint = Checkpoint::parse("ops/checkpoint", &data).unwrap();
+        let last_key = checkpoint.last_key.clone().unwrap();
+        // nothing after the checkpoint is skipped
+        assert!(left.iter().all(|k| k > &last_key));
+
+        storage.mock_stats().clear_faults();
+        let report = storage
+            .delete_prefix_resumable("raw", "in/", "ops/checkpoint", options)
+            .await
+            .unwrap();
+        assert_eq!(report.runs, 2);
+        assert_eq!(report.resumed_after, Some(last_key));
+        assert_eq!(report.deleted, left.len() as u64);
+        assert_eq!(report.total_deleted, checkpoint.deleted + left.len() as u64);
+        // the keys of the page being deleted when killed aren't counted, and none is counted twice
+        assert!(report.total_deleted <= KEYS as u64);
+        assert!(report.total_deleted > (KEYS - 50) as u64);
+
+        assert!(remaining(&storage, "in/").await.is_empty());
+        assert!(!storage
+            .object_exists("raw", "ops/checkpoint")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_test() {
+        let storage = bucket().await;
+        storage.mock_stats().set_fault(
+            "delete_file",
+            Fault::new().fail(0.2, ErrorCode::RateLimited),
+        );
+
+        let report = storage
+            .delete_prefix_resumable(
+                "raw",
+                "in/",
+                "ops/checkpoint",
+                DeletePrefixOptions::new().max_attempts(20),
+            )
+            .await
+            .unwrap();
+        assert!(report.throttled > 0);
+        assert_eq!(report.total_deleted, KEYS as u64);
+        assert!(remaining(&storage, "in/").await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_page_test() {
+        let storage = bucket().await;
+        storage.mock_stats().set_fault(
+            "delete_file",
+            Fault::new().fail(0.05, ErrorCode::PermissionDenied),
+        );
+
+        let options = DeletePrefixOptions::new().page_size(100);
+        let error = storage
+            .delete_prefix_resumable("raw", "in/", "ops/checkpoint", options.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::PermissionDenied);
+        assert!(storage
+            .object_exists("raw", "ops/checkpoint")
+            .await
+            .unwrap());
+
+        storage.mock_stats().clear_faults();
+        let report = storage
+            .delete_prefix_resumable("raw", "in/", "ops/checkpoint", options)
+            .await
+            .unwrap();
+        // the keys deleted before the failure are counted once
+        assert_eq!(report.runs, 2);
+        assert_eq!(report.total_deleted, KEYS as u64);
+
+        // a checkpoint of another prefix is refused
+        storage
+            .upload_from_bytes(
+                "raw",
+                "ops/other",
+                None,
+                br#"{"prefix":"out/","last_key":null,"deleted":0,"runs":1}"#.to_vec(),
+            )
+            .await
+            .unwrap();
+        let error = storage
+            .delete_prefix_resumable("raw", "keep", "ops/other", DeletePrefixOptions::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidInput);
+    }
+}
@@ -0,0 +1,243 @@
+//! Replication status of objects, as reported by the providers
+//!
+//! S3 reports it per object for buckets with a replication rule (`x-amz-replication-status`).
+//! GCS replicates dual and multi-region buckets, turbo replication included, without reporting
+//! it per object: those are [`ReplicationState::Unsupported`], single region buckets
+//! [`ReplicationState::NotConfigured`].
+
+use std::time::Duration;
+
+use super::StorageHelper;
+use crate::NimbusError;
+
+/// First delay between two polls of [`StorageHelper::wait_for_replication`]
+pub const REPLICATION_POLL_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two polls of [`StorageHelper::wait_for_replication`]
+pub const REPLICATION_POLL_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Replication state of an object, the same for both providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplicationState {
+    /// a replication rule applies to the object, the copy isn't done yet
+    Pending,
+    /// the object is replicated, or is itself a replica
+    Completed,
+    /// the provider gave up replicating the object
+    Failed,
+    /// no replication applies to the object, e.g. in a single region bucket
+    NotConfigured,
+    /// the provider doesn't report whether this object is replicated
+    Unsupported,
+}
+
+/// Result of [`StorageHelper::replication_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    pub state: ReplicationState,
+    /// as reported by the provider, e.g. `PENDING` on S3 or `dual-region, rpo ASYNC_TURBO` on GCS
+    pub detail: Option<String>,
+}
+
+impl ReplicationStatus {
+    pub fn new(state: ReplicationState) -> Self {
+        Self {
+            state,
+            detail: None,
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state == ReplicationState::Completed
+    }
+
+    /// from the `x-amz-replication-status` of an object, absent when no replication rule applies to it
+    /// values S3 may add later aren't guessed at, they are [`ReplicationState::Unsupported`]
+    #[cfg(any(test, feature = "aws-storage"))]
+    pub(crate) fn from_s3(status: Option<&str>) -> Self {
+        let state = match status {
+            None => ReplicationState::NotConfigured,
+            Some("PENDING") => ReplicationState::Pending,
+            Some("COMPLETE" | "COMPLETED" | "REPLICA") => ReplicationState::Completed,
+            Some("FAILED") => ReplicationState::Failed,
+            Some(_) => ReplicationState::Unsupported,
+        };
+
+        Self {
+            state,
+            detail: status.map(str::to_owned),
+        }
+    }
+
+    /// from the location type and recovery point objective of a GCS bucket
+    #[cfg(any(test, feature = "gcp-storage"))]
+    pub(crate) fn from_gcs(location_type: &str, rpo: Option<&str>) -> Self {
+        let state = match location_type.to_ascii_lowercase().as_str() {
+            "region" => ReplicationState::NotConfigured,
+            _ => ReplicationState::Unsupported,
+        };
+        let detail = match rpo {
+            Some(rpo) => format!("{location_type}, rpo {rpo}"),
+            None => location_type.to_owned(),
+        };
+
+        Self::new(state).detail(detail)
+    }
+}
+
+/// delay before the next poll, doubling up to [`REPLICATION_POLL_MAX_DELAY`] and never past the deadline
+fn next_delay(previous: Option<Duration>, remaining: Duration) -> Duration {
+    let delay = previous.map_or(REPLICATION_POLL_INITIAL_DELAY, |d| {
+        (d * 2).min(REPLICATION_POLL_MAX_DELAY)
+    });
+    delay.min(remaining)
+}
+
+pub(crate) async fn wait<S: StorageHelper + ?Sized + Sync>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    timeout: Duration,
+) -> Result<ReplicationStatus, NimbusError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut delay = None;
+
+    loop {
+        let status = storage.replication_status(bucket, key).await?;
+        if status.state != ReplicationState::Pending {
+            return Ok(status);
+        }
+
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            return Ok(status);
+        }
+
+        let next = next_delay(delay, remaining);
+        tokio::time::sleep(next).await;
+        delay = Some(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryStorage;
+    use crate::ErrorCode;
+
+    #[test]
+    fn from_s3_test() {
+        let cases = [
+            (None, ReplicationState::NotConfigured),
+            (Some("PENDING"), ReplicationState::Pending),
+            (Some("COMPLETE"), ReplicationState::Completed),
+            (Some("COMPLETED"), ReplicationState::Completed),
+            (Some("REPLICA"), ReplicationState::Completed),
+            (Some("FAILED"), ReplicationState::Failed),
+            (Some("pending"), ReplicationState::Unsupported),
+            (Some("SOMETHING_NEW"), ReplicationState::Unsupported),
+        ];
+
+        for (header, state) in cases {
+            let status = ReplicationStatus::from_s3(header);
+            assert_eq!(status.state, state, "{header:?}");
+            assert_eq!(status.detail.as_deref(), header);
+        }
+    }
+
+    #[test]
+    fn from_gcs_test() {
+        let cases = [
+            ("region", None, ReplicationState::NotConfigured, "region"),
+            ("REGION", None, ReplicationState::NotConfigured, "REGION"),
+            (
+                "dual-region",
+                Some("ASYNC_TURBO"),
+                ReplicationState::Unsupported,
+                "dual-region, rpo ASYNC_TURBO",
+            ),
+            (
+                "dual-region",
+                Some("DEFAULT"),
+                ReplicationState::Unsupported,
+                "dual-region, rpo DEFAULT",
+            ),
+            (
+                "multi-region",
+                None,
+                ReplicationState::Unsupported,
+                "multi-region",
+            ),
+            ("", None, ReplicationState::Unsupported, ""),
+        ];
+
+        for (location_type, rpo, state, detail) in cases {
+            let status = ReplicationStatus::from_gcs(location_type, rpo);
+            assert_eq!(status.state, state, "{location_type}");
+            assert_eq!(status.detail.as_deref(), Some(detail));
+        }
+    }
+
+    #[test]
+    fn next_delay_test() {
+        let far = Duration::from_secs(3600);
+        assert_eq!(next_delay(None, far), REPLICATION_POLL_INITIAL_DELAY);
+        assert_eq!(
+            next_delay(Some(Duration::from_secs(20)), far),
+            REPLICATION_POLL_MAX_DELAY
+        );
+        assert_eq!(
+            next_delay(None, Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_replication_test() {
+        let storage = MemoryStorage::new();
+        storage
+            .upload_from_bytes("bucket", "key", None, b"data".to_vec())
+            .await
+            .unwrap();
+
+        let status = storage
+            .wait_for_replication("bucket", "key", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(status.state, ReplicationState::NotConfigured);
+
+        let pending = ReplicationStatus::new(ReplicationState::Pending).detail("PENDING");
+        assert!(storage.set_replication_status("bucket", "key", pending.clone()));
+        let status = storage
+            .wait_for_replication("bucket", "key", Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(status, pending);
+        // 1 + 2 + 4 + 3 seconds
+        assert_eq!(storage.stats().calls("replication_status"), 6);
+
+        let waiter = {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                storage
+                    .wait_for_replication("bucket", "key", Duration::from_secs(600))
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let completed = ReplicationStatus::new(ReplicationState::Completed).detail("COMPLETED");
+        storage.set_replication_status("bucket", "key", completed.clone());
+        assert_eq!(waiter.await.unwrap().unwrap(), completed);
+
+        let err = storage
+            .wait_for_replication("bucket", "missing", Duration::from_secs(60))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+}
@@ -0,0 +1,635 @@
+use base64::Engine;
+use bytes::Bytes;
+
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::client::Client;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+#[cfg(feature = "gcp-storage")]
+use google_cloud_storage::http::resumable_upload_client::{
+    ChunkSize, ResumableUploadClient, UploadStatus,
+};
+
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::error::ProvideErrorMetadata;
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::primitives::ByteStream;
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::types::{ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart};
+#[cfg(feature = "aws-storage")]
+use aws_sdk_s3::Client;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::scan::ScanTee;
+use super::traffic::{self, Direction};
+#[cfg(feature = "gcp-storage")]
+use super::ObjectExt;
+use super::{Error, StorageHelper};
+use crate::{ByteSize, NimbusError};
+
+/// Smallest chunk accepted for anything but the last chunk of an upload
+/// (the S3 minimum part size)
+pub const MIN_CHUNK_SIZE: ByteSize = ByteSize::mib(5);
+
+/// Chunks other than the last one must be a multiple of this size (GCS requirement)
+pub const CHUNK_ALIGNMENT: ByteSize = ByteSize::kib(256);
+
+/// CRC32C of `data` in the form both providers use on the wire:
+/// base64 of the big-endian checksum
+pub fn crc32c_base64(data: &[u8]) -> String {
+    encode_crc32c(crc32c::crc32c(data))
+}
+
+fn encode_crc32c(crc: u32) -> String {
+    base64::engine::general_purpose::STANDARD.encode(crc.to_be_bytes())
+}
+
+/// at least [`MIN_CHUNK_SIZE`] and a multiple of [`CHUNK_ALIGNMENT`]
+pub(crate) fn is_valid_chunk_size(size: ByteSize) -> bool {
+    size >= MIN_CHUNK_SIZE && size.bytes().is_multiple_of(CHUNK_ALIGNMENT.bytes())
+}
+
+/// check a chunk that is known not to be the last one of the upload
+fn validate_chunk(len: usize) -> Result<(), Error> {
+    if !is_valid_chunk_size(ByteSize::b(len as u64)) {
+        return Err(Error::Other(format!(
+            "Invalid chunk size {len}: chunks other than the last must be at least {MIN_CHUNK_SIZE} and a multiple of {CHUNK_ALIGNMENT}"
+        )));
+    }
+
+    Ok(())
+}
+
+// one per upload, not worth boxing the GCS client
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Session {
+    #[cfg(feature = "gcp-storage")]
+    Gcs {
+        client: Client,
+        bucket: String,
+        key: String,
+        uploader: ResumableUploadClient,
+    },
+    #[cfg(feature = "aws-storage")]
+    S3 {
+        client: Client,
+        bucket: String,
+        key: String,
+        upload_id: String,
+        parts: Vec<CompletedPart>,
+    },
+    /// chunks are kept in memory and stored on finish
+    #[cfg(any(test, feature = "testing"))]
+    Memory {
+        storage: crate::testing::MemoryStorage,
+        bucket: String,
+        key: String,
+        mime: Option<String>,
+        data: Vec<u8>,
+    },
+}
+
+/// A resumable (GCS) / multipart (S3) upload session
+///
+/// Created with [`super::StorageHelper::start_resumable_upload`], fed with [`ResumableUpload::upload_chunk`]
+/// and completed with [`ResumableUpload::finish`] or dropped server side with [`ResumableUpload::abort`].
+///
+/// Every chunk carries a CRC32C computed locally:
+/// - S3 verifies each part against it and rejects a corrupt part straight away
+/// - GCS has no per-chunk checksum on resumable sessions, so the running CRC32C of all chunks
+///   is compared to the one GCS reports for the finished object; on mismatch the object is deleted
+///
+/// Either way a mismatch surfaces as [`Error::ChunkChecksumMismatch`].
+///
+/// The last chunk handed to `upload_chunk` is held back until the next call (or `finish`),
+/// as only then it is known whether it is the last one. Every chunk except the last must be at least
+/// [`MIN_CHUNK_SIZE`] bytes and a multiple of [`CHUNK_ALIGNMENT`].
+///
+/// Uploads started through a [`super::ScannedStorage`] tee every chunk through its scanner:
+/// a chunk the scanner rejects fails with [`Error::ContentRejected`], and `finish` only completes
+/// the object once the scanner accepted the whole content.
+pub struct ResumableUpload {
+    session: Session,
+    pending: Option<Bytes>,
+    /// the chunk of a failed `upload_chunk` was fed to the scanner already
+    fed_next: bool,
+    chunks: u32,
+    offset: u64,
+    crc: u32,
+    scan: Option<ScanTee>,
+}
+
+impl ResumableUpload {
+    pub(crate) fn new(session: Session) -> Self {
+        Self {
+            session,
+            pending: None,
+            fed_next: false,
+            chunks: 0,
+            offset: 0,
+            crc: 0,
+            scan: None,
+        }
+    }
+
+    /// feed every chunk to `tee` before it is sent
+    pub(crate) fn with_scan(mut self, tee: ScanTee) -> Self {
+        self.scan = Some(tee);
+        self
+    }
+
+    /// bytes acknowledged by the provider so far
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.offset
+    }
+
+    /// queue a chunk for upload, sending the previously queued one
+    ///
+    /// On failure nothing is queued and the previous chunk is kept, so the call can be retried
+    /// with the same chunk.
+    pub async fn upload_chunk(&mut self, data: Vec<u8>) -> Result<(), NimbusError> {
+        if let Some(previous) = &self.pending {
+            validate_chunk(previous.len())?;
+        }
+
+        // the scanner sees a chunk before the previous one is sent, once even if the call is retried
+        if !self.fed_next {
+            if let Some(tee) = self.scan.as_mut() {
+                tee.feed(&data).await?;
+            }
+            self.fed_next = true;
+        }
+
+        if let Some(previous) = &self.pending {
+            // a cheap clone, the chunk stays queued until the provider acknowledged it
+            self.send(previous.clone(), false).await?;
+        }
+        self.pending = Some(data.into());
+        self.fed_next = false;
+
+        Ok(())
+    }
+
+    /// send the last chunk and complete the upload
+    pub async fn finish(mut self) -> Result<(), NimbusError> {
+        // the last chunk completes the object, so the verdict is needed before it is sent
+        if let Some(tee) = self.scan.take() {
+            if let Err(e) = tee.finish().await {
+                // the upload failed already, a failing abort leaves the provider to expire the session
+                let _ = self.abort().await;
+                return Err(e);
+            }
+        }
+
+        let last = self.pending.take().unwrap_or_default();
+        if let Err(e) = self.send(last, true).await {
+            // S3 bills the parts sent so far until the upload is aborted
+            let _ = self.abort().await;
+            return Err(e);
+        }
+
+        match self.session {
+            #[cfg(feature = "gcp-storage")]
+            Session::Gcs { .. } => Ok(()),
+            #[cfg(feature = "aws-storage")]
+            Session::S3 {
+                client,
+                bucket,
+                key,
+                upload_id,
+                parts,
+            } => {
+                let upload = CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build();
+
+                if let Err(e) = client
+                    .complete_multipart_upload()
+                    .bucket(&bucket)
+                    .key(&key)
+                    .upload_id(&upload_id)
+                    .multipart_upload(upload)
+                    .send()
+                    .await
+                {
+                    let _ = client
+                        .abort_multipart_upload()
+                        .bucket(bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .send()
+                        .await;
+                    return Err(NimbusError::from(Error::from_sdk(e)));
+                }
+
+                Ok(())
+            }
+            #[cfg(any(test, feature = "testing"))]
+            Session::Memory {
+                storage,
+                bucket,
+                key,
+                mime,
+                data,
+            } => storage.finish_upload(&bucket, &key, mime, data).await,
+        }
+    }
+
+    /// cancel the upload, discarding every chunk sent so far
+    pub async fn abort(self) -> Result<(), NimbusError> {
+        match self.session {
+            #[cfg(feature = "gcp-storage")]
+            Session::Gcs { uploader, .. } => {
+                uploader.cancel().await.map_err(Error::Storage)?;
+                Ok(())
+            }
+            #[cfg(feature = "aws-storage")]
+            Session::S3 {
+                client,
+                bucket,
+                key,
+                upload_id,
+                ..
+            } => {
+                if let Err(e) = client
+                    .abort_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    return Err(NimbusError::from(Error::from_sdk(e)));
+                }
+
+                Ok(())
+            }
+            #[cfg(any(test, feature = "testing"))]
+            Session::Memory { .. } => Ok(()),
+        }
+    }
+
+    /// count a chunk the provider acknowledged
+    fn record_sent(&self, len: u64) {
+        match self.session {
+            #[cfg(feature = "gcp-storage")]
+            Session::Gcs { ref bucket, .. } => traffic::record(bucket, Direction::Ingress, len),
+            #[cfg(feature = "aws-storage")]
+            Session::S3 { ref bucket, .. } => traffic::record(bucket, Direction::Ingress, len),
+            #[cfg(any(test, feature = "testing"))]
+            Session::Memory {
+                ref storage,
+                ref bucket,
+                ..
+            } => storage.record_traffic(bucket, Direction::Ingress, len),
+        }
+    }
+
+    async fn send(&mut self, data: Bytes, last: bool) -> Result<(), NimbusError> {
+        let len = data.len() as u64;
+        let crc = crc32c::crc32c_append(self.crc, &data);
+
+        match self.session {
+            #[cfg(feature = "gcp-storage")]
+            Session::Gcs {
+                ref client,
+                ref bucket,
+                ref key,
+                ref mut uploader,
+            } => {
+                if len == 0 && self.offset == 0 {
+                    // an empty object
+                    uploader
+                        .upload_single_chunk(data, 0)
+                        .await
+                        .map_err(Error::Storage)?;
+                    return Ok(());
+                }
+
+                // only the last chunk may be empty, it then completes the object at the current offset
+                let size = match len {
+                    0 => ChunkSize::new(self.offset, self.offset, Some(self.offset)),
+                    _ => ChunkSize::new(
+                        self.offset,
+                        self.offset + len - 1,
+                        last.then_some(self.offset + len),
+                    ),
+                };
+                let status = uploader
+                    .upload_multiple_chunk(data, &size)
+                    .await
+                    .map_err(Error::Storage)?;
+
+                if let UploadStatus::Ok(object) = status {
+                    let expected = encode_crc32c(crc);
+                    if object.crc32c() != Some(expected.as_str()) {
+                        let _ = client
+                            .delete_object(&DeleteObjectRequest {
+                                bucket: bucket.to_owned(),
+                                object: key.to_owned(),
+                                generation: Some(object.generation),
+                                ..Default::default()
+                            })
+                            .await;
+
+                        return Err(Error::ChunkChecksumMismatch {
+                            chunk: None,
+                            expected,
+                            actual: object.crc32c,
+                        }
+                        .into());
+                    }
+                }
+            }
+            #[cfg(feature = "aws-storage")]
+            Session::S3 {
+                ref client,
+                ref bucket,
+                ref key,
+                ref upload_id,
+                ref mut parts,
+            } => {
+                // S3 needs at least one part, even for an empty object
+                if last && len == 0 && !parts.is_empty() {
+                    return Ok(());
+                }
+
+                let part_number = self.chunks as i32 + 1;
+                let checksum = crc32c_base64(&data);
+                let res = client
+                    .upload_part()
+                    .bucket(bucket.as_str())
+                    .key(key.as_str())
+                    .upload_id(upload_id.as_str())
+                    .part_number(part_number)
+                    .checksum_algorithm(ChecksumAlgorithm::Crc32C)
+                    .checksum_crc32_c(checksum.as_str())
+                    .body(ByteStream::from(data))
+                    .send()
+                    .await;
+
+                let out = match res {
+                    Ok(out) => out,
+                    Err(e) if e.code() == Some("BadDigest") => {
+                        return Err(Error::ChunkChecksumMismatch {
+                            chunk: Some(self.chunks),
+                            expected: checksum,
+                            actual: None,
+                        }
+                        .into());
+                    }
+                    Err(e) => return Err(NimbusError::from(Error::from_sdk(e))),
+                };
+
+                if let Some(actual) = out.checksum_crc32_c() {
+                    if actual != checksum {
+                        return Err(Error::ChunkChecksumMismatch {
+                            chunk: Some(self.chunks),
+                            expected: checksum,
+                            actual: Some(actual.to_owned()),
+                        }
+                        .into());
+                    }
+                }
+
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(out.e_tag().map(str::to_owned))
+                        .checksum_crc32_c(checksum)
+                        .build(),
+                );
+            }
+            #[cfg(any(test, feature = "testing"))]
+            Session::Memory {
+                ref storage,
+                data: ref mut stored,
+                ..
+            } => {
+                storage.enter("upload_chunk", len).await?;
+                stored.extend_from_slice(&data);
+            }
+        }
+        self.record_sent(len);
+
+        self.chunks += 1;
+        self.offset += len;
+        self.crc = crc;
+
+        Ok(())
+    }
+}
+
+/// the next `part_size` bytes of `reader`, fewer at its end
+async fn read_part<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    part_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut part = Vec::with_capacity(part_size);
+    reader
+        .take(part_size as u64)
+        .read_to_end(&mut part)
+        .await
+        .map_err(Error::IO)?;
+    Ok(part)
+}
+
+pub(crate) async fn upload_from_reader<S, R>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    mime: Option<String>,
+    mut reader: R,
+    part_size: usize,
+) -> Result<(), NimbusError>
+where
+    S: StorageHelper + Sync + ?Sized,
+    R: AsyncRead + Unpin + Send,
+{
+    let size = ByteSize::b(part_size as u64);
+    if !is_valid_chunk_size(size) {
+        return Err(Error::InvalidBufferSize(size).into());
+    }
+
+    let first = read_part(&mut reader, part_size).await?;
+    if first.len() < part_size {
+        return storage.upload_from_bytes(bucket, key, mime, first).await;
+    }
+
+    let mut upload = storage.start_resumable_upload(bucket, key, mime).await?;
+    let sent = async {
+        let mut part = first;
+        while !part.is_empty() {
+            upload.upload_chunk(part).await?;
+            part = read_part(&mut reader, part_size).await?;
+        }
+        Ok::<_, NimbusError>(())
+    }
+    .await;
+    if let Err(e) = sent {
+        // the upload failed already, a failing abort leaves the provider to expire the session
+        let _ = upload.abort().await;
+        return Err(e);
+    }
+    upload.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, MemoryStorage};
+    use crate::ErrorCode;
+
+    #[test]
+    fn crc32c_base64_test() {
+        // standard check value for CRC32C: 0xE3069283
+        assert_eq!(crc32c_base64(b"123456789"), "4waSgw==");
+        assert_eq!(crc32c_base64(b""), "AAAAAA==");
+    }
+
+    #[test]
+    fn running_crc_matches_whole_test() {
+        let data: Vec<u8> = (0..1024u32).map(|i| (i % 251) as u8).collect();
+        let (a, b) = data.split_at(300);
+        let running = crc32c::crc32c_append(crc32c::crc32c(a), b);
+        assert_eq!(encode_crc32c(running), crc32c_base64(&data));
+    }
+
+    /// yields `data`, then fails
+    struct Failing(std::io::Cursor<Vec<u8>>);
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.0.position() == self.0.get_ref().len() as u64 {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_large_from_reader_test() {
+        let storage = MemoryStorage::new();
+        let part = MIN_CHUNK_SIZE.as_usize();
+        let data: Vec<u8> = (0..2 * part + 123).map(|i| (i % 251) as u8).collect();
+
+        storage
+            .upload_large_from_reader(
+                "b",
+                "backup",
+                Some("application/x-tar".into()),
+                &data[..],
+                part,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_to_bytes("b", "backup").await.unwrap(),
+            data
+        );
+        assert_eq!(storage.stats().calls("start_resumable_upload"), 1);
+        let meta = storage.object_metadata("b", "backup").await.unwrap();
+        assert_eq!(meta.content_type.as_deref(), Some("application/x-tar"));
+
+        // exactly a part: the last full part completes the upload
+        storage
+            .upload_large_from_reader("b", "one", None, &data[..part], part)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_to_bytes("b", "one").await.unwrap(),
+            &data[..part]
+        );
+
+        // less than a part: a single request
+        storage.reset_stats();
+        storage
+            .upload_large_from_reader("b", "small", None, &b"small"[..], part)
+            .await
+            .unwrap();
+        assert_eq!(storage.stats().calls("start_resumable_upload"), 0);
+        assert_eq!(storage.stats().calls("upload_from_bytes"), 1);
+
+        let err = storage
+            .upload_large_from_reader("b", "k", None, &data[..], part - 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn upload_large_from_reader_failure_test() {
+        let storage = MemoryStorage::new();
+        let part = MIN_CHUNK_SIZE.as_usize();
+
+        // the reader fails after a part was sent: the upload is aborted
+        let reader = Failing(std::io::Cursor::new(vec![1; part + 10]));
+        let err = storage
+            .upload_large_from_reader("b", "broken", None, reader, part)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, NimbusError::StorageClient(Error::IO(_))),
+            "{err:?}"
+        );
+        assert_eq!(storage.stats().calls("start_resumable_upload"), 1);
+        assert!(!storage.object_exists("b", "broken").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn upload_chunk_retry_test() {
+        let storage = MemoryStorage::new();
+        let part = MIN_CHUNK_SIZE.as_usize();
+        let (first, second) = (vec![1; part], vec![2; 10]);
+
+        let mut upload = storage
+            .start_resumable_upload("b", "k", None)
+            .await
+            .unwrap();
+        upload.upload_chunk(first.clone()).await.unwrap();
+
+        // sending the queued chunk fails: it stays queued and the call can be retried
+        storage.mock_stats().set_fault(
+            "upload_chunk",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        let err = upload.upload_chunk(second.clone()).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert_eq!(upload.bytes_uploaded(), 0);
+        storage.mock_stats().clear_faults();
+        upload.upload_chunk(second.clone()).await.unwrap();
+        assert_eq!(upload.bytes_uploaded(), part as u64);
+        upload.finish().await.unwrap();
+        assert_eq!(
+            storage.download_to_bytes("b", "k").await.unwrap(),
+            [first, second].concat()
+        );
+
+        // so does a queued chunk too small to be followed by another
+        let mut upload = storage
+            .start_resumable_upload("b", "small", None)
+            .await
+            .unwrap();
+        upload.upload_chunk(b"small".to_vec()).await.unwrap();
+        upload.upload_chunk(vec![0; part]).await.unwrap_err();
+        upload.finish().await.unwrap();
+        assert_eq!(
+            storage.download_to_bytes("b", "small").await.unwrap(),
+            b"small"
+        );
+    }
+
+    #[test]
+    fn validate_chunk_test() {
+        let (min, alignment) = (MIN_CHUNK_SIZE.as_usize(), CHUNK_ALIGNMENT.as_usize());
+        assert!(validate_chunk(min).is_ok());
+        assert!(validate_chunk(min + alignment).is_ok());
+        assert!(validate_chunk(min - alignment).is_err());
+        assert!(validate_chunk(min + 1).is_err());
+    }
+}
@@ -0,0 +1,942 @@
+//! Content scanning of uploads, e.g. by an antivirus
+//!
+//! A [`ScannedStorage`] hands every upload to a [`ContentScanner`] before it is stored:
+//! - payloads in memory (bytes, files, conditional uploads, appends) are scanned whole, and the upload
+//!   only goes through once the scanner accepted them
+//! - resumable uploads (progress uploads of large files, multipart form fields) are teed through the
+//!   scanner chunk by chunk, see [`ScanStream`], and only completed once it accepted the whole content
+//!
+//! A rejected upload fails with [`Error::ContentRejected`] and nothing is stored under its key.
+//! With a [`Quarantine`], rejected payloads held in memory are kept in a separate bucket for inspection.
+//!
+//! ```ignore
+//! let storage = ScannedStorage::new(client, ClamdScanner::new(addr))
+//!     .quarantine(Quarantine::new("uploads-quarantine").prefix("rejected/"));
+//! ```
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{self, BoxFuture, Either};
+use futures::{FutureExt, Stream};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+
+use super::{
+    AclEntry, AppendOutcome, CorsRule, EncryptionKey, Error, ListPage, ListParams, ObjectMeta,
+    ObjectStream, PolicyCondition, PostPolicy, Precondition, PublicAccess, ReplicationStatus,
+    ResumableUpload, StorageClass, StorageHelper, UpdateOptions, SNIFF_LEN,
+};
+#[cfg(feature = "aws-storage")]
+use crate::TransportConfig;
+use crate::{ByteSize, ErrorCode, ListLimits, NimbusError};
+
+/// Chunks of a resumable upload buffered ahead of the scanner, the upload waits for it beyond
+pub const SCAN_BUFFER_CHUNKS: usize = 4;
+
+/// Outcome of a scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// the upload fails with [`Error::ContentRejected`] and this reason
+    Rejected {
+        reason: String,
+    },
+}
+
+/// The scanner couldn't reach a verdict, the upload fails with [`Error::ScanFailed`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ScanError {
+    #[error("Scanner unavailable: {0}")]
+    Unavailable(String),
+    #[error("Scanner error: {0}")]
+    Other(String),
+}
+
+impl ScanError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ScanError::Unavailable(_) => ErrorCode::Unavailable,
+            ScanError::Other(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Content handed to a [`ContentScanner`]
+pub enum ScanInput<'a> {
+    /// the whole payload
+    Bytes(&'a [u8]),
+    /// the chunks of a resumable upload as they are sent
+    Stream(ScanStream),
+}
+
+/// Chunks of a resumable upload, in order
+///
+/// At most [`SCAN_BUFFER_CHUNKS`] chunks wait for the scanner, the upload is held back beyond.
+/// The stream ends once the upload is finished. A scanner may return before it ends,
+/// e.g. as soon as it found something to reject, the remaining chunks are then not scanned.
+pub struct ScanStream {
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ScanStream {
+    /// the next chunk, `None` once the upload is finished
+    pub async fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Checks uploads before they are stored, see [`ScannedStorage`]
+#[async_trait::async_trait]
+pub trait ContentScanner: Send + Sync {
+    /// `content_type` is the one declared by the uploader, if any
+    async fn scan(
+        &self,
+        content_type: Option<&str>,
+        data: ScanInput<'_>,
+    ) -> Result<ScanVerdict, ScanError>;
+}
+
+/// Accepts everything, without reading it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopScanner;
+
+#[async_trait::async_trait]
+impl ContentScanner for NoopScanner {
+    async fn scan(
+        &self,
+        _content_type: Option<&str>,
+        _data: ScanInput<'_>,
+    ) -> Result<ScanVerdict, ScanError> {
+        Ok(ScanVerdict::Clean)
+    }
+}
+
+/// Rejects content over a size or of a type not allowed
+///
+/// As for [`super::UploadConstraints`], the type is detected from the first [`SNIFF_LEN`] bytes,
+/// the declared one isn't trusted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyScanner {
+    pub max_size: Option<ByteSize>,
+    /// accepted types, as MIME types or extensions; any type, recognized or not, when empty
+    pub allowed_types: Vec<String>,
+}
+
+impl PolicyScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a bare `u64` is a number of bytes
+    pub fn max_size(mut self, max_size: impl Into<ByteSize>) -> Self {
+        self.max_size = Some(max_size.into());
+        self
+    }
+
+    /// accept a MIME type e.g. `image/png` or an extension e.g. `png`
+    pub fn allow_type(mut self, content_type: impl Into<String>) -> Self {
+        self.allowed_types.push(content_type.into());
+        self
+    }
+
+    fn check_size(&self, size: u64) -> Option<String> {
+        let limit = self.max_size?;
+        (size > limit.bytes()).then(|| format!("content exceeds the limit of {limit}"))
+    }
+
+    fn check_type(&self, head: &[u8]) -> Option<String> {
+        if self.allowed_types.is_empty() {
+            return None;
+        }
+
+        let Some(detected) = infer::get(head) else {
+            return Some("content type wasn't recognized".to_owned());
+        };
+        let allowed = self
+            .allowed_types
+            .iter()
+            .any(|t| t == detected.mime_type() || t == detected.extension());
+
+        (!allowed).then(|| format!("content type {} is not allowed", detected.mime_type()))
+    }
+}
+
+#[async_trait::async_trait]
+impl ContentScanner for PolicyScanner {
+    async fn scan(
+        &self,
+        _content_type: Option<&str>,
+        data: ScanInput<'_>,
+    ) -> Result<ScanVerdict, ScanError> {
+        let rejection = match data {
+            ScanInput::Bytes(data) => self
+                .check_size(data.len() as u64)
+                .or_else(|| self.check_type(&data[..data.len().min(SNIFF_LEN)])),
+            ScanInput::Stream(mut stream) => {
+                let (mut size, mut head, mut typed) = (0, vec![], false);
+                loop {
+                    let chunk = stream.next_chunk().await;
+                    if let Some(chunk) = &chunk {
+                        size += chunk.len() as u64;
+                        let missing = SNIFF_LEN.saturating_sub(head.len()).min(chunk.len());
+                        head.extend_from_slice(&chunk[..missing]);
+                    }
+
+                    if let Some(reason) = self.check_size(size) {
+                        break Some(reason);
+                    }
+                    if !typed && (head.len() >= SNIFF_LEN || chunk.is_none()) {
+                        typed = true;
+                        if let Some(reason) = self.check_type(&head) {
+                            break Some(reason);
+                        }
+                    }
+                    if chunk.is_none() {
+                        break None;
+                    }
+                }
+            }
+        };
+
+        Ok(
+            rejection.map_or(ScanVerdict::Clean, |reason| ScanVerdict::Rejected {
+                reason,
+            }),
+        )
+    }
+}
+
+/// Where rejected payloads are kept, under `{prefix}{bucket}/{key}` of the rejected upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantine {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl Quarantine {
+    pub fn new(bucket: impl Into<String>) -> Self {
+        Self {
+            bucket: bucket.into(),
+            prefix: String::new(),
+        }
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// key a rejected upload to `bucket`/`key` is kept under
+    pub fn key(&self, bucket: &str, key: &str) -> String {
+        format!("{}{bucket}/{key}", self.prefix)
+    }
+}
+
+/// Feeds the chunks of a resumable upload to a scanner running alongside it
+///
+/// The scan isn't spawned: it makes progress while the upload waits to hand it a chunk,
+/// and is run to completion on finish. It is only ever polled through `&mut self`, the mutex
+/// just keeps the upload `Sync`.
+pub(crate) struct ScanTee {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    scan: Option<Mutex<BoxFuture<'static, Result<ScanVerdict, ScanError>>>>,
+    verdict: Option<Result<ScanVerdict, ScanError>>,
+}
+
+impl ScanTee {
+    fn new(scanner: Arc<dyn ContentScanner>, content_type: Option<String>) -> Self {
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER_CHUNKS);
+        let scan = async move {
+            let stream = ScanStream { receiver };
+            scanner
+                .scan(content_type.as_deref(), ScanInput::Stream(stream))
+                .await
+        };
+
+        Self {
+            sender: Some(sender),
+            scan: Some(Mutex::new(scan.boxed())),
+            verdict: None,
+        }
+    }
+
+    /// hand a chunk to the scanner, failing if it rejected the content already
+    pub(crate) async fn feed(&mut self, chunk: &[u8]) -> Result<(), NimbusError> {
+        if let (Some(sender), Some(scan)) = (&self.sender, self.scan.as_mut()) {
+            let scan = scan.get_mut().unwrap_or_else(PoisonError::into_inner);
+            let verdict = {
+                let send = sender.send(chunk.to_vec());
+                futures::pin_mut!(send);
+                // the scan is polled first, so it catches up on the chunks buffered so far
+                match future::select(scan, send).await {
+                    Either::Left((verdict, _)) => verdict,
+                    Either::Right((Ok(()), _)) => return Ok(()),
+                    // the scanner stopped reading, its verdict is on its way
+                    Either::Right((Err(_), scan)) => scan.await,
+                }
+            };
+            self.settle(verdict);
+        }
+
+        self.verdict()
+    }
+
+    /// end the stream and wait for the verdict on the whole content
+    pub(crate) async fn finish(mut self) -> Result<(), NimbusError> {
+        self.sender = None;
+        if let Some(scan) = self.scan.take() {
+            let verdict = scan
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner)
+                .await;
+            self.settle(verdict);
+        }
+
+        self.verdict()
+    }
+
+    fn settle(&mut self, verdict: Result<ScanVerdict, ScanError>) {
+        self.sender = None;
+        self.scan = None;
+        self.verdict = Some(verdict);
+    }
+
+    fn verdict(&self) -> Result<(), NimbusError> {
+        match &self.verdict {
+            None | Some(Ok(ScanVerdict::Clean)) => Ok(()),
+            Some(Ok(ScanVerdict::Rejected { reason })) => Err(Error::ContentRejected {
+                reason: reason.clone(),
+            }
+            .into()),
+            Some(Err(e)) => Err(Error::ScanFailed(e.clone()).into()),
+        }
+    }
+}
+
+/// Storage scanning every upload with a [`ContentScanner`], see the [module](self) docs
+///
+/// Scans that fail, e.g. with the scanner unreachable, fail the upload: nothing unscanned is stored.
+/// Signed POST policies would let browsers upload past the scanner, so
+/// [`StorageHelper::signed_post_policy`] fails with [`Error::Unsupported`].
+pub struct ScannedStorage<T> {
+    inner: T,
+    scanner: Arc<dyn ContentScanner>,
+    quarantine: Option<Quarantine>,
+}
+
+impl<T> ScannedStorage<T> {
+    pub fn new(inner: T, scanner: impl ContentScanner + 'static) -> Self {
+        Self {
+            inner,
+            scanner: Arc::new(scanner),
+            quarantine: None,
+        }
+    }
+
+    /// keep rejected payloads held in memory, the upload still fails with [`Error::ContentRejected`]
+    ///
+    /// Chunks of resumable uploads aren't held, those are only aborted. A failure to keep a payload
+    /// fails the upload with that error instead.
+    pub fn quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.quarantine = Some(quarantine);
+        self
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: StorageHelper + Send + Sync> ScannedStorage<T> {
+    /// scan `data`, returning it if clean, quarantining it with the encryption of the upload if not
+    async fn check(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: &Option<String>,
+        data: Vec<u8>,
+        encryption: Option<&EncryptionKey>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let verdict = self
+            .scanner
+            .scan(mime.as_deref(), ScanInput::Bytes(&data))
+            .await
+            .map_err(Error::ScanFailed)?;
+
+        let ScanVerdict::Rejected { reason } = verdict else {
+            return Ok(data);
+        };
+
+        if let Some(quarantine) = &self.quarantine {
+            let (q_bucket, q_key) = (&quarantine.bucket, quarantine.key(bucket, key));
+            match encryption {
+                Some(encryption) => {
+                    self.inner
+                        .upload_encrypted(q_bucket, &q_key, mime.clone(), data, encryption)
+                        .await?
+                }
+                None => {
+                    self.inner
+                        .upload_from_bytes(q_bucket, &q_key, mime.clone(), data)
+                        .await?
+                }
+            }
+        }
+
+        Err(Error::ContentRejected { reason }.into())
+    }
+}
+
+// the provided upload methods go through these, e.g. `upload_file` through `upload_from_bytes`
+#[async_trait::async_trait]
+impl<T: StorageHelper + Send + Sync> StorageHelper for ScannedStorage<T> {
+    /// scanned with a [`NoopScanner`] until built with [`ScannedStorage::new`]
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_authenticator() -> Self {
+        Self::new(T::new_with_authenticator().await, NoopScanner)
+    }
+
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Self::new(T::new_with_transport(transport).await, NoopScanner)
+    }
+
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        let data = self.check(bucket, key, &mime, data, None).await?;
+        self.inner.upload_from_bytes(bucket, key, mime, data).await
+    }
+
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        self.inner.download_to_bytes(bucket, key).await
+    }
+
+    async fn download_to_writer<W>(
+        &self,
+        bucket: &str,
+        key: &str,
+        writer: &mut W,
+    ) -> Result<u64, NimbusError>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.inner.download_to_writer(bucket, key, writer).await
+    }
+
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        self.inner.download_stream(bucket, key).await
+    }
+
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.inner
+            .download_with_encoding(bucket, key, decompress)
+            .await
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.inner.download_range(bucket, key, start, end).await
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        let data = self
+            .check(bucket, key, &mime, data, Some(encryption))
+            .await?;
+        self.inner
+            .upload_encrypted(bucket, key, mime, data, encryption)
+            .await
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.inner.download_encrypted(bucket, key, encryption).await
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        self.inner
+            .object_metadata_encrypted(bucket, key, encryption)
+            .await
+    }
+
+    /// not scanned, the source was when it was uploaded
+    async fn copy_encrypted(
+        &self,
+        source: (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        destination: (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        self.inner
+            .copy_encrypted(
+                source,
+                source_encryption,
+                destination,
+                destination_encryption,
+            )
+            .await
+    }
+
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        self.inner.set_storage_class(bucket, key, class).await
+    }
+
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        self.inner.delete_file(bucket, key).await
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        self.inner.object_exists(bucket, key).await
+    }
+
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        let data = self.check(bucket, key, &mime, data, None).await?;
+        self.inner
+            .upload_conditional(bucket, key, mime, data, precondition)
+            .await
+    }
+
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        let data = self.check(bucket, key, &mime, data, None).await?;
+        self.inner
+            .upload_with_metadata(bucket, key, mime, metadata, data, precondition)
+            .await
+    }
+
+    /// only the appended bytes are scanned
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        let data = self.check(bucket, key, &mime, data, None).await?;
+        self.inner
+            .append_bytes(bucket, key, mime, data, options)
+            .await
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        self.inner.delete_conditional(bucket, key, version).await
+    }
+
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        self.inner.download_versioned(bucket, key).await
+    }
+
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        self.inner.list_page(bucket, params, page_token).await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        self.inner.object_metadata(bucket, key).await
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        self.inner.replication_status(bucket, key).await
+    }
+
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        self.inner.set_object_tags(bucket, key, tags).await
+    }
+
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        self.inner.get_object_tags(bucket, key).await
+    }
+
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        self.inner.list_soft_deleted(bucket, prefix, limits).await
+    }
+
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        self.inner.restore_object(bucket, key, generation).await
+    }
+
+    async fn test_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        self.inner.test_permissions(bucket, permissions).await
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        self.inner.bucket_is_public(bucket).await
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        self.inner.object_acls_apply(bucket).await
+    }
+
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        self.inner.object_acl(bucket, key).await
+    }
+
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        self.inner.get_cors(bucket).await
+    }
+
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        self.inner.set_cors(bucket, rules).await
+    }
+
+    async fn signed_post_policy(
+        &self,
+        _bucket: &str,
+        _key_prefix: &str,
+        _max_size: ByteSize,
+        _expires: Duration,
+        _conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        Err(Error::Unsupported(
+            "POST policy uploads go straight to the provider, past the content scanner".to_owned(),
+        )
+        .into())
+    }
+
+    /// downloads aren't scanned
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.inner
+            .signed_download_url(bucket, key, expires_in)
+            .await
+    }
+
+    async fn signed_upload_url(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _mime: Option<String>,
+        _expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        Err(Error::Unsupported(
+            "signed URL uploads go straight to the provider, past the content scanner".to_owned(),
+        )
+        .into())
+    }
+
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        let tee = ScanTee::new(self.scanner.clone(), mime.clone());
+        let upload = self.inner.start_resumable_upload(bucket, key, mime).await?;
+        Ok(upload.with_scan(tee))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MIN_CHUNK_SIZE;
+    use crate::testing::MemoryStorage;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// rejects content containing a signature, like an antivirus would
+    struct SignatureScanner(&'static [u8]);
+
+    impl SignatureScanner {
+        fn found(&self, data: &[u8]) -> bool {
+            data.windows(self.0.len()).any(|w| w == self.0)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ContentScanner for SignatureScanner {
+        async fn scan(
+            &self,
+            _content_type: Option<&str>,
+            data: ScanInput<'_>,
+        ) -> Result<ScanVerdict, ScanError> {
+            let found = match data {
+                ScanInput::Bytes(data) => self.found(data),
+                ScanInput::Stream(mut stream) => {
+                    let mut found = false;
+                    while let Some(chunk) = stream.next_chunk().await {
+                        found |= self.found(&chunk);
+                    }
+                    found
+                }
+            };
+
+            Ok(match found {
+                true => ScanVerdict::Rejected {
+                    reason: "signature found".to_owned(),
+                },
+                false => ScanVerdict::Clean,
+            })
+        }
+    }
+
+    fn chunk(fill: u8) -> Vec<u8> {
+        vec![fill; MIN_CHUNK_SIZE.as_usize()]
+    }
+
+    #[tokio::test]
+    async fn policy_scanner_test() {
+        let scanner = PolicyScanner::new().max_size(16u64).allow_type("png");
+        let scan = |data: &'static [u8]| scanner.scan(None, ScanInput::Bytes(data));
+
+        assert_eq!(scan(PNG).await.unwrap(), ScanVerdict::Clean);
+        assert!(matches!(
+            scan(b"plain text").await.unwrap(),
+            ScanVerdict::Rejected { reason } if reason.contains("recognized")
+        ));
+        assert!(matches!(
+            scan(b"GIF89a").await.unwrap(),
+            ScanVerdict::Rejected { reason } if reason.contains("image/gif")
+        ));
+        assert!(matches!(
+            scan(&[0; 17]).await.unwrap(),
+            ScanVerdict::Rejected { reason } if reason.contains("limit")
+        ));
+    }
+
+    #[tokio::test]
+    async fn quarantine_test() {
+        let memory = MemoryStorage::new();
+        let storage = ScannedStorage::new(memory.clone(), SignatureScanner(b"EICAR"))
+            .quarantine(Quarantine::new("quarantine").prefix("rejected/"));
+
+        storage
+            .upload_from_bytes("bucket", "clean.txt", None, b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            memory
+                .download_to_bytes("bucket", "clean.txt")
+                .await
+                .unwrap(),
+            b"hello"
+        );
+
+        let err = storage
+            .upload_from_bytes(
+                "bucket",
+                "bad.txt",
+                Some("text/plain".to_owned()),
+                b"xEICARx".to_vec(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::ContentRejected { ref reason }) if reason == "signature found"
+        ));
+        assert!(!memory.object_exists("bucket", "bad.txt").await.unwrap());
+
+        let kept = memory
+            .object_metadata("quarantine", "rejected/bucket/bad.txt")
+            .await
+            .unwrap();
+        assert_eq!(kept.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(
+            memory
+                .download_to_bytes("quarantine", "rejected/bucket/bad.txt")
+                .await
+                .unwrap(),
+            b"xEICARx"
+        );
+
+        // the provided upload methods go through the scanned ones
+        let res = storage
+            .upload_if_absent("bucket", "other.txt", None, b"EICAR".to_vec())
+            .await;
+        assert!(res.is_err());
+        assert!(memory
+            .object_exists("quarantine", "rejected/bucket/other.txt")
+            .await
+            .unwrap());
+
+        let err = storage
+            .signed_post_policy(
+                "bucket",
+                "uploads/",
+                ByteSize::mib(1),
+                Duration::from_secs(60),
+                vec![],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn streaming_tee_test() {
+        let memory = MemoryStorage::new();
+        let storage = ScannedStorage::new(memory.clone(), SignatureScanner(b"EICAR"))
+            .quarantine(Quarantine::new("quarantine"));
+
+        // more chunks than buffered ahead of the scanner
+        let mut upload = storage
+            .start_resumable_upload("bucket", "clean.bin", None)
+            .await
+            .unwrap();
+        for _ in 0..SCAN_BUFFER_CHUNKS + 2 {
+            upload.upload_chunk(chunk(0)).await.unwrap();
+        }
+        upload.finish().await.unwrap();
+        let meta = memory.object_metadata("bucket", "clean.bin").await.unwrap();
+        assert_eq!(
+            meta.size,
+            (SCAN_BUFFER_CHUNKS as u64 + 2) * MIN_CHUNK_SIZE.bytes()
+        );
+
+        // the verdict on the last chunk comes before the object is completed
+        let mut upload = storage
+            .start_resumable_upload("bucket", "bad.bin", None)
+            .await
+            .unwrap();
+        upload.upload_chunk(chunk(0)).await.unwrap();
+        upload.upload_chunk(b"EICAR".to_vec()).await.unwrap();
+        let err = upload.finish().await.unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::ContentRejected { .. })
+        ));
+        assert!(!memory.object_exists("bucket", "bad.bin").await.unwrap());
+        // chunks aren't held, so nothing is quarantined
+        assert!(memory
+            .list_all_objects("quarantine", None, ListLimits::default())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // a scanner done early fails the next chunk, before it is sent
+        let storage = ScannedStorage::new(
+            memory.clone(),
+            PolicyScanner::new().max_size(MIN_CHUNK_SIZE.bytes() * 2),
+        );
+        let mut upload = storage
+            .start_resumable_upload("bucket", "big.bin", None)
+            .await
+            .unwrap();
+        upload.upload_chunk(chunk(0)).await.unwrap();
+        upload.upload_chunk(chunk(1)).await.unwrap();
+        memory.reset_stats();
+        let mut failed = None;
+        for _ in 0..SCAN_BUFFER_CHUNKS + 2 {
+            if let Err(e) = upload.upload_chunk(chunk(2)).await {
+                failed = Some(e);
+                break;
+            }
+        }
+        assert_eq!(failed.unwrap().code(), ErrorCode::InvalidInput);
+        assert!(memory.stats().calls("upload_chunk") <= 1);
+        upload.abort().await.unwrap();
+        assert!(!memory.object_exists("bucket", "big.bin").await.unwrap());
+    }
+}
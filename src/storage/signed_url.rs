@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use super::Error;
+
+/// Longest validity of a signed URL, the limit of both V4 signing on GCS and SigV4 on S3
+pub const MAX_SIGNED_URL_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// `expires_in` is more than zero and at most [`MAX_SIGNED_URL_EXPIRY`]
+pub(crate) fn validate_expiry(expires_in: Duration) -> Result<(), Error> {
+    if expires_in.is_zero() || expires_in > MAX_SIGNED_URL_EXPIRY {
+        return Err(Error::InvalidExpiry {
+            requested: expires_in,
+            max: MAX_SIGNED_URL_EXPIRY,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageHelper;
+    use crate::testing::MemoryStorage;
+    use crate::ErrorCode;
+
+    #[tokio::test]
+    async fn signed_url_expiry_test() {
+        let storage = MemoryStorage::new();
+        let url = storage
+            .signed_download_url("bucket", "a/b.txt", MAX_SIGNED_URL_EXPIRY)
+            .await
+            .unwrap();
+        assert_eq!(url, "memory://bucket/a/b.txt?expires_in=604800");
+
+        for expires_in in [
+            Duration::ZERO,
+            MAX_SIGNED_URL_EXPIRY + Duration::from_secs(1),
+        ] {
+            let err = storage
+                .signed_download_url("bucket", "a/b.txt", expires_in)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), ErrorCode::InvalidInput);
+            assert!(err.to_string().contains("604800s"), "{err}");
+        }
+    }
+
+    #[cfg(feature = "aws-storage")]
+    fn s3_client() -> aws_sdk_s3::Client {
+        use aws_sdk_s3::config::{Credentials, Region, SharedCredentialsProvider};
+
+        let config = aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(Region::new("eu-west-3"))
+            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+                "AKIDEXAMPLE",
+                "secret",
+                None,
+                None,
+                "test",
+            )))
+            .build();
+        aws_sdk_s3::Client::new(&config)
+    }
+
+    /// the URL before the query, and the query parameters
+    fn split(url: &str) -> (&str, std::collections::HashMap<&str, &str>) {
+        let (base, query) = url.split_once('?').unwrap();
+        let query = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+        (base, query)
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[tokio::test]
+    async fn s3_signed_url_test() {
+        let storage = s3_client();
+
+        // presigning is local, nothing is sent
+        let url = storage
+            .signed_download_url("bucket", "reports/q1.pdf", Duration::from_secs(900))
+            .await
+            .unwrap();
+        let (base, query) = split(&url);
+        assert_eq!(
+            base,
+            "https://bucket.s3.eu-west-3.amazonaws.com/reports/q1.pdf"
+        );
+        assert_eq!(query["X-Amz-Expires"], "900");
+        assert_eq!(query["X-Amz-Algorithm"], "AWS4-HMAC-SHA256");
+        assert!(query["X-Amz-Credential"].starts_with("AKIDEXAMPLE%2F"));
+        assert!(query.contains_key("X-Amz-Signature"));
+        assert!(query.contains_key("X-Amz-Date"));
+
+        let err = storage
+            .signed_download_url("bucket", "reports/q1.pdf", Duration::from_secs(8 * 86400))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[cfg(feature = "aws-storage")]
+    #[tokio::test]
+    async fn s3_signed_upload_url_test() {
+        let storage = s3_client();
+
+        let url = storage
+            .signed_upload_url(
+                "bucket",
+                "uploads/a.png",
+                Some("image/png".to_owned()),
+                Duration::from_secs(300),
+            )
+            .await
+            .unwrap();
+        let (base, query) = split(&url);
+        assert_eq!(
+            base,
+            "https://bucket.s3.eu-west-3.amazonaws.com/uploads/a.png"
+        );
+        assert_eq!(query["X-Amz-Expires"], "300");
+        assert!(query.contains_key("X-Amz-Signature"));
+        // the browser must send this content type, or the signature doesn't match
+        assert_eq!(query["X-Amz-SignedHeaders"], "content-type%3Bhost");
+
+        let url = storage
+            .signed_upload_url("bucket", "uploads/a.png", None, Duration::from_secs(300))
+            .await
+            .unwrap();
+        assert_eq!(split(&url).1["X-Amz-SignedHeaders"], "host");
+    }
+
+    #[tokio::test]
+    async fn signed_upload_url_test() {
+        let storage = MemoryStorage::new();
+        let url = storage
+            .signed_upload_url(
+                "bucket",
+                "a.png",
+                Some("image/png".to_owned()),
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        assert_eq!(split(&url).1["content_type"], "image/png");
+
+        let err = storage
+            .signed_upload_url("bucket", "a.png", None, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+}
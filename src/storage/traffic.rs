@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+use std::time::Duration;
+
+static GLOBAL_METER: OnceLock<TrafficMeter> = OnceLock::new();
+
+/// Way bytes go between the service and a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// into the bucket: uploads
+    Ingress,
+    /// out of the bucket: downloads, what providers bill as egress
+    Egress,
+}
+
+/// bytes moved by bucket and direction
+pub type TrafficSnapshot = HashMap<(String, Direction), u64>;
+
+type Counters = HashMap<(String, Direction), Arc<AtomicU64>>;
+
+/// Bytes moved to and from each bucket, for cost attribution
+///
+/// Once installed with [`TrafficMeter::install`], every storage implementation counts the bytes
+/// it moves: single-request transfers once they succeed, resumable uploads chunk by chunk and
+/// S3 downloads as the body arrives, so a transfer failing midway counts the part that went through.
+/// Copies stay server side and count nothing. Without a meter installed counting costs one atomic load.
+///
+/// Counters are atomic, clones share them.
+///
+/// ```ignore
+/// let meter = TrafficMeter::new();
+/// meter.clone().install()?;
+/// meter.spawn_flush(Duration::from_secs(60), |moved| telemetry.push(moved));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TrafficMeter {
+    counters: Arc<RwLock<Counters>>,
+}
+
+impl TrafficMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// make this the meter of every storage implementation, can only be done once
+    /// returns the meter back if one is installed already
+    pub fn install(self) -> Result<(), TrafficMeter> {
+        GLOBAL_METER.set(self)
+    }
+
+    /// the installed meter, if any
+    pub fn installed() -> Option<&'static TrafficMeter> {
+        GLOBAL_METER.get()
+    }
+
+    /// count `bytes` moved in `direction` for `bucket`
+    pub fn add(&self, bucket: &str, direction: Direction, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+
+        let id = (bucket.to_owned(), direction);
+        let counters = self.counters.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counter) = counters.get(&id) {
+            counter.fetch_add(bytes, Ordering::Relaxed);
+            return;
+        }
+        drop(counters);
+
+        let mut counters = self
+            .counters
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        counters
+            .entry(id)
+            .or_default()
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// bytes moved in `direction` for `bucket` since the last reset
+    pub fn bytes(&self, bucket: &str, direction: Direction) -> u64 {
+        let counters = self.counters.read().unwrap_or_else(PoisonError::into_inner);
+        counters
+            .get(&(bucket.to_owned(), direction))
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// bytes moved since the last reset, buckets without traffic are left out
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        self.collect(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn reset(&self) {
+        self.take();
+    }
+
+    /// bytes moved since the last reset, and reset; no byte counted meanwhile is lost
+    pub fn take(&self) -> TrafficSnapshot {
+        self.collect(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+
+    /// call `flush` every `every` with the bytes moved since the previous flush, see [`TrafficMeter::take`]
+    /// flushes with no traffic are skipped, abort the returned task to stop flushing
+    pub fn spawn_flush(
+        &self,
+        every: Duration,
+        flush: impl Fn(TrafficSnapshot) + Send + 'static,
+    ) -> tokio::task::JoinHandle<()> {
+        let meter = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            // the first tick is immediate
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let moved = meter.take();
+                if !moved.is_empty() {
+                    flush(moved);
+                }
+            }
+        })
+    }
+
+    fn collect(&self, read: impl Fn(&AtomicU64) -> u64) -> TrafficSnapshot {
+        let counters = self.counters.read().unwrap_or_else(PoisonError::into_inner);
+        counters
+            .iter()
+            .map(|(id, counter)| (id.clone(), read(counter)))
+            .filter(|(_, bytes)| *bytes > 0)
+            .collect()
+    }
+}
+
+/// count bytes moved on the installed meter, if any
+pub(crate) fn record(bucket: &str, direction: Direction, bytes: u64) {
+    if let Some(meter) = GLOBAL_METER.get() {
+        meter.add(bucket, direction, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_meter_test() {
+        let meter = TrafficMeter::new();
+        meter.add("a", Direction::Ingress, 10);
+        meter.add("a", Direction::Ingress, 5);
+        meter.add("a", Direction::Egress, 7);
+        meter.add("b", Direction::Egress, 0);
+
+        assert_eq!(meter.bytes("a", Direction::Ingress), 15);
+        assert_eq!(meter.bytes("b", Direction::Egress), 0);
+        assert_eq!(
+            meter.snapshot(),
+            HashMap::from([
+                (("a".to_owned(), Direction::Ingress), 15),
+                (("a".to_owned(), Direction::Egress), 7),
+            ])
+        );
+
+        assert_eq!(meter.take().len(), 2);
+        assert!(meter.snapshot().is_empty());
+        meter.clone().add("a", Direction::Egress, 1);
+        assert_eq!(meter.bytes("a", Direction::Egress), 1);
+        meter.reset();
+        assert_eq!(meter.bytes("a", Direction::Egress), 0);
+    }
+
+    #[test]
+    fn traffic_meter_concurrency_test() {
+        let meter = TrafficMeter::new();
+        let taken = std::thread::scope(|scope| {
+            for i in 0..8 {
+                let meter = &meter;
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        meter.add(&format!("bucket-{}", i % 2), Direction::Egress, 3);
+                    }
+                });
+            }
+            // taken while adding, nothing is lost
+            let meter = &meter;
+            scope
+                .spawn(move || (0..100).map(|_| meter.take()).collect::<Vec<_>>())
+                .join()
+                .unwrap()
+        });
+
+        let total: u64 = taken
+            .iter()
+            .chain([meter.snapshot()].iter())
+            .flat_map(|snapshot| snapshot.values())
+            .sum();
+        assert_eq!(total, 8 * 1000 * 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spawn_flush_test() {
+        let meter = TrafficMeter::new();
+        let flushed = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = Arc::clone(&flushed);
+        let flush = meter.spawn_flush(Duration::from_secs(60), move |moved| {
+            sink.lock().unwrap().push(moved)
+        });
+
+        meter.add("a", Direction::Ingress, 4);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        // no traffic, no flush
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        meter.add("a", Direction::Ingress, 2);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        flush.abort();
+
+        let flushed = flushed.lock().unwrap();
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(flushed[0][&("a".to_owned(), Direction::Ingress)], 4);
+        assert_eq!(flushed[1][&("a".to_owned(), Direction::Ingress)], 2);
+        assert!(meter.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use futures::{Stream, StreamExt, TryStreamExt};
+use tokio::time::{Interval, MissedTickBehavior};
+
+use super::{Error, ObjectMeta, StorageHelper};
+use crate::NimbusError;
+
+/// Keys tracked by a watch unless set with [`WatchOptions::max_keys`]
+pub const DEFAULT_MAX_WATCHED_KEYS: usize = 100_000;
+
+/// Change under a watched prefix, from [`StorageHelper::watch_prefix`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectEvent {
+    /// objects under the prefix when the watch started, only with [`WatchOptions::emit_initial`]
+    Snapshot(Vec<ObjectMeta>),
+    Created(ObjectMeta),
+    /// the object was overwritten, its version changed
+    Updated(ObjectMeta),
+    /// key of the deleted object
+    Deleted(String),
+}
+
+/// Options of [`StorageHelper::watch_prefix_with`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// polls listing more keys than this fail with [`Error::WatchLimitExceeded`]
+    pub max_keys: usize,
+    /// emit the first listing as [`ObjectEvent::Snapshot`] instead of swallowing it
+    pub emit_initial: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            max_keys: DEFAULT_MAX_WATCHED_KEYS,
+            emit_initial: false,
+        }
+    }
+}
+
+impl WatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+
+    pub fn emit_initial(mut self, emit_initial: bool) -> Self {
+        self.emit_initial = emit_initial;
+        self
+    }
+}
+
+/// version of every key seen by the last successful poll
+type Snapshot = BTreeMap<String, Option<String>>;
+
+struct Watch<'a, S> {
+    storage: &'a S,
+    bucket: String,
+    prefix: String,
+    ticks: Interval,
+    options: WatchOptions,
+    /// `None` until a poll succeeded
+    known: Option<Snapshot>,
+    pending: VecDeque<Result<ObjectEvent, NimbusError>>,
+}
+
+pub(crate) fn watch<'a, S>(
+    storage: &'a S,
+    bucket: &str,
+    prefix: &str,
+    interval: Duration,
+    options: WatchOptions,
+) -> impl Stream<Item = Result<ObjectEvent, NimbusError>> + Send + 'a
+where
+    S: StorageHelper + Sync,
+{
+    let mut ticks = tokio::time::interval(interval);
+    // a slow consumer delays the next poll instead of triggering a burst of them
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let watch = Watch {
+        storage,
+        bucket: bucket.to_owned(),
+        prefix: prefix.to_owned(),
+        ticks,
+        options,
+        known: None,
+        pending: VecDeque::new(),
+    };
+
+    futures::stream::unfold(watch, |mut watch| async move {
+        loop {
+            if let Some(event) = watch.pending.pop_front() {
+                return Some((event, watch));
+            }
+
+            watch.ticks.tick().await;
+            watch.poll().await;
+        }
+    })
+}
+
+impl<S: StorageHelper + Sync> Watch<'_, S> {
+    /// list the prefix and queue the changes since the last successful poll
+    async fn poll(&mut self) {
+        let limit = self.options.max_keys;
+        let listed: Result<Vec<ObjectMeta>, NimbusError> = self
+            .storage
+            .list(&self.bucket)
+            .prefix(self.prefix.as_str())
+            .stream()
+            .take(limit.saturating_add(1))
+            .try_collect()
+            .await;
+
+        let listed = match listed {
+            // the snapshot is kept, changes show up on the next successful poll
+            Err(e) => return self.pending.push_back(Err(e)),
+            Ok(listed) if listed.len() > limit => {
+                let e = Error::WatchLimitExceeded {
+                    prefix: self.prefix.clone(),
+                    limit,
+                };
+                return self.pending.push_back(Err(e.into()));
+            }
+            Ok(listed) => listed,
+        };
+
+        let current: Snapshot = listed
+            .iter()
+            .map(|o| (o.key.clone(), o.version.clone()))
+            .collect();
+        let Some(previous) = self.known.replace(current) else {
+            if self.options.emit_initial {
+                self.pending.push_back(Ok(ObjectEvent::Snapshot(listed)));
+            }
+            return;
+        };
+
+        let current = self.known.as_ref().expect("snapshot replaced");
+        for object in listed {
+            match previous.get(&object.key) {
+                None => self.pending.push_back(Ok(ObjectEvent::Created(object))),
+                Some(version) if *version != object.version => {
+                    self.pending.push_back(Ok(ObjectEvent::Updated(object)))
+                }
+                Some(_) => {}
+            }
+        }
+        for key in previous.into_keys().filter(|k| !current.contains_key(k)) {
+            self.pending.push_back(Ok(ObjectEvent::Deleted(key)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, MemoryStorage};
+    use crate::ErrorCode;
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    async fn put(storage: &MemoryStorage, key: &str, data: &str) {
+        storage
+            .upload_from_bytes("b", key, None, data.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+
+    /// summary of the next event: kind, key and size
+    async fn next(
+        events: &mut (impl Stream<Item = Result<ObjectEvent, NimbusError>> + Unpin),
+    ) -> String {
+        match events.next().await.expect("watches don't end") {
+            Ok(ObjectEvent::Snapshot(objects)) => {
+                let keys: Vec<_> = objects.into_iter().map(|o| o.key).collect();
+                format!("snapshot {}", keys.join(","))
+            }
+            Ok(ObjectEvent::Created(o)) => format!("created {} {}", o.key, o.size),
+            Ok(ObjectEvent::Updated(o)) => format!("updated {} {}", o.key, o.size),
+            Ok(ObjectEvent::Deleted(key)) => format!("deleted {key}"),
+            Err(e) => format!("error {:?}", e.code()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_prefix_test() {
+        let storage = MemoryStorage::new();
+        put(&storage, "in/a", "a").await;
+
+        // changes land halfway between the polls at 0s, 30s, 60s...
+        let writer = storage.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(INTERVAL / 2).await;
+            put(&writer, "in/b", "bb").await;
+
+            tokio::time::sleep(INTERVAL).await;
+            put(&writer, "in/a", "aaa").await;
+            put(&writer, "other/x", "x").await;
+            writer.delete_file("b", "in/b").await.unwrap();
+            put(&writer, "in/c", "c").await;
+
+            tokio::time::sleep(INTERVAL).await;
+            let fault = Fault::new().fail(1.0, ErrorCode::Unavailable);
+            writer.mock_stats().set_fault("list_page", fault);
+            writer.delete_file("b", "in/a").await.unwrap();
+
+            tokio::time::sleep(INTERVAL * 2).await;
+            writer.mock_stats().clear_faults();
+        });
+
+        let start = tokio::time::Instant::now();
+        let mut events = Box::pin(storage.watch_prefix("b", "in/", INTERVAL));
+
+        // the first poll is swallowed
+        assert_eq!(next(&mut events).await, "created in/b 2");
+        assert_eq!(start.elapsed(), INTERVAL);
+
+        assert_eq!(next(&mut events).await, "updated in/a 3");
+        assert_eq!(next(&mut events).await, "created in/c 1");
+        assert_eq!(next(&mut events).await, "deleted in/b");
+        assert_eq!(start.elapsed(), INTERVAL * 2);
+
+        // listing failures are reported and the watch goes on from the last snapshot
+        assert_eq!(next(&mut events).await, "error Unavailable");
+        assert_eq!(next(&mut events).await, "error Unavailable");
+        assert_eq!(next(&mut events).await, "deleted in/a");
+        assert_eq!(start.elapsed(), INTERVAL * 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_options_test() {
+        let storage = MemoryStorage::new();
+        put(&storage, "in/a", "a").await;
+        put(&storage, "in/b", "b").await;
+
+        let options = WatchOptions::new().max_keys(2).emit_initial(true);
+        let mut events = Box::pin(storage.watch_prefix_with("b", "in/", INTERVAL, options));
+        assert_eq!(next(&mut events).await, "snapshot in/a,in/b");
+
+        put(&storage, "in/c", "c").await;
+        assert_eq!(next(&mut events).await, "error InvalidInput");
+        assert_eq!(next(&mut events).await, "error InvalidInput");
+
+        storage.delete_file("b", "in/a").await.unwrap();
+        assert_eq!(next(&mut events).await, "created in/c 1");
+        assert_eq!(next(&mut events).await, "deleted in/a");
+    }
+}
@@ -1,14 +1,74 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
-use google_cloudtasks2::api::{CreateTaskRequest, HttpRequest, OidcToken, Task};
+use futures::StreamExt;
+use google_apis_common::FieldMask;
+use google_cloudtasks2::api::{
+    CreateTaskRequest, HttpRequest, OidcToken, PauseQueueRequest, ResumeQueueRequest, Task,
+    TestIamPermissionsRequest,
+};
 use google_cloudtasks2::hyper::client::HttpConnector;
-use google_cloudtasks2::hyper::{self, Body, Response};
-use google_cloudtasks2::hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use google_cloudtasks2::hyper::{Body, Response};
+use google_cloudtasks2::hyper_rustls::HttpsConnector;
 use google_cloudtasks2::{oauth2::authenticator::Authenticator, CloudTasks};
 use thiserror::Error;
 
-use crate::NimbusError;
+use crate::error::with_context;
+use crate::{
+    BatchError, CredentialMonitor, ErrorCode, ErrorContext, ListLimits, NimbusError,
+    TransportConfig,
+};
+
+mod analysis;
+mod config;
+pub mod deadletter;
+mod envelope;
+mod ext;
+pub mod incoming;
+mod kinds;
+mod message;
+mod outcome;
+mod overrides;
+mod payload;
+mod queue;
+mod recurrence;
+mod redact;
+mod shard;
+mod validate;
+mod view;
+
+pub use crate::redact::DEFAULT_SENSITIVE_HEADERS;
+pub use analysis::{analyze_push, PushAnalysis, SCHEDULE_TOLERANCE};
+pub use config::{ApplyReport, ConfigChange, QueueConfigSnapshot, RateLimitsConfig, RetryConfig};
+pub use envelope::{Envelope, VersionMap};
+pub use ext::TaskExt;
+pub use kinds::{RouteSpec, TaskKindRegistry};
+pub use message::{QueueDefaults, QueueMessage, QueueProvider, Target, RESERVED_PREFIX};
+pub use outcome::{TaskOutcome, POLL_INITIAL_DELAY, POLL_MAX_DELAY};
+pub use overrides::TaskOverrides;
+#[cfg(feature = "jsonschema")]
+pub use payload::JsonSchemaValidator;
+pub use payload::{
+    is_json, NoValidation, PayloadSchemas, PayloadValidator, ValidationError, Violation,
+    KIND_HEADER,
+};
+pub use queue::{QueueDiscovery, QueueInfo, QueuePath, QueueState};
+pub use recurrence::{Recurrence, DEFAULT_MAX_OCCURRENCES, MAX_SCHEDULE_AHEAD};
+pub use redact::{RedactedTask, Redaction, DEFAULT_MAX_BODY};
+pub use shard::{fnv1a64, Routing, ShardStats, ShardedQueue};
+pub use validate::{TaskValidation, TaskWarning, BODY_METHODS};
+pub use view::TaskView;
+
+/// Deletes in flight in [`CloudTaskHelper::delete_tasks_where`]
+pub const DELETE_CONCURRENCY: usize = 16;
+
+/// Pushes in flight in [`CloudTaskHelper::push_fanout`]
+pub const FANOUT_CONCURRENCY: usize = 16;
+
+/// Locations searched at once by [`CloudTaskHelper::list_all_queues`]
+pub const LOCATION_CONCURRENCY: usize = 8;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -16,6 +76,105 @@ pub enum Error {
     Other(String),
     #[error("CloudTasks error: {0}")]
     CloudTasks(#[from] google_cloudtasks2::Error),
+    #[error("Not found: {}", crate::redact::resource(.0))]
+    NotFound(String),
+    #[error("Already exists: {}", crate::redact::resource(.0))]
+    AlreadyExists(String),
+    #[error("Missing header {0}")]
+    MissingHeader(String),
+    #[error("Invalid header {header}: {}", crate::redact::header_value(.header, .message))]
+    InvalidHeader { header: String, message: String },
+    #[error("Invalid task view {0:?}, expected BASIC or FULL")]
+    InvalidView(String),
+    #[error("Invalid task name {:?}", crate::redact::resource(.0))]
+    InvalidTaskName(String),
+    #[error(
+        "{method} tasks can't have a body, Cloud Tasks only accepts one with POST, PUT or PATCH"
+    )]
+    BodyNotAllowed { method: String },
+    #[error("{method} task has a Content-Type but no body")]
+    EmptyBody { method: String },
+    #[error("Invalid task payload: {0}")]
+    InvalidPayload(String),
+    #[error("Payload breaks its schema: {0}")]
+    PayloadInvalid(ValidationError),
+    #[error(
+        "Payload version {version} is no longer supported, the oldest supported is {min_supported}"
+    )]
+    UnsupportedVersion { version: u32, min_supported: u32 },
+    #[error("Unknown task kind {0:?}")]
+    UnknownKind(String),
+    #[error("Unknown payload version {version}, the latest known is {latest:?}")]
+    UnknownVersion { version: u32, latest: Option<u32> },
+    #[error("Invalid queue message: {0}")]
+    InvalidMessage(String),
+    #[error("Not representable as a queue message: {0}")]
+    NotRepresentable(String),
+    #[error("Invalid recurrence: {0}")]
+    InvalidRecurrence(String),
+    #[error("Recurrence expands to more than {max} tasks")]
+    TooManyOccurrences { max: usize },
+    #[error("Occurrence at {at} is scheduled past {limit}, the furthest Cloud Tasks accepts")]
+    ScheduleTooFar {
+        at: DateTime<Utc>,
+        limit: DateTime<Utc>,
+    },
+    #[error("Invalid queue config: {0}")]
+    InvalidQueueConfig(String),
+    #[error("Permission denied: {}", crate::redact::resource(.0))]
+    PermissionDenied(String),
+    #[error("Rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl Error {
+    /// provider independent classification of the error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::CloudTasks(e) => crate::error::classify_api_error(e),
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::AlreadyExists(_) => ErrorCode::AlreadyExists,
+            Error::MissingHeader(_)
+            | Error::InvalidHeader { .. }
+            | Error::InvalidView(_)
+            | Error::InvalidTaskName(_)
+            | Error::BodyNotAllowed { .. }
+            | Error::EmptyBody { .. }
+            | Error::InvalidPayload(_)
+            | Error::PayloadInvalid(_)
+            | Error::UnsupportedVersion { .. }
+            | Error::UnknownKind(_)
+            | Error::InvalidMessage(_)
+            | Error::NotRepresentable(_)
+            | Error::InvalidRecurrence(_)
+            | Error::TooManyOccurrences { .. }
+            | Error::ScheduleTooFar { .. }
+            | Error::InvalidQueueConfig(_) => ErrorCode::InvalidInput,
+            // from a newer producer, handled once the handler is deployed too
+            Error::UnknownVersion { .. } => ErrorCode::Unavailable,
+            Error::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            Error::RateLimited { .. } => ErrorCode::RateLimited,
+            Error::Timeout(_) => ErrorCode::Timeout,
+            Error::Unavailable(_) => ErrorCode::Unavailable,
+            Error::Other(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// how long the provider asked to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::CloudTasks(e) => crate::error::api_retry_after(e),
+            Error::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -46,6 +205,13 @@ pub trait TaskHelper: Sized {
             ..Default::default()
         }
     }
+
+    /// Copy of the task safe to log, see [`Redaction::default`]
+    fn redacted(&self) -> Task;
+
+    /// Warnings about the task, or the error it would fail with, see [`TaskValidation`]
+    /// e.g. `Task::new_task(..).validate(&TaskValidation::default())?` fails right away on a GET with a body
+    fn validate(&self, validation: &TaskValidation) -> Result<Vec<TaskWarning>, NimbusError>;
 }
 
 /// CloudTaskHelper trait
@@ -55,6 +221,35 @@ pub trait CloudTaskHelper<S> {
     /// Create a new CloudTasks with an Authenticator
     async fn new_with_authenticator(authenticator: Authenticator<S>) -> Self;
 
+    /// new client whose connections are pooled and kept alive as set in `transport`
+    /// clients without an HTTP transport, like the in-memory one, ignore it
+    async fn new_with_transport(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+    ) -> Self
+    where
+        Self: Sized,
+        S: Send + Sync + 'static,
+    {
+        let _ = transport;
+        Self::new_with_authenticator(authenticator).await
+    }
+
+    /// new client reporting its token fetches to `monitor`, with its transport as in `new_with_transport`
+    /// clients without credentials, like the in-memory one, ignore it
+    async fn new_with_monitor(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self
+    where
+        Self: Sized,
+        S: Send + Sync + 'static,
+    {
+        let _ = monitor;
+        Self::new_with_transport(authenticator, transport).await
+    }
+
     /// Push a task to a queue without creating a task first
     #[allow(clippy::too_many_arguments)]
     async fn push(
@@ -67,7 +262,7 @@ pub trait CloudTaskHelper<S> {
         name: Option<String>,
         schedule_time: Option<DateTime<Utc>>,
         oidc_token: Option<OidcToken>,
-        res_view: Option<String>,
+        res_view: Option<TaskView>,
     ) -> Result<(Response<Body>, Task), NimbusError> {
         let task = Task::new_task(
             service,
@@ -83,53 +278,841 @@ pub trait CloudTaskHelper<S> {
     }
 
     /// Push a task to a queue, takes a Task
+    /// fails before pushing if the task doesn't pass [`TaskValidation::default`]
     async fn push_task(
         &self,
         queue: &str,
         task: Task,
-        res_view: Option<String>,
+        res_view: Option<TaskView>,
+    ) -> Result<(Response<Body>, Task), NimbusError> {
+        self.push_task_with(queue, task, &TaskValidation::default(), res_view)
+            .await
+    }
+
+    /// Push a task to a queue once it passes `validation`, warnings don't stop the push
+    /// e.g. [`TaskValidation::allow_body_with_any_method`] to let the server decide
+    async fn push_task_with(
+        &self,
+        queue: &str,
+        task: Task,
+        validation: &TaskValidation,
+        res_view: Option<TaskView>,
+    ) -> Result<(Response<Body>, Task), NimbusError> {
+        validation.check(&task)?;
+        self.create_task(queue, task, res_view).await
+    }
+
+    /// Push a task like [`CloudTaskHelper::push_task`], along with what the server changed of it
+    /// e.g. [`PushAnalysis::schedule_delayed_by`] as a backpressure signal, see [`analyze_push`]
+    async fn push_task_analyzed(
+        &self,
+        queue: &str,
+        task: Task,
+        res_view: Option<TaskView>,
+    ) -> Result<(Task, PushAnalysis), NimbusError> {
+        // only the name and times are compared, the request isn't copied
+        let requested = Task {
+            http_request: None,
+            app_engine_http_request: None,
+            ..task.clone()
+        };
+        let (_, returned) = self.push_task(queue, task, res_view).await?;
+        let analysis = analyze_push(&requested, &returned);
+        Ok((returned, analysis))
+    }
+
+    /// Push `payload` as a task of `kind`, to the URL, queue and deadline registered for it
+    /// the body is an [`Envelope`] stamped with the kind, see [`TaskKindRegistry::task`]
+    /// fails before pushing if `kind` isn't registered or carries another payload type
+    async fn push_kind<P>(
+        &self,
+        registry: &TaskKindRegistry,
+        kind: &str,
+        payload: &P,
+        overrides: TaskOverrides,
+    ) -> Result<(Response<Body>, Task), NimbusError>
+    where
+        P: serde::Serialize + Sync + 'static,
+    {
+        let (queue, task) = registry.task(kind, payload, overrides)?;
+        self.push_task(&queue, task, None).await
+    }
+
+    /// Send a task to a queue as is, without the client-side checks of [`CloudTaskHelper::push_task`]
+    async fn create_task(
+        &self,
+        queue: &str,
+        task: Task,
+        res_view: Option<TaskView>,
     ) -> Result<(Response<Body>, Task), NimbusError>;
+
+    /// Push a task built from a template, e.g. to push one task to many queues
+    /// the template is borrowed, but its request is copied on every push, body included: the generated
+    /// client takes the `Task` it sends by value. Unlike `push_task(template.clone())`, the output only
+    /// fields of the template are left out, see [`TaskOverrides::apply`] and `benches/push_task_ref.rs`.
+    async fn push_task_ref(
+        &self,
+        queue: &str,
+        task: &Task,
+        overrides: TaskOverrides,
+        res_view: Option<TaskView>,
+    ) -> Result<(Response<Body>, Task), NimbusError> {
+        self.push_task(queue, overrides.apply(task), res_view).await
+    }
+
+    /// Push the same task to several queues, e.g. one per region, returns the result of each push
+    /// in the order of `queues`; pushes run concurrently, at most [`FANOUT_CONCURRENCY`] at once
+    ///
+    /// A task name is scoped to its queue, so a named template is pushed under its task id in each queue:
+    /// `projects/p/locations/l/queues/a/tasks/job-1` becomes `{queue}/tasks/job-1`.
+    /// Every queue then deduplicates the job on its own, pushing it again fails with `AlreadyExists` per queue.
+    /// A template without a name gets a name generated by each queue.
+    /// Fails before pushing anything if the template name has no task id.
+    async fn push_fanout(
+        &self,
+        queues: &[&str],
+        task_template: Task,
+        res_view: Option<TaskView>,
+    ) -> Result<Vec<Result<Task, NimbusError>>, NimbusError> {
+        let id = match task_template.name.as_deref() {
+            Some(name) => match name.rsplit('/').next() {
+                Some(id) if !id.is_empty() => Some(id.to_owned()),
+                _ => return Err(Error::InvalidTaskName(name.to_owned()).into()),
+            },
+            None => None,
+        };
+
+        let template = &task_template;
+        let id = id.as_deref();
+        let queues: Vec<String> = queues.iter().map(|q| q.to_string()).collect();
+        let results = futures::stream::iter(queues)
+            .map(|queue| async move {
+                let overrides = match id {
+                    Some(id) => TaskOverrides::new().name(format!("{queue}/tasks/{id}")),
+                    None => TaskOverrides::new(),
+                };
+                self.push_task_ref(&queue, template, overrides, res_view)
+                    .await
+                    .map(|(_, task)| task)
+            })
+            .buffered(FANOUT_CONCURRENCY)
+            .collect()
+            .await;
+
+        Ok(results)
+    }
+
+    /// Push one task per occurrence of `recurrence` within `window` from now, returns the tasks pushed
+    /// each is `template` named `{queue}/tasks/{id}-{time}` after the task id of the template and the UTC time
+    /// of its occurrence, e.g. `report-20261017T150000Z`, and scheduled at that time (plus jitter)
+    ///
+    /// Occurrences already pushed by a previous expansion fail with `AlreadyExists` and are skipped,
+    /// so running the expansion again, e.g. on a timer shorter than the window, only pushes the new ones.
+    /// Fails before pushing anything if the template has no task id, if the window holds more than
+    /// [`Recurrence::max_occurrences`] or goes past [`MAX_SCHEDULE_AHEAD`].
+    /// Pushes run concurrently, at most [`FANOUT_CONCURRENCY`] at once; a failing push doesn't stop the others,
+    /// the first failure is returned once every occurrence was tried.
+    async fn push_recurring(
+        &self,
+        queue: &str,
+        template: &Task,
+        recurrence: &Recurrence,
+        window: Duration,
+    ) -> Result<Vec<Task>, NimbusError> {
+        let tasks = recurrence.expand(queue, template, window, Utc::now())?;
+
+        let results: Vec<_> = futures::stream::iter(tasks)
+            .map(|task| async move { self.push_task(queue, task, None).await })
+            .buffered(FANOUT_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut pushed = Vec::with_capacity(results.len());
+        let mut first_error = None;
+        for result in results {
+            match result {
+                Ok((_, task)) => pushed.push(task),
+                Err(e) if e.code() == ErrorCode::AlreadyExists => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(pushed),
+        }
+    }
+
+    /// Get a task by its full name
+    /// `res_view` is [`TaskView::Basic`] when `None`; [`TaskView::Full`] includes headers and body
+    /// with `redact` the full view is passed through [`Redaction::default`],
+    /// pass `true` unless the raw headers are really needed
+    async fn get_task(
+        &self,
+        name: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+    ) -> Result<Task, NimbusError>;
+
+    /// List one page of the tasks in a queue
+    /// returns the tasks and the token for the next page, `None` on the last page
+    /// `res_view` and `redact` as in [`CloudTaskHelper::get_task`]
+    async fn list_tasks(
+        &self,
+        queue: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+        page_token: Option<String>,
+    ) -> Result<(Vec<Task>, Option<String>), NimbusError>;
+
+    /// Get a task in the [`TaskView::Full`] view, to debug it
+    /// printing it with `{:?}` is redacted, [`RedactedTask::into_inner`] returns the full task, e.g. to push it again
+    async fn inspect_task(&self, name: &str) -> Result<RedactedTask, NimbusError> {
+        let task = self.get_task(name, Some(TaskView::Full), false).await?;
+        Ok(task.into())
+    }
+
+    /// List one page of the tasks in a queue as [`CloudTaskHelper::inspect_task`] gets them
+    /// returns the tasks and the token for the next page, `None` on the last page
+    async fn inspect_tasks(
+        &self,
+        queue: &str,
+        page_token: Option<String>,
+    ) -> Result<(Vec<RedactedTask>, Option<String>), NimbusError> {
+        let (tasks, next) = self
+            .list_tasks(queue, Some(TaskView::Full), false, page_token)
+            .await?;
+        Ok((tasks.into_iter().map(RedactedTask::from).collect(), next))
+    }
+
+    /// Delete a task by its full name
+    async fn delete_task(&self, name: &str) -> Result<(), NimbusError>;
+
+    /// Delete the tasks of a queue matching `predicate`, returns how many were deleted
+    /// tasks are listed page by page in the [`TaskView::Basic`] view (no headers nor body) and the matching ones
+    /// of each page deleted with at most [`DELETE_CONCURRENCY`] requests in flight
+    ///
+    /// Tasks gone before their delete (e.g. dispatched in between) are skipped.
+    /// A failing delete doesn't stop the others, the first failure is returned once every matching task was tried.
+    async fn delete_tasks_where(
+        &self,
+        queue: &str,
+        predicate: impl for<'t> Fn(&'t Task) -> bool + Send + Sync,
+    ) -> Result<u64, NimbusError> {
+        let mut deleted = 0;
+        let mut failure = None;
+        let mut token = None;
+
+        loop {
+            let (tasks, next) = self.list_tasks(queue, None, false, token).await?;
+            let names: Vec<String> = tasks
+                .iter()
+                .filter(|t| predicate(t))
+                .filter_map(|t| t.name.clone())
+                .collect();
+
+            let results = futures::stream::iter(names)
+                .map(|name| async move { self.delete_task(&name).await })
+                .buffer_unordered(DELETE_CONCURRENCY)
+                .collect::<Vec<_>>()
+                .await;
+
+            for res in results {
+                match res {
+                    Ok(()) => deleted += 1,
+                    Err(e) if e.code() == ErrorCode::NotFound => {}
+                    Err(e) => {
+                        failure.get_or_insert(e);
+                    }
+                }
+            }
+
+            match next {
+                Some(t) => token = Some(t),
+                None => break,
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(deleted),
+        }
+    }
+
+    /// Poll a task (by its full name) until it leaves its queue or fails for good, backing off between polls
+    /// a failure is final once the task used up the `max_attempts` of its queue, which is read first
+    /// (needs `cloudtasks.queues.get`)
+    ///
+    /// Cloud Tasks deletes a task both when it succeeds and when its last allowed attempt fails,
+    /// so a final failure that happens between two polls is reported as [`TaskOutcome::Succeeded`].
+    async fn wait_for_task_completion(
+        &self,
+        task_name: &str,
+        timeout: Duration,
+    ) -> Result<TaskOutcome, NimbusError>;
+
+    /// Ids of the locations of a project e.g. `europe-west1`, every page within `limits`
+    async fn list_locations(
+        &self,
+        project: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError>;
+
+    /// Queues of a project in one location, every page within `limits`
+    async fn list_queues(
+        &self,
+        project: &str,
+        location: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<QueueInfo>, NimbusError>;
+
+    /// Queues of a project in every location, listed with at most [`LOCATION_CONCURRENCY`] locations at once
+    /// each listing is bounded by [`ListLimits::default`], far above the Cloud Tasks queue quota
+    ///
+    /// A location the caller can't list queues in (some folders restrict regions) is reported
+    /// as a warning, any other failure fails the discovery.
+    async fn list_all_queues(
+        &self,
+        project: &str,
+    ) -> Result<QueueDiscovery<QueueInfo>, NimbusError> {
+        let locations = self.list_locations(project, ListLimits::default()).await?;
+
+        let results = futures::stream::iter(locations)
+            .map(|location| async move {
+                let queues = self
+                    .list_queues(project, &location, ListLimits::default())
+                    .await;
+                (location, queues)
+            })
+            .buffer_unordered(LOCATION_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut discovery = QueueDiscovery {
+            found: vec![],
+            warnings: vec![],
+        };
+        for (location, queues) in results {
+            match queues {
+                Ok(queues) => discovery.found.extend(queues),
+                Err(error) if error.code() == ErrorCode::PermissionDenied => {
+                    discovery.warnings.push(BatchError {
+                        key: location,
+                        error,
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        discovery.found.sort_by(|a, b| a.path.cmp(&b.path));
+        discovery.warnings.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(discovery)
+    }
+
+    /// Every queue of a project named `queue_short_name`, whatever its location
+    /// see [`CloudTaskHelper::list_all_queues`]
+    async fn find_queue(
+        &self,
+        project: &str,
+        queue_short_name: &str,
+    ) -> Result<QueueDiscovery<QueuePath>, NimbusError> {
+        let all = self.list_all_queues(project).await?;
+
+        Ok(QueueDiscovery {
+            found: all
+                .found
+                .into_iter()
+                .filter(|q| q.path.queue == queue_short_name)
+                .map(|q| q.path)
+                .collect(),
+            warnings: all.warnings,
+        })
+    }
+
+    /// Settings of a queue to keep as code, see [`QueueConfigSnapshot`]
+    async fn export_queue_config(
+        &self,
+        queue: &QueuePath,
+    ) -> Result<QueueConfigSnapshot, NimbusError>;
+
+    /// Create a queue with the settings of `config`, its state aside: a new queue is running
+    async fn create_queue(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+    ) -> Result<(), NimbusError>;
+
+    /// Set the `fields` of a queue to their value in `config`, fields are update mask paths, e.g.
+    /// `retry_config.max_attempts`; `state` is changed by pausing or resuming the queue
+    async fn update_queue_config(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+        fields: &[&str],
+    ) -> Result<(), NimbusError>;
+
+    /// What [`CloudTaskHelper::apply_queue_config`] would change, without changing anything
+    async fn plan_queue_config(
+        &self,
+        queue: &QueuePath,
+        snapshot: &QueueConfigSnapshot,
+        create_if_missing: bool,
+    ) -> Result<ApplyReport, NimbusError> {
+        snapshot.validate()?;
+
+        let (created, changes) = match self.export_queue_config(queue).await {
+            Ok(current) => (false, snapshot.diff(&current)),
+            Err(e) if create_if_missing && e.code() == ErrorCode::NotFound => {
+                // a new queue is running, its other settings are all changed from unset
+                let new = QueueConfigSnapshot {
+                    state: Some(QueueState::Running),
+                    ..Default::default()
+                };
+                (true, snapshot.diff(&new))
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(ApplyReport {
+            queue: queue.clone(),
+            created,
+            changes,
+            dry_run: true,
+        })
+    }
+
+    /// Bring a queue to the settings of `snapshot`, creating it if missing and `create_if_missing`
+    ///
+    /// Only the settings that differ are updated, in a single call with their update mask, and the
+    /// state last. Applying the snapshot exported from a queue to that same queue changes nothing.
+    async fn apply_queue_config(
+        &self,
+        queue: &QueuePath,
+        snapshot: &QueueConfigSnapshot,
+        create_if_missing: bool,
+    ) -> Result<ApplyReport, NimbusError> {
+        let mut report = self
+            .plan_queue_config(queue, snapshot, create_if_missing)
+            .await?;
+
+        if report.created {
+            self.create_queue(queue, snapshot).await?;
+        }
+        let (state, fields): (Vec<&str>, Vec<&str>) = report
+            .changes
+            .iter()
+            .map(|c| c.field)
+            .filter(|field| !report.created || *field == "state")
+            .partition(|field| *field == "state");
+        if !fields.is_empty() {
+            self.update_queue_config(queue, snapshot, &fields).await?;
+        }
+        if !state.is_empty() {
+            self.update_queue_config(queue, snapshot, &state).await?;
+        }
+
+        report.dry_run = false;
+        Ok(report)
+    }
+
+    /// Returns which of the given IAM permissions the caller holds on a queue
+    /// e.g. `cloudtasks.tasks.create`
+    async fn test_permissions(
+        &self,
+        queue: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError>;
+
+    /// Returns which of the given IAM permissions the caller lacks on a queue
+    async fn missing_permissions(
+        &self,
+        queue: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        let held = self.test_permissions(queue, permissions).await?;
+        Ok(permissions
+            .iter()
+            .filter(|p| !held.iter().any(|h| h == *p))
+            .map(|p| p.to_string())
+            .collect())
+    }
 }
 
-impl TaskHelper for Task {}
+impl TaskHelper for Task {
+    fn redacted(&self) -> Task {
+        Redaction::default().apply(self)
+    }
+
+    fn validate(&self, validation: &TaskValidation) -> Result<Vec<TaskWarning>, NimbusError> {
+        Ok(validation.check(self)?)
+    }
+}
 
 #[async_trait::async_trait]
 impl CloudTaskHelper<HttpsConnector<HttpConnector>> for CloudTasks<HttpsConnector<HttpConnector>> {
     async fn new_with_authenticator(
         authenticator: Authenticator<HttpsConnector<HttpConnector>>,
     ) -> Self {
-        CloudTasks::new(
-            hyper::Client::builder().build(
-                HttpsConnectorBuilder::new()
-                    .with_native_roots()
-                    .https_only()
-                    .enable_http1()
-                    .enable_http2()
-                    .build(),
-            ),
-            authenticator,
-        )
+        Self::new_with_transport(authenticator, &TransportConfig::default()).await
     }
 
-    async fn push_task(
+    async fn new_with_transport(
+        authenticator: Authenticator<HttpsConnector<HttpConnector>>,
+        transport: &TransportConfig,
+    ) -> Self {
+        CloudTasks::new(transport.hyper_client(), authenticator)
+    }
+
+    async fn new_with_monitor(
+        authenticator: Authenticator<HttpsConnector<HttpConnector>>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self {
+        CloudTasks::new(transport.hyper_client(), monitor.wrap(authenticator))
+    }
+
+    async fn create_task(
         &self,
         queue: &str,
         task: Task,
-        res_view: Option<String>,
+        res_view: Option<TaskView>,
     ) -> Result<(Response<Body>, Task), NimbusError> {
-        let rq = CreateTaskRequest {
-            task: Some(task),
-            response_view: res_view,
-        };
+        let resource = task.name.clone().unwrap_or_else(|| queue.to_owned());
+        with_context(|| ErrorContext::new("create_task", resource), async {
+            let rq = CreateTaskRequest {
+                task: Some(task),
+                response_view: res_view.map(String::from),
+            };
 
-        let a = self
-            .projects()
-            .locations_queues_tasks_create(rq, queue)
-            .doit()
-            .await
-            .map_err(Error::CloudTasks)?;
+            let a = self
+                .projects()
+                .locations_queues_tasks_create(rq, queue)
+                .doit()
+                .await
+                .map_err(Error::CloudTasks)?;
+
+            Ok(a)
+        })
+        .await
+    }
+
+    async fn get_task(
+        &self,
+        name: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+    ) -> Result<Task, NimbusError> {
+        with_context(|| ErrorContext::new("get_task", name), async {
+            let mut call = self.projects().locations_queues_tasks_get(name);
+            if let Some(view) = res_view {
+                call = call.response_view(view.as_str());
+            }
+
+            let (_, task) = call.doit().await.map_err(Error::CloudTasks)?;
+
+            Ok(if redact { task.redacted() } else { task })
+        })
+        .await
+    }
+
+    async fn list_tasks(
+        &self,
+        queue: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+        page_token: Option<String>,
+    ) -> Result<(Vec<Task>, Option<String>), NimbusError> {
+        with_context(|| ErrorContext::new("list_tasks", queue), async {
+            let mut call = self.projects().locations_queues_tasks_list(queue);
+            if let Some(view) = res_view {
+                call = call.response_view(view.as_str());
+            }
+            if let Some(token) = page_token.as_deref() {
+                call = call.page_token(token);
+            }
+
+            let (_, res) = call.doit().await.map_err(Error::CloudTasks)?;
+
+            let tasks = res
+                .tasks
+                .unwrap_or_default()
+                .into_iter()
+                .map(|t| if redact { t.redacted() } else { t })
+                .collect();
+
+            // an empty token marks the last page
+            let next = res.next_page_token.filter(|t| !t.is_empty());
+
+            Ok((tasks, next))
+        })
+        .await
+    }
+
+    async fn delete_task(&self, name: &str) -> Result<(), NimbusError> {
+        with_context(|| ErrorContext::new("delete_task", name), async {
+            self.projects()
+                .locations_queues_tasks_delete(name)
+                .doit()
+                .await
+                .map_err(Error::CloudTasks)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn wait_for_task_completion(
+        &self,
+        task_name: &str,
+        timeout: Duration,
+    ) -> Result<TaskOutcome, NimbusError> {
+        with_context(
+            || ErrorContext::new("wait_for_task_completion", task_name),
+            async {
+                let deadline = tokio::time::Instant::now() + timeout;
+
+                let max_attempts = match outcome::queue_of(task_name) {
+                    Some(queue) => {
+                        let (_, queue) = self
+                            .projects()
+                            .locations_queues_get(queue)
+                            .doit()
+                            .await
+                            .map_err(Error::CloudTasks)?;
+                        queue.retry_config.and_then(|c| c.max_attempts)
+                    }
+                    None => None,
+                };
+
+                let mut delay = None;
+                loop {
+                    match self.get_task(task_name, None, true).await {
+                        Ok(task) => {
+                            if let Some(failed) = outcome::terminal_failure(&task, max_attempts) {
+                                return Ok(failed);
+                            }
+                        }
+                        Err(e) if e.code() == ErrorCode::NotFound => {
+                            return Ok(TaskOutcome::Succeeded)
+                        }
+                        Err(e) => return Err(e),
+                    }
+
+                    let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+                    if remaining.is_zero() {
+                        return Ok(TaskOutcome::TimedOut);
+                    }
+
+                    let next = outcome::next_delay(delay, remaining);
+                    tokio::time::sleep(next).await;
+                    delay = Some(next);
+                }
+            },
+        )
+        .await
+    }
+
+    async fn list_locations(
+        &self,
+        project: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        with_context(
+            || ErrorContext::new("list_locations", format!("projects/{project}")),
+            async {
+                let name = format!("projects/{project}");
+                let mut drain = limits.drain();
+                let mut locations = vec![];
+                let mut token: Option<String> = None;
+
+                loop {
+                    let mut call = self.projects().locations_list(&name);
+                    if let Some(token) = token.as_deref() {
+                        call = call.page_token(token);
+                    }
+                    let (_, res) = call.doit().await.map_err(Error::CloudTasks)?;
+
+                    let page: Vec<String> = res
+                        .locations
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|l| {
+                            l.location_id
+                                .or_else(|| l.name?.rsplit('/').next().map(str::to_owned))
+                        })
+                        .collect();
+
+                    // an empty token marks the last page
+                    let next = res.next_page_token.filter(|t| !t.is_empty());
+                    drain.page(page.len(), next.is_some())?;
+                    locations.extend(page);
+
+                    match next {
+                        Some(next) => token = Some(next),
+                        None => return Ok(locations),
+                    }
+                }
+            },
+        )
+        .await
+    }
+
+    async fn list_queues(
+        &self,
+        project: &str,
+        location: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<QueueInfo>, NimbusError> {
+        with_context(
+            || {
+                ErrorContext::new(
+                    "list_queues",
+                    format!("projects/{project}/locations/{location}"),
+                )
+            },
+            async {
+                let parent = format!("projects/{project}/locations/{location}");
+                let mut drain = limits.drain();
+                let mut queues = vec![];
+                let mut token: Option<String> = None;
+
+                loop {
+                    let mut call = self.projects().locations_queues_list(&parent);
+                    if let Some(token) = token.as_deref() {
+                        call = call.page_token(token);
+                    }
+                    let (_, res) = call.doit().await.map_err(Error::CloudTasks)?;
+
+                    let page: Vec<QueueInfo> = res
+                        .queues
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|q| {
+                            Some(QueueInfo {
+                                path: QueuePath::parse(q.name.as_deref()?)?,
+                                state: QueueState::from_api(q.state.as_deref()),
+                            })
+                        })
+                        .collect();
+
+                    let next = res.next_page_token.filter(|t| !t.is_empty());
+                    drain.page(page.len(), next.is_some())?;
+                    queues.extend(page);
+
+                    match next {
+                        Some(next) => token = Some(next),
+                        None => return Ok(queues),
+                    }
+                }
+            },
+        )
+        .await
+    }
+
+    async fn export_queue_config(
+        &self,
+        queue: &QueuePath,
+    ) -> Result<QueueConfigSnapshot, NimbusError> {
+        let name = queue.to_string();
+        with_context(|| ErrorContext::new("export_queue_config", &name), async {
+            let (_, res) = self
+                .projects()
+                .locations_queues_get(&name)
+                .doit()
+                .await
+                .map_err(Error::CloudTasks)?;
 
-        Ok(a)
+            Ok(QueueConfigSnapshot::from_api(&res))
+        })
+        .await
+    }
+
+    async fn create_queue(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+    ) -> Result<(), NimbusError> {
+        with_context(
+            || ErrorContext::new("create_queue", queue.to_string()),
+            async {
+                let parent = format!("projects/{}/locations/{}", queue.project, queue.location);
+                self.projects()
+                    .locations_queues_create(config.to_api(queue), &parent)
+                    .doit()
+                    .await
+                    .map_err(Error::CloudTasks)?;
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn update_queue_config(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+        fields: &[&str],
+    ) -> Result<(), NimbusError> {
+        let name = queue.to_string();
+        with_context(|| ErrorContext::new("update_queue_config", &name), async {
+            let mask: Vec<&str> = fields.iter().copied().filter(|f| *f != "state").collect();
+            if !mask.is_empty() {
+                self.projects()
+                    .locations_queues_patch(config.to_api(queue), &name)
+                    .update_mask(FieldMask::from_str(&mask.join(",")).expect("infallible"))
+                    .doit()
+                    .await
+                    .map_err(Error::CloudTasks)?;
+            }
+
+            if fields.contains(&"state") {
+                match config.state {
+                    Some(QueueState::Paused) => {
+                        self.projects()
+                            .locations_queues_pause(PauseQueueRequest::default(), &name)
+                            .doit()
+                            .await
+                            .map_err(Error::CloudTasks)?;
+                    }
+                    Some(QueueState::Running) => {
+                        self.projects()
+                            .locations_queues_resume(ResumeQueueRequest::default(), &name)
+                            .doit()
+                            .await
+                            .map_err(Error::CloudTasks)?;
+                    }
+                    _ => config.validate()?,
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn test_permissions(
+        &self,
+        queue: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        with_context(|| ErrorContext::new("test_permissions", queue), async {
+            let rq = TestIamPermissionsRequest {
+                permissions: Some(permissions.iter().map(|p| p.to_string()).collect()),
+            };
+
+            let (_, res) = self
+                .projects()
+                .locations_queues_test_iam_permissions(rq, queue)
+                .doit()
+                .await
+                .map_err(Error::CloudTasks)?;
+
+            Ok(res.permissions.unwrap_or_default())
+        })
+        .await
     }
 }
 
@@ -137,7 +1120,7 @@ impl CloudTaskHelper<HttpsConnector<HttpConnector>> for CloudTasks<HttpsConnecto
 mod tests {
     use google_auth_helper::helper::AuthHelper;
 
-    use super::{Authenticator, CloudTaskHelper, CloudTasks, HashMap, Task, Utc};
+    use super::{Authenticator, CloudTaskHelper, CloudTasks, HashMap, Task, TaskExt, Utc};
 
     #[tokio::test]
     async fn test_new_http_task() {
@@ -153,14 +1136,8 @@ mod tests {
             None,
         );
 
-        assert_eq!(
-            task.clone().http_request.unwrap().url.unwrap(),
-            "https://example.com"
-        );
-        assert_eq!(
-            task.clone().http_request.unwrap().http_method.unwrap(),
-            "POST"
-        );
+        assert_eq!(task.url(), Some("https://example.com"));
+        assert_eq!(task.method(), Some("POST"));
         assert_eq!(task.clone().name.unwrap(), "test");
         assert_eq!(task.clone().schedule_time.unwrap(), date);
     }
@@ -211,6 +1188,73 @@ mod tests {
         assert_eq!(res.status(), 200);
     }
 
+    #[tokio::test]
+    async fn cloud_task_wait_for_completion() {
+        use super::TaskHelper;
+        let auth = Authenticator::auth().await.unwrap();
+        let client = CloudTasks::new_with_authenticator(auth).await;
+
+        let queue = std::env::var("QUEUE").unwrap();
+        let task = Task::new_task(
+            "https://jsonplaceholder.typicode.com/posts/1",
+            "GET",
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let (_, task) = client.push_task(&queue, task, None).await.unwrap();
+        let outcome = client
+            .wait_for_task_completion(&task.name.unwrap(), std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(outcome.is_success());
+    }
+
+    #[tokio::test]
+    async fn cloud_task_push_task_ref() {
+        use super::{TaskHelper, TaskOverrides, TaskView};
+        let auth = Authenticator::auth().await.unwrap();
+        let client = CloudTasks::new_with_authenticator(auth).await;
+
+        let queue = std::env::var("QUEUE").unwrap();
+        let template = Task::new_task(
+            "https://jsonplaceholder.typicode.com/posts",
+            "POST",
+            Some(b"{\"title\": \"template\"}".to_vec()),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        for i in 0..3 {
+            let overrides = TaskOverrides::new().header("X-Push", i.to_string());
+            let (res, task) = client
+                .push_task_ref(&queue, &template, overrides, Some(TaskView::Full))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+
+            assert_eq!(task.header("X-Push"), Some(&*i.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn cloud_task_test_permissions() {
+        let auth = Authenticator::auth().await.unwrap();
+        let client = CloudTasks::new_with_authenticator(auth).await;
+
+        let queue = std::env::var("QUEUE").unwrap();
+        let held = client
+            .test_permissions(&queue, &["cloudtasks.tasks.create"])
+            .await
+            .unwrap();
+        assert!(held.len() <= 1);
+    }
+
     #[tokio::test]
     async fn cloud_task_helper_push() {
         let auth = Authenticator::auth().await.unwrap();
@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use google_cloudtasks2::api::Task;
+
+use super::TaskView;
+
+/// Differences of schedule time below this are the precision of the API, not a delay
+pub const SCHEDULE_TOLERANCE: Duration = Duration::from_millis(1);
+
+/// What the server changed of a pushed task, from [`analyze_push`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PushAnalysis {
+    /// how much later than requested the task is scheduled, a sign of backpressure
+    /// `None` when on time, or when the returned task has no schedule time to compare
+    pub schedule_delayed_by: Option<Duration>,
+    /// the request had no name and the queue generated one
+    pub name_assigned_by_server: bool,
+    /// view of the returned task, `None` if the server didn't say
+    pub response_view: Option<TaskView>,
+}
+
+impl PushAnalysis {
+    pub fn is_delayed(&self) -> bool {
+        self.schedule_delayed_by.is_some()
+    }
+}
+
+/// compare a task as pushed with the task the server returned for it
+///
+/// The task is expected at its requested schedule time, or at its creation when it asked for none
+/// or for a time already past. Being scheduled later than that by more than [`SCHEDULE_TOLERANCE`]
+/// is a delay. Missing fields on either side are no delay.
+pub fn analyze_push(requested: &Task, returned: &Task) -> PushAnalysis {
+    let expected = latest(requested.schedule_time, returned.create_time);
+    let schedule_delayed_by = match (expected, returned.schedule_time) {
+        (Some(expected), Some(actual)) => (actual - expected)
+            .to_std()
+            .ok()
+            .filter(|delay| *delay > SCHEDULE_TOLERANCE),
+        _ => None,
+    };
+
+    let named = |task: &Task| task.name.as_deref().is_some_and(|n| !n.is_empty());
+
+    PushAnalysis {
+        schedule_delayed_by,
+        name_assigned_by_server: !named(requested) && named(returned),
+        response_view: returned.view.as_deref().and_then(|v| v.parse().ok()),
+    }
+}
+
+fn latest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: i64, nanos: u32) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(1_710_072_000_000 + millis).unwrap()
+            + chrono::Duration::nanoseconds(nanos.into())
+    }
+
+    fn task(
+        name: Option<&str>,
+        schedule_time: Option<DateTime<Utc>>,
+        create_time: Option<DateTime<Utc>>,
+    ) -> Task {
+        Task {
+            name: name.map(str::to_owned),
+            schedule_time,
+            create_time,
+            ..Default::default()
+        }
+    }
+
+    const NAME: &str = "projects/p/locations/l/queues/q/tasks/123";
+
+    #[test]
+    fn delayed_test() {
+        // scheduled in the future, moved later
+        let analysis = analyze_push(
+            &task(None, Some(at(10_000, 0)), None),
+            &task(Some(NAME), Some(at(12_500, 0)), Some(at(0, 0))),
+        );
+        assert_eq!(
+            analysis.schedule_delayed_by,
+            Some(Duration::from_millis(2500))
+        );
+        assert!(analysis.is_delayed());
+
+        // no schedule requested, due later than its creation
+        let analysis = analyze_push(
+            &task(None, None, None),
+            &task(Some(NAME), Some(at(3_000, 0)), Some(at(0, 0))),
+        );
+        assert_eq!(analysis.schedule_delayed_by, Some(Duration::from_secs(3)));
+
+        // requested in the past, due later than its creation
+        let analysis = analyze_push(
+            &task(None, Some(at(-60_000, 0)), None),
+            &task(Some(NAME), Some(at(500, 0)), Some(at(0, 0))),
+        );
+        assert_eq!(
+            analysis.schedule_delayed_by,
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn undelayed_test() {
+        for (requested, returned) in [
+            // exactly as requested
+            (
+                task(None, Some(at(10_000, 0)), None),
+                task(Some(NAME), Some(at(10_000, 0)), Some(at(0, 0))),
+            ),
+            // the API truncated the nanoseconds
+            (
+                task(None, Some(at(10_000, 999_999)), None),
+                task(Some(NAME), Some(at(10_000, 0)), Some(at(0, 0))),
+            ),
+            // rounded up within the tolerance
+            (
+                task(None, Some(at(10_000, 1)), None),
+                task(Some(NAME), Some(at(10_001, 0)), Some(at(0, 0))),
+            ),
+            // requested in the past, due at its creation
+            (
+                task(None, Some(at(-60_000, 0)), None),
+                task(Some(NAME), Some(at(0, 0)), Some(at(0, 0))),
+            ),
+            // no schedule requested, due at its creation
+            (
+                task(None, None, None),
+                task(Some(NAME), Some(at(0, 0)), Some(at(0, 0))),
+            ),
+            // earlier than requested isn't a delay
+            (
+                task(None, Some(at(10_000, 0)), None),
+                task(Some(NAME), Some(at(9_000, 0)), None),
+            ),
+            // nothing returned to compare
+            (
+                task(None, Some(at(10_000, 0)), None),
+                task(Some(NAME), None, Some(at(0, 0))),
+            ),
+            (
+                task(None, None, None),
+                task(Some(NAME), Some(at(10_000, 0)), None),
+            ),
+            (task(None, None, None), Task::default()),
+        ] {
+            let analysis = analyze_push(&requested, &returned);
+            assert_eq!(
+                analysis.schedule_delayed_by, None,
+                "{requested:?} {returned:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn server_named_test() {
+        let returned = Task {
+            view: Some("BASIC".to_owned()),
+            ..task(Some(NAME), Some(at(0, 0)), Some(at(0, 0)))
+        };
+
+        let analysis = analyze_push(&task(None, None, None), &returned);
+        assert!(analysis.name_assigned_by_server);
+        assert_eq!(analysis.response_view, Some(TaskView::Basic));
+        // an empty name is no name
+        assert!(analyze_push(&task(Some(""), None, None), &returned).name_assigned_by_server);
+
+        let analysis = analyze_push(&task(Some(NAME), None, None), &returned);
+        assert!(!analysis.name_assigned_by_server);
+
+        // nothing returned
+        assert_eq!(
+            analyze_push(&task(None, None, None), &Task::default()),
+            PushAnalysis::default()
+        );
+        let unknown_view = Task {
+            view: Some("VIEW_UNSPECIFIED".to_owned()),
+            ..Task::default()
+        };
+        assert_eq!(
+            analyze_push(&Task::default(), &unknown_view).response_view,
+            None
+        );
+    }
+}
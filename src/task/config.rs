@@ -0,0 +1,404 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Error, QueuePath, QueueState};
+use crate::report::{self, Report};
+
+/// Settings of a queue that can be kept as code, from [`super::CloudTaskHelper::export_queue_config`]
+/// and restored with [`super::CloudTaskHelper::apply_queue_config`]
+///
+/// Durations serialize as in the Cloud Tasks API, e.g. `"0.100s"`. A field left out (`None`) is not
+/// managed: it is neither compared nor changed when applying the snapshot, and a created queue gets
+/// the Cloud Tasks default. Output only settings, like `max_burst_size`, are not part of the snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueConfigSnapshot {
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// fraction of the task operations logged to Cloud Logging, 0 to 1
+    #[serde(default)]
+    pub logging_sampling_ratio: Option<f64>,
+    /// only `RUNNING` and `PAUSED` can be applied
+    #[serde(default)]
+    pub state: Option<QueueState>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitsConfig {
+    #[serde(default)]
+    pub max_dispatches_per_second: Option<f64>,
+    #[serde(default)]
+    pub max_concurrent_dispatches: Option<i32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// -1 for unlimited attempts
+    #[serde(default)]
+    pub max_attempts: Option<i32>,
+    #[serde(default, with = "api_duration")]
+    pub max_retry_duration: Option<Duration>,
+    #[serde(default, with = "api_duration")]
+    pub min_backoff: Option<Duration>,
+    #[serde(default, with = "api_duration")]
+    pub max_backoff: Option<Duration>,
+    #[serde(default)]
+    pub max_doublings: Option<i32>,
+}
+
+/// the queue settings compared and applied, by update mask path
+pub(crate) const FIELDS: [&str; 9] = [
+    "rate_limits.max_dispatches_per_second",
+    "rate_limits.max_concurrent_dispatches",
+    "retry_config.max_attempts",
+    "retry_config.max_retry_duration",
+    "retry_config.min_backoff",
+    "retry_config.max_backoff",
+    "retry_config.max_doublings",
+    "stackdriver_logging_config.sampling_ratio",
+    "state",
+];
+
+impl QueueConfigSnapshot {
+    /// `field` of [`FIELDS`] as shown in an [`ApplyReport`], `None` if not managed
+    fn value(&self, field: &str) -> Option<String> {
+        let rate = &self.rate_limits;
+        let retry = &self.retry;
+        match field {
+            "rate_limits.max_dispatches_per_second" => {
+                rate.max_dispatches_per_second.map(|v| v.to_string())
+            }
+            "rate_limits.max_concurrent_dispatches" => {
+                rate.max_concurrent_dispatches.map(|v| v.to_string())
+            }
+            "retry_config.max_attempts" => retry.max_attempts.map(|v| v.to_string()),
+            "retry_config.max_retry_duration" => retry.max_retry_duration.map(format_duration),
+            "retry_config.min_backoff" => retry.min_backoff.map(format_duration),
+            "retry_config.max_backoff" => retry.max_backoff.map(format_duration),
+            "retry_config.max_doublings" => retry.max_doublings.map(|v| v.to_string()),
+            "stackdriver_logging_config.sampling_ratio" => {
+                self.logging_sampling_ratio.map(|v| v.to_string())
+            }
+            "state" => self.state.map(|s| s.as_api().to_owned()),
+            _ => None,
+        }
+    }
+
+    /// copy `field` of [`FIELDS`] from `other`
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn set(&mut self, other: &Self, field: &str) {
+        match field {
+            "rate_limits.max_dispatches_per_second" => {
+                self.rate_limits.max_dispatches_per_second =
+                    other.rate_limits.max_dispatches_per_second
+            }
+            "rate_limits.max_concurrent_dispatches" => {
+                self.rate_limits.max_concurrent_dispatches =
+                    other.rate_limits.max_concurrent_dispatches
+            }
+            "retry_config.max_attempts" => self.retry.max_attempts = other.retry.max_attempts,
+            "retry_config.max_retry_duration" => {
+                self.retry.max_retry_duration = other.retry.max_retry_duration
+            }
+            "retry_config.min_backoff" => self.retry.min_backoff = other.retry.min_backoff,
+            "retry_config.max_backoff" => self.retry.max_backoff = other.retry.max_backoff,
+            "retry_config.max_doublings" => self.retry.max_doublings = other.retry.max_doublings,
+            "stackdriver_logging_config.sampling_ratio" => {
+                self.logging_sampling_ratio = other.logging_sampling_ratio
+            }
+            "state" => self.state = other.state,
+            _ => {}
+        }
+    }
+
+    /// fail on settings that can't be applied
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        match self.state {
+            None | Some(QueueState::Running) | Some(QueueState::Paused) => Ok(()),
+            Some(state) => Err(Error::InvalidQueueConfig(format!(
+                "state {} can't be applied, only RUNNING or PAUSED",
+                state.as_api()
+            ))),
+        }
+    }
+
+    /// settings of `self` that `current` doesn't have, the unmanaged ones left out
+    pub(crate) fn diff(&self, current: &Self) -> Vec<ConfigChange> {
+        FIELDS
+            .iter()
+            .filter_map(|field| {
+                let desired = self.value(field)?;
+                let current = current.value(field);
+                (current.as_deref() != Some(desired.as_str())).then_some(ConfigChange {
+                    field,
+                    current,
+                    desired,
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn from_api(queue: &google_cloudtasks2::api::Queue) -> Self {
+        let rate = queue.rate_limits.as_ref();
+        let retry = queue.retry_config.as_ref();
+
+        Self {
+            rate_limits: RateLimitsConfig {
+                max_dispatches_per_second: rate.and_then(|r| r.max_dispatches_per_second),
+                max_concurrent_dispatches: rate.and_then(|r| r.max_concurrent_dispatches),
+            },
+            retry: RetryConfig {
+                max_attempts: retry.and_then(|r| r.max_attempts),
+                max_retry_duration: retry.and_then(|r| from_chrono(r.max_retry_duration)),
+                min_backoff: retry.and_then(|r| from_chrono(r.min_backoff)),
+                max_backoff: retry.and_then(|r| from_chrono(r.max_backoff)),
+                max_doublings: retry.and_then(|r| r.max_doublings),
+            },
+            logging_sampling_ratio: queue
+                .stackdriver_logging_config
+                .as_ref()
+                .and_then(|c| c.sampling_ratio),
+            state: Some(QueueState::from_api(queue.state.as_deref())),
+        }
+    }
+
+    /// the queue `name` with these settings, the state is left to pause and resume
+    pub(crate) fn to_api(&self, name: &QueuePath) -> google_cloudtasks2::api::Queue {
+        use google_cloudtasks2::api;
+
+        api::Queue {
+            name: Some(name.to_string()),
+            rate_limits: Some(api::RateLimits {
+                max_dispatches_per_second: self.rate_limits.max_dispatches_per_second,
+                max_concurrent_dispatches: self.rate_limits.max_concurrent_dispatches,
+                ..Default::default()
+            }),
+            retry_config: Some(api::RetryConfig {
+                max_attempts: self.retry.max_attempts,
+                max_retry_duration: self.retry.max_retry_duration.and_then(to_chrono),
+                min_backoff: self.retry.min_backoff.and_then(to_chrono),
+                max_backoff: self.retry.max_backoff.and_then(to_chrono),
+                max_doublings: self.retry.max_doublings,
+            }),
+            stackdriver_logging_config: self.logging_sampling_ratio.map(|ratio| {
+                api::StackdriverLoggingConfig {
+                    sampling_ratio: Some(ratio),
+                }
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+fn from_chrono(duration: Option<chrono::Duration>) -> Option<Duration> {
+    duration?.to_std().ok()
+}
+
+fn to_chrono(duration: Duration) -> Option<chrono::Duration> {
+    chrono::Duration::from_std(duration).ok()
+}
+
+/// duration in the format of the Cloud Tasks API: seconds with up to 9 decimals and an `s` suffix
+fn format_duration(duration: Duration) -> String {
+    match duration.subsec_nanos() {
+        0 => format!("{}s", duration.as_secs()),
+        nanos => {
+            let fraction = format!("{nanos:09}");
+            format!("{}.{}s", duration.as_secs(), fraction.trim_end_matches('0'))
+        }
+    }
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.strip_suffix('s')?;
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        format!("{fraction:0<9}").parse().ok()?
+    };
+
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+mod api_duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        value.map(format_duration).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        match Option::<String>::deserialize(d)? {
+            None => Ok(None),
+            Some(value) => parse_duration(&value).map(Some).ok_or_else(|| {
+                serde::de::Error::custom(format!("invalid duration {value:?}, expected e.g. 0.5s"))
+            }),
+        }
+    }
+}
+
+/// Setting of a queue that differs from the snapshot applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ConfigChange {
+    /// update mask path, e.g. `retry_config.max_attempts`
+    pub field: &'static str,
+    /// `None` for a queue created by the apply
+    pub current: Option<String>,
+    pub desired: String,
+}
+
+/// Outcome of [`super::CloudTaskHelper::apply_queue_config`] and [`super::CloudTaskHelper::plan_queue_config`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ApplyReport {
+    pub queue: QueuePath,
+    /// the queue was missing, and is (or would be on a dry run) created
+    pub created: bool,
+    pub changes: Vec<ConfigChange>,
+    /// only planned, nothing was changed
+    pub dry_run: bool,
+}
+
+impl ApplyReport {
+    /// true if the queue already matched the snapshot
+    pub fn is_noop(&self) -> bool {
+        !self.created && self.changes.is_empty()
+    }
+}
+
+impl Report for ApplyReport {
+    fn summary_line(&self) -> String {
+        let mode = if self.dry_run { " (dry run)" } else { "" };
+        let action = if self.created { "created" } else { "updated" };
+        format!(
+            "apply queue config{mode}: {} {action}, {} changed",
+            self.queue,
+            self.changes.len()
+        )
+    }
+
+    /// differences are the point of the report, nothing fails
+    fn has_failures(&self) -> bool {
+        false
+    }
+}
+
+impl fmt::Display for ApplyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary_line())?;
+        report::write_section(
+            f,
+            "changes",
+            self.changes.iter().map(|c| {
+                let current = c.current.as_deref().unwrap_or("unset");
+                format!("{}: {current} -> {}", c.field, c.desired)
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> QueueConfigSnapshot {
+        QueueConfigSnapshot {
+            rate_limits: RateLimitsConfig {
+                max_dispatches_per_second: Some(500.0),
+                max_concurrent_dispatches: Some(1000),
+            },
+            retry: RetryConfig {
+                max_attempts: Some(-1),
+                max_retry_duration: Some(Duration::ZERO),
+                min_backoff: Some(Duration::from_millis(100)),
+                max_backoff: Some(Duration::from_secs(3600)),
+                max_doublings: Some(16),
+            },
+            logging_sampling_ratio: Some(0.5),
+            state: Some(QueueState::Running),
+        }
+    }
+
+    #[test]
+    fn duration_test() {
+        for (duration, text) in [
+            (Duration::ZERO, "0s"),
+            (Duration::from_millis(100), "0.1s"),
+            (Duration::new(3600, 5), "3600.000000005s"),
+        ] {
+            assert_eq!(format_duration(duration), text);
+            assert_eq!(parse_duration(text), Some(duration));
+        }
+        assert_eq!(parse_duration("0.100s"), Some(Duration::from_millis(100)));
+        for invalid in ["1", "s", "1.s5", "0.0000000001s", "-1s"] {
+            assert_eq!(parse_duration(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn snapshot_json_test() {
+        let snapshot = snapshot();
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["retry"]["min_backoff"], "0.1s");
+        assert_eq!(json["state"], "RUNNING");
+
+        let parsed: QueueConfigSnapshot = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, snapshot);
+
+        // settings left out are not managed
+        let partial: QueueConfigSnapshot =
+            serde_json::from_str(r#"{"retry": {"max_attempts": 5}}"#).unwrap();
+        assert_eq!(partial.retry.max_attempts, Some(5));
+        assert_eq!(partial.state, None);
+    }
+
+    #[test]
+    fn diff_test() {
+        let current = snapshot();
+        assert!(current.diff(&current).is_empty());
+
+        let api = current.to_api(&QueuePath::new("p", "l", "q"));
+        let mut exported = QueueConfigSnapshot::from_api(&api);
+        exported.state = current.state;
+        assert_eq!(exported, current);
+
+        let desired = QueueConfigSnapshot {
+            retry: RetryConfig {
+                max_attempts: Some(5),
+                min_backoff: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+            state: Some(QueueState::Paused),
+            ..Default::default()
+        };
+        assert_eq!(
+            desired.diff(&current),
+            [
+                ConfigChange {
+                    field: "retry_config.max_attempts",
+                    current: Some("-1".to_owned()),
+                    desired: "5".to_owned(),
+                },
+                ConfigChange {
+                    field: "state",
+                    current: Some("RUNNING".to_owned()),
+                    desired: "PAUSED".to_owned(),
+                },
+            ]
+        );
+
+        let disabled = QueueConfigSnapshot {
+            state: Some(QueueState::Disabled),
+            ..Default::default()
+        };
+        assert!(disabled.validate().is_err());
+    }
+}
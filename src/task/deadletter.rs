@@ -0,0 +1,338 @@
+//! Dead letters: payloads of tasks that failed for good, kept in a bucket to be inspected and replayed
+//!
+//! Cloud Tasks has no dead-letter queue, a task is dropped once its handler gives up (answers 2xx)
+//! or its queue runs out of attempts. A handler giving up records the task with [`DeadLetterSink::record`]
+//! first, the record can be pushed again later with [`DeadLetterSink::replay`].
+//!
+//! ```ignore
+//! let sink = DeadLetterSink::new(&storage, "ops", "dead-letters/")
+//!     .target("https://worker.example.com/jobs", "POST");
+//! if info.should_give_up(5) {
+//!     sink.record(&info, &body, &error.to_string()).await?;
+//!     return StatusCode::OK;
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use google_cloudtasks2::api::{OidcToken, Task};
+use serde::{Deserialize, Serialize};
+
+use super::incoming::TaskRequestInfo;
+use super::{CloudTaskHelper, Error, QueuePath, TaskHelper};
+use crate::storage::PartitionScheme;
+use crate::{ListLimits, NimbusError, StorageHelper};
+
+const RECORD_MIME: &str = "application/json";
+
+/// A task recorded by [`DeadLetterSink::record`], stored as one JSON object with the body in base64
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// short names, as in [`TaskRequestInfo`]
+    pub queue_name: String,
+    pub task_name: String,
+    pub retry_count: u32,
+    pub execution_count: u32,
+    pub recorded_at: DateTime<Utc>,
+    pub error: String,
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+    /// request replayed by [`DeadLetterSink::replay`], `None` if the sink had no target
+    pub url: Option<String>,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+mod base64_body {
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let b64 = String::deserialize(deserializer)?;
+        base64::engine::general_purpose::STANDARD
+            .decode(b64)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Writes dead letters under `{prefix}YYYY/MM/DD/HH/` of a bucket, see the [module](self) docs
+///
+/// The key of a record is `{queue}.{task}.{execution count}.json` within the hour it was recorded,
+/// a handler recording the same attempt twice overwrites its first record.
+pub struct DeadLetterSink<'a, S: StorageHelper + Sync> {
+    storage: &'a S,
+    bucket: String,
+    prefix: String,
+    scheme: PartitionScheme,
+    url: Option<String>,
+    method: Option<String>,
+    headers: HashMap<String, String>,
+    oidc_token: Option<OidcToken>,
+}
+
+impl<'a, S: StorageHelper + Sync> DeadLetterSink<'a, S> {
+    pub fn new(storage: &'a S, bucket: &str, prefix: &str) -> Self {
+        Self {
+            storage,
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            scheme: PartitionScheme::hourly(prefix),
+            url: None,
+            method: None,
+            headers: HashMap::new(),
+            oidc_token: None,
+        }
+    }
+
+    /// request the recorded tasks are replayed to, usually the url and method of the handler recording them
+    pub fn target(mut self, url: impl Into<String>, method: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self.method = Some(method.into());
+        self
+    }
+
+    /// header recorded along with the body, e.g. `Content-Type`
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// token replayed tasks authenticate to the target with, it isn't recorded
+    pub fn oidc_token(mut self, token: OidcToken) -> Self {
+        self.oidc_token = Some(token);
+        self
+    }
+
+    pub fn scheme(&self) -> &PartitionScheme {
+        &self.scheme
+    }
+
+    /// store the task being dispatched with its body and the error it failed with, returns the key of the record
+    pub async fn record(
+        &self,
+        info: &TaskRequestInfo,
+        body: &[u8],
+        error: &str,
+    ) -> Result<String, NimbusError> {
+        let letter = DeadLetter {
+            queue_name: info.queue_name.clone(),
+            task_name: info.task_name.clone(),
+            retry_count: info.retry_count,
+            execution_count: info.execution_count,
+            recorded_at: Utc::now(),
+            error: error.to_owned(),
+            body: body.to_vec(),
+            url: self.url.clone(),
+            method: self.method.clone(),
+            headers: self.headers.clone(),
+        };
+
+        let suffix = format!(
+            "{}.{}.{}.json",
+            info.queue_name, info.task_name, info.execution_count
+        );
+        let key = self.scheme.key_for(letter.recorded_at, &suffix);
+        let data = serde_json::to_vec(&letter).expect("dead letter serializes");
+        self.storage
+            .upload_from_bytes(&self.bucket, &key, Some(RECORD_MIME.to_owned()), data)
+            .await?;
+
+        Ok(key)
+    }
+
+    /// the record stored under `key`
+    pub async fn read(&self, key: &str) -> Result<DeadLetter, NimbusError> {
+        let data = self.storage.download_to_bytes(&self.bucket, key).await?;
+        serde_json::from_slice(&data)
+            .map_err(|e| Error::Other(format!("Invalid dead letter {key}: {e}")).into())
+    }
+
+    /// push the request of the record under `key` to `queue` as a new task, with the oidc token of the sink
+    /// the record is kept, delete it with [`DeadLetterSink::delete`] once the replay is known to be handled
+    pub async fn replay<C>(
+        &self,
+        helper: &(impl CloudTaskHelper<C> + Sync),
+        key: &str,
+        queue: &QueuePath,
+    ) -> Result<Task, NimbusError> {
+        let letter = self.read(key).await?;
+        let (Some(url), Some(method)) = (letter.url, letter.method) else {
+            return Err(
+                Error::Other(format!("Dead letter {key} has no target to replay to")).into(),
+            );
+        };
+
+        let headers = (!letter.headers.is_empty()).then_some(letter.headers);
+        let task = Task::new_task(
+            &url,
+            &method,
+            Some(letter.body),
+            headers,
+            None,
+            None,
+            self.oidc_token.clone(),
+        );
+        let (_, task) = helper.push_task(&queue.to_string(), task, None).await?;
+
+        Ok(task)
+    }
+
+    /// keys of the records of `[from, to)`, in order, see [`StorageHelper::list_objects_in_range`]
+    pub async fn list(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        self.storage
+            .list_objects_in_range(&self.bucket, &self.scheme, from, to, limits)
+            .await
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), NimbusError> {
+        self.storage.delete_file(&self.bucket, key).await
+    }
+
+    /// delete the records of the hours before the one of `before`, returns how many were deleted
+    /// the whole prefix is listed, objects not laid out like records are left alone
+    pub async fn purge_before(&self, before: DateTime<Utc>) -> Result<u64, NimbusError> {
+        let end = self.scheme.prefix_for(before);
+        let mut listing = std::pin::pin!(self
+            .storage
+            .list(&self.bucket)
+            .prefix(&self.prefix)
+            .stream());
+
+        let mut deleted = 0;
+        while let Some(meta) = listing.try_next().await? {
+            if self.scheme.partition_of(&meta.key).is_some() && meta.key < end {
+                self.delete(&meta.key).await?;
+                deleted += 1;
+            }
+        }
+
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::task::TaskExt;
+    use crate::testing::{MemoryCloudTasks, MemoryStorage};
+
+    fn info(task: &str, execution_count: u32) -> TaskRequestInfo {
+        TaskRequestInfo {
+            queue_name: "jobs".to_owned(),
+            task_name: task.to_owned(),
+            retry_count: execution_count + 1,
+            execution_count,
+            eta: Utc.with_ymd_and_hms(2024, 3, 5, 7, 0, 0).unwrap(),
+            previous_response: Some(500),
+            retry_reason: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn record_replay_test() {
+        let storage = MemoryStorage::new();
+        let tasks = MemoryCloudTasks::new();
+        let sink = DeadLetterSink::new(&storage, "ops", "dead/")
+            .target("https://worker.example.com/jobs", "POST")
+            .header("Content-Type", "application/octet-stream");
+        // not valid UTF-8
+        let body: Vec<u8> = (0..=255).collect();
+
+        let key = sink
+            .record(&info("t-1", 4), &body, "schema mismatch")
+            .await
+            .unwrap();
+        assert!(key.starts_with("dead/"));
+        assert!(key.ends_with("/jobs.t-1.4.json"));
+
+        let letter = sink.read(&key).await.unwrap();
+        assert_eq!(letter.task_name, "t-1");
+        assert_eq!(letter.retry_count, 5);
+        assert_eq!(letter.error, "schema mismatch");
+        assert_eq!(letter.body, body);
+
+        let raw = storage.download_to_bytes("ops", &key).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(
+            json["body"],
+            base64::engine::general_purpose::STANDARD.encode(&body)
+        );
+
+        let queue = QueuePath::new("p", "l", "retry");
+        let task = sink.replay(&tasks, &key, &queue).await.unwrap();
+        assert!(task
+            .name
+            .as_deref()
+            .is_some_and(|name| name.starts_with(&format!("{queue}/tasks/"))));
+        assert_eq!(task.body_bytes(), Some(&body[..]));
+        assert_eq!(task.url(), Some("https://worker.example.com/jobs"));
+        assert_eq!(task.method(), Some("POST"));
+        assert_eq!(
+            task.header("Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(tasks.len(&queue.to_string()), 1);
+    }
+
+    #[tokio::test]
+    async fn replay_without_target_test() {
+        let storage = MemoryStorage::new();
+        let sink = DeadLetterSink::new(&storage, "ops", "dead");
+
+        let key = sink.record(&info("t-1", 0), b"{}", "boom").await.unwrap();
+        let err = sink
+            .replay(
+                &MemoryCloudTasks::new(),
+                &key,
+                &QueuePath::new("p", "l", "q"),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no target"));
+    }
+
+    #[tokio::test]
+    async fn list_purge_test() {
+        let storage = MemoryStorage::new();
+        let sink = DeadLetterSink::new(&storage, "ops", "dead/");
+        let old = sink
+            .scheme()
+            .key_for(Utc.with_ymd_and_hms(2024, 1, 1, 3, 0, 0).unwrap(), "a.json");
+        storage
+            .upload_from_bytes("ops", &old, None, b"{}".to_vec())
+            .await
+            .unwrap();
+        let recent = sink.record(&info("t-2", 1), b"x", "boom").await.unwrap();
+
+        let from = Utc.with_ymd_and_hms(2023, 12, 1, 0, 0, 0).unwrap();
+        let all = sink
+            .list(
+                from,
+                Utc::now() + chrono::Duration::hours(1),
+                ListLimits::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(all, [old.clone(), recent.clone()]);
+
+        let deleted = sink.purge_before(Utc::now()).await.unwrap();
+        assert_eq!(deleted, 1);
+        assert!(!storage.object_exists("ops", &old).await.unwrap());
+        assert!(storage.object_exists("ops", &recent).await.unwrap());
+    }
+}
@@ -0,0 +1,302 @@
+//! Versioned task payloads, decoded whatever the version of the code that pushed them
+//!
+//! During a rolling deploy a handler receives bodies from the previous and the current producer.
+//! Each body is an [`Envelope`] carrying the schema version of its payload, and the handler registers
+//! in a [`VersionMap`] how each version it still accepts becomes the current type:
+//!
+//! ```ignore
+//! let decoders = VersionMap::new()
+//!     .upgrade(1, |v1: JobV1| JobV3::from(JobV2::from(v1)))
+//!     .upgrade(2, |v2: JobV2| JobV3::from(v2))
+//!     .current(3)
+//!     .min_supported_version(1);
+//!
+//! let job = match Envelope::decode_versioned(&body, &decoders) {
+//!     Ok(job) => job,
+//!     // retrying won't help, drop the task
+//!     Err(e) if e.code() == ErrorCode::InvalidInput => return StatusCode::OK,
+//!     Err(_) => return StatusCode::SERVICE_UNAVAILABLE,
+//! };
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::Error;
+use crate::redact::json_error;
+
+type Decoder<T> = Box<dyn Fn(Value) -> Result<T, serde_json::Error> + Send + Sync>;
+
+/// Body of a versioned task: `{"version": 3, "payload": {...}}`, with a `"kind"` when pushed by kind
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// schema version of the payload, set by the producer
+    pub version: u32,
+    /// task kind the payload was pushed as, see [`super::TaskKindRegistry`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    pub payload: Value,
+}
+
+impl Envelope {
+    pub fn new<P: Serialize>(version: u32, payload: &P) -> Result<Self, Error> {
+        let payload = serde_json::to_value(payload).map_err(|e| {
+            Error::InvalidPayload(format!("payload doesn't serialize: {}", json_error(&e)))
+        })?;
+        Ok(Self {
+            version,
+            kind: None,
+            payload,
+        })
+    }
+
+    /// stamp the envelope with the task kind of its payload
+    pub fn kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind = Some(kind.into());
+        self
+    }
+
+    /// JSON body of a task
+    pub fn to_body(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("envelope serializes")
+    }
+
+    /// the envelope of a task body, without decoding its payload
+    pub fn parse(body: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(body)
+            .map_err(|e| Error::InvalidPayload(format!("invalid envelope: {}", json_error(&e))))
+    }
+
+    /// the payload of a task body as the current type, upgraded by the decoder of its version
+    ///
+    /// Fails with [`Error::UnsupportedVersion`] below [`VersionMap::min_supported_version`],
+    /// [`Error::UnknownVersion`] for a version without decoder (e.g. from a newer producer)
+    /// and [`Error::InvalidPayload`] for a body that doesn't decode.
+    pub fn decode_versioned<T: 'static>(body: &[u8], decoders: &VersionMap<T>) -> Result<T, Error> {
+        let envelope = Self::parse(body)?;
+        decoders.decode(envelope.version, envelope.payload)
+    }
+}
+
+/// Decoders of the payload versions a handler accepts, see the [module](self) docs
+pub struct VersionMap<T> {
+    decoders: BTreeMap<u32, Decoder<T>>,
+    min_supported: u32,
+}
+
+impl<T> Default for VersionMap<T> {
+    fn default() -> Self {
+        Self {
+            decoders: BTreeMap::new(),
+            min_supported: 0,
+        }
+    }
+}
+
+impl<T> fmt::Debug for VersionMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VersionMap")
+            .field("versions", &self.decoders.keys().collect::<Vec<_>>())
+            .field("min_supported", &self.min_supported)
+            .finish()
+    }
+}
+
+impl<T: 'static> VersionMap<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// decode payloads of `version` with `decode`, replacing its previous decoder
+    pub fn version(
+        mut self,
+        version: u32,
+        decode: impl Fn(Value) -> Result<T, serde_json::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.decoders.insert(version, Box::new(decode));
+        self
+    }
+
+    /// payloads of `version` deserialize as `P`, then `migrate` turns them into the current type
+    pub fn upgrade<P: DeserializeOwned>(
+        self,
+        version: u32,
+        migrate: impl Fn(P) -> T + Send + Sync + 'static,
+    ) -> Self {
+        self.version(version, move |payload| {
+            serde_json::from_value(payload).map(&migrate)
+        })
+    }
+
+    /// payloads of `version` deserialize as the current type
+    pub fn current(self, version: u32) -> Self
+    where
+        T: DeserializeOwned,
+    {
+        self.version(version, serde_json::from_value)
+    }
+
+    /// versions below fail with [`Error::UnsupportedVersion`], even with a decoder
+    pub fn min_supported_version(mut self, version: u32) -> Self {
+        self.min_supported = version;
+        self
+    }
+
+    /// latest version with a decoder
+    pub fn latest(&self) -> Option<u32> {
+        self.decoders.keys().next_back().copied()
+    }
+
+    /// `payload` of `version` as the current type, see [`Envelope::decode_versioned`]
+    pub fn decode(&self, version: u32, payload: Value) -> Result<T, Error> {
+        if version < self.min_supported {
+            return Err(Error::UnsupportedVersion {
+                version,
+                min_supported: self.min_supported,
+            });
+        }
+
+        let decode = self.decoders.get(&version).ok_or(Error::UnknownVersion {
+            version,
+            latest: self.latest(),
+        })?;
+        decode(payload).map_err(|e| {
+            Error::InvalidPayload(format!("payload of version {version}: {}", json_error(&e)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::ErrorCode;
+
+    #[derive(Deserialize)]
+    struct JobV1 {
+        user: String,
+    }
+
+    #[derive(Deserialize)]
+    struct JobV2 {
+        user_id: String,
+        priority: u8,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct JobV3 {
+        user_id: String,
+        priority: u8,
+        tags: Vec<String>,
+    }
+
+    impl From<JobV1> for JobV2 {
+        fn from(v1: JobV1) -> Self {
+            Self {
+                user_id: v1.user,
+                priority: 5,
+            }
+        }
+    }
+
+    impl From<JobV2> for JobV3 {
+        fn from(v2: JobV2) -> Self {
+            Self {
+                user_id: v2.user_id,
+                priority: v2.priority,
+                tags: vec![],
+            }
+        }
+    }
+
+    fn decoders() -> VersionMap<JobV3> {
+        VersionMap::new()
+            .upgrade(1, |v1: JobV1| JobV3::from(JobV2::from(v1)))
+            .upgrade(2, |v2: JobV2| JobV3::from(v2))
+            .current(3)
+    }
+
+    fn body(version: u32, payload: Value) -> Vec<u8> {
+        Envelope {
+            version,
+            kind: None,
+            payload,
+        }
+        .to_body()
+    }
+
+    #[test]
+    fn upgrade_chain_test() {
+        let decoders = decoders();
+        let expected = |priority| JobV3 {
+            user_id: "u-1".to_owned(),
+            priority,
+            tags: vec![],
+        };
+
+        let v1 = body(1, json!({ "user": "u-1" }));
+        assert_eq!(
+            Envelope::decode_versioned(&v1, &decoders).unwrap(),
+            expected(5)
+        );
+        let v2 = body(2, json!({ "user_id": "u-1", "priority": 9 }));
+        assert_eq!(
+            Envelope::decode_versioned(&v2, &decoders).unwrap(),
+            expected(9)
+        );
+        let current = Envelope::new(3, &expected(1)).unwrap().to_body();
+        assert_eq!(
+            Envelope::decode_versioned(&current, &decoders).unwrap(),
+            expected(1)
+        );
+    }
+
+    #[test]
+    fn unsupported_version_test() {
+        let decoders = decoders().min_supported_version(2);
+
+        let err =
+            Envelope::decode_versioned(&body(1, json!({ "user": "u-1" })), &decoders).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnsupportedVersion {
+                version: 1,
+                min_supported: 2
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+
+        // from a newer producer, the handler may be deployed by the next attempt
+        let err = Envelope::decode_versioned(&body(4, json!({})), &decoders).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnknownVersion {
+                version: 4,
+                latest: Some(3)
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert!(err.to_string().contains('4'));
+    }
+
+    #[test]
+    fn corrupted_payload_test() {
+        let decoders = decoders();
+        for corrupted in [
+            b"{\"version\": 3, \"payload\": {".to_vec(),
+            b"not json".to_vec(),
+            br#"{"payload": {}}"#.to_vec(),
+            body(3, json!({ "user_id": 7 })),
+            body(1, json!(["u-1"])),
+        ] {
+            let err = Envelope::decode_versioned(&corrupted, &decoders).unwrap_err();
+            assert!(matches!(err, Error::InvalidPayload(_)), "{err}");
+            assert_eq!(err.code(), ErrorCode::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use google_cloudtasks2::api::Task;
+
+/// Borrowing accessors of the request of a [`Task`], HTTP or App Engine
+///
+/// Prefer them to chains like `task.clone().http_request.unwrap().url.unwrap()`: a task read with
+/// the BASIC view, an App Engine task or one built by hand may lack any of these fields, and the
+/// unwraps then panic where the accessors return `None`. Nothing is cloned.
+pub trait TaskExt {
+    /// URL of an HTTP task, App Engine tasks only have a relative URI
+    fn url(&self) -> Option<&str>;
+
+    fn method(&self) -> Option<&str>;
+
+    /// the request body, decoded: the API's base64 form never reaches the generated types
+    fn body_bytes(&self) -> Option<&[u8]>;
+
+    fn headers(&self) -> Option<&HashMap<String, String>>;
+
+    /// value of the header `name`, whatever its case
+    fn header(&self, name: &str) -> Option<&str>;
+
+    fn schedule(&self) -> Option<DateTime<Utc>>;
+}
+
+impl TaskExt for Task {
+    fn url(&self) -> Option<&str> {
+        self.http_request.as_ref()?.url.as_deref()
+    }
+
+    fn method(&self) -> Option<&str> {
+        match (&self.http_request, &self.app_engine_http_request) {
+            (Some(r), _) => r.http_method.as_deref(),
+            (None, Some(r)) => r.http_method.as_deref(),
+            (None, None) => None,
+        }
+    }
+
+    fn body_bytes(&self) -> Option<&[u8]> {
+        match (&self.http_request, &self.app_engine_http_request) {
+            (Some(r), _) => r.body.as_deref(),
+            (None, Some(r)) => r.body.as_deref(),
+            (None, None) => None,
+        }
+    }
+
+    fn headers(&self) -> Option<&HashMap<String, String>> {
+        match (&self.http_request, &self.app_engine_http_request) {
+            (Some(r), _) => r.headers.as_ref(),
+            (None, Some(r)) => r.headers.as_ref(),
+            (None, None) => None,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers()?
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn schedule(&self) -> Option<DateTime<Utc>> {
+        self.schedule_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use google_cloudtasks2::api::AppEngineHttpRequest;
+
+    use super::*;
+    use crate::task::TaskHelper;
+
+    #[test]
+    fn task_ext_test() {
+        let at = Utc::now();
+        let task = Task::new_task(
+            "https://example.com/run",
+            "PUT",
+            Some(b"body".to_vec()),
+            Some([("X-Trace".to_owned(), "t-1".to_owned())].into()),
+            None,
+            Some(at),
+            None,
+        );
+        assert_eq!(task.url(), Some("https://example.com/run"));
+        assert_eq!(task.method(), Some("PUT"));
+        assert_eq!(task.body_bytes(), Some(&b"body"[..]));
+        assert_eq!(task.header("x-trace"), Some("t-1"));
+        assert_eq!(task.header("x-other"), None);
+        assert_eq!(task.schedule(), Some(at));
+
+        let app_engine = Task {
+            app_engine_http_request: Some(AppEngineHttpRequest {
+                http_method: Some("POST".to_owned()),
+                relative_uri: Some("/run".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(app_engine.url(), None);
+        assert_eq!(app_engine.method(), Some("POST"));
+        assert_eq!(app_engine.body_bytes(), None);
+
+        // a task read with the BASIC view has no request
+        let basic = Task::default();
+        assert_eq!(
+            (basic.url(), basic.method(), basic.header("a")),
+            (None, None, None)
+        );
+    }
+}
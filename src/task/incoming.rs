@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use super::Error;
+
+/// Read access to the headers of an incoming request, names are case insensitive
+pub trait HeaderGetter {
+    fn get_header(&self, name: &str) -> Option<&str>;
+}
+
+impl HeaderGetter for HashMap<String, String> {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get(name)
+            .or_else(|| {
+                self.iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v)
+            })
+            .map(String::as_str)
+    }
+}
+
+#[cfg(feature = "axum")]
+impl HeaderGetter for axum::http::HeaderMap {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+#[cfg(feature = "actix")]
+impl HeaderGetter for actix_web::http::header::HeaderMap {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// Task headers set by Cloud Tasks on HTTP targets, and by App Engine on App Engine targets
+const HEADER_PREFIXES: [&str; 2] = ["X-CloudTasks-", "X-AppEngine-"];
+
+/// Task being dispatched, as described by the Cloud Tasks request headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskRequestInfo {
+    /// short name of the queue
+    pub queue_name: String,
+    /// short name of the task, generated by Cloud Tasks when the task was pushed without a name
+    pub task_name: String,
+    /// times the task was retried, 0 on the first attempt
+    /// counts attempts that didn't reach the handler, unlike `execution_count`
+    pub retry_count: u32,
+    /// times the handler responded to the task with an error
+    pub execution_count: u32,
+    /// schedule time of the task
+    pub eta: DateTime<Utc>,
+    /// status code of the previous attempt, `None` on the first attempt
+    pub previous_response: Option<u16>,
+    /// why the task is retried, `None` on the first attempt
+    pub retry_reason: Option<String>,
+}
+
+impl TaskRequestInfo {
+    /// read the task headers, both the `X-CloudTasks-*` and the `X-AppEngine-*` variants
+    // `Error` is as large as the `google_cloudtasks2::Error` it wraps
+    #[allow(clippy::result_large_err)]
+    pub fn from_headers(headers: &impl HeaderGetter) -> Result<Self, Error> {
+        let get = |name: &str| {
+            HEADER_PREFIXES.iter().find_map(|prefix| {
+                let header = format!("{prefix}{name}");
+                headers.get_header(&header).map(|v| (header, v.trim()))
+            })
+        };
+        let required = |name: &str| {
+            get(name).ok_or_else(|| Error::MissingHeader(format!("{}{name}", HEADER_PREFIXES[0])))
+        };
+
+        let (_, queue_name) = required("QueueName")?;
+        let (_, task_name) = required("TaskName")?;
+        let (header, retry_count) = required("TaskRetryCount")?;
+        let retry_count = parse_count(&header, retry_count)?;
+        let (header, execution_count) = required("TaskExecutionCount")?;
+        let execution_count = parse_count(&header, execution_count)?;
+        let (header, eta) = required("TaskETA")?;
+        let eta = parse_eta(&header, eta)?;
+
+        let previous_response = match get("TaskPreviousResponse") {
+            Some((header, value)) => {
+                Some(value.parse::<u16>().map_err(|e| Error::InvalidHeader {
+                    header,
+                    message: format!("{value:?} is not a status code: {e}"),
+                })?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            queue_name: queue_name.to_owned(),
+            task_name: task_name.to_owned(),
+            retry_count,
+            execution_count,
+            eta,
+            previous_response,
+            retry_reason: get("TaskRetryReason").map(|(_, v)| v.to_owned()),
+        })
+    }
+
+    /// whether the task was retried at least `max_retries` times
+    /// a handler answering 2xx once this is true stops further retries
+    pub fn should_give_up(&self, max_retries: u32) -> bool {
+        self.retry_count >= max_retries
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_count(header: &str, value: &str) -> Result<u32, Error> {
+    value.parse().map_err(|e| Error::InvalidHeader {
+        header: header.to_owned(),
+        message: format!("{value:?} is not a count: {e}"),
+    })
+}
+
+/// seconds since the epoch, with an optional fraction e.g. `1700000000.123456`
+#[allow(clippy::result_large_err)]
+fn parse_eta(header: &str, value: &str) -> Result<DateTime<Utc>, Error> {
+    let invalid = || Error::InvalidHeader {
+        header: header.to_owned(),
+        message: format!("{value:?} is not a timestamp in seconds"),
+    };
+
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let secs = secs.parse::<i64>().map_err(|_| invalid())?;
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        format!("{fraction:0<9}")
+            .parse::<u32>()
+            .map_err(|_| invalid())?
+    };
+
+    crate::time::from_unix(secs, nanos).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(prefix: &str) -> HashMap<String, String> {
+        [
+            ("QueueName", "my-queue"),
+            ("TaskName", "1234"),
+            ("TaskRetryCount", "3"),
+            ("TaskExecutionCount", "2"),
+            ("TaskETA", "1700000000.25"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (format!("{prefix}{k}"), v.to_owned()))
+        .collect()
+    }
+
+    #[test]
+    fn from_headers_test() {
+        let mut headers = headers("X-CloudTasks-");
+        headers.insert(
+            "x-cloudtasks-taskpreviousresponse".to_owned(),
+            "503".to_owned(),
+        );
+
+        let info = TaskRequestInfo::from_headers(&headers).unwrap();
+        assert_eq!(
+            info,
+            TaskRequestInfo {
+                queue_name: "my-queue".to_owned(),
+                task_name: "1234".to_owned(),
+                retry_count: 3,
+                execution_count: 2,
+                eta: DateTime::from_timestamp(1_700_000_000, 250_000_000).unwrap(),
+                previous_response: Some(503),
+                retry_reason: None,
+            }
+        );
+
+        assert!(info.should_give_up(3));
+        assert!(!info.should_give_up(4));
+    }
+
+    #[test]
+    fn app_engine_headers_test() {
+        let info = TaskRequestInfo::from_headers(&headers("X-AppEngine-")).unwrap();
+        assert_eq!(info.queue_name, "my-queue");
+        assert_eq!(info.retry_count, 3);
+        assert_eq!(info.previous_response, None);
+    }
+
+    #[test]
+    fn invalid_headers_test() {
+        let mut bad = headers("X-CloudTasks-");
+        bad.insert("X-CloudTasks-TaskRetryCount".to_owned(), "-1".to_owned());
+        let err = TaskRequestInfo::from_headers(&bad).unwrap_err();
+        assert!(
+            err.to_string().contains("X-CloudTasks-TaskRetryCount"),
+            "{err}"
+        );
+        assert_eq!(err.code(), crate::ErrorCode::InvalidInput);
+
+        let mut bad = headers("X-AppEngine-");
+        bad.insert("X-AppEngine-TaskETA".to_owned(), "yesterday".to_owned());
+        let err = TaskRequestInfo::from_headers(&bad).unwrap_err();
+        assert!(err.to_string().contains("X-AppEngine-TaskETA"), "{err}");
+
+        let mut missing = headers("X-CloudTasks-");
+        missing.remove("X-CloudTasks-TaskName");
+        let err = TaskRequestInfo::from_headers(&missing).unwrap_err();
+        assert!(matches!(err, Error::MissingHeader(ref h) if h == "X-CloudTasks-TaskName"));
+    }
+
+    #[test]
+    fn parse_eta_test() {
+        let eta = |v| parse_eta("X-CloudTasks-TaskETA", v);
+
+        assert_eq!(
+            eta("1700000000").unwrap(),
+            DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+        );
+        assert_eq!(
+            eta("1700000000.123456").unwrap(),
+            DateTime::from_timestamp(1_700_000_000, 123_456_000).unwrap()
+        );
+        assert!(eta("1700000000.1234567890").is_err());
+        assert!(eta("1700000000.-1").is_err());
+        assert!(eta("").is_err());
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn axum_header_map_test() {
+        let mut headers = axum::http::HeaderMap::new();
+        for (k, v) in super::tests::headers("X-CloudTasks-") {
+            headers.insert(
+                axum::http::HeaderName::from_bytes(k.as_bytes()).unwrap(),
+                v.parse().unwrap(),
+            );
+        }
+
+        let info = TaskRequestInfo::from_headers(&headers).unwrap();
+        assert_eq!(info.task_name, "1234");
+    }
+}
@@ -0,0 +1,422 @@
+//! Task kinds registered once, pushed and decoded by name
+//!
+//! Each kind of task a service pushes has a target path, a queue and a payload type. A
+//! [`TaskKindRegistry`] built at startup holds them, producers push by kind name with
+//! [`super::CloudTaskHelper::push_kind`] and handlers decode by the same name:
+//!
+//! ```ignore
+//! static KINDS: LazyLock<TaskKindRegistry> = LazyLock::new(|| {
+//!     TaskKindRegistry::new("https://worker.example.com")
+//!         .register::<Invoice>("invoice", RouteSpec::new("/tasks/invoice", BILLING_QUEUE))
+//!         .register::<Email>(
+//!             "email",
+//!             RouteSpec::new("/tasks/email", MAIL_QUEUE).default_deadline(Duration::from_secs(30)),
+//!         )
+//! });
+//!
+//! tasks.push_kind(&KINDS, "invoice", &invoice, TaskOverrides::new()).await?;
+//! // in the handler of /tasks/invoice
+//! let invoice: Invoice = KINDS.decode("invoice", &body)?;
+//! ```
+
+use std::any::{type_name, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use google_cloudtasks2::api::Task;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Envelope, Error, TaskHelper, TaskOverrides, KIND_HEADER};
+use crate::redact::json_error;
+
+/// Where the tasks of a kind go, see [`TaskKindRegistry::register`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteSpec {
+    /// joined onto the base URL of the registry, e.g. `/tasks/email`
+    pub path: String,
+    /// `projects/{project}/locations/{location}/queues/{queue}`
+    pub queue: String,
+    pub method: String,
+    /// dispatch deadline of the tasks, the queue's default if unset
+    pub default_deadline: Option<Duration>,
+    /// [`Envelope::version`] of the payloads
+    pub version: u32,
+}
+
+impl RouteSpec {
+    /// tasks POSTed to `path` through `queue`, with payloads of version 1
+    pub fn new(path: impl Into<String>, queue: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            queue: queue.into(),
+            method: "POST".to_owned(),
+            default_deadline: None,
+            version: 1,
+        }
+    }
+
+    /// one of [`crate::task::BODY_METHODS`]: the payload is sent as the body
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.method = method.into();
+        self
+    }
+
+    pub fn default_deadline(mut self, deadline: Duration) -> Self {
+        self.default_deadline = Some(deadline);
+        self
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Kind {
+    route: RouteSpec,
+    payload: TypeId,
+    payload_name: &'static str,
+}
+
+impl Kind {
+    fn check_type<P: 'static>(&self, kind: &str) -> Result<(), Error> {
+        if TypeId::of::<P>() == self.payload {
+            return Ok(());
+        }
+        Err(Error::Other(format!(
+            "task kind {kind} carries {}, not {}",
+            self.payload_name,
+            type_name::<P>()
+        )))
+    }
+}
+
+/// Task kinds by name, with their route and payload type, see the [module](self) docs
+///
+/// Built without async, e.g. in a `LazyLock`, and read concurrently once built.
+#[derive(Debug, Clone)]
+pub struct TaskKindRegistry {
+    base_url: String,
+    kinds: BTreeMap<String, Kind>,
+}
+
+impl TaskKindRegistry {
+    /// kinds whose paths are joined onto `base_url`, e.g. `https://worker.example.com`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            kinds: BTreeMap::new(),
+        }
+    }
+
+    /// tasks of `kind` carry a `P` and follow `route`
+    ///
+    /// Panics if `kind` is already registered: two routes for a kind is a startup bug.
+    pub fn register<P>(mut self, kind: impl Into<String>, route: RouteSpec) -> Self
+    where
+        P: Serialize + DeserializeOwned + 'static,
+    {
+        let kind = kind.into();
+        assert!(
+            !self.kinds.contains_key(&kind),
+            "task kind {kind} is registered twice"
+        );
+        self.kinds.insert(
+            kind,
+            Kind {
+                route,
+                payload: TypeId::of::<P>(),
+                payload_name: type_name::<P>(),
+            },
+        );
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// the registered kinds, in order
+    pub fn kinds(&self) -> impl Iterator<Item = &str> {
+        self.kinds.keys().map(String::as_str)
+    }
+
+    /// route of `kind`, if registered
+    pub fn route(&self, kind: &str) -> Option<&RouteSpec> {
+        self.kinds.get(kind).map(|k| &k.route)
+    }
+
+    /// URL the tasks of `kind` are sent to
+    pub fn url(&self, kind: &str) -> Result<String, Error> {
+        let route = &self.get(kind)?.route;
+        Ok(format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            route.path.trim_start_matches('/')
+        ))
+    }
+
+    /// queue and task of `kind` carrying `payload` in an [`Envelope`] stamped with the kind,
+    /// as pushed by [`super::CloudTaskHelper::push_kind`]
+    ///
+    /// The task has a JSON `Content-Type` and a [`KIND_HEADER`], so [`super::PayloadSchemas`]
+    /// picks the validator of the kind. Fails with [`Error::UnknownKind`] for a kind not registered.
+    pub fn task<P: Serialize + 'static>(
+        &self,
+        kind: &str,
+        payload: &P,
+        overrides: TaskOverrides,
+    ) -> Result<(String, Task), Error> {
+        let url = self.url(kind)?;
+        let registered = self.get(kind)?;
+        registered.check_type::<P>(kind)?;
+        let route = &registered.route;
+
+        let body = Envelope::new(route.version, payload)?.kind(kind).to_body();
+        let headers = HashMap::from([
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+            (KIND_HEADER.to_owned(), kind.to_owned()),
+        ]);
+        let mut task = Task::new_task(
+            &url,
+            &route.method,
+            Some(body),
+            Some(headers),
+            None,
+            None,
+            None,
+        );
+        task.dispatch_deadline = route
+            .default_deadline
+            .and_then(|d| chrono::Duration::from_std(d).ok());
+
+        Ok((route.queue.clone(), overrides.apply(&task)))
+    }
+
+    /// the payload of a task body pushed as `kind`
+    ///
+    /// Fails with [`Error::UnknownKind`] for a kind not registered, [`Error::InvalidPayload`] for
+    /// a body that doesn't decode or was pushed as another kind, and as [`super::VersionMap::decode`]
+    /// for a version other than the registered one: payloads of several versions decode with
+    /// [`Envelope::decode_versioned`].
+    pub fn decode<P: DeserializeOwned + 'static>(
+        &self,
+        kind: &str,
+        body: &[u8],
+    ) -> Result<P, Error> {
+        let registered = self.get(kind)?;
+        registered.check_type::<P>(kind)?;
+        let version = registered.route.version;
+
+        let envelope = Envelope::parse(body)?;
+        if let Some(pushed) = envelope.kind.as_deref().filter(|&k| k != kind) {
+            return Err(Error::InvalidPayload(format!(
+                "task of kind {pushed} decoded as {kind}"
+            )));
+        }
+        if envelope.version < version {
+            return Err(Error::UnsupportedVersion {
+                version: envelope.version,
+                min_supported: version,
+            });
+        }
+        if envelope.version > version {
+            return Err(Error::UnknownVersion {
+                version: envelope.version,
+                latest: Some(version),
+            });
+        }
+        serde_json::from_value(envelope.payload).map_err(|e| {
+            Error::InvalidPayload(format!("payload of kind {kind}: {}", json_error(&e)))
+        })
+    }
+
+    fn get(&self, kind: &str) -> Result<&Kind, Error> {
+        self.kinds
+            .get(kind)
+            .ok_or_else(|| Error::UnknownKind(kind.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::task::{CloudTaskHelper, TaskExt};
+    use crate::testing::MemoryCloudTasks;
+    use crate::ErrorCode;
+
+    const BILLING: &str = "projects/p/locations/l/queues/billing";
+    const MAIL: &str = "projects/p/locations/l/queues/mail";
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Invoice {
+        id: u64,
+        amount_cents: i64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Email {
+        to: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Purge {
+        older_than_days: u32,
+    }
+
+    fn registry() -> TaskKindRegistry {
+        TaskKindRegistry::new("https://worker.example.com/")
+            .register::<Invoice>("invoice", RouteSpec::new("/tasks/invoice", BILLING))
+            .register::<Email>(
+                "email",
+                RouteSpec::new("tasks/email", MAIL).default_deadline(Duration::from_secs(30)),
+            )
+            .register::<Purge>(
+                "purge",
+                RouteSpec::new("/tasks/purge", BILLING)
+                    .method("PUT")
+                    .version(2),
+            )
+    }
+
+    #[tokio::test]
+    async fn push_kind_routing_test() {
+        let kinds = registry();
+        let tasks = MemoryCloudTasks::new();
+
+        let (_, invoice) = tasks
+            .push_kind(
+                &kinds,
+                "invoice",
+                &Invoice {
+                    id: 7,
+                    amount_cents: 1250,
+                },
+                TaskOverrides::new().name(format!("{BILLING}/tasks/invoice-7")),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            invoice.url(),
+            Some("https://worker.example.com/tasks/invoice")
+        );
+        assert_eq!(invoice.method(), Some("POST"));
+        assert_eq!(invoice.header(KIND_HEADER), Some("invoice"));
+        assert_eq!(invoice.header("content-type"), Some("application/json"));
+        assert_eq!(invoice.name, Some(format!("{BILLING}/tasks/invoice-7")));
+        assert!(invoice.dispatch_deadline.is_none());
+
+        let email = Email {
+            to: "a@example.com".to_owned(),
+        };
+        let (_, email) = tasks
+            .push_kind(&kinds, "email", &email, TaskOverrides::new())
+            .await
+            .unwrap();
+        assert_eq!(email.url(), Some("https://worker.example.com/tasks/email"));
+        assert_eq!(email.dispatch_deadline, Some(chrono::Duration::seconds(30)));
+
+        let purge = Purge {
+            older_than_days: 30,
+        };
+        let (_, purge) = tasks
+            .push_kind(&kinds, "purge", &purge, TaskOverrides::new())
+            .await
+            .unwrap();
+        assert_eq!(purge.method(), Some("PUT"));
+
+        assert_eq!(tasks.len(BILLING), 2);
+        assert_eq!(tasks.len(MAIL), 1);
+
+        // unknown kinds and payloads of another type fail before pushing
+        let err = tasks
+            .push_kind(&kinds, "refund", &email, TaskOverrides::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert!(err.to_string().contains("refund"), "{err}");
+        let err = tasks
+            .push_kind(&kinds, "invoice", &email, TaskOverrides::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(tasks.stats().calls("push_task"), 3);
+    }
+
+    #[test]
+    fn envelope_kind_test() {
+        let kinds = registry();
+        let (queue, task) = kinds
+            .task(
+                "purge",
+                &Purge {
+                    older_than_days: 30,
+                },
+                TaskOverrides::new(),
+            )
+            .unwrap();
+        assert_eq!(queue, BILLING);
+
+        let envelope = Envelope::parse(task.body_bytes().unwrap_or_default()).unwrap();
+        assert_eq!(envelope.kind.as_deref(), Some("purge"));
+        assert_eq!(envelope.version, 2);
+
+        // envelopes without a kind keep their previous shape
+        let plain = Envelope::new(1, &Purge { older_than_days: 1 }).unwrap();
+        assert!(!String::from_utf8(plain.to_body()).unwrap().contains("kind"));
+    }
+
+    fn body<P: Serialize + 'static>(kinds: &TaskKindRegistry, kind: &str, payload: &P) -> Vec<u8> {
+        let (_, task) = kinds.task(kind, payload, TaskOverrides::new()).unwrap();
+        task.body_bytes().unwrap_or_default().to_vec()
+    }
+
+    #[test]
+    fn decode_round_trip_test() {
+        let kinds = registry();
+        let invoice = Invoice {
+            id: 7,
+            amount_cents: 1250,
+        };
+        let email = Email {
+            to: "a@example.com".to_owned(),
+        };
+        let invoice_body = body(&kinds, "invoice", &invoice);
+        let email_body = body(&kinds, "email", &email);
+        let purge_body = body(&kinds, "purge", &Purge { older_than_days: 3 });
+
+        assert_eq!(
+            kinds.decode::<Invoice>("invoice", &invoice_body).unwrap(),
+            invoice
+        );
+        assert_eq!(kinds.decode::<Email>("email", &email_body).unwrap(), email);
+        assert_eq!(
+            kinds.decode::<Purge>("purge", &purge_body).unwrap(),
+            Purge { older_than_days: 3 }
+        );
+
+        // a body pushed as another kind
+        let err = kinds.decode::<Email>("email", &invoice_body).unwrap_err();
+        assert!(matches!(err, Error::InvalidPayload(_)), "{err}");
+        // a kind not registered
+        let err = kinds.decode::<Email>("refund", &email_body).unwrap_err();
+        assert!(matches!(err, Error::UnknownKind(_)), "{err}");
+        // a version the handler doesn't know yet is retried
+        let newer = Envelope::new(3, &Purge { older_than_days: 3 })
+            .unwrap()
+            .kind("purge")
+            .to_body();
+        let err = kinds.decode::<Purge>("purge", &newer).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+    }
+
+    #[test]
+    #[should_panic(expected = "task kind email is registered twice")]
+    fn duplicate_kind_test() {
+        let _ = registry().register::<Email>("email", RouteSpec::new("/tasks/email-v2", MAIL));
+    }
+}
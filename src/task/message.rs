@@ -0,0 +1,632 @@
+//! Provider neutral queue messages, converted to and from Cloud Tasks tasks
+//!
+//! Application code builds and reads [`QueueMessage`]s; [`QueueMessage::into_task`] and
+//! [`QueueMessage::from_task`] translate at the edge. What a message can't hold natively travels as
+//! attributes with reserved names, prefixed with [`RESERVED_PREFIX`]:
+//!
+//! | Cloud Tasks                         | message                                                    |
+//! |-------------------------------------|------------------------------------------------------------|
+//! | `http_request.url`, `http_method`   | [`Target::Url`], the method defaults to POST               |
+//! | `http_request.headers`              | attributes                                                 |
+//! | `http_request.body`                 | body, an empty body is no body                             |
+//! | `schedule_time`                     | delay from the conversion, a time in the past is no delay  |
+//! | id in `name`                        | dedup key                                                  |
+//! | `dispatch_deadline`                 | [`DISPATCH_DEADLINE_MS`] attribute, in milliseconds        |
+//! | `oidc_token`                        | [`OIDC_SERVICE_ACCOUNT`] and [`OIDC_AUDIENCE`] attributes  |
+//! | `oauth_token`                       | [`OAUTH_SERVICE_ACCOUNT`] and [`OAUTH_SCOPE`] attributes   |
+//!
+//! Those attributes mean nothing to other providers: a message read from SQS has no token nor deadline.
+//! App Engine requests, headers named with the reserved prefix and deadlines finer than a millisecond
+//! are not representable; [`QueueMessage::from_task`] fails on them in strict mode and drops them otherwise.
+//! The fields set by Cloud Tasks (creation time, dispatch and response counts, attempts, view)
+//! describe a delivery, not a message, and are always left out.
+//!
+//! [`Target::QueueOnly`] messages are delivered to the default target of the queue on Cloud Tasks,
+//! see [`QueueDefaults`].
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use google_cloudtasks2::api::{HttpRequest, OAuthToken, OidcToken, Task};
+
+use super::Error;
+use crate::ByteSize;
+
+/// Prefix of the attributes carrying Cloud Tasks features, not available to applications
+pub const RESERVED_PREFIX: &str = "nimbus-";
+pub const DISPATCH_DEADLINE_MS: &str = "nimbus-dispatch-deadline-ms";
+pub const OIDC_SERVICE_ACCOUNT: &str = "nimbus-oidc-service-account";
+pub const OIDC_AUDIENCE: &str = "nimbus-oidc-audience";
+pub const OAUTH_SERVICE_ACCOUNT: &str = "nimbus-oauth-service-account";
+pub const OAUTH_SCOPE: &str = "nimbus-oauth-scope";
+
+const RESERVED: [&str; 5] = [
+    DISPATCH_DEADLINE_MS,
+    OIDC_SERVICE_ACCOUNT,
+    OIDC_AUDIENCE,
+    OAUTH_SERVICE_ACCOUNT,
+    OAUTH_SCOPE,
+];
+
+/// Message attributes SQS accepts
+pub const SQS_MAX_ATTRIBUTES: usize = 10;
+/// Size of an SQS message, body and attributes
+pub const SQS_MAX_MESSAGE_SIZE: ByteSize = ByteSize::kib(256);
+/// Longest delivery delay of SQS
+pub const SQS_MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+/// Size of the headers of a Cloud Tasks task
+pub const CLOUD_TASKS_MAX_HEADERS_SIZE: ByteSize = ByteSize::kib(80);
+/// Size of a Cloud Tasks task
+pub const CLOUD_TASKS_MAX_TASK_SIZE: ByteSize = ByteSize::mib(1);
+/// Furthest a Cloud Tasks task can be scheduled
+pub const CLOUD_TASKS_MAX_DELAY: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// headers Cloud Tasks sets itself, lowercase, the prefixes end with `-`
+const CLOUD_TASKS_OWN_HEADERS: [&str; 6] = [
+    "host",
+    "content-length",
+    "user-agent",
+    "x-google-",
+    "x-appengine-",
+    "x-cloudtasks-",
+];
+
+/// Where a message is delivered
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Target {
+    /// pushed to an HTTP endpoint
+    Url { method: String, url: String },
+    /// wherever the queue delivers, or pulled by the consumers of the queue
+    #[default]
+    QueueOnly,
+}
+
+/// Provider whose limits a message is checked against, see [`QueueMessage::validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueueProvider {
+    CloudTasks,
+    Sqs,
+}
+
+/// Message of a queue, whatever the provider, see the [module](self) docs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueMessage {
+    pub target: Target,
+    pub body: Vec<u8>,
+    pub attributes: HashMap<String, String>,
+    /// delivered no sooner than this after being sent
+    pub delay: Option<Duration>,
+    /// messages with the same key are delivered once, the task id on Cloud Tasks
+    pub dedup_key: Option<String>,
+}
+
+/// What [`QueueMessage::into_task`] needs to know about the destination queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueDefaults {
+    /// `projects/{project}/locations/{location}/queues/{queue}`, tasks are named under it
+    pub queue: String,
+    /// target of the [`Target::QueueOnly`] messages
+    pub url: String,
+    pub method: String,
+}
+
+impl QueueDefaults {
+    /// `QueueOnly` messages are POSTed to `url`
+    pub fn new(queue: impl Into<String>, url: impl Into<String>) -> Self {
+        Self {
+            queue: queue.into(),
+            url: url.into(),
+            method: "POST".to_owned(),
+        }
+    }
+
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.method = method.into();
+        self
+    }
+}
+
+impl TryFrom<Task> for QueueMessage {
+    type Error = Error;
+
+    /// strict conversion, see [`QueueMessage::from_task`]
+    fn try_from(task: Task) -> Result<Self, Error> {
+        QueueMessage::from_task(task, true)
+    }
+}
+
+impl QueueMessage {
+    pub fn new(target: Target, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            target,
+            body: body.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn dedup_key(mut self, key: impl Into<String>) -> Self {
+        self.dedup_key = Some(key.into());
+        self
+    }
+
+    /// message of `task`, with the delay until its schedule time
+    /// in `strict` mode what the message can't represent fails with [`Error::NotRepresentable`],
+    /// otherwise it is dropped, see the [module](self) docs
+    pub fn from_task(task: Task, strict: bool) -> Result<Self, Error> {
+        Self::from_task_at(task, strict, Utc::now())
+    }
+
+    /// task delivering the message to the queue of `defaults`, scheduled after its delay
+    /// the message is expected to be [valid](QueueMessage::validate) for Cloud Tasks
+    pub fn into_task(self, defaults: &QueueDefaults) -> Task {
+        self.into_task_at(defaults, Utc::now())
+    }
+
+    fn from_task_at(task: Task, strict: bool, now: DateTime<Utc>) -> Result<Self, Error> {
+        let lost = |what: &str| -> Result<(), Error> {
+            if strict {
+                Err(Error::NotRepresentable(what.to_owned()))
+            } else {
+                Ok(())
+            }
+        };
+
+        let mut message = QueueMessage::default();
+
+        if task.app_engine_http_request.is_some() {
+            lost("App Engine request")?;
+        }
+        if let Some(request) = task.http_request {
+            match request.url {
+                Some(url) => {
+                    let method = request.http_method.unwrap_or_else(|| "POST".to_owned());
+                    message.target = Target::Url { method, url };
+                }
+                None => lost("HTTP request without url")?,
+            }
+            message.body = request.body.unwrap_or_default();
+
+            for (name, value) in request.headers.unwrap_or_default() {
+                if is_reserved(&name) {
+                    lost(&format!("header {name}"))?;
+                    continue;
+                }
+                message.attributes.insert(name, value);
+            }
+
+            if let Some(token) = request.oidc_token {
+                message.set_reserved(OIDC_SERVICE_ACCOUNT, token.service_account_email);
+                message.set_reserved(OIDC_AUDIENCE, token.audience);
+            }
+            if let Some(token) = request.oauth_token {
+                message.set_reserved(OAUTH_SERVICE_ACCOUNT, token.service_account_email);
+                message.set_reserved(OAUTH_SCOPE, token.scope);
+            }
+        }
+
+        if let Some(deadline) = task.dispatch_deadline {
+            let ms = deadline.num_milliseconds();
+            if deadline != chrono::Duration::milliseconds(ms) {
+                lost("dispatch deadline below the millisecond")?;
+            }
+            message.set_reserved(DISPATCH_DEADLINE_MS, Some(ms.to_string()));
+        }
+
+        message.delay = task
+            .schedule_time
+            .and_then(|at| (at - now).to_std().ok())
+            .filter(|delay| !delay.is_zero());
+
+        if let Some(name) = task.name {
+            match name.rsplit_once("/tasks/") {
+                Some((_, id)) => message.dedup_key = Some(id.to_owned()),
+                None => return Err(Error::InvalidTaskName(name)),
+            }
+        }
+
+        Ok(message)
+    }
+
+    fn into_task_at(mut self, defaults: &QueueDefaults, now: DateTime<Utc>) -> Task {
+        let (method, url) = match self.target {
+            Target::Url { method, url } => (method, url),
+            Target::QueueOnly => (defaults.method.clone(), defaults.url.clone()),
+        };
+
+        let mut take = |name: &str| self.attributes.remove(name);
+        let oidc_token = match (take(OIDC_SERVICE_ACCOUNT), take(OIDC_AUDIENCE)) {
+            (None, None) => None,
+            (service_account_email, audience) => Some(OidcToken {
+                service_account_email,
+                audience,
+            }),
+        };
+        let oauth_token = match (take(OAUTH_SERVICE_ACCOUNT), take(OAUTH_SCOPE)) {
+            (None, None) => None,
+            (service_account_email, scope) => Some(OAuthToken {
+                service_account_email,
+                scope,
+            }),
+        };
+        let dispatch_deadline = take(DISPATCH_DEADLINE_MS)
+            .and_then(|ms| ms.parse().ok())
+            .map(chrono::Duration::milliseconds);
+
+        let http_request = HttpRequest {
+            url: Some(url),
+            http_method: Some(method),
+            body: (!self.body.is_empty()).then_some(self.body),
+            headers: (!self.attributes.is_empty()).then_some(self.attributes),
+            oidc_token,
+            oauth_token,
+        };
+
+        Task {
+            name: self
+                .dedup_key
+                .map(|id| format!("{}/tasks/{id}", defaults.queue)),
+            http_request: Some(http_request),
+            schedule_time: self
+                .delay
+                .and_then(|delay| chrono::Duration::from_std(delay).ok())
+                .map(|delay| now + delay),
+            dispatch_deadline,
+            ..Default::default()
+        }
+    }
+
+    fn set_reserved(&mut self, name: &str, value: Option<String>) {
+        if let Some(value) = value {
+            self.attributes.insert(name.to_owned(), value);
+        }
+    }
+
+    /// check the message against the limits of `provider`, fails with [`Error::InvalidMessage`]
+    ///
+    /// On SQS attributes are message attributes: at most [`SQS_MAX_ATTRIBUTES`] of them, reserved ones included.
+    /// On Cloud Tasks they are headers, limited in size rather than in number, and can't be those
+    /// Cloud Tasks sets itself (`Host`, `User-Agent`, `X-CloudTasks-*`...).
+    pub fn validate(&self, provider: QueueProvider) -> Result<(), Error> {
+        let invalid = |reason: String| Err(Error::InvalidMessage(reason));
+
+        for name in self.attributes.keys() {
+            if is_reserved(name) && !RESERVED.contains(&name.as_str()) {
+                return invalid(format!("unknown reserved attribute {name}"));
+            }
+        }
+        if let Some(ms) = self.attributes.get(DISPATCH_DEADLINE_MS) {
+            if ms.parse::<i64>().is_err() {
+                return invalid(format!("{DISPATCH_DEADLINE_MS} is not a number: {ms:?}"));
+            }
+        }
+
+        let attributes_size: usize = self
+            .attributes
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        let delay = self.delay.unwrap_or_default();
+
+        match provider {
+            QueueProvider::Sqs => {
+                if self.attributes.len() > SQS_MAX_ATTRIBUTES {
+                    return invalid(format!(
+                        "{} attributes, SQS accepts {SQS_MAX_ATTRIBUTES}",
+                        self.attributes.len()
+                    ));
+                }
+                if let Some(name) = self.attributes.keys().find(|n| !is_sqs_attribute_name(n)) {
+                    return invalid(format!("invalid SQS attribute name {name:?}"));
+                }
+                let size = (self.body.len() + attributes_size) as u64;
+                if size > SQS_MAX_MESSAGE_SIZE.bytes() {
+                    return invalid(format!(
+                        "{} message, SQS accepts {SQS_MAX_MESSAGE_SIZE}",
+                        ByteSize::b(size)
+                    ));
+                }
+                if delay > SQS_MAX_DELAY {
+                    return invalid(format!("delay of {delay:?}, SQS accepts {SQS_MAX_DELAY:?}"));
+                }
+                if let Some(key) = &self.dedup_key {
+                    let valid =
+                        (1..=128).contains(&key.len()) && key.chars().all(|c| c.is_ascii_graphic());
+                    if !valid {
+                        return invalid(format!("invalid SQS deduplication id {key:?}"));
+                    }
+                }
+            }
+            QueueProvider::CloudTasks => {
+                if let Some(name) = self.attributes.keys().find(|n| !is_header_name(n)) {
+                    return invalid(format!("attribute {name:?} is not a valid header name"));
+                }
+                if let Some(name) = self.attributes.keys().find(|n| is_cloud_tasks_header(n)) {
+                    return invalid(format!("header {name} is set by Cloud Tasks"));
+                }
+                if attributes_size as u64 > CLOUD_TASKS_MAX_HEADERS_SIZE.bytes() {
+                    return invalid(format!(
+                        "{} of headers, Cloud Tasks accepts {CLOUD_TASKS_MAX_HEADERS_SIZE}",
+                        ByteSize::b(attributes_size as u64)
+                    ));
+                }
+                if self.body.len() as u64 > CLOUD_TASKS_MAX_TASK_SIZE.bytes() {
+                    return invalid(format!(
+                        "{} body, Cloud Tasks accepts {CLOUD_TASKS_MAX_TASK_SIZE}",
+                        ByteSize::b(self.body.len() as u64)
+                    ));
+                }
+                if delay > CLOUD_TASKS_MAX_DELAY {
+                    return invalid(format!(
+                        "delay of {delay:?}, Cloud Tasks accepts {CLOUD_TASKS_MAX_DELAY:?}"
+                    ));
+                }
+                if let Some(key) = &self.dedup_key {
+                    let valid = (1..=500).contains(&key.len())
+                        && key
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                    if !valid {
+                        return invalid(format!("invalid task id {key:?}"));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    name.len() >= RESERVED_PREFIX.len()
+        && name[..RESERVED_PREFIX.len()].eq_ignore_ascii_case(RESERVED_PREFIX)
+}
+
+/// letters, digits, `_`, `-` and `.`, not starting with `AWS.` or `Amazon.` nor with two periods in a row
+fn is_sqs_attribute_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    (1..=256).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !name.starts_with('.')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !lower.starts_with("aws.")
+        && !lower.starts_with("amazon.")
+}
+
+/// an HTTP token
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+fn is_cloud_tasks_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    CLOUD_TASKS_OWN_HEADERS
+        .iter()
+        .any(|own| match own.strip_suffix('-') {
+            Some(_) => name.starts_with(own),
+            None => name == *own,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::JitterRng;
+    use crate::task::TaskExt;
+    use crate::ErrorCode;
+
+    const QUEUE: &str = "projects/p/locations/l/queues/q";
+
+    fn defaults() -> QueueDefaults {
+        QueueDefaults::new(QUEUE, "https://worker.example.com/default")
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    fn pick<'a>(rng: &mut JitterRng, from: &[&'a str]) -> &'a str {
+        from[(rng.next_u64() % from.len() as u64) as usize]
+    }
+
+    /// a message Cloud Tasks can carry, with some of every field
+    fn generate(rng: &mut JitterRng) -> QueueMessage {
+        let mut message = QueueMessage::new(
+            Target::Url {
+                method: pick(rng, &["POST", "PUT", "PATCH", "GET"]).to_owned(),
+                url: format!("https://worker.example.com/{}", rng.next_u64() % 100),
+            },
+            (0..rng.next_u64() % 64)
+                .map(|_| rng.next_u64() as u8)
+                .collect::<Vec<_>>(),
+        );
+        for i in 0..rng.next_u64() % 6 {
+            let value = format!("value-{}", rng.next_u64());
+            message = message.attribute(format!("x-attr-{i}"), value);
+        }
+        let reserved = [
+            DISPATCH_DEADLINE_MS,
+            OIDC_SERVICE_ACCOUNT,
+            OIDC_AUDIENCE,
+            OAUTH_SERVICE_ACCOUNT,
+            OAUTH_SCOPE,
+        ];
+        for name in reserved {
+            if rng.next_u64() % 3 == 0 {
+                let value = if name == DISPATCH_DEADLINE_MS {
+                    (rng.next_u64() % 1_800_000).to_string()
+                } else {
+                    format!("{}@p.iam.gserviceaccount.com", rng.next_u64() % 10)
+                };
+                message = message.attribute(name, value);
+            }
+        }
+        if rng.next_u64() % 2 == 0 {
+            message = message.delay(Duration::from_millis(1 + rng.next_u64() % 86_400_000));
+        }
+        if rng.next_u64() % 2 == 0 {
+            message = message.dedup_key(format!("job-{}", rng.next_u64()));
+        }
+        message
+    }
+
+    #[test]
+    fn message_round_trip_test() {
+        let mut rng = JitterRng::seeded(478);
+        for _ in 0..500 {
+            let message = generate(&mut rng);
+            message.validate(QueueProvider::CloudTasks).unwrap();
+
+            let task = message.clone().into_task_at(&defaults(), now());
+            let back = QueueMessage::from_task_at(task, true, now()).unwrap();
+            assert_eq!(back, message);
+        }
+    }
+
+    #[test]
+    fn task_round_trip_test() {
+        let mut rng = JitterRng::seeded(1478);
+        for _ in 0..500 {
+            let task = generate(&mut rng).into_task_at(&defaults(), now());
+
+            let message = QueueMessage::from_task_at(task.clone(), true, now()).unwrap();
+            let back = message.into_task_at(&defaults(), now());
+            assert_eq!(format!("{back:?}"), format!("{task:?}"));
+        }
+    }
+
+    #[test]
+    fn queue_only_test() {
+        let message = QueueMessage::new(Target::QueueOnly, "{}").dedup_key("job-1");
+        let task = message.into_task_at(&defaults(), now());
+        assert_eq!(task.name.as_deref(), Some(&*format!("{QUEUE}/tasks/job-1")));
+        assert_eq!(task.url(), Some("https://worker.example.com/default"));
+        assert_eq!(task.method(), Some("POST"));
+
+        // a task without a request has nowhere to go
+        let message = QueueMessage::try_from(Task::default()).unwrap();
+        assert_eq!(message, QueueMessage::default());
+    }
+
+    #[test]
+    fn strict_mode_test() {
+        let mut task = QueueMessage::new(
+            Target::Url {
+                method: "POST".to_owned(),
+                url: "https://worker.example.com".to_owned(),
+            },
+            "{}",
+        )
+        .into_task_at(&defaults(), now());
+        task.http_request
+            .as_mut()
+            .unwrap()
+            .headers
+            .get_or_insert_with(HashMap::new)
+            .insert("Nimbus-Internal".to_owned(), "x".to_owned());
+        task.dispatch_deadline = Some(chrono::Duration::microseconds(1_500));
+
+        let err = QueueMessage::try_from(task.clone()).unwrap_err();
+        assert!(matches!(err, Error::NotRepresentable(_)));
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+
+        let lenient = QueueMessage::from_task_at(task.clone(), false, now()).unwrap();
+        assert!(!lenient.attributes.contains_key("Nimbus-Internal"));
+        assert_eq!(lenient.attributes[DISPATCH_DEADLINE_MS], "1");
+
+        task.app_engine_http_request = Some(Default::default());
+        assert!(QueueMessage::from_task_at(task, true, now()).is_err());
+
+        // a schedule time in the past is no delay
+        let past = Task {
+            schedule_time: Some(now() - chrono::Duration::minutes(5)),
+            ..Default::default()
+        };
+        assert_eq!(
+            QueueMessage::from_task_at(past, true, now()).unwrap().delay,
+            None
+        );
+    }
+
+    #[test]
+    fn validate_test() {
+        let target = Target::Url {
+            method: "POST".to_owned(),
+            url: "https://worker.example.com".to_owned(),
+        };
+        let mut message = QueueMessage::new(target, "{}");
+        for i in 0..SQS_MAX_ATTRIBUTES {
+            message = message.attribute(format!("x-attr-{i}"), "v");
+        }
+        message.validate(QueueProvider::Sqs).unwrap();
+        message.validate(QueueProvider::CloudTasks).unwrap();
+
+        // reserved attributes count on SQS, headers are not counted on Cloud Tasks
+        let more = message.clone().attribute(OIDC_AUDIENCE, "aud");
+        assert!(more.validate(QueueProvider::Sqs).is_err());
+        more.validate(QueueProvider::CloudTasks).unwrap();
+
+        let invalid = [
+            (
+                message.clone().attribute("nimbus-unknown", "v"),
+                QueueProvider::CloudTasks,
+            ),
+            (
+                QueueMessage::default().attribute("User-Agent", "me"),
+                QueueProvider::CloudTasks,
+            ),
+            (
+                QueueMessage::default().attribute("X-CloudTasks-TaskName", "t"),
+                QueueProvider::CloudTasks,
+            ),
+            (
+                QueueMessage::default().attribute("bad header", "v"),
+                QueueProvider::CloudTasks,
+            ),
+            (
+                QueueMessage::default().attribute("AWS.trace", "v"),
+                QueueProvider::Sqs,
+            ),
+            (
+                QueueMessage::default().delay(Duration::from_secs(16 * 60)),
+                QueueProvider::Sqs,
+            ),
+            (
+                QueueMessage::default().dedup_key("job.1"),
+                QueueProvider::CloudTasks,
+            ),
+            (
+                QueueMessage::new(Target::QueueOnly, vec![0; 300 * 1024]),
+                QueueProvider::Sqs,
+            ),
+        ];
+        for (message, provider) in invalid {
+            let err = message.validate(provider).unwrap_err();
+            assert!(matches!(err, Error::InvalidMessage(_)), "{message:?}");
+        }
+        QueueMessage::default()
+            .delay(Duration::from_secs(16 * 60))
+            .validate(QueueProvider::CloudTasks)
+            .unwrap();
+        QueueMessage::default()
+            .dedup_key("job.1")
+            .validate(QueueProvider::Sqs)
+            .unwrap();
+    }
+}
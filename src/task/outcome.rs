@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use google_cloudtasks2::api::{Status, Task};
+
+/// First delay between two polls of [`super::CloudTaskHelper::wait_for_task_completion`]
+pub const POLL_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between two polls of [`super::CloudTaskHelper::wait_for_task_completion`]
+pub const POLL_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Result of [`super::CloudTaskHelper::wait_for_task_completion`]
+#[derive(Debug, Clone)]
+pub enum TaskOutcome {
+    /// the task is gone from its queue
+    Succeeded,
+    /// the last attempt failed and the queue allows no further attempts
+    Failed { status: Status, attempts: i32 },
+    /// the task still exists after the timeout
+    TimedOut,
+}
+
+impl TaskOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, TaskOutcome::Succeeded)
+    }
+}
+
+/// queue a task belongs to, from its full name
+pub(crate) fn queue_of(task_name: &str) -> Option<&str> {
+    task_name.split_once("/tasks/").map(|(queue, _)| queue)
+}
+
+/// [`TaskOutcome::Failed`] if the last attempt of a task failed and was its last allowed one
+/// `max_attempts` is the queue's retry limit, unlimited when `None` or negative
+pub(crate) fn terminal_failure(task: &Task, max_attempts: Option<i32>) -> Option<TaskOutcome> {
+    let status = task.last_attempt.as_ref()?.response_status.as_ref()?;
+
+    // code 0 is OK
+    if status.code.unwrap_or(0) == 0 {
+        return None;
+    }
+
+    let attempts = task.dispatch_count.unwrap_or(0);
+    match max_attempts {
+        Some(max) if max > 0 && attempts >= max => Some(TaskOutcome::Failed {
+            status: status.clone(),
+            attempts,
+        }),
+        _ => None,
+    }
+}
+
+/// delay before the next poll, doubling up to [`POLL_MAX_DELAY`] and never past the deadline
+pub(crate) fn next_delay(previous: Option<Duration>, remaining: Duration) -> Duration {
+    let delay = previous.map_or(POLL_INITIAL_DELAY, |d| (d * 2).min(POLL_MAX_DELAY));
+    delay.min(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use google_cloudtasks2::api::Attempt;
+
+    use super::*;
+
+    fn task(code: Option<i32>, dispatch_count: i32) -> Task {
+        Task {
+            dispatch_count: Some(dispatch_count),
+            last_attempt: Some(Attempt {
+                response_status: code.map(|code| Status {
+                    code: Some(code),
+                    message: Some("failed".to_owned()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn queue_of_test() {
+        assert_eq!(
+            queue_of("projects/p/locations/l/queues/q/tasks/t"),
+            Some("projects/p/locations/l/queues/q")
+        );
+        assert_eq!(queue_of("t"), None);
+    }
+
+    #[test]
+    fn terminal_failure_test() {
+        // failed, attempts exhausted
+        let outcome = terminal_failure(&task(Some(13), 3), Some(3));
+        assert!(matches!(
+            outcome,
+            Some(TaskOutcome::Failed { attempts: 3, ref status }) if status.code == Some(13)
+        ));
+
+        // failed, retries left
+        assert!(terminal_failure(&task(Some(13), 2), Some(3)).is_none());
+        // failed, unlimited retries
+        assert!(terminal_failure(&task(Some(13), 100), Some(-1)).is_none());
+        assert!(terminal_failure(&task(Some(13), 100), None).is_none());
+        // last attempt succeeded or never ran
+        assert!(terminal_failure(&task(Some(0), 3), Some(3)).is_none());
+        assert!(terminal_failure(&task(None, 0), Some(3)).is_none());
+        assert!(terminal_failure(&Task::default(), Some(1)).is_none());
+    }
+
+    #[test]
+    fn next_delay_test() {
+        let far = Duration::from_secs(3600);
+
+        assert_eq!(next_delay(None, far), POLL_INITIAL_DELAY);
+        assert_eq!(
+            next_delay(Some(POLL_INITIAL_DELAY), far),
+            POLL_INITIAL_DELAY * 2
+        );
+        assert_eq!(next_delay(Some(POLL_MAX_DELAY), far), POLL_MAX_DELAY);
+        assert_eq!(
+            next_delay(Some(Duration::from_secs(8)), far),
+            POLL_MAX_DELAY
+        );
+        assert_eq!(
+            next_delay(None, Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use google_cloudtasks2::api::{AppEngineHttpRequest, HttpRequest, Task};
+
+/// Per-push differences applied to a template task by [`super::CloudTaskHelper::push_task_ref`]
+///
+/// Overrides win over the template: a set `name` or `schedule_time` replaces the template's,
+/// `header` is inserted into the template's headers, replacing a header of the same name.
+#[derive(Debug, Clone, Default)]
+pub struct TaskOverrides {
+    pub name: Option<String>,
+    pub schedule_time: Option<DateTime<Utc>>,
+    pub header: Option<(String, String)>,
+}
+
+impl TaskOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn schedule_time(mut self, schedule_time: DateTime<Utc>) -> Self {
+        self.schedule_time = Some(schedule_time);
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.header = Some((name.into(), value.into()));
+        self
+    }
+
+    /// task to create from `template` with the overrides applied
+    ///
+    /// Only the fields the API reads on create are copied, the template's output only fields
+    /// (create time, attempts, counts, view) are left out, as are the fields being overridden.
+    /// The request body is copied on every call: the generated `Task` owns it as a `Vec<u8>`,
+    /// so this saves nothing over a clone for a template without output only fields.
+    pub fn apply(self, template: &Task) -> Task {
+        let Self {
+            name,
+            schedule_time,
+            header,
+        } = self;
+
+        Task {
+            name: name.or_else(|| template.name.clone()),
+            schedule_time: schedule_time.or(template.schedule_time),
+            dispatch_deadline: template.dispatch_deadline,
+            http_request: template.http_request.as_ref().map(|r| HttpRequest {
+                body: r.body.clone(),
+                headers: with_header(r.headers.as_ref(), header.clone()),
+                http_method: r.http_method.clone(),
+                oauth_token: r.oauth_token.clone(),
+                oidc_token: r.oidc_token.clone(),
+                url: r.url.clone(),
+            }),
+            app_engine_http_request: template.app_engine_http_request.as_ref().map(|r| {
+                AppEngineHttpRequest {
+                    app_engine_routing: r.app_engine_routing.clone(),
+                    body: r.body.clone(),
+                    headers: with_header(r.headers.as_ref(), header),
+                    http_method: r.http_method.clone(),
+                    relative_uri: r.relative_uri.clone(),
+                }
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+fn with_header(
+    headers: Option<&HashMap<String, String>>,
+    header: Option<(String, String)>,
+) -> Option<HashMap<String, String>> {
+    let Some((name, value)) = header else {
+        return headers.cloned();
+    };
+
+    let mut headers = headers.cloned().unwrap_or_default();
+    // header names are case insensitive
+    headers.retain(|k, _| !k.eq_ignore_ascii_case(&name));
+    headers.insert(name, value);
+    Some(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use google_cloudtasks2::api::Attempt;
+
+    use super::*;
+    use crate::task::{TaskExt, TaskHelper};
+
+    fn template(body_len: usize) -> Task {
+        let headers = HashMap::from([
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+            ("X-Trace".to_owned(), "template".to_owned()),
+        ]);
+
+        Task {
+            create_time: Some(Utc::now()),
+            dispatch_count: Some(3),
+            last_attempt: Some(Attempt::default()),
+            ..Task::new_task(
+                "https://example.com/fan-out",
+                "POST",
+                Some(vec![7; body_len]),
+                Some(headers),
+                Some("projects/p/locations/l/queues/q/tasks/template".to_owned()),
+                None,
+                None,
+            )
+        }
+    }
+
+    #[test]
+    fn overrides_win_test() {
+        let template = template(16);
+        let at = Utc::now();
+
+        let task = TaskOverrides::new()
+            .name("projects/p/locations/l/queues/q/tasks/1")
+            .schedule_time(at)
+            .header("x-trace", "push-1")
+            .apply(&template);
+
+        assert_eq!(
+            task.name.as_deref(),
+            Some("projects/p/locations/l/queues/q/tasks/1")
+        );
+        assert_eq!(task.schedule_time, Some(at));
+
+        assert_eq!(task.headers().map(HashMap::len), Some(2));
+        assert_eq!(task.header("x-trace"), Some("push-1"));
+        assert_eq!(task.header("Content-Type"), Some("application/json"));
+        assert_eq!(task.body_bytes(), template.body_bytes());
+
+        // output only fields are not carried over
+        assert!(task.create_time.is_none());
+        assert!(task.dispatch_count.is_none());
+        assert!(task.last_attempt.is_none());
+    }
+
+    #[test]
+    fn no_overrides_keeps_template_test() {
+        let template = template(16);
+        let task = TaskOverrides::new().apply(&template);
+
+        assert_eq!(task.name, template.name);
+        assert_eq!(task.headers(), template.headers());
+    }
+
+    #[test]
+    fn apply_copies_request_only_test() {
+        const BODY: usize = 60 * 1024;
+        let template = template(BODY);
+
+        let task = TaskOverrides::new()
+            .name("projects/p/locations/l/queues/q/tasks/1")
+            .apply(&template);
+
+        // the body is copied into the task, the template keeps its own
+        let (body, template_body) = (task.body_bytes().unwrap(), template.body_bytes().unwrap());
+        assert_eq!(body, template_body);
+        assert_ne!(body.as_ptr(), template_body.as_ptr());
+        // the output only fields are left out, they carry whole attempts
+        assert!(task.first_attempt.is_none() && task.last_attempt.is_none());
+        assert!(task.app_engine_http_request.is_none());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use google_cloudtasks2::api::Task;
+use thiserror::Error;
+
+use super::TaskExt;
+
+/// Header naming the kind of a task, to pick its validator in [`PayloadSchemas`]
+pub const KIND_HEADER: &str = "nimbus-kind";
+
+/// A part of a payload breaking its schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// JSON pointer to the offending value in the payload, empty for the root
+    pub instance_path: String,
+    /// JSON pointer to the schema keyword it breaks, e.g. `/properties/user/required`
+    pub schema_path: String,
+}
+
+/// Why a payload was rejected, only the pointers of the violations are kept as the values may be sensitive
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{}", describe(.violations))]
+pub struct ValidationError {
+    pub violations: Vec<Violation>,
+}
+
+fn describe(violations: &[Violation]) -> String {
+    let paths: Vec<String> = violations
+        .iter()
+        .map(|v| format!("{:?} breaks {:?}", v.instance_path, v.schema_path))
+        .collect();
+    paths.join(", ")
+}
+
+impl ValidationError {
+    /// a payload that isn't valid at all, e.g. not JSON
+    pub fn malformed() -> Self {
+        Self {
+            violations: vec![Violation {
+                instance_path: String::new(),
+                schema_path: String::new(),
+            }],
+        }
+    }
+}
+
+/// Check of a task body before it is pushed, see [`PayloadSchemas`]
+pub trait PayloadValidator: Send + Sync {
+    fn validate(&self, content_type: &str, body: &[u8]) -> Result<(), ValidationError>;
+}
+
+/// Validator accepting every payload
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoValidation;
+
+impl PayloadValidator for NoValidation {
+    fn validate(&self, _: &str, _: &[u8]) -> Result<(), ValidationError> {
+        Ok(())
+    }
+}
+
+/// whether a content type is JSON, `application/json` or a `+json` type, parameters aside
+pub fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// JSON Schema checked against JSON bodies, bodies of other content types are not checked
+#[cfg(feature = "jsonschema")]
+pub struct JsonSchemaValidator {
+    schema: jsonschema::JSONSchema,
+}
+
+#[cfg(feature = "jsonschema")]
+impl fmt::Debug for JsonSchemaValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonSchemaValidator")
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "jsonschema")]
+impl JsonSchemaValidator {
+    /// compile `schema` once for every validation, fails if it isn't a valid schema
+    pub fn new(schema: &serde_json::Value) -> Result<Self, super::Error> {
+        let schema = jsonschema::JSONSchema::compile(schema)
+            .map_err(|e| super::Error::Other(format!("Invalid JSON schema: {e}")))?;
+        Ok(Self { schema })
+    }
+}
+
+#[cfg(feature = "jsonschema")]
+impl PayloadValidator for JsonSchemaValidator {
+    fn validate(&self, content_type: &str, body: &[u8]) -> Result<(), ValidationError> {
+        if !is_json(content_type) {
+            return Ok(());
+        }
+        let payload: serde_json::Value =
+            serde_json::from_slice(body).map_err(|_| ValidationError::malformed())?;
+
+        self.schema
+            .validate(&payload)
+            .map_err(|errors| ValidationError {
+                violations: errors
+                    .map(|e| Violation {
+                        instance_path: e.instance_path.to_string(),
+                        schema_path: e.schema_path.to_string(),
+                    })
+                    .collect(),
+            })
+    }
+}
+
+/// Validators of the payloads of many task types, picked by the [`KIND_HEADER`] of a task,
+/// else by the path of its URL, else the fallback; tasks matching none aren't checked
+///
+/// ```ignore
+/// let schemas = PayloadSchemas::new()
+///     .path("/tasks/email", JsonSchemaValidator::new(&email_schema)?)
+///     .kind("invoice", JsonSchemaValidator::new(&invoice_schema)?);
+/// helper.push_task_with(queue, task, &TaskValidation::new().payload(schemas), None).await?;
+/// ```
+#[derive(Clone, Default)]
+pub struct PayloadSchemas {
+    by_path: HashMap<String, Arc<dyn PayloadValidator>>,
+    by_kind: HashMap<String, Arc<dyn PayloadValidator>>,
+    fallback: Option<Arc<dyn PayloadValidator>>,
+}
+
+impl fmt::Debug for PayloadSchemas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut paths: Vec<_> = self.by_path.keys().collect();
+        paths.sort();
+        let mut kinds: Vec<_> = self.by_kind.keys().collect();
+        kinds.sort();
+        f.debug_struct("PayloadSchemas")
+            .field("paths", &paths)
+            .field("kinds", &kinds)
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl PayloadSchemas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// validator of the tasks sent to the URL path `path`, e.g. `/tasks/email`
+    pub fn path(
+        mut self,
+        path: impl Into<String>,
+        validator: impl PayloadValidator + 'static,
+    ) -> Self {
+        self.by_path.insert(path.into(), Arc::new(validator));
+        self
+    }
+
+    /// validator of the tasks with a [`KIND_HEADER`] of `kind`
+    pub fn kind(
+        mut self,
+        kind: impl Into<String>,
+        validator: impl PayloadValidator + 'static,
+    ) -> Self {
+        self.by_kind.insert(kind.into(), Arc::new(validator));
+        self
+    }
+
+    /// validator of the tasks matching no path or kind
+    pub fn fallback(mut self, validator: impl PayloadValidator + 'static) -> Self {
+        self.fallback = Some(Arc::new(validator));
+        self
+    }
+
+    /// validate the body of an HTTP task, tasks without a body or validator pass
+    pub fn check(&self, task: &Task) -> Result<(), ValidationError> {
+        let Some(request) = &task.http_request else {
+            return Ok(());
+        };
+        let Some(body) = request.body.as_deref().filter(|b| !b.is_empty()) else {
+            return Ok(());
+        };
+        let by_kind = task
+            .header(KIND_HEADER)
+            .and_then(|kind| self.by_kind.get(kind));
+        let by_path = || {
+            let path = url_path(request.url.as_deref()?);
+            self.by_path.get(path)
+        };
+        let Some(validator) = by_kind.or_else(by_path).or(self.fallback.as_ref()) else {
+            return Ok(());
+        };
+
+        validator.validate(task.header("content-type").unwrap_or_default(), body)
+    }
+}
+
+/// path of a URL, without its query or fragment
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = rest.find('/').map_or("/", |start| &rest[start..]);
+    path.split(['?', '#']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_path_test() {
+        for (url, path) in [
+            ("https://example.com/tasks/email", "/tasks/email"),
+            (
+                "https://example.com/tasks/email?retry=1#top",
+                "/tasks/email",
+            ),
+            ("https://example.com", "/"),
+            ("/tasks/email", "/tasks/email"),
+        ] {
+            assert_eq!(url_path(url), path, "{url}");
+        }
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(is_json("Application/Problem+JSON"));
+        assert!(!is_json("text/plain"));
+        assert!(!is_json(""));
+    }
+
+    #[cfg(feature = "jsonschema")]
+    fn order_schema() -> JsonSchemaValidator {
+        JsonSchemaValidator::new(&serde_json::json!({
+            "type": "object",
+            "required": ["id", "customer"],
+            "properties": {
+                "id": {"type": "integer", "minimum": 1},
+                "customer": {
+                    "type": "object",
+                    "required": ["email"],
+                    "properties": {
+                        "email": {"type": "string", "format": "email"},
+                        "tags": {"type": "array", "items": {"type": "string"}}
+                    }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn json_schema_test() {
+        let schema = order_schema();
+        let json = "application/json";
+        let check = |body: &str| schema.validate(json, body.as_bytes());
+
+        check(r#"{"id": 1, "customer": {"email": "a@example.com", "tags": ["vip"]}}"#).unwrap();
+
+        let pointers = |body: &str| {
+            let mut violations: Vec<(String, String)> = check(body)
+                .unwrap_err()
+                .violations
+                .into_iter()
+                .map(|v| (v.instance_path, v.schema_path))
+                .collect();
+            violations.sort();
+            violations
+        };
+        // missing nested required field
+        assert_eq!(
+            pointers(r#"{"id": 1, "customer": {}}"#),
+            [("/customer".into(), "/properties/customer/required".into())]
+        );
+        // wrong type and out of range, both reported
+        assert_eq!(
+            pointers(r#"{"id": 0, "customer": {"email": 7}}"#),
+            [
+                (
+                    "/customer/email".into(),
+                    "/properties/customer/properties/email/type".into()
+                ),
+                ("/id".into(), "/properties/id/minimum".into()),
+            ]
+        );
+        // wrong item type in a nested array
+        assert_eq!(
+            pointers(r#"{"id": 1, "customer": {"email": "a@example.com", "tags": [1]}}"#),
+            [(
+                "/customer/tags/0".into(),
+                "/properties/customer/properties/tags/items/type".into()
+            )]
+        );
+        // missing root field
+        assert_eq!(
+            pointers(r#"{"customer": {"email": "a@example.com"}}"#),
+            [(String::new(), "/required".into())]
+        );
+        // not JSON at all
+        assert_eq!(check("{").unwrap_err(), ValidationError::malformed());
+
+        // the values are never part of the message
+        let error = check(r#"{"id": 1, "customer": {"email": 42424242}}"#).unwrap_err();
+        assert!(!error.to_string().contains("42424242"), "{error}");
+
+        // other content types aren't checked
+        for content_type in ["text/plain", "application/octet-stream", ""] {
+            schema.validate(content_type, b"{").unwrap();
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::BatchError;
+
+/// Full name of a queue, `projects/{project}/locations/{location}/queues/{queue}`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct QueuePath {
+    pub project: String,
+    pub location: String,
+    pub queue: String,
+}
+
+impl QueuePath {
+    pub fn new(
+        project: impl Into<String>,
+        location: impl Into<String>,
+        queue: impl Into<String>,
+    ) -> Self {
+        Self {
+            project: project.into(),
+            location: location.into(),
+            queue: queue.into(),
+        }
+    }
+
+    /// split a full queue name, `None` if it isn't one
+    pub fn parse(name: &str) -> Option<Self> {
+        let parts: Vec<&str> = name.split('/').collect();
+        match parts[..] {
+            ["projects", project, "locations", location, "queues", queue]
+                if [project, location, queue].iter().all(|p| !p.is_empty()) =>
+            {
+                Some(Self::new(project, location, queue))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// the full name, as taken by [`super::CloudTaskHelper`] methods
+impl fmt::Display for QueuePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "projects/{}/locations/{}/queues/{}",
+            self.project, self.location, self.queue
+        )
+    }
+}
+
+/// Whether a queue dispatches its tasks, serialized by its API name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum QueueState {
+    Running,
+    /// tasks are kept but not dispatched
+    Paused,
+    /// disabled through queue.yaml/xml, tasks can't be added
+    Disabled,
+    /// a state unknown to this crate
+    #[serde(rename = "STATE_UNSPECIFIED")]
+    Unspecified,
+}
+
+impl QueueState {
+    /// state from the API name e.g. `RUNNING`
+    pub fn from_api(state: Option<&str>) -> Self {
+        match state {
+            Some("RUNNING") => QueueState::Running,
+            Some("PAUSED") => QueueState::Paused,
+            Some("DISABLED") => QueueState::Disabled,
+            _ => QueueState::Unspecified,
+        }
+    }
+
+    /// API name of the state e.g. `RUNNING`
+    pub fn as_api(&self) -> &'static str {
+        match self {
+            QueueState::Running => "RUNNING",
+            QueueState::Paused => "PAUSED",
+            QueueState::Disabled => "DISABLED",
+            QueueState::Unspecified => "STATE_UNSPECIFIED",
+        }
+    }
+}
+
+/// A queue found by [`super::CloudTaskHelper::list_all_queues`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueInfo {
+    pub path: QueuePath,
+    pub state: QueueState,
+}
+
+/// Result of a discovery across the locations of a project
+///
+/// Locations the caller may not list queues in don't fail the discovery,
+/// they are reported in `warnings` keyed by location id.
+#[derive(Debug)]
+pub struct QueueDiscovery<T> {
+    /// sorted by queue path
+    pub found: Vec<T>,
+    pub warnings: Vec<BatchError<String>>,
+}
+
+impl<T> QueueDiscovery<T> {
+    /// true if every location was searched
+    pub fn is_complete(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_path_test() {
+        let name = "projects/p/locations/europe-west1/queues/q";
+        let path = QueuePath::parse(name).unwrap();
+        assert_eq!(path, QueuePath::new("p", "europe-west1", "q"));
+        assert_eq!(path.to_string(), name);
+
+        for invalid in [
+            "projects/p/locations/l/queues",
+            "projects/p/locations/l/queues/q/tasks/t",
+            "projects/p/locations//queues/q",
+            "q",
+        ] {
+            assert_eq!(QueuePath::parse(invalid), None, "{invalid}");
+        }
+    }
+}
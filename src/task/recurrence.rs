@@ -0,0 +1,394 @@
+//! Recurring tasks expanded client-side
+//!
+//! Cloud Tasks has no recurring tasks: a [`Recurrence`] lists the times a job runs within a window,
+//! and [`CloudTaskHelper::push_recurring`](super::CloudTaskHelper::push_recurring) pushes one task per time.
+//! Each task is named after the template and the time it stands for, so expanding the same window again,
+//! or an overlapping one, only pushes the occurrences not pushed yet.
+//!
+//! Times are UTC throughout: a recurrence every hour is every hour across daylight saving changes,
+//! and a cron expression is matched against the UTC clock.
+//!
+//! ```ignore
+//! // the next 24 hours of 15 minute slots, to run again every few hours
+//! let recurrence = Recurrence::every(Duration::from_secs(15 * 60)).jitter(Duration::from_secs(30));
+//! let pushed = client
+//!     .push_recurring(queue, &template, &recurrence, Duration::from_secs(24 * 3600))
+//!     .await?;
+//! ```
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use super::{Error, TaskOverrides};
+use crate::retry::JitterRng;
+use crate::{NimbusError, Task};
+
+/// How far ahead Cloud Tasks accepts a schedule time
+pub const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Tasks pushed by one expansion unless set with [`Recurrence::max_occurrences`]
+pub const DEFAULT_MAX_OCCURRENCES: usize = 1000;
+
+#[derive(Debug, Clone)]
+enum Schedule {
+    /// every `interval` seconds since the Unix epoch, shifted by `offset` seconds
+    Every { interval: i64, offset: i64 },
+    #[cfg(feature = "cron")]
+    Cron(Box<cron::Schedule>),
+}
+
+/// Times a recurring task runs, see the [module](self) docs
+#[derive(Debug, Clone)]
+pub struct Recurrence {
+    schedule: Schedule,
+    jitter: Duration,
+    max_occurrences: usize,
+}
+
+impl Recurrence {
+    /// every `interval`, in whole seconds and at least one, aligned on the Unix epoch
+    /// e.g. every 15 minutes is at :00, :15, :30 and :45 whenever the expansion starts
+    pub fn every(interval: Duration) -> Self {
+        Self {
+            schedule: Schedule::Every {
+                interval: interval.as_secs().clamp(1, i64::MAX as u64) as i64,
+                offset: 0,
+            },
+            jitter: Duration::ZERO,
+            max_occurrences: DEFAULT_MAX_OCCURRENCES,
+        }
+    }
+
+    /// from a cron expression with seconds: `sec min hour day-of-month month day-of-week [year]`
+    /// e.g. `0 */15 * * * *` every 15 minutes, `0 0 2 * * Mon-Fri` at 02:00 UTC on weekdays
+    #[cfg(feature = "cron")]
+    pub fn cron(expression: &str) -> Result<Self, NimbusError> {
+        let schedule = expression
+            .parse::<cron::Schedule>()
+            .map_err(|e| Error::InvalidRecurrence(format!("{expression:?}: {e}")))?;
+        Ok(Self {
+            schedule: Schedule::Cron(Box::new(schedule)),
+            jitter: Duration::ZERO,
+            max_occurrences: DEFAULT_MAX_OCCURRENCES,
+        })
+    }
+
+    /// shift an [`every`](Recurrence::every) recurrence to run at `anchor` rather than on the epoch,
+    /// ignored by cron expressions
+    pub fn anchor(mut self, anchor: DateTime<Utc>) -> Self {
+        match &mut self.schedule {
+            Schedule::Every { interval, offset } => {
+                *offset = anchor.timestamp().rem_euclid(*interval);
+            }
+            #[cfg(feature = "cron")]
+            Schedule::Cron(_) => {}
+        }
+        self
+    }
+
+    /// delay each occurrence by up to `jitter`, e.g. so recurring jobs of many tenants don't all start at once
+    /// the delay of an occurrence only depends on its time, it is the same for every expansion
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// most tasks one expansion may push, [`DEFAULT_MAX_OCCURRENCES`] by default
+    pub fn max_occurrences(mut self, max: usize) -> Self {
+        self.max_occurrences = max;
+        self
+    }
+
+    /// times of the occurrences due from `from` included to `to` excluded, jitter included
+    pub fn occurrences_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<DateTime<Utc>> {
+        self.slots(from, to)
+            .map(|slot| self.jittered(slot))
+            .collect()
+    }
+
+    /// times the occurrences stand for, without jitter
+    fn slots(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Box<dyn Iterator<Item = DateTime<Utc>> + '_> {
+        match &self.schedule {
+            Schedule::Every { interval, offset } => {
+                let (interval, offset) = (*interval, *offset);
+                // first slot at or after `from`, slots are whole seconds
+                let start = from.timestamp() + i64::from(from.timestamp_subsec_nanos() > 0);
+                let first = start + (offset - start).rem_euclid(interval);
+                let slots = (0..).map_while(move |i: i64| {
+                    let secs = i.checked_mul(interval)?.checked_add(first)?;
+                    DateTime::from_timestamp(secs, 0)
+                });
+                Box::new(slots.take_while(move |slot| *slot < to))
+            }
+            #[cfg(feature = "cron")]
+            Schedule::Cron(schedule) => {
+                // `after` is exclusive and to the second
+                let before = from - chrono::Duration::seconds(1);
+                Box::new(
+                    schedule
+                        .after(&before)
+                        .skip_while(move |slot| *slot < from)
+                        .take_while(move |slot| *slot < to),
+                )
+            }
+        }
+    }
+
+    fn jittered(&self, slot: DateTime<Utc>) -> DateTime<Utc> {
+        if self.jitter.is_zero() {
+            return slot;
+        }
+        let delay = JitterRng::seeded(slot.timestamp() as u64).between(Duration::ZERO, self.jitter);
+        slot + chrono::Duration::from_std(delay).unwrap_or_default()
+    }
+
+    /// one task per occurrence in `[now, now + window)`, named `{queue}/tasks/{id}-{slot}` after the template
+    /// fails without any task if the expansion is too large or goes past [`MAX_SCHEDULE_AHEAD`]
+    pub(crate) fn expand(
+        &self,
+        queue: &str,
+        template: &Task,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Task>, NimbusError> {
+        let id = match template.name.as_deref() {
+            Some(name) => match name.rsplit('/').next() {
+                Some(id) if !id.is_empty() => id,
+                _ => return Err(Error::InvalidTaskName(name.to_owned()).into()),
+            },
+            None => {
+                return Err(Error::InvalidRecurrence(
+                    "the template needs a name to name its occurrences after".to_owned(),
+                )
+                .into())
+            }
+        };
+
+        let limit = now + chrono::Duration::from_std(MAX_SCHEDULE_AHEAD).unwrap_or_default();
+        let end = chrono::Duration::from_std(window)
+            .ok()
+            .and_then(|window| now.checked_add_signed(window))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+        let slots: Vec<_> = self
+            .slots(now, end)
+            .take(self.max_occurrences.saturating_add(1))
+            .collect();
+        if slots.len() > self.max_occurrences {
+            return Err(Error::TooManyOccurrences {
+                max: self.max_occurrences,
+            }
+            .into());
+        }
+
+        slots
+            .into_iter()
+            .map(|slot| {
+                let at = self.jittered(slot);
+                if at > limit {
+                    return Err(Error::ScheduleTooFar { at, limit }.into());
+                }
+                let name = format!("{queue}/tasks/{id}-{}", slot.format("%Y%m%dT%H%M%SZ"));
+                Ok(TaskOverrides::new()
+                    .name(name)
+                    .schedule_time(at)
+                    .apply(template))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::task::{CloudTaskHelper, TaskHelper};
+    use crate::testing::MemoryCloudTasks;
+    use crate::ErrorCode;
+
+    const QUEUE: &str = "projects/p/locations/l/queues/q";
+    const MINUTE: Duration = Duration::from_secs(60);
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 8, h, m, s).unwrap()
+    }
+
+    fn template() -> Task {
+        Task::new_task(
+            "https://example.com/report",
+            "POST",
+            Some(b"{}".to_vec()),
+            None,
+            Some(format!("{QUEUE}/tasks/report")),
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn every_test() {
+        let recurrence = Recurrence::every(15 * MINUTE);
+        assert_eq!(
+            recurrence.occurrences_between(at(10, 7, 0), at(11, 0, 0)),
+            [at(10, 15, 0), at(10, 30, 0), at(10, 45, 0)]
+        );
+        // from included, to excluded
+        assert_eq!(
+            recurrence.occurrences_between(at(10, 0, 0), at(10, 30, 0)),
+            [at(10, 0, 0), at(10, 15, 0)]
+        );
+
+        let anchored = Recurrence::every(15 * MINUTE).anchor(at(0, 5, 0));
+        assert_eq!(
+            anchored.occurrences_between(at(10, 0, 0), at(10, 30, 0)),
+            [at(10, 5, 0), at(10, 20, 0)]
+        );
+    }
+
+    #[test]
+    fn dst_irrelevant_test() {
+        // clocks go forward in the US on 2026-03-08 and in Europe on 2026-03-29, UTC doesn't
+        let from = Utc.with_ymd_and_hms(2026, 3, 8, 0, 0, 0).unwrap();
+        let hours = Recurrence::every(Duration::from_secs(3600))
+            .occurrences_between(from, from + chrono::Duration::days(1));
+        assert_eq!(hours.len(), 24);
+        assert!(hours
+            .windows(2)
+            .all(|w| w[1] - w[0] == chrono::Duration::hours(1)));
+
+        let from = Utc.with_ymd_and_hms(2026, 3, 29, 0, 0, 0).unwrap();
+        let hours = Recurrence::every(Duration::from_secs(3600))
+            .occurrences_between(from, from + chrono::Duration::days(1));
+        assert_eq!(hours.len(), 24);
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn cron_test() {
+        let nightly = Recurrence::cron("0 0 2 * * *").unwrap();
+        let from = Utc.with_ymd_and_hms(2026, 3, 7, 0, 0, 0).unwrap();
+        let runs = nightly.occurrences_between(from, from + chrono::Duration::days(3));
+        // 02:00 UTC on the day of the change too
+        assert_eq!(
+            runs,
+            [7, 8, 9].map(|d| Utc.with_ymd_and_hms(2026, 3, d, 2, 0, 0).unwrap())
+        );
+
+        let quarters = Recurrence::cron("0 */15 * * * *").unwrap();
+        assert_eq!(
+            quarters.occurrences_between(at(10, 0, 0), at(10, 30, 0)),
+            [at(10, 0, 0), at(10, 15, 0)]
+        );
+
+        let err = Recurrence::cron("every day").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[test]
+    fn jitter_test() {
+        let recurrence = Recurrence::every(15 * MINUTE).jitter(MINUTE);
+        let runs = recurrence.occurrences_between(at(10, 0, 0), at(12, 0, 0));
+        assert_eq!(runs.len(), 8);
+        for (run, slot) in runs
+            .iter()
+            .zip(Recurrence::every(15 * MINUTE).occurrences_between(at(10, 0, 0), at(12, 0, 0)))
+        {
+            assert!(*run >= slot && *run <= slot + chrono::Duration::minutes(1));
+        }
+        // the same on every expansion
+        assert_eq!(
+            runs,
+            recurrence.occurrences_between(at(10, 0, 0), at(12, 0, 0))
+        );
+    }
+
+    #[test]
+    fn expand_test() {
+        let recurrence = Recurrence::every(15 * MINUTE);
+        let tasks = recurrence
+            .expand(QUEUE, &template(), DAY, at(10, 7, 30))
+            .unwrap();
+        assert_eq!(tasks.len(), 96);
+        assert_eq!(
+            tasks[0].name.as_deref(),
+            Some("projects/p/locations/l/queues/q/tasks/report-20260308T101500Z")
+        );
+        assert_eq!(tasks[0].schedule_time, Some(at(10, 15, 0)));
+        // the API types don't implement PartialEq
+        assert_eq!(
+            format!("{:?}", tasks[0].http_request),
+            format!("{:?}", template().http_request)
+        );
+
+        // overlapping windows name the shared occurrences alike
+        let later = recurrence
+            .expand(QUEUE, &template(), DAY, at(16, 0, 0))
+            .unwrap();
+        assert_eq!(later[0].name, tasks[(6 * 4) - 1].name);
+    }
+
+    #[test]
+    fn guard_rails_test() {
+        let err = Recurrence::every(MINUTE)
+            .expand(QUEUE, &template(), DAY, at(0, 0, 0))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert!(Recurrence::every(MINUTE)
+            .max_occurrences(24 * 60)
+            .expand(QUEUE, &template(), DAY, at(0, 0, 0))
+            .is_ok());
+
+        // up to 30 days ahead included
+        assert!(Recurrence::every(DAY)
+            .expand(QUEUE, &template(), 31 * DAY, at(0, 0, 0))
+            .is_ok());
+        let err = Recurrence::every(DAY)
+            .expand(QUEUE, &template(), 32 * DAY, at(0, 0, 0))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::TasksClient(Error::ScheduleTooFar { .. })
+        ));
+
+        let unnamed = Task {
+            name: None,
+            ..template()
+        };
+        let err = Recurrence::every(MINUTE)
+            .expand(QUEUE, &unnamed, MINUTE, at(0, 0, 0))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn push_recurring_idempotent_test() {
+        let tasks = MemoryCloudTasks::new();
+        let recurrence = Recurrence::every(15 * MINUTE);
+
+        let pushed = tasks
+            .push_recurring(QUEUE, &template(), &recurrence, DAY)
+            .await
+            .unwrap();
+        assert_eq!(pushed.len(), 96);
+        assert_eq!(tasks.len(QUEUE), 96);
+
+        // the same window again pushes at most the slot that came into it
+        let again = tasks
+            .push_recurring(QUEUE, &template(), &recurrence, DAY)
+            .await
+            .unwrap();
+        assert!(again.len() <= 1);
+        assert_eq!(tasks.len(QUEUE), 96 + again.len());
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+
+use google_cloudtasks2::api::Task;
+
+use crate::redact::Policy;
+use crate::ByteSize;
+
+/// Bodies longer than this are truncated by [`Redaction::default`]
+pub const DEFAULT_MAX_BODY: ByteSize = ByteSize::kib(1);
+
+const MASK: &str = "[REDACTED]";
+
+/// Redaction applied to tasks before they are logged or printed
+///
+/// Masks the values of sensitive headers (matched case-insensitively) and
+/// truncates long bodies, noting the original length.
+/// The default masks the sensitive headers of the [global policy](Policy::global).
+/// Always works on a copy, the task passed in is never modified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    headers: Vec<String>,
+    max_body: ByteSize,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Self::from_policy(Policy::global())
+    }
+}
+
+impl Redaction {
+    /// redaction masking exactly the given headers
+    pub fn new(headers: &[&str], max_body: impl Into<ByteSize>) -> Self {
+        Self {
+            headers: headers.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            max_body: max_body.into(),
+        }
+    }
+
+    /// redaction masking the sensitive headers of `policy`
+    pub fn from_policy(policy: &Policy) -> Self {
+        Self {
+            headers: policy.sensitive_headers().map(str::to_owned).collect(),
+            max_body: DEFAULT_MAX_BODY,
+        }
+    }
+
+    /// also mask `header`
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.headers.push(header.to_ascii_lowercase());
+        self
+    }
+
+    /// truncate bodies longer than `max_body`, a bare `u64` is a number of bytes
+    pub fn with_max_body(mut self, max_body: impl Into<ByteSize>) -> Self {
+        self.max_body = max_body.into();
+        self
+    }
+
+    pub fn is_sensitive(&self, header: &str) -> bool {
+        self.headers.iter().any(|h| h.eq_ignore_ascii_case(header))
+    }
+
+    /// redacted copy of `task`
+    pub fn apply(&self, task: &Task) -> Task {
+        let mut task = task.clone();
+
+        if let Some(rq) = task.http_request.as_mut() {
+            if let Some(headers) = rq.headers.as_mut() {
+                for (name, value) in headers.iter_mut() {
+                    if self.is_sensitive(name) {
+                        *value = MASK.to_owned();
+                    }
+                }
+            }
+
+            if let Some(body) = rq.body.as_mut() {
+                let max_body = self.max_body.as_usize();
+                if body.len() > max_body {
+                    let note = format!("... [truncated, {} bytes total]", body.len());
+                    body.truncate(max_body);
+                    body.extend_from_slice(note.as_bytes());
+                }
+            }
+        }
+
+        task
+    }
+}
+
+/// A task whose `Debug` output is redacted
+///
+/// Returned by the debugging helpers, e.g. [`crate::CloudTaskHelper::inspect_task`], so that printing
+/// it cannot leak credentials;
+/// the unredacted task stays available through [`RedactedTask::into_inner`].
+#[derive(Clone)]
+pub struct RedactedTask {
+    task: Task,
+    redaction: Redaction,
+}
+
+impl RedactedTask {
+    pub fn new(task: Task, redaction: Redaction) -> Self {
+        Self { task, redaction }
+    }
+
+    /// redacted copy of the task
+    pub fn redacted(&self) -> Task {
+        self.redaction.apply(&self.task)
+    }
+
+    /// the original, unredacted task
+    pub fn into_inner(self) -> Task {
+        self.task
+    }
+}
+
+impl From<Task> for RedactedTask {
+    fn from(task: Task) -> Self {
+        Self::new(task, Redaction::default())
+    }
+}
+
+impl fmt::Debug for RedactedTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RedactedTask")
+            .field(&self.redacted())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use google_cloudtasks2::api::HttpRequest;
+
+    use super::*;
+    use crate::task::TaskExt;
+
+    fn task(body: Vec<u8>) -> Task {
+        let headers = HashMap::from([
+            ("Authorization".to_owned(), "Bearer secret-token".to_owned()),
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+            ("x-api-key".to_owned(), "secret-key".to_owned()),
+            ("X-Custom".to_owned(), "secret-custom".to_owned()),
+        ]);
+
+        Task {
+            name: Some("task".to_owned()),
+            http_request: Some(HttpRequest {
+                url: Some("https://example.com".to_owned()),
+                headers: Some(headers),
+                body: Some(body),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn redacts_sensitive_headers() {
+        let original = task(b"{}".to_vec());
+        let redacted = Redaction::default().apply(&original);
+
+        assert_eq!(redacted.header("Authorization"), Some(MASK));
+        assert_eq!(redacted.header("x-api-key"), Some(MASK));
+        assert_eq!(redacted.header("Content-Type"), Some("application/json"));
+        assert_eq!(redacted.header("X-Custom"), Some("secret-custom"));
+
+        let redacted = Redaction::default()
+            .with_header("x-custom")
+            .apply(&original);
+        assert_eq!(redacted.header("X-Custom"), Some(MASK));
+    }
+
+    #[test]
+    fn truncates_long_bodies() {
+        let original = task(vec![b'a'; 100]);
+        let redacted = Redaction::default().with_max_body(10).apply(&original);
+
+        assert_eq!(
+            redacted.body_bytes(),
+            Some(&b"aaaaaaaaaa... [truncated, 100 bytes total]"[..])
+        );
+
+        let short = Redaction::default().apply(&original);
+        assert_eq!(short.body_bytes(), Some(&[b'a'; 100][..]));
+    }
+
+    #[test]
+    fn original_is_untouched() {
+        let original = task(vec![b'a'; 2048]);
+        let snapshot = original.clone();
+
+        let _ = Redaction::default().apply(&original);
+        let wrapped = RedactedTask::from(original);
+        let _ = format!("{wrapped:?}");
+
+        let after = wrapped.into_inner();
+        assert_eq!(after.headers(), snapshot.headers());
+        assert_eq!(after.body_bytes(), snapshot.body_bytes());
+    }
+
+    #[test]
+    fn debug_is_redacted() {
+        let wrapped = RedactedTask::from(task(b"{}".to_vec()));
+        let printed = format!("{wrapped:?}");
+
+        assert!(!printed.contains("secret-token"));
+        assert!(!printed.contains("secret-key"));
+        assert!(printed.contains(MASK));
+    }
+}
@@ -0,0 +1,276 @@
+//! A queue sharded across several queues, `emails-0..emails-7`, to get around the dispatch
+//! limits of a single queue.
+//!
+//! Sticky routing maps a key to a shard with the FNV-1a 64 bit hash of its UTF-8 bytes, modulo the
+//! number of shards: offset basis `0xcbf29ce484222325`, prime `0x100000001b3`, xor then multiply for
+//! each byte, wrapping. Any other language computing the same gets the same shard for a key. Changing
+//! the number of shards moves most keys to another shard.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use google_cloudtasks2::api::Task;
+
+use super::{ApplyReport, CloudTaskHelper, QueueConfigSnapshot, QueuePath, TaskView};
+use crate::NimbusError;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// FNV-1a 64 bit hash of `key`, see the [module docs](self)
+pub fn fnv1a64(key: &str) -> u64 {
+    key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// How [`ShardedQueue::push_sharded`] picks the shard of a task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Routing {
+    /// a shard at random, by the weights of the shards
+    Random,
+    /// the shard of the key, tasks of a key always go to the same shard
+    Sticky(String),
+}
+
+/// Tasks in each shard, from [`ShardedQueue::stats_all`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardStats {
+    /// in shard order
+    pub tasks: Vec<(QueuePath, u64)>,
+}
+
+impl ShardStats {
+    /// tasks across every shard
+    pub fn total(&self) -> u64 {
+        self.tasks.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/// Queues `{queue}-0` to `{queue}-{shards - 1}` of a base queue path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardedQueue {
+    base: QueuePath,
+    shards: u32,
+    /// of each shard for random picks, equal when empty
+    weights: Vec<u32>,
+}
+
+impl ShardedQueue {
+    /// panics if `shards` is 0
+    pub fn new(base: QueuePath, shards: u32) -> Self {
+        assert!(shards > 0, "a sharded queue needs at least one shard");
+        Self {
+            base,
+            shards,
+            weights: Vec::new(),
+        }
+    }
+
+    /// weight of each shard for [`ShardedQueue::pick_random`], e.g. 0 to drain a shard
+    /// sticky picks ignore the weights so keys never move
+    ///
+    /// panics if there isn't one weight per shard or they are all 0
+    pub fn weights(mut self, weights: Vec<u32>) -> Self {
+        assert_eq!(weights.len(), self.shards as usize, "one weight per shard");
+        assert!(
+            weights.iter().any(|w| *w > 0),
+            "at least one shard weighted"
+        );
+        self.weights = weights;
+        self
+    }
+
+    pub fn base(&self) -> &QueuePath {
+        &self.base
+    }
+
+    pub fn shard_count(&self) -> u32 {
+        self.shards
+    }
+
+    /// path of shard `index`
+    pub fn shard(&self, index: u32) -> QueuePath {
+        QueuePath {
+            queue: format!("{}-{index}", self.base.queue),
+            ..self.base.clone()
+        }
+    }
+
+    /// paths of every shard, in order
+    pub fn shards(&self) -> Vec<QueuePath> {
+        (0..self.shards).map(|i| self.shard(i)).collect()
+    }
+
+    /// index of the shard of `key`, `fnv1a64(key) % shards`
+    pub fn sticky_index(&self, key: &str) -> u32 {
+        (fnv1a64(key) % u64::from(self.shards)) as u32
+    }
+
+    /// the shard of `key`, the same for as long as the number of shards doesn't change
+    pub fn pick_sticky(&self, key: &str) -> QueuePath {
+        self.shard(self.sticky_index(key))
+    }
+
+    /// a shard at random, by the weights of the shards
+    pub fn pick_random(&self) -> QueuePath {
+        self.shard(self.weighted_index(random_u64()))
+    }
+
+    /// shard of a random number, by the weights of the shards
+    fn weighted_index(&self, random: u64) -> u32 {
+        if self.weights.is_empty() {
+            return (random % u64::from(self.shards)) as u32;
+        }
+        let total: u64 = self.weights.iter().map(|w| u64::from(*w)).sum();
+        let mut point = random % total;
+        for (index, weight) in self.weights.iter().enumerate() {
+            let weight = u64::from(*weight);
+            if point < weight {
+                return index as u32;
+            }
+            point -= weight;
+        }
+        unreachable!("point is below the total weight")
+    }
+
+    /// the shard picked by `routing`
+    pub fn pick(&self, routing: &Routing) -> QueuePath {
+        match routing {
+            Routing::Random => self.pick_random(),
+            Routing::Sticky(key) => self.pick_sticky(key),
+        }
+    }
+
+    /// push `task` to the shard picked by `routing`, see [`CloudTaskHelper::push_task`]
+    /// returns the shard with the created task
+    pub async fn push_sharded<C>(
+        &self,
+        helper: &(impl CloudTaskHelper<C> + Sync),
+        task: Task,
+        routing: Routing,
+        res_view: Option<TaskView>,
+    ) -> Result<(QueuePath, Task), NimbusError> {
+        let shard = self.pick(&routing);
+        let (_, task) = helper.push_task(&shard.to_string(), task, res_view).await?;
+        Ok((shard, task))
+    }
+
+    /// bring every shard to `config`, creating the missing ones, see [`CloudTaskHelper::apply_queue_config`]
+    /// stops at the first shard failing, the shards before it are applied already
+    pub async fn ensure_all<C>(
+        &self,
+        helper: &(impl CloudTaskHelper<C> + Sync),
+        config: &QueueConfigSnapshot,
+    ) -> Result<Vec<ApplyReport>, NimbusError> {
+        let mut reports = Vec::with_capacity(self.shards as usize);
+        for shard in self.shards() {
+            reports.push(helper.apply_queue_config(&shard, config, true).await?);
+        }
+        Ok(reports)
+    }
+
+    /// tasks in each shard, counted by listing them as the API doesn't report it
+    pub async fn stats_all<C>(
+        &self,
+        helper: &(impl CloudTaskHelper<C> + Sync),
+    ) -> Result<ShardStats, NimbusError> {
+        let mut tasks = Vec::with_capacity(self.shards as usize);
+        for shard in self.shards() {
+            let queue = shard.to_string();
+            let mut count = 0;
+            let mut page_token = None;
+            loop {
+                let (page, next) = helper
+                    .list_tasks(&queue, Some(TaskView::Basic), true, page_token)
+                    .await?;
+                count += page.len() as u64;
+                match next {
+                    Some(token) => page_token = Some(token),
+                    None => break,
+                }
+            }
+            tasks.push((shard, count));
+        }
+        Ok(ShardStats { tasks })
+    }
+}
+
+/// random enough to spread tasks, not for anything else
+fn random_u64() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(nanos);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emails(shards: u32) -> ShardedQueue {
+        ShardedQueue::new(QueuePath::new("p", "europe-west1", "emails"), shards)
+    }
+
+    // golden values, other languages must match them and a change here reshards every key
+    #[test]
+    fn fnv1a64_golden_test() {
+        for (key, hash) in [
+            ("", 0xcbf29ce484222325),
+            ("a", 0xaf63dc4c8601ec8c),
+            ("user-42", 0x32c6d7a54d35dacb),
+            ("user-43", 0x32c6d6a54d35d918),
+            ("customer/1234", 0x79f8be48c1f67de6),
+            ("ünïcode", 0xb1b0d40350a25beb),
+        ] {
+            assert_eq!(fnv1a64(key), hash, "{key}");
+        }
+    }
+
+    #[test]
+    fn sticky_golden_test() {
+        let eight = emails(8);
+        let three = emails(3);
+        for (key, of_eight, of_three) in [
+            ("", 5, 2),
+            ("a", 4, 1),
+            ("user-42", 3, 0),
+            ("user-43", 0, 2),
+            ("customer/1234", 6, 0),
+            ("ünïcode", 3, 2),
+        ] {
+            assert_eq!(eight.sticky_index(key), of_eight, "{key}");
+            assert_eq!(three.sticky_index(key), of_three, "{key}");
+        }
+        assert_eq!(
+            eight.pick_sticky("user-42").to_string(),
+            "projects/p/locations/europe-west1/queues/emails-3"
+        );
+        // weights don't move keys
+        let weighted = emails(8).weights(vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            weighted.pick_sticky("user-42"),
+            eight.pick_sticky("user-42")
+        );
+    }
+
+    #[test]
+    fn weighted_random_test() {
+        let queue = emails(3).weights(vec![1, 0, 2]);
+        let picks: Vec<u32> = (0..6).map(|r| queue.weighted_index(r)).collect();
+        assert_eq!(picks, vec![0, 2, 2, 0, 2, 2]);
+
+        let queue = emails(4);
+        for _ in 0..100 {
+            assert!(queue.shards().contains(&queue.pick_random()));
+        }
+        let drained = emails(4).weights(vec![0, 0, 5, 0]);
+        for _ in 0..100 {
+            assert_eq!(drained.pick_random(), drained.shard(2));
+        }
+    }
+}
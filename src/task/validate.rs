@@ -0,0 +1,173 @@
+use google_cloudtasks2::api::Task;
+
+use super::{Error, PayloadSchemas};
+
+/// Methods Cloud Tasks accepts a request body with, it rejects a body with any other
+pub const BODY_METHODS: &[&str] = &["POST", "PUT", "PATCH"];
+
+/// Suspicious but valid task, found by [`TaskValidation::check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskWarning {
+    /// a `Content-Type` header without a body, usually a body the caller forgot to serialize
+    EmptyBodyWithContentType { method: String },
+}
+
+/// Client-side checks of a task before it is pushed, see [`super::CloudTaskHelper::push_task_with`]
+///
+/// By default a body with a method that can't carry one (GET, HEAD, DELETE...) fails with
+/// [`Error::BodyNotAllowed`] instead of the bare 400 Cloud Tasks answers, and a POST, PUT or PATCH
+/// with a `Content-Type` but no body is only reported as a [`TaskWarning`].
+/// Tasks the server accepts are never changed.
+#[derive(Debug, Clone, Default)]
+pub struct TaskValidation {
+    allow_any_body: bool,
+    strict: bool,
+    payload: Option<PayloadSchemas>,
+}
+
+impl TaskValidation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// send bodies with any method and let the server decide
+    pub fn allow_body_with_any_method(mut self) -> Self {
+        self.allow_any_body = true;
+        self
+    }
+
+    /// fail on warnings too, an empty body with a `Content-Type` fails with [`Error::EmptyBody`]
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// validate bodies with `schemas`, a body breaking its schema fails with [`Error::PayloadInvalid`]
+    pub fn payload(mut self, schemas: PayloadSchemas) -> Self {
+        self.payload = Some(schemas);
+        self
+    }
+
+    /// the warnings about a task, or the error Cloud Tasks (or strict mode) would fail it with
+    /// tasks without an HTTP request (App Engine ones) are not checked
+    pub fn check(&self, task: &Task) -> Result<Vec<TaskWarning>, Error> {
+        let Some(request) = &task.http_request else {
+            return Ok(vec![]);
+        };
+
+        let method = request
+            .http_method
+            .as_deref()
+            .unwrap_or("POST")
+            .to_ascii_uppercase();
+        let has_body = request.body.as_ref().is_some_and(|b| !b.is_empty());
+        let accepts_body = BODY_METHODS.contains(&method.as_str());
+
+        if has_body && !accepts_body && !self.allow_any_body {
+            return Err(Error::BodyNotAllowed { method });
+        }
+        if let Some(schemas) = &self.payload {
+            schemas.check(task).map_err(Error::PayloadInvalid)?;
+        }
+
+        let content_type = request.headers.as_ref().is_some_and(|headers| {
+            headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case("content-type"))
+        });
+        if !has_body && accepts_body && content_type {
+            if self.strict {
+                return Err(Error::EmptyBody { method });
+            }
+            return Ok(vec![TaskWarning::EmptyBodyWithContentType { method }]);
+        }
+
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::task::TaskHelper;
+
+    fn task(method: &str, body: Option<&[u8]>, content_type: bool) -> Task {
+        let headers = content_type
+            .then(|| HashMap::from([("content-type".to_owned(), "application/json".to_owned())]));
+        Task::new_task(
+            "https://example.com",
+            method,
+            body.map(<[u8]>::to_vec),
+            headers,
+            None,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn body_not_allowed_test() {
+        let default = TaskValidation::new();
+        for method in ["GET", "head", "DELETE"] {
+            let err = default
+                .check(&task(method, Some(b"{}"), false))
+                .unwrap_err();
+            assert!(
+                matches!(err, Error::BodyNotAllowed { method: m } if m == method.to_ascii_uppercase())
+            );
+            // an empty body is no body
+            assert!(default
+                .check(&task(method, Some(b""), false))
+                .unwrap()
+                .is_empty());
+            assert!(default
+                .check(&task(method, None, false))
+                .unwrap()
+                .is_empty());
+        }
+        for method in BODY_METHODS {
+            assert!(default
+                .check(&task(method, Some(b"{}"), true))
+                .unwrap()
+                .is_empty());
+        }
+
+        let lenient = TaskValidation::new().allow_body_with_any_method();
+        assert!(lenient
+            .check(&task("GET", Some(b"{}"), false))
+            .unwrap()
+            .is_empty());
+        assert!(TaskValidation::new()
+            .check(&Task::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn empty_body_test() {
+        let default = TaskValidation::new();
+        assert_eq!(
+            default.check(&task("PUT", None, true)).unwrap(),
+            [TaskWarning::EmptyBodyWithContentType {
+                method: "PUT".to_owned()
+            }]
+        );
+        // no Content-Type, no body expected
+        assert!(default
+            .check(&task("POST", None, false))
+            .unwrap()
+            .is_empty());
+        // a GET can't have a body, its Content-Type is harmless
+        assert!(default.check(&task("GET", None, true)).unwrap().is_empty());
+
+        let strict = TaskValidation::new().strict();
+        let err = strict.check(&task("POST", Some(b""), true)).unwrap_err();
+        assert_eq!(err.code(), crate::ErrorCode::InvalidInput);
+        assert!(strict
+            .check(&task("POST", Some(b"{}"), true))
+            .unwrap()
+            .is_empty());
+    }
+}
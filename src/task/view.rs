@@ -0,0 +1,64 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::Error;
+
+/// How much of a task the API returns, `response_view` in the API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TaskView {
+    /// everything but the request headers and body
+    #[default]
+    Basic,
+    /// the whole task, reading it needs the `cloudtasks.tasks.fullView` permission
+    Full,
+}
+
+impl TaskView {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskView::Basic => "BASIC",
+            TaskView::Full => "FULL",
+        }
+    }
+}
+
+impl fmt::Display for TaskView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<TaskView> for String {
+    fn from(view: TaskView) -> Self {
+        view.as_str().to_owned()
+    }
+}
+
+/// the API names, case insensitive
+impl FromStr for TaskView {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [TaskView::Basic, TaskView::Full]
+            .into_iter()
+            .find(|v| v.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::InvalidView(s.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+
+    #[test]
+    fn task_view_test() {
+        assert_eq!("FULL".parse::<TaskView>().unwrap(), TaskView::Full);
+        assert_eq!("basic".parse::<TaskView>().unwrap(), TaskView::Basic);
+        assert_eq!(String::from(TaskView::Full), "FULL");
+
+        let err = "FUL".parse::<TaskView>().unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert!(err.to_string().contains("\"FUL\""), "{err}");
+    }
+}
@@ -0,0 +1,34 @@
+//! In-memory implementations of the helper traits
+//!
+//! Meant for tests of code built on this crate, enable the `testing` feature to use them.
+//! Writes are atomic and preconditions are checked like the providers do, so races between
+//! concurrent callers resolve the same way as against the real services.
+//!
+//! Every mock records its calls in a [`MockStats`], which also injects latency and failures:
+//!
+//! ```ignore
+//! let storage = MemoryStorage::new();
+//! storage.mock_stats().set_fault(
+//!     "upload_from_bytes",
+//!     Fault::new().fail(0.01, ErrorCode::RateLimited),
+//! );
+//! // ...
+//! assert_eq!(storage.stats().calls("upload_from_bytes"), 1000);
+//! ```
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+mod secret;
+mod stats;
+mod storage;
+#[cfg(feature = "gcp-tasks")]
+mod task;
+
+#[cfg(any(feature = "gcp-secrets", feature = "aws-secrets"))]
+pub use secret::MemorySecretManager;
+pub use stats::{
+    Fault, Latency, MockStats, MockStatsSnapshot, OperationRecord, OperationStats, ANY_OPERATION,
+    DEFAULT_RECENT_CAPACITY,
+};
+pub use storage::MemoryStorage;
+#[cfg(feature = "gcp-tasks")]
+pub use task::MemoryCloudTasks;
@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "gcp-secrets")]
+use google_secretmanager1::{
+    hyper::client::HttpConnector, hyper_rustls::HttpsConnector,
+    oauth2::authenticator::Authenticator,
+};
+
+use super::{MockStats, MockStatsSnapshot};
+use crate::secret::{Error, SecretManagerHelper};
+use crate::{ErrorCode, NimbusError};
+
+/// versions by project and secret, oldest first, `None` once destroyed
+type Versions = HashMap<(String, String), Vec<Option<Vec<u8>>>>;
+
+/// [`SecretManagerHelper`] keeping the versions of each secret in memory
+///
+/// Versions are numbered from 1, `latest` is the last one added. Clones share their secrets.
+/// Destroyed versions keep their number and can't be read anymore, like on GCP.
+/// Debug shows the secrets and their number of versions, never a value.
+#[derive(Clone, Default)]
+pub struct MemorySecretManager {
+    secrets: Arc<Mutex<Versions>>,
+    stats: MockStats,
+}
+
+impl fmt::Debug for MemorySecretManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secrets = self.secrets.lock().unwrap();
+        let versions: BTreeMap<_, _> = secrets
+            .iter()
+            .map(|((project, secret), versions)| (format!("{project}/{secret}"), versions.len()))
+            .collect();
+        f.debug_struct("MemorySecretManager")
+            .field("versions", &versions)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl MemorySecretManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record calls in `stats`, shared with other mocks
+    pub fn with_stats(mut self, stats: MockStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// statistics of the calls, also used to inject faults
+    pub fn mock_stats(&self) -> &MockStats {
+        &self.stats
+    }
+
+    pub fn stats(&self) -> MockStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// add a version to a secret, created if missing, returns the version number
+    pub fn add_version(&self, project: &str, secret: &str, data: impl Into<Vec<u8>>) -> usize {
+        let mut secrets = self.secrets.lock().unwrap();
+        let versions = secrets
+            .entry((project.to_owned(), secret.to_owned()))
+            .or_default();
+        versions.push(Some(data.into()));
+        versions.len()
+    }
+
+    /// destroy a version of a secret, returns false if it doesn't exist
+    pub fn destroy_version(&self, project: &str, secret: &str, version: usize) -> bool {
+        let mut secrets = self.secrets.lock().unwrap();
+        let version = secrets
+            .get_mut(&(project.to_owned(), secret.to_owned()))
+            .and_then(|versions| versions.get_mut(version.checked_sub(1)?));
+        match version {
+            Some(data) => data.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// record a call, failing it if a fault was injected
+    async fn enter(&self, operation: &'static str, bytes: u64) -> Result<(), NimbusError> {
+        self.stats
+            .call(operation, bytes)
+            .await
+            .map_err(|code| injected(operation, code).into())
+    }
+
+    fn create(&self, project: &str, secret: &str, data: &[u8]) -> Result<(), NimbusError> {
+        let mut secrets = self.secrets.lock().unwrap();
+        let id = (project.to_owned(), secret.to_owned());
+        if secrets.contains_key(&id) {
+            return Err(
+                Error::AlreadyExists(format!("projects/{project}/secrets/{secret}")).into(),
+            );
+        }
+        secrets.insert(id, vec![Some(data.to_vec())]);
+
+        Ok(())
+    }
+
+    fn version(&self, project: &str, secret: &str, version: &str) -> Option<Vec<u8>> {
+        let secrets = self.secrets.lock().unwrap();
+        let versions = secrets.get(&(project.to_owned(), secret.to_owned()))?;
+        let data = match version {
+            "latest" => versions.last(),
+            n => n
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| n.checked_sub(1))
+                .and_then(|i| versions.get(i)),
+        };
+
+        data.cloned().flatten()
+    }
+}
+
+/// error of a call failed by an injected fault
+fn injected(operation: &str, code: ErrorCode) -> Error {
+    let message = format!("injected failure of {operation}");
+    match code {
+        ErrorCode::NotFound => Error::NotFound(message),
+        ErrorCode::AlreadyExists => Error::AlreadyExists(message),
+        ErrorCode::PermissionDenied => Error::PermissionDenied(message),
+        ErrorCode::RateLimited => Error::RateLimited {
+            message,
+            retry_after: None,
+        },
+        ErrorCode::Timeout => Error::Timeout(message),
+        ErrorCode::Unavailable => Error::Unavailable(message),
+        _ => Error::Other(message),
+    }
+}
+
+#[cfg(feature = "gcp-secrets")]
+type Connector = HttpsConnector<HttpConnector>;
+#[cfg(feature = "aws-secrets")]
+type Connector = ();
+
+#[async_trait::async_trait]
+impl SecretManagerHelper<Connector> for MemorySecretManager {
+    /// the authenticator is not used
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_authenticator(_: Authenticator<Connector>) -> Self {
+        Self::new()
+    }
+
+    #[cfg(feature = "aws-secrets")]
+    async fn new_with_authenticator() -> Self {
+        Self::new()
+    }
+
+    async fn get_secret(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
+        self.get_secret_version(project, secret, "latest").await
+    }
+
+    async fn create_secret(
+        &self,
+        project: &str,
+        secret_name: &str,
+        secret_val: &str,
+    ) -> Result<(), NimbusError> {
+        self.enter("create_secret", secret_val.len() as u64).await?;
+        self.create(project, secret_name, secret_val.as_bytes())
+    }
+
+    async fn create_secret_bytes(
+        &self,
+        project: &str,
+        secret_name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError> {
+        self.enter("create_secret_bytes", data.len() as u64).await?;
+        self.create(project, secret_name, data)
+    }
+
+    async fn delete_secret(&self, project: &str, secret: &str) -> Result<(), NimbusError> {
+        self.enter("delete_secret", 0).await?;
+
+        let mut secrets = self.secrets.lock().unwrap();
+        match secrets.remove(&(project.to_owned(), secret.to_owned())) {
+            Some(_) => Ok(()),
+            None => Err(Error::NotFound(format!("projects/{project}/secrets/{secret}")).into()),
+        }
+    }
+
+    async fn latest_version_id(&self, project: &str, secret: &str) -> Result<String, NimbusError> {
+        self.enter("latest_version_id", 0).await?;
+
+        let secrets = self.secrets.lock().unwrap();
+        match secrets.get(&(project.to_owned(), secret.to_owned())) {
+            Some(versions) => Ok(versions.len().to_string()),
+            None => Err(Error::NotFound(format!("projects/{project}/secrets/{secret}")).into()),
+        }
+    }
+
+    /// `version` is a version number or `latest`
+    async fn get_secret_version(
+        &self,
+        project: &str,
+        secret: &str,
+        version: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.stats
+            .call_object("get_secret_version", Some(secret), 0)
+            .await
+            .map_err(|code| NimbusError::from(injected("get_secret_version", code)))?;
+
+        let data = self.version(project, secret, version).ok_or_else(|| {
+            Error::NotFound(format!(
+                "projects/{project}/secrets/{secret}/versions/{version}"
+            ))
+        })?;
+        self.stats
+            .add_bytes("get_secret_version", data.len() as u64);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fault;
+
+    #[tokio::test]
+    async fn memory_secret_manager_test() {
+        let secrets = MemorySecretManager::new();
+        secrets.create_secret("p", "s", "one").await.unwrap();
+        assert_eq!(secrets.add_version("p", "s", "two"), 2);
+
+        assert_eq!(secrets.get_secret("p", "s").await.unwrap(), b"two");
+        assert_eq!(
+            secrets.get_secret_version("p", "s", "1").await.unwrap(),
+            b"one"
+        );
+
+        let exists = secrets.create_secret("p", "s", "three").await.unwrap_err();
+        assert_eq!(exists.code(), ErrorCode::AlreadyExists);
+        for version in ["0", "3", "first"] {
+            let missing = secrets
+                .get_secret_version("p", "s", version)
+                .await
+                .unwrap_err();
+            assert_eq!(missing.code(), ErrorCode::NotFound);
+        }
+
+        secrets.mock_stats().set_fault(
+            "get_secret_version",
+            Fault::new().fail(1.0, ErrorCode::PermissionDenied),
+        );
+        let denied = secrets.get_secret("p", "s").await.unwrap_err();
+        assert_eq!(denied.code(), ErrorCode::PermissionDenied);
+
+        let stats = secrets.stats();
+        assert_eq!(stats.calls("get_secret_version"), 6);
+        assert_eq!(stats.operations["get_secret_version"].bytes, 6);
+        assert_eq!(stats.total_failures(), 1);
+    }
+}
@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::ErrorCode;
+
+/// Operations kept in [`MockStatsSnapshot::recent`] by default
+pub const DEFAULT_RECENT_CAPACITY: usize = 1024;
+
+/// Operation name matching every operation in [`MockStats::set_fault`]
+pub const ANY_OPERATION: &str = "*";
+
+/// latencies kept per operation for its quantiles, a uniform sample of the calls past that
+const LATENCY_SAMPLES: usize = 1024;
+
+/// Simulated latency of an operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// uniformly distributed in `[min, max]`
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// exponentially distributed, a long tail around `mean`
+    Exponential {
+        mean: Duration,
+    },
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Fixed(Duration::ZERO)
+    }
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut SplitMix64) -> Duration {
+        match *self {
+            Latency::Fixed(d) => d,
+            Latency::Uniform { min, max } => {
+                let (min, max) = (min.min(max), min.max(max));
+                min + (max - min).mul_f64(rng.next_f64())
+            }
+            // inverse transform, 1 - u is in (0, 1]
+            Latency::Exponential { mean } => mean.mul_f64(-(1.0 - rng.next_f64()).ln()),
+        }
+    }
+}
+
+/// Latency and failures injected into the calls of an operation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fault {
+    pub latency: Latency,
+    /// share of the calls failing, from 0 to 1
+    pub failure_rate: f64,
+    /// classification of the injected failures, [`ErrorCode::Unavailable`] when `None`
+    pub error: Option<ErrorCode>,
+}
+
+impl Fault {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// fail `rate` of the calls (from 0 to 1) with an error classified as `error`
+    pub fn fail(mut self, rate: f64, error: ErrorCode) -> Self {
+        self.failure_rate = rate;
+        self.error = Some(error);
+        self
+    }
+}
+
+/// Counters of one operation in a [`MockStatsSnapshot`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OperationStats {
+    pub calls: u64,
+    /// calls failed by an injected fault
+    pub failures: u64,
+    /// payload bytes written or read
+    pub bytes: u64,
+    /// quantiles of the simulated latency, over a sample of the calls once there are many
+    pub latency_p50: Duration,
+    pub latency_p99: Duration,
+    pub latency_max: Duration,
+}
+
+/// A call recorded in [`MockStatsSnapshot::recent`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OperationRecord {
+    pub operation: String,
+    pub at: DateTime<Utc>,
+    pub latency: Duration,
+    pub failed: bool,
+}
+
+/// Copy of the statistics of a [`MockStats`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MockStatsSnapshot {
+    /// counters by operation, named after the trait methods e.g. `upload_from_bytes`
+    pub operations: BTreeMap<String, OperationStats>,
+    /// latest calls, oldest first
+    pub recent: Vec<OperationRecord>,
+}
+
+impl MockStatsSnapshot {
+    /// calls of `operation`
+    pub fn calls(&self, operation: &str) -> u64 {
+        self.operations.get(operation).map_or(0, |o| o.calls)
+    }
+
+    /// calls of every operation
+    pub fn total_calls(&self) -> u64 {
+        self.operations.values().map(|o| o.calls).sum()
+    }
+
+    /// failed calls of every operation
+    pub fn total_failures(&self) -> u64 {
+        self.operations.values().map(|o| o.failures).sum()
+    }
+
+    /// the counters in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: fn(&OperationStats) -> u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            for (op, stats) in &self.operations {
+                let _ = writeln!(out, "{name}{{operation=\"{op}\"}} {}", value(stats));
+            }
+        };
+        counter("nimbus_mock_calls_total", "Calls to the mock", |o| o.calls);
+        counter("nimbus_mock_failures_total", "Injected failures", |o| {
+            o.failures
+        });
+        counter("nimbus_mock_bytes_total", "Payload bytes", |o| o.bytes);
+
+        let name = "nimbus_mock_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Simulated latency\n# TYPE {name} summary"
+        );
+        for (op, stats) in &self.operations {
+            for (quantile, latency) in [("0.5", stats.latency_p50), ("0.99", stats.latency_p99)] {
+                let _ = writeln!(
+                    out,
+                    "{name}{{operation=\"{op}\",quantile=\"{quantile}\"}} {}",
+                    latency.as_secs_f64()
+                );
+            }
+            let _ = writeln!(out, "{name}_count{{operation=\"{op}\"}} {}", stats.calls);
+        }
+
+        out
+    }
+}
+
+/// Statistics and fault injection shared by the in-memory mocks
+///
+/// Clones share their state, pass the same `MockStats` to several mocks to aggregate them.
+/// Latencies and failures are drawn from a seeded generator, so a sequence of calls is
+/// reproducible; concurrent calls draw in scheduling order.
+#[derive(Debug, Clone)]
+pub struct MockStats {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    operations: BTreeMap<&'static str, Counters>,
+    recent: VecDeque<OperationRecord>,
+    recent_capacity: usize,
+    faults: HashMap<String, Fault>,
+    object_faults: HashMap<String, Fault>,
+    rng: SplitMix64,
+    /// draws the latency samples, apart from `rng` so sampling doesn't change the injected faults
+    sampler: SplitMix64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    calls: u64,
+    failures: u64,
+    bytes: u64,
+    /// at most [`LATENCY_SAMPLES`], reservoir sampled
+    latencies: Vec<Duration>,
+    latency_max: Duration,
+}
+
+impl Counters {
+    /// record the latency of the `calls`th call
+    fn sample_latency(&mut self, latency: Duration, sampler: &mut SplitMix64) {
+        self.latency_max = self.latency_max.max(latency);
+        if self.latencies.len() < LATENCY_SAMPLES {
+            self.latencies.push(latency);
+            return;
+        }
+        // every call so far has the same chance to be in the sample
+        let slot = (sampler.next_f64() * self.calls as f64) as usize;
+        if let Some(sample) = self.latencies.get_mut(slot) {
+            *sample = latency;
+        }
+    }
+}
+
+impl Default for MockStats {
+    fn default() -> Self {
+        Self::with_seed(0)
+    }
+}
+
+impl MockStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// statistics drawing injected faults from a generator seeded with `seed`
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                operations: BTreeMap::new(),
+                recent: VecDeque::new(),
+                recent_capacity: DEFAULT_RECENT_CAPACITY,
+                faults: HashMap::new(),
+                object_faults: HashMap::new(),
+                rng: SplitMix64(seed),
+                sampler: SplitMix64(!seed),
+            })),
+        }
+    }
+
+    /// keep the latest `capacity` calls in [`MockStatsSnapshot::recent`]
+    pub fn with_recent_capacity(self, capacity: usize) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.recent_capacity = capacity;
+            let excess = state.recent.len().saturating_sub(capacity);
+            state.recent.drain(..excess);
+        }
+        self
+    }
+
+    /// inject `fault` into the calls of `operation`, or of every operation without a fault of its own
+    /// for [`ANY_OPERATION`]
+    pub fn set_fault(&self, operation: &str, fault: Fault) {
+        self.state
+            .lock()
+            .unwrap()
+            .faults
+            .insert(operation.to_owned(), fault);
+    }
+
+    /// inject `fault` into the calls on objects or secrets named `key`, in place of the fault of the
+    /// operation: the downloads of `MemoryStorage`, in any bucket, and the reads of `MemorySecretManager`,
+    /// in any project
+    pub fn set_object_fault(&self, key: &str, fault: Fault) {
+        self.state
+            .lock()
+            .unwrap()
+            .object_faults
+            .insert(key.to_owned(), fault);
+    }
+
+    pub fn clear_faults(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.faults.clear();
+        state.object_faults.clear();
+    }
+
+    pub fn snapshot(&self) -> MockStatsSnapshot {
+        let state = self.state.lock().unwrap();
+
+        let operations = state
+            .operations
+            .iter()
+            .map(|(op, c)| {
+                let mut latencies = c.latencies.clone();
+                latencies.sort();
+
+                let stats = OperationStats {
+                    calls: c.calls,
+                    failures: c.failures,
+                    bytes: c.bytes,
+                    latency_p50: quantile(&latencies, 0.5),
+                    latency_p99: quantile(&latencies, 0.99),
+                    latency_max: c.latency_max,
+                };
+                (op.to_string(), stats)
+            })
+            .collect();
+
+        MockStatsSnapshot {
+            operations,
+            recent: state.recent.iter().cloned().collect(),
+        }
+    }
+
+    /// forget the recorded calls, faults and the generator state are kept
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.operations.clear();
+        state.recent.clear();
+    }
+
+    /// record a call of `operation` carrying `bytes` of payload and apply its fault:
+    /// wait for the simulated latency, then fail with the injected error code if the call was drawn to fail
+    pub(crate) async fn call(&self, operation: &'static str, bytes: u64) -> Result<(), ErrorCode> {
+        self.call_object(operation, None, bytes).await
+    }
+
+    /// [`MockStats::call`] on the object named `key`, applying its fault if it has one
+    pub(crate) async fn call_object(
+        &self,
+        operation: &'static str,
+        key: Option<&str>,
+        bytes: u64,
+    ) -> Result<(), ErrorCode> {
+        let (latency, failure) = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+
+            let fault = key
+                .and_then(|key| state.object_faults.get(key))
+                .or_else(|| state.faults.get(operation))
+                .or_else(|| state.faults.get(ANY_OPERATION))
+                .cloned()
+                .unwrap_or_default();
+            let latency = fault.latency.sample(&mut state.rng);
+            let failed = fault.failure_rate > 0.0 && state.rng.next_f64() < fault.failure_rate;
+            let failure = failed.then(|| fault.error.unwrap_or(ErrorCode::Unavailable));
+
+            let counters = state.operations.entry(operation).or_default();
+            counters.calls += 1;
+            counters.sample_latency(latency, &mut state.sampler);
+            if failed {
+                counters.failures += 1;
+            } else {
+                counters.bytes += bytes;
+            }
+
+            if state.recent_capacity > 0 {
+                if state.recent.len() == state.recent_capacity {
+                    state.recent.pop_front();
+                }
+                state.recent.push_back(OperationRecord {
+                    operation: operation.to_owned(),
+                    at: Utc::now(),
+                    latency,
+                    failed,
+                });
+            }
+
+            (latency, failure)
+        };
+
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        match failure {
+            Some(code) => Err(code),
+            None => Ok(()),
+        }
+    }
+
+    /// add the payload read by a successful call of `operation`
+    pub(crate) fn add_bytes(&self, operation: &'static str, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.operations.entry(operation).or_default().bytes += bytes;
+    }
+}
+
+/// nearest rank quantile of sorted values
+fn quantile(sorted: &[Duration], q: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// small seedable generator, statistical quality is plenty for fault injection
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// uniform in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantile_test() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+        assert_eq!(quantile(&sorted, 0.5), Duration::from_millis(50));
+        assert_eq!(quantile(&sorted, 0.99), Duration::from_millis(99));
+        assert_eq!(quantile(&sorted, 1.0), Duration::from_millis(100));
+        assert_eq!(quantile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn latency_sample_test() {
+        let mut rng = SplitMix64(7);
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(20));
+
+        for _ in 0..1000 {
+            let d = Latency::Uniform { min, max }.sample(&mut rng);
+            assert!(d >= min && d <= max);
+        }
+
+        let mean = Duration::from_millis(10);
+        let total: Duration = (0..10_000)
+            .map(|_| Latency::Exponential { mean }.sample(&mut rng))
+            .sum();
+        let observed = total / 10_000;
+        assert!(observed > mean.mul_f64(0.9) && observed < mean.mul_f64(1.1));
+    }
+
+    #[tokio::test]
+    async fn seeded_failures_are_reproducible_test() {
+        async fn run(seed: u64) -> Vec<bool> {
+            let stats = MockStats::with_seed(seed);
+            stats.set_fault(
+                "upload_from_bytes",
+                Fault::new().fail(0.3, ErrorCode::RateLimited),
+            );
+
+            let mut failed = vec![];
+            for _ in 0..200 {
+                let res = stats.call("upload_from_bytes", 10).await;
+                assert!(matches!(res, Ok(()) | Err(ErrorCode::RateLimited)));
+                failed.push(res.is_err());
+            }
+            failed
+        }
+
+        let first = run(42).await;
+        assert_eq!(first, run(42).await);
+        assert_ne!(first, run(43).await);
+
+        let failures = first.iter().filter(|f| **f).count();
+        assert!((30..90).contains(&failures), "{failures} failures");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_sample_bounded_test() {
+        let stats = MockStats::new();
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(20));
+        stats.set_fault(
+            "download_to_bytes",
+            Fault::new().latency(Latency::Uniform { min, max }),
+        );
+
+        for _ in 0..10 * LATENCY_SAMPLES {
+            stats.call("download_to_bytes", 0).await.unwrap();
+        }
+
+        let state = stats.state.lock().unwrap();
+        let counters = &state.operations["download_to_bytes"];
+        assert_eq!(counters.latencies.len(), LATENCY_SAMPLES);
+        drop(state);
+        let op = &stats.snapshot().operations["download_to_bytes"];
+        assert_eq!(op.calls, 10 * LATENCY_SAMPLES as u64);
+        // the sample stays representative of every call
+        let mid = Duration::from_millis(15);
+        assert!(op.latency_p50.abs_diff(mid) < Duration::from_millis(1));
+        assert!(op.latency_p99 > Duration::from_micros(19_700) && op.latency_p99 <= max);
+        assert!(op.latency_max >= op.latency_p99 && op.latency_max <= max);
+    }
+
+    #[tokio::test]
+    async fn snapshot_test() {
+        let stats = MockStats::new().with_recent_capacity(3);
+        stats.set_fault(
+            ANY_OPERATION,
+            Fault::new().latency(Latency::Fixed(Duration::from_millis(1))),
+        );
+
+        for _ in 0..4 {
+            stats.call("upload_from_bytes", 100).await.unwrap();
+        }
+        stats.call("download_to_bytes", 0).await.unwrap();
+        stats.add_bytes("download_to_bytes", 100);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.calls("upload_from_bytes"), 4);
+        assert_eq!(snapshot.total_calls(), 5);
+        assert_eq!(snapshot.operations["upload_from_bytes"].bytes, 400);
+        assert_eq!(snapshot.operations["download_to_bytes"].bytes, 100);
+        assert_eq!(
+            snapshot.operations["upload_from_bytes"].latency_p99,
+            Duration::from_millis(1)
+        );
+        assert_eq!(snapshot.recent.len(), 3);
+        assert_eq!(snapshot.recent[2].operation, "download_to_bytes");
+
+        let prometheus = snapshot.to_prometheus();
+        assert!(prometheus.contains("nimbus_mock_calls_total{operation=\"upload_from_bytes\"} 4"));
+        assert!(prometheus.contains(
+            "nimbus_mock_latency_seconds{operation=\"upload_from_bytes\",quantile=\"0.99\"} 0.001"
+        ));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&snapshot).unwrap();
+            assert_eq!(json["operations"]["upload_from_bytes"]["calls"], 4);
+        }
+
+        stats.reset();
+        assert_eq!(stats.snapshot(), MockStatsSnapshot::default());
+    }
+}
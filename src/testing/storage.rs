@@ -0,0 +1,2130 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+
+use super::{MockStats, MockStatsSnapshot};
+use crate::storage::{
+    crc32c_base64, range_header, rewrite_append, sha256_hex, unsigned_post_policy, validate_cors,
+    validate_expiry, AclEntry, AppendOutcome, CorsRule, Direction, EncryptionKey, Error, Exposure,
+    ListPage, ListParams, ObjectMeta, ObjectStream, PolicyCondition, PostPolicy, Precondition,
+    PublicAccess, ReplicationState, ReplicationStatus, ResumableUpload, Session, StorageClass,
+    StorageHelper, TrafficMeter, UpdateOptions,
+};
+use crate::{ByteSize, ErrorCode, ListLimits, NimbusError};
+
+/// bytes per chunk of `download_stream`
+const MEMORY_DOWNLOAD_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+struct StoredObject {
+    /// shared by the clones of the object, streamed downloads hand out slices of it
+    data: Bytes,
+    content_type: Option<String>,
+    metadata: HashMap<String, String>,
+    tags: HashMap<String, String>,
+    acl: Vec<AclEntry>,
+    generation: i64,
+    updated: DateTime<Utc>,
+    /// SHA-256 of the customer-supplied key the object is encrypted with, as the providers keep it
+    key_sha256: Option<String>,
+    /// GCS name, none for the default class
+    storage_class: Option<String>,
+    /// as set with [`MemoryStorage::set_replication_status`], not configured when `None`
+    replication: Option<ReplicationStatus>,
+    /// as set with [`MemoryStorage::interrupt_downloads`]
+    interrupt_after: Option<u64>,
+}
+
+/// deleted objects by bucket and key, oldest first
+type Deleted = HashMap<(String, String), Vec<StoredObject>>;
+
+/// public access settings of a bucket
+#[derive(Debug, Clone, Copy, Default)]
+struct BucketAccess {
+    public: bool,
+    object_acls: bool,
+}
+
+/// [`StorageHelper`] keeping objects in memory
+///
+/// Versions are generation numbers like on GCS. Deleted objects stay restorable like with GCS soft delete,
+/// for as long as the storage lives. Clones share their objects.
+/// Buckets are private with object ACLs disabled, like new buckets on both providers,
+/// until set otherwise with [`MemoryStorage::set_bucket_public`] and [`MemoryStorage::set_object_acls`].
+/// Listings return every entry in one page unless capped with [`MemoryStorage::with_max_page_size`].
+/// Transfers are counted on the installed [`TrafficMeter`] like the providers do, or on the storage's own
+/// (see [`MemoryStorage::with_traffic_meter`]); resumable uploads count each chunk, as an `upload_chunk` call.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    objects: Arc<Mutex<HashMap<(String, String), StoredObject>>>,
+    generation: Arc<AtomicI64>,
+    deleted: Arc<Mutex<Deleted>>,
+    access: Arc<Mutex<HashMap<String, BucketAccess>>>,
+    cors: Arc<Mutex<HashMap<String, Vec<CorsRule>>>>,
+    stats: MockStats,
+    traffic: Option<TrafficMeter>,
+    bare_listings: bool,
+    max_page_size: Option<usize>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record calls in `stats`, shared with other mocks
+    pub fn with_stats(mut self, stats: MockStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// count transfers on `meter` instead of the installed one
+    pub fn with_traffic_meter(mut self, meter: TrafficMeter) -> Self {
+        self.traffic = Some(meter);
+        self
+    }
+
+    /// leave the checksums out of listings, like S3 listings
+    pub fn without_listing_checksums(mut self) -> Self {
+        self.bare_listings = true;
+        self
+    }
+
+    /// end listing pages after `max` entries whatever the page size asked, like the 1000 of both providers
+    pub fn with_max_page_size(mut self, max: usize) -> Self {
+        self.max_page_size = Some(max.max(1));
+        self
+    }
+
+    /// statistics of the calls, also used to inject faults
+    pub fn mock_stats(&self) -> &MockStats {
+        &self.stats
+    }
+
+    pub fn stats(&self) -> MockStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// grant or revoke public access to the whole bucket
+    pub fn set_bucket_public(&self, bucket: &str, public: bool) {
+        let mut access = self.access.lock().unwrap();
+        access.entry(bucket.to_owned()).or_default().public = public;
+    }
+
+    /// enable or disable object ACLs, like turning uniform bucket-level access off or on
+    pub fn set_object_acls(&self, bucket: &str, enabled: bool) {
+        let mut access = self.access.lock().unwrap();
+        access.entry(bucket.to_owned()).or_default().object_acls = enabled;
+    }
+
+    /// replace the ACL of an object, returns false if it doesn't exist
+    /// the ACL is reset when the object is overwritten
+    pub fn set_object_acl(&self, bucket: &str, key: &str, acl: Vec<AclEntry>) -> bool {
+        let mut objects = self.objects.lock().unwrap();
+        match objects.get_mut(&(bucket.to_owned(), key.to_owned())) {
+            Some(object) => {
+                object.acl = acl;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// set the replication status reported for an object, returns false if it doesn't exist
+    /// the status is reset to not configured when the object is overwritten
+    pub fn set_replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+        status: ReplicationStatus,
+    ) -> bool {
+        let mut objects = self.objects.lock().unwrap();
+        match objects.get_mut(&(bucket.to_owned(), key.to_owned())) {
+            Some(object) => {
+                object.replication = Some(status);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// make the streamed downloads of an object fail with [`ErrorCode::Unavailable`] after `after` bytes,
+    /// like on a dropped connection, until it's overwritten; returns whether the object exists
+    pub fn interrupt_downloads(&self, bucket: &str, key: &str, after: u64) -> bool {
+        let mut objects = self.objects.lock().unwrap();
+        match objects.get_mut(&(bucket.to_owned(), key.to_owned())) {
+            Some(object) => {
+                object.interrupt_after = Some(after);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bucket_access(&self, bucket: &str) -> BucketAccess {
+        let access = self.access.lock().unwrap();
+        access.get(bucket).copied().unwrap_or_default()
+    }
+
+    /// record a call, failing it if a fault was injected
+    pub(crate) async fn enter(
+        &self,
+        operation: &'static str,
+        bytes: u64,
+    ) -> Result<(), NimbusError> {
+        self.stats
+            .call(operation, bytes)
+            .await
+            .map_err(|code| injected(operation, code).into())
+    }
+
+    /// [`MemoryStorage::enter`] for a download of `key`, subject to its [`MockStats::set_object_fault`]
+    pub(crate) async fn enter_download(
+        &self,
+        operation: &'static str,
+        key: &str,
+    ) -> Result<(), NimbusError> {
+        self.stats
+            .call_object(operation, Some(key), 0)
+            .await
+            .map_err(|code| injected(operation, code).into())
+    }
+
+    /// record the bytes returned by a call
+    fn read(&self, operation: &'static str, bucket: &str, data: Bytes) -> Vec<u8> {
+        self.stats.add_bytes(operation, data.len() as u64);
+        self.record_traffic(bucket, Direction::Egress, data.len() as u64);
+        data.into()
+    }
+
+    pub(crate) fn record_traffic(&self, bucket: &str, direction: Direction, bytes: u64) {
+        match &self.traffic {
+            Some(meter) => meter.add(bucket, direction, bytes),
+            None => crate::storage::record_traffic(bucket, direction, bytes),
+        }
+    }
+
+    /// store the object of a resumable upload, whose chunks were counted as they were sent
+    pub(crate) async fn finish_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        self.enter("upload_from_bytes", data.len() as u64).await?;
+        self.store(bucket, key, mime, data, None);
+        Ok(())
+    }
+
+    fn store(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        key_sha256: Option<String>,
+    ) {
+        let object = StoredObject {
+            data: data.into(),
+            content_type: mime,
+            metadata: HashMap::new(),
+            tags: HashMap::new(),
+            acl: vec![],
+            generation: self.generation.fetch_add(1, Ordering::SeqCst) + 1,
+            updated: Utc::now(),
+            key_sha256,
+            storage_class: None,
+            replication: None,
+            interrupt_after: None,
+        };
+        self.objects
+            .lock()
+            .unwrap()
+            .insert((bucket.to_owned(), key.to_owned()), object);
+    }
+
+    /// write an object if `precondition` holds, as the conditional uploads do
+    fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        let mut objects = self.objects.lock().unwrap();
+        let id = (bucket.to_owned(), key.to_owned());
+
+        let current = objects.get(&id).map(|o| o.generation.to_string());
+        let holds = match precondition {
+            None => true,
+            Some(Precondition::DoesNotExist) => current.is_none(),
+            Some(Precondition::VersionMatches(version)) => current.as_ref() == Some(version),
+        };
+        if !holds {
+            return Err(Error::PreconditionFailed(format!("{bucket}/{key}")).into());
+        }
+
+        self.record_traffic(bucket, Direction::Ingress, data.len() as u64);
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        objects.insert(
+            id,
+            StoredObject {
+                data: data.into(),
+                content_type: mime,
+                metadata,
+                tags: HashMap::new(),
+                acl: vec![],
+                generation,
+                updated: Utc::now(),
+                key_sha256: None,
+                storage_class: None,
+                replication: None,
+                interrupt_after: None,
+            },
+        );
+
+        Ok(generation.to_string())
+    }
+
+    /// keep a deleted object restorable
+    fn soft_delete(&self, id: (String, String), object: StoredObject) {
+        self.deleted
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .push(object);
+    }
+
+    fn not_found(bucket: &str, key: &str) -> NimbusError {
+        Error::NotFound(format!("{bucket}/{key}")).into()
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Option<StoredObject> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&(bucket.to_owned(), key.to_owned()))
+            .cloned()
+    }
+
+    /// the object, if the call supplies its customer-supplied key when it has one
+    fn get_readable(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: Option<&EncryptionKey>,
+    ) -> Result<StoredObject, NimbusError> {
+        let object = self
+            .get(bucket, key)
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        if object.key_sha256 != encryption.map(EncryptionKey::sha256_base64) {
+            return Err(Error::EncryptionKeyRequired(format!("{bucket}/{key}")).into());
+        }
+        Ok(object)
+    }
+
+    /// checksums of encrypted objects are left out, like GCS does without the key
+    fn meta(key: &str, object: &StoredObject) -> ObjectMeta {
+        let plain = object.key_sha256.is_none();
+        ObjectMeta {
+            key: key.to_owned(),
+            size: object.data.len() as u64,
+            content_type: object.content_type.clone(),
+            content_encoding: None,
+            updated: Some(object.updated),
+            etag: Some(sha256_hex(&object.data)),
+            crc32c: plain.then(|| crc32c_base64(&object.data)),
+            md5: None,
+            version: Some(object.generation.to_string()),
+            storage_class: object.storage_class.clone(),
+            metadata: Some(object.metadata.clone()),
+        }
+    }
+}
+
+/// error of a call failed by an injected fault
+fn injected(operation: &str, code: ErrorCode) -> Error {
+    let message = format!("injected failure of {operation}");
+    match code {
+        ErrorCode::NotFound => Error::NotFound(message),
+        ErrorCode::PermissionDenied => Error::PermissionDenied(message),
+        ErrorCode::PreconditionFailed => Error::PreconditionFailed(message),
+        ErrorCode::RateLimited => Error::RateLimited {
+            message,
+            retry_after: None,
+        },
+        ErrorCode::Timeout => Error::Timeout(message),
+        ErrorCode::Unavailable => Error::Unavailable(message),
+        ErrorCode::Unsupported => Error::Unsupported(message),
+        _ => Error::Other(message),
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageHelper for MemoryStorage {
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_authenticator() -> Self {
+        Self::new()
+    }
+
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        self.enter("upload_from_bytes", data.len() as u64).await?;
+
+        self.record_traffic(bucket, Direction::Ingress, data.len() as u64);
+        self.store(bucket, key, mime, data, None);
+
+        Ok(())
+    }
+
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        self.enter_download("download_to_bytes", key).await?;
+
+        let object = self.get_readable(bucket, key, None)?;
+        Ok(self.read("download_to_bytes", bucket, object.data))
+    }
+
+    /// 64 KiB chunks, like a provider body arrives, each slices of the stored object
+    /// chunks are counted as read as the stream hands them out
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        self.enter_download("download_stream", key).await?;
+
+        let object = self.get_readable(bucket, key, None)?;
+        let interrupted = object
+            .interrupt_after
+            .filter(|after| *after < object.data.len() as u64)
+            .map(|after| {
+                let message = format!("download of {bucket}/{key} interrupted after {after} bytes");
+                (after as usize, Error::Unavailable(message).into())
+            });
+        let (storage, bucket, data) = (self.clone(), bucket.to_owned(), object.data);
+        let end = interrupted.as_ref().map_or(data.len(), |(after, _)| *after);
+        let chunks = (0..end)
+            .step_by(MEMORY_DOWNLOAD_CHUNK)
+            .map(move |start| Ok(data.slice(start..(start + MEMORY_DOWNLOAD_CHUNK).min(end))))
+            .chain(interrupted.map(|(_, e)| Err(e)));
+        let stream = futures::stream::iter(chunks).inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                storage
+                    .stats
+                    .add_bytes("download_stream", chunk.len() as u64);
+                storage.record_traffic(&bucket, Direction::Egress, chunk.len() as u64);
+            }
+        });
+
+        Ok(stream.boxed())
+    }
+
+    /// objects are stored without encoding
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        _: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.enter_download("download_with_encoding", key).await?;
+
+        let object = self.get_readable(bucket, key, None)?;
+        Ok(self.read("download_with_encoding", bucket, object.data))
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.enter_download("download_range", key).await?;
+        range_header(start, end)?;
+
+        let object = self.get_readable(bucket, key, None)?;
+        let size = object.data.len() as u64;
+        if start >= size {
+            return Err(
+                Error::Other(format!("range {start}.. of {bucket}/{key} is past its end")).into(),
+            );
+        }
+        let end = end.map_or(size, |end| end.saturating_add(1).min(size));
+        let data = object.data.slice(start as usize..end as usize);
+        Ok(self.read("download_range", bucket, data))
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        self.enter("upload_encrypted", data.len() as u64).await?;
+
+        self.record_traffic(bucket, Direction::Ingress, data.len() as u64);
+        let key_sha256 = Some(encryption.sha256_base64());
+        self.store(bucket, key, mime, data, key_sha256);
+
+        Ok(())
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        self.enter_download("download_encrypted", key).await?;
+
+        let object = self.get_readable(bucket, key, Some(encryption))?;
+        Ok(self.read("download_encrypted", bucket, object.data))
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        self.enter("object_metadata_encrypted", 0).await?;
+
+        let object = self.get_readable(bucket, key, Some(encryption))?;
+        Ok(ObjectMeta {
+            crc32c: Some(crc32c_base64(&object.data)),
+            ..Self::meta(key, &object)
+        })
+    }
+
+    async fn copy_encrypted(
+        &self,
+        (source_bucket, source_key): (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        (destination_bucket, destination_key): (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        self.enter("copy_encrypted", 0).await?;
+
+        let source = self.get_readable(source_bucket, source_key, source_encryption)?;
+        let copy = StoredObject {
+            tags: HashMap::new(),
+            acl: vec![],
+            generation: self.generation.fetch_add(1, Ordering::SeqCst) + 1,
+            updated: Utc::now(),
+            key_sha256: destination_encryption.map(EncryptionKey::sha256_base64),
+            storage_class: None,
+            replication: None,
+            interrupt_after: None,
+            ..source
+        };
+        self.objects.lock().unwrap().insert(
+            (destination_bucket.to_owned(), destination_key.to_owned()),
+            copy,
+        );
+
+        Ok(())
+    }
+
+    /// a rewrite in place like on GCS, the object gets a new generation
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        self.enter("set_storage_class", 0).await?;
+
+        let mut objects = self.objects.lock().unwrap();
+        let object = objects
+            .get_mut(&(bucket.to_owned(), key.to_owned()))
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        object.storage_class = Some(class.gcs_name().to_owned());
+        object.generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        object.updated = Utc::now();
+
+        Ok(())
+    }
+
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        self.enter("delete_file", 0).await?;
+
+        let id = (bucket.to_owned(), key.to_owned());
+        let object = self
+            .objects
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        self.soft_delete(id, object);
+
+        Ok(())
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        self.enter("object_exists", 0).await?;
+
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .contains_key(&(bucket.to_owned(), key.to_owned())))
+    }
+
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        self.enter("upload_conditional", data.len() as u64).await?;
+        self.put(bucket, key, mime, HashMap::new(), data, Some(precondition))
+    }
+
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        self.enter("upload_with_metadata", data.len() as u64)
+            .await?;
+        self.put(bucket, key, mime, metadata, data, precondition)
+    }
+
+    /// downloaded and uploaded again, as S3 does for small objects
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        self.enter("append_bytes", data.len() as u64).await?;
+        rewrite_append(self, bucket, key, mime, data, &options).await
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        self.enter("delete_conditional", 0).await?;
+
+        let id = (bucket.to_owned(), key.to_owned());
+        let object = {
+            let mut objects = self.objects.lock().unwrap();
+            match objects.get(&id) {
+                None => return Err(Self::not_found(bucket, key)),
+                Some(o) if o.generation.to_string() != version => {
+                    return Err(Error::PreconditionFailed(format!("{bucket}/{key}")).into())
+                }
+                Some(_) => objects.remove(&id).expect("object exists"),
+            }
+        };
+        self.soft_delete(id, object);
+
+        Ok(())
+    }
+
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        self.enter_download("download_versioned", key).await?;
+
+        let object = self.get_readable(bucket, key, None)?;
+        let version = object.generation.to_string();
+        Ok((
+            self.read("download_versioned", bucket, object.data),
+            version,
+        ))
+    }
+
+    /// the page token is the last entry of the previous page, only live objects are listed
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        self.enter("list_page", 0).await?;
+
+        let prefix = params.prefix.as_deref().unwrap_or_default();
+        let delimiter = params.delimiter.as_deref().filter(|d| !d.is_empty());
+
+        // objects and common prefixes sorted together, `None` for a prefix
+        let mut entries = BTreeMap::new();
+        for ((b, key), object) in self.objects.lock().unwrap().iter() {
+            if b != bucket || !key.starts_with(prefix) || !params.in_bounds(key) {
+                continue;
+            }
+
+            let grouped = delimiter.and_then(|d| {
+                key[prefix.len()..]
+                    .find(d)
+                    .map(|i| &key[..prefix.len() + i + d.len()])
+            });
+            match grouped {
+                Some(common) => entries.insert(common.to_owned(), None),
+                None => {
+                    let mut meta = Self::meta(key, object);
+                    if self.bare_listings {
+                        meta.crc32c = None;
+                    }
+                    entries.insert(key.clone(), Some(meta))
+                }
+            };
+        }
+
+        // the bounds were applied to the keys above
+        let start = match page_token {
+            Some(token) => Bound::Excluded(token),
+            None => Bound::Unbounded,
+        };
+        let page_size = params
+            .page_size
+            .map_or(usize::MAX, |n| n.max(1) as usize)
+            .min(self.max_page_size.unwrap_or(usize::MAX));
+        let mut rest = entries.range((start, Bound::Unbounded)).peekable();
+
+        let mut page = ListPage::default();
+        let mut last = None;
+        for (entry, meta) in rest.by_ref().take(page_size) {
+            match meta {
+                Some(meta) => page.objects.push(meta.clone()),
+                None => page.prefixes.push(entry.clone()),
+            }
+            last = Some(entry.clone());
+        }
+        if rest.peek().is_some() {
+            page.next_page_token = last;
+        }
+
+        Ok(page)
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        self.enter("object_metadata", 0).await?;
+
+        Ok(Self::meta(
+            key,
+            &self
+                .get(bucket, key)
+                .ok_or_else(|| Self::not_found(bucket, key))?,
+        ))
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        self.enter("replication_status", 0).await?;
+
+        let object = self
+            .get(bucket, key)
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        Ok(object
+            .replication
+            .unwrap_or(ReplicationStatus::new(ReplicationState::NotConfigured)))
+    }
+
+    /// tags are kept until the object is overwritten, like on S3
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        self.enter("set_object_tags", 0).await?;
+
+        let mut objects = self.objects.lock().unwrap();
+        let object = objects
+            .get_mut(&(bucket.to_owned(), key.to_owned()))
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        object.tags = tags;
+
+        Ok(())
+    }
+
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        self.enter("get_object_tags", 0).await?;
+
+        let object = self
+            .get(bucket, key)
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        Ok(object.tags)
+    }
+
+    /// keys with their generations, sorted by key then generation
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        self.enter("list_soft_deleted", 0).await?;
+
+        let prefix = prefix.unwrap_or_default();
+        let mut deleted: Vec<(String, i64)> = self
+            .deleted
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((b, key), _)| b == bucket && key.starts_with(&prefix))
+            .flat_map(|((_, key), objects)| objects.iter().map(|o| (key.clone(), o.generation)))
+            .collect();
+        deleted.sort();
+        limits.check(deleted.len())?;
+
+        Ok(deleted)
+    }
+
+    /// the restored object gets a new generation, like on GCS
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        self.enter("restore_object", 0).await?;
+
+        let id = (bucket.to_owned(), key.to_owned());
+        let mut objects = self.objects.lock().unwrap();
+        let mut deleted = self.deleted.lock().unwrap();
+
+        let versions = deleted.get_mut(&id);
+        let index = versions
+            .as_ref()
+            .and_then(|v| v.iter().position(|o| o.generation == generation))
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+        if objects.contains_key(&id) {
+            return Err(Error::PreconditionFailed(format!("{bucket}/{key} is live")).into());
+        }
+
+        let versions = versions.expect("position found");
+        let object = versions.remove(index);
+        if versions.is_empty() {
+            deleted.remove(&id);
+        }
+        objects.insert(
+            id,
+            StoredObject {
+                generation: self.generation.fetch_add(1, Ordering::SeqCst) + 1,
+                updated: Utc::now(),
+                ..object
+            },
+        );
+
+        Ok(())
+    }
+
+    /// every permission is held
+    async fn test_permissions(
+        &self,
+        _: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        self.enter("test_permissions", 0).await?;
+
+        Ok(permissions.iter().map(|p| p.to_string()).collect())
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        self.enter("bucket_is_public", 0).await?;
+
+        let access = self.bucket_access(bucket);
+        Ok(Exposure {
+            granted: access.public,
+            object_acls: access.object_acls,
+            ..Default::default()
+        }
+        .verdict())
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        self.enter("object_acls_apply", 0).await?;
+
+        Ok(self.bucket_access(bucket).object_acls)
+    }
+
+    /// fails with [`Error::Unsupported`] while object ACLs are disabled, like GCS
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        self.enter("object_acl", 0).await?;
+
+        if !self.bucket_access(bucket).object_acls {
+            return Err(Error::Unsupported(format!("object ACLs are disabled on {bucket}")).into());
+        }
+        let object = self
+            .get(bucket, key)
+            .ok_or_else(|| Self::not_found(bucket, key))?;
+
+        Ok(object.acl)
+    }
+
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        self.enter("get_cors", 0).await?;
+
+        let cors = self.cors.lock().unwrap();
+        Ok(cors.get(bucket).cloned().unwrap_or_default())
+    }
+
+    /// the rules are kept as given, not normalized
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        self.enter("set_cors", 0).await?;
+        validate_cors(&rules)?;
+
+        let mut cors = self.cors.lock().unwrap();
+        cors.insert(bucket.to_owned(), rules);
+        Ok(())
+    }
+
+    /// the policy isn't signed, its url is `memory://{bucket}/`
+    async fn signed_post_policy(
+        &self,
+        bucket: &str,
+        key_prefix: &str,
+        max_size: ByteSize,
+        expires: Duration,
+        conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        self.enter("signed_post_policy", 0).await?;
+
+        Ok(unsigned_post_policy(
+            bucket,
+            key_prefix,
+            max_size,
+            expires,
+            &conditions,
+        )?)
+    }
+
+    /// the URL isn't signed, it is `memory://{bucket}/{key}?expires_in={seconds}`
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.enter("signed_download_url", 0).await?;
+        validate_expiry(expires_in)?;
+
+        Ok(format!(
+            "memory://{bucket}/{key}?expires_in={}",
+            expires_in.as_secs()
+        ))
+    }
+
+    /// the URL isn't signed, it is the one of [`StorageHelper::signed_download_url`]
+    /// followed by `&content_type={mime}`
+    async fn signed_upload_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        self.enter("signed_upload_url", 0).await?;
+        validate_expiry(expires_in)?;
+
+        let mut url = format!(
+            "memory://{bucket}/{key}?expires_in={}",
+            expires_in.as_secs()
+        );
+        if let Some(mime) = mime {
+            url.push_str("&content_type=");
+            url.push_str(&mime);
+        }
+        Ok(url)
+    }
+
+    /// chunks are kept aside until the upload finishes
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        self.enter("start_resumable_upload", 0).await?;
+
+        Ok(ResumableUpload::new(Session::Memory {
+            storage: self.clone(),
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            mime,
+            data: vec![],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::ProgressPhase;
+    use crate::testing::{Fault, Latency};
+    use crate::{CancellationToken, OpContext};
+
+    #[tokio::test]
+    async fn memory_storage_conditional_test() {
+        let storage = MemoryStorage::new();
+
+        let v1 = storage
+            .upload_conditional("b", "k", None, b"one".to_vec(), &Precondition::DoesNotExist)
+            .await
+            .unwrap();
+        assert!(!storage
+            .upload_if_absent("b", "k", None, b"two".to_vec())
+            .await
+            .unwrap());
+
+        let v2 = storage
+            .upload_conditional(
+                "b",
+                "k",
+                None,
+                b"two".to_vec(),
+                &Precondition::VersionMatches(v1.clone()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_versioned("b", "k").await.unwrap(),
+            (b"two".to_vec(), v2.clone())
+        );
+
+        let stale = storage.delete_conditional("b", "k", &v1).await.unwrap_err();
+        assert_eq!(stale.code(), ErrorCode::PreconditionFailed);
+        storage.delete_conditional("b", "k", &v2).await.unwrap();
+
+        let missing = storage.download_to_bytes("b", "k").await.unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_tags_test() {
+        let storage = MemoryStorage::new();
+        storage
+            .upload_from_bytes("b", "k", None, b"one".to_vec())
+            .await
+            .unwrap();
+
+        let tags = HashMap::from([("retention".to_owned(), "30d".to_owned())]);
+        storage
+            .set_object_tags("b", "k", tags.clone())
+            .await
+            .unwrap();
+        assert_eq!(storage.get_object_tags("b", "k").await.unwrap(), tags);
+
+        // an overwrite drops the tags
+        storage
+            .upload_from_bytes("b", "k", None, b"two".to_vec())
+            .await
+            .unwrap();
+        assert!(storage.get_object_tags("b", "k").await.unwrap().is_empty());
+
+        let missing = storage
+            .set_object_tags("b", "missing", tags)
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_soft_delete_test() {
+        let storage = MemoryStorage::new();
+        for key in ["a/1", "a/2", "b/1"] {
+            storage
+                .upload_from_bytes("b", key, None, key.as_bytes().to_vec())
+                .await
+                .unwrap();
+            storage.delete_file("b", key).await.unwrap();
+        }
+        storage
+            .upload_from_bytes("b", "a/1", None, b"again".to_vec())
+            .await
+            .unwrap();
+
+        let deleted = storage
+            .list_soft_deleted("b", Some("a/".to_owned()), ListLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(deleted, [("a/1".to_owned(), 1), ("a/2".to_owned(), 2)]);
+
+        // the key was written again
+        let live = storage.restore_object("b", "a/1", 1).await.unwrap_err();
+        assert_eq!(live.code(), ErrorCode::PreconditionFailed);
+
+        storage.restore_object("b", "a/2", 2).await.unwrap();
+        assert_eq!(storage.download_to_bytes("b", "a/2").await.unwrap(), b"a/2");
+        assert_ne!(
+            storage
+                .object_metadata("b", "a/2")
+                .await
+                .unwrap()
+                .version
+                .unwrap(),
+            "2"
+        );
+
+        let restored = storage.restore_object("b", "a/2", 2).await.unwrap_err();
+        assert_eq!(restored.code(), ErrorCode::NotFound);
+        assert_eq!(
+            storage
+                .list_soft_deleted("b", None, ListLimits::default())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        let err = storage
+            .list_soft_deleted("b", None, ListLimits::new().max_results(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NimbusError::ResultsTruncated { .. }));
+    }
+
+    #[tokio::test]
+    async fn memory_storage_post_policy_test() {
+        let storage = MemoryStorage::new();
+        let policy = storage
+            .signed_post_policy(
+                "b",
+                "uploads/",
+                ByteSize::kib(1),
+                Duration::from_secs(60),
+                vec![PolicyCondition::content_type("image/png")],
+            )
+            .await
+            .unwrap();
+        assert_eq!(policy.url, "memory://b/");
+        assert_eq!(policy.fields["key"], "uploads/${filename}");
+        assert_eq!(policy.fields["Content-Type"], "image/png");
+        assert!(policy.fields.contains_key("policy"));
+
+        let err = storage
+            .signed_post_policy(
+                "b",
+                "",
+                ByteSize::kib(1),
+                Duration::from_secs(8 * 24 * 3600),
+                vec![],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_checksum_test() {
+        let storage = MemoryStorage::new();
+        storage
+            .upload_from_bytes("b", "k", None, b"hello".to_vec())
+            .await
+            .unwrap();
+
+        let checksum = storage.object_checksum("b", "k").await.unwrap();
+        assert_eq!(checksum.matches_crc32c(b"hello"), Some(true));
+        assert_eq!(checksum.etag, sha256_hex(b"hello"));
+
+        let err = storage.object_checksum("b", "missing").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_storage_deadline_test() {
+        let storage = MemoryStorage::new();
+        let keys: Vec<String> = (0..1000).map(|i| format!("sync/{i:04}")).collect();
+        for key in &keys {
+            storage
+                .upload_from_bytes("b", key, None, vec![0])
+                .await
+                .unwrap();
+        }
+        let latency = Latency::Fixed(Duration::from_millis(10));
+        storage
+            .mock_stats()
+            .set_fault("object_metadata", Fault::new().latency(latency));
+
+        // 10 requests every 10ms, the 26th round is still in flight at the deadline
+        let start = tokio::time::Instant::now();
+        let ctx = OpContext::new().timeout(Duration::from_millis(255));
+        let err = storage
+            .objects_metadata("b", &keys, 10, &ctx)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::DeadlineExceeded {
+                completed: 250,
+                remaining: 750
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::Timeout);
+        assert_eq!(start.elapsed(), Duration::from_millis(255));
+
+        // an expired context doesn't issue any request
+        let calls = storage.stats().operations["object_metadata"].calls;
+        let err = storage
+            .objects_metadata("b", &keys, 10, &ctx)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::DeadlineExceeded {
+                completed: 0,
+                remaining: 1000
+            }
+        ));
+        assert_eq!(storage.stats().operations["object_metadata"].calls, calls);
+
+        let token = CancellationToken::new();
+        let ctx = OpContext::new().cancel(token.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(35)).await;
+            token.cancel();
+        });
+        let err = storage
+            .objects_metadata("b", &keys[..100], 10, &ctx)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::Cancelled {
+                completed: 30,
+                remaining: 70
+            }
+        ));
+        assert_eq!(err.code(), ErrorCode::Cancelled);
+
+        let outcome = storage
+            .objects_metadata("b", &keys[..20], 10, &OpContext::default())
+            .await
+            .unwrap();
+        assert_eq!(outcome.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn upload_files_test() {
+        let storage = MemoryStorage::new();
+        let dir = std::env::temp_dir().join(format!("nimbus-upload-files-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let mut files = vec![];
+        for i in 0..8 {
+            let path = dir.join(format!("{i}.txt"));
+            tokio::fs::write(&path, format!("file {i}")).await.unwrap();
+            files.push((format!("up/{i}.txt"), path));
+        }
+        storage.mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new().latency(Latency::Fixed(Duration::from_millis(100))),
+        );
+
+        // 2 rounds of 4, not 8 one after the other
+        let start = std::time::Instant::now();
+        storage.upload_files("b", files.clone(), 4).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(600));
+        for (key, _) in &files {
+            assert!(storage.object_exists("b", key).await.unwrap());
+        }
+        assert_eq!(
+            storage.download_to_bytes("b", "up/5.txt").await.unwrap(),
+            b"file 5"
+        );
+
+        // the first error is returned at once, the uploads in flight are cancelled
+        let storage = MemoryStorage::new();
+        storage.mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new().latency(Latency::Fixed(Duration::from_secs(60))),
+        );
+        let mut failing = files[..3].to_vec();
+        failing.push(("up/missing.txt".to_owned(), dir.join("missing.txt")));
+        let start = std::time::Instant::now();
+        let err = storage.upload_files("b", failing, 4).await.unwrap_err();
+        assert!(
+            matches!(err, NimbusError::StorageClient(Error::IO(_))),
+            "{err:?}"
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(!storage.object_exists("b", "up/0.txt").await.unwrap());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_storage_progress_test() {
+        let storage = MemoryStorage::new();
+        let dir = std::env::temp_dir().join(format!("nimbus-progress-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("big.bin");
+        let data: Vec<u8> = (0..20 * 1024 * 1024 + 123).map(|i| i as u8).collect();
+        tokio::fs::write(&path, &data).await.unwrap();
+
+        let events = Mutex::new(vec![]);
+        storage
+            .upload_file_with_progress("b", "big.bin", path.clone(), ByteSize::mib(1), |e| {
+                events.lock().unwrap().push(e)
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_to_bytes("b", "big.bin").await.unwrap(),
+            data
+        );
+
+        let events = events.into_inner().unwrap();
+        let size = data.len() as u64;
+        assert!(events.len() > 20);
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].bytes_done <= w[1].bytes_done));
+        assert!(events.iter().all(|e| e.total == Some(size)));
+        let last = events.last().unwrap();
+        assert_eq!(
+            (last.phase, last.bytes_done),
+            (ProgressPhase::Finalizing, size)
+        );
+        let uploads = events
+            .iter()
+            .filter(|e| e.phase == ProgressPhase::Uploading)
+            .count();
+        assert_eq!(uploads, 3);
+
+        // a panicking callback doesn't fail the download
+        let events = Mutex::new(vec![]);
+        let out = dir.join("out");
+        let written = storage
+            .download_file_with_progress("b", "big.bin", out.clone(), ByteSize::mib(4), |e| {
+                events.lock().unwrap().push(e);
+                if e.phase == ProgressPhase::Writing {
+                    panic!("progress bar gone");
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(written, out.join("big.bin"));
+        assert_eq!(tokio::fs::read(&written).await.unwrap(), data);
+        let phases: Vec<_> = events
+            .into_inner()
+            .unwrap()
+            .iter()
+            .map(|e| e.phase)
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                ProgressPhase::Downloading,
+                ProgressPhase::Downloading,
+                ProgressPhase::Writing
+            ]
+        );
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_storage_stats_test() {
+        let storage = MemoryStorage::new();
+        storage.mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new()
+                .latency(Latency::Fixed(Duration::from_millis(2)))
+                .fail(0.25, ErrorCode::RateLimited),
+        );
+
+        let mut uploaded = 0;
+        for i in 0..40 {
+            match storage
+                .upload_from_bytes("b", &format!("k{i}"), None, vec![0; 10])
+                .await
+            {
+                Ok(()) => uploaded += 1,
+                Err(e) => assert_eq!(e.code(), ErrorCode::RateLimited),
+            }
+        }
+        storage.download_to_bytes("b", "k0").await.ok();
+
+        let stats = storage.stats();
+        let uploads = &stats.operations["upload_from_bytes"];
+        assert_eq!(uploads.calls, 40);
+        assert_eq!(uploads.calls - uploads.failures, uploaded);
+        assert!(uploads.failures > 0);
+        assert_eq!(uploads.bytes, 10 * uploaded);
+        assert_eq!(uploads.latency_p99, Duration::from_millis(2));
+        assert_eq!(stats.calls("download_to_bytes"), 1);
+
+        // mocks sharing their stats aggregate them
+        let other = MemoryStorage::new().with_stats(storage.mock_stats().clone());
+        other.object_exists("b", "k").await.ok();
+        assert_eq!(storage.stats().total_calls(), 42);
+
+        storage.reset_stats();
+        assert_eq!(other.stats().total_calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_audit_test() {
+        use crate::storage::Grantee;
+
+        let storage = MemoryStorage::new();
+        for i in 0..40 {
+            storage
+                .upload_from_bytes("b", &format!("logs/{i:02}"), None, vec![0])
+                .await
+                .unwrap();
+        }
+        storage
+            .upload_from_bytes("b", "other", None, vec![0])
+            .await
+            .unwrap();
+        let public = vec![AclEntry::new(Grantee::AllUsers, "READER")];
+
+        // ACLs are disabled: nothing to read, objects can't be public one by one
+        assert_eq!(
+            storage.bucket_is_public("b").await.unwrap(),
+            PublicAccess::NotPublic
+        );
+        assert!(storage.set_object_acl("b", "logs/07", public.clone()));
+        assert!(storage
+            .audit_public_objects("b", "")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.stats().calls("object_acl"), 0);
+
+        storage.set_object_acls("b", true);
+        assert!(matches!(
+            storage.bucket_is_public("b").await.unwrap(),
+            PublicAccess::Indeterminate { .. }
+        ));
+        let owner = AclEntry::new(Grantee::Other("user-a@b.c".to_owned()), "OWNER");
+        storage.set_object_acl("b", "logs/31", vec![owner, public[0].clone()]);
+        storage.set_object_acl("b", "other", public.clone());
+        storage.set_object_acl("b", "logs/12", vec![]);
+        assert!(!storage.set_object_acl("b", "missing", public));
+        assert_eq!(
+            storage.audit_public_objects("b", "logs/").await.unwrap(),
+            ["logs/07", "logs/31"]
+        );
+        assert_eq!(storage.stats().calls("object_acl"), 40);
+
+        // an overwrite resets the ACL
+        storage
+            .upload_from_bytes("b", "other", None, vec![1])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.audit_public_objects("b", "").await.unwrap(),
+            ["logs/07", "logs/31"]
+        );
+
+        storage.set_bucket_public("b", true);
+        assert!(storage.bucket_is_public("b").await.unwrap().is_public());
+
+        // an ACL that can't be read fails the audit rather than leaving an object out
+        storage
+            .mock_stats()
+            .set_fault("object_acl", Fault::new().fail(1.0, ErrorCode::Unavailable));
+        let err = storage.audit_public_objects("b", "").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn get_metadata_test() {
+        let storage = MemoryStorage::new();
+        storage
+            .upload_from_bytes("b", "k", Some("text/csv".to_owned()), b"a,b\n".to_vec())
+            .await
+            .unwrap();
+
+        let meta = storage.get_metadata("b", "k").await.unwrap();
+        assert_eq!(meta.size, 4);
+        assert_eq!(meta.content_type.as_deref(), Some("text/csv"));
+        assert!(meta.updated.is_some());
+        assert_eq!(meta.etag, Some(sha256_hex(b"a,b\n")));
+        assert_eq!(meta, storage.object_metadata("b", "k").await.unwrap());
+
+        let err = storage.get_metadata("b", "missing").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn copy_move_object_test() {
+        let storage = MemoryStorage::new();
+        let key = "dir/sub dir/a b+c.txt";
+        storage
+            .upload_from_bytes("b", key, Some("text/plain".to_owned()), b"data".to_vec())
+            .await
+            .unwrap();
+
+        storage
+            .copy_object("b", key, "c", "copies/a b.txt")
+            .await
+            .unwrap();
+        assert_eq!(storage.download_to_bytes("b", key).await.unwrap(), b"data");
+        assert_eq!(
+            storage
+                .download_to_bytes("c", "copies/a b.txt")
+                .await
+                .unwrap(),
+            b"data"
+        );
+
+        storage.move_object("b", key, "b", "moved").await.unwrap();
+        assert!(!storage.object_exists("b", key).await.unwrap());
+        assert_eq!(
+            storage.download_to_bytes("b", "moved").await.unwrap(),
+            b"data"
+        );
+
+        // onto itself: nothing happens
+        storage.reset_stats();
+        storage
+            .copy_object("b", "moved", "b", "moved")
+            .await
+            .unwrap();
+        storage
+            .move_object("b", "moved", "b", "moved")
+            .await
+            .unwrap();
+        assert!(storage.object_exists("b", "moved").await.unwrap());
+        assert_eq!(storage.stats().calls("copy_encrypted"), 0);
+
+        let err = storage
+            .copy_object("b", "missing", "c", "x")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.to_string().contains("missing"), "{err}");
+        let err = storage
+            .copy_object("b", "missing", "b", "missing")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        // the source is only deleted once copied
+        storage.reset_stats();
+        storage.mock_stats().set_fault(
+            "copy_encrypted",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        let err = storage
+            .move_object("b", "moved", "c", "elsewhere")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert_eq!(storage.stats().calls("delete_file"), 0);
+        assert!(storage.object_exists("b", "moved").await.unwrap());
+
+        storage.mock_stats().clear_faults();
+        let err = storage
+            .move_object("b", "missing", "c", "x")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn rename_object_test() {
+        let storage = MemoryStorage::new();
+        for (key, data) in [("a", "new"), ("b", "old")] {
+            storage
+                .upload_from_bytes("b", key, None, data.into())
+                .await
+                .unwrap();
+        }
+
+        // onto an existing key: replaced
+        storage.rename_object("b", "a", "b").await.unwrap();
+        assert!(!storage.object_exists("b", "a").await.unwrap());
+        assert_eq!(storage.download_to_bytes("b", "b").await.unwrap(), b"new");
+
+        let err = storage
+            .rename_object("b", "missing", "c")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(!storage.object_exists("b", "c").await.unwrap());
+
+        // onto itself: checked like a copy, and kept
+        storage.rename_object("b", "b", "b").await.unwrap();
+        assert!(storage.object_exists("b", "b").await.unwrap());
+        let err = storage
+            .rename_object("b", "missing", "missing")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        // copied but not deleted: told apart from a failed copy
+        storage.mock_stats().set_fault(
+            "delete_file",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        let err = storage.rename_object("b", "b", "c").await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                NimbusError::StorageClient(Error::DeleteAfterCopy { from, to, .. })
+                    if from == "b/b" && to == "b/c"
+            ),
+            "{err:?}"
+        );
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert!(storage.object_exists("b", "b").await.unwrap());
+        assert!(storage.object_exists("b", "c").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn download_range_test() {
+        let storage = MemoryStorage::new();
+        let data: Vec<u8> = (0..=255).collect();
+        storage
+            .upload_from_bytes("b", "k", None, data.clone())
+            .await
+            .unwrap();
+
+        let range = |start, end| storage.download_range("b", "k", start, end);
+        assert_eq!(range(10, Some(19)).await.unwrap(), &data[10..20]);
+        assert_eq!(range(7, Some(7)).await.unwrap(), [7]);
+        assert_eq!(range(200, None).await.unwrap(), &data[200..]);
+        assert_eq!(range(250, Some(1000)).await.unwrap(), &data[250..]);
+        assert_eq!(range(0, None).await.unwrap(), data);
+
+        let err = range(20, Some(10)).await.unwrap_err();
+        assert!(
+            err.to_string().contains("start 20 is after end 10"),
+            "{err}"
+        );
+        range(256, None).await.unwrap_err();
+
+        assert_eq!(range_header(1000, Some(1999)).unwrap(), "bytes=1000-1999");
+        assert_eq!(range_header(1000, None).unwrap(), "bytes=1000-");
+    }
+
+    #[tokio::test]
+    async fn download_to_writer_test() {
+        use tokio::io::AsyncReadExt;
+
+        let storage = MemoryStorage::new();
+        let data: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        storage
+            .upload_from_bytes("b", "big", None, data.clone())
+            .await
+            .unwrap();
+
+        let mut out = vec![];
+        let written = storage
+            .download_to_writer("b", "big", &mut out)
+            .await
+            .unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out, data);
+
+        // a writer that doesn't keep up holds the download back
+        let (mut writer, mut reader) = tokio::io::duplex(8 * 1024);
+        let download = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.download_to_writer("b", "big", &mut writer).await })
+        };
+        let mut head = vec![0; 8 * 1024];
+        reader.read_exact(&mut head).await.unwrap();
+        tokio::task::yield_now().await;
+        assert!(!download.is_finished());
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(download.await.unwrap().unwrap(), data.len() as u64);
+        head.extend(rest);
+        assert_eq!(head, data);
+
+        let err = storage
+            .download_to_writer("b", "missing", &mut vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn interrupted_download_test() {
+        let storage = MemoryStorage::new();
+        let data = vec![7; 200 * 1024];
+        storage
+            .upload_from_bytes("b", "dir/big", None, data.clone())
+            .await
+            .unwrap();
+        assert!(storage.interrupt_downloads("b", "dir/big", 100_000));
+        assert!(!storage.interrupt_downloads("b", "missing", 0));
+
+        // what was received is flushed before the error is returned
+        let mut writer = tokio::io::BufWriter::with_capacity(1024 * 1024, vec![]);
+        let err = storage
+            .download_to_writer("b", "dir/big", &mut writer)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert!(matches!(
+            err.without_context(),
+            NimbusError::StorageClient(Error::Unavailable(_))
+        ));
+        assert!(writer.buffer().is_empty());
+        assert_eq!(writer.into_inner(), &data[..100_000]);
+
+        let dir = std::env::temp_dir().join(format!("nimbus-interrupted-{}", std::process::id()));
+        let err = storage
+            .download_file("b", "dir/big", dir.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+        assert!(!dir.join("dir/big").exists());
+
+        // overwriting the object ends the interruption
+        storage
+            .upload_from_bytes("b", "dir/big", None, data.clone())
+            .await
+            .unwrap();
+        let path = storage
+            .download_file("b", "dir/big", dir.clone())
+            .await
+            .unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), data);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    /// a writer recording how far the download stream ran ahead of the bytes written, i.e. the bytes
+    /// of the download held in memory at once
+    struct InFlightWriter {
+        stats: MockStats,
+        written: u64,
+        max_in_flight: u64,
+    }
+
+    impl tokio::io::AsyncWrite for InFlightWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let read = self
+                .stats
+                .snapshot()
+                .operations
+                .get("download_stream")
+                .map_or(0, |o| o.bytes);
+            self.max_in_flight = self.max_in_flight.max(read - self.written);
+            self.written += buf.len() as u64;
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn download_file_bounded_memory_test() {
+        const SIZE: usize = 16 * 1024 * 1024;
+
+        let storage = MemoryStorage::new();
+        storage
+            .upload_from_bytes("b", "big.bin", None, vec![7; SIZE])
+            .await
+            .unwrap();
+
+        // download_file streams through download_to_writer: one chunk is read ahead of the writer
+        let mut writer = InFlightWriter {
+            stats: storage.mock_stats().clone(),
+            written: 0,
+            max_in_flight: 0,
+        };
+        let written = storage
+            .download_to_writer("b", "big.bin", &mut writer)
+            .await
+            .unwrap();
+        assert_eq!(written, SIZE as u64);
+        assert_eq!(writer.max_in_flight, MEMORY_DOWNLOAD_CHUNK as u64);
+
+        let dir = std::env::temp_dir().join(format!("nimbus-stream-{}", std::process::id()));
+        let path = storage
+            .download_file("b", "big.bin", dir.clone())
+            .await
+            .unwrap();
+        let len = tokio::fs::metadata(&path).await.unwrap().len();
+        assert_eq!(len, SIZE as u64);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_objects_test() {
+        let storage = MemoryStorage::new();
+
+        // an empty bucket is an empty last page
+        let (keys, next) = storage.list_objects("b", None, None).await.unwrap();
+        assert!(keys.is_empty());
+        assert_eq!(next, None);
+
+        for key in ["logs/1", "logs/2", "logs/3", "logs/4", "other"] {
+            storage
+                .upload_from_bytes("b", key, None, vec![])
+                .await
+                .unwrap();
+        }
+        let (keys, next) = storage
+            .list_objects("b", Some("missing/"), None)
+            .await
+            .unwrap();
+        assert!(keys.is_empty());
+        assert_eq!(next, None);
+
+        // a token of a smaller page resumes where it stopped
+        let params = ListParams {
+            prefix: Some("logs/".to_owned()),
+            page_size: Some(2),
+            ..Default::default()
+        };
+        let page = storage.list_page("b", &params, None).await.unwrap();
+        let mut listed: Vec<_> = page.objects.into_iter().map(|o| o.key).collect();
+        let mut token = page.next_page_token;
+        assert!(token.is_some());
+        while let Some(page_token) = token {
+            let (keys, next) = storage
+                .list_objects("b", Some("logs/"), Some(page_token))
+                .await
+                .unwrap();
+            listed.extend(keys);
+            token = next;
+        }
+        assert_eq!(listed, ["logs/1", "logs/2", "logs/3", "logs/4"]);
+    }
+
+    #[tokio::test]
+    async fn list_all_objects_test() {
+        let storage = MemoryStorage::new().with_max_page_size(1000);
+        for i in 0..2500 {
+            storage
+                .upload_from_bytes("b", &format!("logs/{i:04}"), None, vec![0; i % 3])
+                .await
+                .unwrap();
+        }
+        storage
+            .upload_from_bytes("b", "other", None, vec![])
+            .await
+            .unwrap();
+        storage.reset_stats();
+
+        let keys = storage
+            .list_all_objects("b", Some("logs/"), ListLimits::new())
+            .await
+            .unwrap();
+        assert_eq!(keys.len(), 2500);
+        assert_eq!(keys[0], "logs/0000");
+        assert_eq!(keys[2499], "logs/2499");
+        assert_eq!(storage.stats().calls("list_page"), 3);
+
+        // the whole bucket
+        for prefix in [None, Some("")] {
+            let all = storage
+                .list_all_objects("b", prefix, ListLimits::new())
+                .await
+                .unwrap();
+            assert_eq!(all.len(), 2501);
+        }
+
+        assert!(storage
+            .list_all_objects("b", Some("missing/"), ListLimits::new())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .list_all_objects("empty", None, ListLimits::new())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let metas = storage
+            .list_all_metadata("b", Some("logs/000"), ListLimits::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            metas.iter().map(|m| m.size).collect::<Vec<_>>(),
+            [0, 1, 2, 0, 1, 2, 0, 1, 2, 0]
+        );
+        assert!(metas.iter().all(|m| m.updated.is_some()));
+
+        let e = storage
+            .list_all_objects("b", None, ListLimits::new().max_results(2000))
+            .await
+            .unwrap_err();
+        assert!(matches!(e, NimbusError::ResultsTruncated { .. }));
+    }
+
+    #[tokio::test]
+    async fn memory_storage_cors_test() {
+        let storage = MemoryStorage::new();
+        assert!(storage.get_cors("b").await.unwrap().is_empty());
+
+        let upload = CorsRule::new()
+            .origin("https://app.example.com")
+            .method("PUT")
+            .response_header("Content-Type");
+        assert!(storage
+            .ensure_cors_contains("b", upload.clone())
+            .await
+            .unwrap());
+        // the same rule written differently is there already
+        let same = CorsRule::new()
+            .origin("https://app.example.com")
+            .method("put")
+            .response_header("content-type");
+        assert!(!storage.ensure_cors_contains("b", same).await.unwrap());
+        let read = CorsRule::new().origin("*").method("GET");
+        assert!(storage
+            .ensure_cors_contains("b", read.clone())
+            .await
+            .unwrap());
+        assert_eq!(storage.get_cors("b").await.unwrap(), [upload, read]);
+        assert_eq!(storage.stats().calls("set_cors"), 2);
+
+        // invalid rules are rejected before anything is read or written
+        let err = storage
+            .ensure_cors_contains("b", CorsRule::new().origin("*").method("*"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert_eq!(storage.stats().calls("get_cors"), 5);
+        let err = storage
+            .set_cors("b", vec![CorsRule::new().method("GET")])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert_eq!(storage.get_cors("b").await.unwrap().len(), 2);
+
+        storage.set_cors("b", vec![]).await.unwrap();
+        assert!(storage.get_cors("b").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_storage_encryption_test() {
+        let storage = MemoryStorage::new();
+        let key = EncryptionKey::new([1; 32]);
+        let other = EncryptionKey::new([2; 32]);
+        storage
+            .upload_encrypted("b", "secret", None, b"data".to_vec(), &key)
+            .await
+            .unwrap();
+
+        let err = storage.download_to_bytes("b", "secret").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert!(storage
+            .download_encrypted("b", "secret", &other)
+            .await
+            .is_err());
+        assert_eq!(
+            storage
+                .download_encrypted("b", "secret", &key)
+                .await
+                .unwrap(),
+            b"data"
+        );
+
+        // checksums need the key, the rest of the metadata doesn't
+        let meta = storage.object_metadata("b", "secret").await.unwrap();
+        assert_eq!((meta.size, meta.crc32c), (4, None));
+        let meta = storage
+            .object_metadata_encrypted("b", "secret", &key)
+            .await
+            .unwrap();
+        assert_eq!(meta.crc32c, Some(crc32c_base64(b"data")));
+
+        // rotate the key, then decrypt
+        storage
+            .copy_encrypted(("b", "secret"), Some(&key), ("b", "secret"), Some(&other))
+            .await
+            .unwrap();
+        assert!(storage
+            .download_encrypted("b", "secret", &key)
+            .await
+            .is_err());
+        storage
+            .copy_encrypted(("b", "secret"), Some(&other), ("c", "plain"), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_to_bytes("c", "plain").await.unwrap(),
+            b"data"
+        );
+        assert!(storage
+            .copy_encrypted(("b", "secret"), None, ("c", "x"), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_storage_placeholder_test() {
+        let storage = MemoryStorage::new();
+        assert_eq!(
+            storage.create_placeholder_dir("b", "a").await.unwrap(),
+            "a/"
+        );
+        assert_eq!(
+            storage.create_placeholder_dir("b", "a/b/").await.unwrap(),
+            "a/b/"
+        );
+        storage
+            .upload_from_bytes("b", "a/b/file", None, b"data".to_vec())
+            .await
+            .unwrap();
+        assert!(storage
+            .object_metadata("b", "a/b/")
+            .await
+            .unwrap()
+            .is_placeholder_dir());
+        assert!(!storage
+            .object_metadata("b", "a/b/file")
+            .await
+            .unwrap()
+            .is_placeholder_dir());
+
+        let dir = std::env::temp_dir().join(format!("nimbus-placeholder-{}", std::process::id()));
+        let err = storage
+            .download_file("b", "a/b/", dir.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NimbusError::StorageClient(Error::IsDirectoryPlaceholder(_))
+        ));
+        let err = storage
+            .download_file_with_progress("b", "a/", dir.clone(), ByteSize::mib(1), |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        let path = storage
+            .download_file("b", "a/b/file", dir.clone())
+            .await
+            .unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"data");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        // only the placeholder goes, and only a placeholder
+        storage.delete_placeholder_dir("b", "a/b").await.unwrap();
+        assert!(!storage.object_exists("b", "a/b/").await.unwrap());
+        assert!(storage.object_exists("b", "a/b/file").await.unwrap());
+        storage
+            .upload_from_bytes("b", "x/", None, b"data".to_vec())
+            .await
+            .unwrap();
+        assert!(storage.delete_placeholder_dir("b", "x/").await.is_err());
+        // an existing object isn't replaced
+        storage.create_placeholder_dir("b", "x").await.unwrap();
+        assert_eq!(storage.download_to_bytes("b", "x/").await.unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn memory_storage_traffic_test() {
+        use crate::storage::MIN_CHUNK_SIZE;
+
+        let meter = TrafficMeter::new();
+        let storage = MemoryStorage::new().with_traffic_meter(meter.clone());
+
+        // full transfers
+        storage
+            .upload_from_bytes("b", "k", None, vec![1; 100])
+            .await
+            .unwrap();
+        storage.download_to_bytes("b", "k").await.unwrap();
+        storage.download_range("b", "k", 90, None).await.unwrap();
+        storage.download_to_bytes("b", "missing").await.unwrap_err();
+        storage
+            .copy_encrypted(("b", "k"), None, ("other", "k"), None)
+            .await
+            .unwrap();
+        assert_eq!(meter.bytes("b", Direction::Ingress), 100);
+        assert_eq!(meter.bytes("b", Direction::Egress), 110);
+        assert_eq!(meter.bytes("other", Direction::Ingress), 0);
+        meter.reset();
+
+        // streamed, counted as the chunks are sent
+        let chunk = MIN_CHUNK_SIZE.bytes() as usize;
+        let mut upload = storage
+            .start_resumable_upload("b", "big", None)
+            .await
+            .unwrap();
+        upload.upload_chunk(vec![0; chunk]).await.unwrap();
+        // held back until the next chunk
+        assert_eq!(meter.bytes("b", Direction::Ingress), 0);
+        upload.upload_chunk(vec![0; chunk]).await.unwrap();
+        assert_eq!(meter.bytes("b", Direction::Ingress), chunk as u64);
+        upload.upload_chunk(vec![0; 10]).await.unwrap();
+        upload.finish().await.unwrap();
+        assert_eq!(meter.bytes("b", Direction::Ingress), 2 * chunk as u64 + 10);
+        meter.reset();
+
+        // failing midway, only what went through is counted
+        let mut upload = storage
+            .start_resumable_upload("b", "partial", None)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            upload.upload_chunk(vec![0; chunk]).await.unwrap();
+        }
+        storage.mock_stats().set_fault(
+            "upload_chunk",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        upload.upload_chunk(vec![0; chunk]).await.unwrap_err();
+        assert_eq!(meter.bytes("b", Direction::Ingress), 2 * chunk as u64);
+        assert!(!storage.object_exists("b", "partial").await.unwrap());
+
+        storage.mock_stats().clear_faults();
+        storage.mock_stats().set_fault(
+            "upload_from_bytes",
+            Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        storage
+            .upload_from_bytes("b", "k", None, vec![1; 100])
+            .await
+            .unwrap_err();
+        assert_eq!(meter.bytes("b", Direction::Ingress), 2 * chunk as u64);
+    }
+}
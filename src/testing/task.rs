@@ -0,0 +1,810 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use google_cloudtasks2::hyper::client::HttpConnector;
+use google_cloudtasks2::hyper::{Body, Response};
+use google_cloudtasks2::hyper_rustls::HttpsConnector;
+use google_cloudtasks2::oauth2::authenticator::Authenticator;
+
+use super::{MockStats, MockStatsSnapshot};
+use crate::task::{
+    self, CloudTaskHelper, QueueConfigSnapshot, QueueInfo, QueuePath, QueueState, TaskExt,
+    TaskHelper, TaskOutcome, TaskView, POLL_INITIAL_DELAY,
+};
+use crate::{ErrorCode, ListLimits, NimbusError, Task};
+
+/// [`CloudTaskHelper`] keeping the tasks of each queue in memory
+///
+/// Tasks are never dispatched, [`MemoryCloudTasks::complete`] removes one as if it ran successfully.
+/// Listings return pages of [`MemoryCloudTasks::PAGE_SIZE`] tasks ordered by name.
+/// Queues accept tasks whether they were added or not, only added queues are listed. Clones share their state.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCloudTasks {
+    /// tasks by full name, the queue is the part before `/tasks/`
+    tasks: Arc<Mutex<BTreeMap<String, Task>>>,
+    next_id: Arc<AtomicI64>,
+    /// settings of the added queues, their state always set
+    queues: Arc<Mutex<BTreeMap<QueuePath, QueueConfigSnapshot>>>,
+    /// `(project, location)` where listing queues is denied
+    restricted: Arc<Mutex<BTreeSet<(String, String)>>>,
+    stats: MockStats,
+}
+
+impl MemoryCloudTasks {
+    pub const PAGE_SIZE: usize = 100;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// remove a task as if it was dispatched successfully, returns `false` if there was no such task
+    pub fn complete(&self, name: &str) -> bool {
+        self.tasks.lock().unwrap().remove(name).is_some()
+    }
+
+    /// add a queue, or change its state, for the queue listings
+    pub fn add_queue(&self, path: QueuePath, state: QueueState) {
+        self.queues.lock().unwrap().entry(path).or_default().state = Some(state);
+    }
+
+    /// deny listing the queues of a location, it is still listed by [`CloudTaskHelper::list_locations`]
+    pub fn restrict_location(&self, project: &str, location: &str) {
+        self.restricted
+            .lock()
+            .unwrap()
+            .insert((project.to_owned(), location.to_owned()));
+    }
+
+    /// number of tasks in a queue
+    pub fn len(&self, queue: &str) -> usize {
+        let prefix = format!("{queue}/tasks/");
+        self.tasks
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .count()
+    }
+
+    /// record calls in `stats`, shared with other mocks
+    pub fn with_stats(mut self, stats: MockStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// statistics of the calls, also used to inject faults
+    pub fn mock_stats(&self) -> &MockStats {
+        &self.stats
+    }
+
+    pub fn stats(&self) -> MockStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// record a call, failing it if a fault was injected
+    async fn enter(&self, operation: &'static str, bytes: u64) -> Result<(), NimbusError> {
+        self.stats
+            .call(operation, bytes)
+            .await
+            .map_err(|code| injected(operation, code).into())
+    }
+
+    fn not_found(name: &str) -> NimbusError {
+        task::Error::NotFound(name.to_owned()).into()
+    }
+}
+
+/// error of a call failed by an injected fault
+fn injected(operation: &str, code: ErrorCode) -> task::Error {
+    let message = format!("injected failure of {operation}");
+    match code {
+        ErrorCode::NotFound => task::Error::NotFound(message),
+        ErrorCode::AlreadyExists => task::Error::AlreadyExists(message),
+        ErrorCode::PermissionDenied => task::Error::PermissionDenied(message),
+        ErrorCode::RateLimited => task::Error::RateLimited {
+            message,
+            retry_after: None,
+        },
+        ErrorCode::Timeout => task::Error::Timeout(message),
+        ErrorCode::Unavailable => task::Error::Unavailable(message),
+        _ => task::Error::Other(message),
+    }
+}
+
+#[async_trait::async_trait]
+impl CloudTaskHelper<HttpsConnector<HttpConnector>> for MemoryCloudTasks {
+    /// the authenticator is not used
+    async fn new_with_authenticator(_: Authenticator<HttpsConnector<HttpConnector>>) -> Self {
+        Self::new()
+    }
+
+    async fn create_task(
+        &self,
+        queue: &str,
+        task: Task,
+        _: Option<TaskView>,
+    ) -> Result<(Response<Body>, Task), NimbusError> {
+        self.enter("push_task", task.body_bytes().map_or(0, |b| b.len() as u64))
+            .await?;
+
+        let name = match task.name.clone() {
+            Some(name) => name,
+            None => {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+                format!("{queue}/tasks/{id:020}")
+            }
+        };
+
+        let task = Task {
+            name: Some(name.clone()),
+            create_time: Some(Utc::now()),
+            schedule_time: task.schedule_time.or_else(|| Some(Utc::now())),
+            dispatch_count: Some(0),
+            response_count: Some(0),
+            ..task
+        };
+
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.contains_key(&name) {
+            return Err(task::Error::AlreadyExists(name).into());
+        }
+        tasks.insert(name, task.clone());
+
+        Ok((Response::new(Body::empty()), task))
+    }
+
+    async fn get_task(
+        &self,
+        name: &str,
+        _: Option<TaskView>,
+        redact: bool,
+    ) -> Result<Task, NimbusError> {
+        self.enter("get_task", 0).await?;
+
+        let task = self
+            .tasks
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Self::not_found(name))?;
+
+        Ok(if redact { task.redacted() } else { task })
+    }
+
+    /// the page token is the name of the last task of the previous page
+    async fn list_tasks(
+        &self,
+        queue: &str,
+        _: Option<TaskView>,
+        redact: bool,
+        page_token: Option<String>,
+    ) -> Result<(Vec<Task>, Option<String>), NimbusError> {
+        self.enter("list_tasks", 0).await?;
+
+        let prefix = format!("{queue}/tasks/");
+        let start = match page_token {
+            Some(token) => Bound::Excluded(token),
+            None => Bound::Included(prefix.clone()),
+        };
+
+        let tasks = self.tasks.lock().unwrap();
+        let mut rest = tasks
+            .range((start, Bound::Unbounded))
+            .take_while(|(name, _)| name.starts_with(&prefix))
+            .peekable();
+
+        let page: Vec<Task> = rest
+            .by_ref()
+            .take(Self::PAGE_SIZE)
+            .map(|(_, t)| if redact { t.redacted() } else { t.clone() })
+            .collect();
+        let next = match rest.peek() {
+            Some(_) => page.last().and_then(|t| t.name.clone()),
+            None => None,
+        };
+
+        Ok((page, next))
+    }
+
+    async fn delete_task(&self, name: &str) -> Result<(), NimbusError> {
+        self.enter("delete_task", 0).await?;
+
+        self.tasks
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Self::not_found(name))
+    }
+
+    /// tasks only leave their queue through [`MemoryCloudTasks::complete`] or a delete
+    async fn wait_for_task_completion(
+        &self,
+        task_name: &str,
+        timeout: Duration,
+    ) -> Result<TaskOutcome, NimbusError> {
+        self.enter("wait_for_task_completion", 0).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            if !self.tasks.lock().unwrap().contains_key(task_name) {
+                return Ok(TaskOutcome::Succeeded);
+            }
+
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                return Ok(TaskOutcome::TimedOut);
+            }
+            tokio::time::sleep(remaining.min(POLL_INITIAL_DELAY)).await;
+        }
+    }
+
+    /// locations of the added queues and the restricted locations
+    async fn list_locations(
+        &self,
+        project: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        self.enter("list_locations", 0).await?;
+
+        let mut locations: BTreeSet<String> = self
+            .queues
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|q| q.project == project)
+            .map(|q| q.location.clone())
+            .collect();
+        locations.extend(
+            self.restricted
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(p, _)| p == project)
+                .map(|(_, l)| l.clone()),
+        );
+
+        limits.check(locations.len())?;
+        Ok(locations.into_iter().collect())
+    }
+
+    async fn list_queues(
+        &self,
+        project: &str,
+        location: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<QueueInfo>, NimbusError> {
+        self.enter("list_queues", 0).await?;
+
+        let id = (project.to_owned(), location.to_owned());
+        if self.restricted.lock().unwrap().contains(&id) {
+            return Err(task::Error::PermissionDenied(format!(
+                "projects/{project}/locations/{location}"
+            ))
+            .into());
+        }
+
+        let queues: Vec<QueueInfo> = self
+            .queues
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(q, _)| q.project == project && q.location == location)
+            .map(|(path, config)| QueueInfo {
+                path: path.clone(),
+                state: config.state.unwrap_or(QueueState::Unspecified),
+            })
+            .collect();
+        limits.check(queues.len())?;
+
+        Ok(queues)
+    }
+
+    async fn export_queue_config(
+        &self,
+        queue: &QueuePath,
+    ) -> Result<QueueConfigSnapshot, NimbusError> {
+        self.enter("export_queue_config", 0).await?;
+
+        let queues = self.queues.lock().unwrap();
+        queues
+            .get(queue)
+            .cloned()
+            .ok_or_else(|| Self::not_found(&queue.to_string()))
+    }
+
+    /// the settings left out of `config` stay unset
+    async fn create_queue(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+    ) -> Result<(), NimbusError> {
+        self.enter("create_queue", 0).await?;
+
+        let mut queues = self.queues.lock().unwrap();
+        if queues.contains_key(queue) {
+            return Err(task::Error::AlreadyExists(queue.to_string()).into());
+        }
+        queues.insert(
+            queue.clone(),
+            QueueConfigSnapshot {
+                state: Some(QueueState::Running),
+                ..config.clone()
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn update_queue_config(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+        fields: &[&str],
+    ) -> Result<(), NimbusError> {
+        self.enter("update_queue_config", 0).await?;
+        config.validate()?;
+
+        let mut queues = self.queues.lock().unwrap();
+        let current = queues
+            .get_mut(queue)
+            .ok_or_else(|| Self::not_found(&queue.to_string()))?;
+        for field in fields {
+            current.set(config, field);
+        }
+
+        Ok(())
+    }
+
+    /// every permission is held
+    async fn test_permissions(
+        &self,
+        _: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        self.enter("test_permissions", 0).await?;
+
+        Ok(permissions.iter().map(|p| p.to_string()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::task::{
+        PayloadSchemas, PayloadValidator, Routing, ShardedQueue, TaskValidation, ValidationError,
+        KIND_HEADER,
+    };
+
+    async fn queue_with(tasks: &MemoryCloudTasks, queue: &str, names: &[String]) {
+        for name in names {
+            let task = Task {
+                name: Some(format!("{queue}/tasks/{name}")),
+                ..Default::default()
+            };
+            tasks.push_task(queue, task, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn inspect_task_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+        let headers = HashMap::from([("Authorization".to_owned(), "Bearer secret".to_owned())]);
+        let task = Task::new_task(
+            "https://example.com",
+            "POST",
+            Some(b"{}".to_vec()),
+            Some(headers),
+            Some(format!("{queue}/tasks/t-1")),
+            None,
+            None,
+        );
+        tasks.push_task(queue, task, None).await.unwrap();
+
+        let inspected = tasks
+            .inspect_task(&format!("{queue}/tasks/t-1"))
+            .await
+            .unwrap();
+        assert!(!format!("{inspected:?}").contains("secret"));
+        assert_eq!(
+            inspected.into_inner().header("Authorization"),
+            Some("Bearer secret")
+        );
+
+        let (page, next) = tasks.inspect_tasks(queue, None).await.unwrap();
+        assert_eq!((page.len(), next), (1, None));
+        assert!(!format!("{page:?}").contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn delete_tasks_where_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+
+        // several pages of each kind
+        let names: Vec<String> = (0..250)
+            .flat_map(|i| [format!("import-{i:03}"), format!("export-{i:03}")])
+            .collect();
+        queue_with(&tasks, queue, &names).await;
+        queue_with(&tasks, "projects/p/locations/l/queues/other", &names[..10]).await;
+
+        let deleted = tasks
+            .delete_tasks_where(queue, |t| {
+                t.name
+                    .as_deref()
+                    .is_some_and(|n| n.contains("/tasks/import-"))
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 250);
+        assert_eq!(tasks.len(queue), 250);
+        assert_eq!(tasks.len("projects/p/locations/l/queues/other"), 10);
+    }
+
+    #[tokio::test]
+    async fn delete_tasks_where_skips_completed_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+        let names: Vec<String> = (0..20).map(|i| format!("t-{i:02}")).collect();
+        queue_with(&tasks, queue, &names).await;
+
+        // every other task is dispatched between the listing and its delete
+        let dispatcher = tasks.clone();
+        let deleted = tasks
+            .delete_tasks_where(queue, |t| {
+                let name = t.name.as_deref().unwrap();
+                if name.ends_with(['0', '2', '4', '6', '8']) {
+                    dispatcher.complete(name);
+                }
+                true
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 10);
+        assert_eq!(tasks.len(queue), 0);
+    }
+
+    #[tokio::test]
+    async fn find_queue_test() {
+        let tasks = MemoryCloudTasks::new();
+        for (project, location, queue, state) in [
+            ("p", "europe-west1", "imports", QueueState::Running),
+            ("p", "us-central1", "imports", QueueState::Paused),
+            ("p", "us-central1", "exports", QueueState::Running),
+            ("p", "asia-east1", "exports", QueueState::Disabled),
+            ("other", "europe-west1", "imports", QueueState::Running),
+        ] {
+            tasks.add_queue(QueuePath::new(project, location, queue), state);
+        }
+        tasks.restrict_location("p", "southamerica-east1");
+
+        let imports = tasks.find_queue("p", "imports").await.unwrap();
+        assert_eq!(
+            imports.found,
+            [
+                QueuePath::new("p", "europe-west1", "imports"),
+                QueuePath::new("p", "us-central1", "imports"),
+            ]
+        );
+        assert!(!imports.is_complete());
+        assert_eq!(imports.warnings[0].key, "southamerica-east1");
+
+        let all = tasks.list_all_queues("p").await.unwrap();
+        assert_eq!(all.found.len(), 4);
+        assert_eq!(all.found[0].path.location, "asia-east1");
+        assert_eq!(all.found[0].state, QueueState::Disabled);
+
+        assert!(tasks
+            .find_queue("p", "missing")
+            .await
+            .unwrap()
+            .found
+            .is_empty());
+
+        // other failures fail the discovery
+        tasks.mock_stats().set_fault(
+            "list_queues",
+            crate::testing::Fault::new().fail(1.0, ErrorCode::Unavailable),
+        );
+        let err = tasks.list_all_queues("p").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn queue_config_test() {
+        let tasks = MemoryCloudTasks::new();
+        let source = QueuePath::new("p", "europe-west1", "imports");
+        let restored = QueuePath::new("p", "europe-west1", "imports-restored");
+        let config: QueueConfigSnapshot = serde_json::from_str(
+            r#"{
+                "rate_limits": {"max_dispatches_per_second": 5.0, "max_concurrent_dispatches": 10},
+                "retry": {"max_attempts": 3, "min_backoff": "0.5s", "max_backoff": "60s"},
+                "logging_sampling_ratio": 1.0,
+                "state": "PAUSED"
+            }"#,
+        )
+        .unwrap();
+
+        let missing = tasks
+            .apply_queue_config(&source, &config, false)
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), ErrorCode::NotFound);
+
+        // dry run of a creation changes nothing
+        let plan = tasks
+            .plan_queue_config(&source, &config, true)
+            .await
+            .unwrap();
+        assert!(plan.created && plan.dry_run);
+        assert_eq!(plan.changes.len(), 7);
+        assert!(tasks.export_queue_config(&source).await.is_err());
+
+        let created = tasks
+            .apply_queue_config(&source, &config, true)
+            .await
+            .unwrap();
+        assert!(created.created && !created.dry_run);
+        let exported = tasks.export_queue_config(&source).await.unwrap();
+        assert_eq!(exported, config);
+
+        // round trip: export then apply to the same queue is a no-op
+        let json = serde_json::to_string(&exported).unwrap();
+        let backup: QueueConfigSnapshot = serde_json::from_str(&json).unwrap();
+        tasks.reset_stats();
+        let noop = tasks
+            .apply_queue_config(&source, &backup, true)
+            .await
+            .unwrap();
+        assert!(noop.is_noop(), "{noop}");
+        assert_eq!(tasks.stats().calls("update_queue_config"), 0);
+
+        // restore to another queue, then change a setting of it
+        tasks
+            .apply_queue_config(&restored, &backup, true)
+            .await
+            .unwrap();
+        let mut changed = backup.clone();
+        changed.retry.max_attempts = Some(10);
+        changed.state = Some(QueueState::Running);
+        let plan = tasks
+            .plan_queue_config(&restored, &changed, false)
+            .await
+            .unwrap();
+        let fields: Vec<_> = plan.changes.iter().map(|c| c.field).collect();
+        assert_eq!(fields, ["retry_config.max_attempts", "state"]);
+        assert_eq!(plan.changes[0].current.as_deref(), Some("3"));
+
+        tasks.reset_stats();
+        let applied = tasks
+            .apply_queue_config(&restored, &changed, false)
+            .await
+            .unwrap();
+        assert_eq!(applied.changes, plan.changes);
+        assert_eq!(tasks.export_queue_config(&restored).await.unwrap(), changed);
+        assert_eq!(tasks.stats().calls("update_queue_config"), 2);
+    }
+
+    /// rejects bodies without `valid` in them
+    struct Contains;
+
+    impl PayloadValidator for Contains {
+        fn validate(&self, _: &str, body: &[u8]) -> Result<(), ValidationError> {
+            match body.windows(5).any(|w| w == b"valid") {
+                true => Ok(()),
+                false => Err(ValidationError::malformed()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn payload_validation_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+        let validation = TaskValidation::new().payload(
+            PayloadSchemas::new()
+                .path("/tasks/email", Contains)
+                .kind("invoice", Contains),
+        );
+        let task = |url: &str, kind: Option<&str>, body: &str| {
+            let headers = kind.map(|kind| [(KIND_HEADER.to_owned(), kind.to_owned())].into());
+            Task::new_task(url, "POST", Some(body.into()), headers, None, None, None)
+        };
+
+        for rejected in [
+            task("https://example.com/tasks/email?id=1", None, "{}"),
+            task("https://example.com/other", Some("invoice"), "{}"),
+        ] {
+            let error = tasks
+                .push_task_with(queue, rejected, &validation, None)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                NimbusError::TasksClient(task::Error::PayloadInvalid(_))
+            ));
+            assert_eq!(error.code(), ErrorCode::InvalidInput);
+        }
+        assert_eq!(tasks.len(queue), 0);
+        assert_eq!(tasks.stats().calls("push_task"), 0);
+
+        for accepted in [
+            task("https://example.com/tasks/email", None, "valid"),
+            // matches no validator
+            task("https://example.com/other", None, "{}"),
+        ] {
+            tasks
+                .push_task_with(queue, accepted, &validation, None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(tasks.len(queue), 2);
+    }
+
+    #[tokio::test]
+    async fn push_task_analyzed_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+        let at = Utc::now() + chrono::Duration::minutes(5);
+
+        let task = Task::new_task(
+            "https://example.com",
+            "GET",
+            None,
+            None,
+            None,
+            Some(at),
+            None,
+        );
+        let (returned, analysis) = tasks.push_task_analyzed(queue, task, None).await.unwrap();
+        assert_eq!(returned.schedule_time, Some(at));
+        assert!(analysis.name_assigned_by_server);
+        assert!(!analysis.is_delayed());
+
+        let name = format!("{queue}/tasks/job-1");
+        let task = Task::new_task(
+            "https://example.com",
+            "GET",
+            None,
+            None,
+            Some(name),
+            None,
+            None,
+        );
+        let (_, analysis) = tasks.push_task_analyzed(queue, task, None).await.unwrap();
+        assert!(!analysis.name_assigned_by_server);
+        assert!(!analysis.is_delayed());
+    }
+
+    #[tokio::test]
+    async fn sharded_queue_test() {
+        let tasks = MemoryCloudTasks::new();
+        let emails = ShardedQueue::new(QueuePath::new("p", "europe-west1", "emails"), 4);
+        let config = QueueConfigSnapshot {
+            state: Some(QueueState::Running),
+            ..Default::default()
+        };
+
+        let created = emails.ensure_all(&tasks, &config).await.unwrap();
+        assert!(created.iter().all(|r| r.created));
+        let again = emails.ensure_all(&tasks, &config).await.unwrap();
+        assert!(again.iter().all(|r| r.is_noop()));
+
+        let task = Task::new_task("https://example.com", "POST", None, None, None, None, None);
+        for _ in 0..3 {
+            let (shard, _) = emails
+                .push_sharded(
+                    &tasks,
+                    task.clone(),
+                    Routing::Sticky("user-42".into()),
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(shard, emails.shard(3));
+        }
+        emails
+            .push_sharded(&tasks, task, Routing::Random, None)
+            .await
+            .unwrap();
+
+        let stats = emails.stats_all(&tasks).await.unwrap();
+        assert_eq!(stats.total(), 4);
+        assert!(stats.tasks[3].1 >= 3);
+        assert_eq!(stats.tasks.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn push_fanout_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queues = [
+            "projects/p/locations/europe-west1/queues/q",
+            "projects/p/locations/us-central1/queues/q",
+            "projects/p/locations/asia-east1/queues/q",
+        ];
+        let template = Task {
+            name: Some(format!("{}/tasks/job-1", queues[0])),
+            ..Task::new_task("https://example.com", "POST", None, None, None, None, None)
+        };
+
+        let pushed = tasks
+            .push_fanout(&queues, template.clone(), None)
+            .await
+            .unwrap();
+        for (queue, task) in queues.iter().zip(&pushed) {
+            let name = task.as_ref().unwrap().name.clone().unwrap();
+            assert_eq!(name, format!("{queue}/tasks/job-1"));
+            assert_eq!(tasks.len(queue), 1);
+        }
+
+        // each queue deduplicates on its own
+        tasks.complete(&format!("{}/tasks/job-1", queues[1]));
+        let again = tasks.push_fanout(&queues, template, None).await.unwrap();
+        let codes: Vec<_> = again
+            .iter()
+            .map(|r| r.as_ref().err().map(|e| e.code()))
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                Some(ErrorCode::AlreadyExists),
+                None,
+                Some(ErrorCode::AlreadyExists)
+            ]
+        );
+
+        // unnamed templates are named by each queue
+        let unnamed = Task::new_task("https://example.com", "POST", None, None, None, None, None);
+        let pushed = tasks.push_fanout(&queues, unnamed, None).await.unwrap();
+        assert!(pushed.iter().all(|r| r.is_ok()));
+        assert_eq!(tasks.len(queues[2]), 2);
+
+        let invalid = Task {
+            name: Some(format!("{}/tasks/", queues[0])),
+            ..Default::default()
+        };
+        let err = tasks.push_fanout(&queues, invalid, None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn push_task_validation_test() {
+        let tasks = MemoryCloudTasks::new();
+        let queue = "projects/p/locations/l/queues/q";
+        let get = Task::new_task(
+            "https://example.com",
+            "GET",
+            Some(b"{}".to_vec()),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let err = tasks.push_task(queue, get.clone(), None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidInput);
+        assert_eq!(tasks.stats().calls("push_task"), 0);
+
+        let lenient = TaskValidation::new().allow_body_with_any_method();
+        tasks
+            .push_task_with(queue, get, &lenient, None)
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(queue), 1);
+    }
+}
@@ -0,0 +1,242 @@
+//! Timestamps exchanged with the providers, the one place they are parsed and formatted
+//!
+//! Timestamps are formatted as RFC 3339 in UTC with a `Z`, e.g. `2024-03-10T12:00:00.123Z`, which
+//! Cloud Tasks, GCS and S3 all accept. Parsing takes what the providers send back: any UTC offset,
+//! `Z` or `+00:00`, from none to nanosecond fractions of a second, and the HTTP dates of S3 headers.
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use thiserror::Error;
+
+/// Error parsing or converting a timestamp, with the offending value
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid timestamp {input:?}: {reason}")]
+pub struct TimeError {
+    input: String,
+    reason: &'static str,
+}
+
+impl TimeError {
+    fn new(input: impl Into<String>, reason: &'static str) -> Self {
+        Self {
+            input: input.into(),
+            reason,
+        }
+    }
+
+    /// the value that isn't a timestamp
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+}
+
+/// RFC 3339 in UTC, with as many digits of fraction as needed among none, 3, 6 or 9
+/// e.g. `2024-03-10T12:00:00Z` or `2024-03-10T12:00:00.123456Z`
+pub fn to_api_timestamp(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// RFC 3339 in UTC truncated to milliseconds, for the APIs taking no more e.g. POST policies
+/// e.g. `2024-03-10T12:00:00.000Z`
+pub fn to_api_timestamp_millis(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// timestamp sent by a provider, RFC 3339 with any offset and precision or an HTTP date,
+/// surrounding whitespace aside; a timestamp without an offset is ambiguous and rejected
+pub fn parse_api_timestamp(s: &str) -> Result<DateTime<Utc>, TimeError> {
+    let trimmed = s.trim();
+    if trimmed.is_empty() {
+        return Err(TimeError::new(s, "empty"));
+    }
+
+    if let Ok(t) = DateTime::parse_from_rfc3339(trimmed) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc2822(trimmed) {
+        return Ok(t.with_timezone(&Utc));
+    }
+
+    let naive = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .any(|format| NaiveDateTime::parse_from_str(trimmed, format).is_ok());
+    let reason = match naive {
+        true => "no UTC offset",
+        false => "not an RFC 3339 timestamp or HTTP date",
+    };
+    Err(TimeError::new(s, reason))
+}
+
+/// timestamp `secs` and `nanos` after the epoch, as the SDKs of the providers give them
+pub fn from_unix(secs: i64, nanos: u32) -> Result<DateTime<Utc>, TimeError> {
+    DateTime::from_timestamp(secs, nanos)
+        .ok_or_else(|| TimeError::new(format!("{secs}.{nanos:09}"), "out of range"))
+}
+
+/// timestamp of the AWS SDKs, e.g. the `LastModified` of an S3 object
+#[cfg(any(feature = "aws-secrets", feature = "aws-storage"))]
+pub fn from_aws_datetime(t: &aws_smithy_types::DateTime) -> Result<DateTime<Utc>, TimeError> {
+    from_unix(t.secs(), t.subsec_nanos())
+}
+
+/// timestamp for the AWS SDKs
+#[cfg(any(feature = "aws-secrets", feature = "aws-storage"))]
+pub fn to_aws_datetime(t: DateTime<Utc>) -> aws_smithy_types::DateTime {
+    aws_smithy_types::DateTime::from_secs_and_nanos(t.timestamp(), t.timestamp_subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64, nanos: u32) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, nanos).unwrap()
+    }
+
+    // 2024-03-10T12:00:00Z
+    const NOON: i64 = 1_710_072_000;
+
+    #[test]
+    fn parse_samples_test() {
+        for (sample, expected) in [
+            // Cloud Tasks scheduleTime, nanoseconds
+            ("2024-03-10T12:00:00.123456789Z", at(NOON, 123_456_789)),
+            // GCS updated, milliseconds
+            ("2024-03-10T12:00:00.123Z", at(NOON, 123_000_000)),
+            // microseconds
+            ("2024-03-10T12:00:00.123456Z", at(NOON, 123_456_000)),
+            // no fraction
+            ("2024-03-10T12:00:00Z", at(NOON, 0)),
+            // a single digit of fraction
+            ("2024-03-10T12:00:00.5Z", at(NOON, 500_000_000)),
+            // trailing zeros
+            ("2024-03-10T12:00:00.100000000Z", at(NOON, 100_000_000)),
+            // +00:00 instead of Z
+            ("2024-03-10T12:00:00+00:00", at(NOON, 0)),
+            ("2024-03-10T12:00:00.000001+00:00", at(NOON, 1_000)),
+            // lowercase separators
+            ("2024-03-10t12:00:00z", at(NOON, 0)),
+            // space separator
+            ("2024-03-10 12:00:00Z", at(NOON, 0)),
+            // other offsets, converted to UTC
+            ("2024-03-10T05:00:00-07:00", at(NOON, 0)),
+            ("2024-03-10T17:30:00.25+05:30", at(NOON, 250_000_000)),
+            // across a day boundary
+            ("2024-03-09T23:00:00-13:00", at(NOON, 0)),
+            // surrounding whitespace, e.g. of a header
+            (" 2024-03-10T12:00:00Z\n", at(NOON, 0)),
+            // S3 Last-Modified and Expires headers
+            ("Sun, 10 Mar 2024 12:00:00 GMT", at(NOON, 0)),
+            ("Sun, 10 Mar 2024 12:00:00 +0000", at(NOON, 0)),
+            // the epoch and before
+            ("1970-01-01T00:00:00Z", at(0, 0)),
+            ("1969-12-31T23:59:59.999Z", at(-1, 999_000_000)),
+            // leap day
+            ("2024-02-29T00:00:00Z", at(1_709_164_800, 0)),
+        ] {
+            assert_eq!(parse_api_timestamp(sample).unwrap(), expected, "{sample:?}");
+        }
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        for (sample, reason) in [
+            ("", "empty"),
+            ("  ", "empty"),
+            ("2024-03-10T12:00:00", "no UTC offset"),
+            ("2024-03-10T12:00:00.123456", "no UTC offset"),
+            ("2024-03-10 12:00:00", "no UTC offset"),
+            ("2024-03-10", "not an RFC 3339 timestamp or HTTP date"),
+            ("1710072000", "not an RFC 3339 timestamp or HTTP date"),
+            (
+                "2024-02-30T12:00:00Z",
+                "not an RFC 3339 timestamp or HTTP date",
+            ),
+            (
+                "2024-03-10T25:00:00Z",
+                "not an RFC 3339 timestamp or HTTP date",
+            ),
+            (
+                "2024-03-10T12:00:00.Z",
+                "not an RFC 3339 timestamp or HTTP date",
+            ),
+            (
+                "2024-03-10T12:00:00+0000",
+                "not an RFC 3339 timestamp or HTTP date",
+            ),
+            ("null", "not an RFC 3339 timestamp or HTTP date"),
+        ] {
+            let error = parse_api_timestamp(sample).unwrap_err();
+            assert_eq!(error.input(), sample);
+            assert_eq!(error.reason, reason, "{sample:?}");
+            assert!(
+                error.to_string().contains(&format!("{sample:?}")),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn format_test() {
+        for (t, api, millis) in [
+            (
+                at(NOON, 0),
+                "2024-03-10T12:00:00Z",
+                "2024-03-10T12:00:00.000Z",
+            ),
+            (
+                at(NOON, 123_000_000),
+                "2024-03-10T12:00:00.123Z",
+                "2024-03-10T12:00:00.123Z",
+            ),
+            (
+                at(NOON, 123_456_000),
+                "2024-03-10T12:00:00.123456Z",
+                "2024-03-10T12:00:00.123Z",
+            ),
+            (
+                at(NOON, 123_456_789),
+                "2024-03-10T12:00:00.123456789Z",
+                "2024-03-10T12:00:00.123Z",
+            ),
+            // truncated, not rounded
+            (
+                at(NOON, 999_999_999),
+                "2024-03-10T12:00:00.999999999Z",
+                "2024-03-10T12:00:00.999Z",
+            ),
+        ] {
+            assert_eq!(to_api_timestamp(t), api);
+            assert_eq!(to_api_timestamp_millis(t), millis);
+            assert_eq!(parse_api_timestamp(api).unwrap(), t);
+        }
+    }
+
+    #[test]
+    fn from_unix_test() {
+        assert_eq!(from_unix(NOON, 5).unwrap(), at(NOON, 5));
+        let error = from_unix(i64::MAX, 0).unwrap_err();
+        assert_eq!(error.input(), format!("{}.000000000", i64::MAX));
+        assert!(from_unix(NOON, 2_000_000_000).is_err());
+    }
+
+    #[cfg(any(feature = "aws-secrets", feature = "aws-storage"))]
+    #[test]
+    fn aws_datetime_test() {
+        for t in [at(NOON, 0), at(NOON, 123_456_789), at(-1, 999_000_000)] {
+            let aws = to_aws_datetime(t);
+            assert_eq!(from_aws_datetime(&aws).unwrap(), t);
+        }
+        let aws = aws_smithy_types::DateTime::from_str(
+            "2024-03-10T12:00:00.5Z",
+            aws_smithy_types::date_time::Format::DateTime,
+        )
+        .unwrap();
+        assert_eq!(from_aws_datetime(&aws).unwrap(), at(NOON, 500_000_000));
+        let http = aws_smithy_types::DateTime::from_str(
+            "Sun, 10 Mar 2024 12:00:00 GMT",
+            aws_smithy_types::date_time::Format::HttpDate,
+        )
+        .unwrap();
+        assert_eq!(from_aws_datetime(&http).unwrap(), at(NOON, 0));
+    }
+}
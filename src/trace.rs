@@ -0,0 +1,446 @@
+//! Spans around the calls of the helper traits, with bounded cardinality
+//!
+//! Wrap a client in [`Traced`] to get one span per call: `nimbus.storage`, `nimbus.secret` or
+//! `nimbus.task`, with the operation, the bucket, project or queue, and the outcome as fields.
+//! Those are a handful of values per deployment. Object keys and task names are not, so how
+//! they are recorded is up to [`TraceConfig::record_keys`], and secret names are never recorded
+//! in clear whatever the configuration.
+//!
+//! ```ignore
+//! // every client not configured otherwise
+//! nimbus::trace::set_global_trace_config(TraceConfig::new().sample_ratio(0.1));
+//!
+//! let storage = Traced::new(client);
+//! let secrets = Traced::new(secret_manager)
+//!     .with_config(TraceConfig::new().record_keys(KeyRecording::Off));
+//! ```
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+
+use tracing::field::{self, Empty};
+use tracing::{Instrument, Span};
+
+use crate::redact::Policy;
+use crate::NimbusError;
+
+mod secret;
+mod storage;
+#[cfg(feature = "gcp-tasks")]
+mod task;
+
+pub use crate::redact::{short_hash, HASH_LEN};
+
+static GLOBAL_CONFIG: RwLock<Option<Arc<TraceConfig>>> = RwLock::new(None);
+
+/// How the identifiers of the objects an operation touches (object keys, task names) are recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRecording {
+    /// as is, one series per object in the trace backend
+    Full,
+    /// as a short stable hash, see [`short_hash`]: repeated accesses to the same object correlate
+    /// without revealing it
+    Hashed,
+    /// the first characters only, e.g. `Prefix(8)` groups `tenants/…` keys by tenant
+    Prefix(usize),
+    Off,
+}
+
+impl KeyRecording {
+    /// the recorded value of `key`, `None` when not recorded
+    pub fn record(&self, key: &str) -> Option<String> {
+        match *self {
+            KeyRecording::Full => Some(key.to_owned()),
+            KeyRecording::Hashed => Some(short_hash(key)),
+            KeyRecording::Prefix(len) => Some(key.chars().take(len).collect()),
+            KeyRecording::Off => None,
+        }
+    }
+
+    /// recording of sensitive identifiers: at least hashed, never in clear nor truncated
+    pub fn at_least_hashed(self) -> Self {
+        match self {
+            KeyRecording::Off => KeyRecording::Off,
+            _ => KeyRecording::Hashed,
+        }
+    }
+}
+
+/// What the spans of a [`Traced`] client record
+///
+/// The default records every operation, hashed keys and payload sizes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceConfig {
+    pub record_keys: KeyRecording,
+    /// share of the operations traced, from 0 to 1
+    /// unsampled operations create no span at all
+    pub sample_ratio: f64,
+    /// bytes sent or received, on the operations moving a payload
+    pub record_payload_sizes: bool,
+    /// policy of these spans instead of the [global one](Policy::global)
+    pub redact: Option<Policy>,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            record_keys: KeyRecording::Hashed,
+            sample_ratio: 1.0,
+            record_payload_sizes: true,
+            redact: None,
+        }
+    }
+}
+
+impl TraceConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_keys(mut self, recording: KeyRecording) -> Self {
+        self.record_keys = recording;
+        self
+    }
+
+    /// clamped to `[0, 1]`, NaN traces nothing
+    pub fn sample_ratio(mut self, ratio: f64) -> Self {
+        self.sample_ratio = if ratio.is_nan() {
+            0.0
+        } else {
+            ratio.clamp(0.0, 1.0)
+        };
+        self
+    }
+
+    pub fn record_payload_sizes(mut self, record: bool) -> Self {
+        self.record_payload_sizes = record;
+        self
+    }
+
+    /// keys the policy hashes are recorded hashed at most, whatever [`TraceConfig::record_keys`]
+    pub fn redact(mut self, policy: Policy) -> Self {
+        self.redact = Some(policy);
+        self
+    }
+
+    /// the redaction policy of the spans
+    pub fn policy(&self) -> &Policy {
+        self.redact.as_ref().unwrap_or_else(|| Policy::global())
+    }
+}
+
+/// configuration of the [`Traced`] clients without their own, from the next call on
+pub fn set_global_trace_config(config: TraceConfig) {
+    *GLOBAL_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(config));
+}
+
+/// configuration of the [`Traced`] clients without their own, the default one until set
+pub fn global_trace_config() -> Arc<TraceConfig> {
+    static DEFAULT: OnceLock<Arc<TraceConfig>> = OnceLock::new();
+
+    GLOBAL_CONFIG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| Arc::clone(DEFAULT.get_or_init(Default::default)))
+}
+
+/// Client whose calls run in spans, implements the same helper traits as the wrapped client
+///
+/// Calls go through the [global configuration](global_trace_config) unless the client has its
+/// own, see [`Traced::with_config`]. Clones share the sampling of the client.
+#[derive(Debug, Clone)]
+pub struct Traced<C> {
+    inner: C,
+    config: Option<Arc<TraceConfig>>,
+    calls: Arc<AtomicU64>,
+}
+
+impl<C> Traced<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            config: None,
+            calls: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// configuration of this client, instead of the global one
+    pub fn with_config(mut self, config: TraceConfig) -> Self {
+        self.config = Some(Arc::new(config));
+        self
+    }
+
+    /// configuration the next call goes through
+    pub fn config(&self) -> Arc<TraceConfig> {
+        self.config.clone().unwrap_or_else(global_trace_config)
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// whether the next call is traced, every `1 / ratio` calls are
+    /// deterministic rather than random, so a ratio holds exactly over any run of calls
+    fn sampled(&self, ratio: f64) -> bool {
+        if ratio >= 1.0 {
+            return true;
+        }
+        if ratio <= 0.0 {
+            return false;
+        }
+        let n = self.calls.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * ratio).floor() > (n * ratio).floor()
+    }
+
+    /// span of a storage operation on `bucket`, and `key` if it is about one object or prefix
+    pub(crate) fn storage_span(&self, op: &'static str, bucket: &str, key: Option<&str>) -> OpSpan {
+        let config = self.config();
+        if !self.sampled(config.sample_ratio) {
+            return OpSpan::disabled();
+        }
+
+        let span = tracing::info_span!(
+            "nimbus.storage",
+            op,
+            bucket,
+            key = Empty,
+            size = Empty,
+            error = Empty
+        );
+        OpSpan::new(span, "key", key, config.record_keys, &config)
+    }
+
+    /// span of a secret manager operation, the secret name is at least hashed
+    pub(crate) fn secret_span(
+        &self,
+        op: &'static str,
+        project: &str,
+        secret: Option<&str>,
+    ) -> OpSpan {
+        let config = self.config();
+        if !self.sampled(config.sample_ratio) {
+            return OpSpan::disabled();
+        }
+
+        let span = tracing::info_span!(
+            "nimbus.secret",
+            op,
+            project,
+            secret = Empty,
+            size = Empty,
+            error = Empty
+        );
+        let recording = config.record_keys.at_least_hashed();
+        OpSpan::new(span, "secret", secret, recording, &config)
+    }
+
+    /// span of a Cloud Tasks operation on `queue` (or project), and `task` if it is about one task
+    #[cfg(feature = "gcp-tasks")]
+    pub(crate) fn task_span(&self, op: &'static str, queue: &str, task: Option<&str>) -> OpSpan {
+        let config = self.config();
+        if !self.sampled(config.sample_ratio) {
+            return OpSpan::disabled();
+        }
+
+        let span = tracing::info_span!(
+            "nimbus.task",
+            op,
+            queue,
+            task = Empty,
+            size = Empty,
+            error = Empty
+        );
+        OpSpan::new(span, "task", task, config.record_keys, &config)
+    }
+}
+
+/// Span of one call, disabled when the call isn't sampled
+pub(crate) struct OpSpan {
+    span: Span,
+    record_sizes: bool,
+}
+
+impl OpSpan {
+    fn new(
+        span: Span,
+        id_field: &'static str,
+        id: Option<&str>,
+        recording: KeyRecording,
+        config: &TraceConfig,
+    ) -> Self {
+        let recording = match id {
+            Some(id) if config.policy().hashes_key(id) => recording.at_least_hashed(),
+            _ => recording,
+        };
+        if let Some(recorded) = id.and_then(|id| recording.record(id)) {
+            span.record(id_field, recorded);
+        }
+
+        Self {
+            span,
+            record_sizes: config.record_payload_sizes,
+        }
+    }
+
+    fn disabled() -> Self {
+        Self {
+            span: Span::none(),
+            record_sizes: false,
+        }
+    }
+
+    /// record the bytes the call moves, if configured
+    pub(crate) fn size(&self, len: usize) {
+        if self.record_sizes {
+            self.span.record("size", len as u64);
+        }
+    }
+
+    /// run the call within the span, recording the code of its error
+    pub(crate) async fn run<T>(
+        &self,
+        call: impl Future<Output = Result<T, NimbusError>>,
+    ) -> Result<T, NimbusError> {
+        let res = call.instrument(self.span.clone()).await;
+        if let Err(e) = &res {
+            self.span.record("error", field::debug(e.code()));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::Mutex;
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::subscriber::{DefaultGuard, Interest};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use super::*;
+
+    /// fields of a captured span, its name under `"name"`
+    pub(crate) type CapturedSpan = HashMap<String, String>;
+
+    /// Subscriber keeping the fields of every span created on the current thread
+    #[derive(Clone, Default)]
+    pub(crate) struct Capture {
+        spans: Arc<Mutex<Vec<CapturedSpan>>>,
+    }
+
+    impl Capture {
+        pub(crate) fn install() -> (Self, DefaultGuard) {
+            let capture = Self::default();
+            let guard = tracing::subscriber::set_default(capture.clone());
+            (capture, guard)
+        }
+
+        pub(crate) fn spans(&self) -> Vec<CapturedSpan> {
+            self.spans.lock().unwrap().clone()
+        }
+    }
+
+    struct Fields<'a>(&'a mut CapturedSpan);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_owned(), value.to_owned());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_owned(), format!("{value:?}"));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+            Interest::sometimes()
+        }
+
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+            let mut span = CapturedSpan::new();
+            span.insert("name".to_owned(), attrs.metadata().name().to_owned());
+            attrs.record(&mut Fields(&mut span));
+
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, id: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Fields(&mut spans[id.into_u64() as usize - 1]));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn key_recording_test() {
+        let key = "tenants/acme/invoices/2024-001.pdf";
+        assert_eq!(KeyRecording::Full.record(key).unwrap(), key);
+        assert_eq!(KeyRecording::Off.record(key), None);
+        assert_eq!(
+            KeyRecording::Prefix(12).record(key).unwrap(),
+            "tenants/acme"
+        );
+        assert_eq!(KeyRecording::Prefix(100).record(key).unwrap(), key);
+        // characters, not bytes
+        assert_eq!(KeyRecording::Prefix(2).record("été").unwrap(), "ét");
+
+        let hashed = KeyRecording::Hashed.record(key).unwrap();
+        assert_eq!(hashed.len(), HASH_LEN);
+        assert!(hashed.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hashed, short_hash(key));
+        assert_ne!(hashed, short_hash("tenants/acme/invoices/2024-002.pdf"));
+
+        assert_eq!(
+            KeyRecording::Prefix(4).at_least_hashed(),
+            KeyRecording::Hashed
+        );
+        assert_eq!(KeyRecording::Off.at_least_hashed(), KeyRecording::Off);
+    }
+
+    #[test]
+    fn sample_ratio_test() {
+        assert_eq!(TraceConfig::new().sample_ratio(3.0).sample_ratio, 1.0);
+        assert_eq!(TraceConfig::new().sample_ratio(-1.0).sample_ratio, 0.0);
+        assert_eq!(TraceConfig::new().sample_ratio(f64::NAN).sample_ratio, 0.0);
+
+        let traced = Traced::new(());
+        let sampled = (0..1000).filter(|_| traced.sampled(0.25)).count();
+        assert_eq!(sampled, 250);
+        assert!((0..100).all(|_| !traced.sampled(0.0)));
+        assert!((0..100).all(|_| traced.sampled(1.0)));
+    }
+
+    #[test]
+    fn global_config_test() {
+        let traced = Traced::new(());
+        assert_eq!(*traced.config(), *global_trace_config());
+
+        let own = TraceConfig::new().record_keys(KeyRecording::Off);
+        assert_eq!(*traced.with_config(own.clone()).config(), own);
+    }
+}
@@ -0,0 +1,167 @@
+#[cfg(feature = "gcp-secrets")]
+use google_secretmanager1::oauth2::authenticator::Authenticator;
+
+use super::Traced;
+#[cfg(feature = "gcp-secrets")]
+use crate::CredentialMonitor;
+use crate::{NimbusError, SecretManagerHelper, TransportConfig};
+
+// secret names are at least hashed, see `Traced::secret_span`
+#[async_trait::async_trait]
+impl<M: SecretManagerHelper<S> + Send + Sync, S: Send + Sync + 'static> SecretManagerHelper<S>
+    for Traced<M>
+{
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_authenticator(authenticator: Authenticator<S>) -> Self {
+        Self::new(M::new_with_authenticator(authenticator).await)
+    }
+
+    #[cfg(feature = "aws-secrets")]
+    async fn new_with_authenticator() -> Self {
+        Self::new(M::new_with_authenticator().await)
+    }
+
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_transport(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+    ) -> Self {
+        Self::new(M::new_with_transport(authenticator, transport).await)
+    }
+
+    #[cfg(feature = "gcp-secrets")]
+    async fn new_with_monitor(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self {
+        Self::new(M::new_with_monitor(authenticator, transport, monitor).await)
+    }
+
+    #[cfg(feature = "aws-secrets")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Self::new(M::new_with_transport(transport).await)
+    }
+
+    async fn get_secret(&self, project: &str, secret: &str) -> Result<Vec<u8>, NimbusError> {
+        let span = self.secret_span("get_secret", project, Some(secret));
+        let value = span.run(self.inner.get_secret(project, secret)).await?;
+        span.size(value.len());
+        Ok(value)
+    }
+
+    async fn create_secret(
+        &self,
+        project: &str,
+        secret_name: &str,
+        secret_val: &str,
+    ) -> Result<(), NimbusError> {
+        let span = self.secret_span("create_secret", project, Some(secret_name));
+        span.size(secret_val.len());
+        span.run(self.inner.create_secret(project, secret_name, secret_val))
+            .await
+    }
+
+    async fn create_secret_bytes(
+        &self,
+        project: &str,
+        secret_name: &str,
+        data: &[u8],
+    ) -> Result<(), NimbusError> {
+        let span = self.secret_span("create_secret_bytes", project, Some(secret_name));
+        span.size(data.len());
+        span.run(self.inner.create_secret_bytes(project, secret_name, data))
+            .await
+    }
+
+    async fn delete_secret(&self, project: &str, secret: &str) -> Result<(), NimbusError> {
+        let span = self.secret_span("delete_secret", project, Some(secret));
+        span.run(self.inner.delete_secret(project, secret)).await
+    }
+
+    async fn get_secret_version(
+        &self,
+        project: &str,
+        secret: &str,
+        version: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let span = self.secret_span("get_secret_version", project, Some(secret));
+        let value = span
+            .run(self.inner.get_secret_version(project, secret, version))
+            .await?;
+        span.size(value.len());
+        Ok(value)
+    }
+
+    async fn latest_version_id(&self, project: &str, secret: &str) -> Result<String, NimbusError> {
+        let span = self.secret_span("latest_version_id", project, Some(secret));
+        span.run(self.inner.latest_version_id(project, secret))
+            .await
+    }
+
+    // provided by the trait, but overridden by some providers
+    async fn get_secret_version_id(
+        &self,
+        project: &str,
+        secret: &str,
+        version_id: &str,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let span = self.secret_span("get_secret_version_id", project, Some(secret));
+        let value = span
+            .run(
+                self.inner
+                    .get_secret_version_id(project, secret, version_id),
+            )
+            .await?;
+        span.size(value.len());
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Capture;
+    use super::super::{short_hash, KeyRecording, TraceConfig};
+    use super::*;
+    use crate::testing::MemorySecretManager;
+
+    #[tokio::test]
+    async fn secret_names_hashed_test() {
+        let (capture, _guard) = Capture::install();
+
+        for recording in [
+            KeyRecording::Full,
+            KeyRecording::Hashed,
+            KeyRecording::Prefix(3),
+        ] {
+            let secrets = Traced::new(MemorySecretManager::new())
+                .with_config(TraceConfig::new().record_keys(recording));
+            secrets
+                .create_secret("project", "db-password", "hunter2")
+                .await
+                .unwrap();
+            secrets.get_secret("project", "db-password").await.unwrap();
+        }
+        let secrets = Traced::new(MemorySecretManager::new())
+            .with_config(TraceConfig::new().record_keys(KeyRecording::Off));
+        secrets
+            .get_secret("project", "db-password")
+            .await
+            .unwrap_err();
+
+        let spans = capture.spans();
+        assert_eq!(spans.len(), 7);
+        for span in &spans[..6] {
+            assert_eq!(span["name"], "nimbus.secret");
+            assert_eq!(span["project"], "project");
+            assert_eq!(span["secret"], short_hash("db-password"));
+            assert_eq!(span["size"], "7");
+        }
+        assert!(!spans[6].contains_key("secret"));
+        assert_eq!(spans[6]["error"], "NotFound");
+        assert!(spans
+            .iter()
+            .flat_map(|span| span.values())
+            .all(|v| !v.contains("db-") && !v.contains("hunter2")));
+    }
+}
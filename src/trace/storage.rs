@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+
+use super::Traced;
+use crate::storage::{
+    AclEntry, AppendOutcome, CorsRule, EncryptionKey, ListPage, ListParams, ObjectMeta,
+    ObjectStream, PolicyCondition, PostPolicy, Precondition, PublicAccess, ReplicationStatus,
+    ResumableUpload, StorageClass, UpdateOptions,
+};
+#[cfg(feature = "aws-storage")]
+use crate::TransportConfig;
+use crate::{ByteSize, ListLimits, NimbusError, StorageHelper};
+
+// the provided methods of the trait go through these, each of their calls gets its own span
+#[async_trait::async_trait]
+impl<C: StorageHelper + Send + Sync> StorageHelper for Traced<C> {
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_authenticator() -> Self {
+        Self::new(C::new_with_authenticator().await)
+    }
+
+    #[cfg(feature = "aws-storage")]
+    async fn new_with_transport(transport: &TransportConfig) -> Self {
+        Self::new(C::new_with_transport(transport).await)
+    }
+
+    async fn upload_from_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("upload_from_bytes", bucket, Some(key));
+        span.size(data.len());
+        span.run(self.inner.upload_from_bytes(bucket, key, mime, data))
+            .await
+    }
+
+    async fn download_to_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>, NimbusError> {
+        let span = self.storage_span("download_to_bytes", bucket, Some(key));
+        let data = span.run(self.inner.download_to_bytes(bucket, key)).await?;
+        span.size(data.len());
+        Ok(data)
+    }
+
+    async fn download_to_writer<W>(
+        &self,
+        bucket: &str,
+        key: &str,
+        writer: &mut W,
+    ) -> Result<u64, NimbusError>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let span = self.storage_span("download_to_writer", bucket, Some(key));
+        let written = span
+            .run(self.inner.download_to_writer(bucket, key, writer))
+            .await?;
+        span.size(written as usize);
+        Ok(written)
+    }
+
+    /// only opening the stream is traced, the chunks are read after the span ends
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectStream, NimbusError> {
+        let span = self.storage_span("download_stream", bucket, Some(key));
+        span.run(self.inner.download_stream(bucket, key)).await
+    }
+
+    async fn download_with_encoding(
+        &self,
+        bucket: &str,
+        key: &str,
+        decompress: bool,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let span = self.storage_span("download_with_encoding", bucket, Some(key));
+        let data = span
+            .run(self.inner.download_with_encoding(bucket, key, decompress))
+            .await?;
+        span.size(data.len());
+        Ok(data)
+    }
+
+    async fn download_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let span = self.storage_span("download_range", bucket, Some(key));
+        let data = span
+            .run(self.inner.download_range(bucket, key, start, end))
+            .await?;
+        span.size(data.len());
+        Ok(data)
+    }
+
+    async fn upload_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        encryption: &EncryptionKey,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("upload_encrypted", bucket, Some(key));
+        span.size(data.len());
+        span.run(
+            self.inner
+                .upload_encrypted(bucket, key, mime, data, encryption),
+        )
+        .await
+    }
+
+    async fn download_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<Vec<u8>, NimbusError> {
+        let span = self.storage_span("download_encrypted", bucket, Some(key));
+        let data = span
+            .run(self.inner.download_encrypted(bucket, key, encryption))
+            .await?;
+        span.size(data.len());
+        Ok(data)
+    }
+
+    async fn object_metadata_encrypted(
+        &self,
+        bucket: &str,
+        key: &str,
+        encryption: &EncryptionKey,
+    ) -> Result<ObjectMeta, NimbusError> {
+        let span = self.storage_span("object_metadata_encrypted", bucket, Some(key));
+        span.run(
+            self.inner
+                .object_metadata_encrypted(bucket, key, encryption),
+        )
+        .await
+    }
+
+    /// traced on the source bucket and key
+    async fn copy_encrypted(
+        &self,
+        source: (&str, &str),
+        source_encryption: Option<&EncryptionKey>,
+        destination: (&str, &str),
+        destination_encryption: Option<&EncryptionKey>,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("copy_encrypted", source.0, Some(source.1));
+        span.run(self.inner.copy_encrypted(
+            source,
+            source_encryption,
+            destination,
+            destination_encryption,
+        ))
+        .await
+    }
+
+    async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        class: StorageClass,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("set_storage_class", bucket, Some(key));
+        span.run(self.inner.set_storage_class(bucket, key, class))
+            .await
+    }
+
+    async fn delete_file(&self, bucket: &str, key: &str) -> Result<(), NimbusError> {
+        let span = self.storage_span("delete_file", bucket, Some(key));
+        span.run(self.inner.delete_file(bucket, key)).await
+    }
+
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, NimbusError> {
+        let span = self.storage_span("object_exists", bucket, Some(key));
+        span.run(self.inner.object_exists(bucket, key)).await
+    }
+
+    async fn upload_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<String, NimbusError> {
+        let span = self.storage_span("upload_conditional", bucket, Some(key));
+        span.size(data.len());
+        span.run(
+            self.inner
+                .upload_conditional(bucket, key, mime, data, precondition),
+        )
+        .await
+    }
+
+    async fn upload_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        metadata: HashMap<String, String>,
+        data: Vec<u8>,
+        precondition: Option<&Precondition>,
+    ) -> Result<String, NimbusError> {
+        let span = self.storage_span("upload_with_metadata", bucket, Some(key));
+        span.size(data.len());
+        span.run(
+            self.inner
+                .upload_with_metadata(bucket, key, mime, metadata, data, precondition),
+        )
+        .await
+    }
+
+    async fn append_bytes(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        data: Vec<u8>,
+        options: UpdateOptions,
+    ) -> Result<AppendOutcome, NimbusError> {
+        let span = self.storage_span("append_bytes", bucket, Some(key));
+        span.size(data.len());
+        span.run(self.inner.append_bytes(bucket, key, mime, data, options))
+            .await
+    }
+
+    async fn delete_conditional(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("delete_conditional", bucket, Some(key));
+        span.run(self.inner.delete_conditional(bucket, key, version))
+            .await
+    }
+
+    async fn download_versioned(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(Vec<u8>, String), NimbusError> {
+        let span = self.storage_span("download_versioned", bucket, Some(key));
+        let res = span.run(self.inner.download_versioned(bucket, key)).await?;
+        span.size(res.0.len());
+        Ok(res)
+    }
+
+    /// traced on the prefix of the listing, as a key
+    async fn list_page(
+        &self,
+        bucket: &str,
+        params: &ListParams,
+        page_token: Option<String>,
+    ) -> Result<ListPage, NimbusError> {
+        let span = self.storage_span("list_page", bucket, params.prefix.as_deref());
+        span.run(self.inner.list_page(bucket, params, page_token))
+            .await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMeta, NimbusError> {
+        let span = self.storage_span("object_metadata", bucket, Some(key));
+        span.run(self.inner.object_metadata(bucket, key)).await
+    }
+
+    async fn replication_status(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ReplicationStatus, NimbusError> {
+        let span = self.storage_span("replication_status", bucket, Some(key));
+        span.run(self.inner.replication_status(bucket, key)).await
+    }
+
+    async fn set_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: HashMap<String, String>,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("set_object_tags", bucket, Some(key));
+        span.run(self.inner.set_object_tags(bucket, key, tags))
+            .await
+    }
+
+    async fn get_object_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<HashMap<String, String>, NimbusError> {
+        let span = self.storage_span("get_object_tags", bucket, Some(key));
+        span.run(self.inner.get_object_tags(bucket, key)).await
+    }
+
+    async fn list_soft_deleted(
+        &self,
+        bucket: &str,
+        prefix: Option<String>,
+        limits: ListLimits,
+    ) -> Result<Vec<(String, i64)>, NimbusError> {
+        let span = self.storage_span("list_soft_deleted", bucket, prefix.as_deref());
+        span.run(self.inner.list_soft_deleted(bucket, prefix, limits))
+            .await
+    }
+
+    async fn restore_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        generation: i64,
+    ) -> Result<(), NimbusError> {
+        let span = self.storage_span("restore_object", bucket, Some(key));
+        span.run(self.inner.restore_object(bucket, key, generation))
+            .await
+    }
+
+    async fn test_permissions(
+        &self,
+        bucket: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        let span = self.storage_span("test_permissions", bucket, None);
+        span.run(self.inner.test_permissions(bucket, permissions))
+            .await
+    }
+
+    async fn bucket_is_public(&self, bucket: &str) -> Result<PublicAccess, NimbusError> {
+        let span = self.storage_span("bucket_is_public", bucket, None);
+        span.run(self.inner.bucket_is_public(bucket)).await
+    }
+
+    async fn object_acls_apply(&self, bucket: &str) -> Result<bool, NimbusError> {
+        let span = self.storage_span("object_acls_apply", bucket, None);
+        span.run(self.inner.object_acls_apply(bucket)).await
+    }
+
+    async fn object_acl(&self, bucket: &str, key: &str) -> Result<Vec<AclEntry>, NimbusError> {
+        let span = self.storage_span("object_acl", bucket, Some(key));
+        span.run(self.inner.object_acl(bucket, key)).await
+    }
+
+    async fn get_cors(&self, bucket: &str) -> Result<Vec<CorsRule>, NimbusError> {
+        let span = self.storage_span("get_cors", bucket, None);
+        span.run(self.inner.get_cors(bucket)).await
+    }
+
+    async fn set_cors(&self, bucket: &str, rules: Vec<CorsRule>) -> Result<(), NimbusError> {
+        let span = self.storage_span("set_cors", bucket, None);
+        span.run(self.inner.set_cors(bucket, rules)).await
+    }
+
+    async fn signed_post_policy(
+        &self,
+        bucket: &str,
+        key_prefix: &str,
+        max_size: ByteSize,
+        expires: Duration,
+        conditions: Vec<PolicyCondition>,
+    ) -> Result<PostPolicy, NimbusError> {
+        let span = self.storage_span("signed_post_policy", bucket, Some(key_prefix));
+        span.run(
+            self.inner
+                .signed_post_policy(bucket, key_prefix, max_size, expires, conditions),
+        )
+        .await
+    }
+
+    async fn signed_download_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        let span = self.storage_span("signed_download_url", bucket, Some(key));
+        span.run(self.inner.signed_download_url(bucket, key, expires_in))
+            .await
+    }
+
+    async fn signed_upload_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, NimbusError> {
+        let span = self.storage_span("signed_upload_url", bucket, Some(key));
+        span.run(self.inner.signed_upload_url(bucket, key, mime, expires_in))
+            .await
+    }
+
+    /// only the start is traced, the chunks go straight through the returned upload
+    async fn start_resumable_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        mime: Option<String>,
+    ) -> Result<ResumableUpload, NimbusError> {
+        let span = self.storage_span("start_resumable_upload", bucket, Some(key));
+        span.run(self.inner.start_resumable_upload(bucket, key, mime))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Capture;
+    use super::super::{short_hash, KeyRecording, TraceConfig};
+    use super::*;
+    use crate::testing::MemoryStorage;
+
+    const KEY: &str = "tenants/acme/report.csv";
+
+    async fn traced_round_trip(config: TraceConfig) -> Vec<super::super::tests::CapturedSpan> {
+        let (capture, _guard) = Capture::install();
+        let storage = Traced::new(MemoryStorage::new()).with_config(config);
+
+        storage
+            .upload_from_bytes("bucket", KEY, None, b"a,b,c".to_vec())
+            .await
+            .unwrap();
+        storage.download_to_bytes("bucket", KEY).await.unwrap();
+        storage
+            .download_to_bytes("bucket", "missing")
+            .await
+            .unwrap_err();
+
+        capture.spans()
+    }
+
+    #[tokio::test]
+    async fn key_recording_modes_test() {
+        let spans = traced_round_trip(TraceConfig::new().record_keys(KeyRecording::Full)).await;
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0]["name"], "nimbus.storage");
+        assert_eq!(spans[0]["op"], "upload_from_bytes");
+        assert_eq!(spans[0]["bucket"], "bucket");
+        assert_eq!(spans[0]["key"], KEY);
+        assert_eq!(spans[0]["size"], "5");
+        assert_eq!(spans[1]["op"], "download_to_bytes");
+        assert_eq!(spans[1]["size"], "5");
+        assert!(!spans[1].contains_key("error"));
+        assert_eq!(spans[2]["error"], "NotFound");
+        assert!(!spans[2].contains_key("size"));
+
+        let spans = traced_round_trip(TraceConfig::new()).await;
+        assert_eq!(spans[0]["key"], short_hash(KEY));
+        assert_eq!(spans[0]["key"], spans[1]["key"]);
+        assert_ne!(spans[0]["key"], spans[2]["key"]);
+
+        let spans = traced_round_trip(
+            TraceConfig::new()
+                .record_keys(KeyRecording::Prefix(12))
+                .record_payload_sizes(false),
+        )
+        .await;
+        assert_eq!(spans[0]["key"], "tenants/acme");
+        assert!(spans.iter().all(|span| !span.contains_key("size")));
+
+        let spans = traced_round_trip(TraceConfig::new().record_keys(KeyRecording::Off)).await;
+        assert_eq!(spans.len(), 3);
+        assert!(spans.iter().all(|span| !span.contains_key("key")));
+        assert!(spans
+            .iter()
+            .all(|span| span.values().all(|v| !v.contains("acme"))));
+    }
+
+    #[tokio::test]
+    async fn sampling_test() {
+        assert!(traced_round_trip(TraceConfig::new().sample_ratio(0.0))
+            .await
+            .is_empty());
+
+        let (capture, _guard) = Capture::install();
+        let storage =
+            Traced::new(MemoryStorage::new()).with_config(TraceConfig::new().sample_ratio(0.1));
+        for i in 0..50 {
+            storage
+                .upload_from_bytes("bucket", &format!("key-{i}"), None, vec![])
+                .await
+                .unwrap();
+        }
+        assert_eq!(capture.spans().len(), 5);
+        // unsampled calls still go through
+        assert_eq!(storage.inner().stats().calls("upload_from_bytes"), 50);
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use google_cloudtasks2::api::Task;
+use google_cloudtasks2::hyper::{Body, Response};
+use google_cloudtasks2::oauth2::authenticator::Authenticator;
+
+use super::Traced;
+use crate::task::{QueueConfigSnapshot, QueueInfo, QueuePath, TaskExt, TaskOutcome, TaskView};
+use crate::{CloudTaskHelper, CredentialMonitor, ListLimits, NimbusError, TransportConfig};
+
+/// queue of a full task name and the id of the task in it
+/// `projects/p/locations/l/queues/q/tasks/id` gives `projects/p/locations/l/queues/q` and `id`
+fn split_task_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once("/tasks/") {
+        Some((queue, id)) => (queue, Some(id)),
+        None => (name, None),
+    }
+}
+
+// the provided methods of the trait go through these, each of their calls gets its own span
+#[async_trait::async_trait]
+impl<C: CloudTaskHelper<S> + Send + Sync, S: Send + Sync + 'static> CloudTaskHelper<S>
+    for Traced<C>
+{
+    async fn new_with_authenticator(authenticator: Authenticator<S>) -> Self {
+        Self::new(C::new_with_authenticator(authenticator).await)
+    }
+
+    async fn new_with_transport(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+    ) -> Self {
+        Self::new(C::new_with_transport(authenticator, transport).await)
+    }
+
+    async fn new_with_monitor(
+        authenticator: Authenticator<S>,
+        transport: &TransportConfig,
+        monitor: &CredentialMonitor,
+    ) -> Self {
+        Self::new(C::new_with_monitor(authenticator, transport, monitor).await)
+    }
+
+    /// traced on the id of the task, if named
+    async fn create_task(
+        &self,
+        queue: &str,
+        task: Task,
+        res_view: Option<TaskView>,
+    ) -> Result<(Response<Body>, Task), NimbusError> {
+        let id = task
+            .name
+            .as_deref()
+            .and_then(|name| split_task_name(name).1);
+        let span = self.task_span("create_task", queue, id);
+        span.size(task.body_bytes().map_or(0, <[u8]>::len));
+        span.run(self.inner.create_task(queue, task, res_view))
+            .await
+    }
+
+    async fn get_task(
+        &self,
+        name: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+    ) -> Result<Task, NimbusError> {
+        let (queue, id) = split_task_name(name);
+        let span = self.task_span("get_task", queue, id);
+        span.run(self.inner.get_task(name, res_view, redact)).await
+    }
+
+    async fn list_tasks(
+        &self,
+        queue: &str,
+        res_view: Option<TaskView>,
+        redact: bool,
+        page_token: Option<String>,
+    ) -> Result<(Vec<Task>, Option<String>), NimbusError> {
+        let span = self.task_span("list_tasks", queue, None);
+        span.run(self.inner.list_tasks(queue, res_view, redact, page_token))
+            .await
+    }
+
+    async fn delete_task(&self, name: &str) -> Result<(), NimbusError> {
+        let (queue, id) = split_task_name(name);
+        let span = self.task_span("delete_task", queue, id);
+        span.run(self.inner.delete_task(name)).await
+    }
+
+    async fn wait_for_task_completion(
+        &self,
+        task_name: &str,
+        timeout: Duration,
+    ) -> Result<TaskOutcome, NimbusError> {
+        let (queue, id) = split_task_name(task_name);
+        let span = self.task_span("wait_for_task_completion", queue, id);
+        span.run(self.inner.wait_for_task_completion(task_name, timeout))
+            .await
+    }
+
+    /// traced on the project, in place of the queue
+    async fn list_locations(
+        &self,
+        project: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<String>, NimbusError> {
+        let span = self.task_span("list_locations", project, None);
+        span.run(self.inner.list_locations(project, limits)).await
+    }
+
+    /// traced on the project, in place of the queue
+    async fn list_queues(
+        &self,
+        project: &str,
+        location: &str,
+        limits: ListLimits,
+    ) -> Result<Vec<QueueInfo>, NimbusError> {
+        let span = self.task_span("list_queues", project, None);
+        span.run(self.inner.list_queues(project, location, limits))
+            .await
+    }
+
+    async fn export_queue_config(
+        &self,
+        queue: &QueuePath,
+    ) -> Result<QueueConfigSnapshot, NimbusError> {
+        let span = self.task_span("export_queue_config", &queue.to_string(), None);
+        span.run(self.inner.export_queue_config(queue)).await
+    }
+
+    async fn create_queue(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+    ) -> Result<(), NimbusError> {
+        let span = self.task_span("create_queue", &queue.to_string(), None);
+        span.run(self.inner.create_queue(queue, config)).await
+    }
+
+    async fn update_queue_config(
+        &self,
+        queue: &QueuePath,
+        config: &QueueConfigSnapshot,
+        fields: &[&str],
+    ) -> Result<(), NimbusError> {
+        let span = self.task_span("update_queue_config", &queue.to_string(), None);
+        span.run(self.inner.update_queue_config(queue, config, fields))
+            .await
+    }
+
+    async fn test_permissions(
+        &self,
+        queue: &str,
+        permissions: &[&str],
+    ) -> Result<Vec<String>, NimbusError> {
+        let span = self.task_span("test_permissions", queue, None);
+        span.run(self.inner.test_permissions(queue, permissions))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Capture;
+    use super::super::{short_hash, KeyRecording, TraceConfig};
+    use super::*;
+    use crate::testing::MemoryCloudTasks;
+    use crate::TaskHelper;
+
+    const QUEUE: &str = "projects/p/locations/l/queues/q";
+
+    #[test]
+    fn split_task_name_test() {
+        assert_eq!(
+            split_task_name(&format!("{QUEUE}/tasks/t-1")),
+            (QUEUE, Some("t-1"))
+        );
+        assert_eq!(split_task_name(QUEUE), (QUEUE, None));
+    }
+
+    #[tokio::test]
+    async fn task_spans_test() {
+        let (capture, _guard) = Capture::install();
+        let tasks = Traced::new(MemoryCloudTasks::new())
+            .with_config(TraceConfig::new().record_keys(KeyRecording::Hashed));
+
+        let task = Task::new_task(
+            "https://example.com",
+            "POST",
+            Some(b"{}".to_vec()),
+            None,
+            Some(format!("{QUEUE}/tasks/t-1")),
+            None,
+            None,
+        );
+        tasks.push_task(QUEUE, task, None).await.unwrap();
+        tasks
+            .get_task(&format!("{QUEUE}/tasks/t-1"), None, true)
+            .await
+            .unwrap();
+
+        let spans = capture.spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["name"], "nimbus.task");
+        assert_eq!(spans[0]["op"], "create_task");
+        assert_eq!(spans[0]["queue"], QUEUE);
+        assert_eq!(spans[0]["task"], short_hash("t-1"));
+        assert_eq!(spans[0]["size"], "2");
+        assert_eq!(spans[1]["task"], spans[0]["task"]);
+    }
+}
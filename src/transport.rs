@@ -0,0 +1,333 @@
+//! Connection pooling and keep-alive of the HTTP clients, for high-throughput workloads
+//!
+//! A [`TransportConfig`] is applied when the client is built: with `new_with_transport` on the helper traits,
+//! or [`TransportConfig::apply`] on the `ClientConfig` of the GCS client. The default is hyper's own,
+//! what the clients built by `new_with_authenticator` use on GCP; on AWS `new_with_authenticator` keeps
+//! the SDK's client, whose connect timeout of 3.1s also applies to `new_with_transport` unless set.
+//!
+//! Many small requests to one host are best served by keeping a large idle pool around for long enough
+//! to bridge the gaps between bursts:
+//!
+//! ```ignore
+//! let transport = TransportConfig::new()
+//!     .pool_max_idle_per_host(256)
+//!     .pool_idle_timeout(Some(Duration::from_secs(300)))
+//!     .tcp_keepalive(Some(Duration::from_secs(60)))
+//!     .connect_timeout(Some(Duration::from_secs(2)));
+//! let storage = aws_sdk_s3::Client::new_with_transport(&transport).await;
+//! ```
+//!
+//! HTTP/2 requests to a host share its connection up to the number of streams the server allows,
+//! hyper follows the limit the server advertises and has no setting of its own for it.
+
+use std::time::Duration;
+
+use crate::env::{EnvError, Mode, Var, Vars};
+
+/// Idle connections are closed after this long unless set with [`TransportConfig::pool_idle_timeout`]
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Settings of the HTTP transport of a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// idle connections kept per host, unlimited by default
+    pub pool_max_idle_per_host: usize,
+    /// how long a connection is kept idle, forever if `None`
+    pub pool_idle_timeout: Option<Duration>,
+    /// interval of the HTTP/2 pings keeping connections alive, none by default
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// interval of the TCP keep-alive probes, the system's default if `None`
+    pub tcp_keepalive: Option<Duration>,
+    /// time allowed to connect, unlimited if `None`
+    pub connect_timeout: Option<Duration>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: usize::MAX,
+            pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            http2_keep_alive_interval: None,
+            tcp_keepalive: None,
+            connect_timeout: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 0 closes connections once their request is done
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pool_idle_timeout = timeout;
+        self
+    }
+
+    pub fn http2_keep_alive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.http2_keep_alive_interval = interval;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.tcp_keepalive = interval;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// the default transport with the `NIMBUS_` variables of the environment applied:
+    /// `NIMBUS_CONNECT_TIMEOUT`, `NIMBUS_POOL_IDLE_TIMEOUT` and `NIMBUS_POOL_MAX_IDLE_PER_HOST`
+    ///
+    /// The whole environment is [validated](crate::env::validate) first, in [`Mode::current`],
+    /// and any issue fails with every issue found.
+    pub fn from_env() -> Result<Self, EnvError> {
+        let vars = Vars::from_process();
+        Self::from_vars(&vars, Mode::from_vars(&vars))
+    }
+
+    fn from_vars(vars: &Vars, mode: Mode) -> Result<Self, EnvError> {
+        let issues = vars.validate(mode);
+        if !issues.is_empty() {
+            return Err(EnvError { issues });
+        }
+        let invalid = |issue| EnvError {
+            issues: vec![issue],
+        };
+
+        let mut config = Self::default();
+        if let Some(timeout) = vars.duration(Var::NimbusConnectTimeout).map_err(invalid)? {
+            config.connect_timeout = Some(timeout);
+        }
+        if let Some(timeout) = vars.duration(Var::NimbusPoolIdleTimeout).map_err(invalid)? {
+            config.pool_idle_timeout = Some(timeout);
+        }
+        if let Some(max) = vars
+            .integer(Var::NimbusPoolMaxIdlePerHost)
+            .map_err(invalid)?
+        {
+            config.pool_max_idle_per_host = max;
+        }
+        Ok(config)
+    }
+
+    /// the `ClientConfig` of the GCS client with its HTTP client built from this transport
+    #[cfg(feature = "gcp-storage")]
+    pub fn apply(
+        &self,
+        mut config: google_cloud_storage::client::ClientConfig,
+    ) -> google_cloud_storage::client::ClientConfig {
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .http2_keep_alive_interval(self.http2_keep_alive_interval)
+            .tcp_keepalive(self.tcp_keepalive);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        let client = builder
+            .build()
+            .expect("the TLS backend of reqwest is available");
+        config.http = Some(reqwest_middleware::ClientBuilder::new(client).build());
+        config
+    }
+
+    /// TCP connector, taking `https` URIs to wrap it in TLS
+    #[cfg(any(
+        feature = "gcp-secrets",
+        feature = "gcp-tasks",
+        feature = "aws-secrets",
+        feature = "aws-storage"
+    ))]
+    pub(crate) fn http_connector(&self) -> hyper::client::HttpConnector {
+        let mut connector = hyper::client::HttpConnector::new();
+        connector.enforce_http(false);
+        connector.set_keepalive(self.tcp_keepalive);
+        connector.set_connect_timeout(self.connect_timeout);
+        connector
+    }
+
+    /// TLS connector with the native roots, over HTTP/1 or HTTP/2
+    #[cfg(any(
+        feature = "gcp-secrets",
+        feature = "gcp-tasks",
+        feature = "aws-secrets",
+        feature = "aws-storage"
+    ))]
+    pub(crate) fn https_connector(
+        &self,
+        https_only: bool,
+    ) -> hyper_rustls::HttpsConnector<hyper::client::HttpConnector> {
+        let builder = hyper_rustls::HttpsConnectorBuilder::new().with_native_roots();
+        let builder = if https_only {
+            builder.https_only()
+        } else {
+            builder.https_or_http()
+        };
+        builder
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(self.http_connector())
+    }
+
+    /// pool settings of the hyper clients, of the generated GCP APIs and the AWS SDK
+    #[cfg(any(
+        feature = "gcp-secrets",
+        feature = "gcp-tasks",
+        feature = "aws-secrets",
+        feature = "aws-storage"
+    ))]
+    pub(crate) fn hyper_builder(&self) -> hyper::client::Builder {
+        let mut builder = hyper::Client::builder();
+        builder
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .http2_keep_alive_interval(self.http2_keep_alive_interval);
+        builder
+    }
+
+    /// client of the generated GCP APIs
+    #[cfg(any(feature = "gcp-secrets", feature = "gcp-tasks"))]
+    pub(crate) fn hyper_client(
+        &self,
+    ) -> hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>> {
+        self.hyper_builder().build(self.https_connector(true))
+    }
+
+    /// configuration of the AWS SDK clients, from the default provider chain
+    /// plain HTTP stays allowed for local endpoints, the SDK's connect timeout applies unless set
+    #[cfg(any(feature = "aws-secrets", feature = "aws-storage"))]
+    pub(crate) async fn aws_sdk_config(&self) -> aws_config::SdkConfig {
+        let client = aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder::new()
+            .hyper_builder(self.hyper_builder())
+            .build(self.https_connector(false));
+
+        let mut loader =
+            aws_config::defaults(aws_config::BehaviorVersion::latest()).http_client(client);
+        if let Some(timeout) = self.connect_timeout {
+            loader = loader.timeout_config(
+                aws_config::timeout::TimeoutConfig::builder()
+                    .connect_timeout(timeout)
+                    .build(),
+            );
+        }
+        loader.load().await
+    }
+}
+
+#[cfg(any(
+    feature = "gcp-secrets",
+    feature = "gcp-tasks",
+    feature = "aws-secrets",
+    feature = "aws-storage"
+))]
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// HTTP/1.1 server answering every request with an empty 200, counting the connections it accepts
+    async fn stub_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let mut request = Vec::new();
+                    loop {
+                        let Ok(n) = socket.read(&mut buf).await else {
+                            return;
+                        };
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                        // GETs without a body, a request ends with its headers
+                        while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            request.drain(..end + 4);
+                            let response = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+                            if socket.write_all(response).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        (url, connections)
+    }
+
+    async fn sequential_gets(transport: &TransportConfig, requests: usize) -> usize {
+        let (url, connections) = stub_server().await;
+        let client = transport
+            .hyper_builder()
+            .build::<_, hyper::Body>(transport.http_connector());
+        for _ in 0..requests {
+            let response = client.get(url.parse().unwrap()).await.unwrap();
+            assert_eq!(response.status(), 200);
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+        connections.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn from_env_test() {
+        let vars = Vars::new([
+            ("NIMBUS_CONNECT_TIMEOUT", "2s"),
+            ("NIMBUS_POOL_MAX_IDLE_PER_HOST", "32"),
+        ]);
+        let config = TransportConfig::from_vars(&vars, Mode::Aws).unwrap();
+        assert_eq!(
+            config,
+            TransportConfig::new()
+                .connect_timeout(Some(Duration::from_secs(2)))
+                .pool_max_idle_per_host(32)
+        );
+
+        let vars = Vars::new([
+            ("NIMBUS_CONNECT_TIMEOUT", "2 seconds"),
+            ("NIMBUS_POOL_IDLE_TIMOUT", "5m"),
+        ]);
+        let e = TransportConfig::from_vars(&vars, Mode::Aws).unwrap_err();
+        let names: Vec<_> = e.issues.iter().map(|issue| issue.name()).collect();
+        assert_eq!(names, ["NIMBUS_CONNECT_TIMEOUT", "NIMBUS_POOL_IDLE_TIMOUT"]);
+        assert!(
+            e.to_string()
+                .contains("did you mean NIMBUS_POOL_IDLE_TIMEOUT?"),
+            "{e}"
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_reuse_test() {
+        let pooled = TransportConfig::new()
+            .pool_max_idle_per_host(8)
+            .tcp_keepalive(Some(Duration::from_secs(30)))
+            .connect_timeout(Some(Duration::from_secs(2)));
+        assert_eq!(sequential_gets(&pooled, 10).await, 1);
+
+        let unpooled = TransportConfig::new().pool_max_idle_per_host(0);
+        assert_eq!(sequential_gets(&unpooled, 10).await, 10);
+    }
+}
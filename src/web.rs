@@ -0,0 +1,207 @@
+//! [`NimbusError`] as an error response of web frameworks
+//!
+//! Both integrations answer with the status given by [`crate::ErrorCode::http_status`],
+//! a `Retry-After` header when the provider sent one and the [`crate::ErrorSummary`] as json body.
+use crate::{ErrorSummary, NimbusError};
+
+fn json(summary: &ErrorSummary) -> String {
+    serde_json::to_string(summary).expect("ErrorSummary is always serializable")
+}
+
+#[cfg(feature = "axum")]
+impl axum::response::IntoResponse for NimbusError {
+    fn into_response(self) -> axum::response::Response {
+        use axum::http::{header, HeaderValue, StatusCode};
+
+        let summary = self.summary();
+        let status = StatusCode::from_u16(summary.code.http_status())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        let mut res = (
+            status,
+            [(header::CONTENT_TYPE, "application/json")],
+            json(&summary),
+        )
+            .into_response();
+
+        if let Some(secs) = summary.retry_after_secs {
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        res
+    }
+}
+
+#[cfg(feature = "actix")]
+impl actix_web::ResponseError for NimbusError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+
+        StatusCode::from_u16(self.code().http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        use actix_web::http::header;
+
+        let summary = self.summary();
+        let mut res = actix_web::HttpResponse::build(self.status_code());
+        res.content_type("application/json");
+
+        if let Some(secs) = summary.retry_after_secs {
+            res.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+
+        res.body(json(&summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{secret, storage};
+
+    use super::*;
+
+    /// (error, status, retry-after, body)
+    fn cases() -> Vec<(NimbusError, u16, Option<&'static str>, &'static str)> {
+        vec![
+            (
+                storage::Error::NotFound("bucket/secret-key".to_owned()).into(),
+                404,
+                None,
+                r#"{"code":"not_found","message":"resource not found","retryable":false}"#,
+            ),
+            (
+                storage::Error::PermissionDenied("bucket".to_owned()).into(),
+                403,
+                None,
+                r#"{"code":"permission_denied","message":"permission denied","retryable":false}"#,
+            ),
+            (
+                storage::Error::RateLimited {
+                    message: "SlowDown".to_owned(),
+                    retry_after: Some(Duration::from_secs(30)),
+                }
+                .into(),
+                429,
+                Some("30"),
+                r#"{"code":"rate_limited","message":"rate limited","retryable":true,"retry_after_secs":30}"#,
+            ),
+            (
+                storage::Error::Timeout("upload".to_owned()).into(),
+                504,
+                None,
+                r#"{"code":"timeout","message":"timed out","retryable":true}"#,
+            ),
+            (
+                storage::Error::InvalidFileType("application/zip".to_owned()).into(),
+                500,
+                None,
+                r#"{"code":"invalid_input","message":"invalid input","retryable":false}"#,
+            ),
+            (
+                secret::Error::NotFound("db-password".to_owned()).into(),
+                404,
+                None,
+                r#"{"code":"not_found","message":"resource not found","retryable":false}"#,
+            ),
+            (
+                secret::Error::RateLimited {
+                    message: "ThrottlingException".to_owned(),
+                    retry_after: None,
+                }
+                .into(),
+                429,
+                None,
+                r#"{"code":"rate_limited","message":"rate limited","retryable":true}"#,
+            ),
+            (
+                secret::Error::NoPayload.into(),
+                500,
+                None,
+                r#"{"code":"internal","message":"internal error","retryable":false}"#,
+            ),
+            #[cfg(feature = "gcp-tasks")]
+            (
+                crate::task::Error::CloudTasks(google_cloudtasks2::Error::BadRequest(
+                    serde_json::json!({ "error": { "code": 404, "message": "queue secret-queue" } }),
+                ))
+                .into(),
+                404,
+                None,
+                r#"{"code":"not_found","message":"resource not found","retryable":false}"#,
+            ),
+            #[cfg(feature = "gcp-tasks")]
+            (
+                crate::task::Error::Other("secret".to_owned()).into(),
+                500,
+                None,
+                r#"{"code":"internal","message":"internal error","retryable":false}"#,
+            ),
+            (
+                NimbusError::Other("secret".to_owned()),
+                500,
+                None,
+                r#"{"code":"internal","message":"internal error","retryable":false}"#,
+            ),
+        ]
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn axum_response_test() {
+        use axum::response::IntoResponse;
+
+        for (error, status, retry_after, body) in cases() {
+            let name = error.to_string();
+            let res = error.into_response();
+
+            assert_eq!(res.status().as_u16(), status, "{name}");
+            assert_eq!(
+                res.headers()
+                    .get("retry-after")
+                    .map(|v| v.to_str().unwrap()),
+                retry_after,
+                "{name}"
+            );
+            assert_eq!(res.headers()["content-type"], "application/json", "{name}");
+
+            let bytes =
+                futures::executor::block_on(axum::body::to_bytes(res.into_body(), usize::MAX))
+                    .unwrap();
+            assert_eq!(bytes, body.as_bytes(), "{name}");
+        }
+    }
+
+    #[cfg(feature = "actix")]
+    #[test]
+    fn actix_response_test() {
+        use actix_web::ResponseError;
+
+        for (error, status, retry_after, body) in cases() {
+            let name = error.to_string();
+            let res = error.error_response();
+
+            assert_eq!(error.status_code().as_u16(), status, "{name}");
+            assert_eq!(res.status().as_u16(), status, "{name}");
+            assert_eq!(
+                res.headers()
+                    .get("retry-after")
+                    .map(|v| v.to_str().unwrap()),
+                retry_after,
+                "{name}"
+            );
+            assert_eq!(
+                res.headers().get("content-type").unwrap(),
+                "application/json",
+                "{name}"
+            );
+
+            let bytes =
+                futures::executor::block_on(actix_web::body::to_bytes(res.into_body())).unwrap();
+            assert_eq!(bytes, body.as_bytes(), "{name}");
+        }
+    }
+}